@@ -0,0 +1,78 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use fizyr_rpc::{ReceivedMessage, StreamBody, UnixStreamPeer, UnixStreamTransport};
+use tokio::net::UnixStream;
+
+/// Create a runtime for the benchmarks.
+fn runtime() -> tokio::runtime::Runtime {
+	tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap()
+}
+
+/// Create a pair of connected peers and spawn a task that echoes all requests of the second peer.
+async fn echo_pair() -> fizyr_rpc::PeerHandle<StreamBody> {
+	let (peer_a, peer_b) = UnixStream::pair().unwrap();
+	let handle_a = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, Default::default()));
+	let mut handle_b = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default()));
+	tokio::spawn(async move {
+		while let Ok(message) = handle_b.recv_message().await {
+			if let ReceivedMessage::Request(request, body) = message {
+				let _ = request.send_response(request.service_id(), body).await;
+			}
+		}
+	});
+	handle_a
+}
+
+/// Measure the round trip time of a request and response.
+fn request_response(c: &mut Criterion) {
+	let runtime = runtime();
+	let peer = runtime.block_on(echo_pair());
+
+	let mut group = c.benchmark_group("request_response");
+	for size in [0, 1024, 8 * 1024] {
+		let body = vec![0u8; size];
+		group.throughput(Throughput::Bytes(size as u64));
+		group.bench_with_input(BenchmarkId::from_parameter(size), &body, |b, body| {
+			b.to_async(&runtime).iter(|| async {
+				let mut request = peer.send_request(1, body.as_slice()).await.unwrap();
+				request.recv_response().await.unwrap()
+			})
+		});
+	}
+	group.finish();
+}
+
+/// Measure the throughput of stream messages, sent one by one and in batches.
+fn stream_messages(c: &mut Criterion) {
+	let runtime = runtime();
+	let (peer_a, mut peer_b) = runtime.block_on(async {
+		let (peer_a, peer_b) = UnixStream::pair().unwrap();
+		let peer_a = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, Default::default()));
+		let peer_b = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default()));
+		(peer_a, peer_b)
+	});
+
+	let mut group = c.benchmark_group("stream_messages");
+	group.throughput(Throughput::Elements(100));
+	group.bench_function("single", |b| {
+		b.iter(|| runtime.block_on(async {
+			for _ in 0..100 {
+				peer_a.send_stream(1, &b"hello"[..]).await.unwrap();
+			}
+			for _ in 0..100 {
+				peer_b.recv_message().await.unwrap();
+			}
+		}))
+	});
+	group.bench_function("batch", |b| {
+		b.iter(|| runtime.block_on(async {
+			peer_a.send_stream_batch((0..100).map(|_| (1, StreamBody::from(&b"hello"[..])))).await.unwrap();
+			for _ in 0..100 {
+				peer_b.recv_message().await.unwrap();
+			}
+		}))
+	});
+	group.finish();
+}
+
+criterion_group!(benches, request_response, stream_messages);
+criterion_main!(benches);
@@ -14,6 +14,7 @@ fizyr_rpc::interface! {
 		service 0 ping: () -> (),
 
 		/// Record an image.
+		#[builder(color: bool, cloud: bool)]
 		service 1 record: RecordRequest -> () {
 			/// Cancel the recording prematurely.
 			request_update 10 cancel: CancelReason,
@@ -63,7 +64,200 @@ pub mod camera_events {
 	}
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+pub mod camera_frames {
+	fizyr_rpc::interface! {
+		pub interface CameraFrames {
+			/// Notifications whenever the camera changes record state.
+			stream 40 record_state: super::RecordState,
+
+			/// Notifications for each captured frame, with the frame number.
+			///
+			/// The notifications may be delivered out of order.
+			#[unordered]
+			stream 41 frame_captured: u64,
+
+			/// Capture a number of frames, and get the number of dropped frames as response.
+			service 42 capture: u32 -> u32 {
+				/// Update with the frame number of each captured frame.
+				#[stream]
+				response_update 43 frame: u64,
+			},
+		}
+	}
+}
+
+fizyr_rpc::service_registry! {
+	/// Service IDs shared with other implementations of the camera configuration interface.
+	pub mod ids {
+		GET_RESOLUTION = 20,
+		SET_RESOLUTION = 21,
+		RESOLUTION_CHANGED = 22,
+		SET_FRAME_RATE = 23,
+		RESOLUTION_APPLIED = 30,
+	}
+}
+
+pub mod camera_config {
+	use super::{ids, ConfigError, Resolution};
+
+	fizyr_rpc::interface! {
+		pub interface CameraConfig {
+			/// Get the current resolution.
+			service ids::GET_RESOLUTION get_resolution: () -> Resolution,
+
+			/// Change the resolution.
+			service ids::SET_RESOLUTION set_resolution: Resolution -> () ! ConfigError {
+				/// The new resolution has been applied.
+				response_update ids::RESOLUTION_APPLIED applied: (),
+			},
+
+			/// Change the frame rate, and get the frame rates supported at the current resolution.
+			service ids::SET_FRAME_RATE set_frame_rate: u32 -> Vec<u32> ! ConfigError,
+
+			/// Notifications whenever the resolution changes.
+			stream ids::RESOLUTION_CHANGED resolution_changed: Resolution,
+		}
+	}
+}
+
+/// Interfaces with services, updates and streams that are disabled by `#[cfg]` attributes.
+///
+/// `#[cfg(any())]` is never enabled and `#[cfg(all())]` is always enabled.
+/// The disabled items refer to types that do not exist, so they must not generate any code.
+pub mod camera_optional {
+	use super::{CancelReason, RecordState};
+
+	fizyr_rpc::interface! {
+		pub interface CameraOptional {
+			/// Ping the server.
+			service 0 ping: () -> (),
+
+			/// Calibrate the camera.
+			#[cfg(any())]
+			service 1 calibrate: CalibrationRequest -> CalibrationResult,
+
+			/// Record an image.
+			#[cfg(all())]
+			service 2 record: () -> () {
+				/// Cancel the recording prematurely.
+				request_update 10 cancel: CancelReason,
+
+				/// Change the exposure during the recording.
+				#[cfg(any())]
+				request_update 11 exposure: Exposure,
+
+				/// Update sent by the server to notify the client about recording progress.
+				#[cfg(all())]
+				response_update 12 state: RecordState,
+			},
+
+			/// Notifications of calibration progress.
+			#[cfg(any())]
+			stream 1 calibration_progress: CalibrationProgress,
+
+			/// Notifications whenever the camera changes record state.
+			#[cfg(all())]
+			stream 2 record_state: RecordState,
+		}
+	}
+
+	pub mod disabled {
+		fizyr_rpc::interface! {
+			pub interface Disabled {
+				/// Calibrate the camera.
+				#[cfg(any())]
+				service 1 calibrate: CalibrationRequest -> CalibrationResult,
+
+				/// Notifications of calibration progress.
+				#[cfg(any())]
+				stream 1 calibration_progress: CalibrationProgress,
+			}
+		}
+	}
+}
+
+/// Two versions of an interface, where the newer version added a value to the message bodies.
+pub mod camera_exposure {
+	/// The old version, which can receive messages with values it does not know.
+	pub mod v1 {
+		use serde::{Deserialize, Serialize};
+
+		fizyr_rpc::interface! {
+			#[forward_compatible]
+			pub interface CameraExposure {
+				/// Automatically adjust the exposure.
+				service 1 auto_expose: () -> () {
+					/// The exposure mode used for the next image.
+					response_update 10 mode: ExposureMode,
+				},
+
+				/// Notifications whenever the exposure mode changes.
+				stream 2 mode_changed: ExposureMode,
+			}
+		}
+
+		#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+		pub enum ExposureMode {
+			Auto,
+			Manual,
+		}
+	}
+
+	/// The new version, with an additional exposure mode.
+	pub mod v2 {
+		use serde::{Deserialize, Serialize};
+
+		fizyr_rpc::interface! {
+			pub interface CameraExposure {
+				service 1 auto_expose: () -> () {
+					response_update 10 mode: ExposureMode,
+				},
+				stream 2 mode_changed: ExposureMode,
+			}
+		}
+
+		#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+		pub enum ExposureMode {
+			Auto,
+			Manual,
+			Hdr,
+		}
+	}
+}
+
+/// An older build of the camera interface, where the record service did not take a request body yet.
+pub mod camera_legacy {
+	fizyr_rpc::interface! {
+		pub interface Camera {
+			service 0 ping: () -> (),
+			service 1 record: () -> (),
+		}
+	}
+}
+
+/// A camera interface where clients send heartbeats, so the camera can detect clients that went away.
+pub mod camera_heartbeat {
+	fizyr_rpc::interface! {
+		#[heartbeat(interval = "10ms")]
+		pub interface CameraHeartbeat {
+			service 0 ping: () -> (),
+		}
+	}
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Resolution {
+	pub width: u32,
+	pub height: u32,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum ConfigError {
+	Unsupported,
+	OutOfRange { min: u32, max: u32 },
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct RecordRequest {
 	pub color: bool,
 	pub cloud: bool,
@@ -1,3 +1,42 @@
+//! Tests for the code generated by the `interface!` macro.
+//!
+//! Service IDs that are paths to constants can only be compared after constant evaluation,
+//! so duplicates are caught by the `const` assertions emitted by the macro:
+//!
+//! ```compile_fail,E0080
+//! mod ids {
+//!     pub const PING: i32 = 1;
+//!     pub const PONG: i32 = 1;
+//! }
+//!
+//! fizyr_rpc::interface! {
+//!     pub interface Duplicate {
+//!         service ids::PING ping: () -> (),
+//!         service ids::PONG pong: () -> (),
+//!     }
+//! }
+//!
+//! fn main() {}
+//! ```
+//!
+//! The same interface compiles if the constants have different values:
+//!
+//! ```
+//! mod ids {
+//!     pub const PING: i32 = 1;
+//!     pub const PONG: i32 = 2;
+//! }
+//!
+//! fizyr_rpc::interface! {
+//!     pub interface Duplicate {
+//!         service ids::PING ping: () -> (),
+//!         service ids::PONG pong: () -> (),
+//!     }
+//! }
+//!
+//! fn main() {}
+//! ```
+
 pub mod camera;
 
 pub struct Json;
@@ -11,6 +50,16 @@ impl<T: serde::de::DeserializeOwned> fizyr_rpc::format::DecodeBody<T> for Json {
 		serde_json::from_slice(&body.data)
 			.map_err(|e| Box::new(e) as _)
 	}
+
+	fn decode_body_partial(body: Self::Body, context: &fizyr_rpc::format::DecodeContext) -> Result<fizyr_rpc::format::PartialDecode<T>, Box<dyn std::error::Error + Send>> {
+		use fizyr_rpc::format::PartialDecode;
+		context.check_body_len(body.data.len())?;
+		match serde_json::from_slice(&body.data) {
+			Ok(value) => Ok(PartialDecode::Decoded(value)),
+			Err(e) if e.is_data() && e.to_string().starts_with("unknown variant") => Ok(PartialDecode::Unrecognized(body.data.to_vec())),
+			Err(e) => Err(Box::new(e)),
+		}
+	}
 }
 
 impl<T: serde::Serialize + ?Sized> fizyr_rpc::format::EncodeBody<T> for Json {
@@ -1,6 +1,6 @@
 use assert2::{let_assert, assert};
+use fizyr_rpc::prelude::*;
 use fizyr_rpc::{UnixStreamPeer, UnixStreamTransport};
-use fizyr_rpc::format::Format;
 
 use macros_tests::{camera, Json};
 
@@ -17,6 +17,8 @@ async fn ping() {
 
 	let server = tokio::spawn(async move {
 		let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Ping(request, ()))) = server.recv_message().await);
+		assert!(request.received_at() <= std::time::Instant::now());
+		assert!(request.decode_duration() < std::time::Duration::from_secs(1));
 		assert!(let Ok(()) = request.send_response(&()).await);
 		let_assert!(Err(fizyr_rpc::RecvMessageError::Other(e)) = server.recv_message().await);
 		assert!(e.is_connection_aborted());
@@ -28,6 +30,224 @@ async fn ping() {
 	assert!(let Ok(()) = server.await);
 }
 
+#[tokio::test]
+async fn retry_after() {
+	let_assert!(Ok((client, mut server)) = client_server_pair::<Json>());
+
+	let server = tokio::spawn(async move {
+		let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Ping(request, ()))) = server.recv_message().await);
+		assert!(let Ok(()) = request.send_retry_after(std::time::Duration::from_millis(250), "busy recording").await);
+
+		let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Record(request, _body))) = server.recv_message().await);
+		assert!(let Ok(()) = request.send_retry_after(std::time::Duration::from_secs(1), "").await);
+	});
+
+	let_assert!(Err(e) = client.ping().await);
+	assert!(e.is_retry_after());
+	assert!(e.as_retry_after() == Some(std::time::Duration::from_millis(250)));
+	assert!(e.as_remote_error() == Some("busy recording"));
+
+	let_assert!(Ok(mut sent_request) = client.record(&camera::RecordRequest { color: false, cloud: true }).await);
+	let_assert!(Err(e) = sent_request.recv_response().await);
+	assert!(e.as_retry_after() == Some(std::time::Duration::from_secs(1)));
+	assert!(let Ok(()) = server.await);
+}
+
+#[tokio::test]
+async fn service_error_without_error_type() {
+	let_assert!(Ok((client, mut server)) = client_server_pair::<Json>());
+
+	let server = tokio::spawn(async move {
+		let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Ping(request, ()))) = server.recv_message().await);
+		assert!(let Ok(()) = request.inner().send_response(fizyr_rpc::service_id::SERVICE_ERROR, &b"null"[..]).await);
+	});
+
+	// The body of the service error must not be decoded as the regular response.
+	let_assert!(Err(e) = client.ping().await);
+	assert!(e.as_remote_error() == Some("service error"));
+	let_assert!(Some(body) = e.service_error_body::<fizyr_rpc::StreamBody>());
+	assert!(body.as_ref() == b"null");
+	assert!(let Ok(()) = server.await);
+}
+
+#[tokio::test]
+async fn type_fingerprints() {
+	use camera::camera_legacy;
+
+	assert!(camera::ping::TYPE_FINGERPRINT == camera_legacy::ping::TYPE_FINGERPRINT);
+	assert!(camera::record::TYPE_FINGERPRINT != camera_legacy::record::TYPE_FINGERPRINT);
+
+	let_assert!(Ok((client, server)) = tokio::net::UnixStream::pair());
+	let mut client = camera_legacy::Client::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default())));
+	let mut server = camera::Server::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default())));
+	assert!(!client.type_fingerprints());
+	client.set_type_fingerprints(true);
+
+	let server = tokio::spawn(async move {
+		let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Ping(request, ()))) = server.recv_message().await);
+		assert!(let Ok(()) = request.send_response(&()).await);
+
+		// The mismatch is detected before decoding and answered automatically.
+		let_assert!(Err(e) = server.recv_message().await);
+		assert!(e.is_type_fingerprint_mismatch());
+		let_assert!(Some(request) = e.request_handle());
+		assert!(request.type_fingerprint() == Some(camera_legacy::record::TYPE_FINGERPRINT));
+	});
+
+	assert!(let Ok(()) = client.ping().await);
+	let_assert!(Err(e) = client.record().await);
+	assert!(e.is_type_fingerprint_mismatch());
+	assert!(e.is_remote_error());
+	assert!(let Ok(()) = server.await);
+}
+
+#[tokio::test]
+async fn decode_offloaded() {
+	let_assert!(Ok((client, mut server)) = client_server_pair::<Json>());
+	server.set_decode_offload_threshold(Some(0));
+
+	let server = tokio::spawn(async move {
+		let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Record(request, body))) = server.recv_message_offloaded().await);
+		assert!(body.color == false);
+		assert!(body.cloud == true);
+		assert!(let Ok(()) = request.send_response(&()).await);
+	});
+
+	let_assert!(Ok(mut sent_request) = client.record(&camera::RecordRequest { color: false, cloud: true }).await);
+	assert!(let Ok(()) = sent_request.recv_response().await);
+	assert!(let Ok(()) = server.await);
+}
+
+#[tokio::test]
+async fn decode_budget() {
+	let_assert!(Ok((client, mut server)) = client_server_pair::<Json>());
+	assert!(server.decode_budget() == None);
+	server.set_decode_budget(Some(1));
+	assert!(server.decode_budget() == Some(1));
+
+	let server = tokio::spawn(async move {
+		for _ in 0..2 {
+			let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Record(request, body))) = server.recv_message().await);
+			assert!(body.cloud == true);
+			assert!(let Ok(()) = request.send_response(&()).await);
+		}
+	});
+
+	for _ in 0..2 {
+		let_assert!(Ok(mut sent_request) = client.record(&camera::RecordRequest { color: false, cloud: true }).await);
+		assert!(let Ok(()) = sent_request.recv_response().await);
+	}
+	assert!(let Ok(()) = server.await);
+}
+
+#[tokio::test]
+async fn connection_context() {
+	#[derive(Debug, Eq, PartialEq)]
+	struct User(&'static str);
+
+	let_assert!(Ok((client, server)) = tokio::net::UnixStream::pair());
+	let client = camera::Client::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default())));
+	let (server, server_handle) = UnixStreamPeer::new(UnixStreamTransport::new(server, Default::default()));
+	tokio::spawn(server.with_context(std::sync::Arc::new(User("admin"))).run());
+	let mut server = camera::Server::<Json>::from(server_handle);
+	assert!(server.context::<User>().as_deref() == Some(&User("admin")));
+	assert!(server.context::<String>() == None);
+
+	let server = tokio::spawn(async move {
+		let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Ping(request, ()))) = server.recv_message().await);
+		assert!(request.context::<User>().as_deref() == Some(&User("admin")));
+		assert!(let Ok(()) = request.send_response(&()).await);
+	});
+
+	assert!(let Ok(()) = client.ping().await);
+	assert!(let Ok(()) = server.await);
+}
+
+#[tokio::test]
+async fn decode_context() {
+	let_assert!(Ok((mut client, mut server)) = client_server_pair::<Json>());
+	let mut context = fizyr_rpc::format::DecodeContext::default();
+	context.max_body_len = Some(10);
+	server.set_decode_context(context.clone());
+	context.max_body_len = Some(1);
+	client.set_decode_context(context);
+	assert!(server.decode_context().max_body_len == Some(10));
+	assert!(client.decode_context().max_body_len == Some(1));
+
+	let server = tokio::spawn(async move {
+		// The request body is larger than the limit of the server.
+		let_assert!(Err(fizyr_rpc::RecvMessageError::InvalidRequest(request, e)) = server.recv_message().await);
+		assert!(e.to_string().contains("payload too large"));
+		assert!(let Ok(()) = request.send_error_response("request too large").await);
+
+		// The response body is larger than the limit of the client.
+		let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Ping(request, ()))) = server.recv_message().await);
+		assert!(let Ok(()) = request.send_response(&()).await);
+	});
+
+	let_assert!(Ok(mut sent_request) = client.record(&camera::RecordRequest { color: false, cloud: true }).await);
+	let_assert!(Err(e) = sent_request.recv_response().await);
+	assert!(e.as_remote_error() == Some("request too large"));
+
+	let_assert!(Err(e) = client.ping().await);
+	assert!(e.to_string().contains("payload too large"));
+	assert!(let Ok(()) = server.await);
+}
+
+#[tokio::test]
+async fn deadline() {
+	let_assert!(Ok((mut client, mut server)) = client_server_pair::<Json>());
+	let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+
+	let server = tokio::spawn(async move {
+		// The request with an expired deadline is answered by the server without returning it.
+		let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Ping(request, ()))) = server.recv_message().await);
+		let_assert!(Some(received_deadline) = request.deadline());
+		assert!(received_deadline < deadline + std::time::Duration::from_secs(1));
+		assert!(let Ok(()) = request.send_response(&()).await);
+	});
+
+	client.set_deadline(Some(std::time::Instant::now() - std::time::Duration::from_millis(10)));
+	let_assert!(Err(e) = client.ping().await);
+	assert!(e.is_deadline_exceeded());
+
+	client.set_deadline(Some(deadline));
+	assert!(client.deadline() == Some(deadline));
+	assert!(let Ok(()) = client.ping().await);
+	assert!(let Ok(()) = server.await);
+}
+
+#[tokio::test]
+async fn bad_request_responses() {
+	let_assert!(Ok((client, server)) = tokio::net::UnixStream::pair());
+	let client = UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default()));
+	let mut server = camera::Server::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default())));
+	assert!(server.bad_request_responses() == false);
+	server.set_bad_request_responses(true);
+	assert!(server.bad_request_responses() == true);
+
+	let server = tokio::spawn(async move {
+		// Unknown and invalid requests are still reported to the server.
+		let_assert!(Err(e) = server.recv_message().await);
+		assert!(let fizyr_rpc::RecvMessageError::UnknownRequest(_, _) = &e);
+		let_assert!(Err(e) = server.recv_message().await);
+		assert!(let fizyr_rpc::RecvMessageError::InvalidRequest(_, _) = &e);
+		assert!(e.bad_request_message().unwrap().starts_with("bad request: invalid request body for service ID 1: "));
+	});
+
+	let_assert!(Ok(mut sent_request) = client.send_request(99, &b"hello"[..]).await);
+	let_assert!(Ok(response) = sent_request.recv_response().await);
+	let_assert!(Err(e) = response.check_error_response());
+	assert!(e.is_bad_request());
+	assert!(e.as_remote_error() == Some("bad request: unknown service ID 99"));
+
+	let_assert!(Ok(mut sent_request) = client.send_request(1, &b"not json"[..]).await);
+	let_assert!(Ok(response) = sent_request.recv_response().await);
+	let_assert!(Err(e) = response.check_error_response());
+	assert!(e.is_bad_request());
+	assert!(let Ok(()) = server.await);
+}
+
 #[tokio::test]
 async fn record() {
 	let_assert!(Ok((client, mut server)) = client_server_pair::<Json>());
@@ -51,6 +271,7 @@ async fn record() {
 	});
 
 	let_assert!(Ok(mut sent_request) = client.record(&camera::RecordRequest { color: true, cloud: false }).await);
+	sent_request.set_user_data(String::from("first recording"));
 
 	let_assert!(Some(Ok(update)) = sent_request.recv_update().await);
 	assert!(update.is_state() == true);
@@ -74,12 +295,65 @@ async fn record() {
 
 	assert!(let None = sent_request.recv_update().await);
 
+	assert!(sent_request.user_data::<String>().map(String::as_str) == Some("first recording"));
 	assert!(let Ok(()) = sent_request.recv_response().await);
 	drop(client);
 
 	assert!(let Ok(()) = server.await);
 }
 
+#[tokio::test]
+async fn request_builder() {
+	let_assert!(Ok((client, mut server)) = client_server_pair::<Json>());
+
+	let server = tokio::spawn(async move {
+		let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Record(request, body))) = server.recv_message().await);
+		assert!(body.color == true);
+		assert!(body.cloud == false);
+		assert!(let Ok(()) = request.send_response(&()).await);
+	});
+
+	let request = client.record_builder().cloud(true).into_request();
+	assert!(request.color == false);
+	assert!(request.cloud == true);
+
+	let_assert!(Ok(mut sent_request) = client.record_builder().color(true).cloud(false).send().await);
+	assert!(let None = sent_request.recv_update().await);
+	assert!(let Ok(()) = sent_request.recv_response().await);
+	assert!(let Ok(()) = server.await);
+}
+
+#[tokio::test]
+async fn forward() {
+	let_assert!(Ok((client, mut gateway_in)) = client_server_pair::<Json>());
+	let_assert!(Ok((gateway_out, mut server)) = client_server_pair::<Json>());
+
+	let gateway = tokio::spawn(async move {
+		let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Record(request, body))) = gateway_in.recv_message().await);
+		gateway_out.forward_record(request, &body).await
+	});
+
+	let server = tokio::spawn(async move {
+		let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Record(mut request, body))) = server.recv_message().await);
+		assert!(body.color == true);
+		assert!(body.cloud == true);
+		let_assert!(Some(Ok(update)) = request.recv_update().await);
+		assert!(let Ok(camera::CancelReason::BecauseISaidSo) = update.into_cancel());
+		assert!(let Ok(()) = request.send_state_update(&camera::RecordState::Recording).await);
+		assert!(let Ok(()) = request.send_response(&()).await);
+	});
+
+	let_assert!(Ok(mut sent_request) = client.record(&camera::RecordRequest { color: true, cloud: true }).await);
+	assert!(let Ok(()) = sent_request.send_cancel_update(&camera::CancelReason::BecauseISaidSo).await);
+	let_assert!(Some(Ok(update)) = sent_request.recv_update().await);
+	assert!(let Ok(camera::RecordState::Recording) = update.into_state());
+	assert!(let None = sent_request.recv_update().await);
+	assert!(let Ok(()) = sent_request.recv_response().await);
+
+	assert!(let Ok(Ok(())) = gateway.await);
+	assert!(let Ok(()) = server.await);
+}
+
 #[tokio::test]
 async fn record_state() {
 	use camera::camera_events;
@@ -118,6 +392,700 @@ async fn record_state() {
 	assert!(let Ok(()) = server.await);
 }
 
+#[tokio::test]
+async fn cfg_attributes() {
+	use camera::camera_optional;
+
+	let_assert!(Ok((client, server)) = tokio::net::UnixStream::pair());
+	let client = UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default()));
+	let server = UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default()));
+	let raw_client = client.split().1;
+	let client = camera_optional::Client::<Json>::from(raw_client.clone());
+	let mut server = camera_optional::Server::<Json>::from(server);
+
+	let server = tokio::spawn(async move {
+		let_assert!(Ok(camera_optional::ReceivedMessage::Request(camera_optional::ReceivedRequestHandle::Record(mut request, ()))) = server.recv_message().await);
+		let_assert!(Some(Ok(update)) = request.recv_update().await);
+		assert!(let camera_optional::record::RequestUpdate::Cancel(camera::CancelReason::BecauseISaidSo) = update);
+		assert!(let Ok(()) = request.send_state_update(&camera::RecordState::Done).await);
+		assert!(let Ok(()) = request.send_response(&()).await);
+
+		// Messages for disabled services and streams are unknown to the server.
+		let_assert!(Err(fizyr_rpc::RecvMessageError::UnknownRequest(_, _)) = server.recv_message().await);
+		let_assert!(Err(fizyr_rpc::RecvMessageError::UnknownStream(_)) = server.recv_message().await);
+
+		let_assert!(Ok(camera_optional::ReceivedMessage::Stream(msg)) = server.recv_message().await);
+		let_assert!(camera_optional::StreamMessage::RecordState(camera::RecordState::Recording) = msg);
+	});
+
+	let_assert!(Ok(mut sent_request) = client.record().await);
+	assert!(let Ok(()) = sent_request.send_cancel_update(&camera::CancelReason::BecauseISaidSo).await);
+	let_assert!(Some(Ok(update)) = sent_request.recv_update().await);
+	assert!(let Ok(camera::RecordState::Done) = update.into_state());
+	assert!(let Ok(()) = sent_request.recv_response().await);
+
+	assert!(let Ok(_) = raw_client.send_request(1, fizyr_rpc::StreamBody::from(b"{}".to_vec())).await);
+	assert!(let Ok(()) = raw_client.send_stream(1, fizyr_rpc::StreamBody::from(b"{}".to_vec())).await);
+	assert!(let Ok(()) = client.send_record_state(&camera::RecordState::Recording).await);
+	assert!(let Ok(()) = server.await);
+
+	// Disabled items are not part of the introspection data, but they are part of the version hash.
+	let interface = camera_optional::Interface::definition::<Json>();
+	let names: Vec<_> = interface.services.iter().map(|x| x.name.as_str()).collect();
+	assert!(names == ["ping", "record"]);
+	let names: Vec<_> = interface.services[1].request_updates.iter().map(|x| x.name.as_str()).collect();
+	assert!(names == ["cancel"]);
+	assert!(interface.services[1].response_updates.len() == 1);
+	let names: Vec<_> = interface.streams.iter().map(|x| x.name.as_str()).collect();
+	assert!(names == ["record_state"]);
+	assert!(camera_optional::Interface::version_hash() != camera_optional::disabled::Interface::version_hash());
+
+	let interface = camera_optional::disabled::Interface::definition::<Json>();
+	assert!(interface.services.is_empty());
+	assert!(interface.streams.is_empty());
+}
+
+#[tokio::test]
+async fn client_receives_streams() {
+	use camera::camera_events;
+
+	let_assert!(Ok((client, server)) = tokio::net::UnixStream::pair());
+	let client = UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default()));
+	let server = UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default()));
+
+	// Keep the read half of the client peer to receive stream messages from the server.
+	let (client, mut events) = camera_events::Client::<Json>::new_with_server(client);
+	let (mut server, server_client) = {
+		let (read, write) = server.split();
+		(camera_events::Server::<Json>::from(read), camera_events::Client::<Json>::from(write))
+	};
+
+	assert!(let Ok(()) = server_client.send_record_state(&camera::RecordState::Recording).await);
+	let_assert!(Ok(camera_events::ReceivedMessage::Stream(msg)) = events.recv_message().await);
+	let_assert!(camera_events::StreamMessage::RecordState(state) = msg);
+	assert!(state == camera::RecordState::Recording);
+
+	assert!(let Ok(()) = client.send_record_state(&camera::RecordState::Done).await);
+	let_assert!(Ok(camera_events::ReceivedMessage::Stream(msg)) = server.recv_message().await);
+	let_assert!(camera_events::StreamMessage::RecordState(state) = msg);
+	assert!(state == camera::RecordState::Done);
+}
+
+#[tokio::test]
+async fn broadcast_streams() {
+	use camera::camera_events;
+
+	let_assert!(Ok((client_a, server_a)) = tokio::net::UnixStream::pair());
+	let_assert!(Ok((client_b, server_b)) = tokio::net::UnixStream::pair());
+	let mut events_a = camera_events::Server::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(client_a, Default::default())));
+	let mut events_b = camera_events::Server::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(client_b, Default::default())));
+	let server_a = camera_events::Client::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(server_a, Default::default())));
+	let server_b = camera_events::Client::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(server_b, Default::default())));
+
+	let mut broadcaster = camera_events::Broadcaster::<Json>::new();
+	assert!(broadcaster.add(&server_a));
+	assert!(broadcaster.add(&server_b));
+	assert!(!broadcaster.add(&server_a));
+	assert!(broadcaster.len() == 2);
+
+	assert!(let Ok(2) = broadcaster.broadcast_record_state(&camera::RecordState::Recording).await);
+	for events in [&mut events_a, &mut events_b] {
+		let_assert!(Ok(camera_events::ReceivedMessage::Stream(msg)) = events.recv_message().await);
+		let_assert!(camera_events::StreamMessage::RecordState(state) = msg);
+		assert!(state == camera::RecordState::Recording);
+	}
+
+	// Peers that disconnected are evicted on the next broadcast.
+	drop(events_b);
+	server_b.close();
+	assert!(let Ok(1) = broadcaster.broadcast_record_state(&camera::RecordState::Done).await);
+	assert!(broadcaster.len() == 1);
+	let_assert!(Ok(camera_events::ReceivedMessage::Stream(msg)) = events_a.recv_message().await);
+	let_assert!(camera_events::StreamMessage::RecordState(state) = msg);
+	assert!(state == camera::RecordState::Done);
+}
+
+#[tokio::test]
+async fn unordered_streams() {
+	use camera::camera_frames;
+
+	let_assert!(Ok((client, server)) = tokio::net::UnixStream::pair());
+	let client = camera_frames::Client::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default())));
+	let mut server = camera_frames::Server::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default())));
+
+	// Without decode workers, all messages are delivered in order.
+	assert!(server.unordered_decode_workers() == 0);
+	for i in 0..3 {
+		assert!(let Ok(()) = client.send_frame_captured(&i).await);
+	}
+	for i in 0..3 {
+		let_assert!(Ok(camera_frames::ReceivedMessage::Stream(camera_frames::StreamMessage::FrameCaptured(frame))) = server.recv_message().await);
+		assert!(frame == i);
+	}
+
+	// With decode workers, unordered streams may be delivered in any order, but none are lost.
+	server.set_unordered_decode_workers(4);
+	for i in 0..20 {
+		assert!(let Ok(()) = client.send_frame_captured(&i).await);
+	}
+	assert!(let Ok(()) = client.send_record_state(&camera::RecordState::Done).await);
+
+	let mut frames = Vec::new();
+	let mut record_state = None;
+	while frames.len() < 20 || record_state.is_none() {
+		let_assert!(Ok(camera_frames::ReceivedMessage::Stream(message)) = server.recv_message_offloaded().await);
+		match message {
+			camera_frames::StreamMessage::FrameCaptured(frame) => frames.push(frame),
+			camera_frames::StreamMessage::RecordState(state) => record_state = Some(state),
+		}
+	}
+	frames.sort();
+	assert!(frames == (0..20).collect::<Vec<_>>());
+	assert!(record_state == Some(camera::RecordState::Done));
+
+	let interface = camera_frames::Interface::definition::<Json>();
+	assert!(interface.streams[0].ordered == true);
+	assert!(interface.streams[1].ordered == false);
+}
+
+#[tokio::test]
+async fn update_stream() {
+	use camera::camera_frames;
+	use fizyr_rpc::macros::Stream;
+
+	let_assert!(Ok((client, server)) = tokio::net::UnixStream::pair());
+	let client = camera_frames::Client::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default())));
+	let mut server = camera_frames::Server::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default())));
+
+	let server = tokio::spawn(async move {
+		let_assert!(Ok(camera_frames::ReceivedMessage::Request(camera_frames::ReceivedRequestHandle::Capture(request, 3))) = server.recv_message().await);
+		for i in 0..3 {
+			assert!(let Ok(()) = request.send_frame_update(&i).await);
+		}
+		assert!(let Ok(()) = request.send_response(&1).await);
+	});
+
+	let_assert!(Ok(mut sent_request) = client.capture(&3).await);
+	let mut frames = Vec::new();
+	let mut stream = sent_request.frame_stream();
+	while let Some(frame) = std::future::poll_fn(|context| std::pin::Pin::new(&mut stream).poll_next(context)).await {
+		let_assert!(Ok(frame) = frame);
+		frames.push(frame);
+	}
+	assert!(frames == [0, 1, 2]);
+
+	// The stream ends at the final response, which stays available.
+	let_assert!(Ok(1) = sent_request.recv_response().await);
+	assert!(let Ok(()) = server.await);
+}
+
+#[tokio::test]
+async fn heartbeat() {
+	use camera::camera_heartbeat;
+
+	assert!(camera::Interface::heartbeat_interval() == None);
+	assert!(camera_heartbeat::Interface::heartbeat_interval() == Some(std::time::Duration::from_millis(10)));
+
+	let_assert!(Ok((client, server)) = tokio::net::UnixStream::pair());
+	let client = camera_heartbeat::Client::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default())));
+	let mut server = camera_heartbeat::Server::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default())));
+	assert!(server.last_heartbeat() == None);
+
+	// Heartbeats are consumed by the server, so the first message returned is the request.
+	let start = std::time::Instant::now();
+	tokio::time::sleep(std::time::Duration::from_millis(35)).await;
+	let client = tokio::spawn(async move {
+		assert!(let Ok(()) = client.ping().await);
+		client
+	});
+	let_assert!(Ok(camera_heartbeat::ReceivedMessage::Request(camera_heartbeat::ReceivedRequestHandle::Ping(request, ()))) = server.recv_message().await);
+	let_assert!(Some(last_heartbeat) = server.last_heartbeat());
+	assert!(last_heartbeat >= start);
+	assert!(let Ok(()) = request.send_response(&()).await);
+	let_assert!(Ok(client) = client.await);
+
+	// Dropping the client stops the heartbeats and closes the connection.
+	drop(client);
+	let_assert!(Err(fizyr_rpc::RecvMessageError::Other(e)) = server.recv_message().await);
+	assert!(e.is_connection_aborted());
+	assert!(server.last_heartbeat() >= Some(last_heartbeat));
+}
+
+#[tokio::test]
+async fn error_decoder() {
+	#[derive(Debug, serde::Deserialize)]
+	struct Problem {
+		status: u16,
+		title: String,
+	}
+
+	impl std::fmt::Display for Problem {
+		fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+			write!(f, "{} ({})", self.title, self.status)
+		}
+	}
+
+	impl std::error::Error for Problem {}
+
+	let_assert!(Ok((mut client, mut server)) = client_server_pair::<Json>());
+	client.set_error_decoder(|message: &str| -> Option<Box<dyn std::error::Error + Send>> {
+		let problem: Problem = serde_json::from_str(message).ok()?;
+		Some(Box::new(problem))
+	});
+
+	let server = tokio::spawn(async move {
+		let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Ping(request, ()))) = server.recv_message().await);
+		assert!(let Ok(()) = request.send_error_response(r#"{"status": 503, "title": "camera busy"}"#).await);
+		let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Ping(request, ()))) = server.recv_message().await);
+		assert!(let Ok(()) = request.send_error_response("camera busy").await);
+	});
+
+	let_assert!(Err(e) = client.ping().await);
+	assert!(e.as_remote_error() == Some(r#"{"status": 503, "title": "camera busy"}"#));
+	let_assert!(Some(problem) = e.downcast_remote_error_details::<Problem>());
+	assert!(problem.status == 503);
+	assert!(problem.title == "camera busy");
+
+	// Messages that are not recognized by the decoder stay plain remote errors.
+	let_assert!(Err(e) = client.ping().await);
+	assert!(e.as_remote_error() == Some("camera busy"));
+	assert!(e.remote_error_details().is_none());
+	assert!(let Ok(()) = server.await);
+}
+
+#[tokio::test]
+async fn client_api_trait() {
+	use camera::camera_config::{self, CameraConfigClientApi};
+	use camera::{ConfigError, Resolution};
+	use std::future::Future;
+	use std::pin::Pin;
+	use std::sync::Mutex;
+
+	type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+	/// Application code that only depends on the client API.
+	async fn highest_frame_rate(client: &dyn CameraConfigClientApi) -> Result<u32, fizyr_rpc::ServiceError<ConfigError>> {
+		let frame_rates = client.set_frame_rate(&30).await?;
+		Ok(frame_rates.into_iter().max().unwrap_or(0))
+	}
+
+	/// Mock client that records the requested frame rates and returns canned responses.
+	#[derive(Default)]
+	struct MockClient {
+		frame_rates: Mutex<Vec<u32>>,
+	}
+
+	impl CameraConfigClientApi for MockClient {
+		fn get_resolution<'a>(&'a self) -> BoxFuture<'a, Result<Resolution, fizyr_rpc::Error>> {
+			Box::pin(async { Err(fizyr_rpc::Error::custom("not connected".into())) })
+		}
+
+		fn set_frame_rate<'a>(&'a self, request: &'a u32) -> BoxFuture<'a, Result<Vec<u32>, fizyr_rpc::ServiceError<ConfigError>>> {
+			Box::pin(async move {
+				self.frame_rates.lock().unwrap().push(*request);
+				if *request > 60 {
+					Err(fizyr_rpc::ServiceError::Service(ConfigError::OutOfRange { min: 1, max: 60 }))
+				} else {
+					Ok(vec![15, 30, 60])
+				}
+			})
+		}
+
+		fn send_resolution_changed<'a>(&'a self, _body: &'a Resolution) -> BoxFuture<'a, Result<(), fizyr_rpc::Error>> {
+			Box::pin(async { Ok(()) })
+		}
+	}
+
+	// The mock can be used without a connection.
+	let mock = MockClient::default();
+	assert!(let Ok(60) = highest_frame_rate(&mock).await);
+	let_assert!(Err(fizyr_rpc::ServiceError::Service(ConfigError::OutOfRange { min: 1, max: 60 })) = mock.set_frame_rate(&100).await);
+	assert!(*mock.frame_rates.lock().unwrap() == [30, 100]);
+
+	// The real client implements the trait too.
+	let_assert!(Ok((client, server)) = tokio::net::UnixStream::pair());
+	let client = camera_config::Client::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default())));
+	let mut server = camera_config::Server::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default())));
+	let server = tokio::spawn(async move {
+		let_assert!(Ok(camera_config::ReceivedMessage::Request(camera_config::ReceivedRequestHandle::SetFrameRate(request, 30))) = server.recv_message().await);
+		assert!(let Ok(()) = request.send_response(&vec![10, 20]).await);
+	});
+	assert!(let Ok(20) = highest_frame_rate(&client).await);
+	assert!(let Ok(()) = server.await);
+}
+
+#[tokio::test]
+async fn server_runner() {
+	use camera::camera_config;
+	use camera::{ConfigError, Resolution};
+	use std::future::Future;
+	use std::pin::Pin;
+	use std::sync::Mutex;
+
+	type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+	/// Handler that keeps the current resolution as state.
+	struct Handler {
+		resolution: Mutex<(u32, u32)>,
+		notifications: tokio::sync::mpsc::UnboundedSender<Resolution>,
+	}
+
+	impl camera_config::ServerHandler<Json> for Handler {
+		fn get_resolution<'a>(&'a self, _request: &'a mut camera_config::get_resolution::ReceivedRequestHandle<Json>, _body: ()) -> BoxFuture<'a, Result<Resolution, fizyr_rpc::Error>> {
+			Box::pin(async move {
+				let (width, height) = *self.resolution.lock().unwrap();
+				Ok(Resolution { width, height })
+			})
+		}
+
+		fn set_resolution<'a>(&'a self, request: &'a mut camera_config::set_resolution::ReceivedRequestHandle<Json>, body: Resolution) -> BoxFuture<'a, Result<(), fizyr_rpc::ServiceError<ConfigError>>> {
+			Box::pin(async move {
+				if body.width == 0 {
+					panic!("zero width");
+				} else if body.width > 4096 {
+					return Err(fizyr_rpc::ServiceError::Service(ConfigError::Unsupported));
+				}
+				*self.resolution.lock().unwrap() = (body.width, body.height);
+				request.send_applied_update().await?;
+				Ok(())
+			})
+		}
+
+		fn set_frame_rate<'a>(&'a self, _request: &'a mut camera_config::set_frame_rate::ReceivedRequestHandle<Json>, _body: u32) -> BoxFuture<'a, Result<Vec<u32>, fizyr_rpc::ServiceError<ConfigError>>> {
+			Box::pin(async { Err(fizyr_rpc::ServiceError::Rpc(fizyr_rpc::Error::custom("camera disconnected".into()))) })
+		}
+
+		fn on_resolution_changed<'a>(&'a self, body: Resolution) -> BoxFuture<'a, ()> {
+			Box::pin(async move {
+				let _ = self.notifications.send(body);
+			})
+		}
+	}
+
+	let (notifications, mut notifications_rx) = tokio::sync::mpsc::unbounded_channel();
+	let handler = Handler {
+		resolution: Mutex::new((640, 480)),
+		notifications,
+	};
+
+	let_assert!(Ok((client, server)) = tokio::net::UnixStream::pair());
+	let client = camera_config::Client::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default())));
+	let server = camera_config::Server::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default())));
+	let runner = camera_config::ServerRunner::new(server, handler);
+	assert!(runner.server().bad_request_responses());
+	let runner = tokio::spawn(runner.run());
+
+	let_assert!(Ok(Resolution { width: 640, height: 480 }) = client.get_resolution().await);
+
+	// The handler can send updates before the runner sends the response.
+	let_assert!(Ok(mut sent_request) = client.set_resolution(&Resolution { width: 1920, height: 1080 }).await);
+	let_assert!(Some(Ok(update)) = sent_request.recv_update().await);
+	assert!(update.is_applied());
+	assert!(let Ok(()) = sent_request.recv_response().await);
+	let_assert!(Ok(Resolution { width: 1920, height: 1080 }) = client.get_resolution().await);
+
+	// Errors returned by the handler are sent as error response.
+	let_assert!(Ok(mut sent_request) = client.set_resolution(&Resolution { width: 8192, height: 1080 }).await);
+	let_assert!(Err(fizyr_rpc::ServiceError::Service(ConfigError::Unsupported)) = sent_request.recv_response().await);
+	let_assert!(Err(fizyr_rpc::ServiceError::Rpc(e)) = client.set_frame_rate(&30).await);
+	assert!(e.as_remote_error() == Some("camera disconnected"));
+
+	// A panic in the handler is reported to the remote peer too.
+	let_assert!(Ok(mut sent_request) = client.set_resolution(&Resolution { width: 0, height: 0 }).await);
+	let_assert!(Err(fizyr_rpc::ServiceError::Rpc(e)) = sent_request.recv_response().await);
+	assert!(e.as_remote_error() == Some("internal error: request handler panicked"));
+
+	// Stream messages are passed to the handler as well.
+	assert!(let Ok(()) = client.send_resolution_changed(&Resolution { width: 1, height: 2 }).await);
+	let_assert!(Some(Resolution { width: 1, height: 2 }) = notifications_rx.recv().await);
+
+	// The runner stops when the connection is closed.
+	client.close();
+	assert!(let Ok(()) = runner.await);
+}
+
+#[tokio::test]
+async fn negotiate_version() {
+	use camera::camera_events;
+
+	assert!(camera::Interface::version_hash() != camera_events::Interface::version_hash());
+
+	let_assert!(Ok((client, mut server)) = client_server_pair::<Json>());
+	let server = tokio::spawn(async move {
+		// The negotiation request is answered by the server without being returned.
+		let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Ping(request, ()))) = server.recv_message().await);
+		assert!(let Ok(()) = request.send_response(&()).await);
+	});
+	assert!(let Ok(()) = client.negotiate_version().await);
+	assert!(let Ok(()) = client.ping().await);
+	assert!(let Ok(()) = server.await);
+
+	// Negotiating with a server for a different interface fails with a descriptive error.
+	let_assert!(Ok((client, server)) = tokio::net::UnixStream::pair());
+	let client = camera::Client::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default())));
+	let mut server = camera_events::Server::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default())));
+	let server = tokio::spawn(async move {
+		let_assert!(Err(fizyr_rpc::RecvMessageError::Other(e)) = server.recv_message().await);
+		assert!(e.is_connection_aborted());
+	});
+	let_assert!(Err(e) = client.negotiate_version().await);
+	let_assert!(Some(message) = e.as_remote_error());
+	assert!(message.starts_with("interface version mismatch: server implements CameraEvents "));
+	assert!(message.contains("client uses Camera "));
+	drop(client);
+	assert!(let Ok(()) = server.await);
+}
+
+#[tokio::test]
+async fn negotiate_version_with_payload() {
+	let_assert!(Ok((client, mut server)) = client_server_pair::<Json>());
+	server.set_handshake_payload(Some(&String::from("camera server 1.0")));
+	let server = tokio::spawn(async move {
+		let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Ping(request, ()))) = server.recv_message().await);
+		let_assert!(Ok(Some(token)) = server.remote_handshake_payload::<String>());
+		assert!(token == "secret token");
+		let_assert!(Some(negotiation) = server.negotiation());
+		let transcript_hash = *negotiation.transcript_hash();
+		assert!(let Ok(()) = request.send_response(&()).await);
+		transcript_hash
+	});
+	let_assert!(Ok(negotiation) = client.negotiate_version_with_payload(&String::from("secret token")).await);
+	let_assert!(Ok(Some(remote)) = negotiation.remote_payload::<String>());
+	assert!(remote == "camera server 1.0");
+	assert!(let Ok(()) = client.ping().await);
+	let_assert!(Ok(transcript_hash) = server.await);
+	assert!(negotiation.transcript_hash() == &transcript_hash);
+}
+
+#[tokio::test]
+async fn service_ids_from_constants() {
+	use camera::camera_config;
+
+	fn client_server_pair<F: fizyr_rpc::format::Format<Body = fizyr_rpc::StreamBody>>() -> std::io::Result<(camera_config::Client<F>, camera_config::Server<F>)> {
+		let (client, server) = tokio::net::UnixStream::pair()?;
+		let client = UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default()));
+		let server = UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default()));
+		Ok((client.into(), server.into()))
+	}
+
+	let_assert!(Ok((client, mut server)) = client_server_pair::<Json>());
+
+	let server = tokio::spawn(async move {
+		let_assert!(Ok(camera_config::ReceivedMessage::Request(camera_config::ReceivedRequestHandle::GetResolution(request, ()))) = server.recv_message().await);
+		assert!(request.service_id() == camera::ids::GET_RESOLUTION);
+		assert!(let Ok(()) = request.send_response(&camera::Resolution { width: 640, height: 480 }).await);
+
+		let_assert!(Ok(camera_config::ReceivedMessage::Request(camera_config::ReceivedRequestHandle::SetResolution(request, resolution))) = server.recv_message().await);
+		assert!(resolution == camera::Resolution { width: 1280, height: 720 });
+		assert!(let Ok(()) = request.send_applied_update().await);
+		assert!(let Ok(()) = request.send_response(&()).await);
+
+		let_assert!(Ok(camera_config::ReceivedMessage::Stream(message)) = server.recv_message().await);
+		let_assert!(camera_config::StreamMessage::ResolutionChanged(resolution) = message);
+		assert!(resolution == camera::Resolution { width: 1280, height: 720 });
+	});
+
+	let_assert!(Ok(resolution) = client.get_resolution().await);
+	assert!(resolution == camera::Resolution { width: 640, height: 480 });
+
+	let_assert!(Ok(mut sent_request) = client.set_resolution(&camera::Resolution { width: 1280, height: 720 }).await);
+	let_assert!(Some(Ok(update)) = sent_request.recv_update().await);
+	assert!(update.is_applied());
+	assert!(let Ok(()) = sent_request.recv_response().await);
+
+	assert!(let Ok(()) = client.send_resolution_changed(&camera::Resolution { width: 1280, height: 720 }).await);
+	assert!(let Ok(()) = server.await);
+
+	let interface = camera_config::Interface::definition::<Json>();
+	assert!(interface.services[0].service_id == 20);
+	assert!(interface.services[0].error_body == None);
+	assert!(interface.services[1].service_id == 21);
+	assert!(interface.services[1].error_body == Some("macros_tests::camera::ConfigError"));
+	assert!(interface.services[1].response_updates[0].service_id == 30);
+	assert!(interface.streams[0].service_id == 22);
+}
+
+#[tokio::test]
+async fn service_id_module() {
+	use camera::camera_config;
+
+	assert!(camera::service_ids::PING == 0);
+	assert!(camera::service_ids::RECORD == 1);
+	assert!(camera::service_ids::HIDDEN_SERVICE == 2);
+	assert!(camera::service_ids::record::request_updates::CANCEL == 10);
+	assert!(camera::service_ids::record::request_updates::DISCONNECT == 101);
+	assert!(camera::service_ids::record::response_updates::STATE == 11);
+	assert!(camera::service_ids::record::response_updates::IMAGE == 12);
+	assert!(camera::service_ids::streams::HIDDEN_STREAM == 3);
+	assert!(camera_config::service_ids::GET_RESOLUTION == camera::ids::GET_RESOLUTION);
+	assert!(camera_config::service_ids::set_resolution::response_updates::APPLIED == camera::ids::RESOLUTION_APPLIED);
+	assert!(camera_config::service_ids::streams::RESOLUTION_CHANGED == camera::ids::RESOLUTION_CHANGED);
+
+	// The constants can be used to talk to a service with a raw peer handle.
+	let_assert!(Ok((client, server)) = tokio::net::UnixStream::pair());
+	let client = UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default()));
+	let mut server: camera::Server<Json> = UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default())).into();
+
+	let server = tokio::spawn(async move {
+		let_assert!(Ok(camera::ReceivedMessage::Request(camera::ReceivedRequestHandle::Ping(request, ()))) = server.recv_message().await);
+		assert!(let Ok(()) = request.send_response(&()).await);
+	});
+
+	let_assert!(Ok(mut sent_request) = client.send_request(camera::service_ids::PING, &b"null"[..]).await);
+	let_assert!(Ok(response) = sent_request.recv_response().await);
+	assert!(response.header.service_id == 0);
+	assert!(let Ok(()) = server.await);
+}
+
+#[tokio::test]
+async fn forward_compatible() {
+	use camera::camera_exposure::{v1, v2};
+
+	// An old server receives a stream message with an unknown value from a new client.
+	let_assert!(Ok((client, server)) = tokio::net::UnixStream::pair());
+	let client = v2::Client::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default())));
+	let mut server = v1::Server::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default())));
+
+	assert!(let Ok(()) = client.send_mode_changed(&v2::ExposureMode::Manual).await);
+	assert!(let Ok(()) = client.send_mode_changed(&v2::ExposureMode::Hdr).await);
+	let_assert!(Ok(v1::ReceivedMessage::Stream(message)) = server.recv_message().await);
+	let_assert!(v1::StreamMessage::ModeChanged(v1::ExposureMode::Manual) = message);
+	let_assert!(Ok(v1::ReceivedMessage::Stream(message)) = server.recv_message().await);
+	assert!(message.is_unrecognized());
+	let_assert!(v1::StreamMessage::Unrecognized { service_id, raw_body } = message);
+	assert!(service_id == 2);
+	assert!(raw_body == b"\"Hdr\"");
+
+	// An old client receives an update with an unknown value from a new server.
+	let_assert!(Ok((client, server)) = tokio::net::UnixStream::pair());
+	let client = v1::Client::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default())));
+	let mut server = v2::Server::<Json>::from(UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default())));
+
+	let server = tokio::spawn(async move {
+		let_assert!(Ok(v2::ReceivedMessage::Request(v2::ReceivedRequestHandle::AutoExpose(request, ()))) = server.recv_message().await);
+		assert!(let Ok(()) = request.send_mode_update(&v2::ExposureMode::Hdr).await);
+		assert!(let Ok(()) = request.send_response(&()).await);
+	});
+
+	let_assert!(Ok(mut sent_request) = client.auto_expose().await);
+	let_assert!(Some(Ok(update)) = sent_request.recv_update().await);
+	let_assert!(v1::auto_expose::ResponseUpdate::Unrecognized { service_id, raw_body } = update);
+	assert!(service_id == 10);
+	assert!(raw_body == b"\"Hdr\"");
+	assert!(let Ok(()) = sent_request.recv_response().await);
+	assert!(let Ok(()) = server.await);
+
+	// Unrecognized messages can not be sent.
+	let update = v1::auto_expose::ResponseUpdate::Unrecognized { service_id: 10, raw_body: Vec::new() };
+	assert!(let Err(_) = fizyr_rpc::format::ToMessage::<Json>::to_message(&update));
+}
+
+#[tokio::test]
+async fn service_error() {
+	use camera::camera_config;
+	use camera::ConfigError;
+
+	let_assert!(Ok((client, server)) = tokio::net::UnixStream::pair());
+	let client: camera_config::Client<Json> = UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default())).into();
+	let mut server: camera_config::Server<Json> = UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default())).into();
+
+	let server = tokio::spawn(async move {
+		let_assert!(Ok(camera_config::ReceivedMessage::Request(camera_config::ReceivedRequestHandle::SetFrameRate(request, 30))) = server.recv_message().await);
+		assert!(let Ok(()) = request.send_response(&vec![15, 30, 60]).await);
+
+		let_assert!(Ok(camera_config::ReceivedMessage::Request(camera_config::ReceivedRequestHandle::SetFrameRate(request, 1000))) = server.recv_message().await);
+		assert!(let Ok(()) = request.send_error_response(&ConfigError::OutOfRange { min: 1, max: 60 }).await);
+
+		let_assert!(Ok(camera_config::ReceivedMessage::Request(camera_config::ReceivedRequestHandle::SetFrameRate(request, 0))) = server.recv_message().await);
+		assert!(let Ok(()) = request.send_error_message("camera disconnected").await);
+
+		let_assert!(Ok(camera_config::ReceivedMessage::Request(camera_config::ReceivedRequestHandle::SetResolution(request, _resolution))) = server.recv_message().await);
+		assert!(let Ok(()) = request.send_error_response(&ConfigError::Unsupported).await);
+	});
+
+	let_assert!(Ok(frame_rates) = client.set_frame_rate(&30).await);
+	assert!(frame_rates == [15, 30, 60]);
+
+	let_assert!(Err(e) = client.set_frame_rate(&1000).await);
+	assert!(e.as_service_error() == Some(&ConfigError::OutOfRange { min: 1, max: 60 }));
+
+	let_assert!(Err(fizyr_rpc::ServiceError::Rpc(e)) = client.set_frame_rate(&0).await);
+	assert!(e.as_remote_error() == Some("camera disconnected"));
+
+	let_assert!(Ok(mut sent_request) = client.set_resolution(&camera::Resolution { width: 1, height: 1 }).await);
+	let_assert!(Err(fizyr_rpc::ServiceError::Service(ConfigError::Unsupported)) = sent_request.recv_response().await);
+	assert!(let Ok(()) = server.await);
+}
+
+#[tokio::test]
+async fn request_handlers() {
+	use std::future::Future;
+	use std::pin::Pin;
+
+	type HandlerFuture = Pin<Box<dyn Future<Output = Result<(), fizyr_rpc::Error>> + Send>>;
+
+	let_assert!(Ok((client, mut server)) = client_server_pair::<Json>());
+
+	let server = tokio::spawn(async move {
+		let mut handlers = camera::RequestHandlers::<Json, HandlerFuture>::new(|request| {
+			let service_name = request.service_name();
+			Box::pin(async move {
+				let_assert!(camera::ReceivedRequestHandle::Record(request, _body) = request);
+				request.send_error_response(&format!("{service_name} is not supported")).await
+			})
+		})
+		.on_ping(|request, ()| Box::pin(async move { request.send_response(&()).await }));
+
+		let_assert!(Ok(camera::ReceivedMessage::Request(request)) = server.recv_message().await);
+		assert!(request.service_name() == "ping");
+		assert!(request.service_id() == 0);
+		assert!(let Ok(()) = handlers.handle(request).await);
+
+		let_assert!(Ok(camera::ReceivedMessage::Request(request)) = server.recv_message().await);
+		assert!(request.service_name() == "record");
+		assert!(request.service_id() == 1);
+		assert!(let Ok(()) = handlers.handle(request).await);
+	});
+
+	assert!(let Ok(()) = client.ping().await);
+	let_assert!(Ok(mut sent_request) = client.record(&camera::RecordRequest { color: false, cloud: true }).await);
+	let_assert!(Err(e) = sent_request.recv_response().await);
+	assert!(e.as_remote_error() == Some("record is not supported"));
+	assert!(let Ok(()) = server.await);
+}
+
+/// A request handler that must handle all services of the camera interface.
+struct NameHandler;
+
+impl<F: Format> camera::RequestHandler<F> for NameHandler {
+	type Output = &'static str;
+
+	fn ping(&mut self, _request: camera::ping::ReceivedRequestHandle<F>, _body: ()) -> Self::Output {
+		"ping"
+	}
+
+	fn record(&mut self, _request: camera::record::ReceivedRequestHandle<F>, _body: camera::RecordRequest) -> Self::Output {
+		"record"
+	}
+
+	fn hidden_service(&mut self, _request: camera::hidden_service::ReceivedRequestHandle<F>, _body: ()) -> Self::Output {
+		"hidden_service"
+	}
+}
+
+#[tokio::test]
+async fn request_handler_trait() {
+	let_assert!(Ok((client, mut server)) = client_server_pair::<Json>());
+
+	let server = tokio::spawn(async move {
+		let_assert!(Ok(camera::ReceivedMessage::Request(request)) = server.recv_message().await);
+		assert!(request.handle_with(&mut NameHandler) == "record");
+	});
+
+	let_assert!(Ok(_sent_request) = client.record(&camera::RecordRequest { color: true, cloud: false }).await);
+	assert!(let Ok(()) = server.await);
+}
+
 #[allow(dead_code, clippy::all)]
 fn assert_client_clone<F: Format>(camera: camera::Client<F>) {
 	let _ = camera.clone();
@@ -263,6 +1231,20 @@ fn interface_introspection_camera_events() {
 	assert!(interface.streams[0].name == "record_state");
 	assert!(interface.streams[0].doc == "Notifications whenever the camera changes record state.\n");
 	assert!(interface.streams[0].hidden == false);
+	assert!(interface.streams[0].ordered == true);
 	assert!(interface.streams[0].service_id == 11);
 	assert!(interface.streams[0].body == "macros_tests::camera::RecordState");
 }
+
+#[test]
+fn service_registry() {
+	use camera::{camera_config, camera_events, ids};
+
+	assert!(ids::SET_FRAME_RATE == 23);
+	assert!(ids::name_of(22) == Some("RESOLUTION_CHANGED"));
+
+	let camera = camera::Interface::definition::<Json>();
+	let camera_config = camera_config::Interface::definition::<Json>();
+	let camera_events = camera_events::Interface::definition::<Json>();
+	assert!(let Ok(()) = fizyr_rpc::registry::check_interfaces(&[&camera, &camera_config, &camera_events]));
+}
@@ -0,0 +1,166 @@
+use proc_macro2::{TokenStream, Span};
+use quote::quote;
+
+use crate::interface::parse::cooked::InterfaceDefinition;
+
+use super::is_unit_type;
+
+/// Generate a typed broadcaster struct.
+///
+/// Nothing is generated if the interface has no streams.
+pub fn generate_broadcaster(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, interface: &InterfaceDefinition) {
+	if interface.streams().is_empty() {
+		return;
+	}
+
+	let mut broadcast_fns = TokenStream::new();
+	for stream in interface.streams() {
+		let service_id = stream.service_id();
+		let fn_name = syn::Ident::new(&format!("broadcast_{}", stream.name()), Span::call_site());
+		let fn_doc = format!("Send a `{}` stream message to all registered peers.", stream.name());
+		let body_arg;
+		let body_val;
+		let body_type = stream.body_type();
+		let cfg = stream.cfg();
+		if is_unit_type(body_type) {
+			body_arg = None;
+			body_val = quote!(&());
+		} else {
+			body_arg = Some(quote!(body: &#body_type));
+			body_val = quote!(body);
+		}
+		broadcast_fns.extend(quote! {
+			#[doc = #fn_doc]
+			///
+			/// The message is encoded only once and the encoded body is cloned for each peer.
+			///
+			/// Returns the number of peers that the message was written to,
+			/// or an error if the message could not be encoded.
+			/// Peers with a closed connection are removed from the broadcaster.
+			#cfg
+			#[allow(clippy::ptr_arg)]
+			pub async fn #fn_name(&mut self, #body_arg) -> ::core::result::Result<usize, #fizyr_rpc::Error>
+			where
+				F: #fizyr_rpc::format::EncodeBody<#body_type>,
+				F::Body: ::core::clone::Clone,
+			{
+				self.inner.send_encoded::<F, #body_type>(#service_id, #body_val).await
+			}
+		});
+	}
+
+	let broadcaster_doc = format!("Broadcaster to send stream messages of the {} interface to multiple peers at once.", interface.name());
+	let visibility = interface.visibility();
+	item_tokens.extend(quote! {
+		#[doc = #broadcaster_doc]
+		///
+		/// This is a typed wrapper around the untyped `Broadcaster` from `fizyr_rpc`.
+		/// It has a `broadcast_*` function for each stream message of the interface.
+		#visibility struct Broadcaster<F: #fizyr_rpc::format::Format> {
+			inner: #fizyr_rpc::Broadcaster<F::Body>,
+		}
+
+		impl<F: #fizyr_rpc::format::Format> ::core::fmt::Debug for Broadcaster<F> {
+			fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+				f.debug_struct(::core::any::type_name::<Self>())
+					.field("inner", &self.inner)
+					.finish()
+			}
+		}
+
+		impl<F: #fizyr_rpc::format::Format> ::core::default::Default for Broadcaster<F> {
+			fn default() -> Self {
+				Self::new()
+			}
+		}
+
+		impl<F: #fizyr_rpc::format::Format> ::core::convert::From<#fizyr_rpc::Broadcaster<F::Body>> for Broadcaster<F> {
+			fn from(other: #fizyr_rpc::Broadcaster<F::Body>) -> Self {
+				Self { inner: other }
+			}
+		}
+
+		impl<F: #fizyr_rpc::format::Format> ::core::iter::Extend<Client<F>> for Broadcaster<F> {
+			fn extend<I: ::core::iter::IntoIterator<Item = Client<F>>>(&mut self, iter: I) {
+				self.inner.extend(iter.into_iter().map(|client| client.peer));
+			}
+		}
+
+		impl<F: #fizyr_rpc::format::Format> ::core::iter::FromIterator<Client<F>> for Broadcaster<F> {
+			fn from_iter<I: ::core::iter::IntoIterator<Item = Client<F>>>(iter: I) -> Self {
+				let mut broadcaster = Self::new();
+				broadcaster.extend(iter);
+				broadcaster
+			}
+		}
+
+		impl<F: #fizyr_rpc::format::Format> Broadcaster<F> {
+			/// Create a new broadcaster without any registered peers.
+			pub fn new() -> Self {
+				Self {
+					inner: #fizyr_rpc::Broadcaster::new(),
+				}
+			}
+
+			/// Register the peer of a client with the broadcaster.
+			///
+			/// If the peer is already registered, `false` is returned.
+			pub fn add(&mut self, client: &Client<F>) -> bool {
+				self.inner.add(client.peer.clone())
+			}
+
+			/// Register a peer with the broadcaster using a raw write handle.
+			///
+			/// If the peer is already registered, the handle is dropped and `false` is returned.
+			pub fn add_peer(&mut self, peer: #fizyr_rpc::PeerWriteHandle<F::Body>) -> bool {
+				self.inner.add(peer)
+			}
+
+			/// Remove the peer of a client from the broadcaster.
+			///
+			/// Returns `true` if the peer was registered with the broadcaster.
+			pub fn remove(&mut self, client: &Client<F>) -> bool {
+				self.inner.remove(&client.peer)
+			}
+
+			/// Check if the peer of a client is registered with the broadcaster.
+			pub fn contains(&self, client: &Client<F>) -> bool {
+				self.inner.contains(&client.peer)
+			}
+
+			/// Get the number of registered peers.
+			///
+			/// This may include peers that disconnected since the last broadcast.
+			pub fn len(&self) -> usize {
+				self.inner.len()
+			}
+
+			/// Check if the broadcaster has no registered peers.
+			pub fn is_empty(&self) -> bool {
+				self.inner.is_empty()
+			}
+
+			/// Remove all registered peers.
+			pub fn clear(&mut self) {
+				self.inner.clear()
+			}
+
+			/// Get a reference to the untyped broadcaster.
+			pub fn inner(&self) -> &#fizyr_rpc::Broadcaster<F::Body> {
+				&self.inner
+			}
+
+			/// Get a mutable reference to the untyped broadcaster.
+			pub fn inner_mut(&mut self) -> &mut #fizyr_rpc::Broadcaster<F::Body> {
+				&mut self.inner
+			}
+
+			/// Consume the typed broadcaster to get the untyped broadcaster.
+			pub fn into_inner(self) -> #fizyr_rpc::Broadcaster<F::Body> {
+				self.inner
+			}
+
+			#broadcast_fns
+		}
+	})
+}
@@ -9,16 +9,57 @@ use crate::interface::parse::cooked::InterfaceDefinition;
 pub fn generate_client(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, interface: &InterfaceDefinition, extra_impl: TokenStream) {
 	let client_doc = format!("RPC client for the {} interface.", interface.name());
 	let visibility = interface.visibility();
+
+	// Tokens for sending heartbeat messages, if enabled.
+	let mut heartbeat_field = TokenStream::new();
+	let mut heartbeat_debug_field = TokenStream::new();
+	let mut heartbeat_clone = TokenStream::new();
+	let mut heartbeat_init = TokenStream::new();
+	let mut new_doc = TokenStream::new();
+	if let Some(interval) = interface.heartbeat_interval() {
+		let interval_ms = interval.as_millis() as u64;
+		heartbeat_field = quote! {
+			heartbeat: ::std::sync::Arc<#fizyr_rpc::heartbeat::HeartbeatSender>,
+		};
+		heartbeat_debug_field = quote! {
+			.field("heartbeat", &self.heartbeat)
+		};
+		heartbeat_clone = quote! {
+			heartbeat: self.heartbeat.clone(),
+		};
+		heartbeat_init = quote! {
+			heartbeat: ::std::sync::Arc::new(#fizyr_rpc::heartbeat::HeartbeatSender::spawn(peer.clone(), ::core::time::Duration::from_millis(#interval_ms))),
+		};
+		new_doc = quote! {
+			///
+			/// The client sends heartbeat messages to the remote peer until the client and all its clones are dropped.
+			/// See the `heartbeat` module of `fizyr_rpc` for more details.
+			///
+			/// # Panics
+			/// This function panics if it is not called from within a tokio runtime.
+		};
+	}
+
 	item_tokens.extend(quote! {
 		#[doc = #client_doc]
 		#visibility struct Client<F: #fizyr_rpc::format::Format> {
 			peer: #fizyr_rpc::PeerWriteHandle<F::Body>,
+			decode_context: #fizyr_rpc::format::DecodeContext,
+			deadline: ::core::option::Option<::std::time::Instant>,
+			type_fingerprints: bool,
+			error_decoder: ::core::option::Option<::std::sync::Arc<dyn #fizyr_rpc::ErrorDecoder>>,
+			#heartbeat_field
 		}
 
 		impl<F: #fizyr_rpc::format::Format> ::core::fmt::Debug for Client<F> {
 			fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
 				f.debug_struct(::core::any::type_name::<Self>())
 					.field("peer", &self.peer)
+					.field("decode_context", &self.decode_context)
+					.field("deadline", &self.deadline)
+					.field("type_fingerprints", &self.type_fingerprints)
+					.field("error_decoder", &self.error_decoder.is_some())
+					#heartbeat_debug_field
 					.finish()
 			}
 		}
@@ -27,6 +68,11 @@ pub fn generate_client(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, in
 			fn clone(&self) -> Self {
 				Self {
 					peer: self.peer.clone(),
+					decode_context: self.decode_context.clone(),
+					deadline: self.deadline,
+					type_fingerprints: self.type_fingerprints,
+					error_decoder: self.error_decoder.clone(),
+					#heartbeat_clone
 				}
 			}
 		}
@@ -37,6 +83,10 @@ pub fn generate_client(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, in
 			}
 		}
 
+		/// Create a client from a peer handle, discarding the read half.
+		///
+		/// Incoming requests from the remote peer are rejected and incoming stream messages are dropped.
+		/// Use [`Client::new_with_server()`] to keep the read half.
 		impl<F: #fizyr_rpc::format::Format> ::core::convert::From<#fizyr_rpc::PeerHandle<F::Body>> for Client<F> {
 			fn from(other: #fizyr_rpc::PeerHandle<F::Body>) -> Self {
 				let (_read, write) = other.split();
@@ -46,8 +96,125 @@ pub fn generate_client(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, in
 
 		impl<F: #fizyr_rpc::format::Format> Client<F> {
 			/// Create a new interface-specific RPC client from a raw write handle.
+			#new_doc
 			pub fn new(peer: #fizyr_rpc::PeerWriteHandle<F::Body>) -> Self {
-				Self { peer }
+				Self {
+					#heartbeat_init
+					peer,
+					decode_context: ::core::default::Default::default(),
+					deadline: ::core::option::Option::None,
+					type_fingerprints: false,
+					error_decoder: ::core::option::Option::None,
+				}
+			}
+
+			/// Create an interface-specific RPC client and server from a peer handle.
+			///
+			/// The client uses the write half of the peer to send requests and stream messages.
+			/// The server uses the read half to receive stream messages and requests from the remote peer.
+			pub fn new_with_server(peer: #fizyr_rpc::PeerHandle<F::Body>) -> (Self, Server<F>) {
+				let (read, write) = peer.split();
+				(Self::new(write), Server::from(read))
+			}
+
+			/// Set the limits for decoding incoming messages.
+			///
+			/// The decode context is passed to the format for all responses and response updates received by the client.
+			/// Requests that were sent before the decode context was changed keep using the old limits.
+			pub fn set_decode_context(&mut self, context: #fizyr_rpc::format::DecodeContext) {
+				self.decode_context = context;
+			}
+
+			/// Get the limits for decoding incoming messages.
+			pub fn decode_context(&self) -> &#fizyr_rpc::format::DecodeContext {
+				&self.decode_context
+			}
+
+			/// Set the deadline to send along with all requests.
+			///
+			/// The deadline tells the remote peer how long you are willing to wait for the response.
+			/// Generated servers automatically reject requests if the deadline passed before they are handled.
+			///
+			/// To propagate the deadline of a received request, set the deadline of the received request on a clone of the client.
+			pub fn set_deadline(&mut self, deadline: ::core::option::Option<::std::time::Instant>) {
+				self.deadline = deadline;
+			}
+
+			/// Get the deadline that is sent along with all requests.
+			pub fn deadline(&self) -> ::core::option::Option<::std::time::Instant> {
+				self.deadline
+			}
+
+			/// Enable or disable sending type fingerprints along with all requests.
+			///
+			/// The type fingerprint of a service is a hash of its body types, see the `TYPE_FINGERPRINT` constant in the module of each service.
+			/// Generated servers check the fingerprint before decoding the request body,
+			/// and answer requests with a mismatching fingerprint with an error response.
+			/// The error can be recognized with `Error::is_type_fingerprint_mismatch()`.
+			///
+			/// This detects peers that were built with a different version of a service right away,
+			/// instead of through confusing decode errors or garbled messages.
+			///
+			/// This is disabled by default.
+			pub fn set_type_fingerprints(&mut self, enabled: bool) {
+				self.type_fingerprints = enabled;
+			}
+
+			/// Check if type fingerprints are sent along with all requests.
+			pub fn type_fingerprints(&self) -> bool {
+				self.type_fingerprints
+			}
+
+			/// Set the decoder for the message of plain error responses.
+			///
+			/// The decoder is applied to all error responses received by the client,
+			/// so structured error objects from the remote peer can be retrieved as typed errors
+			/// with `Error::downcast_remote_error_details()`.
+			/// Requests that were sent before the decoder was changed keep using the old decoder.
+			///
+			/// By default, error responses are reported as plain remote errors.
+			pub fn set_error_decoder<D: #fizyr_rpc::ErrorDecoder>(&mut self, decoder: D) {
+				self.error_decoder = ::core::option::Option::Some(::std::sync::Arc::new(decoder));
+			}
+
+			/// Remove the decoder for the message of plain error responses.
+			///
+			/// Afterwards, error responses are reported as plain remote errors again.
+			pub fn clear_error_decoder(&mut self) {
+				self.error_decoder = ::core::option::Option::None;
+			}
+
+			/// Get the decoder for the message of plain error responses, if one is set.
+			pub fn error_decoder(&self) -> ::core::option::Option<&::std::sync::Arc<dyn #fizyr_rpc::ErrorDecoder>> {
+				self.error_decoder.as_ref()
+			}
+
+			/// Negotiate the interface version with the remote peer.
+			///
+			/// The remote peer must be a server for the same version of the interface,
+			/// as determined by [`Interface::version_hash()`].
+			/// If the versions do not match, this function returns an error describing the mismatch.
+			///
+			/// Call this function right after connecting to detect a mismatch early,
+			/// instead of failing on individual requests later.
+			pub async fn negotiate_version(&self) -> ::core::result::Result<(), #fizyr_rpc::Error> {
+				#fizyr_rpc::negotiation::negotiate_version(&self.peer, Interface::name(), Interface::version_hash()).await
+			}
+
+			/// Negotiate the interface version with the remote peer and exchange handshake payloads.
+			///
+			/// The `payload` is sent to the remote peer together with the version,
+			/// and can be retrieved by the server with `Server::remote_handshake_payload()`.
+			/// On success, the negotiation is returned.
+			/// It holds the payload of the remote peer, if it sent one, and the transcript hash of the negotiation.
+			///
+			/// See [`Self::negotiate_version()`] for more details.
+			pub async fn negotiate_version_with_payload<P>(&self, payload: &P) -> ::core::result::Result<#fizyr_rpc::negotiation::Negotiation, #fizyr_rpc::Error>
+			where
+				P: #fizyr_rpc::negotiation::HandshakePayload,
+			{
+				let payload = payload.encode_payload();
+				#fizyr_rpc::negotiation::negotiate_version_with_payload(&self.peer, Interface::name(), Interface::version_hash(), ::core::option::Option::Some(&payload)).await
 			}
 
 			/// Close the connection with the remote peer.
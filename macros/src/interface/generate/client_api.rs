@@ -0,0 +1,131 @@
+use proc_macro2::{TokenStream, Span};
+use quote::quote;
+
+use crate::interface::parse::cooked::InterfaceDefinition;
+
+use super::{cfg_format_bound, is_unit_type, to_doc_attrs, to_upper_camel_case};
+
+/// Generate a trait with the client API of the interface, and implement it for the client struct.
+///
+/// The trait has a function for each service without update messages and for each stream.
+/// Services with update messages return request handles that are tied to a peer, so they are left out.
+pub fn generate_client_api(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, interface: &InterfaceDefinition) {
+	let mut trait_items = TokenStream::new();
+	let mut impl_items = TokenStream::new();
+	let mut format_bounds = TokenStream::new();
+
+	for service in interface.services() {
+		if !service.request_updates().is_empty() || !service.response_updates().is_empty() {
+			continue;
+		}
+
+		let service_name = service.name();
+		let service_doc = to_doc_attrs(service.doc());
+		let cfg = service.cfg();
+		let request_type = service.request_type();
+		let response_type = service.response_type();
+
+		let error_type;
+		let mut bounds = quote! {
+			#fizyr_rpc::format::EncodeBody<#request_type> + #fizyr_rpc::format::DecodeBody<#response_type>
+		};
+		if let Some(service_error_type) = service.error_type() {
+			error_type = quote!(#fizyr_rpc::ServiceError<#service_error_type>);
+			bounds.extend(quote!(+ #fizyr_rpc::format::DecodeBody<#service_error_type>));
+		} else {
+			error_type = quote!(#fizyr_rpc::Error);
+		}
+		let trait_name = format!("__ClientApiService{}", to_upper_camel_case(&service_name.to_string()));
+		let bound = cfg_format_bound(item_tokens, cfg, &trait_name, bounds);
+		format_bounds.extend(quote!(F: #bound,));
+
+		let request_param;
+		let request_arg;
+		if is_unit_type(request_type) {
+			request_param = None;
+			request_arg = None;
+		} else {
+			request_param = Some(quote!(request: &'a #request_type));
+			request_arg = Some(quote!(request));
+		}
+		let output = quote! {
+			::core::pin::Pin<::std::boxed::Box<dyn ::core::future::Future<Output = ::core::result::Result<#response_type, #error_type>> + ::core::marker::Send + 'a>>
+		};
+
+		trait_items.extend(quote! {
+			#service_doc
+			#cfg
+			#[allow(clippy::ptr_arg)]
+			fn #service_name<'a>(&'a self, #request_param) -> #output;
+		});
+		impl_items.extend(quote! {
+			#cfg
+			#[allow(clippy::ptr_arg)]
+			fn #service_name<'a>(&'a self, #request_param) -> #output {
+				::std::boxed::Box::pin(Client::#service_name(self, #request_arg))
+			}
+		});
+	}
+
+	for stream in interface.streams() {
+		let fn_name = syn::Ident::new(&format!("send_{}", stream.name()), Span::call_site());
+		let fn_doc = format!("Send a `{}` stream message to the remote peer.", stream.name());
+		let cfg = stream.cfg();
+		let body_type = stream.body_type();
+
+		let trait_name = format!("__ClientApiStream{}", to_upper_camel_case(&stream.name().to_string()));
+		let bound = cfg_format_bound(item_tokens, cfg, &trait_name, quote!(#fizyr_rpc::format::EncodeBody<#body_type>));
+		format_bounds.extend(quote!(F: #bound,));
+
+		let body_param;
+		let body_arg;
+		if is_unit_type(body_type) {
+			body_param = None;
+			body_arg = None;
+		} else {
+			body_param = Some(quote!(body: &'a #body_type));
+			body_arg = Some(quote!(body));
+		}
+		let output = quote! {
+			::core::pin::Pin<::std::boxed::Box<dyn ::core::future::Future<Output = ::core::result::Result<(), #fizyr_rpc::Error>> + ::core::marker::Send + 'a>>
+		};
+
+		trait_items.extend(quote! {
+			#[doc = #fn_doc]
+			#cfg
+			#[allow(clippy::ptr_arg)]
+			fn #fn_name<'a>(&'a self, #body_param) -> #output;
+		});
+		impl_items.extend(quote! {
+			#cfg
+			#[allow(clippy::ptr_arg)]
+			fn #fn_name<'a>(&'a self, #body_param) -> #output {
+				::std::boxed::Box::pin(Client::#fn_name(self, #body_arg))
+			}
+		});
+	}
+
+	let trait_name = syn::Ident::new(&format!("{}ClientApi", interface.name()), Span::call_site());
+	let trait_doc = format!("The client API of the {} interface as a trait.", interface.name());
+	let visibility = interface.visibility();
+	item_tokens.extend(quote! {
+		#[doc = #trait_doc]
+		///
+		/// The trait has a function for each service without update messages, and for each stream message.
+		/// Services with update messages are not part of the trait, because their request handles are tied to a peer.
+		///
+		/// The trait is implemented for [`Client`].
+		/// Application code can depend on the trait instead of the client,
+		/// so that tests can replace the client with a mock implementation that does not need a connection to a remote peer.
+		#visibility trait #trait_name {
+			#trait_items
+		}
+
+		impl<F: #fizyr_rpc::format::Format> #trait_name for Client<F>
+		where
+			#format_bounds
+		{
+			#impl_items
+		}
+	});
+}
@@ -3,29 +3,50 @@ use quote::quote;
 
 use crate::interface::parse::cooked::InterfaceDefinition;
 
+use super::{cfg_format_bound, to_upper_camel_case};
+
 /// Generate a format trait specifically for the given RPC interface.
 pub fn generate_format_trait(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, interface: &InterfaceDefinition) {
-	let mut types = Vec::new();
+	let mut bounds = quote!(#fizyr_rpc::format::Format);
 
 	for service in interface.services() {
-		types.push(service.request_type());
-		types.push(service.response_type());
-		for update in service.request_updates() {
-			types.push(update.body_type());
+		let mut types = vec![service.request_type(), service.response_type()];
+		if let Some(error_type) = service.error_type() {
+			types.push(error_type);
 		}
-		for update in service.response_updates() {
-			types.push(update.body_type());
+		// Updates with their own `#[cfg]` conditions get a separate bound below.
+		for update in service.request_updates().iter().chain(service.response_updates()) {
+			if update.cfg().is_empty() {
+				types.push(update.body_type());
+			}
 		}
-	}
+		let item_bounds = encode_decode_bounds(fizyr_rpc, &types);
+		let trait_name = format!("__FormatService{}", to_upper_camel_case(&service.name().to_string()));
+		let item_bounds = cfg_format_bound(item_tokens, service.cfg(), &trait_name, item_bounds);
+		bounds.extend(quote!( + #item_bounds));
 
-	for stream in interface.streams() {
-		types.push(stream.body_type())
+		let updates = service.request_updates().iter().map(|x| ("RequestUpdate", x))
+			.chain(service.response_updates().iter().map(|x| ("ResponseUpdate", x)));
+		for (kind, update) in updates {
+			if !update.cfg().is_empty() {
+				let trait_name = format!(
+					"__FormatService{}{}{}",
+					to_upper_camel_case(&service.name().to_string()),
+					kind,
+					to_upper_camel_case(&update.name().to_string()),
+				);
+				let update_bounds = encode_decode_bounds(fizyr_rpc, &[update.body_type()]);
+				let update_bounds = cfg_format_bound(item_tokens, &service.cfg().and(update.cfg()), &trait_name, update_bounds);
+				bounds.extend(quote!( + #update_bounds));
+			}
+		}
 	}
 
-	let mut bounds = quote!(#fizyr_rpc::format::Format);
-	for typ in &types {
-		bounds.extend(quote!( + #fizyr_rpc::format::EncodeBody<#typ>));
-		bounds.extend(quote!( + #fizyr_rpc::format::DecodeBody<#typ>));
+	for stream in interface.streams() {
+		let trait_name = format!("__FormatStream{}", to_upper_camel_case(&stream.name().to_string()));
+		let item_bounds = encode_decode_bounds(fizyr_rpc, &[stream.body_type()]);
+		let item_bounds = cfg_format_bound(item_tokens, stream.cfg(), &trait_name, item_bounds);
+		bounds.extend(quote!( + #item_bounds));
 	}
 
 	let visibility = interface.visibility();
@@ -42,3 +63,15 @@ pub fn generate_format_trait(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ide
 		{}
 	})
 }
+
+/// Generate the bounds to encode and decode all the given types.
+fn encode_decode_bounds(fizyr_rpc: &syn::Ident, types: &[&syn::Type]) -> TokenStream {
+	let mut bounds = TokenStream::new();
+	for typ in types {
+		if !bounds.is_empty() {
+			bounds.extend(quote!(+));
+		}
+		bounds.extend(quote!(#fizyr_rpc::format::EncodeBody<#typ> + #fizyr_rpc::format::DecodeBody<#typ>));
+	}
+	bounds
+}
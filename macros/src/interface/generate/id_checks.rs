@@ -0,0 +1,45 @@
+use proc_macro2::TokenStream;
+use quote::quote_spanned;
+
+use crate::interface::parse::cooked::{CfgConditions, InterfaceDefinition, ServiceId};
+use crate::util::WithSpan;
+
+/// Generate compile time checks for duplicate service IDs.
+///
+/// Duplicate integer literals are already reported by the macro itself.
+/// Service IDs given as path to a constant can only be compared after constant evaluation,
+/// so we generate a `const` assertion for each pair that involves a path.
+/// The assertion is only enabled if both items are enabled.
+pub fn generate_id_checks(item_tokens: &mut TokenStream, interface: &InterfaceDefinition) {
+	let services: Vec<_> = interface.services().iter().map(|x| (x.name(), x.service_id(), x.cfg())).collect();
+	generate_pairwise_checks(item_tokens, "services", &services);
+
+	let streams: Vec<_> = interface.streams().iter().map(|x| (x.name(), x.service_id(), x.cfg())).collect();
+	generate_pairwise_checks(item_tokens, "streams", &streams);
+
+	for service in interface.services() {
+		let updates: Vec<_> = service.request_updates().iter().map(|x| (x.name(), x.service_id(), x.cfg())).collect();
+		generate_pairwise_checks(item_tokens, &format!("request updates of service `{}`", service.name()), &updates);
+
+		let updates: Vec<_> = service.response_updates().iter().map(|x| (x.name(), x.service_id(), x.cfg())).collect();
+		generate_pairwise_checks(item_tokens, &format!("response updates of service `{}`", service.name()), &updates);
+	}
+}
+
+/// Generate a `const` assertion for each pair of items where at least one service ID is a path.
+fn generate_pairwise_checks(item_tokens: &mut TokenStream, kind: &str, items: &[(&syn::Ident, &WithSpan<ServiceId>, &CfgConditions)]) {
+	for (i, (a_name, a_id, a_cfg)) in items.iter().enumerate() {
+		for (b_name, b_id, b_cfg) in items.iter().skip(i + 1) {
+			if !a_id.value.is_path() && !b_id.value.is_path() {
+				continue;
+			}
+			let message = format!("duplicate service ID for {kind}: `{a_name}` and `{b_name}` have the same service ID");
+			let span = b_id.span;
+			let cfg = a_cfg.and(b_cfg);
+			item_tokens.extend(quote_spanned! { span =>
+				#cfg
+				const _: () = ::core::assert!(#a_id != #b_id, #message);
+			});
+		}
+	}
+}
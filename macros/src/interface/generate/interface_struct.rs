@@ -1,8 +1,10 @@
 use proc_macro2::TokenStream;
-use quote::quote;
+use quote::{quote, ToTokens};
 
 use crate::{interface::parse::cooked::{InterfaceDefinition, ServiceDefinition, UpdateDefinition, StreamDefinition}, util::WithSpan};
 
+use super::{cfg_format_bound, to_upper_camel_case};
+
 /// Generate a struct representing the interface.
 pub fn generate_interface_struct(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, interface: &InterfaceDefinition) {
 	let name = interface.name().to_string();
@@ -14,8 +16,16 @@ pub fn generate_interface_struct(item_tokens: &mut TokenStream, fizyr_rpc: &syn:
 
 	let mut services_format_bounds = TokenStream::new();
 	let mut streams_format_bounds = TokenStream::new();
-	let service_definitions = service_definitions(&mut services_format_bounds, fizyr_rpc, interface.services());
-	let stream_definitions = stream_definitions(&mut streams_format_bounds, fizyr_rpc, interface.streams());
+	let service_definitions = service_definitions(item_tokens, &mut services_format_bounds, fizyr_rpc, interface.services());
+	let stream_definitions = stream_definitions(item_tokens, &mut streams_format_bounds, fizyr_rpc, interface.streams());
+	let version_signature = version_signature(interface);
+	let heartbeat_interval = match interface.heartbeat_interval() {
+		Some(interval) => {
+			let interval_ms = interval.as_millis() as u64;
+			quote!(::core::option::Option::Some(::core::time::Duration::from_millis(#interval_ms)))
+		},
+		None => quote!(::core::option::Option::None),
+	};
 
 	item_tokens.extend(quote! {
 		#[doc = #interface_doc]
@@ -37,6 +47,21 @@ pub fn generate_interface_struct(item_tokens: &mut TokenStream, fizyr_rpc: &syn:
 				#doc
 			}
 
+			/// Get the version hash of the interface.
+			///
+			/// The hash is computed from the names, service IDs and body types in the interface definition.
+			/// It is used by the generated client and server to negotiate the interface version.
+			pub const fn version_hash() -> u64 {
+				#fizyr_rpc::negotiation::interface_hash(#version_signature)
+			}
+
+			/// Get the interval at which the client sends heartbeat messages.
+			///
+			/// Returns `None` if the interface does not have the `#[heartbeat]` attribute.
+			pub const fn heartbeat_interval() -> ::core::option::Option<::core::time::Duration> {
+				#heartbeat_interval
+			}
+
 			/// Get the full interface definition.
 			///
 			/// The type information for message bodies depends on serialization format used.
@@ -85,24 +110,41 @@ pub fn generate_interface_struct(item_tokens: &mut TokenStream, fizyr_rpc: &syn:
 /// This function returns tokens that represent a vector of service definitions.
 ///
 /// It also pushes required trait bounds to `format_bounds`.
-fn service_definitions(format_bounds: &mut TokenStream, fizyr_rpc: &syn::Ident, services: &[ServiceDefinition]) -> TokenStream {
+/// Helper traits for the bounds of items with `#[cfg]` attributes are added to `item_tokens`.
+fn service_definitions(item_tokens: &mut TokenStream, format_bounds: &mut TokenStream, fizyr_rpc: &syn::Ident, services: &[ServiceDefinition]) -> TokenStream {
 	let mut push_items = TokenStream::new();
 	for service in services {
 		let name = service.name().to_string();
 		let doc = to_doc_string(service.doc());
 		let hidden = service.hidden().is_some();
-		let service_id = service.service_id().value;
+		let cfg = service.cfg();
+		let service_id = &service.service_id().value;
 		let request_type = service.request_type();
 		let response_type = service.response_type();
-		let request_updates = update_definitions(format_bounds, fizyr_rpc, service.request_updates());
-		let response_updates = update_definitions(format_bounds, fizyr_rpc, service.response_updates());
 
+		let mut service_bounds = vec![
+			quote!(#fizyr_rpc::introspection::FormatTypeInfo<#request_type>),
+			quote!(#fizyr_rpc::introspection::FormatTypeInfo<#response_type>),
+		];
+		let request_updates = update_definitions(item_tokens, format_bounds, &mut service_bounds, fizyr_rpc, service, "RequestUpdate", service.request_updates());
+		let response_updates = update_definitions(item_tokens, format_bounds, &mut service_bounds, fizyr_rpc, service, "ResponseUpdate", service.response_updates());
+
+		let error_body = match service.error_type() {
+			None => quote!(::core::option::Option::None),
+			Some(error_type) => {
+				service_bounds.push(quote!(#fizyr_rpc::introspection::FormatTypeInfo<#error_type>));
+				quote!(::core::option::Option::Some(<F as #fizyr_rpc::introspection::FormatTypeInfo<#error_type>>::type_info()))
+			},
+		};
+
+		let trait_name = format!("__IntrospectService{}", to_upper_camel_case(&service.name().to_string()));
+		let service_bounds = cfg_format_bound(item_tokens, cfg, &trait_name, quote!(#(#service_bounds)+*));
 		format_bounds.extend(quote! {
-			F: #fizyr_rpc::introspection::FormatTypeInfo<#request_type>,
-			F: #fizyr_rpc::introspection::FormatTypeInfo<#response_type>,
+			F: #service_bounds,
 		});
 
 		push_items.extend(quote! {
+			#cfg
 			vector.push(#fizyr_rpc::introspection::ServiceDefinition {
 				name: #name.to_string(),
 				doc: #doc.to_string(),
@@ -110,6 +152,7 @@ fn service_definitions(format_bounds: &mut TokenStream, fizyr_rpc: &syn::Ident,
 				service_id: #service_id,
 				request_body: <F as #fizyr_rpc::introspection::FormatTypeInfo<#request_type>>::type_info(),
 				response_body: <F as #fizyr_rpc::introspection::FormatTypeInfo<#response_type>>::type_info(),
+				error_body: #error_body,
 				request_updates: #request_updates,
 				response_updates: #response_updates,
 			});
@@ -128,21 +171,46 @@ fn service_definitions(format_bounds: &mut TokenStream, fizyr_rpc: &syn::Ident,
 ///
 /// This function returns tokens that represent a vector of update definitions.
 ///
-/// It also pushes required trait bounds to `format_bounds`.
-fn update_definitions(format_bounds: &mut TokenStream, fizyr_rpc: &syn::Ident, updates: &[UpdateDefinition]) -> TokenStream {
+/// The required trait bounds of updates without `#[cfg]` attributes are pushed to `service_bounds`.
+/// The trait bounds of other updates are pushed to `format_bounds`,
+/// and their helper traits are added to `item_tokens`.
+#[allow(clippy::too_many_arguments)]
+fn update_definitions(
+	item_tokens: &mut TokenStream,
+	format_bounds: &mut TokenStream,
+	service_bounds: &mut Vec<TokenStream>,
+	fizyr_rpc: &syn::Ident,
+	service: &ServiceDefinition,
+	kind: &str,
+	updates: &[UpdateDefinition],
+) -> TokenStream {
 	let mut push_items = TokenStream::new();
 	for update in updates {
 		let name = update.name().to_string();
 		let doc = to_doc_string(update.doc());
 		let hidden = update.hidden().is_some();
-		let service_id = update.service_id().value;
+		let cfg = update.cfg();
+		let service_id = &update.service_id().value;
 		let body_type = update.body_type();
 
-		format_bounds.extend(quote! {
-			F: #fizyr_rpc::introspection::FormatTypeInfo<#body_type>,
-		});
+		let bound = quote!(#fizyr_rpc::introspection::FormatTypeInfo<#body_type>);
+		if cfg.is_empty() {
+			service_bounds.push(bound);
+		} else {
+			let trait_name = format!(
+				"__IntrospectService{}{}{}",
+				to_upper_camel_case(&service.name().to_string()),
+				kind,
+				to_upper_camel_case(&update.name().to_string()),
+			);
+			let bound = cfg_format_bound(item_tokens, &service.cfg().and(cfg), &trait_name, bound);
+			format_bounds.extend(quote! {
+				F: #bound,
+			});
+		}
 
 		push_items.extend(quote! {
+			#cfg
 			vector.push(#fizyr_rpc::introspection::UpdateDefinition {
 				name: #name.to_string(),
 				doc: #doc.to_string(),
@@ -166,24 +234,31 @@ fn update_definitions(format_bounds: &mut TokenStream, fizyr_rpc: &syn::Ident, u
 /// This function returns tokens that represent a vector of stream definitions.
 ///
 /// It also pushes required trait bounds to `format_bounds`.
-fn stream_definitions(format_bounds: &mut TokenStream, fizyr_rpc: &syn::Ident, streams: &[StreamDefinition]) -> TokenStream {
+/// Helper traits for the bounds of streams with `#[cfg]` attributes are added to `item_tokens`.
+fn stream_definitions(item_tokens: &mut TokenStream, format_bounds: &mut TokenStream, fizyr_rpc: &syn::Ident, streams: &[StreamDefinition]) -> TokenStream {
 	let mut push_items = TokenStream::new();
 	for stream in streams {
 		let name = stream.name().to_string();
 		let doc = to_doc_string(stream.doc());
 		let hidden = stream.hidden().is_some();
-		let service_id = stream.service_id().value;
+		let ordered = !stream.unordered();
+		let cfg = stream.cfg();
+		let service_id = &stream.service_id().value;
 		let body_type = stream.body_type();
 
+		let trait_name = format!("__IntrospectStream{}", to_upper_camel_case(&stream.name().to_string()));
+		let bound = cfg_format_bound(item_tokens, cfg, &trait_name, quote!(#fizyr_rpc::introspection::FormatTypeInfo<#body_type>));
 		format_bounds.extend(quote! {
-			F: #fizyr_rpc::introspection::FormatTypeInfo<#body_type>,
+			F: #bound,
 		});
 
 		push_items.extend(quote! {
+			#cfg
 			vector.push(#fizyr_rpc::introspection::StreamDefinition {
 				name: #name.to_string(),
 				doc: #doc.to_string(),
 				hidden: #hidden,
+				ordered: #ordered,
 				service_id: #service_id,
 				body: <F as #fizyr_rpc::introspection::FormatTypeInfo<#body_type>>::type_info(),
 			});
@@ -198,6 +273,52 @@ fn stream_definitions(format_bounds: &mut TokenStream, fizyr_rpc: &syn::Ident, s
 	})
 }
 
+/// Generate the signature of an interface that is used to compute the version hash.
+///
+/// The signature contains everything that affects the wire format, but not the documentation.
+/// Items with `#[cfg]` attributes are always included,
+/// so peers that enable a different subset of the interface still agree on the version.
+fn version_signature(interface: &InterfaceDefinition) -> String {
+	let mut signature = format!("interface {}\n", interface.name());
+	for service in interface.services() {
+		signature += &service_signature(service);
+	}
+	for stream in interface.streams() {
+		let service_id = stream.service_id().value.to_token_stream();
+		let body_type = stream.body_type().to_token_stream();
+		signature += &format!("stream {} {}: {}\n", service_id, stream.name(), body_type);
+	}
+	signature
+}
+
+/// Generate the signature of a single service.
+///
+/// The signature is part of the interface signature,
+/// and it is used on its own to compute the type fingerprint of the service.
+pub fn service_signature(service: &ServiceDefinition) -> String {
+	fn update_signatures(signature: &mut String, kind: &str, updates: &[UpdateDefinition]) {
+		for update in updates {
+			let service_id = update.service_id().value.to_token_stream();
+			let body_type = update.body_type().to_token_stream();
+			*signature += &format!("{} {} {}: {}\n", kind, service_id, update.name(), body_type);
+		}
+	}
+
+	let service_id = service.service_id().value.to_token_stream();
+	let request_type = service.request_type().to_token_stream();
+	let response_type = service.response_type().to_token_stream();
+	let mut signature = match service.error_type() {
+		None => format!("service {} {}: {} -> {}\n", service_id, service.name(), request_type, response_type),
+		Some(error_type) => {
+			let error_type = error_type.to_token_stream();
+			format!("service {} {}: {} -> {} ! {}\n", service_id, service.name(), request_type, response_type, error_type)
+		},
+	};
+	update_signatures(&mut signature, "request_update", service.request_updates());
+	update_signatures(&mut signature, "response_update", service.response_updates());
+	signature
+}
+
 /// Collect the doc string lines into one string.
 ///
 /// Common leading whitespace is stripped from each line.
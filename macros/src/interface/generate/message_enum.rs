@@ -3,10 +3,21 @@ use quote::quote;
 
 use crate::interface::parse::cooked::MessageDefinition;
 
-use super::{to_upper_camel_case, to_doc_attrs};
+use super::{cfg_catch_all_arm, cfg_format_bound, service_id_pattern, to_upper_camel_case, to_doc_attrs};
 
 /// Generate an enum with all possible body types for a message.
-pub fn generate_message_enum(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, messages: &[impl MessageDefinition], enum_name: &syn::Ident, enum_doc: &str) {
+///
+/// Messages with `#[cfg]` attributes are only included in the enum if their conditions hold.
+///
+/// If `forward_compatible` is true, the enum gets an `Unrecognized` variant for bodies that the format could only partially decode.
+pub fn generate_message_enum(
+	item_tokens: &mut TokenStream,
+	fizyr_rpc: &syn::Ident,
+	messages: &[impl MessageDefinition],
+	enum_name: &syn::Ident,
+	enum_doc: &str,
+	forward_compatible: bool,
+) {
 	let mut variants = TokenStream::new();
 	let mut from_message = TokenStream::new();
 	let mut to_message = TokenStream::new();
@@ -19,31 +30,56 @@ pub fn generate_message_enum(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ide
 		let variant_name = syn::Ident::new(&variant_name, message.name().span());
 		let variant_doc = to_doc_attrs(message.doc());
 		let body_type = message.body_type();
+		let cfg = message.cfg();
 
 		let service_id = message.service_id();
 		variants.extend(quote! {
 			#variant_doc
+			#cfg
 			#variant_name(#body_type),
 		});
 
-		from_message.extend(quote! {
-			#service_id => ::core::result::Result::Ok(Self::#variant_name(F::decode_body(message.body).map_err(#fizyr_rpc::Error::decode_failed)?)),
-		});
+		let service_id_pattern = service_id_pattern(service_id);
+		if forward_compatible {
+			from_message.extend(quote! {
+				#cfg
+				#service_id_pattern => {
+					match #fizyr_rpc::format::decode_body_partial_instrumented::<F, _>(message.header.service_id, message.body, &::core::default::Default::default()).map_err(#fizyr_rpc::Error::decode_failed)? {
+						#fizyr_rpc::format::PartialDecode::Decoded(body) => ::core::result::Result::Ok(Self::#variant_name(body)),
+						#fizyr_rpc::format::PartialDecode::Unrecognized(raw_body) => ::core::result::Result::Ok(Self::Unrecognized {
+							service_id: message.header.service_id,
+							raw_body,
+						}),
+					}
+				},
+			});
+		} else {
+			from_message.extend(quote! {
+				#cfg
+				#service_id_pattern => ::core::result::Result::Ok(Self::#variant_name(#fizyr_rpc::format::decode_body_instrumented::<F, _>(message.header.service_id, message.body, &::core::default::Default::default()).map_err(#fizyr_rpc::Error::decode_failed)?)),
+			});
+		}
 
+		let trait_name = format!("__{}{}Decode", enum_name, variant_name);
+		let decode_bound = cfg_format_bound(item_tokens, cfg, &trait_name, quote!(#fizyr_rpc::format::DecodeBody<#body_type>));
 		decode_all.extend(quote! {
-			F: #fizyr_rpc::format::DecodeBody<#body_type>,
+			F: #decode_bound,
 		});
 
 		to_message.extend(quote! {
-			Self::#variant_name(message) => ::core::result::Result::Ok((#service_id, F::encode_body(message)?)),
+			#cfg
+			Self::#variant_name(message) => ::core::result::Result::Ok((#service_id, #fizyr_rpc::format::encode_body_instrumented::<F, _>(#service_id, message)?)),
 		});
 
 		service_id_arms.extend(quote! {
+			#cfg
 			Self::#variant_name(_) => #service_id,
 		});
 
+		let trait_name = format!("__{}{}Encode", enum_name, variant_name);
+		let encode_bound = cfg_format_bound(item_tokens, cfg, &trait_name, quote!(#fizyr_rpc::format::EncodeBody<#body_type>));
 		encode_all.extend(quote! {
-			F: #fizyr_rpc::format::EncodeBody<#body_type>,
+			F: #encode_bound,
 		});
 
 		let is_fn_name = syn::Ident::new(&format!("is_{}", message.name()), Span::call_site());
@@ -57,6 +93,7 @@ pub fn generate_message_enum(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ide
 
 		impl_tokens.extend(quote! {
 			#[doc = #is_fn_doc]
+			#cfg
 			pub fn #is_fn_name(&self) -> bool {
 				if let Self::#variant_name(_) = self {
 					true
@@ -66,6 +103,7 @@ pub fn generate_message_enum(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ide
 			}
 
 			#[doc = #as_fn_doc]
+			#cfg
 			pub fn #as_fn_name(&self) -> ::core::option::Option<&#body_type> {
 				if let Self::#variant_name(x) = self {
 					::core::option::Option::Some(x)
@@ -75,6 +113,7 @@ pub fn generate_message_enum(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ide
 			}
 
 			#[doc = #into_fn_doc]
+			#cfg
 			pub fn #into_fn_name(self) -> ::core::result::Result<#body_type, #fizyr_rpc::Error> {
 				let service_id = self.service_id();
 				if let Self::#variant_name(x) = self {
@@ -86,6 +125,38 @@ pub fn generate_message_enum(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ide
 		})
 	}
 
+	if forward_compatible {
+		variants.extend(quote! {
+			/// A message with a known service ID, but a body that holds a value this version of the interface does not recognize.
+			///
+			/// This happens when the remote peer uses a newer version of the interface that added new values to the message body.
+			/// Unrecognized messages can not be sent.
+			Unrecognized {
+				/// The service ID of the message.
+				service_id: i32,
+
+				/// The raw data of the message body.
+				raw_body: ::std::vec::Vec<u8>,
+			},
+		});
+		service_id_arms.extend(quote! {
+			Self::Unrecognized { service_id, .. } => *service_id,
+		});
+		to_message.extend(quote! {
+			Self::Unrecognized { .. } => {
+				let error: ::std::boxed::Box<dyn ::std::error::Error + ::core::marker::Send> = ::std::boxed::Box::<dyn ::std::error::Error + ::core::marker::Send + ::core::marker::Sync>::from("unrecognized messages can not be encoded");
+				::core::result::Result::Err(error)
+			},
+		});
+		impl_tokens.extend(quote! {
+			/// Check if the message is a [`Self::Unrecognized`].
+			pub fn is_unrecognized(&self) -> bool {
+				::core::matches!(self, Self::Unrecognized { .. })
+			}
+		});
+	}
+
+	let catch_all_arm = cfg_catch_all_arm(messages.iter().map(|x| x.cfg()));
 	item_tokens.extend(quote! {
 		#[doc = #enum_doc]
 		#[derive(Debug)]
@@ -98,6 +169,7 @@ pub fn generate_message_enum(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ide
 			fn service_id(&self) -> i32 {
 				match self {
 					#service_id_arms
+					#catch_all_arm
 				}
 			}
 
@@ -123,6 +195,7 @@ pub fn generate_message_enum(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ide
 			fn to_message(&self) -> ::core::result::Result<(i32, F::Body), ::std::boxed::Box<dyn ::std::error::Error + ::core::marker::Send>> {
 				match self {
 					#to_message
+					#catch_all_arm
 				}
 			}
 		}
@@ -1,12 +1,18 @@
 use proc_macro2::TokenStream;
 
-use super::parse::cooked::InterfaceDefinition;
+use super::parse::cooked::{CfgConditions, InterfaceDefinition, ServiceId};
+use crate::util::WithSpan;
 
+mod broadcaster;
 mod client;
+mod client_api;
+mod id_checks;
 mod interface_struct;
 mod format_trait;
 mod message_enum;
 mod server;
+mod server_runner;
+mod service_ids;
 mod services;
 mod streams;
 
@@ -15,11 +21,16 @@ pub fn generate_interface(fizyr_rpc: &syn::Ident, interface: &InterfaceDefinitio
 	let mut item_tokens = TokenStream::new();
 	let mut client_impl_tokens = TokenStream::new();
 
+	id_checks::generate_id_checks(&mut item_tokens, interface);
 	interface_struct::generate_interface_struct(&mut item_tokens, fizyr_rpc, interface);
+	service_ids::generate_service_ids(&mut item_tokens, interface);
 	services::generate_services(&mut item_tokens, &mut client_impl_tokens, fizyr_rpc, interface);
 	streams::generate_streams(&mut item_tokens, &mut client_impl_tokens, fizyr_rpc, interface);
 	client::generate_client(&mut item_tokens, fizyr_rpc, interface, client_impl_tokens);
-	server::generate_server(&mut item_tokens, fizyr_rpc, interface);
+	client_api::generate_client_api(&mut item_tokens, fizyr_rpc, interface);
+	let recv_message_where = server::generate_server(&mut item_tokens, fizyr_rpc, interface);
+	server_runner::generate_server_runner(&mut item_tokens, fizyr_rpc, interface, &recv_message_where);
+	broadcaster::generate_broadcaster(&mut item_tokens, fizyr_rpc, interface);
 	format_trait::generate_format_trait(&mut item_tokens, fizyr_rpc, interface);
 
 	item_tokens
@@ -58,3 +69,60 @@ fn is_unit_type(ty: &syn::Type) -> bool {
 		false
 	}
 }
+
+/// Generate a match arm pattern that matches a service ID.
+///
+/// Service IDs given as path to a constant are matched with a guard,
+/// so that an unresolved path can never turn into a catch-all binding.
+fn service_id_pattern(service_id: &WithSpan<ServiceId>) -> TokenStream {
+	match &service_id.value {
+		ServiceId::Literal(_) => quote::quote!(#service_id),
+		ServiceId::Path(path) => quote::quote_spanned!(service_id.span => __service_id if __service_id == #path),
+	}
+}
+
+/// Generate a trait bound on the format `F` for an item that may be disabled by `#[cfg]` attributes.
+///
+/// Where clauses and supertraits can not have `#[cfg]` attributes.
+/// So if the item has `#[cfg]` conditions, the bounds are moved to a hidden helper trait named `trait_name`.
+/// The helper trait has the bounds as supertraits if the conditions hold, and no supertraits otherwise.
+/// It is implemented for all types that satisfy it.
+///
+/// Returns the bound to use for `F`: either the original bounds or the helper trait.
+fn cfg_format_bound(item_tokens: &mut TokenStream, cfg: &CfgConditions, trait_name: &str, bounds: TokenStream) -> TokenStream {
+	if cfg.is_empty() {
+		return bounds;
+	}
+
+	let trait_name = syn::Ident::new(trait_name, proc_macro2::Span::call_site());
+	let negated = cfg.negated();
+	item_tokens.extend(quote::quote! {
+		#cfg
+		#[doc(hidden)]
+		pub trait #trait_name: #bounds {}
+		#cfg
+		impl<F: #bounds> #trait_name for F {}
+
+		#negated
+		#[doc(hidden)]
+		pub trait #trait_name {}
+		#negated
+		impl<F> #trait_name for F {}
+	});
+	quote::quote!(#trait_name)
+}
+
+/// Generate a catch-all match arm for matches on enums with variants that may be disabled by `#[cfg]` attributes.
+///
+/// If all variants are disabled, the enum is empty and a match on a reference to it still needs an arm.
+/// Generates nothing if none of the variants have `#[cfg]` conditions.
+fn cfg_catch_all_arm<'a>(mut conditions: impl Iterator<Item = &'a CfgConditions>) -> TokenStream {
+	if conditions.all(|x| x.is_empty()) {
+		TokenStream::new()
+	} else {
+		quote::quote! {
+			#[allow(unreachable_patterns)]
+			_ => ::core::unreachable!(),
+		}
+	}
+}
@@ -3,12 +3,12 @@ use quote::quote;
 
 use crate::interface::parse::cooked::InterfaceDefinition;
 
-use super::{to_upper_camel_case, to_doc_attrs};
+use super::{cfg_catch_all_arm, cfg_format_bound, service_id_pattern, to_upper_camel_case, to_doc_attrs};
 
 /// Generate a server struct.
 ///
-/// `extra_impl` is used to add additional functions to the main `impl` block.
-pub fn generate_server(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, interface: &InterfaceDefinition) {
+/// Returns the where clause of the `recv_message_offloaded` function of the server.
+pub fn generate_server(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, interface: &InterfaceDefinition) -> TokenStream {
 	// Generic parameters to the `ReceivedMessage` struct.
 	let mut received_msg_generics = TokenStream::new();
 	// Where clauses for the `ReceivedMessage` struct.
@@ -19,31 +19,91 @@ pub fn generate_server(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, in
 	let mut received_msg_debug_arms = TokenStream::new();
 	// Where clause for the `recv_message` function.
 	let mut recv_message_where = TokenStream::new();
-	// Match arms for decoding a request message.
+	// Additional where clause for the `recv_message_offloaded` function.
+	let mut offload_where = TokenStream::new();
+	// Match arms for decoding a stream message on the current task.
 	let mut decode_stream_arms = TokenStream::new();
-	// Match arms for decoding a request message.
+	// Match arms for decoding a stream message, possibly on a blocking thread.
+	let mut decode_stream_arms_offloaded = TokenStream::new();
+	// Match arms for detecting streams that do not need to be delivered in order.
+	let mut unordered_stream_arms = TokenStream::new();
+	// Match arms for decoding a request message on the current task.
 	let mut decode_request_arms = TokenStream::new();
+	// Match arms for decoding a request message, possibly on a blocking thread.
+	let mut decode_request_arms_offloaded = TokenStream::new();
 
 	for stream in interface.streams() {
-		let service_id = stream.service_id();
+		let service_id = service_id_pattern(stream.service_id());
 		let stream_name = stream.name();
 		let variant_name = syn::Ident::new(&to_upper_camel_case(&stream_name.to_string()), Span::call_site());
 		let body_type = stream.body_type();
-		recv_message_where.extend(quote! {
-			F: #fizyr_rpc::format::DecodeBody<#body_type>,
-		});
-		decode_stream_arms.extend(quote! {
-			#service_id =>  {
-				match F::decode_body(message.body) {
-					::core::result::Result::Ok(body) => {
-						::core::result::Result::Ok(ReceivedMessage::Stream(StreamMessage::#variant_name(body)))
+		let cfg = stream.cfg();
+		if cfg.is_empty() {
+			recv_message_where.extend(quote! {
+				F: #fizyr_rpc::format::DecodeBody<#body_type>,
+			});
+			offload_where.extend(quote! {
+				#body_type: ::core::marker::Send + 'static,
+			});
+		} else {
+			// The body type may not exist, so the `Send + 'static` bound is checked where it is used instead.
+			let trait_name = format!("__ServerStream{}", variant_name);
+			let bound = cfg_format_bound(item_tokens, cfg, &trait_name, quote!(#fizyr_rpc::format::DecodeBody<#body_type>));
+			recv_message_where.extend(quote! {
+				F: #bound,
+			});
+		}
+		if stream.unordered() {
+			unordered_stream_arms.extend(quote! {
+				#cfg
+				#service_id => true,
+			});
+		}
+		let (decode, decode_offloaded) = if interface.forward_compatible() {
+			(
+				quote!(#fizyr_rpc::format::decode_body_partial_instrumented::<F, #body_type>(message.header.service_id, message.body, decode_context)),
+				quote!(#fizyr_rpc::format::decode_body_partial_offloaded::<F, #body_type>(message.header.service_id, message.body, decode_offload_threshold, decode_context).await),
+			)
+		} else {
+			(
+				quote!(#fizyr_rpc::format::decode_body_instrumented::<F, #body_type>(message.header.service_id, message.body, decode_context)),
+				quote!(#fizyr_rpc::format::decode_body_offloaded::<F, #body_type>(message.header.service_id, message.body, decode_offload_threshold, decode_context).await),
+			)
+		};
+		for (arms, decode) in [(&mut decode_stream_arms, decode), (&mut decode_stream_arms_offloaded, decode_offloaded)] {
+			if interface.forward_compatible() {
+				arms.extend(quote! {
+					#cfg
+					#service_id =>  {
+						match #decode {
+							::core::result::Result::Ok(#fizyr_rpc::format::PartialDecode::Decoded(body)) => {
+								::core::result::Result::Ok(StreamMessage::#variant_name(body))
+							},
+							::core::result::Result::Ok(#fizyr_rpc::format::PartialDecode::Unrecognized(raw_body)) => {
+								::core::result::Result::Ok(StreamMessage::Unrecognized { service_id: message.header.service_id, raw_body })
+							},
+							::core::result::Result::Err(e) => {
+								::core::result::Result::Err(#fizyr_rpc::RecvMessageError::InvalidStream(message.header, e))
+							},
+						}
 					},
-					::core::result::Result::Err(e) => {
-						::core::result::Result::Err(#fizyr_rpc::RecvMessageError::InvalidStream(message.header, e))
+				});
+			} else {
+				arms.extend(quote! {
+					#cfg
+					#service_id =>  {
+						match #decode {
+							::core::result::Result::Ok(body) => {
+								::core::result::Result::Ok(StreamMessage::#variant_name(body))
+							},
+							::core::result::Result::Err(e) => {
+								::core::result::Result::Err(#fizyr_rpc::RecvMessageError::InvalidStream(message.header, e))
+							},
+						}
 					},
-				}
-			},
-		});
+				});
+			}
+		}
 	}
 
 	if !interface.streams().is_empty() {
@@ -64,26 +124,59 @@ pub fn generate_server(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, in
 	}
 
 	for service in interface.services() {
-		let service_id = service.service_id();
+		let service_id = service_id_pattern(service.service_id());
 		let service_name = service.name();
 		let variant_name = syn::Ident::new(&to_upper_camel_case(&service_name.to_string()), Span::call_site());
 		let request_type = service.request_type();
-		recv_message_where.extend(quote! {
-			F: #fizyr_rpc::format::DecodeBody<#request_type>,
-		});
-		decode_request_arms.extend(quote! {
-			#service_id =>  {
-				match F::decode_body(body) {
-					::core::result::Result::Ok(body) => {
-						let request = #service_name::ReceivedRequestHandle { request };
-						::core::result::Result::Ok(ReceivedMessage::Request(ReceivedRequestHandle::#variant_name(request, body)))
-					},
-					::core::result::Result::Err(e) => {
-						::core::result::Result::Err(#fizyr_rpc::RecvMessageError::InvalidRequest(request, e))
-					},
-				}
-			},
-		});
+		let cfg = service.cfg();
+		if cfg.is_empty() {
+			recv_message_where.extend(quote! {
+				F: #fizyr_rpc::format::DecodeBody<#request_type>,
+			});
+			offload_where.extend(quote! {
+				#request_type: ::core::marker::Send + 'static,
+			});
+		} else {
+			// The request type may not exist, so the `Send + 'static` bound is checked where it is used instead.
+			let trait_name = format!("__ServerService{}", variant_name);
+			let bound = cfg_format_bound(item_tokens, cfg, &trait_name, quote!(#fizyr_rpc::format::DecodeBody<#request_type>));
+			recv_message_where.extend(quote! {
+				F: #bound,
+			});
+		}
+		let decode = quote!(#fizyr_rpc::format::decode_body_instrumented::<F, #request_type>(request.service_id(), body, &self.decode_context));
+		let decode_offloaded = quote!(#fizyr_rpc::format::decode_body_offloaded::<F, #request_type>(request.service_id(), body, self.decode_offload_threshold, &self.decode_context).await);
+		let arms = [
+			(&mut decode_request_arms, decode, quote!(::core::option::Option::None)),
+			(&mut decode_request_arms_offloaded, decode_offloaded, quote!(self.decode_offload_threshold)),
+		];
+		for (arms, decode, offload_threshold) in arms {
+			arms.extend(quote! {
+				#cfg
+				#service_id =>  {
+					// Check the type fingerprint first, a mismatching body could decode into garbage instead of failing.
+					if let ::core::result::Result::Err(e) = request.check_type_fingerprint(#service_name::TYPE_FINGERPRINT) {
+						::core::result::Result::Err(#fizyr_rpc::RecvMessageError::InvalidRequest(request, ::std::boxed::Box::new(e)))
+					} else {
+						let decode_start = ::std::time::Instant::now();
+						let body_len = #fizyr_rpc::Body::data_len(&body);
+						let decoded = #decode;
+						self.decode_budget.consume(body_len, #offload_threshold).await;
+						match decoded {
+							::core::result::Result::Ok(body) => {
+								let decode_duration = decode_start.elapsed();
+								let decode_context = self.decode_context.clone();
+								let request = #service_name::ReceivedRequestHandle { request, decode_duration, decode_context };
+								::core::result::Result::Ok(ReceivedMessage::Request(ReceivedRequestHandle::#variant_name(request, body)))
+							},
+							::core::result::Result::Err(e) => {
+								::core::result::Result::Err(#fizyr_rpc::RecvMessageError::InvalidRequest(request, e))
+							},
+						}
+					}
+				},
+			});
+		}
 	}
 
 	if !interface.services().is_empty() {
@@ -104,18 +197,244 @@ pub fn generate_server(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, in
 		});
 	}
 
+	// Message bodies may be decoded on a blocking thread by `recv_message_offloaded`.
+	offload_where.extend(quote! {
+		F: 'static,
+	});
+
+	// Tokens for decoding stream messages, in order or in parallel.
+	let mut stream_arm = quote! {
+		#fizyr_rpc::ReceivedMessage::Stream(message) => ::core::result::Result::Err(#fizyr_rpc::RecvMessageError::UnknownStream(message)),
+	};
+	let mut stream_arm_offloaded = stream_arm.clone();
+	let mut decode_stream_fn = TokenStream::new();
+	let mut unordered_fields = TokenStream::new();
+	let mut unordered_field_inits = TokenStream::new();
+	let mut unordered_debug_fields = TokenStream::new();
+	let mut unordered_fns = TokenStream::new();
+	let mut recv_received = quote! {
+		let received = self.peer.recv_message().await?;
+	};
+	let mut spawn_unordered = TokenStream::new();
+
+	if interface.streams().iter().any(|x| x.unordered()) {
+		unordered_fields.extend(quote! {
+			unordered_decoder: #fizyr_rpc::format::UnorderedDecoder<StreamMessage, F::Body>,
+		});
+		unordered_field_inits.extend(quote! {
+			unordered_decoder: #fizyr_rpc::format::UnorderedDecoder::new(0),
+		});
+		unordered_debug_fields.extend(quote! {
+			.field("unordered_decoder", &self.unordered_decoder)
+		});
+		unordered_fns.extend(quote! {
+			/// Set the maximum number of unordered stream messages to decode in parallel.
+			///
+			/// Messages of streams marked with `#[unordered]` in the interface definition are decoded in separate tasks if this is more than zero.
+			/// Only [`Self::recv_message_offloaded()`] decodes messages in parallel.
+			/// They are returned as soon as they are decoded,
+			/// so they may be delivered out of order, even relative to other messages of the same stream.
+			/// All other messages are still delivered in the order they were received.
+			///
+			/// If set to zero (the default), all messages are decoded in order.
+			pub fn set_unordered_decode_workers(&mut self, workers: usize) {
+				self.unordered_decoder.set_max_parallel(workers);
+			}
+
+			/// Get the maximum number of unordered stream messages to decode in parallel.
+			pub fn unordered_decode_workers(&self) -> usize {
+				self.unordered_decoder.max_parallel()
+			}
+
+			/// Check if a stream message does not need to be delivered in order.
+			fn is_unordered_stream(service_id: i32) -> bool {
+				match service_id {
+					#unordered_stream_arms
+					_ => false,
+				}
+			}
+		});
+		recv_received = quote! {
+			let received = match self.unordered_decoder.recv(&mut self.peer).await {
+				#fizyr_rpc::format::UnorderedNext::Decoded(message) => return message.map(ReceivedMessage::Stream),
+				#fizyr_rpc::format::UnorderedNext::Received(received) => received?,
+			};
+		};
+		spawn_unordered = quote! {
+			if self.unordered_decoder.is_enabled() && Self::is_unordered_stream(message.header.service_id) {
+				let decode_offload_threshold = self.decode_offload_threshold;
+				let decode_context = self.decode_context.clone();
+				self.unordered_decoder.spawn(async move {
+					Self::decode_stream_message_offloaded(message, decode_offload_threshold, &decode_context).await
+				});
+				continue;
+			}
+		};
+	}
+
+	if !interface.streams().is_empty() {
+		decode_stream_fn.extend(quote! {
+			/// Decode a stream message on the current task.
+			fn decode_stream_message(
+				message: #fizyr_rpc::Message<F::Body>,
+				decode_context: &#fizyr_rpc::format::DecodeContext,
+			) -> ::core::result::Result<StreamMessage, #fizyr_rpc::RecvMessageError<F::Body>>
+			where
+				#recv_message_where
+			{
+				match message.header.service_id {
+					#decode_stream_arms
+					_ => ::core::result::Result::Err(#fizyr_rpc::RecvMessageError::UnknownStream(message)),
+				}
+			}
+
+			/// Decode a stream message, on a blocking thread if the body is large enough.
+			async fn decode_stream_message_offloaded(
+				message: #fizyr_rpc::Message<F::Body>,
+				decode_offload_threshold: ::core::option::Option<usize>,
+				decode_context: &#fizyr_rpc::format::DecodeContext,
+			) -> ::core::result::Result<StreamMessage, #fizyr_rpc::RecvMessageError<F::Body>>
+			where
+				#recv_message_where
+				#offload_where
+			{
+				match message.header.service_id {
+					#decode_stream_arms_offloaded
+					_ => ::core::result::Result::Err(#fizyr_rpc::RecvMessageError::UnknownStream(message)),
+				}
+			}
+		});
+		stream_arm = quote! {
+			#fizyr_rpc::ReceivedMessage::Stream(message) => {
+				let body_len = #fizyr_rpc::Body::data_len(&message.body);
+				let decoded = Self::decode_stream_message(message, &self.decode_context);
+				self.decode_budget.consume(body_len, ::core::option::Option::None).await;
+				decoded.map(ReceivedMessage::Stream)
+			},
+		};
+		stream_arm_offloaded = quote! {
+			#fizyr_rpc::ReceivedMessage::Stream(message) => {
+				#spawn_unordered
+				let body_len = #fizyr_rpc::Body::data_len(&message.body);
+				let decoded = Self::decode_stream_message_offloaded(message, self.decode_offload_threshold, &self.decode_context).await;
+				self.decode_budget.consume(body_len, self.decode_offload_threshold).await;
+				decoded.map(ReceivedMessage::Stream)
+			},
+		};
+	}
+
+	// Tokens for tracking heartbeat messages, if enabled.
+	let mut heartbeat_field = TokenStream::new();
+	let mut heartbeat_debug_field = TokenStream::new();
+	let mut heartbeat_init = TokenStream::new();
+	let mut heartbeat_fns = TokenStream::new();
+	let mut heartbeat_arm = TokenStream::new();
+	let mut recv_message_heartbeat_doc = TokenStream::new();
+	if interface.heartbeat_interval().is_some() {
+		heartbeat_field = quote! {
+			last_heartbeat: ::core::option::Option<::std::time::Instant>,
+		};
+		heartbeat_debug_field = quote! {
+			.field("last_heartbeat", &self.last_heartbeat)
+		};
+		heartbeat_init = quote! {
+			last_heartbeat: ::core::option::Option::None,
+		};
+		heartbeat_fns = quote! {
+			/// Get the time when the last heartbeat message was received from the remote peer.
+			///
+			/// Returns `None` if no heartbeat was received yet.
+			/// Heartbeat messages are only processed by [`Self::recv_message()`] and [`Self::recv_message_offloaded()`],
+			/// so the time is not updated while the application is not receiving messages.
+			///
+			/// The interval at which the client sends heartbeats is given by [`Interface::heartbeat_interval()`].
+			pub fn last_heartbeat(&self) -> ::core::option::Option<::std::time::Instant> {
+				self.last_heartbeat
+			}
+		};
+		heartbeat_arm = quote! {
+			#fizyr_rpc::ReceivedMessage::Stream(message) if message.header.service_id == #fizyr_rpc::service_id::HEARTBEAT => {
+				self.last_heartbeat = ::core::option::Option::Some(::std::time::Instant::now());
+				continue;
+			},
+		};
+		recv_message_heartbeat_doc = quote! {
+			///
+			/// Heartbeat messages are consumed to update [`Self::last_heartbeat()`] and are not returned.
+		};
+	}
+
+	// Tokens for the body of `recv_message` and `recv_message_offloaded`.
+	let recv_loop = |stream_arm: &TokenStream, decode_request_arms: &TokenStream| quote! {
+		loop {
+			#recv_received
+			let message = match received {
+				#heartbeat_arm
+				#stream_arm
+				// The remote peer is no longer waiting for the response, so do not bother the application with the request.
+				// A failure to send the response is not reported, since the caller is not interested in the request.
+				#fizyr_rpc::ReceivedMessage::Request(request, _body) if request.deadline().map_or(false, |deadline| deadline <= ::std::time::Instant::now()) => {
+					let _ = request.send_deadline_exceeded().await;
+					continue;
+				},
+				#fizyr_rpc::ReceivedMessage::Request(request, body) if request.service_id() == #fizyr_rpc::service_id::NEGOTIATE_VERSION => {
+					let payload = self.handshake_payload.as_deref();
+					self.negotiation = #fizyr_rpc::negotiation::respond_to_negotiation_with_payload(request, body, Interface::name(), Interface::version_hash(), payload).await?;
+					continue;
+				},
+				#fizyr_rpc::ReceivedMessage::Request(request, body) => {
+					match request.service_id() {
+						#decode_request_arms
+						_ => ::core::result::Result::Err(#fizyr_rpc::RecvMessageError::UnknownRequest(request, body)),
+					}
+				},
+			};
+
+			// The error is still reported to the caller, so a failure to send the response is not reported separately.
+			// Type fingerprint mismatches are always answered, so the remote peer learns about the mismatch right away.
+			if self.bad_request_responses || message.as_ref().err().map_or(false, #fizyr_rpc::RecvMessageError::is_type_fingerprint_mismatch) {
+				let response = match &message {
+					::core::result::Result::Err(e) => ::core::option::Option::Some(e.send_bad_request_response()),
+					::core::result::Result::Ok(_) => ::core::option::Option::None,
+				};
+				if let ::core::option::Option::Some(response) = response {
+					let _ = response.await;
+				}
+			}
+			return message;
+		}
+	};
+	let recv_message_loop = recv_loop(&stream_arm, &decode_request_arms);
+	let recv_message_offloaded_loop = recv_loop(&stream_arm_offloaded, &decode_request_arms_offloaded);
+
 	let visibility = interface.visibility();
 	let server_doc = format!("RPC server for the {} interface.", interface.name());
 	item_tokens.extend(quote! {
 		#[doc = #server_doc]
 		#visibility struct Server<F: #fizyr_rpc::format::Format> {
 			peer: #fizyr_rpc::PeerReadHandle<F::Body>,
+			decode_offload_threshold: ::core::option::Option<usize>,
+			decode_budget: #fizyr_rpc::format::DecodeBudget,
+			decode_context: #fizyr_rpc::format::DecodeContext,
+			bad_request_responses: bool,
+			handshake_payload: ::core::option::Option<::std::string::String>,
+			negotiation: ::core::option::Option<#fizyr_rpc::negotiation::Negotiation>,
+			#heartbeat_field
+			#unordered_fields
 		}
 
 		impl<F: #fizyr_rpc::format::Format> ::core::fmt::Debug for Server<F> {
 			fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
 				f.debug_struct(::core::any::type_name::<Self>())
 					.field("peer", &self.peer)
+					.field("decode_offload_threshold", &self.decode_offload_threshold)
+					.field("decode_budget", &self.decode_budget)
+					.field("decode_context", &self.decode_context)
+					.field("bad_request_responses", &self.bad_request_responses)
+					.field("handshake_payload", &self.handshake_payload)
+					.field("negotiation", &self.negotiation)
+					#heartbeat_debug_field
+					#unordered_debug_fields
 					.finish()
 			}
 		}
@@ -123,7 +442,121 @@ pub fn generate_server(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, in
 		impl<F: #fizyr_rpc::format::Format> Server<F> {
 			/// Create a new interface-specific RPC server from a raw write handle.
 			fn new(peer: #fizyr_rpc::PeerReadHandle<F::Body>) -> Self {
-				Self { peer }
+				Self {
+					peer,
+					decode_offload_threshold: ::core::option::Option::None,
+					decode_budget: ::core::default::Default::default(),
+					decode_context: ::core::default::Default::default(),
+					bad_request_responses: false,
+					handshake_payload: ::core::option::Option::None,
+					negotiation: ::core::option::Option::None,
+					#heartbeat_init
+					#unordered_field_inits
+				}
+			}
+
+			/// Set the body size from which incoming messages are decoded on a blocking thread.
+			///
+			/// If set to `Some(n)`, message bodies of `n` bytes or more are decoded using [`tokio::task::spawn_blocking()`].
+			/// If set to `None` (the default), all messages are decoded directly on the current task.
+			///
+			/// The threshold only applies to [`Self::recv_message_offloaded()`],
+			/// [`Self::recv_message()`] always decodes messages on the current task.
+			///
+			/// Offloading expensive decoding keeps the runtime worker threads free for other tasks,
+			/// such as the read/write loops of peers.
+			pub fn set_decode_offload_threshold(&mut self, threshold: ::core::option::Option<usize>) {
+				self.decode_offload_threshold = threshold;
+			}
+
+			/// Get the body size from which incoming messages are decoded on a blocking thread.
+			pub fn decode_offload_threshold(&self) -> ::core::option::Option<usize> {
+				self.decode_offload_threshold
+			}
+
+			/// Set the number of bytes to decode before yielding to the runtime.
+			///
+			/// If set to `Some(n)`, the server yields to the runtime after decoding message bodies with a total size of `n` bytes or more,
+			/// so that a peer sending many large messages can not keep a worker thread busy with decoding.
+			/// Messages that are decoded on a blocking thread are not counted,
+			/// see [`Self::set_decode_offload_threshold()`].
+			///
+			/// If set to `None` (the default), the server only yields when it has to wait for a message.
+			pub fn set_decode_budget(&mut self, budget: ::core::option::Option<usize>) {
+				self.decode_budget.set_budget(budget);
+			}
+
+			/// Get the number of bytes to decode before yielding to the runtime.
+			pub fn decode_budget(&self) -> ::core::option::Option<usize> {
+				self.decode_budget.budget()
+			}
+
+			/// Set the limits for decoding incoming messages.
+			///
+			/// The decode context is passed to the format for all messages received by the server,
+			/// including the update messages of received requests.
+			pub fn set_decode_context(&mut self, context: #fizyr_rpc::format::DecodeContext) {
+				self.decode_context = context;
+			}
+
+			/// Get the limits for decoding incoming messages.
+			pub fn decode_context(&self) -> &#fizyr_rpc::format::DecodeContext {
+				&self.decode_context
+			}
+
+			#unordered_fns
+
+			/// Automatically answer unknown and invalid requests with a "bad request" error response.
+			///
+			/// If enabled, requests with an unknown service ID or an invalid body are answered with a standardized error response,
+			/// so the remote peer does not wait for a response that never comes.
+			/// The error is still returned by [`Self::recv_message()`], but the request is already closed at that point.
+			/// The remote peer can recognize the response with `Error::is_bad_request()`.
+			///
+			/// This is disabled by default.
+			pub fn set_bad_request_responses(&mut self, enabled: bool) {
+				self.bad_request_responses = enabled;
+			}
+
+			/// Check if unknown and invalid requests are automatically answered with a "bad request" error response.
+			pub fn bad_request_responses(&self) -> bool {
+				self.bad_request_responses
+			}
+
+			/// Set the payload to send to the remote peer when answering a version negotiation request.
+			///
+			/// Use `None` to answer version negotiation requests without a payload (the default).
+			pub fn set_handshake_payload<P: #fizyr_rpc::negotiation::HandshakePayload>(&mut self, payload: ::core::option::Option<&P>) {
+				self.handshake_payload = payload.map(|payload| payload.encode_payload());
+			}
+
+			/// Get the handshake payload sent by the remote peer in the last version negotiation request.
+			///
+			/// Returns `Ok(None)` if the remote peer did not negotiate the version yet, or if it did not send a payload.
+			/// Returns an error if the payload could not be decoded.
+			pub fn remote_handshake_payload<P: #fizyr_rpc::negotiation::HandshakePayload>(&self) -> ::core::result::Result<::core::option::Option<P>, #fizyr_rpc::Error> {
+				match &self.negotiation {
+					::core::option::Option::Some(negotiation) => negotiation.remote_payload(),
+					::core::option::Option::None => ::core::result::Result::Ok(::core::option::Option::None),
+				}
+			}
+
+			/// Get the last successful version negotiation with the remote peer.
+			///
+			/// The negotiation holds the nonces and the transcript hash, which can be used to bind authentication to the connection.
+			/// Returns `None` if the remote peer did not negotiate the version yet.
+			pub fn negotiation(&self) -> ::core::option::Option<&#fizyr_rpc::negotiation::Negotiation> {
+				self.negotiation.as_ref()
+			}
+
+			#heartbeat_fns
+
+			/// Get the application context of the connection, if it has type `T`.
+			///
+			/// The context is attached to the peer when the connection is accepted or made.
+			/// See `ConnectionContext` for more details.
+			pub fn context<T: ::core::marker::Send + ::core::marker::Sync + 'static>(&self) -> ::core::option::Option<::std::sync::Arc<T>> {
+				self.peer.context()
 			}
 
 			/// Close the connection with the remote peer.
@@ -140,25 +573,64 @@ pub fn generate_server(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, in
 			}
 
 			/// Receive the next incoming message.
+			///
+			/// Version negotiation requests from the remote peer are answered automatically and are not returned.
+			/// Requests with a deadline that passed before they are received are answered with a "deadline exceeded" error response,
+			/// and are not returned either.
+			#recv_message_heartbeat_doc
+			///
+			/// All message bodies are decoded directly on the current task.
+			/// To decode large message bodies on a blocking thread, use [`Self::recv_message_offloaded()`] instead.
+			/// To keep other tasks responsive, the server can yield to the runtime after decoding a number of bytes,
+			/// see [`Self::set_decode_budget()`].
+			///
+			/// Unknown and invalid requests can be answered automatically,
+			/// see [`Self::set_bad_request_responses()`].
+			///
+			/// Requests with a type fingerprint that does not match the local interface definition
+			/// are returned as `RecvMessageError::InvalidRequest` without decoding the body,
+			/// and they are always answered with a "type fingerprint mismatch" error response.
+			///
+			/// Messages are returned in the order they were received from the remote peer.
+			///
+			/// # Cancel safety
+			/// This function is not cancel safe.
+			/// A message is taken from the peer before it is decoded or answered automatically,
+			/// and both can wait for the runtime.
+			/// If the future is dropped at that point, the message is lost.
+			/// To use the server in `tokio::select!`, receive messages in a separate task and forward them over a channel.
 			pub async fn recv_message(&mut self) -> ::core::result::Result<ReceivedMessage<#received_msg_generics>, #fizyr_rpc::RecvMessageError<F::Body>>
 			where
 				#recv_message_where
 			{
-				match self.peer.recv_message().await? {
-					#fizyr_rpc::ReceivedMessage::Stream(message) => {
-						match message.header.service_id {
-							#decode_stream_arms
-							_ => ::core::result::Result::Err(#fizyr_rpc::RecvMessageError::UnknownStream(message)),
-						}
-					},
-					#fizyr_rpc::ReceivedMessage::Request(request, body) => {
-						match request.service_id() {
-							#decode_request_arms
-							_ => ::core::result::Result::Err(#fizyr_rpc::RecvMessageError::UnknownRequest(request, body)),
-						}
-					},
-				}
+				#recv_message_loop
 			}
+
+			/// Receive the next incoming message, decoding large message bodies on a blocking thread.
+			///
+			/// This is the same as [`Self::recv_message()`],
+			/// except that message bodies may be decoded on a blocking thread,
+			/// see [`Self::set_decode_offload_threshold()`].
+			/// Messages of `#[unordered]` streams may also be decoded in parallel,
+			/// in which case they can be returned out of order.
+			///
+			/// This requires the format and all message body types to be `Send + 'static`.
+			///
+			/// # Cancel safety
+			/// This function is not cancel safe.
+			/// A message is taken from the peer before it is decoded or answered automatically,
+			/// and both can wait for a blocking thread or the runtime.
+			/// If the future is dropped at that point, the message is lost.
+			/// To use the server in `tokio::select!`, receive messages in a separate task and forward them over a channel.
+			pub async fn recv_message_offloaded(&mut self) -> ::core::result::Result<ReceivedMessage<#received_msg_generics>, #fizyr_rpc::RecvMessageError<F::Body>>
+			where
+				#recv_message_where
+				#offload_where
+			{
+				#recv_message_offloaded_loop
+			}
+
+			#decode_stream_fn
 		}
 
 		impl<F: #fizyr_rpc::format::Format> ::core::convert::From<#fizyr_rpc::PeerReadHandle<F::Body>> for Server<F> {
@@ -197,28 +669,116 @@ pub fn generate_server(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, in
 	if !interface.services().is_empty() {
 		generate_received_request_enum(item_tokens, fizyr_rpc, interface);
 	}
+
+	quote!(#recv_message_where #offload_where)
 }
 
 /// Generate an enum for all possible received requests for a server.
 fn generate_received_request_enum(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, interface: &InterfaceDefinition) {
 	let mut variant_tokens = TokenStream::new();
 	let mut debug_tokens = TokenStream::new();
+	let mut service_name_arms = TokenStream::new();
+	let mut service_id_arms = TokenStream::new();
+	let mut handle_with_arms = TokenStream::new();
+	let mut handler_trait_items = TokenStream::new();
+	let mut handlers_fields = TokenStream::new();
+	let mut handlers_field_inits = TokenStream::new();
+	let mut handlers_functions = TokenStream::new();
+	let mut handlers_impl_items = TokenStream::new();
 	for service in interface.services() {
 		let service_name = service.name();
 		let variant_name_string = to_upper_camel_case(&service_name.to_string());
 		let variant_name = syn::Ident::new(&variant_name_string, Span::call_site());
 		let request_type = service.request_type();
 		let doc = to_doc_attrs(service.doc());
+		let cfg = service.cfg();
 		variant_tokens.extend(quote! {
 			#doc
+			#cfg
 			#variant_name(#service_name::ReceivedRequestHandle<F>, #request_type),
 		});
 		debug_tokens.extend(quote! {
+			#cfg
 			Self::#variant_name(request, _body) => ::core::write!(f, "{}({:?})", #variant_name_string, request),
 		});
+
+		let service_name_string = service_name.to_string();
+		service_name_arms.extend(quote! {
+			#cfg
+			Self::#variant_name(..) => #service_name_string,
+		});
+		service_id_arms.extend(quote! {
+			#cfg
+			Self::#variant_name(request, _body) => request.service_id(),
+		});
+		handle_with_arms.extend(quote! {
+			#cfg
+			Self::#variant_name(request, body) => handler.#service_name(request, body),
+		});
+
+		let handler_doc = format!("Handle a `{}` request.", service_name);
+		handler_trait_items.extend(quote! {
+			#[doc = #handler_doc]
+			#cfg
+			fn #service_name(&mut self, request: #service_name::ReceivedRequestHandle<F>, body: #request_type) -> Self::Output;
+		});
+
+		let register_name = syn::Ident::new(&format!("on_{}", service_name), Span::call_site());
+		let register_doc = format!("Register the handler for `{}` requests.", service_name);
+		handlers_fields.extend(quote! {
+			#cfg
+			#service_name: ::core::option::Option<::std::boxed::Box<dyn ::core::ops::FnMut(#service_name::ReceivedRequestHandle<F>, #request_type) -> Output + ::core::marker::Send>>,
+		});
+		handlers_field_inits.extend(quote! {
+			#cfg
+			#service_name: ::core::option::Option::None,
+		});
+		handlers_functions.extend(quote! {
+			#[doc = #register_doc]
+			///
+			/// This replaces any previously registered handler for the service.
+			#cfg
+			pub fn #register_name(mut self, handler: impl ::core::ops::FnMut(#service_name::ReceivedRequestHandle<F>, #request_type) -> Output + ::core::marker::Send + 'static) -> Self {
+				self.#service_name = ::core::option::Option::Some(::std::boxed::Box::new(handler));
+				self
+			}
+		});
+		handlers_impl_items.extend(quote! {
+			#cfg
+			fn #service_name(&mut self, request: #service_name::ReceivedRequestHandle<F>, body: #request_type) -> Output {
+				match &mut self.#service_name {
+					::core::option::Option::Some(handler) => handler(request, body),
+					::core::option::Option::None => (self.fallback)(ReceivedRequestHandle::#variant_name(request, body)),
+				}
+			}
+		});
+	}
+
+	// If all services have `#[cfg]` attributes, they may all be disabled.
+	// Add a hidden variant to keep the enum generic over the format in that case.
+	if interface.services().iter().all(|x| !x.cfg().is_empty()) {
+		variant_tokens.extend(quote! {
+			#[doc(hidden)]
+			__Disabled(::core::convert::Infallible, ::core::marker::PhantomData<F>),
+		});
+		debug_tokens.extend(quote! {
+			Self::__Disabled(never, _) => match *never {},
+		});
+		service_name_arms.extend(quote! {
+			Self::__Disabled(never, _) => match *never {},
+		});
+		service_id_arms.extend(quote! {
+			Self::__Disabled(never, _) => match *never {},
+		});
+		handle_with_arms.extend(quote! {
+			Self::__Disabled(never, _) => match never {},
+		});
 	}
+	let catch_all_arm = cfg_catch_all_arm(interface.services().iter().map(|x| x.cfg()));
 
 	let enum_doc = format!("Enum for all possible incoming requests of the {} interface.", interface.name());
+	let handler_doc = format!("Handler for all possible incoming requests of the {} interface.", interface.name());
+	let handlers_doc = format!("Collection of request handlers for the {} interface, registered by service.", interface.name());
 	let visibility = interface.visibility();
 	item_tokens.extend(quote! {
 		#[doc = #enum_doc]
@@ -226,12 +786,99 @@ fn generate_received_request_enum(item_tokens: &mut TokenStream, fizyr_rpc: &syn
 			#variant_tokens
 		}
 
+		impl<F: #fizyr_rpc::format::Format> ReceivedRequestHandle<F> {
+			/// Get the name of the service of the request, as written in the interface definition.
+			pub fn service_name(&self) -> &'static str {
+				match self {
+					#service_name_arms
+					#catch_all_arm
+				}
+			}
+
+			/// Get the service ID of the request.
+			pub fn service_id(&self) -> i32 {
+				match self {
+					#service_id_arms
+					#catch_all_arm
+				}
+			}
+
+			/// Pass the request to the matching function of a request handler.
+			///
+			/// Returns the output of the handler function.
+			/// If the handler functions are `async`, the output is a future that still needs to be awaited.
+			pub fn handle_with<H: RequestHandler<F>>(self, handler: &mut H) -> H::Output {
+				match self {
+					#handle_with_arms
+					#catch_all_arm
+				}
+			}
+		}
+
 		impl<F: #fizyr_rpc::format::Format> ::core::fmt::Debug for ReceivedRequestHandle<F> {
 			fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
 				match self {
 					#debug_tokens
+					#catch_all_arm
 				}
 			}
 		}
+
+		#[doc = #handler_doc]
+		///
+		/// The trait has one function for each service, so forgetting to handle a service is a compile error.
+		/// Use [`ReceivedRequestHandle::handle_with()`] to call the function for a received request.
+		///
+		/// To register handlers one by one, use [`RequestHandlers`] instead.
+		#visibility trait RequestHandler<F: #fizyr_rpc::format::Format> {
+			/// The output of all handler functions.
+			///
+			/// This can be a future to handle requests asynchronously.
+			type Output;
+
+			#handler_trait_items
+		}
+
+		#[doc = #handlers_doc]
+		///
+		/// Handlers for individual services are registered with the `on_*` functions.
+		/// Requests for services without a registered handler are passed to the fallback handler.
+		///
+		/// To handle asynchronous requests, use a boxed future as `Output`.
+		#visibility struct RequestHandlers<F: #fizyr_rpc::format::Format, Output> {
+			#handlers_fields
+			fallback: ::std::boxed::Box<dyn ::core::ops::FnMut(ReceivedRequestHandle<F>) -> Output + ::core::marker::Send>,
+		}
+
+		impl<F: #fizyr_rpc::format::Format, Output> RequestHandlers<F, Output> {
+			/// Create a new set of request handlers with only a fallback handler.
+			///
+			/// The fallback handler is called for all requests without a registered handler.
+			pub fn new(fallback: impl ::core::ops::FnMut(ReceivedRequestHandle<F>) -> Output + ::core::marker::Send + 'static) -> Self {
+				Self {
+					#handlers_field_inits
+					fallback: ::std::boxed::Box::new(fallback),
+				}
+			}
+
+			#handlers_functions
+
+			/// Pass a received request to the matching handler.
+			pub fn handle(&mut self, request: ReceivedRequestHandle<F>) -> Output {
+				request.handle_with(self)
+			}
+		}
+
+		impl<F: #fizyr_rpc::format::Format, Output> ::core::fmt::Debug for RequestHandlers<F, Output> {
+			fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+				f.debug_struct(::core::any::type_name::<Self>()).finish_non_exhaustive()
+			}
+		}
+
+		impl<F: #fizyr_rpc::format::Format, Output> RequestHandler<F> for RequestHandlers<F, Output> {
+			type Output = Output;
+
+			#handlers_impl_items
+		}
 	})
 }
@@ -0,0 +1,255 @@
+use proc_macro2::{TokenStream, Span};
+use quote::quote;
+
+use crate::interface::parse::cooked::InterfaceDefinition;
+
+use super::{cfg_format_bound, to_doc_attrs, to_upper_camel_case};
+
+/// Generate a server handler trait and a runner that dispatches incoming messages to it.
+///
+/// `recv_message_where` is the where clause of the `recv_message_offloaded` function of the server struct.
+///
+/// Nothing is generated if the interface has no services and no streams.
+pub fn generate_server_runner(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, interface: &InterfaceDefinition, recv_message_where: &TokenStream) {
+	if interface.services().is_empty() && interface.streams().is_empty() {
+		return;
+	}
+
+	// Functions of the handler trait.
+	let mut trait_items = TokenStream::new();
+	// Where clause for the `run` function.
+	let mut run_where = TokenStream::new();
+	// Match arms for all received requests.
+	let mut request_arms = TokenStream::new();
+	// Match arms for all received stream messages.
+	let mut stream_arms = TokenStream::new();
+
+	for service in interface.services() {
+		let service_name = service.name();
+		let service_doc = to_doc_attrs(service.doc());
+		let variant_name = syn::Ident::new(&to_upper_camel_case(&service_name.to_string()), Span::call_site());
+		let cfg = service.cfg();
+		let request_type = service.request_type();
+		let response_type = service.response_type();
+
+		let error_type;
+		let send_error;
+		let send_panic;
+		let mut bounds = quote!(#fizyr_rpc::format::EncodeBody<#response_type>);
+		if let Some(service_error_type) = service.error_type() {
+			error_type = quote!(#fizyr_rpc::ServiceError<#service_error_type>);
+			bounds.extend(quote!(+ #fizyr_rpc::format::EncodeBody<#service_error_type>));
+			send_error = quote! {
+				::core::result::Result::Err(#fizyr_rpc::ServiceError::Service(e)) => request.send_error_response(&e).await,
+				::core::result::Result::Err(#fizyr_rpc::ServiceError::Rpc(e)) => request.send_error_message(&e.to_string()).await,
+			};
+			send_panic = quote!(write_handle.send_error_message("internal error: request handler panicked").await);
+		} else {
+			error_type = quote!(#fizyr_rpc::Error);
+			send_error = quote! {
+				::core::result::Result::Err(e) => request.send_error_response(&e.to_string()).await,
+			};
+			send_panic = quote!(write_handle.send_error_response("internal error: request handler panicked").await);
+		}
+		let trait_name = format!("__ServerRunnerService{}", variant_name);
+		let bound = cfg_format_bound(item_tokens, cfg, &trait_name, bounds);
+		run_where.extend(quote!(F: #bound,));
+
+		trait_items.extend(quote! {
+			#service_doc
+			///
+			/// The returned value is sent to the remote peer as response.
+			/// The request handle can be used to inspect the request and to send and receive update messages,
+			/// but the handler should not send the final response itself.
+			#cfg
+			fn #service_name<'a>(&'a self, request: &'a mut #service_name::ReceivedRequestHandle<F>, body: #request_type)
+				-> ::core::pin::Pin<::std::boxed::Box<dyn ::core::future::Future<Output = ::core::result::Result<#response_type, #error_type>> + ::core::marker::Send + 'a>>;
+		});
+
+		request_arms.extend(quote! {
+			#cfg
+			ReceivedRequestHandle::#variant_name(request, body) => {
+				let handler = handler.clone();
+				#fizyr_rpc::macros::spawn_handler(async move {
+					let write_handle = request.write_handle();
+					let result = #fizyr_rpc::macros::run_handler(async move {
+						let mut request = request;
+						let result = ServerHandler::<F>::#service_name(&*handler, &mut request, body).await;
+						(request, result)
+					}).await;
+
+					// The remote peer may have gone away already, so failing to send the response is not an error.
+					let _ = match result {
+						::core::option::Option::Some((request, result)) => match result {
+							::core::result::Result::Ok(response) => request.send_response(&response).await,
+							#send_error
+						},
+						::core::option::Option::None => #send_panic,
+					};
+				});
+			},
+		});
+	}
+
+	// If all services have `#[cfg]` attributes, the request enum has a hidden variant that can never be constructed.
+	if !interface.services().is_empty() && interface.services().iter().all(|x| !x.cfg().is_empty()) {
+		request_arms.extend(quote! {
+			ReceivedRequestHandle::__Disabled(never, _) => match never {},
+		});
+	}
+
+	for stream in interface.streams() {
+		let stream_name = stream.name();
+		let variant_name = syn::Ident::new(&to_upper_camel_case(&stream_name.to_string()), stream_name.span());
+		let fn_name = syn::Ident::new(&format!("on_{}", stream_name), Span::call_site());
+		let fn_doc = format!("Handle a `{}` stream message.", stream_name);
+		let cfg = stream.cfg();
+		let body_type = stream.body_type();
+
+		trait_items.extend(quote! {
+			#[doc = #fn_doc]
+			///
+			/// The default implementation ignores the message.
+			#cfg
+			fn #fn_name<'a>(&'a self, body: #body_type) -> ::core::pin::Pin<::std::boxed::Box<dyn ::core::future::Future<Output = ()> + ::core::marker::Send + 'a>> {
+				let _ = body;
+				::std::boxed::Box::pin(async {})
+			}
+		});
+
+		stream_arms.extend(quote! {
+			#cfg
+			StreamMessage::#variant_name(body) => {
+				let handler = handler.clone();
+				let _ = #fizyr_rpc::macros::run_handler(async move {
+					ServerHandler::<F>::#fn_name(&*handler, body).await
+				}).await;
+			},
+		});
+	}
+
+	if interface.forward_compatible() && !interface.streams().is_empty() {
+		stream_arms.extend(quote! {
+			StreamMessage::Unrecognized { .. } => (),
+		});
+	}
+
+	let mut message_arms = TokenStream::new();
+	if !interface.services().is_empty() {
+		message_arms.extend(quote! {
+			ReceivedMessage::Request(request) => match request {
+				#request_arms
+			},
+		});
+	}
+	if !interface.streams().is_empty() {
+		message_arms.extend(quote! {
+			ReceivedMessage::Stream(message) => match message {
+				#stream_arms
+			},
+		});
+	}
+
+	let visibility = interface.visibility();
+	let trait_doc = format!("Handler for all incoming messages of the {} interface, used by [`ServerRunner`].", interface.name());
+	let runner_doc = format!("Runner that passes all incoming messages of the {} interface to a [`ServerHandler`].", interface.name());
+	item_tokens.extend(quote! {
+		#[doc = #trait_doc]
+		///
+		/// The trait has one function for each service, so forgetting to handle a service is a compile error.
+		/// The functions for stream messages have a default implementation that ignores the message.
+		///
+		/// The handler is shared by all requests of the connection, so it can hold the state of the server.
+		/// Use interior mutability for state that needs to be modified by the handler.
+		#visibility trait ServerHandler<F: #fizyr_rpc::format::Format>: ::core::marker::Send + ::core::marker::Sync + 'static {
+			#trait_items
+		}
+
+		#[doc = #runner_doc]
+		///
+		/// Each request is handled in a separate task.
+		/// When the handler finishes, the returned value is sent as response, or as error response if the handler returned an error.
+		/// If the handler panics, an error response is sent to the remote peer as well.
+		///
+		/// Stream messages are handled one by one in the order they are received from the server.
+		/// The next message is not received until the handler for the previous stream message finished.
+		///
+		/// Unknown and invalid requests are answered with a "bad request" error response.
+		#visibility struct ServerRunner<F: #fizyr_rpc::format::Format, H> {
+			server: Server<F>,
+			handler: ::std::sync::Arc<H>,
+		}
+
+		impl<F: #fizyr_rpc::format::Format, H> ::core::fmt::Debug for ServerRunner<F, H> {
+			fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+				f.debug_struct(::core::any::type_name::<Self>())
+					.field("server", &self.server)
+					.finish_non_exhaustive()
+			}
+		}
+
+		impl<F: #fizyr_rpc::format::Format, H: ServerHandler<F>> ServerRunner<F, H> {
+			/// Create a new runner that passes all messages received by the server to the handler.
+			///
+			/// This enables automatic "bad request" responses on the server,
+			/// see [`Server::set_bad_request_responses()`].
+			pub fn new(server: Server<F>, handler: H) -> Self {
+				Self::with_shared_handler(server, ::std::sync::Arc::new(handler))
+			}
+
+			/// Create a new runner with a handler that is shared with other runners.
+			///
+			/// This can be used to serve multiple connections with the same handler and state.
+			pub fn with_shared_handler(mut server: Server<F>, handler: ::std::sync::Arc<H>) -> Self {
+				server.set_bad_request_responses(true);
+				Self { server, handler }
+			}
+
+			/// Get a reference to the handler.
+			pub fn handler(&self) -> &::std::sync::Arc<H> {
+				&self.handler
+			}
+
+			/// Get a reference to the server.
+			pub fn server(&self) -> &Server<F> {
+				&self.server
+			}
+
+			/// Get a mutable reference to the server.
+			///
+			/// This can be used to configure the server before calling [`Self::run()`].
+			pub fn server_mut(&mut self) -> &mut Server<F> {
+				&mut self.server
+			}
+
+			/// Consume the runner to get the server and the handler.
+			pub fn into_parts(self) -> (Server<F>, ::std::sync::Arc<H>) {
+				(self.server, self.handler)
+			}
+
+			/// Receive and handle messages until the connection is closed.
+			///
+			/// Messages are received with [`Server::recv_message_offloaded()`],
+			/// so large message bodies are decoded on a blocking thread if a decode offload threshold is set on the server.
+			/// Errors for individual messages do not stop the runner.
+			/// Requests that are still being handled when this function returns keep running in their own task.
+			pub async fn run(self)
+			where
+				#recv_message_where
+				#run_where
+			{
+				let Self { mut server, handler } = self;
+				loop {
+					let message = match server.recv_message_offloaded().await {
+						::core::result::Result::Ok(message) => message,
+						::core::result::Result::Err(e) if e.is_connection_aborted() => return,
+						::core::result::Result::Err(_) => continue,
+					};
+					match message {
+						#message_arms
+					}
+				}
+			}
+		}
+	});
+}
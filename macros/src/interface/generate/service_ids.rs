@@ -0,0 +1,108 @@
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+
+use crate::interface::parse::cooked::{CfgConditions, InterfaceDefinition, ServiceId, UpdateDefinition};
+use crate::util::WithSpan;
+
+/// Generate a module with a constant for the service ID of each service, update and stream message.
+///
+/// The constants for stream messages are in a nested `streams` module.
+/// The constants for updates are in a nested module for each service with updates,
+/// with separate `request_updates` and `response_updates` modules.
+pub fn generate_service_ids(item_tokens: &mut TokenStream, interface: &InterfaceDefinition) {
+	let mut service_ids = TokenStream::new();
+	for service in interface.services() {
+		let doc = format!("The service ID of the `{}` service.", service.name());
+		service_ids.extend(generate_const(service.name(), service.service_id(), service.cfg(), service.hidden().is_some(), &doc));
+
+		if service.request_updates().is_empty() && service.response_updates().is_empty() {
+			continue;
+		}
+
+		let mut update_ids = TokenStream::new();
+		generate_update_module(&mut update_ids, "request_updates", "request updates", service.request_updates());
+		generate_update_module(&mut update_ids, "response_updates", "response updates", service.response_updates());
+
+		let service_name = service.name();
+		let cfg = service.cfg();
+		let doc = format!("The service IDs of the updates of the `{}` service.", service.name());
+		service_ids.extend(quote! {
+			#[doc = #doc]
+			#cfg
+			pub mod #service_name {
+				#[allow(unused_imports)]
+				use super::*;
+
+				#update_ids
+			}
+		});
+	}
+
+	if !interface.streams().is_empty() {
+		let mut stream_ids = TokenStream::new();
+		for stream in interface.streams() {
+			let doc = format!("The service ID of the `{}` stream message.", stream.name());
+			stream_ids.extend(generate_const(stream.name(), stream.service_id(), stream.cfg(), stream.hidden().is_some(), &doc));
+		}
+		service_ids.extend(quote! {
+			/// The service IDs of the stream messages.
+			pub mod streams {
+				#[allow(unused_imports)]
+				use super::*;
+
+				#stream_ids
+			}
+		});
+	}
+
+	let visibility = interface.visibility();
+	let doc = format!("The service IDs of the {} interface.", interface.name());
+	item_tokens.extend(quote! {
+		#[doc = #doc]
+		///
+		/// This allows code that uses a raw peer handle to refer to the services by name.
+		#visibility mod service_ids {
+			#[allow(unused_imports)]
+			use super::*;
+
+			#service_ids
+		}
+	});
+}
+
+/// Generate a module with the service IDs of the request updates or the response updates of a service.
+fn generate_update_module(item_tokens: &mut TokenStream, module_name: &str, kind: &str, updates: &[UpdateDefinition]) {
+	if updates.is_empty() {
+		return;
+	}
+
+	let mut update_ids = TokenStream::new();
+	for update in updates {
+		let doc = format!("The service ID of the `{}` update.", update.name());
+		update_ids.extend(generate_const(update.name(), update.service_id(), update.cfg(), update.hidden().is_some(), &doc));
+	}
+
+	let module_name = syn::Ident::new(module_name, Span::call_site());
+	let doc = format!("The service IDs of the {kind}.");
+	item_tokens.extend(quote! {
+		#[doc = #doc]
+		pub mod #module_name {
+			#[allow(unused_imports)]
+			use super::*;
+
+			#update_ids
+		}
+	});
+}
+
+/// Generate a constant for a single service ID.
+fn generate_const(name: &syn::Ident, service_id: &WithSpan<ServiceId>, cfg: &CfgConditions, hidden: bool, doc: &str) -> TokenStream {
+	let const_name = syn::Ident::new(&name.to_string().to_ascii_uppercase(), name.span());
+	let hidden = hidden.then(|| quote!(#[doc(hidden)]));
+	quote! {
+		#[doc = #doc]
+		#hidden
+		#cfg
+		pub const #const_name: i32 = #service_id;
+	}
+}
@@ -3,7 +3,8 @@ use quote::quote;
 
 use crate::interface::parse::cooked::{InterfaceDefinition, ServiceDefinition, UpdateDefinition};
 
-use super::{to_doc_attrs, is_unit_type, to_upper_camel_case, message_enum::generate_message_enum};
+use super::{cfg_format_bound, to_doc_attrs, is_unit_type, service_id_pattern, to_upper_camel_case, message_enum::generate_message_enum};
+use super::interface_struct::service_signature;
 
 #[derive(Debug, Eq, PartialEq)]
 enum UpdateKind {
@@ -14,26 +15,35 @@ enum UpdateKind {
 /// Generate the support types and function definitions for each service.
 pub fn generate_services(item_tokens: &mut TokenStream, client_impl_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, interface: &InterfaceDefinition) {
 	for service in interface.services() {
-		generate_service(item_tokens, client_impl_tokens, fizyr_rpc, service, interface.visibility());
+		generate_service(item_tokens, client_impl_tokens, fizyr_rpc, service, interface.visibility(), interface.forward_compatible());
 	}
 }
 
 /// Generate the support types and function definitions for each service.
 #[allow(clippy::needless_late_init)]
-fn generate_service(item_tokens: &mut TokenStream, client_impl_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, service: &ServiceDefinition, visibility: &syn::Visibility) {
+fn generate_service(
+	item_tokens: &mut TokenStream,
+	client_impl_tokens: &mut TokenStream,
+	fizyr_rpc: &syn::Ident,
+	service: &ServiceDefinition,
+	visibility: &syn::Visibility,
+	forward_compatible: bool,
+) {
 	let service_name = service.name();
 	let service_doc = to_doc_attrs(service.doc());
+	let service_example = generate_client_example(service);
 	let service_id = service.service_id();
+	let cfg = service.cfg();
 
 	let request_type = service.request_type();
 	let request_param;
 	let request_body;
 	if is_unit_type(request_type) {
 		request_param = None;
-		request_body = quote!(F::encode_body(&()))
+		request_body = quote!(#fizyr_rpc::format::encode_body_instrumented::<F, _>(#service_id, &()))
 	} else {
 		request_param = Some(quote!(request: &#request_type));
-		request_body = quote!(F::encode_body(request))
+		request_body = quote!(#fizyr_rpc::format::encode_body_instrumented::<F, _>(#service_id, request))
 	}
 
 	let response_type = service.response_type();
@@ -41,33 +51,32 @@ fn generate_service(item_tokens: &mut TokenStream, client_impl_tokens: &mut Toke
 
 	// Service without updates, so directly return the response (asynchronously).
 	if service.request_updates().is_empty() && service.response_updates().is_empty() {
+		let DecodeResponse { error_type, error_bound, decode_response } = generate_decode_response(fizyr_rpc, service);
 		client_impl_tokens.extend(quote! {
 			#service_doc
+			#service_example
+			#cfg
 			#[allow(clippy::ptr_arg)]
-			pub async fn #service_name(&self, #request_param) -> ::core::result::Result<#response_type, #fizyr_rpc::Error>
+			pub async fn #service_name(&self, #request_param) -> ::core::result::Result<#response_type, #error_type>
 			where
 				F: #fizyr_rpc::format::EncodeBody<#request_type>,
 				F: #fizyr_rpc::format::DecodeBody<#response_type>,
+				#error_bound
 			{
 				let request_body = #request_body.map_err(#fizyr_rpc::Error::encode_failed)?;
-				let mut request = self.peer.send_request(#service_id, request_body).await?;
+				let type_fingerprint = self.type_fingerprints.then_some(#service_name::TYPE_FINGERPRINT);
+				let mut request = self.peer.send_request_with_type_fingerprint(#service_id, request_body, self.deadline, type_fingerprint).await?;
 
 				let response = request.recv_response().await?;
-				if response.header.service_id == #fizyr_rpc::service_id::ERROR {
-					use #fizyr_rpc::Body;
-					let message = response.body
-						.into_error()
-						.map_err(|e| #fizyr_rpc::Error::decode_failed(::std::boxed::Box::new(e)))?;
-					::core::result::Result::Err(#fizyr_rpc::Error::remote_error(message))
-				} else {
-					F::decode_body(response.body).map_err(#fizyr_rpc::Error::decode_failed)
-				}
+				#decode_response
 			}
 		})
 	} else {
-		generate_sent_request(&mut service_item_tokens, fizyr_rpc, service);
+		generate_sent_request(&mut service_item_tokens, fizyr_rpc, service, forward_compatible);
 		client_impl_tokens.extend(quote! {
 			#service_doc
+			#service_example
+			#cfg
 			#[allow(clippy::ptr_arg)]
 			pub async fn #service_name(&self, #request_param) -> ::core::result::Result<#service_name::SentRequestHandle<F>, #fizyr_rpc::Error>
 			where
@@ -75,32 +84,355 @@ fn generate_service(item_tokens: &mut TokenStream, client_impl_tokens: &mut Toke
 				F: #fizyr_rpc::format::DecodeBody<#response_type>,
 			{
 				let request_body = #request_body.map_err(#fizyr_rpc::Error::encode_failed)?;
-				let mut request = self.peer.send_request(#service_id, request_body).await?;
-				::core::result::Result::Ok(#service_name::SentRequestHandle { request })
+				let type_fingerprint = self.type_fingerprints.then_some(#service_name::TYPE_FINGERPRINT);
+				let mut request = self.peer.send_request_with_type_fingerprint(#service_id, request_body, self.deadline, type_fingerprint).await?;
+				let decode_context = self.decode_context.clone();
+				let error_decoder = self.error_decoder.clone();
+				::core::result::Result::Ok(#service_name::SentRequestHandle { request, decode_context, error_decoder })
 			}
 		});
 
 	}
 
-	generate_received_request(&mut service_item_tokens, fizyr_rpc, service);
+	generate_request_builder(&mut service_item_tokens, client_impl_tokens, fizyr_rpc, service);
+	generate_received_request(&mut service_item_tokens, fizyr_rpc, service, forward_compatible);
+	generate_forward_function(client_impl_tokens, fizyr_rpc, service);
 
 	let mod_doc = format!("Support types for the `{}` service.", service.name());
+	let type_fingerprint_signature = service_signature(service);
 	item_tokens.extend(quote! {
 		#[doc = #mod_doc]
+		#cfg
 		#visibility mod #service_name {
 			#[allow(unused_imports)]
 			use super::*;
 
+			/// The type fingerprint of the service.
+			///
+			/// The fingerprint is a hash of the service ID, the name and the body types of the service and its updates,
+			/// as written in the interface definition.
+			/// It is sent along with requests by clients with type fingerprints enabled,
+			/// and checked by the server before the request body is decoded.
+			pub const TYPE_FINGERPRINT: u64 = #fizyr_rpc::negotiation::interface_hash(#type_fingerprint_signature);
+
 			#service_item_tokens
 		}
 	});
 }
 
+/// Generate a client-side request builder for a service with a `#[builder]` attribute.
+///
+/// The builder starts with the default value of the request type.
+/// It has a setter for each field listed in the attribute, and a `send()` function that calls the normal client function.
+fn generate_request_builder(item_tokens: &mut TokenStream, client_impl_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, service: &ServiceDefinition) {
+	let fields = match service.builder_fields() {
+		Some(x) => x,
+		None => return,
+	};
+
+	let service_name = service.name();
+	let request_type = service.request_type();
+	let response_type = service.response_type();
+	let cfg = service.cfg();
+
+	let mut setters = TokenStream::new();
+	for field in fields {
+		let name = field.name();
+		let field_type = field.field_type();
+		let doc = format!("Set the `{}` field of the request.", name);
+		setters.extend(quote! {
+			#[doc = #doc]
+			pub fn #name(mut self, #name: #field_type) -> Self {
+				self.request.#name = #name;
+				self
+			}
+		});
+	}
+
+	let (return_type, error_bound) = if service.request_updates().is_empty() && service.response_updates().is_empty() {
+		let DecodeResponse { error_type, error_bound, .. } = generate_decode_response(fizyr_rpc, service);
+		(quote!(::core::result::Result<#response_type, #error_type>), error_bound)
+	} else {
+		(quote!(::core::result::Result<SentRequestHandle<F>, #fizyr_rpc::Error>), TokenStream::new())
+	};
+
+	let builder_doc = format!("Builder for the request of the `{}` service.", service_name);
+	let builder_fn_doc = format!("Build and send a `{}` request with a builder.", service_name);
+	let builder_fn_name = syn::Ident::new(&format!("{}_builder", service_name), service_name.span());
+	item_tokens.extend(quote! {
+		#[doc = #builder_doc]
+		///
+		/// The builder starts with the default value of the request.
+		/// Set the fields you need and call [`Self::send()`] to send the request.
+		pub struct RequestBuilder<'a, F: #fizyr_rpc::format::Format> {
+			pub(super) client: &'a Client<F>,
+			pub(super) request: #request_type,
+		}
+
+		impl<'a, F: #fizyr_rpc::format::Format> RequestBuilder<'a, F> {
+			#setters
+
+			/// Get the request that was built, without sending it.
+			pub fn into_request(self) -> #request_type {
+				self.request
+			}
+
+			/// Send the request.
+			///
+			/// This is the same as passing the request to the normal client function for the service.
+			pub async fn send(self) -> #return_type
+			where
+				F: #fizyr_rpc::format::EncodeBody<#request_type>,
+				F: #fizyr_rpc::format::DecodeBody<#response_type>,
+				#error_bound
+			{
+				self.client.#service_name(&self.request).await
+			}
+		}
+
+		impl<F: #fizyr_rpc::format::Format> ::core::fmt::Debug for RequestBuilder<'_, F> {
+			fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+				f.debug_struct(::core::any::type_name::<Self>())
+					.field("client", &self.client)
+					.finish()
+			}
+		}
+	});
+
+	client_impl_tokens.extend(quote! {
+		#[doc = #builder_fn_doc]
+		///
+		/// The builder starts with the default value of the request.
+		/// This is an alternative to constructing the request yourself.
+		#cfg
+		pub fn #builder_fn_name(&self) -> #service_name::RequestBuilder<'_, F> {
+			#service_name::RequestBuilder {
+				client: self,
+				request: ::core::default::Default::default(),
+			}
+		}
+	});
+}
+
+/// Generate an example section for the documentation of a client function.
+///
+/// The example shows how to call the service, send request updates and handle response updates.
+/// The comments in the example are taken from the documentation of the updates.
+///
+/// The macro does not know the module path of the interface or how to construct the request,
+/// so the example can not be compiled as doctest.
+/// It is emitted as ignored Rust code instead, with a sentence explaining the variables that it does not declare.
+fn generate_client_example(service: &ServiceDefinition) -> TokenStream {
+	let service_name = service.name();
+	let request_arg = match is_unit_type(service.request_type()) {
+		true => String::new(),
+		false => "&request".to_string(),
+	};
+	let response_binding = match is_unit_type(service.response_type()) {
+		true => "",
+		false => "let response = ",
+	};
+	let request_update = service.request_updates().iter().find(|update| update.hidden().is_none());
+
+	let mut variables = vec!["`client` is a connected `Client`".to_string()];
+	if !request_arg.is_empty() {
+		variables.push("`request` is the request to send".into());
+	}
+	if let Some(update) = request_update.filter(|update| !is_unit_type(update.body_type())) {
+		variables.push(format!("`{0}` is the `{0}` update to send", update.name()));
+	}
+	let last_variable = variables.pop().unwrap();
+	let variables = match variables.is_empty() {
+		true => last_variable,
+		false => format!("{} and {}", variables.join(", "), last_variable),
+	};
+
+	let mut lines = vec![
+		String::new(),
+		"# Example".to_string(),
+		format!("In this example, {}.", variables),
+		"```rust,ignore".to_string(),
+	];
+
+	if service.request_updates().is_empty() && service.response_updates().is_empty() {
+		lines.push(format!("{}client.{}({}).await?;", response_binding, service_name, request_arg));
+	} else {
+		lines.push(format!("let mut sent_request = client.{}({}).await?;", service_name, request_arg));
+
+		if let Some(update) = request_update {
+			let update_arg = match is_unit_type(update.body_type()) {
+				true => String::new(),
+				false => format!("&{}", update.name()),
+			};
+			lines.push(String::new());
+			lines.extend(first_doc_line(update.doc()).map(|doc| format!("// {}", doc)));
+			lines.push(format!("sent_request.send_{}_update({}).await?;", update.name(), update_arg));
+		}
+
+		if !service.response_updates().is_empty() {
+			lines.push(String::new());
+			lines.push("while let Some(update) = sent_request.recv_update().await {".into());
+			lines.push("    match update? {".into());
+			for update in service.response_updates() {
+				if update.hidden().is_some() {
+					continue;
+				}
+				let variant_name = to_upper_camel_case(&update.name().to_string());
+				lines.push(format!("        {}::ResponseUpdate::{}({}) => {{", service_name, variant_name, update.name()));
+				lines.extend(first_doc_line(update.doc()).map(|doc| format!("            // {}", doc)));
+				lines.push("        },".into());
+			}
+			if service.response_updates().iter().any(|update| update.hidden().is_some()) {
+				lines.push("        _ => (),".into());
+			}
+			lines.push("    }".into());
+			lines.push("}".into());
+		}
+
+		lines.push(String::new());
+		lines.push(format!("{}sent_request.recv_response().await?;", response_binding));
+	}
+	lines.push("```".into());
+
+	let mut tokens = TokenStream::new();
+	for line in lines {
+		let line = match line.is_empty() {
+			true => line,
+			false => format!(" {}", line),
+		};
+		tokens.extend(quote!(#[doc = #line]));
+	}
+	tokens
+}
+
+/// Get the first non-empty line of a documentation comment, without leading and trailing whitespace.
+fn first_doc_line(doc: &[crate::util::WithSpan<String>]) -> Option<&str> {
+	doc.iter()
+		.map(|line| line.value.trim())
+		.find(|line| !line.is_empty())
+}
+
+/// Generate a client function that forwards a received request to the remote peer of the client.
+///
+/// The request, updates and response are re-encoded with the format of the other side.
+fn generate_forward_function(client_impl_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, service: &ServiceDefinition) {
+	let service_name = service.name();
+	let service_id = service.service_id();
+	let cfg = service.cfg();
+	let request_type = service.request_type();
+	let response_type = service.response_type();
+	let function_name = syn::Ident::new(&format!("forward_{}", service_name), Span::call_site());
+	let doc = format!("Forward a received `{}` request to the remote peer of this client.", service_name);
+
+	let mut where_clause = quote! {
+		FIn: #fizyr_rpc::format::Format,
+		F: #fizyr_rpc::format::EncodeBody<#request_type>,
+		F: #fizyr_rpc::format::DecodeBody<#response_type>,
+		FIn: #fizyr_rpc::format::EncodeBody<#response_type>,
+	};
+
+	let decode_error = match service.error_type() {
+		None => TokenStream::new(),
+		Some(error_type) => {
+			where_clause.extend(quote! {
+				F: #fizyr_rpc::format::DecodeBody<#error_type>,
+				FIn: #fizyr_rpc::format::EncodeBody<#error_type>,
+			});
+			quote! {
+				if response.header.service_id == #fizyr_rpc::service_id::SERVICE_ERROR {
+					let error: #error_type = #fizyr_rpc::format::decode_body_instrumented::<F, _>(response.header.service_id, response.body, &decode_context).map_err(#fizyr_rpc::Error::decode_failed)?;
+					let body = #fizyr_rpc::format::encode_body_instrumented::<FIn, _>(#fizyr_rpc::service_id::SERVICE_ERROR, &error).map_err(#fizyr_rpc::Error::encode_failed)?;
+					return ::core::result::Result::Ok((#fizyr_rpc::service_id::SERVICE_ERROR, body));
+				}
+			}
+		},
+	};
+
+	let convert_request_update = match service.request_updates().is_empty() {
+		true => quote! {
+			|update: #fizyr_rpc::Message<FIn::Body>| ::core::result::Result::Err(#fizyr_rpc::Error::unexpected_service_id(update.header.service_id))
+		},
+		false => {
+			where_clause.extend(quote! {
+				#service_name::RequestUpdate: #fizyr_rpc::format::FromMessage<FIn> + #fizyr_rpc::format::ToMessage<F>,
+			});
+			quote! {
+				|update: #fizyr_rpc::Message<FIn::Body>| {
+					let update: #service_name::RequestUpdate = FIn::decode_message(update)?;
+					F::encode_message(&update).map_err(#fizyr_rpc::Error::encode_failed)
+				}
+			}
+		},
+	};
+
+	let convert_response_update = match service.response_updates().is_empty() {
+		true => quote! {
+			|update: #fizyr_rpc::Message<F::Body>| ::core::result::Result::Err(#fizyr_rpc::Error::unexpected_service_id(update.header.service_id))
+		},
+		false => {
+			where_clause.extend(quote! {
+				#service_name::ResponseUpdate: #fizyr_rpc::format::FromMessage<F> + #fizyr_rpc::format::ToMessage<FIn>,
+			});
+			quote! {
+				|update: #fizyr_rpc::Message<F::Body>| {
+					let update: #service_name::ResponseUpdate = F::decode_message(update)?;
+					FIn::encode_message(&update).map_err(#fizyr_rpc::Error::encode_failed)
+				}
+			}
+		},
+	};
+
+	client_impl_tokens.extend(quote! {
+		#[doc = #doc]
+		///
+		/// The request is sent with the deadline of the received request.
+		/// Request updates, response updates and the final response are relayed between the two peers until the request is finished,
+		/// and re-encoded with the format of the other side.
+		/// Errors are reported to the original requester before they are returned.
+		///
+		/// See `fizyr_rpc::forward_request()` for more details.
+		#cfg
+		#[allow(clippy::ptr_arg)]
+		pub async fn #function_name<FIn>(&self, request: #service_name::ReceivedRequestHandle<FIn>, body: &#request_type) -> ::core::result::Result<(), #fizyr_rpc::Error>
+		where
+			#where_clause
+		{
+			let #service_name::ReceivedRequestHandle { request, .. } = request;
+			let request_out = async {
+				let request_body = #fizyr_rpc::format::encode_body_instrumented::<F, _>(#service_id, body).map_err(#fizyr_rpc::Error::encode_failed)?;
+				let type_fingerprint = self.type_fingerprints.then_some(#service_name::TYPE_FINGERPRINT);
+				self.peer.send_request_with_type_fingerprint(#service_id, request_body, request.deadline(), type_fingerprint).await
+			};
+			let request_out = match request_out.await {
+				::core::result::Result::Ok(x) => x,
+				::core::result::Result::Err(e) => {
+					let _ = request.send_error_response(&e.to_string()).await;
+					return ::core::result::Result::Err(e);
+				},
+			};
+
+			let decode_context = self.decode_context.clone();
+			#fizyr_rpc::forward_request(
+				request,
+				request_out,
+				#convert_request_update,
+				#convert_response_update,
+				move |response: #fizyr_rpc::Message<F::Body>| {
+					#decode_error
+					let response: #response_type = #fizyr_rpc::format::decode_body_instrumented::<F, _>(response.header.service_id, response.body, &decode_context).map_err(#fizyr_rpc::Error::decode_failed)?;
+					let body = #fizyr_rpc::format::encode_body_instrumented::<FIn, _>(#service_id, &response).map_err(#fizyr_rpc::Error::encode_failed)?;
+					::core::result::Result::Ok((#service_id, body))
+				},
+			).await
+		}
+	});
+}
+
 /// Generate a type for the sent request for a specific service.
 ///
 /// Only used for service calls that have update messages.
 /// Otherwise, the return type of a service call will simply be the response message.
-fn generate_sent_request(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, service: &ServiceDefinition) {
+fn generate_sent_request(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, service: &ServiceDefinition, forward_compatible: bool) {
 	let service_name = service.name();
 	let mut read_handle_impl_tokens = TokenStream::new();
 	let mut write_handle_impl_tokens = TokenStream::new();
@@ -116,6 +448,7 @@ fn generate_sent_request(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident,
 		},
 	};
 
+	let DecodeResponse { error_type, error_bound, decode_response } = generate_decode_response(fizyr_rpc, service);
 	read_handle_impl_tokens.extend(quote! {
 		/// Receive the final response.
 		///
@@ -123,20 +456,13 @@ fn generate_sent_request(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident,
 		/// The update message will remain in the message queue and must be read before the response can be received.
 		///
 		#doc_recv_update
-		pub async fn recv_response(&mut self) -> ::core::result::Result<#response_type, #fizyr_rpc::Error>
+		pub async fn recv_response(&mut self) -> ::core::result::Result<#response_type, #error_type>
 		where
 			F: #fizyr_rpc::format::DecodeBody<#response_type>,
+			#error_bound
 		{
 			let response = self.request.recv_response().await?;
-			if response.header.service_id == #fizyr_rpc::service_id::ERROR {
-				use #fizyr_rpc::Body;
-				let message = response.body
-					.into_error()
-					.map_err(|e| #fizyr_rpc::Error::decode_failed(::std::boxed::Box::new(e)))?;
-				::core::result::Result::Err(#fizyr_rpc::Error::remote_error(message))
-			} else {
-				F::decode_body(response.body).map_err(#fizyr_rpc::Error::decode_failed)
-			}
+			#decode_response
 		}
 	});
 
@@ -147,6 +473,7 @@ fn generate_sent_request(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident,
 			service.request_updates(),
 			&syn::Ident::new("RequestUpdate", Span::call_site()),
 			&format!("A request update for the {} service", service.name()),
+			forward_compatible,
 		);
 		generate_send_update_functions(&mut write_handle_impl_tokens, fizyr_rpc, &quote!(#service_name::RequestUpdate), service.request_updates());
 	}
@@ -158,8 +485,12 @@ fn generate_sent_request(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident,
 			service.response_updates(),
 			&syn::Ident::new("ResponseUpdate", Span::call_site()),
 			&format!("A response update for the {} service", service.name()),
+			forward_compatible,
 		);
-		generate_recv_update_function(&mut read_handle_impl_tokens, fizyr_rpc, service.response_updates(), UpdateKind::ResponseUpdate);
+		generate_recv_update_function(item_tokens, &mut read_handle_impl_tokens, fizyr_rpc, service.response_updates(), UpdateKind::ResponseUpdate, forward_compatible);
+		for update in service.response_updates().iter().filter(|update| update.stream()) {
+			generate_update_stream(item_tokens, &mut read_handle_impl_tokens, fizyr_rpc, update, forward_compatible);
+		}
 	}
 
 	let handle_doc = format!("Read/write handle for a sent request for the `{}` service.", service.name());
@@ -169,6 +500,8 @@ fn generate_sent_request(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident,
 		#[doc = #handle_doc]
 		pub struct SentRequestHandle<F: #fizyr_rpc::format::Format> {
 			pub(super) request: #fizyr_rpc::SentRequestHandle<F::Body>,
+			pub(super) decode_context: #fizyr_rpc::format::DecodeContext,
+			pub(super) error_decoder: ::core::option::Option<::std::sync::Arc<dyn #fizyr_rpc::ErrorDecoder>>,
 		}
 
 		#[doc = #write_handle_doc]
@@ -228,6 +561,28 @@ fn generate_sent_request(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident,
 				self.request.service_id()
 			}
 
+			/// Attach application data to the request handle.
+			///
+			/// The handle holds at most one value, so any previously attached data is dropped.
+			pub fn set_user_data<T: ::core::marker::Send + ::core::marker::Sync + 'static>(&mut self, data: T) {
+				self.request.set_user_data(data)
+			}
+
+			/// Get a reference to the attached application data, if it has type `T`.
+			pub fn user_data<T: ::core::marker::Send + ::core::marker::Sync + 'static>(&self) -> ::core::option::Option<&T> {
+				self.request.user_data()
+			}
+
+			/// Get a mutable reference to the attached application data, if it has type `T`.
+			pub fn user_data_mut<T: ::core::marker::Send + ::core::marker::Sync + 'static>(&mut self) -> ::core::option::Option<&mut T> {
+				self.request.user_data_mut()
+			}
+
+			/// Remove the attached application data and return it, if it has type `T`.
+			pub fn take_user_data<T: ::core::marker::Send + ::core::marker::Sync + 'static>(&mut self) -> ::core::option::Option<T> {
+				self.request.take_user_data()
+			}
+
 			/// Get a write handle for the sent request.
 			///
 			/// The write handle can be cloned and sent to other threads freely,
@@ -274,7 +629,7 @@ fn generate_sent_request(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident,
 	});
 }
 
-fn generate_received_request(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, service: &ServiceDefinition) {
+fn generate_received_request(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, service: &ServiceDefinition, forward_compatible: bool) {
 	let response_type = service.response_type();
 	let service_name = service.name();
 	let service_id = service.service_id();
@@ -285,9 +640,39 @@ fn generate_received_request(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ide
 		generate_send_update_functions(&mut write_handle_impl_tokens, fizyr_rpc, &quote!(#service_name::ResponseUpdate), service.response_updates());
 	}
 	if !service.request_updates().is_empty() {
-		generate_recv_update_function(&mut read_handle_impl_tokens, fizyr_rpc, service.request_updates(), UpdateKind::RequestUpdate);
+		generate_recv_update_function(item_tokens, &mut read_handle_impl_tokens, fizyr_rpc, service.request_updates(), UpdateKind::RequestUpdate, forward_compatible);
 	}
 
+	let send_error_response = match service.error_type() {
+		None => quote! {
+			/// Send the final response.
+			pub async fn send_error_response(&self, error: &str) -> ::core::result::Result<(), #fizyr_rpc::Error> {
+				self.request.send_error_response(error).await
+			}
+		},
+		Some(error_type) => quote! {
+			/// Send a structured service error as final response.
+			///
+			/// The error is encoded with the same format as regular responses.
+			/// Generated clients report it as `ServiceError::Service`.
+			#[allow(clippy::ptr_arg)]
+			pub async fn send_error_response(&self, error: &#error_type) -> ::core::result::Result<(), #fizyr_rpc::Error>
+			where
+				F: #fizyr_rpc::format::EncodeBody<#error_type>,
+			{
+				let encoded = #fizyr_rpc::format::encode_body_instrumented::<F, _>(#fizyr_rpc::service_id::SERVICE_ERROR, error).map_err(#fizyr_rpc::Error::encode_failed)?;
+				self.request.send_response(#fizyr_rpc::service_id::SERVICE_ERROR, encoded).await
+			}
+
+			/// Send a plain error message as final response.
+			///
+			/// Generated clients report it as `ServiceError::Rpc` with a remote error.
+			pub async fn send_error_message(&self, message: &str) -> ::core::result::Result<(), #fizyr_rpc::Error> {
+				self.request.send_error_response(message).await
+			}
+		},
+	};
+
 	write_handle_impl_tokens.extend(quote! {
 		/// Send the final response.
 		#[allow(clippy::ptr_arg)]
@@ -295,14 +680,19 @@ fn generate_received_request(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ide
 		where
 			F: #fizyr_rpc::format::EncodeBody<#response_type>,
 		{
-			let encoded = F::encode_body(response).map_err(#fizyr_rpc::Error::encode_failed)?;
+			let encoded = #fizyr_rpc::format::encode_body_instrumented::<F, _>(#service_id, response).map_err(#fizyr_rpc::Error::encode_failed)?;
 			let _response = self.request.send_response(#service_id, encoded).await?;
 			::core::result::Result::Ok(())
 		}
 
-		/// Send the final response.
-		pub async fn send_error_response(&self, error: &str) -> ::core::result::Result<(), #fizyr_rpc::Error> {
-			self.request.send_error_response(error).await
+		#send_error_response
+
+		/// Send the final response indicating that the service is temporarily unavailable.
+		///
+		/// Generated clients report this response as an error with a retry hint.
+		/// The retry hint is available through `Error::as_retry_after()`.
+		pub async fn send_retry_after(&self, retry_after: ::core::time::Duration, message: &str) -> ::core::result::Result<(), #fizyr_rpc::Error> {
+			self.request.send_retry_after(retry_after, message).await
 		}
 	});
 
@@ -312,6 +702,8 @@ fn generate_received_request(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ide
 		#[doc = #handle_doc]
 		pub struct ReceivedRequestHandle<F: #fizyr_rpc::format::Format> {
 			pub(super) request: #fizyr_rpc::ReceivedRequestHandle<F::Body>,
+			pub(super) decode_duration: ::core::time::Duration,
+			pub(super) decode_context: #fizyr_rpc::format::DecodeContext,
 		}
 
 		#[doc = #write_handle_doc]
@@ -380,6 +772,66 @@ fn generate_received_request(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ide
 				self.request.service_id()
 			}
 
+			/// Get the time when the request message was read from the transport.
+			///
+			/// This can be used to measure how long the request was queued before it was handled.
+			pub fn received_at(&self) -> ::std::time::Instant {
+				self.request.received_at()
+			}
+
+			/// Get the time it took to decode the request body.
+			pub fn decode_duration(&self) -> ::core::time::Duration {
+				self.decode_duration
+			}
+
+			/// Get the deadline of the request, if the remote peer sent one.
+			///
+			/// After the deadline, the remote peer is no longer interested in the response.
+			/// Pass the deadline on to requests that are sent to handle this request,
+			/// for example with `Client::set_deadline()`.
+			pub fn deadline(&self) -> ::core::option::Option<::std::time::Instant> {
+				self.request.deadline()
+			}
+
+			/// Get the annotations that were attached to the request by the interceptor of the peer.
+			pub fn annotations(&self) -> &#fizyr_rpc::Annotations {
+				self.request.annotations()
+			}
+
+			/// Get mutable access to the annotations of the request.
+			pub fn annotations_mut(&mut self) -> &mut #fizyr_rpc::Annotations {
+				self.request.annotations_mut()
+			}
+
+			/// Get the application context of the connection the request was received on, if it has type `T`.
+			///
+			/// See `ConnectionContext` for more details.
+			pub fn context<T: ::core::marker::Send + ::core::marker::Sync + 'static>(&self) -> ::core::option::Option<::std::sync::Arc<T>> {
+				self.request.context()
+			}
+
+			/// Attach application data to the request handle.
+			///
+			/// The handle holds at most one value, so any previously attached data is dropped.
+			pub fn set_user_data<T: ::core::marker::Send + ::core::marker::Sync + 'static>(&mut self, data: T) {
+				self.request.set_user_data(data)
+			}
+
+			/// Get a reference to the attached application data, if it has type `T`.
+			pub fn user_data<T: ::core::marker::Send + ::core::marker::Sync + 'static>(&self) -> ::core::option::Option<&T> {
+				self.request.user_data()
+			}
+
+			/// Get a mutable reference to the attached application data, if it has type `T`.
+			pub fn user_data_mut<T: ::core::marker::Send + ::core::marker::Sync + 'static>(&mut self) -> ::core::option::Option<&mut T> {
+				self.request.user_data_mut()
+			}
+
+			/// Remove the attached application data and return it, if it has type `T`.
+			pub fn take_user_data<T: ::core::marker::Send + ::core::marker::Sync + 'static>(&mut self) -> ::core::option::Option<T> {
+				self.request.take_user_data()
+			}
+
 			/// Get a write handle for the received request.
 			///
 			/// The write handle can be cloned and sent to other threads freely,
@@ -435,6 +887,53 @@ fn generate_received_request(item_tokens: &mut TokenStream, fizyr_rpc: &syn::Ide
 	})
 }
 
+/// Tokens for decoding the final response of a service call.
+struct DecodeResponse {
+	/// The error type of the service call.
+	error_type: TokenStream,
+
+	/// Additional trait bounds needed to decode the response.
+	error_bound: TokenStream,
+
+	/// Statements that turn the raw `response` into the result of the service call.
+	decode_response: TokenStream,
+}
+
+/// Generate the code to decode the final response of a service call.
+///
+/// If the service declares an error type, responses with service ID `SERVICE_ERROR` are decoded as that type.
+fn generate_decode_response(fizyr_rpc: &syn::Ident, service: &ServiceDefinition) -> DecodeResponse {
+	match service.error_type() {
+		None => DecodeResponse {
+			error_type: quote!(#fizyr_rpc::Error),
+			error_bound: TokenStream::new(),
+			decode_response: quote! {
+				let response = response.check_error_response().map_err(|e| match &self.error_decoder {
+					::core::option::Option::Some(decoder) => e.decode_remote_error(decoder.as_ref()),
+					::core::option::Option::None => e,
+				})?;
+				#fizyr_rpc::format::decode_body_instrumented::<F, _>(response.header.service_id, response.body, &self.decode_context).map_err(#fizyr_rpc::Error::decode_failed)
+			},
+		},
+		Some(error_type) => DecodeResponse {
+			error_type: quote!(#fizyr_rpc::ServiceError<#error_type>),
+			error_bound: quote!(F: #fizyr_rpc::format::DecodeBody<#error_type>,),
+			decode_response: quote! {
+				if response.header.service_id == #fizyr_rpc::service_id::SERVICE_ERROR {
+					let error = #fizyr_rpc::format::decode_body_instrumented::<F, _>(response.header.service_id, response.body, &self.decode_context).map_err(#fizyr_rpc::Error::decode_failed)?;
+					return ::core::result::Result::Err(#fizyr_rpc::ServiceError::Service(error));
+				}
+				let response = response.check_error_response().map_err(|e| match &self.error_decoder {
+					::core::option::Option::Some(decoder) => e.decode_remote_error(decoder.as_ref()),
+					::core::option::Option::None => e,
+				})?;
+				let response = #fizyr_rpc::format::decode_body_instrumented::<F, _>(response.header.service_id, response.body, &self.decode_context).map_err(#fizyr_rpc::Error::decode_failed)?;
+				::core::result::Result::Ok(response)
+			},
+		},
+	}
+}
+
 fn generate_send_update_functions(impl_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, enum_type: &TokenStream, updates: &[UpdateDefinition]) {
 	quote! {
 		/// Send a request update to the remote peer.
@@ -454,6 +953,7 @@ fn generate_send_update_functions(impl_tokens: &mut TokenStream, fizyr_rpc: &syn
 		let body_type = update.body_type();
 		let service_id = update.service_id();
 		let doc = format!("Send a `{}` update to the remote peer.", update.name());
+		let cfg = update.cfg();
 		let body_arg;
 		let body_val;
 		if is_unit_type(body_type) {
@@ -465,12 +965,13 @@ fn generate_send_update_functions(impl_tokens: &mut TokenStream, fizyr_rpc: &syn
 		}
 		impl_tokens.extend(quote! {
 			#[doc = #doc]
+			#cfg
 			#[allow(clippy::ptr_arg)]
 			pub async fn #function_name(&self, #body_arg) -> ::core::result::Result<(), #fizyr_rpc::Error>
 			where
 				F: #fizyr_rpc::format::EncodeBody<#body_type>,
 			{
-				let body = F::encode_body(#body_val).map_err(#fizyr_rpc::Error::encode_failed)?;
+				let body = #fizyr_rpc::format::encode_body_instrumented::<F, _>(#service_id, #body_val).map_err(#fizyr_rpc::Error::encode_failed)?;
 				self.request.send_update(#service_id, body).await?;
 				::core::result::Result::Ok(())
 			}
@@ -478,7 +979,17 @@ fn generate_send_update_functions(impl_tokens: &mut TokenStream, fizyr_rpc: &syn
 	}
 }
 
-fn generate_recv_update_function(impl_tokens: &mut TokenStream, fizyr_rpc: &syn::Ident, updates: &[UpdateDefinition], kind: UpdateKind) {
+/// Generate the `recv_update()` function for a request handle.
+///
+/// Helper traits for the bounds of updates with `#[cfg]` attributes are added to `item_tokens`.
+fn generate_recv_update_function(
+	item_tokens: &mut TokenStream,
+	impl_tokens: &mut TokenStream,
+	fizyr_rpc: &syn::Ident,
+	updates: &[UpdateDefinition],
+	kind: UpdateKind,
+	forward_compatible: bool,
+) {
 	let mut doc = quote! {
 		/// Receive an update from the remote peer.
 		///
@@ -502,24 +1013,47 @@ fn generate_recv_update_function(impl_tokens: &mut TokenStream, fizyr_rpc: &syn:
 	let mut decode_arms = TokenStream::new();
 	let mut where_clause = TokenStream::new();
 	for update in updates {
-		let service_id = update.service_id();
+		let service_id = service_id_pattern(update.service_id());
 		let body_type = update.body_type();
 		let variant_name = syn::Ident::new(&to_upper_camel_case(&update.name().to_string()), Span::call_site());
+		let cfg = update.cfg();
+		let trait_name = format!("__Recv{}{}", update_kind, variant_name);
+		let bound = cfg_format_bound(item_tokens, cfg, &trait_name, quote!(#fizyr_rpc::format::DecodeBody<#body_type>));
 		where_clause.extend(quote! {
-			F: #fizyr_rpc::format::DecodeBody<#body_type>,
-		});
-		decode_arms.extend(quote! {
-			#service_id =>  {
-				match F::decode_body(update.body) {
-					::core::result::Result::Ok(body) => {
-						::core::result::Result::Ok(#update_kind::#variant_name(body))
-					},
-					::core::result::Result::Err(e) => {
-						::core::result::Result::Err(#fizyr_rpc::ParseUpdateError::InvalidUpdate(update.header, e))
-					},
-				}
-			},
+			F: #bound,
 		});
+		if forward_compatible {
+			decode_arms.extend(quote! {
+				#cfg
+				#service_id =>  {
+					match #fizyr_rpc::format::decode_body_partial_instrumented::<F, _>(update.header.service_id, update.body, &self.decode_context) {
+						::core::result::Result::Ok(#fizyr_rpc::format::PartialDecode::Decoded(body)) => {
+							::core::result::Result::Ok(#update_kind::#variant_name(body))
+						},
+						::core::result::Result::Ok(#fizyr_rpc::format::PartialDecode::Unrecognized(raw_body)) => {
+							::core::result::Result::Ok(#update_kind::Unrecognized { service_id: update.header.service_id, raw_body })
+						},
+						::core::result::Result::Err(e) => {
+							::core::result::Result::Err(#fizyr_rpc::ParseUpdateError::InvalidUpdate(update.header, e))
+						},
+					}
+				},
+			});
+		} else {
+			decode_arms.extend(quote! {
+				#cfg
+				#service_id =>  {
+					match #fizyr_rpc::format::decode_body_instrumented::<F, _>(update.header.service_id, update.body, &self.decode_context) {
+						::core::result::Result::Ok(body) => {
+							::core::result::Result::Ok(#update_kind::#variant_name(body))
+						},
+						::core::result::Result::Err(e) => {
+							::core::result::Result::Err(#fizyr_rpc::ParseUpdateError::InvalidUpdate(update.header, e))
+						},
+					}
+				},
+			});
+		}
 	}
 
 	impl_tokens.extend(quote! {
@@ -536,3 +1070,91 @@ fn generate_recv_update_function(impl_tokens: &mut TokenStream, fizyr_rpc: &syn:
 		}
 	});
 }
+
+/// Generate a `Stream` type and accessor function for a response update marked with `#[stream]`.
+///
+/// The parser only allows this attribute if it is the only response update of the service,
+/// so the stream can yield the update bodies directly instead of a `ResponseUpdate` enum.
+fn generate_update_stream(
+	item_tokens: &mut TokenStream,
+	impl_tokens: &mut TokenStream,
+	fizyr_rpc: &syn::Ident,
+	update: &UpdateDefinition,
+	forward_compatible: bool,
+) {
+	let update_name = update.name();
+	let service_id = service_id_pattern(update.service_id());
+	let body_type = update.body_type();
+	let cfg = update.cfg();
+	let stream_name = syn::Ident::new(&format!("{}Stream", to_upper_camel_case(&update_name.to_string())), Span::call_site());
+	let fn_name = syn::Ident::new(&format!("{}_stream", update_name), Span::call_site());
+	let stream_doc = format!("Stream of `{}` updates for a sent request.", update_name);
+	let fn_doc = format!("Get a [`Stream`][{fizyr_rpc}::macros::Stream] of the `{update_name}` updates of the request.");
+
+	// Forward compatible interfaces silently ignore updates they do not know.
+	let unknown_update = match forward_compatible {
+		true => quote!(continue),
+		false => quote!(return ::core::task::Poll::Ready(::core::option::Option::Some(::core::result::Result::Err(#fizyr_rpc::ParseUpdateError::UnknownUpdate(update))))),
+	};
+
+	item_tokens.extend(quote! {
+		#cfg
+		#[doc = #stream_doc]
+		///
+		/// The stream ends when the final response is received.
+		/// The response can then be received with [`SentRequestHandle::recv_response()`].
+		pub struct #stream_name<'a, F: #fizyr_rpc::format::Format> {
+			request: &'a mut SentRequestHandle<F>,
+		}
+
+		#cfg
+		impl<'a, F: #fizyr_rpc::format::Format> ::core::fmt::Debug for #stream_name<'a, F> {
+			fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+				f.debug_struct(::core::any::type_name::<Self>())
+					.field("request_id", &self.request.request_id())
+					.field("service_id", &self.request.service_id())
+					.finish_non_exhaustive()
+			}
+		}
+
+		#cfg
+		impl<'a, F> #fizyr_rpc::macros::Stream for #stream_name<'a, F>
+		where
+			F: #fizyr_rpc::format::Format + #fizyr_rpc::format::DecodeBody<#body_type>,
+		{
+			type Item = ::core::result::Result<#body_type, #fizyr_rpc::ParseUpdateError<F::Body>>;
+
+			fn poll_next(self: ::core::pin::Pin<&mut Self>, context: &mut ::core::task::Context) -> ::core::task::Poll<::core::option::Option<Self::Item>> {
+				let this = self.get_mut();
+				loop {
+					let update = match this.request.request.poll_recv_update(context) {
+						::core::task::Poll::Pending => return ::core::task::Poll::Pending,
+						::core::task::Poll::Ready(::core::option::Option::None) => return ::core::task::Poll::Ready(::core::option::Option::None),
+						::core::task::Poll::Ready(::core::option::Option::Some(update)) => update,
+					};
+					match update.header.service_id {
+						#service_id => {
+							let body = match #fizyr_rpc::format::decode_body_instrumented::<F, _>(update.header.service_id, update.body, &this.request.decode_context) {
+								::core::result::Result::Ok(body) => ::core::result::Result::Ok(body),
+								::core::result::Result::Err(e) => ::core::result::Result::Err(#fizyr_rpc::ParseUpdateError::InvalidUpdate(update.header, e)),
+							};
+							return ::core::task::Poll::Ready(::core::option::Option::Some(body));
+						},
+						_ => #unknown_update,
+					}
+				}
+			}
+		}
+	});
+
+	impl_tokens.extend(quote! {
+		#cfg
+		#[doc = #fn_doc]
+		///
+		/// The stream yields the update bodies until the final response is received.
+		/// Afterwards, the response can be received with [`Self::recv_response()`].
+		pub fn #fn_name(&mut self) -> #stream_name<'_, F> {
+			#stream_name { request: self }
+		}
+	});
+}
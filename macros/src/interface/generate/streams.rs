@@ -14,6 +14,7 @@ pub fn generate_streams(item_tokens: &mut TokenStream, client_impl_tokens: &mut
 			interface.streams(),
 			&syn::Ident::new("StreamMessage", Span::call_site()),
 			&format!("A stream message for the {} interface.", interface.name()),
+			interface.forward_compatible(),
 		);
 	}
 	for stream in interface.streams() {
@@ -23,6 +24,7 @@ pub fn generate_streams(item_tokens: &mut TokenStream, client_impl_tokens: &mut
 		let body_arg;
 		let body_val;
 		let body_type = stream.body_type();
+		let cfg = stream.cfg();
 		if is_unit_type(body_type) {
 			body_arg = None;
 			body_val = quote!(&());
@@ -32,12 +34,13 @@ pub fn generate_streams(item_tokens: &mut TokenStream, client_impl_tokens: &mut
 		}
 		client_impl_tokens.extend(quote! {
 			#[doc = #fn_doc]
+			#cfg
 			#[allow(clippy::ptr_arg)]
 			pub async fn #fn_name(&self, #body_arg) -> ::core::result::Result<(), #fizyr_rpc::Error>
 			where
 				F: #fizyr_rpc::format::EncodeBody<#body_type>,
 			{
-				let encoded = F::encode_body(#body_val).map_err(#fizyr_rpc::Error::encode_failed)?;
+				let encoded = #fizyr_rpc::format::encode_body_instrumented::<F, _>(#service_id, #body_val).map_err(#fizyr_rpc::Error::encode_failed)?;
 				self.peer.send_stream(#service_id, encoded).await?;
 				::core::result::Result::Ok(())
 			}
@@ -10,7 +10,8 @@
 /// on code using the generated type, but the errors MUST be emitted too.
 pub mod cooked {
 	use crate::util::{parse_doc_attr_contents, WithSpan};
-	use proc_macro2::Span;
+	use proc_macro2::{Span, TokenStream};
+	use quote::ToTokens;
 	use syn::spanned::Spanned;
 	use super::raw;
 
@@ -22,6 +23,26 @@ pub mod cooked {
 		span: Span,
 	}
 
+	/// The `#[cfg]` conditions of a service, update or stream.
+	///
+	/// All conditions must hold for the item to be enabled.
+	#[derive(Clone, Default)]
+	pub struct CfgConditions {
+		/// The predicates of the `#[cfg]` attributes.
+		predicates: Vec<TokenStream>,
+	}
+
+	/// A service ID for a service, update or stream.
+	pub enum ServiceId {
+		/// An integer literal.
+		Literal(i32),
+
+		/// A path to a `const i32` item.
+		///
+		/// The value is not known to the macro, so it can only be checked by the generated code.
+		Path(syn::Path),
+	}
+
 	/// A parsed interface definition.
 	pub struct InterfaceDefinition {
 		/// The visiblity to use for all generated items.
@@ -36,6 +57,12 @@ pub mod cooked {
 		/// If set, the interface should be hidden from documentation.
 		hidden: Option<Hidden>,
 
+		/// If true, update and stream enums get a variant for unrecognized messages.
+		forward_compatible: bool,
+
+		/// The interval for heartbeat messages sent by the client, if enabled.
+		heartbeat_interval: Option<std::time::Duration>,
+
 		/// The services in the interface.
 		services: Vec<ServiceDefinition>,
 
@@ -46,7 +73,7 @@ pub mod cooked {
 	/// A parsed service definition.
 	pub struct ServiceDefinition {
 		/// The ID of the service.
-		service_id: WithSpan<i32>,
+		service_id: WithSpan<ServiceId>,
 
 		/// The name of the service.
 		name: syn::Ident,
@@ -57,23 +84,41 @@ pub mod cooked {
 		/// If set, the service should be hidden from documentation.
 		hidden: Option<Hidden>,
 
+		/// The `#[cfg]` conditions of the service.
+		cfg: CfgConditions,
+
 		/// The type of the request body.
 		request_type: Box<syn::Type>,
 
 		/// The type of the response body.
 		response_type: Box<syn::Type>,
 
+		/// The type of the structured service error, if declared.
+		error_type: Option<Box<syn::Type>>,
+
 		/// The updates that can be sent by the request initiator ("client").
 		request_updates: Vec<UpdateDefinition>,
 
 		/// The updates that can be sent by the request handler ("server").
 		response_updates: Vec<UpdateDefinition>,
+
+		/// The request fields to generate a client-side request builder for, if requested.
+		builder_fields: Option<Vec<BuilderField>>,
+	}
+
+	/// A request field that can be set with a generated request builder.
+	pub struct BuilderField {
+		/// The name of the field.
+		name: syn::Ident,
+
+		/// The type of the field.
+		field_type: syn::Type,
 	}
 
 	/// A parsed definition of an update message.
 	pub struct UpdateDefinition {
 		/// The service ID of the update.
-		service_id: WithSpan<i32>,
+		service_id: WithSpan<ServiceId>,
 
 		/// The name of the update.
 		name: syn::Ident,
@@ -84,6 +129,12 @@ pub mod cooked {
 		/// If set, the update should be hidden from documentation.
 		hidden: Option<Hidden>,
 
+		/// The `#[cfg]` conditions of the update.
+		cfg: CfgConditions,
+
+		/// If set, the client gets a `Stream` accessor for the update.
+		stream: Option<Span>,
+
 		/// The body type of the update.
 		body_type: Box<syn::Type>,
 	}
@@ -91,7 +142,7 @@ pub mod cooked {
 	/// A parsed definition of a stream message.
 	pub struct StreamDefinition {
 		/// The service ID of the stream.
-		service_id: WithSpan<i32>,
+		service_id: WithSpan<ServiceId>,
 
 		/// The name of the stream.
 		name: syn::Ident,
@@ -102,6 +153,12 @@ pub mod cooked {
 		/// If set, the stream should be hidden from documentation.
 		hidden: Option<Hidden>,
 
+		/// If true, the stream messages do not need to be delivered in order.
+		unordered: bool,
+
+		/// The `#[cfg]` conditions of the stream.
+		cfg: CfgConditions,
+
 		/// The body type of the stream message.
 		body_type: Box<syn::Type>,
 	}
@@ -111,7 +168,7 @@ pub mod cooked {
 	/// This is implemented for update messages and stream messages.
 	pub trait MessageDefinition {
 		/// The service ID used for the message.
-		fn service_id(&self) -> &WithSpan<i32>;
+		fn service_id(&self) -> &WithSpan<ServiceId>;
 
 		/// The name of the message.
 		fn name(&self) -> &syn::Ident;
@@ -120,16 +177,27 @@ pub mod cooked {
 		fn doc(&self) -> &[WithSpan<String>];
 
 		/// Check if the message should be hidden from generated documentation.
+		#[allow(unused)]
 		fn hidden(&self) -> Option<Hidden>;
 
+		/// The `#[cfg]` conditions of the message.
+		fn cfg(&self) -> &CfgConditions;
+
 		/// The type of the message body.
 		fn body_type(&self) -> &syn::Type;
 	}
 
-	/// Attributes that include only doc comments.
+	/// Attributes that include only doc comments, `#[hidden]`, `#[cfg]`, `#[forward_compatible]`, `#[unordered]`, `#[builder]`, `#[stream]` and `#[heartbeat]`.
 	struct Attributes {
 		doc: Vec<WithSpan<String>>,
 		hidden: Option<Hidden>,
+		cfg: CfgConditions,
+		cfg_span: Option<Span>,
+		forward_compatible_span: Option<Span>,
+		unordered_span: Option<Span>,
+		builder: Option<WithSpan<Vec<BuilderField>>>,
+		stream_span: Option<Span>,
+		heartbeat: Option<WithSpan<std::time::Duration>>,
 	}
 
 	impl InterfaceDefinition {
@@ -153,6 +221,16 @@ pub mod cooked {
 			self.hidden
 		}
 
+		/// Check if update and stream enums should get a variant for unrecognized messages.
+		pub fn forward_compatible(&self) -> bool {
+			self.forward_compatible
+		}
+
+		/// Get the interval for heartbeat messages sent by the client, if enabled.
+		pub fn heartbeat_interval(&self) -> Option<std::time::Duration> {
+			self.heartbeat_interval
+		}
+
 		/// Get the list of services in the interface.
 		pub fn services(&self) -> &[ServiceDefinition] {
 			&self.services
@@ -166,6 +244,12 @@ pub mod cooked {
 		/// Process a raw interface definition into a cooked one.
 		pub fn from_raw(errors: &mut Vec<syn::Error>, raw: raw::InterfaceDefinition) -> Self {
 			let attrs = Attributes::from_raw(errors, raw.attrs);
+			if let Some(span) = attrs.cfg_span {
+				errors.push(syn::Error::new(span, "`cfg` attributes are not supported on interfaces, put them on the macro invocation instead"));
+			}
+			attrs.reject_unordered(errors);
+			attrs.reject_builder(errors);
+			attrs.reject_stream(errors);
 			let mut services = Vec::new();
 			let mut streams = Vec::new();
 			for item in raw.items {
@@ -182,7 +266,7 @@ pub mod cooked {
 
 			for (a_i, a) in services.iter().enumerate() {
 				for (b_i, b) in services.iter().enumerate().skip(a_i + 1) {
-					if a.service_id.value == b.service_id.value {
+					if a.service_id.value.is_same_literal(&b.service_id.value) {
 						errors.push(syn::Error::new(b.service_id.span, "duplicate service ID"));
 					}
 					if a.name() == b.name() {
@@ -194,7 +278,7 @@ pub mod cooked {
 
 			for (a_i, a) in streams.iter().enumerate() {
 				for (b_i, b) in streams.iter().enumerate().skip(a_i + 1) {
-					if a.service_id.value == b.service_id.value {
+					if a.service_id.value.is_same_literal(&b.service_id.value) {
 						errors.push(syn::Error::new(b.service_id.span, "duplicate service ID"));
 					}
 					if a.name() == b.name() {
@@ -217,6 +301,8 @@ pub mod cooked {
 				name: raw.name,
 				doc: attrs.doc,
 				hidden: attrs.hidden,
+				forward_compatible: attrs.forward_compatible_span.is_some(),
+				heartbeat_interval: attrs.heartbeat.map(|x| x.value),
 				services,
 				streams,
 			}
@@ -225,8 +311,8 @@ pub mod cooked {
 
 	impl ServiceDefinition {
 		/// Get the service ID of the service.
-		pub fn service_id(&self) -> WithSpan<i32> {
-			self.service_id.clone()
+		pub fn service_id(&self) -> &WithSpan<ServiceId> {
+			&self.service_id
 		}
 
 		/// Get the name of the service.
@@ -244,6 +330,11 @@ pub mod cooked {
 			self.hidden
 		}
 
+		/// Get the `#[cfg]` conditions of the service.
+		pub fn cfg(&self) -> &CfgConditions {
+			&self.cfg
+		}
+
 		/// Get the type of the request body.
 		pub fn request_type(&self) -> &syn::Type {
 			self.request_type.as_ref()
@@ -254,6 +345,11 @@ pub mod cooked {
 			self.response_type.as_ref()
 		}
 
+		/// Get the type of the structured service error, if declared.
+		pub fn error_type(&self) -> Option<&syn::Type> {
+			self.error_type.as_deref()
+		}
+
 		/// Get the updates that the request initiator can send.
 		pub fn request_updates(&self) -> &[UpdateDefinition] {
 			&self.request_updates
@@ -264,9 +360,26 @@ pub mod cooked {
 			&self.response_updates
 		}
 
+		/// Get the request fields to generate a client-side request builder for, if requested.
+		pub fn builder_fields(&self) -> Option<&[BuilderField]> {
+			self.builder_fields.as_deref()
+		}
+
 		/// Process a raw service definition into a cooked one.
 		fn from_raw(errors: &mut Vec<syn::Error>, raw: raw::ServiceDefinition) -> Self {
 			let attrs = Attributes::from_raw(errors, raw.attrs);
+			attrs.reject_forward_compatible(errors);
+			attrs.reject_unordered(errors);
+			attrs.reject_stream(errors);
+			attrs.reject_heartbeat(errors);
+			let builder_fields = attrs.builder.and_then(|builder| {
+				if matches!(raw.request_type.as_ref(), syn::Type::Tuple(x) if x.elems.is_empty()) {
+					errors.push(syn::Error::new(builder.span, "`builder` attributes are not supported on services without request body"));
+					None
+				} else {
+					Some(builder.value)
+				}
+			});
 			let mut request_updates = Vec::new();
 			let mut response_updates = Vec::new();
 			if let raw::MaybeServiceBody::Body(body, _) = raw.body {
@@ -285,7 +398,7 @@ pub mod cooked {
 
 			for (a_i, a) in request_updates.iter().enumerate() {
 				for (b_i, b) in request_updates.iter().enumerate().skip(a_i + 1) {
-					if a.service_id.value == b.service_id.value {
+					if a.service_id.value.is_same_literal(&b.service_id.value) {
 						errors.push(syn::Error::new(b.service_id.span, "duplicate service ID"));
 					}
 					if a.name() == b.name() {
@@ -297,7 +410,7 @@ pub mod cooked {
 
 			for (a_i, a) in response_updates.iter().enumerate() {
 				for (b_i, b) in response_updates.iter().enumerate().skip(a_i + 1) {
-					if a.service_id.value == b.service_id.value {
+					if a.service_id.value.is_same_literal(&b.service_id.value) {
 						errors.push(syn::Error::new(b.service_id.span, "duplicate service ID"));
 					}
 					if a.name() == b.name() {
@@ -315,22 +428,33 @@ pub mod cooked {
 				response_updates.remove(i);
 			}
 
+			if response_updates.len() > 1 {
+				for update in &response_updates {
+					if let Some(span) = update.stream {
+						errors.push(syn::Error::new(span, "`stream` attributes are only supported on the only response update of a service"));
+					}
+				}
+			}
+
 			Self {
-				service_id: parse_i32(errors, raw.service_id),
+				service_id: parse_service_id(errors, raw.service_id),
 				name: raw.name,
 				doc: attrs.doc,
 				hidden: attrs.hidden,
+				cfg: attrs.cfg,
 				request_type: raw.request_type,
 				response_type: raw.response_type,
+				error_type: raw.error_type.map(|x| x.error_type),
 				request_updates,
 				response_updates,
+				builder_fields,
 			}
 		}
 	}
 
 	impl UpdateDefinition {
 		/// Get the service ID of the update.
-		pub fn service_id(&self) -> &WithSpan<i32> {
+		pub fn service_id(&self) -> &WithSpan<ServiceId> {
 			&self.service_id
 		}
 
@@ -349,6 +473,16 @@ pub mod cooked {
 			self.hidden
 		}
 
+		/// Get the `#[cfg]` conditions of the update.
+		pub fn cfg(&self) -> &CfgConditions {
+			&self.cfg
+		}
+
+		/// Check if the client should get a `Stream` accessor for the update.
+		pub fn stream(&self) -> bool {
+			self.stream.is_some()
+		}
+
 		/// Get the type of the update body.
 		pub fn body_type(&self) -> &syn::Type {
 			&self.body_type
@@ -357,12 +491,21 @@ pub mod cooked {
 		/// Process a raw update definition into a cooked one.
 		fn from_raw(errors: &mut Vec<syn::Error>, raw: raw::UpdateDefinition) -> (raw::UpdateKind, Self) {
 			let attrs = Attributes::from_raw(errors, raw.attrs);
+			attrs.reject_forward_compatible(errors);
+			attrs.reject_unordered(errors);
+			attrs.reject_builder(errors);
+			attrs.reject_heartbeat(errors);
+			if let (raw::UpdateKind::RequestUpdate(_), Some(span)) = (&raw.kind, attrs.stream_span) {
+				errors.push(syn::Error::new(span, "`stream` attributes are only supported on response updates"));
+			}
 
 			(raw.kind, Self {
-				service_id: parse_i32(errors, raw.service_id),
+				service_id: parse_service_id(errors, raw.service_id),
 				name: raw.name,
 				doc: attrs.doc,
 				hidden: attrs.hidden,
+				cfg: attrs.cfg,
+				stream: attrs.stream_span,
 				body_type: raw.body_type,
 			})
 		}
@@ -370,7 +513,7 @@ pub mod cooked {
 
 	impl StreamDefinition {
 		/// Get the service ID of the stream.
-		pub fn service_id(&self) -> &WithSpan<i32> {
+		pub fn service_id(&self) -> &WithSpan<ServiceId> {
 			&self.service_id
 		}
 
@@ -389,6 +532,16 @@ pub mod cooked {
 			self.hidden
 		}
 
+		/// Check if the stream messages do not need to be delivered in order.
+		pub fn unordered(&self) -> bool {
+			self.unordered
+		}
+
+		/// Get the `#[cfg]` conditions of the stream.
+		pub fn cfg(&self) -> &CfgConditions {
+			&self.cfg
+		}
+
 		/// Get the type of the stream body.
 		pub fn body_type(&self) -> &syn::Type {
 			self.body_type.as_ref()
@@ -397,12 +550,18 @@ pub mod cooked {
 		/// Process a raw stream definition into a cooked one.
 		fn from_raw(errors: &mut Vec<syn::Error>, raw: raw::StreamDefinition) -> Self {
 			let attrs = Attributes::from_raw(errors, raw.attrs);
+			attrs.reject_forward_compatible(errors);
+			attrs.reject_builder(errors);
+			attrs.reject_stream(errors);
+			attrs.reject_heartbeat(errors);
 
 			Self {
-				service_id: parse_i32(errors, raw.service_id),
+				service_id: parse_service_id(errors, raw.service_id),
 				name: raw.name,
 				doc: attrs.doc,
 				hidden: attrs.hidden,
+				unordered: attrs.unordered_span.is_some(),
+				cfg: attrs.cfg,
 				body_type: raw.body_type,
 			}
 		}
@@ -414,6 +573,13 @@ pub mod cooked {
 		fn from_raw(errors: &mut Vec<syn::Error>, attrs: Vec<syn::Attribute>) -> Self {
 			let mut doc = Vec::new();
 			let mut hidden = None;
+			let mut cfg = CfgConditions::default();
+			let mut cfg_span = None;
+			let mut forward_compatible_span = None;
+			let mut unordered_span = None;
+			let mut builder = None;
+			let mut stream_span = None;
+			let mut heartbeat = None;
 
 			for attr in attrs {
 				if attr.path().is_ident("doc") {
@@ -427,28 +593,244 @@ pub mod cooked {
 					} else {
 						hidden = Some(Hidden { span: attr.path().span() });
 					}
+				} else if attr.path().is_ident("cfg") {
+					match attr.meta.require_list() {
+						Ok(list) => {
+							cfg.predicates.push(list.tokens.clone());
+							cfg_span = Some(attr.path().span());
+						},
+						Err(e) => errors.push(e),
+					}
+				} else if attr.path().is_ident("forward_compatible") {
+					if let Err(e) = attr.meta.require_path_only() {
+						errors.push(e);
+					} else {
+						forward_compatible_span = Some(attr.path().span());
+					}
+				} else if attr.path().is_ident("unordered") {
+					if let Err(e) = attr.meta.require_path_only() {
+						errors.push(e);
+					} else {
+						unordered_span = Some(attr.path().span());
+					}
+				} else if attr.path().is_ident("builder") {
+					match attr.parse_args_with(syn::punctuated::Punctuated::<BuilderField, syn::Token![,]>::parse_terminated) {
+						Ok(fields) => builder = Some(WithSpan::new(attr.path().span(), parse_builder_fields(errors, fields))),
+						Err(e) => errors.push(e),
+					}
+				} else if attr.path().is_ident("stream") {
+					if let Err(e) = attr.meta.require_path_only() {
+						errors.push(e);
+					} else {
+						stream_span = Some(attr.path().span());
+					}
+				} else if attr.path().is_ident("heartbeat") {
+					match parse_heartbeat_attr(&attr) {
+						Ok(interval) => heartbeat = Some(WithSpan::new(attr.path().span(), interval)),
+						Err(e) => errors.push(e),
+					}
 				} else {
 					errors.push(syn::Error::new_spanned(attr.path(), "unknown attribute"));
 				}
 			}
 
-			Self { doc, hidden }
+			Self { doc, hidden, cfg, cfg_span, forward_compatible_span, unordered_span, builder, stream_span, heartbeat }
 		}
+
+		/// Report an error if the `#[forward_compatible]` attribute was used on something other than an interface.
+		fn reject_forward_compatible(&self, errors: &mut Vec<syn::Error>) {
+			if let Some(span) = self.forward_compatible_span {
+				errors.push(syn::Error::new(span, "`forward_compatible` attributes are only supported on interfaces"));
+			}
+		}
+
+		/// Report an error if the `#[unordered]` attribute was used on something other than a stream.
+		fn reject_unordered(&self, errors: &mut Vec<syn::Error>) {
+			if let Some(span) = self.unordered_span {
+				errors.push(syn::Error::new(span, "`unordered` attributes are only supported on streams"));
+			}
+		}
+
+		/// Report an error if the `#[builder]` attribute was used on something other than a service.
+		fn reject_builder(&self, errors: &mut Vec<syn::Error>) {
+			if let Some(builder) = &self.builder {
+				errors.push(syn::Error::new(builder.span, "`builder` attributes are only supported on services"));
+			}
+		}
+
+		/// Report an error if the `#[heartbeat]` attribute was used on something other than an interface.
+		fn reject_heartbeat(&self, errors: &mut Vec<syn::Error>) {
+			if let Some(heartbeat) = &self.heartbeat {
+				errors.push(syn::Error::new(heartbeat.span, "`heartbeat` attributes are only supported on interfaces"));
+			}
+		}
+
+		/// Report an error if the `#[stream]` attribute was used on something other than a response update.
+		fn reject_stream(&self, errors: &mut Vec<syn::Error>) {
+			if let Some(span) = self.stream_span {
+				errors.push(syn::Error::new(span, "`stream` attributes are only supported on response updates"));
+			}
+		}
+	}
+
+	impl BuilderField {
+		/// Get the name of the field.
+		pub fn name(&self) -> &syn::Ident {
+			&self.name
+		}
+
+		/// Get the type of the field.
+		pub fn field_type(&self) -> &syn::Type {
+			&self.field_type
+		}
+	}
+
+	impl syn::parse::Parse for BuilderField {
+		fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+			let name = input.parse()?;
+			let _colon: syn::token::Colon = input.parse()?;
+			let field_type = input.parse()?;
+			Ok(Self { name, field_type })
+		}
+	}
+
+	/// Check the fields of a `#[builder]` attribute.
+	///
+	/// Fields with a duplicate name or a name that is used by the builder itself are removed.
+	/// Parse the contents of a `#[heartbeat(interval = "5s")]` attribute.
+	fn parse_heartbeat_attr(attr: &syn::Attribute) -> syn::Result<std::time::Duration> {
+		let mut interval = None;
+		attr.parse_nested_meta(|meta| {
+			if meta.path.is_ident("interval") {
+				let value: syn::LitStr = meta.value()?.parse()?;
+				interval = Some(parse_duration(&value)?);
+				Ok(())
+			} else {
+				Err(meta.error("unknown heartbeat option, expected `interval`"))
+			}
+		})?;
+		interval.ok_or_else(|| syn::Error::new_spanned(attr, "missing heartbeat interval, use `#[heartbeat(interval = \"5s\")]`"))
+	}
+
+	/// Parse a duration like `"500ms"`, `"5s"`, `"2m"` or `"1h"`.
+	fn parse_duration(value: &syn::LitStr) -> syn::Result<std::time::Duration> {
+		let text = value.value();
+		let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+		let (number, unit) = text.split_at(split);
+		let number: u64 = number.parse()
+			.map_err(|_| syn::Error::new(value.span(), "invalid duration, expected a number followed by `ms`, `s`, `m` or `h`"))?;
+		let duration = match unit {
+			"ms" => std::time::Duration::from_millis(number),
+			"s" => std::time::Duration::from_secs(number),
+			"m" => std::time::Duration::from_secs(number.saturating_mul(60)),
+			"h" => std::time::Duration::from_secs(number.saturating_mul(3600)),
+			_ => return Err(syn::Error::new(value.span(), "invalid duration unit, expected `ms`, `s`, `m` or `h`")),
+		};
+		if duration.is_zero() {
+			return Err(syn::Error::new(value.span(), "the heartbeat interval must be larger than zero"));
+		}
+		Ok(duration)
 	}
 
-	/// Parse an integer literal into an i32.
-	fn parse_i32(errors: &mut Vec<syn::Error>, literal: syn::LitInt) -> WithSpan<i32> {
-		match literal.base10_parse() {
-			Ok(x) => WithSpan::new(literal.span(), x),
-			Err(e) => {
-				errors.push(e);
-				WithSpan::new(Span::call_site(), 0)
+	fn parse_builder_fields(errors: &mut Vec<syn::Error>, fields: syn::punctuated::Punctuated<BuilderField, syn::Token![,]>) -> Vec<BuilderField> {
+		let mut result: Vec<BuilderField> = Vec::new();
+		for field in fields {
+			if field.name == "send" || field.name == "into_request" {
+				errors.push(syn::Error::new(field.name.span(), format!("`{}` can not be used as builder field, it is used by the builder itself", field.name)));
+			} else if result.iter().any(|x| x.name == field.name) {
+				errors.push(syn::Error::new(field.name.span(), "duplicate builder field"));
+			} else {
+				result.push(field);
 			}
 		}
+		result
+	}
+
+	impl CfgConditions {
+		/// Check if there are no conditions, meaning the item is always enabled.
+		pub fn is_empty(&self) -> bool {
+			self.predicates.is_empty()
+		}
+
+		/// Combine the conditions of two items.
+		///
+		/// The result holds only if the conditions of both items hold.
+		pub fn and(&self, other: &Self) -> Self {
+			let mut predicates = self.predicates.clone();
+			predicates.extend(other.predicates.iter().cloned());
+			Self { predicates }
+		}
+
+		/// Get a `#[cfg]` attribute that enables an item only if the conditions do NOT hold.
+		///
+		/// If there are no conditions, the item is never enabled.
+		pub fn negated(&self) -> TokenStream {
+			let predicates = &self.predicates;
+			quote::quote!(#[cfg(not(all(#(#predicates),*)))])
+		}
+	}
+
+	/// Generates a `#[cfg]` attribute that enables an item only if the conditions hold.
+	///
+	/// Generates nothing if there are no conditions.
+	impl ToTokens for CfgConditions {
+		fn to_tokens(&self, tokens: &mut TokenStream) {
+			if !self.is_empty() {
+				let predicates = &self.predicates;
+				tokens.extend(quote::quote!(#[cfg(all(#(#predicates),*))]));
+			}
+		}
+	}
+
+	impl ServiceId {
+		/// Check if both service IDs are integer literals with the same value.
+		///
+		/// Service IDs given as path can not be compared by the macro.
+		pub fn is_same_literal(&self, other: &Self) -> bool {
+			match (self, other) {
+				(Self::Literal(a), Self::Literal(b)) => a == b,
+				_ => false,
+			}
+		}
+
+		/// Check if the service ID is given as path to a constant.
+		pub fn is_path(&self) -> bool {
+			matches!(self, Self::Path(_))
+		}
+	}
+
+	impl ToTokens for ServiceId {
+		fn to_tokens(&self, tokens: &mut TokenStream) {
+			match self {
+				Self::Literal(value) => value.to_tokens(tokens),
+				Self::Path(path) => path.to_tokens(tokens),
+			}
+		}
+	}
+
+	/// Parse a raw service ID into a cooked one.
+	fn parse_service_id(errors: &mut Vec<syn::Error>, raw: raw::ServiceId) -> WithSpan<ServiceId> {
+		match raw {
+			raw::ServiceId::Literal(literal) => match literal.base10_parse() {
+				Ok(x) => WithSpan::new(literal.span(), ServiceId::Literal(x)),
+				Err(e) => {
+					errors.push(e);
+					WithSpan::new(Span::call_site(), ServiceId::Literal(0))
+				},
+			},
+			raw::ServiceId::Path(path) => {
+				// The path is used from generated sub-modules, so relative paths would point to the wrong module.
+				let first = &path.segments[0].ident;
+				if first == "self" || first == "super" {
+					errors.push(syn::Error::new_spanned(first, "service ID paths can not start with `self` or `super`"));
+				}
+				WithSpan::new(path.span(), ServiceId::Path(path))
+			},
+		}
 	}
 
 	impl MessageDefinition for UpdateDefinition {
-		fn service_id(&self) -> &WithSpan<i32> {
+		fn service_id(&self) -> &WithSpan<ServiceId> {
 			self.service_id()
 		}
 
@@ -464,13 +846,17 @@ pub mod cooked {
 			self.hidden
 		}
 
+		fn cfg(&self) -> &CfgConditions {
+			&self.cfg
+		}
+
 		fn body_type(&self) -> &syn::Type {
 			self.body_type()
 		}
 	}
 
 	impl MessageDefinition for StreamDefinition {
-		fn service_id(&self) -> &WithSpan<i32> {
+		fn service_id(&self) -> &WithSpan<ServiceId> {
 			self.service_id()
 		}
 
@@ -486,6 +872,10 @@ pub mod cooked {
 			self.hidden
 		}
 
+		fn cfg(&self) -> &CfgConditions {
+			&self.cfg
+		}
+
 		fn body_type(&self) -> &syn::Type {
 			self.body_type()
 		}
@@ -521,6 +911,11 @@ pub mod raw {
 		pub items: Vec<InterfaceItem>,
 	}
 
+	pub enum ServiceId {
+		Literal(syn::LitInt),
+		Path(syn::Path),
+	}
+
 	pub enum InterfaceItem {
 		Service(ServiceDefinition),
 		Stream(StreamDefinition),
@@ -529,15 +924,22 @@ pub mod raw {
 	pub struct ServiceDefinition {
 		pub attrs: Vec<syn::Attribute>,
 		pub _service: keyword::service,
-		pub service_id: syn::LitInt,
+		pub service_id: ServiceId,
 		pub name: syn::Ident,
 		pub _colon: syn::token::Colon,
 		pub request_type: Box<syn::Type>,
 		pub _arrow: syn::Token![->],
 		pub response_type: Box<syn::Type>,
+		pub error_type: Option<ServiceErrorType>,
 		pub body: MaybeServiceBody,
 	}
 
+	pub struct ServiceErrorType {
+		pub _bang: syn::Token![!],
+		pub error_type: Box<syn::Type>,
+	}
+
+	#[allow(unused)]
 	pub enum MaybeServiceBody {
 		NoBody(syn::token::Comma),
 		Body(ServiceBody, Option<syn::token::Comma>),
@@ -551,12 +953,13 @@ pub mod raw {
 	pub struct UpdateDefinition {
 		pub attrs: Vec<syn::Attribute>,
 		pub kind: UpdateKind,
-		pub service_id: syn::LitInt,
+		pub service_id: ServiceId,
 		pub name: syn::Ident,
 		pub _colon_token: syn::token::Colon,
 		pub body_type: Box<syn::Type>,
 	}
 
+	#[allow(unused)]
 	pub enum UpdateKind {
 		RequestUpdate(keyword::request_update),
 		ResponseUpdate(keyword::response_update),
@@ -565,7 +968,7 @@ pub mod raw {
 	pub struct StreamDefinition {
 		pub attrs: Vec<syn::Attribute>,
 		pub _stream: keyword::stream,
-		pub service_id: syn::LitInt,
+		pub service_id: ServiceId,
 		pub name: syn::Ident,
 		pub _colon: syn::token::Colon,
 		pub body_type: Box<syn::Type>,
@@ -596,6 +999,20 @@ pub mod raw {
 		}
 	}
 
+	impl syn::parse::Parse for ServiceId {
+		fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+			use syn::ext::IdentExt;
+
+			if input.peek(syn::LitInt) {
+				Ok(Self::Literal(input.parse()?))
+			} else if input.peek(syn::Ident::peek_any) || input.peek(syn::Token![::]) {
+				Ok(Self::Path(input.call(syn::Path::parse_mod_style)?))
+			} else {
+				Err(input.error("expected an integer literal or a path to a constant"))
+			}
+		}
+	}
+
 	impl syn::parse::Parse for InterfaceItem {
 		fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
 			let attrs = input.call(syn::Attribute::parse_outer)?;
@@ -608,7 +1025,8 @@ pub mod raw {
 					_colon: input.parse()?,
 					request_type: input.parse()?,
 					_arrow: input.parse()?,
-					response_type: input.parse()?,
+					response_type: input.call(parse_response_type)?,
+					error_type: input.call(parse_error_type)?,
 					body: input.parse()?,
 				}))
 			} else if input.peek(keyword::stream) {
@@ -627,6 +1045,59 @@ pub mod raw {
 		}
 	}
 
+	/// Parse the response type of a service.
+	///
+	/// The response type may be followed by `! ErrorType`.
+	/// A plain `syn::Type` would parse `Response !` as the start of a macro invocation,
+	/// so the tokens of the response type are collected up to the `!`, `,` or service body first.
+	fn parse_response_type(input: syn::parse::ParseStream) -> syn::Result<Box<syn::Type>> {
+		use proc_macro2::{Delimiter, Spacing, TokenTree};
+
+		let span = input.span();
+		let tokens = input.step(|cursor| {
+			let mut tokens = proc_macro2::TokenStream::new();
+			let mut depth = 0usize;
+			let mut rest = *cursor;
+			let mut previous_joint_minus = false;
+			while let Some((token, next)) = rest.token_tree() {
+				let joint_minus = match &token {
+					TokenTree::Punct(punct) => {
+						match punct.as_char() {
+							'!' | ',' if depth == 0 => break,
+							'<' => depth += 1,
+							'>' if !previous_joint_minus => depth = depth.saturating_sub(1),
+							_ => (),
+						}
+						punct.as_char() == '-' && punct.spacing() == Spacing::Joint
+					},
+					TokenTree::Group(group) if group.delimiter() == Delimiter::Brace && depth == 0 => break,
+					_ => false,
+				};
+				previous_joint_minus = joint_minus;
+				tokens.extend(std::iter::once(token));
+				rest = next;
+			}
+			Ok((tokens, rest))
+		})?;
+
+		if tokens.is_empty() {
+			return Err(syn::Error::new(span, "expected response type"));
+		}
+		syn::parse2(tokens)
+	}
+
+	/// Parse the optional `! ErrorType` suffix of a service.
+	fn parse_error_type(input: syn::parse::ParseStream) -> syn::Result<Option<ServiceErrorType>> {
+		if input.peek(syn::Token![!]) {
+			Ok(Some(ServiceErrorType {
+				_bang: input.parse()?,
+				error_type: input.parse()?,
+			}))
+		} else {
+			Ok(None)
+		}
+	}
+
 	impl syn::parse::Parse for MaybeServiceBody {
 		fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
 			if input.peek(syn::token::Comma) {
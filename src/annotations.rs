@@ -0,0 +1,108 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+/// Typed data attached to a received request.
+///
+/// Annotations hold at most one value of each type.
+/// An [`Interceptor`][crate::Interceptor] can add annotations to incoming requests with [`Interceptor::annotate()`][crate::Interceptor::annotate],
+/// for example the authenticated user extracted from the message.
+/// The annotations travel with the [`ReceivedRequestHandle`][crate::ReceivedRequestHandle] to the handler of the request,
+/// so the information does not have to be extracted again by every layer.
+///
+/// Use a dedicated type for each annotation to avoid conflicts between different interceptors.
+#[derive(Default)]
+pub struct Annotations {
+	/// The annotations by type.
+	values: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Annotations {
+	/// Create a new empty set of annotations.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Add an annotation.
+	///
+	/// If an annotation of the same type was already present, it is replaced and the old value is returned.
+	pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+		let old = self.values.insert(TypeId::of::<T>(), Box::new(value))?;
+		old.downcast().ok().map(|old| *old)
+	}
+
+	/// Get a reference to the annotation of type `T`, if present.
+	pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+		self.values.get(&TypeId::of::<T>())?.downcast_ref()
+	}
+
+	/// Get a mutable reference to the annotation of type `T`, if present.
+	pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+		self.values.get_mut(&TypeId::of::<T>())?.downcast_mut()
+	}
+
+	/// Remove the annotation of type `T` and return it, if present.
+	pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+		let value = self.values.remove(&TypeId::of::<T>())?;
+		value.downcast().ok().map(|value| *value)
+	}
+
+	/// Check if an annotation of type `T` is present.
+	pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+		self.values.contains_key(&TypeId::of::<T>())
+	}
+
+	/// Get the number of annotations.
+	pub fn len(&self) -> usize {
+		self.values.len()
+	}
+
+	/// Check if there are no annotations.
+	pub fn is_empty(&self) -> bool {
+		self.values.is_empty()
+	}
+
+	/// Remove all annotations.
+	pub fn clear(&mut self) {
+		self.values.clear()
+	}
+}
+
+impl std::fmt::Debug for Annotations {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("Annotations")
+			.field("len", &self.len())
+			.finish_non_exhaustive()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::{assert, let_assert};
+
+	#[derive(Debug, Eq, PartialEq)]
+	struct User(String);
+
+	#[test]
+	fn insert_get_remove() {
+		let mut annotations = Annotations::new();
+		assert!(annotations.is_empty());
+		assert!(annotations.insert(User("alice".into())) == None);
+		assert!(annotations.insert(5u32) == None);
+		assert!(annotations.len() == 2);
+
+		assert!(annotations.get::<User>() == Some(&User("alice".into())));
+		assert!(annotations.get::<u64>() == None);
+		assert!(annotations.contains::<u32>());
+
+		let_assert!(Some(value) = annotations.get_mut::<u32>());
+		*value += 1;
+		assert!(annotations.insert(User("bob".into())) == Some(User("alice".into())));
+		assert!(annotations.remove::<u32>() == Some(6));
+		assert!(annotations.remove::<u32>() == None);
+		assert!(annotations.len() == 1);
+
+		annotations.clear();
+		assert!(annotations.is_empty());
+	}
+}
@@ -0,0 +1,219 @@
+use crate::error::private::connection_aborted;
+use crate::{Error, PeerWriteHandle};
+
+/// Utility to send stream messages to multiple peers at once.
+///
+/// The broadcaster holds a list of [`PeerWriteHandle`] objects.
+/// Each broadcast sends the same stream message to all registered peers.
+///
+/// Peers are removed automatically when their connection is closed.
+/// Note that a registered write handle keeps the peer loop running,
+/// just like any other [`PeerWriteHandle`].
+pub struct Broadcaster<Body> {
+	/// The write handles of the registered peers.
+	peers: Vec<PeerWriteHandle<Body>>,
+}
+
+impl<Body> Broadcaster<Body> {
+	/// Create a new broadcaster without any registered peers.
+	pub fn new() -> Self {
+		Self { peers: Vec::new() }
+	}
+
+	/// Register a peer with the broadcaster.
+	///
+	/// If the peer is already registered, the handle is dropped and `false` is returned.
+	pub fn add(&mut self, peer: PeerWriteHandle<Body>) -> bool {
+		if self.contains(&peer) {
+			false
+		} else {
+			self.peers.push(peer);
+			true
+		}
+	}
+
+	/// Remove a peer from the broadcaster.
+	///
+	/// Returns `true` if the peer was registered with the broadcaster.
+	pub fn remove(&mut self, peer: &PeerWriteHandle<Body>) -> bool {
+		let len = self.peers.len();
+		self.peers.retain(|x| !x.same_peer(peer));
+		self.peers.len() != len
+	}
+
+	/// Check if a peer is registered with the broadcaster.
+	pub fn contains(&self, peer: &PeerWriteHandle<Body>) -> bool {
+		self.peers.iter().any(|x| x.same_peer(peer))
+	}
+
+	/// Get the number of registered peers.
+	///
+	/// This may include peers that disconnected since the last broadcast.
+	pub fn len(&self) -> usize {
+		self.peers.len()
+	}
+
+	/// Check if the broadcaster has no registered peers.
+	pub fn is_empty(&self) -> bool {
+		self.peers.is_empty()
+	}
+
+	/// Remove all registered peers.
+	pub fn clear(&mut self) {
+		self.peers.clear()
+	}
+
+	/// Send a stream message to all registered peers.
+	///
+	/// The body is cloned for each peer.
+	/// For body types that can not be cloned, use [`Self::send_stream_with()`].
+	///
+	/// Returns the number of peers that the message was written to.
+	/// Peers with a closed connection are removed from the broadcaster.
+	/// Peers that failed with a different error stay registered, but are not counted.
+	pub async fn send_stream(&mut self, service_id: i32, body: &Body) -> usize
+	where
+		Body: Clone,
+	{
+		self.send_stream_with(service_id, || body.clone()).await
+	}
+
+	/// Send a stream message to all registered peers, creating a new body for each peer.
+	///
+	/// The `make_body` function is called once for each registered peer.
+	///
+	/// Returns the number of peers that the message was written to.
+	/// Peers with a closed connection are removed from the broadcaster.
+	/// Peers that failed with a different error stay registered, but are not counted.
+	pub async fn send_stream_with<F>(&mut self, service_id: i32, mut make_body: F) -> usize
+	where
+		F: FnMut() -> Body,
+	{
+		// Queue the message for all peers first, so they can all write it concurrently.
+		let pending: Vec<_> = self.peers.iter()
+			.map(|peer| peer.queue_stream(service_id, make_body()))
+			.collect();
+
+		let mut written = 0;
+		let mut closed = Vec::new();
+		for (i, result_rx) in pending.into_iter().enumerate() {
+			let result = match result_rx {
+				Ok(result_rx) => result_rx.await.unwrap_or_else(|_| Err(connection_aborted())),
+				Err(e) => Err(e),
+			};
+			match result {
+				Ok(()) => written += 1,
+				Err(e) if e.is_connection_aborted() => closed.push(i),
+				Err(_) => (),
+			}
+		}
+
+		for i in closed.into_iter().rev() {
+			self.peers.remove(i);
+		}
+
+		written
+	}
+
+	/// Encode a value once and send it as stream message to all registered peers.
+	///
+	/// The encoded body is cloned for each peer.
+	///
+	/// Returns the number of peers that the message was written to,
+	/// or an error if the value could not be encoded.
+	/// Peers with a closed connection are removed from the broadcaster.
+	pub async fn send_encoded<F, T>(&mut self, service_id: i32, value: &T) -> Result<usize, Error>
+	where
+		F: crate::format::EncodeBody<T, Body = Body>,
+		T: ?Sized,
+		Body: crate::Body + Clone,
+	{
+		let body = F::encode_body(value).map_err(Error::encode_failed)?;
+		Ok(self.send_stream(service_id, &body).await)
+	}
+}
+
+impl<Body> Default for Broadcaster<Body> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl<Body> Extend<PeerWriteHandle<Body>> for Broadcaster<Body> {
+	fn extend<I: IntoIterator<Item = PeerWriteHandle<Body>>>(&mut self, iter: I) {
+		for peer in iter {
+			self.add(peer);
+		}
+	}
+}
+
+impl<Body> FromIterator<PeerWriteHandle<Body>> for Broadcaster<Body> {
+	fn from_iter<I: IntoIterator<Item = PeerWriteHandle<Body>>>(iter: I) -> Self {
+		let mut broadcaster = Self::new();
+		broadcaster.extend(iter);
+		broadcaster
+	}
+}
+
+impl<Body> std::fmt::Debug for Broadcaster<Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("Broadcaster")
+			.field("peers", &self.peers.len())
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+
+	use crate::{ReceivedMessage, StreamBody, UnixStreamPeer, UnixStreamTransport};
+	use tokio::net::UnixStream;
+
+	#[tokio::test]
+	async fn broadcast_to_all_peers() {
+		let_assert!(Ok((peer_a, remote_a)) = UnixStream::pair());
+		let_assert!(Ok((peer_b, remote_b)) = UnixStream::pair());
+		let (_read_a, write_a) = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, Default::default())).split();
+		let (_read_b, write_b) = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default())).split();
+		let mut remote_a = UnixStreamPeer::spawn(UnixStreamTransport::new(remote_a, Default::default()));
+		let mut remote_b = UnixStreamPeer::spawn(UnixStreamTransport::new(remote_b, Default::default()));
+
+		let mut broadcaster = Broadcaster::new();
+		assert!(broadcaster.add(write_a.clone()));
+		assert!(broadcaster.add(write_b));
+		assert!(!broadcaster.add(write_a));
+		assert!(broadcaster.len() == 2);
+
+		assert!(broadcaster.send_stream(7, &StreamBody::from(&b"Hello all!"[..])).await == 2);
+
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = remote_a.recv_message().await);
+		assert!(message.header.service_id == 7);
+		assert!(message.body.as_ref() == b"Hello all!");
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = remote_b.recv_message().await);
+		assert!(message.header.service_id == 7);
+		assert!(message.body.as_ref() == b"Hello all!");
+	}
+
+	#[tokio::test]
+	async fn closed_peers_are_removed() {
+		let_assert!(Ok((peer_a, remote_a)) = UnixStream::pair());
+		let_assert!(Ok((peer_b, remote_b)) = UnixStream::pair());
+		let (_read_a, write_a) = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, Default::default())).split();
+		let (_read_b, write_b) = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default())).split();
+		let mut remote_a = UnixStreamPeer::spawn(UnixStreamTransport::new(remote_a, Default::default()));
+		drop(remote_b);
+
+		let mut broadcaster: Broadcaster<StreamBody> = [write_a, write_b.clone()].into_iter().collect();
+		write_b.close();
+
+		assert!(broadcaster.send_stream_with(3, || StreamBody::from(&b"Bye"[..])).await == 1);
+		assert!(broadcaster.len() == 1);
+		assert!(!broadcaster.contains(&write_b));
+
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = remote_a.recv_message().await);
+		assert!(message.body.as_ref() == b"Bye");
+	}
+}
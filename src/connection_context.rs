@@ -0,0 +1,67 @@
+use std::any::Any;
+use std::sync::Arc;
+
+/// Application data attached to a connection.
+///
+/// The context holds at most one shared value of any type, for example the authorization state of the remote peer.
+/// It can be set with [`Peer::with_context()`][crate::Peer::with_context],
+/// with [`Peer::connect_with_context()`][crate::Peer::connect_with_context],
+/// or by the context factory of a [`Listener`][crate::Listener] for every accepted connection.
+///
+/// The context of a peer is available from its handles with `context::<T>()`,
+/// and it is attached to the [`Annotations`][crate::Annotations] of every received request,
+/// so request handlers can use it without a separate table indexed by connection.
+#[derive(Clone, Default)]
+pub struct ConnectionContext {
+	/// The attached value, if any.
+	value: Option<Arc<dyn Any + Send + Sync>>,
+}
+
+impl ConnectionContext {
+	/// Create a new context holding the given value.
+	pub fn new<T: Send + Sync + 'static>(value: Arc<T>) -> Self {
+		Self { value: Some(value) }
+	}
+
+	/// Create a new empty context.
+	pub fn empty() -> Self {
+		Self::default()
+	}
+
+	/// Get the attached value, if it has type `T`.
+	pub fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+		self.value.clone()?.downcast().ok()
+	}
+
+	/// Check if the context holds no value.
+	pub fn is_empty(&self) -> bool {
+		self.value.is_none()
+	}
+}
+
+impl std::fmt::Debug for ConnectionContext {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("ConnectionContext")
+			.field("is_empty", &self.is_empty())
+			.finish_non_exhaustive()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::{assert, let_assert};
+
+	#[test]
+	fn connection_context() {
+		assert!(ConnectionContext::empty().is_empty());
+		assert!(ConnectionContext::empty().get::<u32>() == None);
+
+		let value = Arc::new(String::from("admin"));
+		let context = ConnectionContext::new(value.clone());
+		assert!(!context.is_empty());
+		assert!(context.get::<u32>() == None);
+		let_assert!(Some(shared) = context.get::<String>());
+		assert!(Arc::ptr_eq(&shared, &value));
+	}
+}
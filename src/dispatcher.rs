@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+
+use crate::{Error, PeerReadHandle, ReceivedMessage, ReceivedRequestHandle};
+
+/// Future returned by a [`RequestHandler`].
+pub type HandlerFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Map of shared request handlers by service ID.
+type HandlerMap<Body> = HashMap<i32, Arc<dyn RequestHandler<Body>>>;
+
+/// Handler for incoming requests of a service.
+///
+/// The handler is responsible for sending the response for the request.
+/// Any `Fn(ReceivedRequestHandle<Body>, Body) -> impl Future<Output = ()>` closure can be used as request handler.
+pub trait RequestHandler<Body>: Send + Sync + 'static {
+	/// Handle an incoming request.
+	fn handle(&self, request: ReceivedRequestHandle<Body>, body: Body) -> HandlerFuture;
+}
+
+impl<Body, F, R> RequestHandler<Body> for F
+where
+	F: Fn(ReceivedRequestHandle<Body>, Body) -> R + Send + Sync + 'static,
+	R: Future<Output = ()> + Send + 'static,
+{
+	fn handle(&self, request: ReceivedRequestHandle<Body>, body: Body) -> HandlerFuture {
+		Box::pin(self(request, body))
+	}
+}
+
+/// Registry of request handlers by service ID.
+///
+/// The dispatcher passes each incoming request to the handler registered for the service ID of the request.
+/// Each request is handled in a separate task.
+/// Requests for service IDs without a handler are answered with an error response.
+///
+/// Handlers can be added, replaced and removed at any time, also while connections are being served.
+/// Replacing a handler is atomic: requests received after the swap use the new handler,
+/// while requests that are already being handled finish on the old handler.
+/// This allows long-lived connections to pick up new logic without reconnecting.
+///
+/// The dispatcher can be cloned cheaply.
+/// All clones share the same handlers, so a single dispatcher can serve many connections.
+pub struct Dispatcher<Body> {
+	/// The registered handlers by service ID.
+	handlers: Arc<RwLock<HandlerMap<Body>>>,
+}
+
+impl<Body: crate::Body> Dispatcher<Body> {
+	/// Create a new dispatcher without any handlers.
+	pub fn new() -> Self {
+		Self {
+			handlers: Arc::new(RwLock::new(HashMap::new())),
+		}
+	}
+
+	/// Set the handler for a service ID.
+	///
+	/// If a handler was already registered for the service ID, it is replaced and `true` is returned.
+	/// Requests that are already being handled by the old handler are not affected.
+	pub fn set_handler(&self, service_id: i32, handler: impl RequestHandler<Body>) -> bool {
+		let mut handlers = match self.handlers.write() {
+			Ok(x) => x,
+			Err(e) => e.into_inner(),
+		};
+		handlers.insert(service_id, Arc::new(handler)).is_some()
+	}
+
+	/// Remove the handler for a service ID.
+	///
+	/// Returns `true` if a handler was registered for the service ID.
+	pub fn remove_handler(&self, service_id: i32) -> bool {
+		let mut handlers = match self.handlers.write() {
+			Ok(x) => x,
+			Err(e) => e.into_inner(),
+		};
+		handlers.remove(&service_id).is_some()
+	}
+
+	/// Check if a handler is registered for a service ID.
+	pub fn has_handler(&self, service_id: i32) -> bool {
+		self.handler(service_id).is_some()
+	}
+
+	/// Dispatch a single request to the registered handler.
+	///
+	/// The handler is run in a new task.
+	/// If no handler is registered for the service ID, an error response is sent instead.
+	///
+	/// The returned error only indicates a failure to send the error response.
+	pub async fn dispatch(&self, request: ReceivedRequestHandle<Body>, body: Body) -> Result<(), Error> {
+		match self.handler(request.service_id()) {
+			Some(handler) => {
+				tokio::spawn(handler.handle(request, body));
+				Ok(())
+			},
+			None => {
+				let message = format!("unknown service ID: {}", request.service_id());
+				request.send_error_response(&message).await
+			},
+		}
+	}
+
+	/// Dispatch all incoming requests from a peer until the connection is closed.
+	///
+	/// Incoming stream messages are discarded.
+	///
+	/// Returns `Ok(())` when the connection is closed, or an error if receiving a message failed for a different reason.
+	pub async fn serve(&self, mut peer: PeerReadHandle<Body>) -> Result<(), Error> {
+		loop {
+			match peer.recv_message().await {
+				Ok(ReceivedMessage::Request(request, body)) => {
+					// A failure to send an error response does not affect other requests.
+					let _: Result<_, _> = self.dispatch(request, body).await;
+				},
+				Ok(ReceivedMessage::Stream(message)) => {
+					let _ = message;
+					trace_event!(debug, service_id = message.header.service_id, "discarding stream message in dispatcher");
+				},
+				Err(e) if e.is_connection_aborted() => return Ok(()),
+				Err(e) => return Err(e),
+			}
+		}
+	}
+
+	/// Get the handler for a service ID.
+	fn handler(&self, service_id: i32) -> Option<Arc<dyn RequestHandler<Body>>> {
+		let handlers = match self.handlers.read() {
+			Ok(x) => x,
+			Err(e) => e.into_inner(),
+		};
+		handlers.get(&service_id).cloned()
+	}
+}
+
+impl<Body: crate::Body> Default for Dispatcher<Body> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl<Body> Clone for Dispatcher<Body> {
+	fn clone(&self) -> Self {
+		Self {
+			handlers: self.handlers.clone(),
+		}
+	}
+}
+
+impl<Body> std::fmt::Debug for Dispatcher<Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		let handlers = match self.handlers.read() {
+			Ok(x) => x,
+			Err(e) => e.into_inner(),
+		};
+		let mut service_ids: Vec<i32> = handlers.keys().copied().collect();
+		service_ids.sort_unstable();
+		f.debug_struct(core::any::type_name::<Self>())
+			.field("service_ids", &service_ids)
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+	use tokio::sync::oneshot;
+
+	use crate::{PeerWriteHandle, StreamBody, UnixStreamPeer, UnixStreamTransport};
+
+	/// Send a request and get the response body as string.
+	async fn call(peer: &PeerWriteHandle<StreamBody>, service_id: i32) -> Result<String, Error> {
+		let mut request = peer.send_request(service_id, &b""[..]).await?;
+		let response = request.recv_response().await?.check_error_response()?;
+		Ok(String::from_utf8_lossy(&response.body).into_owned())
+	}
+
+	#[tokio::test]
+	async fn swap_handler_with_request_in_flight() {
+		let_assert!(Ok((peer_a, peer_b)) = tokio::net::UnixStream::pair());
+		let (_read_a, write_a) = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, Default::default())).split();
+		let (read_b, _write_b) = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default())).split();
+
+		let dispatcher = Dispatcher::new();
+		let (release_tx, release_rx) = oneshot::channel::<()>();
+		let release_rx = std::sync::Mutex::new(Some(release_rx));
+		assert!(!dispatcher.set_handler(1, move |request: ReceivedRequestHandle<StreamBody>, _body| {
+			let release = release_rx.lock().unwrap().take();
+			async move {
+				if let Some(release) = release {
+					let _ = release.await;
+				}
+				let _ = request.send_response(1, &b"old"[..]).await;
+			}
+		}));
+
+		let server = tokio::spawn({
+			let dispatcher = dispatcher.clone();
+			async move { dispatcher.serve(read_b).await }
+		});
+
+		// Start a request on the old handler, and keep it in flight.
+		// Requests are dispatched in order, so the old handler has the request once the next request is answered.
+		let_assert!(Ok(mut in_flight) = write_a.send_request(1, &b""[..]).await);
+		let_assert!(Err(e) = call(&write_a, 2).await);
+		assert!(e.as_remote_error() == Some("unknown service ID: 2"));
+
+		// Swap the handler: new requests use the new handler.
+		assert!(dispatcher.set_handler(1, |request: ReceivedRequestHandle<StreamBody>, _body| async move {
+			let _ = request.send_response(1, &b"new"[..]).await;
+		}));
+		assert!(let Ok("new") = call(&write_a, 1).await.as_deref());
+
+		// The request in flight finishes on the old handler.
+		assert!(let Ok(()) = release_tx.send(()));
+		let_assert!(Ok(response) = in_flight.recv_response().await);
+		assert!(response.body.as_ref() == b"old");
+
+		assert!(dispatcher.remove_handler(1));
+		assert!(!dispatcher.has_handler(1));
+		write_a.close();
+		assert!(let Ok(Ok(())) = server.await);
+	}
+}
@@ -0,0 +1,487 @@
+//! Runtime-configured interfaces for generic tooling.
+//!
+//! Normally, interfaces are defined at compile time with the [`interface!`][crate::interface] macro.
+//! For generic tools like test consoles or fuzzers, it is useful to drive any service without compiling its interface.
+//! This module provides a [`DynamicInterface`] that is created from an [`InterfaceDefinition`] at runtime,
+//! and a [`DynamicClient`] that sends and receives message bodies that are already encoded.
+//!
+//! The bodies are not decoded by the client, but all messages are validated against the interface definition:
+//! * outgoing messages are addressed by name, and the given type name must match the type name in the definition,
+//! * incoming updates, responses and stream messages must have a service ID that is declared in the definition,
+//! * incoming messages are reported with their name and type name, so the tool can decode the body.
+//!
+//! The type names are taken from the type information of the definition with the [`TypeName`] trait.
+//!
+//! With the `serde` feature, the [`InterfaceDefinition`] and related types implement `serde::Serialize` and `serde::Deserialize`.
+//! You can then store the definition of a generated interface in a file and load it again in the tool:
+//!
+//! ```no_run
+//! # use fizyr_rpc::dynamic::{DynamicClient, DynamicInterface};
+//! # use fizyr_rpc::introspection::InterfaceDefinition;
+//! # async fn foo(peer: fizyr_rpc::PeerWriteHandle<fizyr_rpc::StreamBody>, data: &[u8], request: Vec<u8>) -> Result<(), fizyr_rpc::Error> {
+//! let definition: InterfaceDefinition<String> = postcard::from_bytes(data).unwrap();
+//! let interface = DynamicInterface::new(definition)?;
+//! let client = DynamicClient::new(interface, peer);
+//! let mut sent_request = client.send_request("record", "RecordRequest", request).await?;
+//! while let Some(update) = sent_request.recv_update().await? {
+//!     println!("received {} update with body of type {}", update.name, update.type_name);
+//! }
+//! let response = sent_request.recv_response().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::introspection::{InterfaceDefinition, ServiceDefinition, StreamDefinition, UpdateDefinition};
+use crate::{Error, Message, PeerWriteHandle, SentRequestHandle};
+
+/// Trait for type information that contains the name of the type.
+pub trait TypeName {
+	/// Get the name of the type.
+	fn type_name(&self) -> &str;
+}
+
+impl TypeName for String {
+	fn type_name(&self) -> &str {
+		self
+	}
+}
+
+impl TypeName for &'static str {
+	fn type_name(&self) -> &str {
+		self
+	}
+}
+
+#[cfg(feature = "schemars")]
+impl TypeName for crate::introspection::TypeSchema {
+	fn type_name(&self) -> &str {
+		&self.type_name
+	}
+}
+
+/// An interface definition that has been checked for use by a [`DynamicClient`].
+#[derive(Debug, Clone)]
+pub struct DynamicInterface<TypeInfo> {
+	/// The interface definition.
+	definition: InterfaceDefinition<TypeInfo>,
+
+	/// The index of each service by name.
+	services: HashMap<String, usize>,
+
+	/// The index of each stream by name.
+	streams: HashMap<String, usize>,
+
+	/// The index of each stream by service ID.
+	stream_ids: HashMap<i32, usize>,
+}
+
+/// A client for a [`DynamicInterface`].
+///
+/// The client can be cloned cheaply.
+pub struct DynamicClient<TypeInfo, Body> {
+	/// The interface of the client.
+	interface: Arc<DynamicInterface<TypeInfo>>,
+
+	/// The peer to send messages to.
+	peer: PeerWriteHandle<Body>,
+}
+
+/// A request sent by a [`DynamicClient`].
+pub struct DynamicSentRequest<TypeInfo, Body> {
+	/// The interface of the client that sent the request.
+	interface: Arc<DynamicInterface<TypeInfo>>,
+
+	/// The index of the service in the interface.
+	service: usize,
+
+	/// The request handle.
+	request: SentRequestHandle<Body>,
+}
+
+/// A message that was validated against a [`DynamicInterface`].
+#[derive(Debug)]
+pub struct DynamicMessage<Body> {
+	/// The name of the update or stream, or the name of the service for responses.
+	pub name: String,
+
+	/// The type name of the body according to the interface definition.
+	pub type_name: String,
+
+	/// The message.
+	pub message: Message<Body>,
+}
+
+/// The response to a request sent by a [`DynamicClient`].
+#[derive(Debug)]
+pub enum DynamicResponse<Body> {
+	/// A regular response.
+	Response(DynamicMessage<Body>),
+
+	/// A structured service error, for services that declare an error type.
+	ServiceError(DynamicMessage<Body>),
+}
+
+impl<TypeInfo: TypeName> DynamicInterface<TypeInfo> {
+	/// Check an interface definition and prepare it for use by a [`DynamicClient`].
+	///
+	/// Returns an error if the definition contains duplicate names or service IDs,
+	/// because messages could not be routed unambiguously.
+	pub fn new(definition: InterfaceDefinition<TypeInfo>) -> Result<Self, Error> {
+		let mut services = HashMap::new();
+		let mut service_ids = HashMap::new();
+		for (i, service) in definition.services.iter().enumerate() {
+			check_unique(&mut services, &service.name, i, "service name")?;
+			check_unique(&mut service_ids, &service.service_id, i, "service ID")?;
+			check_unique_updates(&service.name, "request update", &service.request_updates)?;
+			check_unique_updates(&service.name, "response update", &service.response_updates)?;
+		}
+
+		let mut streams = HashMap::new();
+		let mut stream_ids = HashMap::new();
+		for (i, stream) in definition.streams.iter().enumerate() {
+			check_unique(&mut streams, &stream.name, i, "stream name")?;
+			check_unique(&mut stream_ids, &stream.service_id, i, "stream service ID")?;
+		}
+
+		Ok(Self {
+			definition,
+			services,
+			streams,
+			stream_ids,
+		})
+	}
+
+	/// Get the interface definition.
+	pub fn definition(&self) -> &InterfaceDefinition<TypeInfo> {
+		&self.definition
+	}
+
+	/// Get a service by name.
+	pub fn service(&self, name: &str) -> Option<&ServiceDefinition<TypeInfo>> {
+		let index = self.services.get(name)?;
+		Some(&self.definition.services[*index])
+	}
+
+	/// Get a stream by name.
+	pub fn stream(&self, name: &str) -> Option<&StreamDefinition<TypeInfo>> {
+		let index = self.streams.get(name)?;
+		Some(&self.definition.streams[*index])
+	}
+
+	/// Validate an incoming stream message.
+	///
+	/// Returns an error if the service ID of the message is not a stream in the interface.
+	pub fn parse_stream<Body>(&self, message: Message<Body>) -> Result<DynamicMessage<Body>, Error> {
+		let index = self.stream_ids.get(&message.header.service_id)
+			.ok_or_else(|| Error::unexpected_service_id(message.header.service_id))?;
+		let stream = &self.definition.streams[*index];
+		Ok(DynamicMessage::new(&stream.name, &stream.body, message))
+	}
+}
+
+impl<TypeInfo: TypeName, Body: crate::Body> DynamicClient<TypeInfo, Body> {
+	/// Create a new client for an interface.
+	pub fn new(interface: impl Into<Arc<DynamicInterface<TypeInfo>>>, peer: PeerWriteHandle<Body>) -> Self {
+		Self {
+			interface: interface.into(),
+			peer,
+		}
+	}
+
+	/// Get the interface of the client.
+	pub fn interface(&self) -> &DynamicInterface<TypeInfo> {
+		&self.interface
+	}
+
+	/// Get the write handle of the peer.
+	pub fn peer(&self) -> &PeerWriteHandle<Body> {
+		&self.peer
+	}
+
+	/// Send a request for a service.
+	///
+	/// The `type_name` must match the type name of the request body in the interface definition.
+	pub async fn send_request(&self, service: &str, type_name: &str, body: impl Into<Body>) -> Result<DynamicSentRequest<TypeInfo, Body>, Error> {
+		let index = *self.interface.services.get(service)
+			.ok_or_else(|| Error::custom(format!("unknown service: {}", service)))?;
+		let definition = &self.interface.definition.services[index];
+		check_type_name(&definition.request_body, type_name, || format!("request of service {}", service))?;
+		let request = self.peer.send_request(definition.service_id, body).await?;
+		Ok(DynamicSentRequest {
+			interface: self.interface.clone(),
+			service: index,
+			request,
+		})
+	}
+
+	/// Send a stream message.
+	///
+	/// The `type_name` must match the type name of the stream body in the interface definition.
+	pub async fn send_stream(&self, stream: &str, type_name: &str, body: impl Into<Body>) -> Result<(), Error> {
+		let definition = self.interface.stream(stream)
+			.ok_or_else(|| Error::custom(format!("unknown stream: {}", stream)))?;
+		check_type_name(&definition.body, type_name, || format!("stream {}", stream))?;
+		self.peer.send_stream(definition.service_id, body).await
+	}
+}
+
+impl<TypeInfo: TypeName, Body: crate::Body> DynamicSentRequest<TypeInfo, Body> {
+	/// Get the definition of the service of the request.
+	pub fn service(&self) -> &ServiceDefinition<TypeInfo> {
+		&self.interface.definition.services[self.service]
+	}
+
+	/// Get the request ID of the request.
+	pub fn request_id(&self) -> u32 {
+		self.request.request_id()
+	}
+
+	/// Send a request update.
+	///
+	/// The `type_name` must match the type name of the update body in the interface definition.
+	pub async fn send_update(&self, update: &str, type_name: &str, body: impl Into<Body>) -> Result<(), Error> {
+		let service = self.service();
+		let definition = service.request_updates.iter()
+			.find(|x| x.name == update)
+			.ok_or_else(|| Error::custom(format!("unknown request update for service {}: {}", service.name, update)))?;
+		check_type_name(&definition.body, type_name, || format!("request update {} of service {}", update, service.name))?;
+		self.request.send_update(definition.service_id, body).await
+	}
+
+	/// Receive the next response update.
+	///
+	/// Returns `Ok(None)` if the response is received instead of an update message.
+	/// The response can then be read with [`Self::recv_response()`].
+	///
+	/// Returns an error if the update has a service ID that is not declared for the service.
+	pub async fn recv_update(&mut self) -> Result<Option<DynamicMessage<Body>>, Error> {
+		let message = match self.request.recv_update().await {
+			Some(x) => x,
+			None => return Ok(None),
+		};
+		let definition = self.service().response_updates.iter()
+			.find(|x| x.service_id == message.header.service_id)
+			.ok_or_else(|| Error::unexpected_service_id(message.header.service_id))?;
+		Ok(Some(DynamicMessage::new(&definition.name, &definition.body, message)))
+	}
+
+	/// Receive the response of the request.
+	///
+	/// Error responses from the remote peer are returned as [remote errors][Error::remote_error].
+	/// Returns an error if the response has a service ID that does not match the service,
+	/// or if it is a structured service error for a service that does not declare an error type.
+	pub async fn recv_response(&mut self) -> Result<DynamicResponse<Body>, Error> {
+		let response = self.request.recv_response().await?;
+		let service = self.service();
+		// Structured service errors must be handled before `check_error_response()` turns them into a plain service error.
+		if let (crate::service_id::SERVICE_ERROR, Some(error_body)) = (response.header.service_id, &service.error_body) {
+			return Ok(DynamicResponse::ServiceError(DynamicMessage::new(&service.name, error_body, response)));
+		}
+		let response = response.check_error_response()?;
+		if response.header.service_id == service.service_id {
+			Ok(DynamicResponse::Response(DynamicMessage::new(&service.name, &service.response_body, response)))
+		} else {
+			Err(Error::unexpected_service_id(response.header.service_id))
+		}
+	}
+
+	/// Get the request handle.
+	pub fn into_inner(self) -> SentRequestHandle<Body> {
+		self.request
+	}
+}
+
+impl<Body> DynamicMessage<Body> {
+	/// Create a new validated message.
+	fn new(name: &str, type_info: &impl TypeName, message: Message<Body>) -> Self {
+		Self {
+			name: name.into(),
+			type_name: type_info.type_name().into(),
+			message,
+		}
+	}
+}
+
+impl<TypeInfo, Body> Clone for DynamicClient<TypeInfo, Body> {
+	fn clone(&self) -> Self {
+		Self {
+			interface: self.interface.clone(),
+			peer: self.peer.clone(),
+		}
+	}
+}
+
+impl<TypeInfo, Body> std::fmt::Debug for DynamicClient<TypeInfo, Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct(core::any::type_name::<Self>())
+			.field("interface", &self.interface.definition.name)
+			.finish_non_exhaustive()
+	}
+}
+
+impl<TypeInfo, Body> std::fmt::Debug for DynamicSentRequest<TypeInfo, Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct(core::any::type_name::<Self>())
+			.field("service", &self.interface.definition.services[self.service].name)
+			.field("request_id", &self.request.request_id())
+			.finish_non_exhaustive()
+	}
+}
+
+/// Insert a key in a map, or return an error if the key is already present.
+fn check_unique<K>(map: &mut HashMap<K, usize>, key: &K, index: usize, what: &str) -> Result<(), Error>
+where
+	K: std::hash::Hash + Eq + Clone + std::fmt::Display,
+{
+	if map.insert(key.clone(), index).is_some() {
+		return Err(Error::custom(format!("duplicate {} in interface definition: {}", what, key)));
+	}
+	Ok(())
+}
+
+/// Check that the names and service IDs of the updates of a service are unique.
+fn check_unique_updates<TypeInfo>(service: &str, what: &str, updates: &[UpdateDefinition<TypeInfo>]) -> Result<(), Error> {
+	let mut names = HashMap::new();
+	let mut service_ids = HashMap::new();
+	for (i, update) in updates.iter().enumerate() {
+		check_unique(&mut names, &update.name, i, &format!("{} name for service {}", what, service))?;
+		check_unique(&mut service_ids, &update.service_id, i, &format!("{} service ID for service {}", what, service))?;
+	}
+	Ok(())
+}
+
+/// Check that a type name matches the type information from the interface definition.
+fn check_type_name(expected: &impl TypeName, actual: &str, what: impl FnOnce() -> String) -> Result<(), Error> {
+	if expected.type_name() != actual {
+		return Err(Error::custom(format!("wrong body type for {}: expected {}, got {}", what(), expected.type_name(), actual)));
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::{assert, let_assert};
+
+	use crate::{ReceivedMessage, StreamBody, UnixStreamPeer, UnixStreamTransport};
+
+	fn update(name: &str, service_id: i32, body: &'static str) -> UpdateDefinition<&'static str> {
+		UpdateDefinition {
+			name: name.into(),
+			doc: String::new(),
+			hidden: false,
+			service_id,
+			body,
+		}
+	}
+
+	fn camera() -> InterfaceDefinition<&'static str> {
+		InterfaceDefinition {
+			name: "Camera".into(),
+			doc: String::new(),
+			hidden: false,
+			services: vec![ServiceDefinition {
+				name: "record".into(),
+				doc: String::new(),
+				hidden: false,
+				service_id: 1,
+				request_body: "RecordRequest",
+				response_body: "()",
+				error_body: Some("RecordError"),
+				request_updates: vec![update("stop", 10, "()")],
+				response_updates: vec![update("image", 11, "Image")],
+			}],
+			streams: vec![StreamDefinition {
+				name: "state".into(),
+				doc: String::new(),
+				hidden: false,
+				ordered: true,
+				service_id: 2,
+				body: "State",
+			}],
+		}
+	}
+
+	#[test]
+	fn reject_ambiguous_definitions() {
+		assert!(let Ok(_) = DynamicInterface::new(camera()));
+
+		let mut definition = camera();
+		definition.streams[0].service_id = 1;
+		assert!(let Ok(_) = DynamicInterface::new(definition));
+
+		let mut definition = camera();
+		definition.streams.push(definition.streams[0].clone());
+		definition.streams[1].name = "other".into();
+		let_assert!(Err(e) = DynamicInterface::new(definition));
+		assert!(e.to_string().contains("duplicate stream service ID"));
+
+		let mut definition = camera();
+		definition.services[0].response_updates.push(update("image", 12, "Image"));
+		let_assert!(Err(e) = DynamicInterface::new(definition));
+		assert!(e.to_string().contains("duplicate response update name"));
+	}
+
+	#[tokio::test]
+	async fn request_and_stream() {
+		let_assert!(Ok((client, server)) = tokio::net::UnixStream::pair());
+		let client = UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default()));
+		let mut server = UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default()));
+		let_assert!(Ok(interface) = DynamicInterface::new(camera()));
+		let (mut client_read, client_write) = client.split();
+		let client = DynamicClient::<_, StreamBody>::new(interface, client_write);
+
+		// Names and type names are checked before anything is sent.
+		assert!(let Err(_) = client.send_request("play", "RecordRequest", &b""[..]).await);
+		assert!(let Err(_) = client.send_request("record", "PlayRequest", &b""[..]).await);
+		assert!(let Err(_) = client.send_stream("state", "Image", &b""[..]).await);
+
+		let_assert!(Ok(mut sent_request) = client.send_request("record", "RecordRequest", &b"go"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_request, body)) = server.recv_message().await);
+		assert!(received_request.service_id() == 1);
+		assert!(body.as_ref() == b"go");
+
+		let_assert!(Ok(()) = sent_request.send_update("stop", "()", &b""[..]).await);
+		assert!(let Err(_) = sent_request.send_update("image", "Image", &b""[..]).await);
+
+		// Updates are reported with their name, and unknown updates are rejected.
+		let_assert!(Ok(()) = received_request.send_update(11, &b"pixels"[..]).await);
+		let_assert!(Ok(()) = received_request.send_update(12, &b"what"[..]).await);
+		let_assert!(Ok(()) = received_request.send_response(crate::service_id::SERVICE_ERROR, &b"broken"[..]).await);
+		let_assert!(Ok(Some(update)) = sent_request.recv_update().await);
+		assert!(update.name == "image");
+		assert!(update.type_name == "Image");
+		assert!(update.message.body.as_ref() == b"pixels");
+		let_assert!(Err(e) = sent_request.recv_update().await);
+		assert!(e.as_unexpected_service_id() == Some(12));
+		let_assert!(Ok(None) = sent_request.recv_update().await);
+		let_assert!(Ok(DynamicResponse::ServiceError(error)) = sent_request.recv_response().await);
+		assert!(error.type_name == "RecordError");
+		assert!(error.message.body.as_ref() == b"broken");
+
+		// Incoming stream messages are validated by the interface.
+		let_assert!(Ok(()) = server.send_stream(2, &b"idle"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = client_read.recv_message().await);
+		let_assert!(Ok(stream) = client.interface().parse_stream(message));
+		assert!(stream.name == "state");
+		assert!(stream.type_name == "State");
+		assert!(let Err(_) = client.interface().parse_stream(Message::stream(0, 3, StreamBody::from(&b""[..]))));
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn load_serialized_definition() {
+		let definition = camera();
+		let_assert!(Ok(data) = postcard::to_stdvec(&definition));
+		let_assert!(Ok(loaded) = postcard::from_bytes::<InterfaceDefinition<String>>(&data));
+		let_assert!(Ok(interface) = DynamicInterface::new(loaded));
+		let_assert!(Some(service) = interface.service("record"));
+		assert!(service.request_body == "RecordRequest");
+		assert!(service.response_updates[0].name == "image");
+		let_assert!(Some(stream) = interface.stream("state"));
+		assert!(stream.service_id == 2);
+	}
+}
@@ -0,0 +1,33 @@
+use crate::{Error, MessageHeader};
+
+/// Policy to veto outgoing messages before they are written to the transport.
+///
+/// The peer consults the egress policy for every request, update, response and stream message sent through its handles.
+/// If the policy returns an error, the message is not written and the error is returned to the caller.
+///
+/// This can be used as a last line of defense to block certain messages at the communication layer,
+/// regardless of which part of the application tries to send them.
+///
+/// Error responses that the peer generates by itself, such as the rejection of incoming requests after the read handle was dropped,
+/// are not checked by the policy.
+///
+/// The policy runs inside the peer loop, so it should not block.
+/// Any `FnMut(&MessageHeader, usize) -> Result<(), Error>` closure can be used as egress policy.
+///
+/// Use [`PeerHandle::set_egress_policy()`][crate::PeerHandle::set_egress_policy] or
+/// [`PeerWriteHandle::set_egress_policy()`][crate::PeerWriteHandle::set_egress_policy] to install a policy.
+pub trait EgressPolicy: Send + 'static {
+	/// Check if a message may be sent.
+	///
+	/// The `body_len` is the size of the message body as reported by [`Body::data_len()`][crate::Body::data_len].
+	fn check(&mut self, header: &MessageHeader, body_len: usize) -> Result<(), Error>;
+}
+
+impl<F> EgressPolicy for F
+where
+	F: FnMut(&MessageHeader, usize) -> Result<(), Error> + Send + 'static,
+{
+	fn check(&mut self, header: &MessageHeader, body_len: usize) -> Result<(), Error> {
+		self(header, body_len)
+	}
+}
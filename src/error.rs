@@ -1,12 +1,124 @@
 //! Error types.
 
-/// Opaque error for all RPC operations.
+/// Prefix of the message of standardized "bad request" error responses.
+const BAD_REQUEST_PREFIX: &str = "bad request: ";
+
+/// Message of standardized "deadline exceeded" error responses.
+const DEADLINE_EXCEEDED_MESSAGE: &str = "deadline exceeded";
+
+/// Message of remote errors for structured service error responses.
+const SERVICE_ERROR_MESSAGE: &str = "service error";
+
+/// Prefix of the message of standardized "type fingerprint mismatch" error responses.
+const TYPE_FINGERPRINT_MISMATCH_PREFIX: &str = "type fingerprint mismatch";
+
+/// Error for all RPC operations.
+///
+/// Use [`Error::kind()`] to inspect what went wrong.
+/// The [`Display`][std::fmt::Display] implementation gives a human readable description of the error.
 #[derive(Debug)]
 pub struct Error {
 	pub(crate) inner: private::InnerError,
 }
 
-impl std::error::Error for Error {}
+/// The kind of an [`struct@Error`].
+///
+/// More kinds may be added in the future, so you should always have a fallback match arm.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+	/// An I/O error occurred.
+	///
+	/// Use [`Error::as_io_error()`] to get the underlying I/O error.
+	Io,
+
+	/// A received message is too short to be valid.
+	MessageTooShort,
+
+	/// A received message has an invalid message type in the header.
+	InvalidMessageType,
+
+	/// A message body exceeds the allowed size.
+	PayloadTooLarge,
+
+	/// A request ID is already associated with an open request.
+	DuplicateRequestId,
+
+	/// A request ID is not associated with an open request.
+	UnknownRequestId,
+
+	/// A received message has an unexpected message type.
+	UnexpectedMessageType,
+
+	/// A received message has an unexpected service ID.
+	UnexpectedServiceId,
+
+	/// No free request ID was found for a new request.
+	NoFreeRequestIdFound,
+
+	/// The request has already been closed.
+	RequestClosed,
+
+	/// An outgoing message body could not be encoded.
+	EncodeFailed,
+
+	/// An incoming message body could not be decoded.
+	DecodeFailed,
+
+	/// The remote peer responded with an error message.
+	///
+	/// See [`Error::remote_error()`] for more details.
+	RemoteError,
+
+	/// The remote peer responded that the service is temporarily unavailable.
+	///
+	/// See [`Error::retry_after()`] for more details.
+	RetryAfter,
+
+	/// An internal queue of the peer is full.
+	///
+	/// See [`Error::is_capacity_exceeded()`] for more details.
+	CapacityExceeded,
+
+	/// The remote peer opened more requests than allowed.
+	///
+	/// See [`Peer::with_max_open_received_requests()`][crate::Peer::with_max_open_received_requests] for more details.
+	TooManyOpenRequests,
+
+	/// A received message has a header that was rejected by the [`HeaderPolicy`][crate::HeaderPolicy] of the peer.
+	InvalidHeader,
+
+	/// The peer was shut down by the application.
+	///
+	/// See [`Peer::run_until()`][crate::Peer::run_until] for more details.
+	Shutdown,
+
+	/// A stream message was dropped because it was queued for too long.
+	///
+	/// See [`Error::is_stream_dropped()`] for more details.
+	StreamDropped,
+
+	/// The type fingerprint of a received request does not match the fingerprint of the local interface definition.
+	///
+	/// See [`Error::is_type_fingerprint_mismatch()`] for more details.
+	TypeFingerprintMismatch,
+
+	/// A custom error.
+	Custom,
+}
+
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match &self.inner {
+			private::InnerError::Io(e) => Some(e),
+			private::InnerError::UnexpectedMessageType(e) => Some(e),
+			private::InnerError::EncodeFailed(e) => Some(&**e),
+			private::InnerError::DecodeFailed(e) => Some(&**e),
+			private::InnerError::DecodedRemoteError { details, .. } => Some(&**details),
+			_ => None,
+		}
+	}
+}
 
 impl std::fmt::Display for Error {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -61,6 +173,46 @@ pub enum RecvMessageError<Body> {
 	InvalidRequest(crate::ReceivedRequestHandle<Body>, Box<dyn std::error::Error + Send>),
 }
 
+/// Decoder for the message of plain error responses from the remote peer.
+///
+/// Remote peers send error responses with service ID [`service_id::ERROR`][crate::service_id::ERROR] and a UTF-8 message as body.
+/// Newer servers may put a structured error object in the message, like a JSON problem details object.
+/// An error decoder can turn these messages into a typed error,
+/// which is attached to the remote error and can be retrieved with [`Error::downcast_remote_error_details()`].
+///
+/// Generated clients apply the decoder set with `Client::set_error_decoder()` to all plain error responses.
+/// You can also apply a decoder yourself with [`Error::decode_remote_error()`].
+///
+/// The trait is implemented for all closures with the right signature.
+pub trait ErrorDecoder: Send + Sync + 'static {
+	/// Decode the message of an error response.
+	///
+	/// Return `None` if the message is not recognized, to keep the plain remote error.
+	fn decode_error(&self, message: &str) -> Option<Box<dyn std::error::Error + Send>>;
+}
+
+impl<F> ErrorDecoder for F
+where
+	F: Fn(&str) -> Option<Box<dyn std::error::Error + Send>> + Send + Sync + 'static,
+{
+	fn decode_error(&self, message: &str) -> Option<Box<dyn std::error::Error + Send>> {
+		self(message)
+	}
+}
+
+/// Error returned by generated clients for services that declare an error type.
+///
+/// The remote peer can answer a request with a structured error of type `E`,
+/// encoded with the same format as the regular response body.
+/// All other failures, including plain error responses, are reported as [`ServiceError::Rpc`].
+pub enum ServiceError<E> {
+	/// The remote peer responded with a structured service error.
+	Service(E),
+
+	/// The request failed for a different reason.
+	Rpc(Error),
+}
+
 impl Error {
 	/// Create a new error from an I/O error.
 	pub fn io_error(error: std::io::Error) -> Self {
@@ -106,11 +258,122 @@ impl Error {
 		private::InnerError::RemoteError(message).into()
 	}
 
+	/// Create a new remote error with details decoded from the error message.
+	///
+	/// The details are usually produced by an [`ErrorDecoder`], see [`Self::decode_remote_error()`].
+	/// A decoded remote error is also a [remote error][Self::remote_error], with the original message.
+	pub fn decoded_remote_error(message: String, details: Box<dyn std::error::Error + Send>) -> Self {
+		private::InnerError::DecodedRemoteError { message, details }.into()
+	}
+
+	/// Create a new remote error with a numeric error code and an optional structured payload.
+	///
+	/// The error code lets the application branch on the error without parsing the human readable message.
+	/// The payload is an encoded message body with more details about the error,
+	/// see [`Self::decode_remote_error_payload()`].
+	/// A coded remote error is also a [remote error][Self::remote_error].
+	///
+	/// Coded remote errors are sent with [`Message::coded_error_response()`][crate::Message::coded_error_response].
+	pub fn coded_remote_error(code: i32, message: String, payload: Option<Vec<u8>>) -> Self {
+		private::InnerError::CodedRemoteError { code, message, payload }.into()
+	}
+
+	/// Create a new remote error for a structured service error response.
+	///
+	/// Structured service errors are sent with service ID [`SERVICE_ERROR`][crate::service_id::SERVICE_ERROR].
+	/// The `body` is the raw body of the response, with the encoded error value.
+	/// It can be retrieved with [`Self::service_error_body()`] to decode it.
+	/// A service error is also a [remote error][Self::remote_error], with the message "service error".
+	pub fn service_error<Body: crate::Body>(body: Body) -> Self {
+		private::InnerError::ServiceError(Box::new(body)).into()
+	}
+
+	/// Create a new error for an incoming response indicating that the remote service is temporarily unavailable.
+	///
+	/// The `retry_after` parameter is the time after which the request may be retried.
+	/// A retry-after error is also a [remote error][Self::remote_error],
+	/// with the human readable message from the remote peer.
+	pub fn retry_after(retry_after: std::time::Duration, message: String) -> Self {
+		private::InnerError::RetryAfter { retry_after, message }.into()
+	}
+
+	/// Create a new error indicating that the peer was shut down by the application.
+	///
+	/// This is reported to the handles of a peer when it stops because of the shutdown signal passed to [`Peer::run_until()`][crate::Peer::run_until].
+	pub fn shutdown() -> Self {
+		private::InnerError::Shutdown.into()
+	}
+
 	/// Create a new error with a custom message.
 	pub fn custom(message: String) -> Self {
 		private::InnerError::Custom(message).into()
 	}
 
+	/// Get the kind of this error.
+	pub fn kind(&self) -> ErrorKind {
+		match &self.inner {
+			private::InnerError::Io(_) => ErrorKind::Io,
+			private::InnerError::MessageTooShort { .. } => ErrorKind::MessageTooShort,
+			private::InnerError::InvalidMessageType { .. } => ErrorKind::InvalidMessageType,
+			private::InnerError::PayloadTooLarge { .. } => ErrorKind::PayloadTooLarge,
+			private::InnerError::DuplicateRequestId { .. } => ErrorKind::DuplicateRequestId,
+			private::InnerError::UnknownRequestId { .. } => ErrorKind::UnknownRequestId,
+			private::InnerError::UnexpectedMessageType(_) => ErrorKind::UnexpectedMessageType,
+			private::InnerError::UnexpectedServiceId { .. } => ErrorKind::UnexpectedServiceId,
+			private::InnerError::NoFreeRequestIdFound => ErrorKind::NoFreeRequestIdFound,
+			private::InnerError::RequestClosed => ErrorKind::RequestClosed,
+			private::InnerError::EncodeFailed(_) => ErrorKind::EncodeFailed,
+			private::InnerError::DecodeFailed(_) => ErrorKind::DecodeFailed,
+			private::InnerError::RemoteError(_) => ErrorKind::RemoteError,
+			private::InnerError::DecodedRemoteError { .. } => ErrorKind::RemoteError,
+			private::InnerError::CodedRemoteError { .. } => ErrorKind::RemoteError,
+			private::InnerError::ServiceError(_) => ErrorKind::RemoteError,
+			private::InnerError::RetryAfter { .. } => ErrorKind::RetryAfter,
+			private::InnerError::CapacityExceeded => ErrorKind::CapacityExceeded,
+			private::InnerError::TooManyOpenRequests { .. } => ErrorKind::TooManyOpenRequests,
+			private::InnerError::InvalidHeader { .. } => ErrorKind::InvalidHeader,
+			private::InnerError::Shutdown => ErrorKind::Shutdown,
+			private::InnerError::StreamDropped { .. } => ErrorKind::StreamDropped,
+			private::InnerError::TypeFingerprintMismatch { .. } => ErrorKind::TypeFingerprintMismatch,
+			private::InnerError::Custom(_) => ErrorKind::Custom,
+		}
+	}
+
+	/// Get the underlying I/O error, if this is an I/O error.
+	pub fn as_io_error(&self) -> Option<&std::io::Error> {
+		match &self.inner {
+			private::InnerError::Io(e) => Some(e),
+			_ => None,
+		}
+	}
+
+	/// Get the message body length and the maximum allowed length, if this is a payload too large error.
+	///
+	/// The values are returned as `(body_len, max_len)`.
+	pub fn as_payload_too_large(&self) -> Option<(usize, usize)> {
+		match &self.inner {
+			private::InnerError::PayloadTooLarge { body_len, max_len } => Some((*body_len, *max_len)),
+			_ => None,
+		}
+	}
+
+	/// Get the offending service ID, if this is an unexpected service ID error.
+	pub fn as_unexpected_service_id(&self) -> Option<i32> {
+		match &self.inner {
+			private::InnerError::UnexpectedServiceId { service_id } => Some(*service_id),
+			_ => None,
+		}
+	}
+
+	/// Get the offending request ID, if this is a duplicate or unknown request ID error.
+	pub fn request_id(&self) -> Option<u32> {
+		match &self.inner {
+			private::InnerError::DuplicateRequestId { request_id } => Some(*request_id),
+			private::InnerError::UnknownRequestId { request_id } => Some(*request_id),
+			_ => None,
+		}
+	}
+
 	/// Check if this error is caused by the remote peer closing the connection cleanly.
 	pub fn is_connection_aborted(&self) -> bool {
 		if let private::InnerError::Io(e) = &self.inner {
@@ -131,17 +394,27 @@ impl Error {
 	///
 	/// See [`Self::remote_error()`] for more details on what a remote error is.
 	pub fn is_remote_error(&self) -> bool {
-		matches!(&self.inner, private::InnerError::RemoteError(_))
+		matches!(
+			&self.inner,
+			private::InnerError::RemoteError(_)
+				| private::InnerError::DecodedRemoteError { .. }
+				| private::InnerError::CodedRemoteError { .. }
+				| private::InnerError::ServiceError(_)
+				| private::InnerError::RetryAfter { .. }
+		)
 	}
 
 	/// Get this error as remote error message.
 	///
 	/// See [`Self::remote_error()`] for more details on what a remote error is.
 	pub fn as_remote_error(&self) -> Option<&str> {
-		if let private::InnerError::RemoteError(msg) = &self.inner {
-			Some(msg)
-		} else {
-			None
+		match &self.inner {
+			private::InnerError::RemoteError(msg) => Some(msg),
+			private::InnerError::DecodedRemoteError { message, .. } => Some(message),
+			private::InnerError::CodedRemoteError { message, .. } => Some(message),
+			private::InnerError::ServiceError(_) => Some(SERVICE_ERROR_MESSAGE),
+			private::InnerError::RetryAfter { message, .. } => Some(message),
+			_ => None,
 		}
 	}
 
@@ -149,12 +422,212 @@ impl Error {
 	///
 	/// See [`Self::remote_error()`] for more details on what a remote error is.
 	pub fn into_remote_error(self) -> Option<String> {
-		if let private::InnerError::RemoteError(msg) = self.inner {
-			Some(msg)
+		match self.inner {
+			private::InnerError::RemoteError(msg) => Some(msg),
+			private::InnerError::DecodedRemoteError { message, .. } => Some(message),
+			private::InnerError::CodedRemoteError { message, .. } => Some(message),
+			private::InnerError::ServiceError(_) => Some(SERVICE_ERROR_MESSAGE.into()),
+			private::InnerError::RetryAfter { message, .. } => Some(message),
+			_ => None,
+		}
+	}
+
+	/// Decode the message of a plain remote error with an error decoder.
+	///
+	/// If this is a plain remote error and the decoder recognizes the message,
+	/// the decoded details are attached to the error, see [`Self::decoded_remote_error()`].
+	/// All other errors are returned unmodified.
+	pub fn decode_remote_error(self, decoder: &dyn ErrorDecoder) -> Self {
+		match self.inner {
+			private::InnerError::RemoteError(message) => match decoder.decode_error(&message) {
+				Some(details) => Self::decoded_remote_error(message, details),
+				None => Self::remote_error(message),
+			},
+			inner => Self { inner },
+		}
+	}
+
+	/// Get the details of a remote error that were decoded by an [`ErrorDecoder`].
+	///
+	/// Returns [`None`] if this is not a remote error, or if the message was not decoded.
+	pub fn remote_error_details(&self) -> Option<&(dyn std::error::Error + Send + 'static)> {
+		match &self.inner {
+			private::InnerError::DecodedRemoteError { details, .. } => Some(&**details),
+			_ => None,
+		}
+	}
+
+	/// Get the details of a remote error that were decoded by an [`ErrorDecoder`], if they have type `T`.
+	pub fn downcast_remote_error_details<T: std::error::Error + 'static>(&self) -> Option<&T> {
+		self.remote_error_details()?.downcast_ref()
+	}
+
+	/// Get the error code of a remote error.
+	///
+	/// Returns [`None`] if this is not a remote error, or if the remote peer did not send an error code.
+	/// See [`Self::coded_remote_error()`] for more details.
+	pub fn remote_error_code(&self) -> Option<i32> {
+		match &self.inner {
+			private::InnerError::CodedRemoteError { code, .. } => Some(*code),
+			_ => None,
+		}
+	}
+
+	/// Get the encoded payload of a remote error.
+	///
+	/// Returns [`None`] if this is not a remote error, or if the remote peer did not send a payload.
+	/// Use [`Self::decode_remote_error_payload()`] to decode the payload with a message format.
+	pub fn remote_error_payload(&self) -> Option<&[u8]> {
+		match &self.inner {
+			private::InnerError::CodedRemoteError { payload, .. } => payload.as_deref(),
+			_ => None,
+		}
+	}
+
+	/// Get the raw body of a structured service error.
+	///
+	/// Returns [`None`] if this is not a service error, or if the body is not of type `Body`.
+	/// See [`Self::service_error()`] for more details.
+	pub fn service_error_body<Body: crate::Body>(&self) -> Option<&Body> {
+		match &self.inner {
+			private::InnerError::ServiceError(body) => body.downcast_ref(),
+			_ => None,
+		}
+	}
+
+	/// Decode the payload of a remote error with a message format.
+	///
+	/// Returns [`None`] if there is no payload,
+	/// or an error if the payload could not be decoded as `T`.
+	pub fn decode_remote_error_payload<F, T>(&self) -> Option<Result<T, Error>>
+	where
+		F: crate::format::DecodeBody<T>,
+		F::Body: From<Vec<u8>>,
+	{
+		let payload = self.remote_error_payload()?;
+		Some(F::decode_body(payload.to_vec().into()).map_err(Error::decode_failed))
+	}
+
+	/// Check if this error is a standardized "bad request" error response from the remote peer.
+	///
+	/// A remote peer can be configured to automatically answer requests with an unknown service ID or an invalid body
+	/// with a bad request error response, instead of leaving them unanswered.
+	/// A bad request error is also a [remote error][Self::remote_error].
+	pub fn is_bad_request(&self) -> bool {
+		self.plain_remote_error().map_or(false, |msg| msg.starts_with(BAD_REQUEST_PREFIX))
+	}
+
+	/// Check if this error is a standardized "deadline exceeded" error response from the remote peer.
+	///
+	/// Generated servers answer requests with this error if their deadline passed before they were handled.
+	/// A deadline exceeded error is also a [remote error][Self::remote_error].
+	pub fn is_deadline_exceeded(&self) -> bool {
+		self.plain_remote_error() == Some(DEADLINE_EXCEEDED_MESSAGE)
+	}
+
+	/// Check if this error indicates that the remote service is temporarily unavailable.
+	///
+	/// See [`Self::retry_after()`] for more details.
+	pub fn is_retry_after(&self) -> bool {
+		matches!(&self.inner, private::InnerError::RetryAfter { .. })
+	}
+
+	/// Get the time after which the failed request may be retried.
+	///
+	/// Returns [`None`] if this is not a retry-after error.
+	/// See [`Self::retry_after()`] for more details.
+	pub fn as_retry_after(&self) -> Option<std::time::Duration> {
+		if let private::InnerError::RetryAfter { retry_after, .. } = &self.inner {
+			Some(*retry_after)
 		} else {
 			None
 		}
 	}
+
+	/// Check if this error is caused by a full internal queue of the peer.
+	///
+	/// This can only happen with the `strict-memory` feature, where all internal queues have a fixed capacity.
+	/// Instead of waiting for free space in the queue, the operation fails with this error.
+	/// See [`ChannelCapacities`][crate::ChannelCapacities] for the configuration of the queues.
+	pub fn is_capacity_exceeded(&self) -> bool {
+		matches!(&self.inner, private::InnerError::CapacityExceeded)
+	}
+
+	/// Check if this error indicates that the peer was shut down by the application.
+	///
+	/// Unlike [`Self::is_connection_aborted()`], this means the connection was closed on purpose by the local application,
+	/// using the shutdown signal passed to [`Peer::run_until()`][crate::Peer::run_until].
+	pub fn is_shutdown(&self) -> bool {
+		matches!(&self.inner, private::InnerError::Shutdown)
+	}
+
+	/// Check if this error indicates that a stream message was dropped instead of sent.
+	///
+	/// This only happens when the peer uses a [`BoundedLatencyPolicy`][crate::BoundedLatencyPolicy],
+	/// and the stream message waited longer than the maximum queue age of the policy.
+	pub fn is_stream_dropped(&self) -> bool {
+		matches!(&self.inner, private::InnerError::StreamDropped { .. })
+	}
+
+	/// Check if this error indicates that the request and response body types of a service differ between the peers.
+	///
+	/// Generated clients can send a fingerprint of the body types along with each request,
+	/// see `Client::set_type_fingerprints()`.
+	/// Generated servers compare the fingerprint with their own interface definition before decoding the request body,
+	/// and answer mismatching requests with a standardized error response.
+	///
+	/// This returns true for the local error detected by the server and for the error response received by the client.
+	/// The error response is also a [remote error][Self::remote_error].
+	pub fn is_type_fingerprint_mismatch(&self) -> bool {
+		match &self.inner {
+			private::InnerError::TypeFingerprintMismatch { .. } => true,
+			_ => self.plain_remote_error().map_or(false, |msg| msg.starts_with(TYPE_FINGERPRINT_MISMATCH_PREFIX)),
+		}
+	}
+
+	/// Get the message of a remote error that was sent as plain error response, with or without decoded details.
+	fn plain_remote_error(&self) -> Option<&str> {
+		match &self.inner {
+			private::InnerError::RemoteError(msg) => Some(msg),
+			private::InnerError::DecodedRemoteError { message, .. } => Some(message),
+			_ => None,
+		}
+	}
+}
+
+impl ErrorKind {
+	/// Get a short description of the error kind.
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Self::Io => "I/O error",
+			Self::MessageTooShort => "message too short",
+			Self::InvalidMessageType => "invalid message type",
+			Self::PayloadTooLarge => "payload too large",
+			Self::DuplicateRequestId => "duplicate request ID",
+			Self::UnknownRequestId => "unknown request ID",
+			Self::UnexpectedMessageType => "unexpected message type",
+			Self::UnexpectedServiceId => "unexpected service ID",
+			Self::NoFreeRequestIdFound => "no free request ID found",
+			Self::RequestClosed => "request closed",
+			Self::EncodeFailed => "encode failed",
+			Self::DecodeFailed => "decode failed",
+			Self::RemoteError => "remote error",
+			Self::RetryAfter => "retry after",
+			Self::CapacityExceeded => "capacity exceeded",
+			Self::TooManyOpenRequests => "too many open requests",
+			Self::InvalidHeader => "invalid header",
+			Self::Shutdown => "shutdown",
+			Self::StreamDropped => "stream dropped",
+			Self::TypeFingerprintMismatch => "type fingerprint mismatch",
+			Self::Custom => "custom error",
+		}
+	}
+}
+
+impl std::fmt::Display for ErrorKind {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(self.as_str())
+	}
 }
 
 impl<Body> RecvMessageError<Body> {
@@ -167,6 +640,17 @@ impl<Body> RecvMessageError<Body> {
 		}
 	}
 
+	/// Check if this error is caused by a request with a mismatching type fingerprint.
+	///
+	/// Generated servers report such requests as [`Self::InvalidRequest`] without decoding the body.
+	/// See [`Error::is_type_fingerprint_mismatch()`] for more details.
+	pub fn is_type_fingerprint_mismatch(&self) -> bool {
+		match self {
+			Self::InvalidRequest(_request, error) => error.downcast_ref::<Error>().map_or(false, Error::is_type_fingerprint_mismatch),
+			_ => false,
+		}
+	}
+
 	/// Get the raw request handle associated with the received message.
 	///
 	/// The request handle can be used to send an error response to unknown or invalid requests.
@@ -198,6 +682,85 @@ impl<Body> RecvMessageError<Body> {
 			Self::InvalidRequest(request, _error) => Some(request),
 		}
 	}
+
+	/// Get the message of the standardized "bad request" error response for unknown and invalid requests.
+	///
+	/// Requests with a mismatching type fingerprint are answered with a standardized "type fingerprint mismatch" error instead,
+	/// so the remote peer can recognize them with [`Error::is_type_fingerprint_mismatch()`].
+	///
+	/// For errors other than [`Self::UnknownRequest`] and [`Self::InvalidRequest`],
+	/// this function returns [`None`].
+	pub fn bad_request_message(&self) -> Option<String> {
+		match self {
+			Self::InvalidRequest(_request, error) if self.is_type_fingerprint_mismatch() => Some(error.to_string()),
+			Self::Other(_error) => None,
+			Self::UnknownStream(_message) => None,
+			Self::UnknownRequest(request, _body) => Some(private::bad_request_message(format_args!("unknown service ID {}", request.service_id()))),
+			Self::InvalidStream(_message, _error) => None,
+			Self::InvalidRequest(request, error) => Some(private::bad_request_message(format_args!("invalid request body for service ID {}: {}", request.service_id(), error))),
+		}
+	}
+
+	/// Send the standardized "bad request" error response for unknown and invalid requests.
+	///
+	/// The remote peer can recognize the response with [`Error::is_bad_request()`].
+	/// For errors other than [`Self::UnknownRequest`] and [`Self::InvalidRequest`], nothing is sent.
+	///
+	/// The returned future does not borrow the error,
+	/// so the error can still be reported or handled after the response is sent.
+	pub fn send_bad_request_response(&self) -> impl std::future::Future<Output = Result<(), Error>> + Send + 'static
+	where
+		Body: crate::Body + Send + 'static,
+	{
+		let response = self.request_handle()
+			.map(|request| request.write_handle())
+			.zip(self.bad_request_message());
+		async move {
+			match response {
+				Some((write_handle, message)) => write_handle.send_error_response(&message).await,
+				None => Ok(()),
+			}
+		}
+	}
+}
+
+impl<E> ServiceError<E> {
+	/// Check if this error is a structured service error.
+	pub fn is_service_error(&self) -> bool {
+		matches!(self, Self::Service(_))
+	}
+
+	/// Get the structured service error, if this is one.
+	pub fn as_service_error(&self) -> Option<&E> {
+		match self {
+			Self::Service(e) => Some(e),
+			Self::Rpc(_) => None,
+		}
+	}
+
+	/// Consume `self` to get the structured service error, if this is one.
+	pub fn into_service_error(self) -> Option<E> {
+		match self {
+			Self::Service(e) => Some(e),
+			Self::Rpc(_) => None,
+		}
+	}
+
+	/// Get the RPC error, if this is not a structured service error.
+	pub fn as_rpc_error(&self) -> Option<&Error> {
+		match self {
+			Self::Service(_) => None,
+			Self::Rpc(e) => Some(e),
+		}
+	}
+
+	/// Consume `self` to get the RPC error, if this is not a structured service error.
+	pub fn into_rpc_error(self) -> Option<Error> {
+		match self {
+			Self::Service(_) => None,
+			Self::Rpc(e) => Some(e),
+		}
+	}
 }
 
 impl From<std::io::Error> for Error {
@@ -212,7 +775,14 @@ impl<Body> From<Error> for RecvMessageError<Body> {
 	}
 }
 
+impl<E> From<Error> for ServiceError<E> {
+	fn from(other: Error) -> Self {
+		Self::Rpc(other)
+	}
+}
+
 impl<Body> std::error::Error for ParseUpdateError<Body> {}
+impl<E: std::fmt::Debug + std::fmt::Display> std::error::Error for ServiceError<E> {}
 impl<Body> std::error::Error for RecvMessageError<Body> {}
 
 impl<Body> std::fmt::Display for ParseUpdateError<Body> {
@@ -236,6 +806,15 @@ impl<Body> std::fmt::Display for RecvMessageError<Body> {
 	}
 }
 
+impl<E: std::fmt::Display> std::fmt::Display for ServiceError<E> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Service(e) => write!(f, "service error: {}", e),
+			Self::Rpc(e) => write!(f, "{}", e),
+		}
+	}
+}
+
 impl<Body> std::fmt::Debug for ParseUpdateError<Body> {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		match self {
@@ -257,6 +836,112 @@ impl<Body> std::fmt::Debug for RecvMessageError<Body> {
 	}
 }
 
+impl<E: std::fmt::Debug> std::fmt::Debug for ServiceError<E> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Service(e) => f.debug_tuple("Service").field(e).finish(),
+			Self::Rpc(e) => f.debug_tuple("Rpc").field(e).finish(),
+		}
+	}
+}
+
+/// Define an enum that maps the error codes of [coded remote errors][Error::coded_remote_error] to variants.
+///
+/// Each variant must have an explicit `i32` error code.
+/// The enum derives `Debug`, `Clone`, `Copy`, `Eq`, `PartialEq` and `Hash`,
+/// and gets the following functions:
+/// * `code(self) -> i32` to get the error code of a variant, for example to pass to
+///   [`ReceivedRequestHandle::send_coded_error_response()`][crate::ReceivedRequestHandle::send_coded_error_response].
+/// * `from_code(i32) -> Option<Self>` to look up the variant for an error code.
+/// * `from_error(&Error) -> Option<Self>` to look up the variant for the error code of a remote error.
+///
+/// The enum also implements `From<Self> for i32` and `TryFrom<i32>`.
+/// Compilation fails if two variants have the same error code.
+///
+/// ```
+/// fizyr_rpc::error_codes! {
+///     /// Errors of the camera services.
+///     pub enum CameraError {
+///         /// The camera is not connected.
+///         NotConnected = 1,
+///
+///         /// The camera is busy taking another picture.
+///         Busy = 2,
+///     }
+/// }
+///
+/// let error = fizyr_rpc::Error::coded_remote_error(2, "camera busy".into(), None);
+/// assert!(CameraError::from_error(&error) == Some(CameraError::Busy));
+/// assert!(CameraError::NotConnected.code() == 1);
+/// assert!(CameraError::from_code(3) == None);
+/// ```
+///
+/// ```compile_fail
+/// fizyr_rpc::error_codes! {
+///     enum CameraError {
+///         NotConnected = 1,
+///         Busy = 1,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! error_codes {
+	(
+		$(#[$enum_attr:meta])*
+		$vis:vis enum $name:ident {
+			$(
+				$(#[$attr:meta])*
+				$variant:ident = $code:expr
+			),* $(,)?
+		}
+	) => {
+		$(#[$enum_attr])*
+		#[derive(::core::fmt::Debug, ::core::clone::Clone, ::core::marker::Copy, ::core::cmp::Eq, ::core::cmp::PartialEq, ::core::hash::Hash)]
+		#[repr(i32)]
+		$vis enum $name {
+			$(
+				$(#[$attr])*
+				$variant = $code,
+			)*
+		}
+
+		#[allow(dead_code)]
+		impl $name {
+			/// Get the error code of the variant.
+			pub fn code(self) -> i32 {
+				self as i32
+			}
+
+			/// Get the variant for an error code.
+			pub fn from_code(code: i32) -> ::core::option::Option<Self> {
+				match code {
+					$(code if code == Self::$variant as i32 => ::core::option::Option::Some(Self::$variant),)*
+					_ => ::core::option::Option::None,
+				}
+			}
+
+			/// Get the variant for the error code of a remote error.
+			pub fn from_error(error: &$crate::Error) -> ::core::option::Option<Self> {
+				Self::from_code(error.remote_error_code()?)
+			}
+		}
+
+		impl ::core::convert::From<$name> for i32 {
+			fn from(value: $name) -> i32 {
+				value.code()
+			}
+		}
+
+		impl ::core::convert::TryFrom<i32> for $name {
+			type Error = i32;
+
+			fn try_from(code: i32) -> ::core::result::Result<Self, i32> {
+				Self::from_code(code).ok_or(code)
+			}
+		}
+	};
+}
+
 pub(crate) mod private {
 	use super::*;
 
@@ -264,6 +949,10 @@ pub(crate) mod private {
 		InnerError::from(std::io::Error::from(std::io::ErrorKind::ConnectionAborted)).into()
 	}
 
+	pub(crate) fn write_half_finished() -> Error {
+		InnerError::from(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "the write side of the connection was shut down")).into()
+	}
+
 	#[derive(Debug)]
 	#[doc(hidden)]
 	pub enum InnerError {
@@ -324,6 +1013,75 @@ pub(crate) mod private {
 		/// The remote peer replied with an error instead of the regular response.
 		RemoteError(String),
 
+		/// The remote peer replied with an error, and the message was decoded by an error decoder.
+		DecodedRemoteError {
+			/// The error message from the remote peer.
+			message: String,
+
+			/// The details decoded from the message.
+			details: Box<dyn std::error::Error + Send>,
+		},
+
+		/// The remote peer replied with an error response with an error code.
+		CodedRemoteError {
+			/// The error code.
+			code: i32,
+
+			/// The error message from the remote peer.
+			message: String,
+
+			/// The encoded payload with more details about the error.
+			payload: Option<Vec<u8>>,
+		},
+
+		/// The remote peer replied with a structured service error.
+		ServiceError(Box<dyn std::any::Any + Send>),
+
+		/// The remote peer replied that the service is temporarily unavailable.
+		RetryAfter {
+			/// The time after which the request may be retried.
+			retry_after: std::time::Duration,
+
+			/// The error message from the remote peer.
+			message: String,
+		},
+
+		/// An internal queue of the peer is full.
+		CapacityExceeded,
+
+		/// The remote peer opened more requests than allowed.
+		TooManyOpenRequests {
+			/// The maximum number of open received requests.
+			limit: usize,
+		},
+
+		/// The received message header was rejected by the header policy.
+		InvalidHeader {
+			/// The reason why the header was rejected.
+			reason: &'static str,
+		},
+
+		/// The peer was shut down by the application.
+		Shutdown,
+
+		/// A stream message was dropped because it was queued for too long.
+		StreamDropped {
+			/// The maximum queue age for stream messages.
+			max_age: std::time::Duration,
+		},
+
+		/// The type fingerprint of a received request does not match the local interface definition.
+		TypeFingerprintMismatch {
+			/// The service ID of the request.
+			service_id: i32,
+
+			/// The fingerprint of the local interface definition.
+			expected: u64,
+
+			/// The fingerprint sent by the remote peer.
+			received: u64,
+		},
+
 		/// A custom error message.
 		Custom(String),
 	}
@@ -366,14 +1124,76 @@ pub(crate) mod private {
 				InnerError::UnexpectedServiceId { service_id } => write!(f, "unexpected service ID: {service_id}"),
 				InnerError::NoFreeRequestIdFound => write!(f, "no free request ID was found"),
 				InnerError::RequestClosed => write!(f, "the request is already closed"),
-				InnerError::EncodeFailed(error) => write!(f, "{}", error),
-				InnerError::DecodeFailed(error) => write!(f, "{}", error),
+				InnerError::EncodeFailed(error) => write!(f, "failed to encode message body: {}", error),
+				InnerError::DecodeFailed(error) => write!(f, "failed to decode message body: {}", error),
 				InnerError::RemoteError(error) => write!(f, "{}", error),
+				InnerError::DecodedRemoteError { message, .. } => write!(f, "{}", message),
+				InnerError::CodedRemoteError { code, message, .. } => {
+					write!(f, "error {code}")?;
+					if !message.is_empty() {
+						write!(f, ": {message}")?;
+					}
+					Ok(())
+				},
+				InnerError::ServiceError(_) => write!(f, "{}", super::SERVICE_ERROR_MESSAGE),
+				InnerError::RetryAfter { retry_after, message } => {
+					write!(f, "service temporarily unavailable, retry after {} ms", retry_after.as_millis())?;
+					if !message.is_empty() {
+						write!(f, ": {message}")?;
+					}
+					Ok(())
+				},
+				InnerError::CapacityExceeded => write!(f, "capacity exceeded: the internal queue of the peer is full"),
+				InnerError::TooManyOpenRequests { limit } => write!(f, "too many open requests: at most {limit} received requests may be open at the same time"),
+				InnerError::InvalidHeader { reason } => write!(f, "invalid message header: {reason}"),
+				InnerError::Shutdown => write!(f, "the peer was shut down"),
+				InnerError::StreamDropped { max_age } => write!(f, "stream message dropped: queued for longer than {} ms", max_age.as_millis()),
+				InnerError::TypeFingerprintMismatch { service_id, expected, received } => write!(
+					f,
+					"{}: request and response types of service ID {service_id} differ between the peers: expected fingerprint {expected:016x}, received {received:016x}",
+					super::TYPE_FINGERPRINT_MISMATCH_PREFIX,
+				),
 				InnerError::Custom(error) => write!(f, "{}", error),
 			}
 		}
 	}
 
+	/// The message of a standardized "deadline exceeded" error response.
+	pub const DEADLINE_EXCEEDED_MESSAGE: &str = super::DEADLINE_EXCEEDED_MESSAGE;
+
+	/// The message of the error response for received requests that did not fit in the incoming queue.
+	pub const INCOMING_QUEUE_FULL_MESSAGE: &str = "incoming queue is full";
+
+	/// The message of the retry-after response for received requests that expired before they were picked up.
+	pub const REQUEST_EXPIRED_MESSAGE: &str = "request expired before it was handled";
+
+	/// The suffix appended to error messages that were truncated before sending.
+	pub const TRUNCATED_SUFFIX: &str = "...";
+
+	/// Truncate an error message to at most `max_len` bytes.
+	///
+	/// The message is cut at a character boundary and the [`TRUNCATED_SUFFIX`] is appended to it,
+	/// unless `max_len` is too small to hold the suffix.
+	pub fn truncate_error_message(message: &str, max_len: usize) -> String {
+		if message.len() <= max_len {
+			return message.to_owned();
+		}
+
+		let (mut len, suffix) = match max_len.checked_sub(TRUNCATED_SUFFIX.len()) {
+			Some(len) => (len, TRUNCATED_SUFFIX),
+			None => (max_len, ""),
+		};
+		while !message.is_char_boundary(len) {
+			len -= 1;
+		}
+		format!("{}{}", &message[..len], suffix)
+	}
+
+	/// Create the message of a standardized "bad request" error response.
+	pub fn bad_request_message(reason: impl std::fmt::Display) -> String {
+		format!("{}{}", super::BAD_REQUEST_PREFIX, reason)
+	}
+
 	/// Check if a message size is large enough to contain a valid message.
 	#[allow(dead_code)] // not used when all transports are disabled.
 	pub fn check_message_too_short(message_len: usize) -> Result<(), InnerError> {
@@ -416,6 +1236,8 @@ pub(crate) mod private {
 				crate::MessageType::RequesterUpdate => "an update message",
 				crate::MessageType::ResponderUpdate => "an update message",
 				crate::MessageType::Stream => "a streaming message",
+				crate::MessageType::AckedStream => "an acknowledged streaming message",
+				crate::MessageType::StreamAck => "a stream acknowledgement",
 			};
 			write!(
 				f,
@@ -426,3 +1248,114 @@ pub(crate) mod private {
 		}
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+	use std::error::Error as _;
+
+	#[test]
+	fn error_kind() {
+		assert!(Error::io_error(std::io::ErrorKind::BrokenPipe.into()).kind() == ErrorKind::Io);
+		assert!(Error::payload_too_large(10, 5).kind() == ErrorKind::PayloadTooLarge);
+		assert!(Error::remote_error("oops".into()).kind() == ErrorKind::RemoteError);
+		assert!(Error::coded_remote_error(3, "oops".into(), None).kind() == ErrorKind::RemoteError);
+		assert!(Error::retry_after(std::time::Duration::from_secs(1), String::new()).kind() == ErrorKind::RetryAfter);
+		assert!(Error::shutdown().kind() == ErrorKind::Shutdown);
+		assert!(Error::custom("oops".into()).kind() == ErrorKind::Custom);
+	}
+
+	#[test]
+	fn error_inspection() {
+		let error = Error::payload_too_large(10, 5);
+		assert!(error.as_payload_too_large() == Some((10, 5)));
+		assert!(error.to_string() == "payload too large: maximum payload size is 5, got 10");
+		assert!(error.as_io_error().is_none());
+
+		let error = Error::io_error(std::io::ErrorKind::BrokenPipe.into());
+		let_assert!(Some(io_error) = error.as_io_error());
+		assert!(io_error.kind() == std::io::ErrorKind::BrokenPipe);
+		assert!(error.source().is_some());
+
+		assert!(Error::unexpected_service_id(7).as_unexpected_service_id() == Some(7));
+	}
+
+	#[test]
+	fn coded_remote_error() {
+		let error = Error::coded_remote_error(42, "camera busy".into(), Some(vec![1, 2, 3]));
+		assert!(error.is_remote_error());
+		assert!(error.as_remote_error() == Some("camera busy"));
+		assert!(error.remote_error_code() == Some(42));
+		assert!(error.remote_error_payload() == Some(&[1, 2, 3][..]));
+		assert!(error.to_string() == "error 42: camera busy");
+
+		let error = Error::remote_error("camera busy".into());
+		assert!(error.remote_error_code() == None);
+		assert!(error.remote_error_payload() == None);
+		assert!(Error::coded_remote_error(42, String::new(), None).to_string() == "error 42");
+	}
+
+	#[test]
+	fn decoded_remote_error() {
+		#[derive(Debug)]
+		struct Problem {
+			status: u16,
+		}
+
+		impl std::fmt::Display for Problem {
+			fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+				write!(f, "problem with status {}", self.status)
+			}
+		}
+
+		impl std::error::Error for Problem {}
+
+		let decoder = |message: &str| -> Option<Box<dyn std::error::Error + Send>> {
+			let status = message.strip_prefix("status=")?.parse().ok()?;
+			Some(Box::new(Problem { status }))
+		};
+
+		let error = Error::remote_error("status=404".into()).decode_remote_error(&decoder);
+		assert!(error.kind() == ErrorKind::RemoteError);
+		assert!(error.as_remote_error() == Some("status=404"));
+		assert!(error.to_string() == "status=404");
+		let_assert!(Some(problem) = error.downcast_remote_error_details::<Problem>());
+		assert!(problem.status == 404);
+		assert!(let Some(_) = error.source());
+
+		// Unrecognized messages and other errors are not modified.
+		let error = Error::remote_error("not found".into()).decode_remote_error(&decoder);
+		assert!(error.as_remote_error() == Some("not found"));
+		assert!(error.remote_error_details().is_none());
+		let error = Error::coded_remote_error(42, "status=404".into(), None).decode_remote_error(&decoder);
+		assert!(error.remote_error_code() == Some(42));
+		assert!(error.remote_error_details().is_none());
+
+		// Standardized error responses are still recognized after decoding.
+		let decoder = |_: &str| -> Option<Box<dyn std::error::Error + Send>> { Some(Box::new(Problem { status: 400 })) };
+		let error = Error::remote_error(private::bad_request_message("unknown service")).decode_remote_error(&decoder);
+		assert!(error.is_bad_request());
+		assert!(let Some(_) = error.downcast_remote_error_details::<Problem>());
+	}
+
+	#[test]
+	fn truncate_error_message() {
+		use private::truncate_error_message;
+		assert!(truncate_error_message("short", 10) == "short");
+		assert!(truncate_error_message("a very long message", 10) == "a very ...");
+		assert!(truncate_error_message("héllo", 5) == "h...");
+		assert!(truncate_error_message("hello", 2) == "he");
+	}
+
+	#[test]
+	fn decode_error_display() {
+		let inner = Error::custom("missing field `x`".into());
+		let error = Error::decode_failed(Box::new(inner));
+		assert!(error.kind() == ErrorKind::DecodeFailed);
+		assert!(error.to_string() == "failed to decode message body: missing field `x`");
+		let_assert!(Some(source) = error.source());
+		assert!(source.to_string() == "missing field `x`");
+	}
+}
@@ -0,0 +1,614 @@
+//! Exactly-once processing of requests over reconnects.
+//!
+//! Normally, a request that was sent just before a connection was lost is in an unknown state:
+//! the server may or may not have processed it.
+//! Sending it again risks processing it twice, not sending it again risks losing it.
+//!
+//! This module implements an opt-in protocol to avoid both for critical requests.
+//! The client gives each request a sequence number and stores it in a [`ClientJournal`] before sending it.
+//! The server records the sequence numbers of processed requests in a [`ServerJournal`],
+//! and rejects requests that it already processed.
+//! After reconnecting, the client asks the server which of the pending requests it processed,
+//! and sends the remaining requests again with the same sequence number.
+//!
+//! The journals are traits, so the application decides how and where they are stored.
+//! To survive a restart of the application, they must be stored durably, for example in a file or database.
+//! A [`MemoryClientJournal`] and a [`MemoryServerJournal`] are provided for testing,
+//! and for applications that only need to survive reconnects.
+//!
+//! Sequence numbers are only unique per client journal.
+//! A server must use a separate [`ServerJournal`] for each client,
+//! which requires a stable client identity, for example from authentication.
+//!
+//! # Protocol
+//! A sequenced request is a normal request, immediately followed by a request update with service ID [`service_id::SEQUENCE`].
+//! The body of the update is the sequence number as UTF-8 decimal integer.
+//!
+//! To query which requests were processed, the client sends a request with service ID [`service_id::QUERY_PROCESSED`].
+//! The request body is a list of sequence numbers as UTF-8 decimal integers separated by a single space.
+//! The response has the same service ID and format, and contains the requested sequence numbers that were processed.
+//!
+//! A request is only considered processed when the server sent the final response.
+//! A duplicate of a request that is still being processed is rejected with a retry-after response.
+//! The client can then query the state of the request again later.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::{service_id, Error, PeerWriteHandle, ReceivedRequestHandle, SentRequestHandle};
+
+/// Client side storage for sequenced requests.
+///
+/// The client journal allocates sequence numbers and stores requests until their response has been received.
+pub trait ClientJournal<Body>: Send + 'static {
+	/// Allocate a new sequence number.
+	///
+	/// Sequence numbers must never be reused, also not after restarting the application.
+	fn next_sequence(&mut self) -> Result<u64, Error>;
+
+	/// Store a request before it is sent.
+	fn store(&mut self, sequence: u64, service_id: i32, body: &Body) -> Result<(), Error>;
+
+	/// Remove a request from the journal after it was processed by the server.
+	fn complete(&mut self, sequence: u64) -> Result<(), Error>;
+
+	/// Get all stored requests, ordered by sequence number.
+	fn pending(&mut self) -> Result<Vec<JournalEntry<Body>>, Error>;
+}
+
+/// Server side storage for the sequence numbers of processed requests.
+///
+/// Each journal holds the sequence numbers for a single client.
+pub trait ServerJournal: Send + 'static {
+	/// Check if the request with the given sequence number has been processed.
+	fn is_processed(&mut self, sequence: u64) -> Result<bool, Error>;
+
+	/// Record that the request with the given sequence number has been processed.
+	///
+	/// Ideally, this is done atomically with the effects of the request itself.
+	fn mark_processed(&mut self, sequence: u64) -> Result<(), Error>;
+}
+
+/// A request stored in a [`ClientJournal`].
+#[derive(Debug, Clone)]
+pub struct JournalEntry<Body> {
+	/// The sequence number of the request.
+	pub sequence: u64,
+
+	/// The service ID of the request.
+	pub service_id: i32,
+
+	/// The body of the request.
+	pub body: Body,
+}
+
+/// Client journal that is only stored in memory.
+#[derive(Debug)]
+pub struct MemoryClientJournal<Body> {
+	/// The next sequence number to allocate.
+	next_sequence: u64,
+
+	/// The pending requests by sequence number.
+	entries: BTreeMap<u64, (i32, Body)>,
+}
+
+/// Server journal that is only stored in memory.
+#[derive(Debug, Default)]
+pub struct MemoryServerJournal {
+	/// The sequence numbers of all processed requests.
+	processed: BTreeSet<u64>,
+}
+
+/// Client for sending requests with exactly-once semantics.
+///
+/// The client can be cloned cheaply to share the journal between tasks.
+/// It is not tied to a connection, so it can be used with a new connection after reconnecting.
+pub struct ExactlyOnceClient<J> {
+	/// The journal of the client.
+	journal: Arc<Mutex<J>>,
+}
+
+/// Server for receiving requests with exactly-once semantics.
+///
+/// The server can be cloned cheaply to share the journal between tasks and connections of the same client.
+pub struct ExactlyOnceServer<J> {
+	/// The state of the server.
+	state: Arc<Mutex<ServerState<J>>>,
+}
+
+/// Shared state of an [`ExactlyOnceServer`].
+struct ServerState<J> {
+	/// The journal of processed requests.
+	journal: J,
+
+	/// The sequence numbers of requests that are currently being processed.
+	in_progress: BTreeSet<u64>,
+}
+
+/// A sequenced request that was accepted by an [`ExactlyOnceServer`].
+///
+/// The request is marked as processed when the final response is sent.
+/// If it is dropped without sending a response, the request is not marked as processed,
+/// and the client may send it again.
+pub struct SequencedRequest<Body, J> {
+	/// The sequence number of the request.
+	sequence: u64,
+
+	/// The request handle.
+	request: ReceivedRequestHandle<Body>,
+
+	/// The state of the server that accepted the request.
+	state: Arc<Mutex<ServerState<J>>>,
+}
+
+impl<Body: Clone + Send + 'static> MemoryClientJournal<Body> {
+	/// Create a new empty journal.
+	pub fn new() -> Self {
+		Self {
+			next_sequence: 0,
+			entries: BTreeMap::new(),
+		}
+	}
+}
+
+impl<Body: Clone + Send + 'static> Default for MemoryClientJournal<Body> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl<Body: Clone + Send + 'static> ClientJournal<Body> for MemoryClientJournal<Body> {
+	fn next_sequence(&mut self) -> Result<u64, Error> {
+		let sequence = self.next_sequence;
+		self.next_sequence += 1;
+		Ok(sequence)
+	}
+
+	fn store(&mut self, sequence: u64, service_id: i32, body: &Body) -> Result<(), Error> {
+		self.entries.insert(sequence, (service_id, body.clone()));
+		Ok(())
+	}
+
+	fn complete(&mut self, sequence: u64) -> Result<(), Error> {
+		self.entries.remove(&sequence);
+		Ok(())
+	}
+
+	fn pending(&mut self) -> Result<Vec<JournalEntry<Body>>, Error> {
+		let entries = self.entries.iter()
+			.map(|(&sequence, (service_id, body))| JournalEntry {
+				sequence,
+				service_id: *service_id,
+				body: body.clone(),
+			})
+			.collect();
+		Ok(entries)
+	}
+}
+
+impl MemoryServerJournal {
+	/// Create a new empty journal.
+	pub fn new() -> Self {
+		Self::default()
+	}
+}
+
+impl ServerJournal for MemoryServerJournal {
+	fn is_processed(&mut self, sequence: u64) -> Result<bool, Error> {
+		Ok(self.processed.contains(&sequence))
+	}
+
+	fn mark_processed(&mut self, sequence: u64) -> Result<(), Error> {
+		self.processed.insert(sequence);
+		Ok(())
+	}
+}
+
+impl<J> ExactlyOnceClient<J> {
+	/// Create a new client with the given journal.
+	pub fn new(journal: J) -> Self {
+		Self {
+			journal: Arc::new(Mutex::new(journal)),
+		}
+	}
+
+	/// Send a sequenced request.
+	///
+	/// The request is stored in the journal before it is sent.
+	/// Returns the sequence number of the request and the request handle.
+	///
+	/// After receiving the final response, call [`Self::complete()`] to remove the request from the journal.
+	pub async fn send_request<Body>(&self, peer: &PeerWriteHandle<Body>, service_id: i32, body: impl Into<Body>) -> Result<(u64, SentRequestHandle<Body>), Error>
+	where
+		Body: crate::Body,
+		J: ClientJournal<Body>,
+	{
+		let body = body.into();
+		let sequence = {
+			let mut journal = self.lock();
+			let sequence = journal.next_sequence()?;
+			journal.store(sequence, service_id, &body)?;
+			sequence
+		};
+		let request = send_sequenced(peer, sequence, service_id, body).await?;
+		Ok((sequence, request))
+	}
+
+	/// Remove a request from the journal after receiving the final response.
+	pub fn complete<Body>(&self, sequence: u64) -> Result<(), Error>
+	where
+		J: ClientJournal<Body>,
+	{
+		self.lock().complete(sequence)
+	}
+
+	/// Resume the pending requests in the journal over a new connection.
+	///
+	/// This asks the server which of the pending requests it already processed.
+	/// Those requests are removed from the journal.
+	/// Their responses are lost, but the requests are not processed again.
+	///
+	/// The other pending requests are sent again with their original sequence number.
+	/// Returns the sequence numbers and request handles of the requests that were sent again.
+	pub async fn resume<Body>(&self, peer: &PeerWriteHandle<Body>) -> Result<Vec<(u64, SentRequestHandle<Body>)>, Error>
+	where
+		Body: crate::Body,
+		J: ClientJournal<Body>,
+	{
+		let pending = self.lock().pending()?;
+		if pending.is_empty() {
+			return Ok(Vec::new());
+		}
+
+		let query = encode_sequences(pending.iter().map(|x| x.sequence));
+		let mut request = peer.send_request(service_id::QUERY_PROCESSED, Body::from_error(&query)).await?;
+		let response = request.recv_response().await?.check_error_response()?;
+		let processed = decode_sequences(&response.body)
+			.ok_or_else(|| Error::custom(String::from("received invalid response to query for processed requests")))?;
+
+		let mut resent = Vec::new();
+		for entry in pending {
+			if processed.contains(&entry.sequence) {
+				self.lock().complete(entry.sequence)?;
+			} else {
+				let request = send_sequenced(peer, entry.sequence, entry.service_id, entry.body).await?;
+				resent.push((entry.sequence, request));
+			}
+		}
+		Ok(resent)
+	}
+
+	/// Lock the journal.
+	fn lock(&self) -> MutexGuard<'_, J> {
+		match self.journal.lock() {
+			Ok(x) => x,
+			Err(e) => e.into_inner(),
+		}
+	}
+}
+
+impl<J: ServerJournal> ExactlyOnceServer<J> {
+	/// Create a new server with the given journal.
+	pub fn new(journal: J) -> Self {
+		Self {
+			state: Arc::new(Mutex::new(ServerState {
+				journal,
+				in_progress: BTreeSet::new(),
+			})),
+		}
+	}
+
+	/// Accept an incoming sequenced request.
+	///
+	/// This waits for the sequence number of the request and checks it against the journal.
+	/// Returns `None` if the request has already been answered,
+	/// because it was a duplicate or because it had no valid sequence number.
+	///
+	/// The returned error only indicates a failure of the journal or of sending the response.
+	pub async fn accept<Body: crate::Body>(&self, mut request: ReceivedRequestHandle<Body>, body: Body) -> Result<Option<(SequencedRequest<Body, J>, Body)>, Error> {
+		let sequence = match request.recv_update().await {
+			Some(update) if update.header.service_id == service_id::SEQUENCE => update.body.as_error().ok().and_then(|x| x.parse().ok()),
+			_ => None,
+		};
+		let sequence = match sequence {
+			Some(x) => x,
+			None => {
+				request.send_error_response("missing or invalid sequence number for sequenced request").await?;
+				return Ok(None);
+			},
+		};
+
+		let duplicate = {
+			let mut state = self.lock();
+			if state.in_progress.contains(&sequence) {
+				Some(false)
+			} else if state.journal.is_processed(sequence)? {
+				Some(true)
+			} else {
+				state.in_progress.insert(sequence);
+				None
+			}
+		};
+
+		match duplicate {
+			None => {
+				let request = SequencedRequest {
+					sequence,
+					request,
+					state: self.state.clone(),
+				};
+				Ok(Some((request, body)))
+			},
+			Some(false) => {
+				let message = format!("request with sequence number {} is still being processed", sequence);
+				request.send_retry_after(Duration::from_millis(100), &message).await?;
+				Ok(None)
+			},
+			Some(true) => {
+				let message = format!("request with sequence number {} has already been processed", sequence);
+				request.send_error_response(&message).await?;
+				Ok(None)
+			},
+		}
+	}
+
+	/// Answer a query from the client for processed requests.
+	///
+	/// The request must have service ID [`service_id::QUERY_PROCESSED`].
+	/// Requests that are still being processed are not reported as processed.
+	pub async fn answer_query<Body: crate::Body>(&self, request: ReceivedRequestHandle<Body>, body: Body) -> Result<(), Error> {
+		let sequences = match decode_sequences(&body) {
+			Some(x) => x,
+			None => return request.send_error_response("invalid query for processed requests").await,
+		};
+
+		let mut processed = Vec::new();
+		{
+			let mut state = self.lock();
+			for sequence in sequences {
+				if state.journal.is_processed(sequence)? {
+					processed.push(sequence);
+				}
+			}
+		}
+
+		let response = encode_sequences(processed.into_iter());
+		request.send_response(service_id::QUERY_PROCESSED, Body::from_error(&response)).await
+	}
+
+	/// Lock the server state.
+	fn lock(&self) -> MutexGuard<'_, ServerState<J>> {
+		lock_state(&self.state)
+	}
+}
+
+impl<Body: crate::Body, J: ServerJournal> SequencedRequest<Body, J> {
+	/// Get the sequence number of the request.
+	pub fn sequence(&self) -> u64 {
+		self.sequence
+	}
+
+	/// Get the request handle.
+	pub fn request(&self) -> &ReceivedRequestHandle<Body> {
+		&self.request
+	}
+
+	/// Get the request handle mutably, for example to receive updates.
+	pub fn request_mut(&mut self) -> &mut ReceivedRequestHandle<Body> {
+		&mut self.request
+	}
+
+	/// Mark the request as processed and send the final response.
+	///
+	/// If the request can not be marked as processed in the journal, no response is sent.
+	pub async fn send_response(self, service_id: i32, body: impl Into<Body>) -> Result<(), Error> {
+		self.mark_processed()?;
+		self.request.send_response(service_id, body).await
+	}
+
+	/// Mark the request as processed and send the final response with an error message.
+	///
+	/// If the request can not be marked as processed in the journal, no response is sent.
+	pub async fn send_error_response(self, message: &str) -> Result<(), Error> {
+		self.mark_processed()?;
+		self.request.send_error_response(message).await
+	}
+
+	/// Mark the request as processed in the journal.
+	fn mark_processed(&self) -> Result<(), Error> {
+		lock_state(&self.state).journal.mark_processed(self.sequence)
+	}
+}
+
+impl<Body, J> Drop for SequencedRequest<Body, J> {
+	fn drop(&mut self) {
+		lock_state(&self.state).in_progress.remove(&self.sequence);
+	}
+}
+
+impl<J> Clone for ExactlyOnceClient<J> {
+	fn clone(&self) -> Self {
+		Self {
+			journal: self.journal.clone(),
+		}
+	}
+}
+
+impl<J> Clone for ExactlyOnceServer<J> {
+	fn clone(&self) -> Self {
+		Self {
+			state: self.state.clone(),
+		}
+	}
+}
+
+impl<J> std::fmt::Debug for ExactlyOnceClient<J> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct(core::any::type_name::<Self>())
+			.finish_non_exhaustive()
+	}
+}
+
+impl<J> std::fmt::Debug for ExactlyOnceServer<J> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct(core::any::type_name::<Self>())
+			.finish_non_exhaustive()
+	}
+}
+
+impl<Body, J> std::fmt::Debug for SequencedRequest<Body, J> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct(core::any::type_name::<Self>())
+			.field("sequence", &self.sequence)
+			.field("request", &self.request)
+			.finish_non_exhaustive()
+	}
+}
+
+/// Lock the state of a server.
+fn lock_state<J>(state: &Mutex<ServerState<J>>) -> MutexGuard<'_, ServerState<J>> {
+	match state.lock() {
+		Ok(x) => x,
+		Err(e) => e.into_inner(),
+	}
+}
+
+/// Send a request followed by its sequence number.
+async fn send_sequenced<Body: crate::Body>(peer: &PeerWriteHandle<Body>, sequence: u64, service_id: i32, body: Body) -> Result<SentRequestHandle<Body>, Error> {
+	let request = peer.send_request(service_id, body).await?;
+	request.send_update(service_id::SEQUENCE, Body::from_error(&sequence.to_string())).await?;
+	Ok(request)
+}
+
+/// Encode a list of sequence numbers.
+fn encode_sequences(sequences: impl Iterator<Item = u64>) -> String {
+	sequences.map(|x| x.to_string()).collect::<Vec<_>>().join(" ")
+}
+
+/// Decode a list of sequence numbers.
+fn decode_sequences<Body: crate::Body>(body: &Body) -> Option<BTreeSet<u64>> {
+	body.as_error().ok()?
+		.split_ascii_whitespace()
+		.map(|x| x.parse().ok())
+		.collect()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+
+	use crate::{PeerHandle, ReceivedMessage, StreamBody, UnixStreamPeer, UnixStreamTransport};
+
+	fn peer_pair() -> (PeerHandle<StreamBody>, PeerHandle<StreamBody>) {
+		let_assert!(Ok((peer_a, peer_b)) = tokio::net::UnixStream::pair());
+		let peer_a = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, Default::default()));
+		let peer_b = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default()));
+		(peer_a, peer_b)
+	}
+
+	#[tokio::test]
+	async fn resume_after_reconnect() {
+		let client = ExactlyOnceClient::new(MemoryClientJournal::<StreamBody>::new());
+		let server = ExactlyOnceServer::new(MemoryServerJournal::new());
+
+		// The first request is processed, but the connection is lost before the response is received.
+		// The second request is received, but the connection is lost before it is processed.
+		// The third request is never received by the server.
+		let (client_peer, mut server_peer) = peer_pair();
+		let (_read, client_write) = client_peer.split();
+		let_assert!(Ok((0, _)) = client.send_request(&client_write, 10, &b"first"[..]).await);
+		let_assert!(Ok((1, _)) = client.send_request(&client_write, 11, &b"second"[..]).await);
+
+		let_assert!(Ok(ReceivedMessage::Request(request, body)) = server_peer.recv_message().await);
+		let_assert!(Ok(Some((request, body))) = server.accept(request, body).await);
+		assert!(request.sequence() == 0);
+		assert!(body.as_ref() == b"first");
+		assert!(let Ok(()) = request.send_response(10, &b"done"[..]).await);
+
+		let_assert!(Ok(ReceivedMessage::Request(request, body)) = server_peer.recv_message().await);
+		let_assert!(Ok(Some((request, _body))) = server.accept(request, body).await);
+		assert!(request.sequence() == 1);
+		drop(request);
+		server_peer.close();
+		client_write.close();
+
+		// Pretend the connection dropped before the third request was sent.
+		{
+			let mut journal = client.lock();
+			let_assert!(Ok(2) = journal.next_sequence());
+			let_assert!(Ok(()) = journal.store(2, 12, &b"third"[..].into()));
+		}
+
+		// After reconnecting, only the second and third request are sent again.
+		let (client_peer, mut server_peer) = peer_pair();
+		let (_read, client_write) = client_peer.split();
+		let server_task = tokio::spawn({
+			let server = server.clone();
+			async move {
+				let_assert!(Ok(ReceivedMessage::Request(request, body)) = server_peer.recv_message().await);
+				assert!(request.service_id() == service_id::QUERY_PROCESSED);
+				assert!(let Ok(()) = server.answer_query(request, body).await);
+				for (sequence, service_id, expected) in [(1, 11, &b"second"[..]), (2, 12, &b"third"[..])] {
+					let_assert!(Ok(ReceivedMessage::Request(request, body)) = server_peer.recv_message().await);
+					let_assert!(Ok(Some((request, body))) = server.accept(request, body).await);
+					assert!(request.sequence() == sequence);
+					assert!(request.request().service_id() == service_id);
+					assert!(body.as_ref() == expected);
+					assert!(let Ok(()) = request.send_response(service_id, &b"done"[..]).await);
+				}
+			}
+		});
+
+		let_assert!(Ok(resent) = client.resume(&client_write).await);
+		assert!(resent.len() == 2);
+		for (sequence, mut request) in resent {
+			let_assert!(Ok(response) = request.recv_response().await);
+			assert!(response.body.as_ref() == b"done");
+			assert!(let Ok(()) = client.complete(sequence));
+		}
+		assert!(let Ok(()) = server_task.await);
+
+		let_assert!(Ok(pending) = client.lock().pending());
+		assert!(pending.is_empty());
+		let_assert!(Ok(resent) = client.resume(&client_write).await);
+		assert!(resent.is_empty());
+	}
+
+	#[tokio::test]
+	async fn reject_duplicates() {
+		let server = ExactlyOnceServer::new(MemoryServerJournal::new());
+		let (client_peer, mut server_peer) = peer_pair();
+
+		for _ in 0..3 {
+			let_assert!(Ok(request) = client_peer.send_request(1, &b"hello"[..]).await);
+			assert!(let Ok(()) = request.send_update(service_id::SEQUENCE, &b"7"[..]).await);
+		}
+		let_assert!(Ok(mut unsequenced) = client_peer.send_request(1, &b"hello"[..]).await);
+
+		// A duplicate that arrives while the original is being processed is rejected with a retry-after response.
+		let_assert!(Ok(ReceivedMessage::Request(request, body)) = server_peer.recv_message().await);
+		let_assert!(Ok(Some((original, _body))) = server.accept(request, body).await);
+		let_assert!(Ok(ReceivedMessage::Request(request, body)) = server_peer.recv_message().await);
+		assert!(let Ok(None) = server.accept(request, body).await);
+		assert!(let Ok(()) = original.send_response(1, &b"done"[..]).await);
+
+		// A duplicate that arrives after the original was processed is rejected with an error response.
+		let_assert!(Ok(ReceivedMessage::Request(request, body)) = server_peer.recv_message().await);
+		assert!(let Ok(None) = server.accept(request, body).await);
+
+		// Requests without sequence number are rejected.
+		let_assert!(Ok(ReceivedMessage::Request(request, body)) = server_peer.recv_message().await);
+		let accept = tokio::spawn({
+			let server = server.clone();
+			async move { server.accept(request, body).await.map(|x| x.is_none()) }
+		});
+		assert!(let Ok(()) = unsequenced.send_update(5, &b"not a sequence number"[..]).await);
+		assert!(let Ok(Ok(true)) = accept.await);
+		let_assert!(Ok(response) = unsequenced.recv_response().await);
+		let_assert!(Err(e) = response.check_error_response());
+		assert!(e.as_remote_error() == Some("missing or invalid sequence number for sequenced request"));
+	}
+}
@@ -3,9 +3,17 @@
 //! These traits are used by generated interfaces from the [`interface!`] macro.
 //! Normally, you would only implement these traits for your own serialization format.
 //! However, the traits are covered by semver guarantees, so feel free to use them in your own code.
+//!
+//! With the `format-postcard` feature, this module also provides the [`Postcard`] format.
 
 use crate::Error;
 
+#[cfg(feature = "format-postcard")]
+mod postcard;
+
+#[cfg(feature = "format-postcard")]
+pub use self::postcard::Postcard;
+
 /// A message format, used to encode/decode RPC messages from/to Rust types.
 pub trait Format {
 	/// The body type for the RPC messages.
@@ -34,6 +42,89 @@ pub trait EncodeBody<T: ?Sized>: Format {
 pub trait DecodeBody<T: Sized>: Format {
 	/// Decode a message body to the Rust value.
 	fn decode_body(body: Self::Body) -> Result<T, Box<dyn std::error::Error + Send>>;
+
+	/// Decode a message body to the Rust value, while respecting the limits of a decode context.
+	///
+	/// Generated interfaces use this function to decode all message bodies,
+	/// with the [`DecodeContext`] configured on the client or server.
+	///
+	/// The default implementation checks the body size with [`DecodeContext::check_body_len()`] and then calls [`Self::decode_body()`].
+	/// Formats that support other limits, like a maximum nesting depth, should override this function to enforce them.
+	fn decode_body_with_context(body: Self::Body, context: &DecodeContext) -> Result<T, Box<dyn std::error::Error + Send>> {
+		use crate::Body;
+		context.check_body_len(body.data_len())?;
+		Self::decode_body(body)
+	}
+
+	/// Decode a message body to the Rust value, or report that the value is not recognized.
+	///
+	/// Generated interfaces with the `#[forward_compatible]` attribute use this function to decode update and stream messages.
+	/// If it returns [`PartialDecode::Unrecognized`], the message is exposed as `Unrecognized` variant of the generated enum,
+	/// instead of failing with a decode error.
+	///
+	/// Formats can override this function to recognize bodies that are valid, but hold a value this version of `T` does not know about,
+	/// like an enum variant that was added by a newer version of the remote peer.
+	/// The default implementation calls [`Self::decode_body_with_context()`] and never reports an unrecognized value.
+	fn decode_body_partial(body: Self::Body, context: &DecodeContext) -> Result<PartialDecode<T>, Box<dyn std::error::Error + Send>> {
+		Self::decode_body_with_context(body, context).map(PartialDecode::Decoded)
+	}
+}
+
+/// The result of [`DecodeBody::decode_body_partial()`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PartialDecode<T> {
+	/// The body was decoded successfully.
+	Decoded(T),
+
+	/// The body holds a value that is not recognized, like an unknown enum variant.
+	///
+	/// Holds the raw data of the body.
+	Unrecognized(Vec<u8>),
+}
+
+/// Limits for decoding message bodies.
+///
+/// The decode context is passed to [`DecodeBody::decode_body_with_context()`],
+/// so that format implementations can protect against malicious or malformed input with consistent limits.
+/// It is configured once per generated client or server, rather than separately in each format.
+///
+/// The limits are only enforced by format implementations that support them.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct DecodeContext {
+	/// The maximum size of a message body in bytes.
+	///
+	/// Larger bodies are rejected before decoding.
+	///
+	/// Default: `None`.
+	pub max_body_len: Option<usize>,
+
+	/// The suggested maximum nesting depth of decoded values.
+	///
+	/// Formats that decode recursively should reject values that are nested deeper than this limit,
+	/// to avoid running out of stack space.
+	///
+	/// Default: `Some(128)`.
+	pub max_nesting_depth: Option<usize>,
+}
+
+impl DecodeContext {
+	/// Check the size of a message body against [`Self::max_body_len`].
+	pub fn check_body_len(&self, body_len: usize) -> Result<(), Box<dyn std::error::Error + Send>> {
+		match self.max_body_len {
+			Some(max_len) if body_len > max_len => Err(Box::new(Error::payload_too_large(body_len, max_len))),
+			_ => Ok(()),
+		}
+	}
+}
+
+impl Default for DecodeContext {
+	fn default() -> Self {
+		Self {
+			max_body_len: None,
+			max_nesting_depth: Some(128),
+		}
+	}
 }
 
 /// Trait for values that can be encoded to a message with a specific [`Format`].
@@ -57,3 +148,408 @@ pub trait FromMessage<F: Format + ?Sized>: Sized {
 	/// Decode a message to the Rust value.
 	fn from_message(message: crate::Message<F::Body>) -> Result<Self, Error>;
 }
+
+/// Name of the histogram that records the durations of encoding and decoding message bodies.
+///
+/// The histogram is only recorded if the `metrics` feature is enabled,
+/// using the recorder installed for the [`metrics`](https://docs.rs/metrics) crate.
+/// The durations are recorded in seconds, with these labels:
+///
+/// * `format`: the type name of the format,
+/// * `service_id`: the service ID of the message,
+/// * `direction`: `encode` or `decode`.
+///
+/// Generated interfaces record the duration of all encode and decode operations,
+/// using [`encode_body_instrumented()`], [`decode_body_instrumented()`] and the other `_instrumented` and `_offloaded` functions in this module.
+pub const CODEC_DURATION_METRIC: &str = "fizyr_rpc_body_codec_duration_seconds";
+
+/// Encode a message body and record the duration in the [`CODEC_DURATION_METRIC`] histogram.
+pub fn encode_body_instrumented<F, T>(service_id: i32, value: &T) -> Result<F::Body, Box<dyn std::error::Error + Send>>
+where
+	F: EncodeBody<T>,
+	T: ?Sized,
+{
+	instrument(std::any::type_name::<F>(), service_id, "encode", || F::encode_body(value))
+}
+
+/// Decode a message body with [`DecodeBody::decode_body_with_context()`] and record the duration in the [`CODEC_DURATION_METRIC`] histogram.
+pub fn decode_body_instrumented<F, T>(service_id: i32, body: F::Body, context: &DecodeContext) -> Result<T, Box<dyn std::error::Error + Send>>
+where
+	F: DecodeBody<T>,
+{
+	instrument(std::any::type_name::<F>(), service_id, "decode", || F::decode_body_with_context(body, context))
+}
+
+/// Decode a message body with [`DecodeBody::decode_body_partial()`] and record the duration in the [`CODEC_DURATION_METRIC`] histogram.
+pub fn decode_body_partial_instrumented<F, T>(service_id: i32, body: F::Body, context: &DecodeContext) -> Result<PartialDecode<T>, Box<dyn std::error::Error + Send>>
+where
+	F: DecodeBody<T>,
+{
+	instrument(std::any::type_name::<F>(), service_id, "decode", || F::decode_body_partial(body, context))
+}
+
+/// Run an encode or decode function and record the duration if the `metrics` feature is enabled.
+fn instrument<R>(format: &'static str, service_id: i32, direction: &'static str, function: impl FnOnce() -> R) -> R {
+	#[cfg(feature = "metrics")]
+	{
+		let start = std::time::Instant::now();
+		let result = function();
+		metrics::histogram!(
+			CODEC_DURATION_METRIC,
+			"format" => format,
+			"service_id" => service_id.to_string(),
+			"direction" => direction,
+		).record(start.elapsed());
+		result
+	}
+
+	#[cfg(not(feature = "metrics"))]
+	{
+		let _ = (format, service_id, direction);
+		function()
+	}
+}
+
+/// Decode a message body, offloading the work to a blocking thread for large bodies.
+///
+/// If `offload_threshold` is `Some(n)` and the body holds at least `n` bytes of data,
+/// the body is decoded using [`tokio::task::spawn_blocking()`].
+/// Otherwise, the body is decoded directly on the current task.
+///
+/// Offloading expensive decoding keeps the runtime worker threads free for other tasks,
+/// such as the read/write loops of peers.
+/// The size of a body is determined with [`Body::data_len()`][crate::Body::data_len].
+///
+/// The body is decoded with [`decode_body_instrumented()`],
+/// so the duration of the decoding itself is recorded in the [`CODEC_DURATION_METRIC`] histogram.
+///
+/// # Cancel safety
+/// This function is not cancel safe.
+/// If the future is dropped while the body is decoded on a blocking thread,
+/// the decoding still runs to completion, but the decoded value is discarded.
+pub async fn decode_body_offloaded<F, T>(service_id: i32, body: F::Body, offload_threshold: Option<usize>, context: &DecodeContext) -> Result<T, Box<dyn std::error::Error + Send>>
+where
+	F: DecodeBody<T> + 'static,
+	T: Send + 'static,
+{
+	offload_decode::<F, T, _>(body, offload_threshold, context, move |body, context| decode_body_instrumented::<F, T>(service_id, body, context)).await
+}
+
+/// Decode a message body partially, offloading the work to a blocking thread for large bodies.
+///
+/// This is the same as [`decode_body_offloaded()`],
+/// except that the body is decoded with [`decode_body_partial_instrumented()`].
+pub async fn decode_body_partial_offloaded<F, T>(service_id: i32, body: F::Body, offload_threshold: Option<usize>, context: &DecodeContext) -> Result<PartialDecode<T>, Box<dyn std::error::Error + Send>>
+where
+	F: DecodeBody<T> + 'static,
+	T: Send + 'static,
+{
+	offload_decode::<F, PartialDecode<T>, _>(body, offload_threshold, context, move |body, context| decode_body_partial_instrumented::<F, T>(service_id, body, context)).await
+}
+
+/// Run a decode function, offloading it to a blocking thread for large bodies.
+async fn offload_decode<F, R, D>(body: F::Body, offload_threshold: Option<usize>, context: &DecodeContext, decode: D) -> Result<R, Box<dyn std::error::Error + Send>>
+where
+	F: Format + 'static,
+	R: Send + 'static,
+	D: FnOnce(F::Body, &DecodeContext) -> Result<R, Box<dyn std::error::Error + Send>> + Send + 'static,
+{
+	use crate::Body;
+
+	match offload_threshold {
+		Some(threshold) if body.data_len() >= threshold => {
+			let context = context.clone();
+			match tokio::task::spawn_blocking(move || decode(body, &context)).await {
+				Ok(result) => result,
+				Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+				Err(e) => Err(Box::new(e)),
+			}
+		},
+		_ => decode(body, context),
+	}
+}
+
+/// Budget for decoding message bodies directly on the current task.
+///
+/// Decoding a large message body can keep a runtime worker thread busy for a long time,
+/// and the decoding itself can not be interrupted.
+/// A decode budget limits how long a task keeps the worker thread busy with decoding *in a row*:
+/// once the task decoded bodies with a total size of at least the budget,
+/// it yields to the runtime with [`tokio::task::yield_now()`] so that other tasks can make progress.
+/// This keeps the read and write loops of peers and other connections responsive,
+/// even on a single threaded runtime.
+///
+/// Generated servers use a decode budget in `recv_message()` and `recv_message_offloaded()`.
+/// Bodies that are decoded on a blocking thread because of the offload threshold are not counted,
+/// since they do not keep the worker thread busy.
+#[derive(Debug, Clone)]
+pub struct DecodeBudget {
+	/// The number of bytes that may be decoded before yielding, if limited.
+	budget: Option<usize>,
+
+	/// The number of bytes decoded since the last time the budget ran out.
+	used: usize,
+}
+
+impl DecodeBudget {
+	/// Create a new decode budget.
+	///
+	/// If `budget` is `None`, the budget is unlimited and [`Self::consume()`] never yields.
+	pub fn new(budget: Option<usize>) -> Self {
+		Self { budget, used: 0 }
+	}
+
+	/// Get the number of bytes that may be decoded before yielding to the runtime.
+	pub fn budget(&self) -> Option<usize> {
+		self.budget
+	}
+
+	/// Set the number of bytes that may be decoded before yielding to the runtime.
+	///
+	/// This also resets the bytes used so far.
+	pub fn set_budget(&mut self, budget: Option<usize>) {
+		self.budget = budget;
+		self.used = 0;
+	}
+
+	/// Account for a decoded message body, and yield to the runtime if the budget ran out.
+	///
+	/// Bodies of at least `offload_threshold` bytes are assumed to be decoded on a blocking thread,
+	/// so they are not counted.
+	pub async fn consume(&mut self, body_len: usize, offload_threshold: Option<usize>) {
+		let Some(budget) = self.budget else {
+			return;
+		};
+		if offload_threshold.map_or(false, |threshold| body_len >= threshold) {
+			return;
+		}
+		self.used = self.used.saturating_add(body_len);
+		if self.used >= budget {
+			self.used = 0;
+			tokio::task::yield_now().await;
+		}
+	}
+}
+
+impl Default for DecodeBudget {
+	fn default() -> Self {
+		Self::new(None)
+	}
+}
+
+/// Decoder to decode incoming stream messages in parallel.
+///
+/// Generated servers use this to decode messages of streams marked with `#[unordered]` in the interface definition.
+/// Each message is decoded in a separate task, and decoded messages are returned in the order that decoding finishes.
+/// This means that messages may be delivered out of order, even if they have the same service ID.
+///
+/// At most [`Self::max_parallel()`] messages are decoded at the same time.
+/// While that many messages are being decoded, no new messages are read from the peer.
+///
+/// Messages that are still being decoded when the decoder is dropped are discarded.
+pub struct UnorderedDecoder<T, Body> {
+	/// The tasks decoding messages.
+	tasks: tokio::task::JoinSet<Result<T, crate::RecvMessageError<Body>>>,
+
+	/// The maximum number of messages to decode at the same time.
+	max_parallel: usize,
+}
+
+/// The next item from an [`UnorderedDecoder`].
+#[derive(Debug)]
+pub enum UnorderedNext<T, Body> {
+	/// A message that was decoded in parallel.
+	Decoded(Result<T, crate::RecvMessageError<Body>>),
+
+	/// A new message from the peer that still needs to be processed.
+	Received(Result<crate::ReceivedMessage<Body>, Error>),
+}
+
+impl<T, Body> UnorderedDecoder<T, Body> {
+	/// Create a new decoder that decodes at most `max_parallel` messages at the same time.
+	///
+	/// If `max_parallel` is zero, the decoder is disabled and all messages should be decoded in order by the caller.
+	pub fn new(max_parallel: usize) -> Self {
+		Self {
+			tasks: tokio::task::JoinSet::new(),
+			max_parallel,
+		}
+	}
+
+	/// Get the maximum number of messages to decode at the same time.
+	///
+	/// Zero means that parallel decoding is disabled.
+	pub fn max_parallel(&self) -> usize {
+		self.max_parallel
+	}
+
+	/// Set the maximum number of messages to decode at the same time.
+	///
+	/// Messages that are already being decoded are not affected.
+	pub fn set_max_parallel(&mut self, max_parallel: usize) {
+		self.max_parallel = max_parallel;
+	}
+
+	/// Check if parallel decoding is enabled.
+	pub fn is_enabled(&self) -> bool {
+		self.max_parallel > 0
+	}
+
+	/// Get the number of messages that are currently being decoded.
+	pub fn len(&self) -> usize {
+		self.tasks.len()
+	}
+
+	/// Check if no messages are currently being decoded.
+	pub fn is_empty(&self) -> bool {
+		self.tasks.is_empty()
+	}
+
+	/// Start decoding a message in a new task.
+	///
+	/// The result is returned from [`Self::recv()`] when the decoding is finished.
+	pub fn spawn<Fut>(&mut self, decode: Fut)
+	where
+		Fut: std::future::Future<Output = Result<T, crate::RecvMessageError<Body>>> + Send + 'static,
+		T: Send + 'static,
+		Body: crate::Body,
+	{
+		self.tasks.spawn(decode);
+	}
+
+	/// Get the next decoded message, or the next message from the peer.
+	///
+	/// Decoded messages take precedence over new messages from the peer.
+	/// New messages are only read from the peer while less than [`Self::max_parallel()`] messages are being decoded,
+	/// or if no messages are being decoded at all.
+	///
+	/// This function is cancel safe: if the future is dropped before it completes, no messages are lost.
+	pub async fn recv(&mut self, peer: &mut crate::PeerReadHandle<Body>) -> UnorderedNext<T, Body>
+	where
+		T: 'static,
+		Body: 'static,
+	{
+		std::future::poll_fn(|context| {
+			while let std::task::Poll::Ready(Some(result)) = self.tasks.poll_join_next(context) {
+				match result {
+					Ok(decoded) => return std::task::Poll::Ready(UnorderedNext::Decoded(decoded)),
+					Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+					// The tasks are only cancelled when the runtime shuts down.
+					Err(_) => continue,
+				}
+			}
+			if self.tasks.len() < self.max_parallel.max(1) {
+				peer.poll_recv_message(context).map(UnorderedNext::Received)
+			} else {
+				std::task::Poll::Pending
+			}
+		}).await
+	}
+}
+
+impl<T, Body> std::fmt::Debug for UnorderedDecoder<T, Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("UnorderedDecoder")
+			.field("pending", &self.tasks.len())
+			.field("max_parallel", &self.max_parallel)
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use std::sync::atomic::{AtomicBool, Ordering};
+	use std::sync::Arc;
+
+	/// The name and labels of a registered histogram.
+	#[cfg(all(feature = "metrics", feature = "format-postcard"))]
+	type Registration = (String, Vec<(String, String)>);
+
+	/// Recorder that remembers the name and labels of all registered histograms.
+	#[cfg(all(feature = "metrics", feature = "format-postcard"))]
+	#[derive(Default)]
+	struct HistogramRecorder {
+		histograms: std::sync::Mutex<Vec<Registration>>,
+	}
+
+	#[cfg(all(feature = "metrics", feature = "format-postcard"))]
+	impl metrics::Recorder for HistogramRecorder {
+		fn describe_counter(&self, _key: metrics::KeyName, _unit: Option<metrics::Unit>, _description: metrics::SharedString) {}
+		fn describe_gauge(&self, _key: metrics::KeyName, _unit: Option<metrics::Unit>, _description: metrics::SharedString) {}
+		fn describe_histogram(&self, _key: metrics::KeyName, _unit: Option<metrics::Unit>, _description: metrics::SharedString) {}
+
+		fn register_counter(&self, _key: &metrics::Key, _metadata: &metrics::Metadata<'_>) -> metrics::Counter {
+			metrics::Counter::noop()
+		}
+
+		fn register_gauge(&self, _key: &metrics::Key, _metadata: &metrics::Metadata<'_>) -> metrics::Gauge {
+			metrics::Gauge::noop()
+		}
+
+		fn register_histogram(&self, key: &metrics::Key, _metadata: &metrics::Metadata<'_>) -> metrics::Histogram {
+			let labels = key.labels()
+				.map(|x| (x.key().to_owned(), x.value().to_owned()))
+				.collect();
+			self.histograms.lock().unwrap().push((key.name().to_owned(), labels));
+			metrics::Histogram::noop()
+		}
+	}
+
+	#[test]
+	#[cfg(all(feature = "metrics", feature = "format-postcard"))]
+	fn record_codec_durations() {
+		use assert2::let_assert;
+
+		let recorder = HistogramRecorder::default();
+		metrics::with_local_recorder(&recorder, || {
+			let_assert!(Ok(body) = encode_body_instrumented::<Postcard, _>(12, "hello"));
+			let_assert!(Ok(decoded) = decode_body_instrumented::<Postcard, String>(13, body, &DecodeContext::default()));
+			assert!(decoded == "hello");
+		});
+
+		let histograms = recorder.histograms.into_inner().unwrap();
+		let format = std::any::type_name::<Postcard>().to_owned();
+		let labels = |service_id: &str, direction: &str| vec![
+			("format".to_owned(), format.clone()),
+			("service_id".to_owned(), service_id.to_owned()),
+			("direction".to_owned(), direction.to_owned()),
+		];
+		assert!(histograms == [
+			(CODEC_DURATION_METRIC.to_owned(), labels("12", "encode")),
+			(CODEC_DURATION_METRIC.to_owned(), labels("13", "decode")),
+		]);
+	}
+
+	#[tokio::test]
+	async fn decode_budget_yields() {
+		// The test runtime is single threaded, so the task only runs if the budget yields.
+		let ran = Arc::new(AtomicBool::new(false));
+		let spawn_task = || {
+			ran.store(false, Ordering::Relaxed);
+			let ran = ran.clone();
+			tokio::spawn(async move { ran.store(true, Ordering::Relaxed) })
+		};
+
+		let mut budget = DecodeBudget::new(Some(100));
+		let _task = spawn_task();
+		budget.consume(60, None).await;
+		assert!(ran.load(Ordering::Relaxed) == false);
+		budget.consume(40, None).await;
+		assert!(ran.load(Ordering::Relaxed) == true);
+
+		// Offloaded bodies do not count.
+		let _task = spawn_task();
+		budget.consume(1000, Some(500)).await;
+		assert!(ran.load(Ordering::Relaxed) == false);
+		budget.consume(100, Some(500)).await;
+		assert!(ran.load(Ordering::Relaxed) == true);
+
+		// An unlimited budget never yields.
+		budget.set_budget(None);
+		let _task = spawn_task();
+		budget.consume(usize::MAX, None).await;
+		assert!(ran.load(Ordering::Relaxed) == false);
+	}
+}
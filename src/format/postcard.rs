@@ -0,0 +1,55 @@
+use crate::StreamBody;
+
+use super::{DecodeBody, EncodeBody, Format};
+
+/// Message format that encodes bodies with [`postcard`](https://docs.rs/postcard).
+///
+/// Postcard is a compact binary encoding for [`serde`](https://docs.rs/serde) types that also works on `no_std` targets,
+/// which makes it a good fit for talking to embedded devices.
+/// All types that implement [`serde::Serialize`] and [`serde::de::DeserializeOwned`] can be used as message body.
+///
+/// The message bodies are [`StreamBody`] values,
+/// so this format can be used with the TCP, Unix stream and QUIC transports.
+///
+/// This type is only available with the `format-postcard` feature.
+pub struct Postcard;
+
+impl Format for Postcard {
+	type Body = StreamBody;
+}
+
+impl<T: serde::Serialize + ?Sized> EncodeBody<T> for Postcard {
+	fn encode_body(value: &T) -> Result<StreamBody, Box<dyn std::error::Error + Send>> {
+		::postcard::to_allocvec(value)
+			.map(StreamBody::from)
+			.map_err(|e| Box::new(e) as _)
+	}
+}
+
+impl<T: serde::de::DeserializeOwned> DecodeBody<T> for Postcard {
+	fn decode_body(body: StreamBody) -> Result<T, Box<dyn std::error::Error + Send>> {
+		::postcard::from_bytes(&body.data)
+			.map_err(|e| Box::new(e) as _)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::{assert, let_assert};
+
+	#[test]
+	fn encode_decode() {
+		let value = (5u32, String::from("hello"), vec![Some(1i16), None, Some(-3)]);
+		let_assert!(Ok(body) = Postcard::encode_body(&value));
+		assert!(body.data.len() < 20);
+		let_assert!(Ok(decoded) = <Postcard as DecodeBody<(u32, String, Vec<Option<i16>>)>>::decode_body(body));
+		assert!(decoded == value);
+	}
+
+	#[test]
+	fn decode_invalid() {
+		let body = StreamBody::from(vec![0xFF]);
+		assert!(let Err(_) = <Postcard as DecodeBody<String>>::decode_body(body));
+	}
+}
@@ -0,0 +1,325 @@
+use std::sync::Arc;
+
+use crate::error::private::connection_aborted;
+use crate::util::{select, Either};
+use crate::{service_id, Error, Message, PeerHandle, PeerWriteHandle, ReceivedMessage, ReceivedRequestHandle, SentRequestHandle};
+
+/// Forward a received request to another peer, and relay the updates and the response between them.
+///
+/// The `request_in` is the request received from the original requester,
+/// and `request_out` is the same request sent to the peer that should handle it.
+/// Request updates from the requester are passed on to `request_out`,
+/// response updates and the final response from the handling peer are passed back to `request_in`.
+///
+/// Each message is converted with one of the conversion functions,
+/// which return the service ID and body of the message to forward.
+/// This allows the bodies to be re-encoded when the two peers use a different message format.
+/// Error responses and retry-after responses are forwarded without calling a conversion function.
+///
+/// If a message can not be converted or forwarded, or if the connection with the handling peer is lost,
+/// an error response is sent to the requester and the error is returned.
+/// If the requester disconnects before the response arrives,
+/// `request_out` is dropped and an error is returned.
+///
+/// Generated interface clients have a `forward_<service>()` function for each service that uses this function.
+pub async fn forward_request<BodyIn, BodyOut, Q, P, R>(
+	mut request_in: ReceivedRequestHandle<BodyIn>,
+	mut request_out: SentRequestHandle<BodyOut>,
+	mut convert_request_update: Q,
+	mut convert_response_update: P,
+	convert_response: R,
+) -> Result<(), Error>
+where
+	BodyIn: crate::Body,
+	BodyOut: crate::Body,
+	Q: FnMut(Message<BodyIn>) -> Result<(i32, BodyOut), Error>,
+	P: FnMut(Message<BodyOut>) -> Result<(i32, BodyIn), Error>,
+	R: FnOnce(Message<BodyOut>) -> Result<(i32, BodyIn), Error>,
+{
+	loop {
+		let event = {
+			let incoming = request_in.recv_update();
+			let outgoing = request_out.recv_update();
+			tokio::pin!(incoming);
+			tokio::pin!(outgoing);
+			match select(incoming, outgoing).await {
+				Either::Left((update, _)) => Either::Left(update),
+				Either::Right((_, update)) => Either::Right(update),
+			}
+		};
+
+		let result = match event {
+			// The requester is gone, so nobody is waiting for the response anymore.
+			Either::Left(None) => return Err(connection_aborted()),
+			Either::Left(Some(update)) => match convert_request_update(update) {
+				Ok((service_id, body)) => request_out.send_update(service_id, body).await,
+				Err(e) => Err(e),
+			},
+			Either::Right(Some(update)) => match convert_response_update(update) {
+				Ok((service_id, body)) => request_in.send_update(service_id, body).await,
+				Err(e) => Err(e),
+			},
+			// No more updates, so the final response is waiting.
+			Either::Right(None) => break,
+		};
+
+		if let Err(e) = result {
+			let _: Result<_, _> = request_in.send_error_response(&e.to_string()).await;
+			return Err(e);
+		}
+	}
+
+	let response = request_out.recv_response().await
+		.and_then(|response| match response.header.service_id {
+			service_id::ERROR | service_id::CODED_ERROR | service_id::RETRY_AFTER => {
+				let message = response.body
+					.into_error()
+					.map_err(|e| Error::decode_failed(Box::new(e)))?;
+				Ok((response.header.service_id, BodyIn::from_error(&message)))
+			},
+			_ => convert_response(response),
+		});
+	match response {
+		Ok((service_id, body)) => request_in.send_response(service_id, body).await,
+		Err(e) => {
+			let _: Result<_, _> = request_in.send_error_response(&e.to_string()).await;
+			Err(e)
+		},
+	}
+}
+
+/// Relay all requests and stream messages between two peers, until one of the connections is closed.
+///
+/// Requests received from one peer are sent to the other peer, and forwarded with [`forward_request()`] in a separate task.
+/// Stream messages are passed on in the order they are received.
+/// The deadline of received requests and the flags of stream messages are passed on as well.
+///
+/// The message bodies are converted with `a_to_b` and `b_to_a`, so the peers can use different body types.
+/// For example, to connect a peer using a stream transport with a peer using a Unix seqpacket transport,
+/// use [`UnixBody::from()`][crate::UnixBody] and [`UnixBody::into_stream_body()`][crate::UnixBody::into_stream_body].
+/// If the body of a request can not be converted or the request can not be sent, an error response is sent back.
+/// Stream messages that can not be converted or sent are dropped.
+///
+/// This function returns when one of the peers closes the connection.
+/// Requests that are still being forwarded at that point keep running in their own task.
+pub async fn relay_peers<BodyA, BodyB, F, G>(peer_a: PeerHandle<BodyA>, peer_b: PeerHandle<BodyB>, a_to_b: F, b_to_a: G) -> Result<(), Error>
+where
+	BodyA: crate::Body,
+	BodyB: crate::Body,
+	F: Fn(BodyA) -> Result<BodyB, Error> + Send + Sync + 'static,
+	G: Fn(BodyB) -> Result<BodyA, Error> + Send + Sync + 'static,
+{
+	let (mut read_a, write_a) = peer_a.split();
+	let (mut read_b, write_b) = peer_b.split();
+	let a_to_b = Arc::new(a_to_b);
+	let b_to_a = Arc::new(b_to_a);
+
+	loop {
+		let event = {
+			let from_a = read_a.recv_message();
+			let from_b = read_b.recv_message();
+			tokio::pin!(from_a);
+			tokio::pin!(from_b);
+			match select(from_a, from_b).await {
+				Either::Left((message, _)) => Either::Left(message),
+				Either::Right((_, message)) => Either::Right(message),
+			}
+		};
+
+		match event {
+			Either::Left(Ok(message)) => relay_message(message, &write_b, a_to_b.clone(), b_to_a.clone()).await,
+			Either::Right(Ok(message)) => relay_message(message, &write_a, b_to_a.clone(), a_to_b.clone()).await,
+			Either::Left(Err(e)) | Either::Right(Err(e)) => {
+				if e.is_connection_aborted() {
+					return Ok(());
+				}
+				trace_event!(debug, error = %e, "failed to receive message to relay");
+			},
+		}
+	}
+}
+
+/// Relay a single message received by [`relay_peers()`] to the other peer.
+async fn relay_message<BodyIn, BodyOut, F, G>(message: ReceivedMessage<BodyIn>, peer_out: &PeerWriteHandle<BodyOut>, to_out: Arc<F>, to_in: Arc<G>)
+where
+	BodyIn: crate::Body,
+	BodyOut: crate::Body,
+	F: Fn(BodyIn) -> Result<BodyOut, Error> + Send + Sync + 'static,
+	G: Fn(BodyOut) -> Result<BodyIn, Error> + Send + Sync + 'static,
+{
+	match message {
+		ReceivedMessage::Stream(message) => {
+			let result = match to_out(message.body) {
+				Ok(body) => peer_out.send_stream_with_flags(message.header.service_id, message.header.flags, body).await,
+				Err(e) => Err(e),
+			};
+			if let Err(_e) = result {
+				trace_event!(debug, error = %_e, service_id = message.header.service_id, "failed to relay stream message");
+			}
+		},
+		ReceivedMessage::Request(request_in, body) => {
+			let request_out = match to_out(body) {
+				Ok(body) => peer_out.send_request_with_deadline(request_in.service_id(), body, request_in.deadline()).await,
+				Err(e) => Err(e),
+			};
+			let request_out = match request_out {
+				Ok(x) => x,
+				Err(e) => {
+					let _: Result<_, _> = request_in.send_error_response(&e.to_string()).await;
+					return;
+				},
+			};
+			tokio::spawn(async move {
+				let convert_out = |message: Message<BodyIn>| Ok((message.header.service_id, to_out(message.body)?));
+				let convert_in = |message: Message<BodyOut>| Ok((message.header.service_id, to_in(message.body)?));
+				let _: Result<_, _> = forward_request(request_in, request_out, convert_out, convert_in, convert_in).await;
+			});
+		},
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+
+	use crate::transport::StreamTransport;
+	use crate::{Peer, ReceivedMessage, StreamBody};
+	use tokio::net::UnixStream;
+
+	/// Create a pair of connected peers.
+	fn peer_pair() -> (crate::PeerHandle<StreamBody>, crate::PeerHandle<StreamBody>) {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
+		(handle_a, handle_b)
+	}
+
+	/// Convert a message by adding a suffix to the body.
+	fn add_suffix(message: Message<StreamBody>) -> Result<(i32, StreamBody), Error> {
+		let mut body = message.body.as_ref().to_vec();
+		body.extend_from_slice(b"!");
+		Ok((message.header.service_id, body.into()))
+	}
+
+	#[tokio::test]
+	async fn forward() {
+		let (client, mut gateway_in) = peer_pair();
+		let (gateway_out, mut server) = peer_pair();
+
+		let gateway = tokio::spawn(async move {
+			let_assert!(Ok(ReceivedMessage::Request(request_in, body)) = gateway_in.recv_message().await);
+			let_assert!(Ok(request_out) = gateway_out.send_request(request_in.service_id(), body).await);
+			forward_request(request_in, request_out, add_suffix, add_suffix, add_suffix).await
+		});
+
+		let_assert!(Ok(mut request) = client.send_request(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(mut received, body)) = server.recv_message().await);
+		assert!(body.as_ref() == b"hello");
+
+		assert!(let Ok(()) = request.send_update(2, &b"request update"[..]).await);
+		let_assert!(Some(update) = received.recv_update().await);
+		assert!(update.header.service_id == 2);
+		assert!(update.body.as_ref() == b"request update!");
+
+		assert!(let Ok(()) = received.send_update(3, &b"response update"[..]).await);
+		let_assert!(Some(update) = request.recv_update().await);
+		assert!(update.header.service_id == 3);
+		assert!(update.body.as_ref() == b"response update!");
+
+		assert!(let Ok(()) = received.send_response(4, &b"response"[..]).await);
+		let_assert!(Ok(response) = request.recv_response().await);
+		assert!(response.header.service_id == 4);
+		assert!(response.body.as_ref() == b"response!");
+		assert!(let Ok(Ok(())) = gateway.await);
+	}
+
+	#[tokio::test]
+	async fn forward_errors() {
+		let (client, mut gateway_in) = peer_pair();
+		let (gateway_out, mut server) = peer_pair();
+
+		let gateway = tokio::spawn(async move {
+			let mut results = Vec::new();
+			for _ in 0..2 {
+				let_assert!(Ok(ReceivedMessage::Request(request_in, body)) = gateway_in.recv_message().await);
+				let_assert!(Ok(request_out) = gateway_out.send_request(request_in.service_id(), body).await);
+				let reject = |_| Err(Error::custom("rejected".into()));
+				results.push(forward_request(request_in, request_out, reject, add_suffix, add_suffix).await);
+			}
+			results
+		});
+
+		// Error responses are forwarded as they are.
+		let_assert!(Ok(mut request) = client.send_request(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received, _body)) = server.recv_message().await);
+		assert!(let Ok(()) = received.send_error_response("oh no").await);
+		let_assert!(Err(e) = request.recv_response().await.and_then(|x| x.check_error_response()));
+		assert!(e.as_remote_error() == Some("oh no"));
+
+		// Failed conversions are reported to the requester.
+		let_assert!(Ok(mut request) = client.send_request(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(_received, _body)) = server.recv_message().await);
+		assert!(let Ok(()) = request.send_update(2, &b"update"[..]).await);
+		let_assert!(Err(e) = request.recv_response().await.and_then(|x| x.check_error_response()));
+		assert!(e.as_remote_error() == Some("rejected"));
+
+		let_assert!(Ok(results) = gateway.await);
+		assert!(let Ok(()) = &results[0]);
+		let_assert!(Err(e) = &results[1]);
+		assert!(e.to_string() == "rejected");
+	}
+
+	#[tokio::test]
+	async fn relay_stream_and_unix_peers() {
+		use crate::transport::FdPolicy;
+		use crate::{UnixBody, UnixSeqpacketPeer, UnixSeqpacketTransport};
+
+		let (mut client, gateway_in) = peer_pair();
+		let_assert!(Ok((socket_a, socket_b)) = tokio_seqpacket::UnixSeqpacket::pair());
+		let gateway_out = UnixSeqpacketPeer::spawn(UnixSeqpacketTransport::new(socket_a, Default::default()));
+		let mut server = UnixSeqpacketPeer::spawn(UnixSeqpacketTransport::new(socket_b, Default::default()));
+
+		let to_unix = |body: StreamBody| Ok(UnixBody::from(body));
+		let to_stream = |body: UnixBody| body.into_stream_body(FdPolicy::Reject);
+		let gateway = tokio::spawn(relay_peers(gateway_in, gateway_out, to_unix, to_stream));
+
+		// Requests are forwarded with their response.
+		let_assert!(Ok(mut request) = client.send_request(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received, body)) = server.recv_message().await);
+		assert!(received.service_id() == 1);
+		assert!(body.data == b"hello");
+		assert!(let Ok(()) = received.send_response(2, &b"world"[..]).await);
+		let_assert!(Ok(response) = request.recv_response().await);
+		assert!(response.header.service_id == 2);
+		assert!(response.body.as_ref() == b"world");
+
+		// Stream messages with file descriptors are dropped, other stream messages are relayed in both directions.
+		let_assert!(Ok(file) = std::fs::File::open("/dev/null"));
+		let fd = filedesc::FileDesc::new(file.into());
+		assert!(let Ok(()) = server.send_stream(3, UnixBody::new(&b"with fd"[..], vec![fd])).await);
+		assert!(let Ok(()) = server.send_stream(4, &b"without fd"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = client.recv_message().await);
+		assert!(message.header.service_id == 4);
+		assert!(message.body.as_ref() == b"without fd");
+		assert!(let Ok(()) = client.send_stream(5, &b"to server"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = server.recv_message().await);
+		assert!(message.header.service_id == 5);
+		assert!(message.body.data == b"to server");
+
+		// A response with file descriptors is turned into an error response.
+		let_assert!(Ok(mut request) = client.send_request(6, &b"send me a file"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received, _body)) = server.recv_message().await);
+		let_assert!(Ok(file) = std::fs::File::open("/dev/null"));
+		let fd = filedesc::FileDesc::new(file.into());
+		assert!(let Ok(()) = received.send_response(6, UnixBody::new(&b"file"[..], vec![fd])).await);
+		let_assert!(Err(e) = request.recv_response().await.and_then(|x| x.check_error_response()));
+		let_assert!(Some(message) = e.as_remote_error());
+		assert!(message.contains("file descriptors"));
+
+		// The relay stops when one of the peers disconnects.
+		drop(client);
+		assert!(let Ok(Ok(())) = gateway.await);
+	}
+}
@@ -0,0 +1,149 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::{Error, PeerHandle, PeerWriteHandle, SentRequestHandle};
+
+/// Future returned by the functions of [`GenericWriteHandle`].
+pub type WriteFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, Error>> + Send + 'a>>;
+
+/// Trait for handles that can send messages to a remote peer.
+///
+/// The trait is implemented for [`PeerWriteHandle`] and [`PeerHandle`] with any body type.
+/// Library code can use it to be generic over the body type of a peer with a single type parameter,
+/// instead of carrying a `Body` parameter around or committing to [`StreamBody`][crate::StreamBody].
+///
+/// The trait is object safe, so it can also be used as `dyn GenericWriteHandle<Body = B>`.
+/// It is implemented for references, [`Box`] and [`Arc`] of other implementations, including trait objects.
+pub trait GenericWriteHandle: Send + Sync {
+	/// The body type of the messages.
+	type Body: crate::Body;
+
+	/// Send a new request to the remote peer.
+	///
+	/// See [`PeerWriteHandle::send_request()`].
+	fn send_request(&self, service_id: i32, body: Self::Body) -> WriteFuture<'_, SentRequestHandle<Self::Body>>;
+
+	/// Send a stream message to the remote peer.
+	///
+	/// See [`PeerWriteHandle::send_stream()`].
+	fn send_stream(&self, service_id: i32, body: Self::Body) -> WriteFuture<'_, ()>;
+
+	/// Get the process-wide unique ID of the connection.
+	fn connection_id(&self) -> u64;
+
+	/// Close the connection with the remote peer.
+	fn close(&self);
+}
+
+impl<Body: crate::Body> GenericWriteHandle for PeerWriteHandle<Body> {
+	type Body = Body;
+
+	fn send_request(&self, service_id: i32, body: Body) -> WriteFuture<'_, SentRequestHandle<Body>> {
+		Box::pin(PeerWriteHandle::send_request(self, service_id, body))
+	}
+
+	fn send_stream(&self, service_id: i32, body: Body) -> WriteFuture<'_, ()> {
+		Box::pin(PeerWriteHandle::send_stream(self, service_id, body))
+	}
+
+	fn connection_id(&self) -> u64 {
+		PeerWriteHandle::connection_id(self)
+	}
+
+	fn close(&self) {
+		PeerWriteHandle::close(self)
+	}
+}
+
+impl<Body: crate::Body> GenericWriteHandle for PeerHandle<Body> {
+	type Body = Body;
+
+	fn send_request(&self, service_id: i32, body: Body) -> WriteFuture<'_, SentRequestHandle<Body>> {
+		Box::pin(PeerHandle::send_request(self, service_id, body))
+	}
+
+	fn send_stream(&self, service_id: i32, body: Body) -> WriteFuture<'_, ()> {
+		Box::pin(PeerHandle::send_stream(self, service_id, body))
+	}
+
+	fn connection_id(&self) -> u64 {
+		PeerHandle::connection_id(self)
+	}
+
+	fn close(&self) {
+		self.close_handle().close()
+	}
+}
+
+macro_rules! impl_generic_write_handle_for_pointer {
+	($($pointer:ty),*) => {
+		$(
+			impl<T: GenericWriteHandle + ?Sized> GenericWriteHandle for $pointer {
+				type Body = T::Body;
+
+				fn send_request(&self, service_id: i32, body: Self::Body) -> WriteFuture<'_, SentRequestHandle<Self::Body>> {
+					T::send_request(self, service_id, body)
+				}
+
+				fn send_stream(&self, service_id: i32, body: Self::Body) -> WriteFuture<'_, ()> {
+					T::send_stream(self, service_id, body)
+				}
+
+				fn connection_id(&self) -> u64 {
+					T::connection_id(self)
+				}
+
+				fn close(&self) {
+					T::close(self)
+				}
+			}
+		)*
+	};
+}
+
+impl_generic_write_handle_for_pointer!(&T, Box<T>, Arc<T>);
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+
+	use crate::{ReceivedMessage, StreamBody, UnixStreamPeer, UnixStreamTransport};
+	use tokio::net::UnixStream;
+
+	/// Library code that is generic over the write handle.
+	async fn send_hello<W: GenericWriteHandle>(handle: W, body: W::Body) -> Result<(), Error> {
+		handle.send_stream(1, body).await
+	}
+
+	#[tokio::test]
+	async fn generic_over_write_handles() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let peer_a = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, Default::default()));
+		let mut peer_b = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default()));
+
+		assert!(let Ok(()) = send_hello(&peer_a, StreamBody::from(&b"peer handle"[..])).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = peer_b.recv_message().await);
+		assert!(message.body.as_ref() == b"peer handle");
+
+		let (_read_a, write_a) = peer_a.split();
+		let write_a: Arc<dyn GenericWriteHandle<Body = StreamBody>> = Arc::new(write_a);
+		assert!(let Ok(()) = send_hello(write_a.clone(), StreamBody::from(&b"trait object"[..])).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = peer_b.recv_message().await);
+		assert!(message.body.as_ref() == b"trait object");
+
+		let_assert!(Ok(mut request) = write_a.send_request(2, StreamBody::from(&b"ping"[..])).await);
+		let_assert!(Ok(ReceivedMessage::Request(received, body)) = peer_b.recv_message().await);
+		assert!(received.service_id() == 2);
+		assert!(body.as_ref() == b"ping");
+		assert!(let Ok(()) = received.send_response(2, &b"pong"[..]).await);
+		let_assert!(Ok(response) = request.recv_response().await);
+		assert!(response.body.as_ref() == b"pong");
+
+		write_a.close();
+		let_assert!(Err(e) = peer_b.recv_message().await);
+		assert!(e.is_connection_aborted());
+	}
+}
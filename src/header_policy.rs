@@ -0,0 +1,162 @@
+use crate::error::private::InnerError;
+use crate::{header_flags, Error, MessageHeader};
+
+/// Validation for the headers of messages received from a remote peer.
+///
+/// A message header can be well-formed, but still contain values that make no sense,
+/// like reserved fields that are set or combinations of fields that are not allowed by the protocol.
+/// By default, such messages are processed like any other message.
+/// When the remote peer is not trusted, you can use a stricter policy,
+/// so that a misbehaving implementation can not bring the request tracker into a strange state.
+///
+/// The peer checks all received messages against the policy before it processes them.
+/// Messages that violate the policy are reported to the read handle as an error with [`ErrorKind::InvalidHeader`][crate::ErrorKind::InvalidHeader].
+/// Requests that violate the policy are also answered with an error response, so the remote peer does not wait for them forever.
+///
+/// Use [`Peer::with_header_policy()`][crate::Peer::with_header_policy] to set the policy of a peer.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct HeaderPolicy {
+	/// Reject messages with flags in the [`header_flags::RESERVED`] range.
+	///
+	/// The reserved flags are meant for protocol extensions of this library, but none of them are in use yet.
+	/// Peers that set them are either misbehaving, or use a newer version of the protocol.
+	pub reject_reserved_flags: bool,
+
+	/// Reject stream messages for application services with a non-zero request ID.
+	///
+	/// Stream messages are not part of a request, so they should have request ID 0.
+	/// Stream messages used by the protocol itself, with a negative service ID, may refer to a request.
+	pub reject_stream_request_ids: bool,
+}
+
+impl HeaderPolicy {
+	/// Create a policy that accepts all well-formed headers.
+	pub fn none() -> Self {
+		Self::default()
+	}
+
+	/// Create a strict policy for untrusted peers that enables all checks.
+	pub fn strict() -> Self {
+		Self {
+			reject_reserved_flags: true,
+			reject_stream_request_ids: true,
+		}
+	}
+
+	/// Check if the policy accepts all well-formed headers.
+	pub fn is_none(&self) -> bool {
+		self == &Self::none()
+	}
+
+	/// Check a message header against the policy.
+	pub fn check(&self, header: &MessageHeader) -> Result<(), Error> {
+		if self.reject_reserved_flags && header.flags & header_flags::RESERVED != 0 {
+			return Err(InnerError::InvalidHeader {
+				reason: "reserved header flags are set",
+			}.into());
+		}
+		if self.reject_stream_request_ids && header.message_type.is_stream() && header.service_id >= 0 && header.request_id != 0 {
+			return Err(InnerError::InvalidHeader {
+				reason: "stream message has a non-zero request ID",
+			}.into());
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::{assert, let_assert};
+
+	use crate::transport::frame::FrameDecoder;
+	use crate::transport::Endian;
+	use crate::{service_id, ErrorKind, MessageType};
+
+	/// Simple xorshift generator, so the fuzz tests are reproducible.
+	struct XorShift(u64);
+
+	impl XorShift {
+		fn next(&mut self) -> u64 {
+			self.0 ^= self.0 << 13;
+			self.0 ^= self.0 >> 7;
+			self.0 ^= self.0 << 17;
+			self.0
+		}
+
+		fn fill(&mut self, buffer: &mut [u8]) {
+			for chunk in buffer.chunks_mut(8) {
+				let value = self.next().to_le_bytes();
+				chunk.copy_from_slice(&value[..chunk.len()]);
+			}
+		}
+	}
+
+	#[test]
+	fn default_policy_accepts_everything() {
+		let policy = HeaderPolicy::default();
+		assert!(policy.is_none());
+		assert!(let Ok(()) = policy.check(&MessageHeader::stream(3, 1).with_flags(header_flags::RESERVED)));
+	}
+
+	#[test]
+	fn strict_policy() {
+		let policy = HeaderPolicy::strict();
+		assert!(let Ok(()) = policy.check(&MessageHeader::request(3, 1)));
+		assert!(let Ok(()) = policy.check(&MessageHeader::stream(0, 1).with_flags(header_flags::APPLICATION)));
+		assert!(let Ok(()) = policy.check(&MessageHeader::stream(3, service_id::DEADLINE)));
+		assert!(let Ok(()) = policy.check(&MessageHeader::acked_stream(3, 1)));
+
+		let_assert!(Err(e) = policy.check(&MessageHeader::request(3, 1).with_flags(0x0001)));
+		assert!(e.kind() == ErrorKind::InvalidHeader);
+		let_assert!(Err(e) = policy.check(&MessageHeader::stream(3, 1)));
+		assert!(e.kind() == ErrorKind::InvalidHeader);
+	}
+
+	#[test]
+	fn fuzz_header_parsing() {
+		let policy = HeaderPolicy::strict();
+		let mut rng = XorShift(0x9E37_79B9_7F4A_7C15);
+		for _ in 0..100_000 {
+			let mut buffer = [0u8; crate::HEADER_LEN as usize];
+			rng.fill(&mut buffer);
+			// Make valid message types likely enough to exercise the policy.
+			buffer[0] %= 8;
+			buffer[1] = 0;
+			let request_id = if rng.next() % 2 == 0 { 0 } else { buffer[4] };
+			buffer[4..8].fill(request_id);
+
+			// Decoded headers must encode back to the same bytes.
+			let Ok(header) = MessageHeader::decode(&buffer, Endian::LittleEndian) else {
+				continue;
+			};
+			let mut encoded = [0u8; crate::HEADER_LEN as usize];
+			header.encode(&mut encoded, Endian::LittleEndian);
+			assert!(encoded == buffer);
+
+			if policy.check(&header).is_ok() {
+				assert!(header.flags & header_flags::RESERVED == 0);
+				assert!(!(header.message_type == MessageType::Stream && header.service_id >= 0 && header.request_id != 0));
+			}
+		}
+	}
+
+	#[test]
+	fn fuzz_frame_decoder() {
+		let mut rng = XorShift(0x2545_F491_4F6C_DD1D);
+		for _ in 0..1_000 {
+			// Feed random data in random chunks, the decoder must never panic.
+			let mut decoder = FrameDecoder::new(Endian::LittleEndian, 64);
+			let mut data = vec![0u8; (rng.next() % 256) as usize];
+			rng.fill(&mut data);
+			let mut data = &data[..];
+			while !data.is_empty() {
+				let len = (rng.next() as usize % data.len()) + 1;
+				decoder.push(&data[..len]);
+				data = &data[len..];
+				while let Ok(Some(_message)) = decoder.next_message() {}
+			}
+		}
+	}
+}
@@ -0,0 +1,110 @@
+//! Heartbeat messages for liveness tracking.
+//!
+//! Interfaces generated with the [`interface!`][crate::interface] macro can enable heartbeats with the `#[heartbeat(interval = "5s")]` attribute.
+//! The generated `Client` then periodically sends a heartbeat message to the remote peer, as long as the client or one of its clones exists.
+//! The generated `Server` consumes these messages and records when the last one was received,
+//! which can be retrieved with `Server::last_heartbeat()`.
+//!
+//! A heartbeat is an empty stream message with service ID [`service_id::HEARTBEAT`] and request ID 0.
+//!
+//! Note that the server only processes heartbeat messages while the application is receiving messages from it.
+//! If the application stops calling `Server::recv_message()`, the last heartbeat time is not updated anymore.
+
+use std::time::Duration;
+
+use crate::{service_id, PeerWriteHandle};
+
+/// Handle to a task that periodically sends heartbeat messages to a remote peer.
+///
+/// The task is stopped when the handle is dropped, or when a heartbeat could not be sent.
+pub struct HeartbeatSender {
+	task: tokio::task::JoinHandle<()>,
+	interval: Duration,
+}
+
+impl HeartbeatSender {
+	/// Spawn a task that sends a heartbeat message to the remote peer every `interval`.
+	///
+	/// The first heartbeat is sent right away.
+	///
+	/// # Panics
+	/// This function panics if it is not called from within a tokio runtime.
+	pub fn spawn<Body: crate::Body>(peer: PeerWriteHandle<Body>, interval: Duration) -> Self {
+		let task = tokio::spawn(async move {
+			let mut ticker = tokio::time::interval(interval);
+			ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+			loop {
+				ticker.tick().await;
+				if peer.send_stream(service_id::HEARTBEAT, Body::empty()).await.is_err() {
+					break;
+				}
+			}
+		});
+		Self { task, interval }
+	}
+
+	/// Get the interval between heartbeat messages.
+	pub fn interval(&self) -> Duration {
+		self.interval
+	}
+
+	/// Check if the task is still sending heartbeat messages.
+	///
+	/// This returns false if a heartbeat could not be sent, for example because the connection was closed.
+	pub fn is_running(&self) -> bool {
+		!self.task.is_finished()
+	}
+}
+
+impl Drop for HeartbeatSender {
+	fn drop(&mut self) {
+		self.task.abort();
+	}
+}
+
+impl std::fmt::Debug for HeartbeatSender {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("HeartbeatSender")
+			.field("interval", &self.interval)
+			.field("running", &self.is_running())
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::{assert, let_assert};
+
+	use crate::{ReceivedMessage, UnixStreamPeer, UnixStreamTransport};
+
+	#[tokio::test]
+	async fn send_heartbeats() {
+		let_assert!(Ok((peer_a, peer_b)) = tokio::net::UnixStream::pair());
+		let (read_a, write_a) = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, Default::default())).split();
+		let mut peer_b = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default()));
+		drop(read_a);
+
+		let sender = HeartbeatSender::spawn(write_a, Duration::from_millis(10));
+		assert!(sender.interval() == Duration::from_millis(10));
+		for _ in 0..3 {
+			let_assert!(Ok(ReceivedMessage::Stream(message)) = peer_b.recv_message().await);
+			assert!(message.header.service_id == service_id::HEARTBEAT);
+			assert!(message.header.request_id == 0);
+		}
+		assert!(sender.is_running());
+
+		// Dropping the sender stops the task, which closes the connection.
+		drop(sender);
+		loop {
+			match peer_b.recv_message().await {
+				Ok(ReceivedMessage::Stream(message)) => assert!(message.header.service_id == service_id::HEARTBEAT),
+				Ok(ReceivedMessage::Request(..)) => panic!("unexpected request"),
+				Err(e) => {
+					assert!(e.is_connection_aborted());
+					break;
+				},
+			}
+		}
+	}
+}
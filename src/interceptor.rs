@@ -0,0 +1,57 @@
+use crate::{Annotations, Error, Message};
+
+/// Hook to observe and rewrite all messages of a peer.
+///
+/// The interceptor sees every outgoing message before it is written to the transport,
+/// and every incoming message before it is processed by the request tracker.
+/// It can inspect and modify the message, or reject it to drop the message.
+/// This can be used for cross-cutting concerns, like attaching trace IDs or enforcing access control.
+///
+/// Rejected outgoing messages are not written, and the error is returned to the caller that tried to send the message.
+/// Rejected incoming requests are answered with an error response containing the error message.
+/// Other rejected incoming messages are silently discarded.
+///
+/// Outgoing messages are intercepted before they are checked by the [`EgressPolicy`][crate::EgressPolicy].
+/// Error responses that the peer generates by itself, such as the rejection of incoming requests after the read handle was dropped,
+/// are not intercepted.
+/// Acknowledgements of acknowledged stream messages are also handled by the peer itself, and never reach the interceptor.
+/// The acknowledged stream messages themselves are intercepted as regular stream messages after they have been acknowledged.
+///
+/// Changing the request ID or message type of a message can confuse the request tracking of the peer or the remote peer.
+/// Similarly, dropping an update or response leaves the request waiting for the message forever.
+/// An interceptor should normally only rewrite the body, or drop new requests and stream messages.
+///
+/// Information extracted from incoming requests can be passed to the handler of the request as [`Annotations`],
+/// see [`Self::annotate()`].
+///
+/// The interceptor runs inside the peer loop, so it should not block.
+/// Use [`Peer::with_interceptor()`][crate::Peer::with_interceptor] to install an interceptor.
+pub trait Interceptor<Body>: Send + 'static {
+	/// Intercept an outgoing message before it is written to the transport.
+	///
+	/// Return an error to drop the message.
+	/// The default implementation accepts all messages unmodified.
+	fn outgoing(&mut self, message: &mut Message<Body>) -> Result<(), Error> {
+		let _ = message;
+		Ok(())
+	}
+
+	/// Intercept an incoming message before it is processed by the peer.
+	///
+	/// Return an error to drop the message.
+	/// The default implementation accepts all messages unmodified.
+	fn incoming(&mut self, message: &mut Message<Body>) -> Result<(), Error> {
+		let _ = message;
+		Ok(())
+	}
+
+	/// Add annotations to an incoming request.
+	///
+	/// This is called for every incoming request after it was accepted by [`Self::incoming()`].
+	/// The annotations are available to the handler of the request through
+	/// [`ReceivedRequestHandle::annotations()`][crate::ReceivedRequestHandle::annotations].
+	/// The default implementation adds no annotations.
+	fn annotate(&mut self, message: &Message<Body>, annotations: &mut Annotations) {
+		let _ = (message, annotations);
+	}
+}
@@ -3,9 +3,18 @@
 //! These types and traits are used by generated interfaces from the [`interface!`] macro.
 //! Normally, you would only implement the traits for your own serialization format.
 //! However, the traits are covered by semver guarantees, so feel free to use them in your own code.
+//!
+//! The type information is chosen by the format through [`IntrospectableFormat::TypeInfo`].
+//! A simple format could use the name of the Rust type,
+//! but that is not enough to generate bindings for other languages.
+//! With the `schemars` feature, a format can use [`TypeSchema`] to provide a full JSON Schema for each message body.
+//!
+//! With the `serde` feature, the definitions can be serialized,
+//! for example to load them in a [`DynamicInterface`][crate::dynamic::DynamicInterface] at runtime.
 
 /// Metadata about an RPC interface for runtime introspection.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct InterfaceDefinition<TypeInfo> {
 	/// The name of the interface.
 	pub name: String,
@@ -27,6 +36,7 @@ pub struct InterfaceDefinition<TypeInfo> {
 
 /// Metadata about a service for runtime intropection.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ServiceDefinition<TypeInfo> {
 	/// The name of the service.
 	pub name: String,
@@ -48,6 +58,9 @@ pub struct ServiceDefinition<TypeInfo> {
 	/// Information about the response body.
 	pub response_body: TypeInfo,
 
+	/// Information about the structured service error body, if the service declares an error type.
+	pub error_body: Option<TypeInfo>,
+
 	/// Information about the request updates.
 	pub request_updates: Vec<UpdateDefinition<TypeInfo>>,
 
@@ -57,6 +70,7 @@ pub struct ServiceDefinition<TypeInfo> {
 
 /// Metadata about a service update for runtime intropection.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct UpdateDefinition<TypeInfo> {
 	/// The name of the update message.
 	pub name: String,
@@ -78,6 +92,7 @@ pub struct UpdateDefinition<TypeInfo> {
 
 /// Metadata about a stream message for runtime intropection.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StreamDefinition<TypeInfo> {
 	/// The name of the stream message.
 	pub name: String,
@@ -90,6 +105,12 @@ pub struct StreamDefinition<TypeInfo> {
 	/// If true, the item should be hidden from documentation by default.
 	pub hidden: bool,
 
+	/// If true, stream messages for this stream are delivered to the application in the order they were sent.
+	///
+	/// This is false for streams marked with `#[unordered]` in the interface definition.
+	/// Messages of such streams may be decoded in parallel and delivered out of order by generated servers.
+	pub ordered: bool,
+
 	/// The service ID of the stream message.
 	pub service_id: i32,
 
@@ -108,3 +129,151 @@ pub trait FormatTypeInfo<T: ?Sized>: IntrospectableFormat {
 	/// Get type information about a type.
 	fn type_info() -> Self::TypeInfo;
 }
+
+/// Type information consisting of the Rust type name and a JSON Schema of the type.
+///
+/// Formats that serialize messages with `serde` can use this as [`IntrospectableFormat::TypeInfo`].
+/// Use [`type_schema()`] to implement [`FormatTypeInfo`] for all types that implement [`schemars::JsonSchema`].
+#[cfg(feature = "schemars")]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TypeSchema {
+	/// The name of the Rust type, as reported by [`std::any::type_name()`].
+	pub type_name: String,
+
+	/// The JSON Schema of the type.
+	pub schema: schemars::schema::RootSchema,
+}
+
+/// Get the type name and JSON Schema of a type.
+///
+/// This can be used to implement [`FormatTypeInfo`] for formats with [`TypeSchema`] as type information:
+///
+/// ```
+/// # use fizyr_rpc::introspection::{type_schema, FormatTypeInfo, IntrospectableFormat, TypeSchema};
+/// # struct Json;
+/// # impl fizyr_rpc::format::Format for Json {
+/// #     type Body = fizyr_rpc::StreamBody;
+/// # }
+/// impl IntrospectableFormat for Json {
+///     type TypeInfo = TypeSchema;
+/// }
+///
+/// impl<T: schemars::JsonSchema + ?Sized> FormatTypeInfo<T> for Json {
+///     fn type_info() -> TypeSchema {
+///         type_schema::<T>()
+///     }
+/// }
+/// ```
+#[cfg(feature = "schemars")]
+pub fn type_schema<T: schemars::JsonSchema + ?Sized>() -> TypeSchema {
+	TypeSchema {
+		type_name: std::any::type_name::<T>().to_owned(),
+		schema: schemars::gen::SchemaGenerator::default().into_root_schema_for::<T>(),
+	}
+}
+
+impl<TypeInfo> InterfaceDefinition<TypeInfo> {
+	/// Get the type information of all message bodies in the interface.
+	///
+	/// Each body is identified by a path of the form:
+	/// * `service.{name}.request` and `service.{name}.response` for service requests and responses,
+	/// * `service.{name}.error` for the structured error of a service, if it declares one,
+	/// * `service.{name}.request_update.{update}` and `service.{name}.response_update.{update}` for updates, and
+	/// * `stream.{name}` for stream messages.
+	///
+	/// This is useful to generate bindings for other languages, for example from a [`TypeSchema`] for each body.
+	pub fn bodies(&self) -> Vec<(String, &TypeInfo)> {
+		let mut bodies = Vec::new();
+		for service in &self.services {
+			bodies.push((format!("service.{}.request", service.name), &service.request_body));
+			bodies.push((format!("service.{}.response", service.name), &service.response_body));
+			if let Some(error_body) = &service.error_body {
+				bodies.push((format!("service.{}.error", service.name), error_body));
+			}
+			for update in &service.request_updates {
+				bodies.push((format!("service.{}.request_update.{}", service.name, update.name), &update.body));
+			}
+			for update in &service.response_updates {
+				bodies.push((format!("service.{}.response_update.{}", service.name, update.name), &update.body));
+			}
+		}
+		for stream in &self.streams {
+			bodies.push((format!("stream.{}", stream.name), &stream.body));
+		}
+		bodies
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	#[cfg(feature = "schemars")]
+	use assert2::let_assert;
+
+	fn service(name: &str, request_body: &'static str, response_body: &'static str) -> ServiceDefinition<&'static str> {
+		ServiceDefinition {
+			name: name.into(),
+			doc: String::new(),
+			hidden: false,
+			service_id: 1,
+			request_body,
+			response_body,
+			error_body: None,
+			request_updates: Vec::new(),
+			response_updates: Vec::new(),
+		}
+	}
+
+	#[test]
+	fn bodies() {
+		let mut record = service("record", "RecordRequest", "()");
+		record.error_body = Some("RecordError");
+		record.response_updates.push(UpdateDefinition {
+			name: "image".into(),
+			doc: String::new(),
+			hidden: false,
+			service_id: 10,
+			body: "Image",
+		});
+		let interface = InterfaceDefinition {
+			name: "Camera".into(),
+			doc: String::new(),
+			hidden: false,
+			services: vec![service("ping", "()", "()"), record],
+			streams: vec![StreamDefinition {
+				name: "state".into(),
+				doc: String::new(),
+				hidden: false,
+				ordered: true,
+				service_id: 3,
+				body: "State",
+			}],
+		};
+
+		let bodies: Vec<_> = interface.bodies().into_iter().map(|(path, body)| (path, *body)).collect();
+		assert!(bodies == [
+			(String::from("service.ping.request"), "()"),
+			(String::from("service.ping.response"), "()"),
+			(String::from("service.record.request"), "RecordRequest"),
+			(String::from("service.record.response"), "()"),
+			(String::from("service.record.error"), "RecordError"),
+			(String::from("service.record.response_update.image"), "Image"),
+			(String::from("stream.state"), "State"),
+		]);
+	}
+
+	#[test]
+	#[cfg(feature = "schemars")]
+	fn type_schema() {
+		let info = super::type_schema::<Vec<u32>>();
+		assert!(info.type_name == "alloc::vec::Vec<u32>");
+		let schema = info.schema.schema;
+		assert!(schema.instance_type == Some(schemars::schema::InstanceType::Array.into()));
+		let_assert!(Some(array) = schema.array);
+		let_assert!(Some(schemars::schema::SingleOrVec::Single(items)) = array.items);
+		let_assert!(schemars::schema::Schema::Object(items) = *items);
+		assert!(items.instance_type == Some(schemars::schema::InstanceType::Integer.into()));
+	}
+}
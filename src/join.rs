@@ -0,0 +1,270 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Poll;
+
+use crate::Error;
+
+/// Policy that decides when [`join_requests()`] is done.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum JoinPolicy {
+	/// Wait for all requests to complete.
+	///
+	/// The policy is satisfied if all requests succeeded.
+	/// Failed requests do not stop the other requests, so the details of all failures are available.
+	All,
+
+	/// Wait for the first request to succeed.
+	///
+	/// The remaining requests are cancelled as soon as one request succeeded.
+	/// The policy is not satisfied if all requests failed.
+	FirstSuccess,
+
+	/// Wait until the given number of requests succeeded.
+	///
+	/// The remaining requests are cancelled as soon as enough requests succeeded,
+	/// or when so many requests failed that the quorum can no longer be reached.
+	Quorum(usize),
+}
+
+/// The outcome of a single request in [`JoinResults`].
+#[derive(Debug)]
+pub enum RequestOutcome<T> {
+	/// The request succeeded.
+	Success(T),
+
+	/// The request failed.
+	Failed(Error),
+
+	/// The request was cancelled because the outcome of the join was already decided.
+	Cancelled,
+}
+
+/// The results of [`join_requests()`].
+///
+/// The results are in the same order as the requests given to [`join_requests()`].
+#[derive(Debug)]
+pub struct JoinResults<T> {
+	/// The outcome of each request.
+	results: Vec<RequestOutcome<T>>,
+
+	/// If true, the join policy was satisfied.
+	satisfied: bool,
+}
+
+/// Run multiple requests concurrently and wait for them according to a [`JoinPolicy`].
+///
+/// Each request is a future that resolves to a `Result<T, Error>`,
+/// such as the futures returned by the functions of a generated interface client.
+/// The requests can be made through different clients or peers.
+/// To join requests with different response types, map them to a common type first, for example an enum.
+///
+/// The requests are polled concurrently in the current task.
+/// Requests that are still running when the outcome of the policy is decided are dropped,
+/// and they are reported as [`RequestOutcome::Cancelled`].
+pub async fn join_requests<I, F, T>(policy: JoinPolicy, requests: I) -> JoinResults<T>
+where
+	I: IntoIterator<Item = F>,
+	F: Future<Output = Result<T, Error>>,
+{
+	let mut pending: Vec<Option<Pin<Box<F>>>> = requests.into_iter().map(|x| Some(Box::pin(x))).collect();
+	let mut results: Vec<RequestOutcome<T>> = pending.iter().map(|_| RequestOutcome::Cancelled).collect();
+	let total = pending.len();
+	let mut successes = 0;
+	let mut failures = 0;
+
+	std::future::poll_fn(|context| {
+		for (i, slot) in pending.iter_mut().enumerate() {
+			if policy.is_decided(total, successes, failures) {
+				return Poll::Ready(());
+			}
+
+			let request = match slot {
+				Some(x) => x,
+				None => continue,
+			};
+
+			if let Poll::Ready(result) = request.as_mut().poll(context) {
+				*slot = None;
+				results[i] = match result {
+					Ok(value) => {
+						successes += 1;
+						RequestOutcome::Success(value)
+					},
+					Err(e) => {
+						failures += 1;
+						RequestOutcome::Failed(e)
+					},
+				};
+			}
+		}
+
+		if policy.is_decided(total, successes, failures) {
+			Poll::Ready(())
+		} else {
+			Poll::Pending
+		}
+	}).await;
+
+	JoinResults {
+		results,
+		satisfied: policy.is_satisfied(total, successes),
+	}
+}
+
+impl JoinPolicy {
+	/// Check if the outcome of the policy is decided.
+	fn is_decided(self, total: usize, successes: usize, failures: usize) -> bool {
+		match self {
+			Self::All => successes + failures == total,
+			Self::FirstSuccess => successes >= 1 || failures == total,
+			Self::Quorum(quorum) => successes >= quorum || total - failures < quorum,
+		}
+	}
+
+	/// Check if the policy is satisfied.
+	fn is_satisfied(self, total: usize, successes: usize) -> bool {
+		match self {
+			Self::All => successes == total,
+			Self::FirstSuccess => successes >= 1,
+			Self::Quorum(quorum) => successes >= quorum,
+		}
+	}
+}
+
+impl<T> RequestOutcome<T> {
+	/// Check if the request succeeded.
+	pub fn is_success(&self) -> bool {
+		matches!(self, Self::Success(_))
+	}
+
+	/// Check if the request failed.
+	pub fn is_failed(&self) -> bool {
+		matches!(self, Self::Failed(_))
+	}
+
+	/// Check if the request was cancelled.
+	pub fn is_cancelled(&self) -> bool {
+		matches!(self, Self::Cancelled)
+	}
+}
+
+impl<T> JoinResults<T> {
+	/// Check if the join policy was satisfied.
+	pub fn is_satisfied(&self) -> bool {
+		self.satisfied
+	}
+
+	/// Get the outcome of all requests, in the original order.
+	pub fn results(&self) -> &[RequestOutcome<T>] {
+		&self.results
+	}
+
+	/// Consume `self` to get the outcome of all requests, in the original order.
+	pub fn into_results(self) -> Vec<RequestOutcome<T>> {
+		self.results
+	}
+
+	/// Iterate over the successful requests, with their index in the original order.
+	pub fn successes(&self) -> impl Iterator<Item = (usize, &T)> {
+		self.results.iter().enumerate().filter_map(|(i, x)| match x {
+			RequestOutcome::Success(value) => Some((i, value)),
+			_ => None,
+		})
+	}
+
+	/// Iterate over the failed requests, with their index in the original order.
+	pub fn failures(&self) -> impl Iterator<Item = (usize, &Error)> {
+		self.results.iter().enumerate().filter_map(|(i, x)| match x {
+			RequestOutcome::Failed(error) => Some((i, error)),
+			_ => None,
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use tokio::sync::oneshot;
+
+	type Sender = oneshot::Sender<Result<u32, Error>>;
+
+	/// Make `count` requests that complete when a value is sent over the returned channels.
+	fn make_requests(count: usize) -> (Vec<Sender>, Vec<impl Future<Output = Result<u32, Error>>>) {
+		(0..count)
+			.map(|_| {
+				let (tx, rx) = oneshot::channel();
+				(tx, async move { rx.await.unwrap_or_else(|_| Err(Error::custom("dropped".into()))) })
+			})
+			.unzip()
+	}
+
+	#[tokio::test]
+	async fn all_collects_partial_failures() {
+		let (senders, requests) = make_requests(3);
+		let join = tokio::spawn(join_requests(JoinPolicy::All, requests));
+		let mut senders = senders.into_iter();
+		let _ = senders.next().unwrap().send(Err(Error::custom("camera offline".into())));
+		let _ = senders.next().unwrap().send(Ok(1));
+		let _ = senders.next().unwrap().send(Ok(2));
+
+		let results = join.await.unwrap();
+		assert!(!results.is_satisfied());
+		assert!(results.successes().collect::<Vec<_>>() == [(1, &1), (2, &2)]);
+		let failures: Vec<_> = results.failures().map(|(i, e)| (i, e.to_string())).collect();
+		assert!(failures == [(0, String::from("camera offline"))]);
+	}
+
+	#[tokio::test]
+	async fn first_success_cancels_others() {
+		let (senders, requests) = make_requests(3);
+		let mut senders = senders.into_iter();
+		let first = senders.next().unwrap();
+		let _ = senders.next().unwrap().send(Err(Error::custom("busy".into())));
+		let _ = senders.next().unwrap().send(Ok(7));
+
+		let results = join_requests(JoinPolicy::FirstSuccess, requests).await;
+		assert!(results.is_satisfied());
+		assert!(results.results()[0].is_cancelled());
+		assert!(results.results()[1].is_failed());
+		assert!(results.results()[2].is_success());
+
+		// The cancelled request was dropped.
+		assert!(first.is_closed());
+	}
+
+	#[tokio::test]
+	async fn quorum() {
+		let (senders, requests) = make_requests(4);
+		let join = tokio::spawn(join_requests(JoinPolicy::Quorum(2), requests));
+		let mut senders = senders.into_iter();
+		let _ = senders.next().unwrap().send(Ok(1));
+		let _ = senders.next().unwrap().send(Ok(2));
+		let results = join.await.unwrap();
+		assert!(results.is_satisfied());
+		assert!(results.successes().count() == 2);
+		assert!(results.results()[2].is_cancelled());
+		assert!(results.results()[3].is_cancelled());
+
+		// Stop as soon as the quorum can no longer be reached.
+		let (senders, requests) = make_requests(3);
+		let join = tokio::spawn(join_requests(JoinPolicy::Quorum(2), requests));
+		let mut senders = senders.into_iter();
+		let _ = senders.next().unwrap().send(Err(Error::custom("a".into())));
+		let _ = senders.next().unwrap().send(Err(Error::custom("b".into())));
+		let results = join.await.unwrap();
+		assert!(!results.is_satisfied());
+		assert!(results.failures().count() == 2);
+		assert!(results.results()[2].is_cancelled());
+	}
+
+	#[tokio::test]
+	async fn empty() {
+		fn requests() -> Vec<std::future::Ready<Result<(), Error>>> {
+			Vec::new()
+		}
+		assert!(join_requests(JoinPolicy::All, requests()).await.is_satisfied());
+		assert!(!join_requests(JoinPolicy::FirstSuccess, requests()).await.is_satisfied());
+		assert!(join_requests(JoinPolicy::Quorum(0), requests()).await.is_satisfied());
+	}
+}
@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+
+/// Key/value labels attached to a connection.
+///
+/// Labels describe where a connection comes from, for example `network=internal` or `network=external`.
+/// They can be set with [`Peer::with_labels()`][crate::Peer::with_labels],
+/// or by the accept interceptor of a [`Listener`][crate::Listener] for every accepted connection.
+///
+/// The labels of a peer are available from its handles,
+/// they are attached to the [`Annotations`][crate::Annotations] of every received request,
+/// and they are added to the tracing span of the peer and to the metrics recorded by the peer.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ConnectionLabels {
+	/// The labels by key.
+	labels: BTreeMap<String, String>,
+}
+
+impl ConnectionLabels {
+	/// Create a new empty set of labels.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Add a label and return the updated set of labels.
+	pub fn with(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+		self.insert(key, value);
+		self
+	}
+
+	/// Add a label.
+	///
+	/// If a label with the same key was already present, it is replaced and the old value is returned.
+	pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) -> Option<String> {
+		self.labels.insert(key.into(), value.into())
+	}
+
+	/// Get the value of a label, if present.
+	pub fn get(&self, key: &str) -> Option<&str> {
+		self.labels.get(key).map(|value| value.as_str())
+	}
+
+	/// Iterate over the labels, sorted by key.
+	pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+		self.labels.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+	}
+
+	/// Get the number of labels.
+	pub fn len(&self) -> usize {
+		self.labels.len()
+	}
+
+	/// Check if there are no labels.
+	pub fn is_empty(&self) -> bool {
+		self.labels.is_empty()
+	}
+}
+
+impl std::fmt::Display for ConnectionLabels {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		for (i, (key, value)) in self.iter().enumerate() {
+			if i > 0 {
+				write!(f, ",")?;
+			}
+			write!(f, "{key}={value}")?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+
+	#[test]
+	fn labels() {
+		let mut labels = ConnectionLabels::new().with("network", "internal").with("listener", "admin");
+		assert!(labels.len() == 2);
+		assert!(labels.get("network") == Some("internal"));
+		assert!(labels.get("zone") == None);
+		assert!(labels.to_string() == "listener=admin,network=internal");
+
+		assert!(labels.insert("network", "external") == Some("internal".to_owned()));
+		assert!(labels.iter().collect::<Vec<_>>() == [("listener", "admin"), ("network", "external")]);
+		assert!(ConnectionLabels::new().is_empty());
+	}
+}
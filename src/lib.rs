@@ -26,6 +26,48 @@
 //! and gives you a [`PeerHandle`] for each incoming connection.
 //! You can then use the handle to process incoming messages and to send messages to the peer.
 //! Usually, you will want to spawn a task for each accepted connection that handles the communication.
+//! The [`Listener::serve()`] function does this for you, with an optional limit on the number of connections.
+//!
+//! To send the same stream message to many peers, you can collect their [`PeerWriteHandle`]s in a [`Broadcaster`].
+//!
+//! To receive messages from many peers in a single task, you can collect their [`PeerReadHandle`]s in a [`MergedReadHandle`].
+//!
+//! To handle incoming requests with a handler per service ID that can be replaced at runtime, you can use a [`Dispatcher`].
+//!
+//! To spread requests to a single server over multiple connections, you can use a [`PeerPool`].
+//!
+//! To wait for multiple requests at once, possibly to different peers, you can use [`join_requests()`].
+//!
+//! To forward published messages to the peers subscribed to a topic, you can use the [`pubsub`] module.
+//!
+//! To assign service IDs for many interfaces in one central place, you can use the [`service_registry!`] macro from the [`registry`] module.
+//!
+//! To let clients branch on errors without parsing error messages, you can send error responses with an error code with [`ReceivedRequestHandle::send_coded_error_response()`],
+//! and map the codes to an enum with the [`error_codes!`] macro.
+//!
+//! To make sure critical requests are processed exactly once, even if the connection is lost, you can use the [`exactly_once`] module.
+//!
+//! To block certain outgoing messages for all handles of a peer, you can install an [`EgressPolicy`].
+//!
+//! To reject received messages with nonsensical headers from untrusted peers, you can use a strict [`HeaderPolicy`].
+//!
+//! To observe, rewrite or drop all incoming and outgoing messages of a peer, you can install an [`Interceptor`] with [`Peer::with_interceptor()`].
+//! The interceptor can also attach [`Annotations`] to incoming requests, to pass information to the handlers of the requests.
+//!
+//! To monitor a connection, for example to detect slow consumers, you can get the [`PeerStats`] of a peer with [`PeerHandle::stats()`].
+//! To attach the state of a misbehaving connection to a bug report, you can get a [`PeerDiagnostics`] report with [`PeerHandle::diagnose()`].
+//!
+//! To tag accepted connections, for example as coming from an internal or external network, you can attach [`ConnectionLabels`] with [`Listener::with_accept_interceptor()`].
+//!
+//! To know when a stream message has reached the remote peer, or to apply backpressure to stream messages, you can use [`PeerWriteHandle::send_stream_acked()`].
+//!
+//! To capture all messages of a peer to a file and replay them later, you can use the [`recording`] module.
+//!
+//! To drive the services of an interface from generic tooling without compiling the interface, you can use the [`dynamic`] module.
+//!
+//! To run peers on an executor other than tokio, you can use the [`runtime`] module.
+//!
+//! To bring the commonly used traits and handle types into scope with a single import, you can use the [`prelude`].
 //!
 //! ## Transports
 //!
@@ -39,6 +81,7 @@
 //! For example, the [`TcpTransport`] and [`UnixStreamTransport`]
 //! use messages with a [`StreamBody`].
 //! This [`StreamBody`] body type contains raw bytes.
+//! It implements [`SharedBody`], so messages can be cloned cheaply with [`Message::clone_shared()`].
 //!
 //! The [`UnixSeqpacketTransport`] has messages with a [`UnixBody`],
 //! which allows you to embed file descriptors with each message.
@@ -46,13 +89,26 @@
 //! # Features
 //!
 //! The library uses features to avoid unnecessarily large dependency trees.
-//! Each feature corresponds to a different transport type.
+//! Most features correspond to a different transport type.
 //! None of the features are enabled by default.
 //! Currently, the library has these features:
 //!
 //! * `tcp`: for the [`TcpTransport`]
 //! * `unix-stream`: for the [`UnixStreamTransport`]
 //! * `unix-seqpacket`: for the [`UnixSeqpacketTransport`]
+//! * `unix-datagram`: for the [`UnixDatagramTransport`]
+//! * `quic`: for the [`QuicTransport`], based on [`quinn`](https://docs.rs/quinn)
+//! * `generic-stream`: for the [`GenericStreamTransport`], to use any byte stream that implements [`AsyncRead`][tokio::io::AsyncRead] and [`AsyncWrite`][tokio::io::AsyncWrite], like a TLS stream
+//! * `tracing`: to emit [`tracing`](https://docs.rs/tracing) spans and events for peers, requests and transports
+//! * `metrics`: to record the durations of encoding and decoding message bodies (see [`format::CODEC_DURATION_METRIC`]), the number of retried writes (see [`WriteRetryPolicy`]), and the number of dropped stream messages (see [`BoundedLatencyPolicy`]) with [`metrics`](https://docs.rs/metrics)
+//! * `lz4`: for LZ4 compression of message bodies in stream transports
+//! * `zstd`: for Zstandard compression of message bodies in stream transports
+//! * `schemars`: for JSON Schema type information in the [`introspection`] API
+//! * `serde`: to serialize and deserialize the definitions of the [`introspection`] API, for example to load them in the [`dynamic`] module
+//! * `format-postcard`: for the [`format::Postcard`] message format, based on [`postcard`](https://docs.rs/postcard)
+//! * `strict-memory`: to give all internal queues a fixed capacity, see [`ChannelCapacities`]
+//! * `tokio-console`: to name spawned peer and connection tasks for [`tokio-console`](https://docs.rs/tokio-console),
+//!   this also requires building with `RUSTFLAGS="--cfg tokio_unstable"`
 //!
 //! # Example
 //!
@@ -90,6 +146,16 @@ macro_rules! ready {
 	};
 }
 
+/// Emit a [`tracing`](https://docs.rs/tracing) event if the `tracing` feature is enabled.
+///
+/// Without the feature, the macro expands to nothing.
+macro_rules! trace_event {
+	($level:ident, $($args:tt)*) => {
+		#[cfg(feature = "tracing")]
+		::tracing::$level!($($args)*);
+	};
+}
+
 #[cfg(feature = "macros")]
 #[doc(hidden)]
 pub mod macros;
@@ -97,40 +163,88 @@ pub mod macros;
 #[cfg(feature = "macros")]
 pub use macros::interface_example;
 
+mod annotations;
+mod broadcaster;
+mod connection_context;
+mod dispatcher;
+mod egress_policy;
 mod error;
+mod forward;
+mod generic_write_handle;
+mod header_policy;
+mod interceptor;
+mod join;
+mod labels;
 mod listener;
+mod merged_read_handle;
 mod message;
 mod peer;
 mod peer_handle;
+mod peer_pool;
 mod request;
 mod request_tracker;
+mod response_future;
+mod response_reader;
+mod stats;
 
+pub mod dynamic;
+pub mod exactly_once;
 pub mod introspection;
 pub mod format;
+pub mod heartbeat;
+pub mod negotiation;
+pub mod prelude;
+pub mod pubsub;
+pub mod recording;
+pub mod registry;
+pub mod runtime;
+pub mod socket_activation;
 pub mod transport;
 pub mod util;
 
+pub use annotations::Annotations;
+pub use broadcaster::Broadcaster;
+pub use connection_context::ConnectionContext;
+pub use dispatcher::{Dispatcher, HandlerFuture, RequestHandler};
+pub use egress_policy::EgressPolicy;
 pub use error::{
 	Error,
+	ErrorDecoder,
+	ErrorKind,
 	ParseUpdateError,
 	RecvMessageError,
+	ServiceError,
 };
+pub use forward::{forward_request, relay_peers};
+pub use generic_write_handle::{GenericWriteHandle, WriteFuture};
+pub use header_policy::HeaderPolicy;
+pub use interceptor::Interceptor;
+pub use join::{join_requests, JoinPolicy, JoinResults, RequestOutcome};
+pub use labels::ConnectionLabels;
 pub use listener::{
 	Listener,
 	ListeningSocket,
+	ServeConfig,
 };
+pub use merged_read_handle::MergedReadHandle;
+pub use message::header_flags;
 pub use message::service_id;
 pub use message::Body;
 pub use message::Message;
+pub use message::SharedBody;
 pub use message::MessageHeader;
 pub use message::MessageType;
 pub use message::HEADER_LEN;
 pub use message::MAX_PAYLOAD_LEN;
+pub use peer::ChannelCapacities;
+pub use peer::BoundedLatencyPolicy;
+pub use peer::WriteRetryPolicy;
 pub use peer::Peer;
 pub use peer_handle::PeerHandle;
 pub use peer_handle::PeerCloseHandle;
 pub use peer_handle::PeerReadHandle;
 pub use peer_handle::PeerWriteHandle;
+pub use peer_pool::PeerPool;
 pub use request::{
 	ReceivedMessage,
 	ReceivedRequestHandle,
@@ -138,6 +252,11 @@ pub use request::{
 	SentRequestHandle,
 	SentRequestWriteHandle,
 };
+pub use response_future::{ResponseFuture, SentRequestUpdates};
+pub use response_reader::ResponseReader;
+pub use stats::PeerDiagnostics;
+pub use stats::PeerStats;
+pub use request_tracker::TrackerAudit;
 
 pub use transport::stream::StreamBody;
 
@@ -159,6 +278,35 @@ pub type TcpPeer = Peer<TcpTransport>;
 #[cfg(feature = "tcp")]
 pub type TcpListener = Listener<tokio::net::TcpListener>;
 
+/// Message transport for QUIC connections.
+///
+/// All messages are sent over a single bidirectional stream of the QUIC connection.
+#[cfg(feature = "quic")]
+pub type QuicTransport = transport::StreamTransport<transport::QuicStream>;
+
+/// Peer using the QUIC transport.
+///
+/// To connect to a remote peer, pass a tuple with a client [`quinn::Endpoint`],
+/// the remote address and the server name to [`Peer::connect()`].
+#[cfg(feature = "quic")]
+pub type QuicPeer = Peer<QuicTransport>;
+
+/// Listener for QUIC connections.
+///
+/// To bind a new listener, pass a tuple with the local address and a [`quinn::ServerConfig`] to [`Listener::bind()`].
+#[cfg(feature = "quic")]
+pub type QuicListener = Listener<transport::QuicEndpoint>;
+
+/// Message transport for any byte stream that implements [`AsyncRead`][tokio::io::AsyncRead] and [`AsyncWrite`][tokio::io::AsyncWrite].
+///
+/// See [`GenericStream`][transport::GenericStream] for more details.
+#[cfg(feature = "generic-stream")]
+pub type GenericStreamTransport<S> = transport::StreamTransport<transport::GenericStream<S>>;
+
+/// Peer using the generic byte stream transport.
+#[cfg(feature = "generic-stream")]
+pub type GenericStreamPeer<S> = Peer<GenericStreamTransport<S>>;
+
 /// Message transport for Unix stream sockets.
 #[cfg(feature = "unix-stream")]
 pub type UnixStreamTransport = transport::StreamTransport<tokio::net::UnixStream>;
@@ -183,6 +331,13 @@ pub type UnixSeqpacketPeer = Peer<UnixSeqpacketTransport>;
 #[cfg(feature = "unix-seqpacket")]
 pub type UnixSeqpacketListener = Listener<tokio_seqpacket::UnixSeqpacketListener>;
 
+#[cfg(feature = "unix-datagram")]
+pub use transport::UnixDatagramTransport;
+
+/// Peer using the Unix datagram transport.
+#[cfg(feature = "unix-datagram")]
+pub type UnixDatagramPeer = Peer<UnixDatagramTransport>;
+
 #[doc(hidden)]
 #[deprecated(note = "This type was renamed to ReceivedMessage. Please use that instead.", since = "0.5.0")]
 pub type Incoming<Body> = ReceivedMessage<Body>;
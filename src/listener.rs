@@ -1,3 +1,9 @@
+use std::os::unix::io::{FromRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::ConnectionContext;
+use crate::ConnectionLabels;
 use crate::Peer;
 use crate::PeerHandle;
 use crate::util;
@@ -10,6 +16,44 @@ where
 {
 	listener: Socket,
 	config: Socket::Config,
+	accept_interceptor: Option<AcceptInterceptor<Socket::TransportInfo>>,
+	context_factory: Option<ContextFactory<Socket::TransportInfo>>,
+}
+
+/// Function that computes the labels of an accepted connection from the transport info.
+type AcceptInterceptor<Info> = Box<dyn FnMut(&Info) -> ConnectionLabels + Send>;
+
+/// Function that creates the application context of an accepted connection from the transport info.
+type ContextFactory<Info> = Box<dyn FnMut(&Info) -> ConnectionContext + Send>;
+
+/// Configuration for [`Listener::serve()`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ServeConfig {
+	/// The maximum number of connections that are handled at the same time.
+	///
+	/// When the limit is reached, no new connections are accepted until a connection handler finishes.
+	/// If `None` (the default), the number of connections is not limited.
+	pub max_connections: Option<usize>,
+
+	/// The time to wait before accepting again after the first failed accept.
+	///
+	/// Accepting a connection can fail temporarily, for example when the process runs out of file descriptors.
+	/// The wait time is doubled for each consecutive failure, up to [`Self::accept_backoff_max`].
+	pub accept_backoff_min: Duration,
+
+	/// The maximum time to wait before accepting again after a failed accept.
+	pub accept_backoff_max: Duration,
+}
+
+impl Default for ServeConfig {
+	fn default() -> Self {
+		Self {
+			max_connections: None,
+			accept_backoff_min: Duration::from_millis(10),
+			accept_backoff_max: Duration::from_secs(1),
+		}
+	}
 }
 
 /// Helper trait for [`Listener`].
@@ -41,7 +85,7 @@ pub trait ListeningSocket: util::Listener + Unpin {
 	fn transport_info(connection: &Self::Transport) -> std::io::Result<Self::TransportInfo>;
 
 	#[doc(hidden)]
-	fn spawn(transport: Self::Transport) -> PeerHandle<Self::Body>;
+	fn spawn(transport: Self::Transport, labels: ConnectionLabels, context: ConnectionContext) -> PeerHandle<Self::Body>;
 }
 
 impl<Socket> ListeningSocket for Socket
@@ -63,8 +107,10 @@ where
 		connection.info()
 	}
 
-	fn spawn(transport: Self::Transport) -> PeerHandle<Self::Body> {
-		Peer::spawn(transport)
+	fn spawn(transport: Self::Transport, labels: ConnectionLabels, context: ConnectionContext) -> PeerHandle<Self::Body> {
+		let (peer, handle) = Peer::new(transport);
+		peer.with_labels(labels).with_connection_context(context).spawn_run();
+		handle
 	}
 }
 
@@ -73,7 +119,48 @@ impl<Socket: ListeningSocket> Listener<Socket> {
 	///
 	/// The passed in config is used to create transports and peers for all accepted connections.
 	pub fn new(listener: Socket, config: Socket::Config) -> Self {
-		Self { listener, config }
+		Self {
+			listener,
+			config,
+			accept_interceptor: None,
+			context_factory: None,
+		}
+	}
+
+	/// Set a function to compute the labels of each accepted connection.
+	///
+	/// The function receives the transport info of the connection, like the address of the remote peer.
+	/// The returned labels are attached to the peer of the connection,
+	/// so they are visible to the connection handler, in the tracing span of the peer and in metrics.
+	/// See [`ConnectionLabels`] for more details.
+	///
+	/// For example, you can tag connections from an internal network differently from external connections,
+	/// without writing your own accept loop.
+	///
+	/// By default, accepted connections have no labels.
+	pub fn with_accept_interceptor<F>(mut self, interceptor: F) -> Self
+	where
+		F: FnMut(&Socket::TransportInfo) -> ConnectionLabels + Send + 'static,
+	{
+		self.accept_interceptor = Some(Box::new(interceptor));
+		self
+	}
+
+	/// Set a function to create the application context of each accepted connection.
+	///
+	/// The function receives the transport info of the connection, like the credentials of the remote process.
+	/// The returned context is attached to the peer of the connection before it starts processing messages,
+	/// so it is available from the handles of the peer and from all received requests.
+	/// See [`ConnectionContext`] for more details.
+	///
+	/// By default, accepted connections have no context.
+	pub fn with_context_factory<F, T>(mut self, mut factory: F) -> Self
+	where
+		F: FnMut(&Socket::TransportInfo) -> Arc<T> + Send + 'static,
+		T: Send + Sync + 'static,
+	{
+		self.context_factory = Some(Box::new(move |info| ConnectionContext::new(factory(info))));
+		self
 	}
 
 	/// Create a server with a new listening socket bound to the given address.
@@ -83,6 +170,9 @@ impl<Socket: ListeningSocket> Listener<Socket> {
 	/// For unix transports, the address must implement [`AsRef<std::path::Path>`].
 	///
 	/// This function is asynchronous because it may perform a DNS lookup for some address types.
+	///
+	/// To listen on multiple addresses at once, use a [`util::MultiListener`] with a [`Vec`] of addresses,
+	/// or a [`util::EitherListener`] with a tuple of addresses for two different socket types.
 	pub async fn bind<'a, Address: 'a>(address: Address, config: Socket::Config) -> std::io::Result<Self>
 	where
 		Socket: util::Bind<'a, Address>,
@@ -90,9 +180,52 @@ impl<Socket: ListeningSocket> Listener<Socket> {
 		Ok(Self::new(Socket::bind(address).await?, config))
 	}
 
+	/// Create a server from a listening socket of the standard library.
+	///
+	/// This is useful for sockets that were set up by other code, for example with custom socket options.
+	/// The socket is put in non-blocking mode.
+	/// This function must be called from within a tokio runtime.
+	pub fn from_std(listener: Socket::Std, config: Socket::Config) -> std::io::Result<Self>
+	where
+		Socket: util::FromStd,
+	{
+		Ok(Self::new(Socket::from_std(listener)?, config))
+	}
+
+	/// Create a server from the file descriptor of a listening socket.
+	///
+	/// The file descriptor must refer to a socket of the right type that is already listening for connections.
+	/// This is useful for sockets that are passed in by a service manager,
+	/// see the [`socket_activation`][crate::socket_activation] module.
+	///
+	/// The socket is put in non-blocking mode.
+	/// This function must be called from within a tokio runtime.
+	pub fn from_fd(fd: OwnedFd, config: Socket::Config) -> std::io::Result<Self>
+	where
+		Socket: util::FromFd,
+	{
+		Ok(Self::new(Socket::from_fd(fd)?, config))
+	}
+
+	/// Create a server from a raw file descriptor of a listening socket.
+	///
+	/// See [`Self::from_fd()`] for more details.
+	///
+	/// # Safety
+	/// The file descriptor must be open and it must not be owned by anything else,
+	/// since ownership of the file descriptor is transferred to the listener.
+	pub unsafe fn from_raw_fd(fd: RawFd, config: Socket::Config) -> std::io::Result<Self>
+	where
+		Socket: util::FromFd,
+	{
+		Self::from_fd(OwnedFd::from_raw_fd(fd), config)
+	}
+
 	/// Run the server.
 	///
 	/// The server will accept connections in a loop and spawn a user task for each new peer.
+	///
+	/// With the `tokio-console` feature, the user task is named after the connection ID of the peer.
 	pub async fn run<F, R>(&mut self, task: F) -> std::io::Result<()>
 	where
 		F: FnMut(PeerHandle<Socket::Body>, Socket::TransportInfo) -> R,
@@ -101,13 +234,73 @@ impl<Socket: ListeningSocket> Listener<Socket> {
 		let mut task = task;
 		loop {
 			let (peer, info) = self.accept().await?;
-			let join_handle = tokio::spawn((task)(peer, info));
+			let connection_id = peer.connection_id();
+			let join_handle = util::spawn_named(move || connection_task_name(connection_id), (task)(peer, info));
 			// TODO: keep join handles around so we can await them later.
 			// If we do, we should also clean them from time to time though.
 			drop(join_handle);
 		}
 	}
 
+	/// Serve connections until the future is dropped.
+	///
+	/// The server accepts connections in a loop and spawns a task running `handler` for each new peer.
+	/// The handler receives the [`PeerHandle`] and the transport info of the connection.
+	///
+	/// If [`ServeConfig::max_connections`] is set, no new connections are accepted while the limit is reached.
+	/// A connection counts towards the limit until the future returned by the handler completes.
+	///
+	/// Failures to accept a connection do not stop the server.
+	/// Instead, the server waits before accepting again, using an exponential backoff as configured in `config`.
+	pub async fn serve<F, R>(&mut self, config: ServeConfig, handler: F)
+	where
+		F: FnMut(PeerHandle<Socket::Body>, Socket::TransportInfo) -> R,
+		R: std::future::Future<Output = ()> + Send + 'static,
+	{
+		let mut handler = handler;
+		let limit = config.max_connections.map(|x| Arc::new(tokio::sync::Semaphore::new(x)));
+		let mut backoff = config.accept_backoff_min;
+		loop {
+			let permit = match &limit {
+				// The semaphore is never closed, so acquiring a permit can not fail.
+				Some(limit) => limit.clone().acquire_owned().await.ok(),
+				None => None,
+			};
+
+			let connection = match self.listener.accept().await {
+				Ok((connection, _addr)) => {
+					backoff = config.accept_backoff_min;
+					connection
+				},
+				Err(e) => {
+					let _ = e;
+					trace_event!(warn, "failed to accept connection: {}, retrying in {:?}", e, backoff);
+					tokio::time::sleep(backoff).await;
+					backoff = (backoff * 2).min(config.accept_backoff_max);
+					continue;
+				},
+			};
+
+			let transport = Socket::into_transport(connection, self.config.clone());
+			let info = match Socket::transport_info(&transport) {
+				Ok(x) => x,
+				Err(e) => {
+					let _ = e;
+					trace_event!(warn, "failed to get transport info of accepted connection: {}", e);
+					continue;
+				},
+			};
+
+			let peer = Socket::spawn(transport, self.labels(&info), self.context(&info));
+			let connection_id = peer.connection_id();
+			let task = handler(peer, info);
+			util::spawn_named(move || connection_task_name(connection_id), async move {
+				task.await;
+				drop(permit);
+			});
+		}
+	}
+
 	/// Accept a connection and spawn a peer for it.
 	///
 	/// A [`Peer`] is spawned for the new connection,
@@ -116,6 +309,197 @@ impl<Socket: ListeningSocket> Listener<Socket> {
 		let (connection, _addr) = self.listener.accept().await?;
 		let transport = Socket::into_transport(connection, self.config.clone());
 		let info = Socket::transport_info(&transport)?;
-		Ok((Socket::spawn(transport), info))
+		let labels = self.labels(&info);
+		let context = self.context(&info);
+		Ok((Socket::spawn(transport, labels, context), info))
+	}
+
+	/// Compute the labels of an accepted connection with the accept interceptor.
+	fn labels(&mut self, info: &Socket::TransportInfo) -> ConnectionLabels {
+		match &mut self.accept_interceptor {
+			Some(interceptor) => interceptor(info),
+			None => ConnectionLabels::new(),
+		}
+	}
+
+	/// Create the application context of an accepted connection with the context factory.
+	fn context(&mut self, info: &Socket::TransportInfo) -> ConnectionContext {
+		match &mut self.context_factory {
+			Some(factory) => factory(info),
+			None => ConnectionContext::empty(),
+		}
+	}
+}
+
+/// Get the name of the task that runs the user code for a connection.
+fn connection_task_name(connection_id: u64) -> String {
+	format!("fizyr-rpc connection #{}", connection_id)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use tokio::sync::oneshot;
+
+	use crate::{ReceivedMessage, StreamBody, TcpListener, TcpPeer, TcpTransport};
+
+	#[tokio::test]
+	async fn serve_with_connection_limit() {
+		let_assert!(Ok(socket) = tokio::net::TcpListener::bind("127.0.0.1:0").await);
+		let_assert!(Ok(address) = socket.local_addr());
+		let mut listener = TcpListener::new(socket, Default::default());
+
+		// Each handler answers one request with the number of connections being handled.
+		// The first handler waits until it is released, so it keeps the only connection slot.
+		let active = Arc::new(AtomicUsize::new(0));
+		let (release_tx, release_rx) = oneshot::channel::<()>();
+		let mut release_rx = Some(release_rx);
+		let config = ServeConfig { max_connections: Some(1), ..Default::default() };
+		let server = tokio::spawn(async move {
+			listener.serve(config, move |mut peer: PeerHandle<StreamBody>, _info| {
+				let active = active.clone();
+				let release = release_rx.take();
+				async move {
+					let count = active.fetch_add(1, Ordering::Relaxed) + 1;
+					if let Ok(ReceivedMessage::Request(request, _body)) = peer.recv_message().await {
+						let _: Result<_, _> = request.send_response(1, vec![count as u8]).await;
+					}
+					if let Some(release) = release {
+						let _ = release.await;
+					}
+					active.fetch_sub(1, Ordering::Relaxed);
+				}
+			}).await
+		});
+
+		let_assert!(Ok(stream) = tokio::net::TcpStream::connect(address).await);
+		let first = TcpPeer::spawn(TcpTransport::new(stream, Default::default()));
+		let_assert!(Ok(mut request) = first.send_request(1, &b""[..]).await);
+		let_assert!(Ok(response) = request.recv_response().await);
+		assert!(response.body.as_ref() == [1]);
+
+		// The second connection is only handled once the first handler finishes.
+		let_assert!(Ok(stream) = tokio::net::TcpStream::connect(address).await);
+		let second = TcpPeer::spawn(TcpTransport::new(stream, Default::default()));
+		let_assert!(Ok(mut request) = second.send_request(1, &b""[..]).await);
+		tokio::task::yield_now().await;
+		assert!(let Ok(()) = release_tx.send(()));
+		let_assert!(Ok(response) = request.recv_response().await);
+		assert!(response.body.as_ref() == [1]);
+
+		server.abort();
+	}
+
+	#[tokio::test]
+	async fn accept_interceptor_labels() {
+		let_assert!(Ok(socket) = tokio::net::TcpListener::bind("127.0.0.1:0").await);
+		let_assert!(Ok(address) = socket.local_addr());
+		let mut listener = TcpListener::new(socket, Default::default())
+			.with_accept_interceptor(|info: &crate::transport::TcpStreamInfo| {
+				let network = if info.remote_address().ip().is_loopback() { "internal" } else { "external" };
+				ConnectionLabels::new().with("network", network)
+			});
+
+		let_assert!(Ok((client, _info)) = TcpPeer::connect(address, Default::default()).await);
+		let_assert!(Ok((mut server, _info)) = listener.accept().await);
+		assert!(server.labels().get("network") == Some("internal"));
+		assert!(client.labels().is_empty());
+
+		// The labels are attached to received requests, so handlers can see where a request came from.
+		let_assert!(Ok(_sent_request) = client.send_request(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(request, _body)) = server.recv_message().await);
+		let_assert!(Some(labels) = request.annotations().get::<ConnectionLabels>());
+		assert!(labels.get("network") == Some("internal"));
+	}
+
+	#[tokio::test]
+	async fn connection_context() {
+		let_assert!(Ok(socket) = tokio::net::TcpListener::bind("127.0.0.1:0").await);
+		let_assert!(Ok(address) = socket.local_addr());
+		let mut listener = TcpListener::new(socket, Default::default())
+			.with_context_factory(|info: &crate::transport::TcpStreamInfo| Arc::new(info.remote_address().port()));
+
+		let_assert!(Ok((client, client_info)) = TcpPeer::connect_with_context(address, Default::default(), Arc::new("client")).await);
+		let_assert!(Ok((mut server, _info)) = listener.accept().await);
+		assert!(client.context::<&str>().as_deref() == Some(&"client"));
+		assert!(server.context::<u16>().as_deref() == Some(&client_info.local_address().port()));
+		assert!(server.context::<&str>() == None);
+
+		// The context is attached to received requests, so handlers can use it without a side table.
+		let_assert!(Ok(_sent_request) = client.send_request(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(request, _body)) = server.recv_message().await);
+		assert!(request.context::<u16>().as_deref() == Some(&client_info.local_address().port()));
+	}
+
+	#[tokio::test]
+	async fn multi_address_listener() {
+		let addresses = vec!["127.0.0.1:0", "127.0.0.1:0"];
+		let_assert!(Ok(mut listener) = Listener::<util::MultiListener<tokio::net::TcpListener>>::bind(addresses, Default::default()).await);
+		let_assert!(Ok(address_a) = listener.listener.listeners()[0].local_addr());
+		let_assert!(Ok(address_b) = listener.listener.listeners()[1].local_addr());
+
+		for address in [address_a, address_b] {
+			let_assert!(Ok((client, _info)) = TcpPeer::connect(address, Default::default()).await);
+			let_assert!(Ok((mut server, info)) = listener.accept().await);
+			assert!(info.remote_address().port() != address.port());
+			let_assert!(Ok(()) = client.send_stream(1, &b"hello"[..]).await);
+			let_assert!(Ok(ReceivedMessage::Stream(message)) = server.recv_message().await);
+			assert!(message.body.as_ref() == b"hello");
+		}
+	}
+
+	#[tokio::test]
+	async fn tcp_and_unix_listener() {
+		let path = std::env::temp_dir().join(format!("fizyr-rpc-test-{}.sock", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		type TcpOrUnix = util::EitherListener<tokio::net::TcpListener, tokio::net::UnixListener>;
+		let_assert!(Ok(mut listener) = Listener::<TcpOrUnix>::bind(("127.0.0.1:0", &path), Default::default()).await);
+		let_assert!(Ok(tcp_address) = listener.listener.left().local_addr());
+
+		let_assert!(Ok((tcp_client, _info)) = TcpPeer::connect(tcp_address, Default::default()).await);
+		let_assert!(Ok((mut server, util::Either::Left(_info))) = listener.accept().await);
+		let_assert!(Ok(()) = tcp_client.send_stream(1, &b"tcp"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = server.recv_message().await);
+		assert!(message.body.as_ref() == b"tcp");
+
+		let_assert!(Ok((unix_client, _info)) = crate::UnixStreamPeer::connect(&path, Default::default()).await);
+		let_assert!(Ok((mut server, util::Either::Right(_info))) = listener.accept().await);
+		let_assert!(Ok(()) = unix_client.send_stream(1, &b"unix"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = server.recv_message().await);
+		assert!(message.body.as_ref() == b"unix");
+
+		let _ = std::fs::remove_file(&path);
+	}
+
+	#[tokio::test]
+	async fn listener_from_std_socket() {
+		let_assert!(Ok(socket) = std::net::TcpListener::bind("127.0.0.1:0"));
+		let_assert!(Ok(address) = socket.local_addr());
+		let_assert!(Ok(mut listener) = TcpListener::from_std(socket, Default::default()));
+
+		let_assert!(Ok((client, _info)) = TcpPeer::connect(address, Default::default()).await);
+		let_assert!(Ok((mut server, _info)) = listener.accept().await);
+		let_assert!(Ok(()) = client.send_stream(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = server.recv_message().await);
+		assert!(message.body.as_ref() == b"hello");
+	}
+
+	#[tokio::test]
+	async fn listener_from_fd() {
+		let path = std::env::temp_dir().join(format!("fizyr-rpc-test-from-fd-{}.sock", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		let_assert!(Ok(socket) = std::os::unix::net::UnixListener::bind(&path));
+		let_assert!(Ok(mut listener) = crate::UnixStreamListener::from_fd(socket.into(), Default::default()));
+
+		let_assert!(Ok((client, _info)) = crate::UnixStreamPeer::connect(&path, Default::default()).await);
+		let_assert!(Ok((mut server, _info)) = listener.accept().await);
+		let_assert!(Ok(()) = client.send_stream(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = server.recv_message().await);
+		assert!(message.body.as_ref() == b"hello");
+
+		let _ = std::fs::remove_file(&path);
 	}
 }
@@ -3,6 +3,32 @@
 #[doc(hidden)]
 pub use fizyr_rpc_macros::interface as interface_impl;
 
+/// The `Stream` trait implemented by the update streams of generated interfaces.
+///
+/// This is a re-export of [`futures_core::Stream`].
+pub use futures_core::Stream;
+
+/// Spawn a task for the generated `ServerRunner`.
+#[doc(hidden)]
+pub fn spawn_handler<F>(future: F)
+where
+	F: std::future::Future<Output = ()> + Send + 'static,
+{
+	tokio::spawn(future);
+}
+
+/// Run a handler future of the generated `ServerRunner` in a separate task.
+///
+/// Returns `None` if the handler panicked.
+#[doc(hidden)]
+pub async fn run_handler<F>(future: F) -> Option<F::Output>
+where
+	F: std::future::Future + Send + 'static,
+	F::Output: Send + 'static,
+{
+	tokio::spawn(future).await.ok()
+}
+
 #[macro_export]
 /// Define an RPC interface.
 ///
@@ -20,6 +46,21 @@ pub use fizyr_rpc_macros::interface as interface_impl;
 /// It can be created from a [`PeerReadHandle`] or a [`PeerHandle`],
 /// but creating it from a [`PeerHandle`] will discard the [`PeerWriteHandle`].
 ///
+/// The macro also generates a `{Name}ClientApi` trait with the functions of the client struct,
+/// except for services with update messages.
+/// Application code can depend on this trait instead of the client struct, so tests can replace the client with a mock.
+///
+/// If the interface has services or streams, the macro also generates a `ServerHandler` trait and a `ServerRunner` struct.
+/// The handler trait has a function for each service and stream message.
+/// The runner receives messages from a server, passes them to the handler and sends the returned value as response.
+/// Errors returned by the handler and panics in the handler are reported to the remote peer with an error response.
+///
+/// Finally, the macro generates a `service_ids` module with a constant for the service ID of each service, update and stream message.
+/// The constant names are the upper case names of the services.
+/// Update IDs are in a nested module named after the service, and stream IDs are in a nested `streams` module.
+/// This is useful for code that uses a raw [`PeerHandle`] to talk to a service,
+/// for example `send_request(camera::service_ids::RECORD, body)`.
+///
 /// # Example
 ///
 /// See the [`interface_example`] module for an example, with the source code and generated documentation.
@@ -43,13 +84,28 @@ pub use fizyr_rpc_macros::interface as interface_impl;
 ///     //
 ///     // The documentation writter on the `interface` item can be retrieved through the introspection API,
 ///     // but does not appear in rustdoc.
+///     //
+///     // The generated client functions also get an example that shows how to call the service and handle the updates.
+///     // The comments in the example are taken from the first line of the documentation of the updates.
+///     //
+///     // With the optional `#[heartbeat]` attribute, the generated client periodically sends a heartbeat message,
+///     // and the generated server records when it received the last one in `Server::last_heartbeat()`.
+///     // The interval is a number followed by `ms`, `s`, `m` or `h`.
+///     // See the `heartbeat` module for more details.
+///     #[heartbeat(interval = "5s")]
 ///     pub interface $interface_name {
 ///         // The `service` keyword defines a service.
 ///         //
 ///         // You can have any amount of service definitions inside an interface definition.
 ///         //
 ///         // The $id is used as the service ID and must be an i32.
+///         // It can be an integer literal or a path to an `i32` constant, like `ids::GET_VERSION`.
+///         // The macro does not look up paths: they are copied into the generated code,
+///         // so they must be valid in the module that invokes the macro.
+///         // To assign service IDs in one central place, you can define the constants with the `service_registry!` macro.
 ///         // The ID must be unique for all services in the interface.
+///         // Duplicate literals are reported by the macro itself,
+///         // duplicate paths by `const` assertions in the generated code, after the constants are evaluated.
 ///         //
 ///         // The $name is the name of the service.
 ///         // It is used to generate function and type names.
@@ -62,6 +118,21 @@ pub use fizyr_rpc_macros::interface as interface_impl;
 ///         // See the next item for the syntax of services with update messages.
 ///         service $id $name: $request_type -> $response_type,
 ///
+///         // A service can declare an error type after the response type.
+///         // The request handler can then send a structured error of this type instead of a plain error message.
+///         // The generated client returns a `ServiceError<$error_type>` for these services.
+///         // This works for services with and without update messages.
+///         service $id $name: $request_type -> $response_type ! $error_type,
+///
+///         // For request types with many fields, you can let the macro generate a request builder with the `#[builder]` attribute.
+///         // The attribute lists the fields of the request type that get a setter, with their type.
+///         // The request type must implement `Default`, which is used as starting point for the builder.
+///         //
+///         // The client then gets a `$name_builder()` function in addition to the normal `$name()` function:
+///         // `client.record_builder().color(true).cloud(false).send().await`.
+///         #[builder(color: bool, cloud: bool)]
+///         service $id $name: $request_type -> $response_type,
+///
 ///         // If a service has update messages, you can declare them in the service block.
 ///         service $id $name: $request_type -> $response_type {
 ///             // The `request_update` keyword defines a request update.
@@ -93,6 +164,16 @@ pub use fizyr_rpc_macros::interface as interface_impl;
 ///             response_update $id $name: $body_type,
 ///         }
 ///
+///         // If a service has exactly one response update, you can mark it with the `#[stream]` attribute.
+///         // The sent request handle then gets a `$name_stream()` function that returns a `Stream` of the update bodies,
+///         // so you do not need to call `recv_update()` in a loop and match on an enum with a single variant.
+///         // The stream ends when the final response is received, which can then be read with `recv_response()`.
+///         // The `Stream` trait is re-exported as `fizyr_rpc::macros::Stream`.
+///         service $id $name: $request_type -> $response_type {
+///             #[stream]
+///             response_update $id $name: $body_type,
+///         }
+///
 ///         // The `stream` keyword defines a stream message.
 ///         // You can have any amount of stream definitions in an interface definition.
 ///         //
@@ -106,6 +187,27 @@ pub use fizyr_rpc_macros::interface as interface_impl;
 ///         // The $body_type indicates the type of the message.
 ///         // If there is no data in the message, you can use the unit type: `()`
 ///        stream $id $name: $body_type,
+///
+///         // Stream messages with the same service ID are delivered to the application in the order they were sent.
+///         // If the order does not matter, you can mark a stream with the `#[unordered]` attribute.
+///         // Generated servers can then decode messages of that stream in parallel for more throughput,
+///         // see `Server::set_unordered_decode_workers()`.
+///         // Whether a stream is ordered is also exposed through the introspection API.
+///         #[unordered]
+///         stream $id $name: $body_type,
+///
+///         // Services, updates and streams can be enabled conditionally with `#[cfg]` attributes.
+///         // No code is generated for disabled items, so their body types do not need to exist.
+///         // Disabled items are also left out of the introspection data,
+///         // but they are still part of the version hash of the interface.
+///         //
+///         // Service IDs must be unique, even for items that are never enabled at the same time.
+///         //
+///         // If all services of an interface have `#[cfg]` attributes,
+///         // the generated `ReceivedRequestHandle` enum has an additional hidden variant,
+///         // so matches on it need a wildcard arm.
+///         #[cfg(feature = "calibration")]
+///         service $id $name: $request_type -> $response_type,
 ///     }
 /// }
 /// ```
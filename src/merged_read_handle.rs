@@ -0,0 +1,186 @@
+use std::future::poll_fn;
+use std::task::{Context, Poll};
+
+use crate::{Error, PeerReadHandle, ReceivedMessage};
+
+/// An incoming message or error, tagged with the connection ID of the peer.
+type TaggedMessage<Body> = (u64, Result<ReceivedMessage<Body>, Error>);
+
+/// Utility to receive messages from multiple peers in a single task.
+///
+/// The merged read handle holds a list of [`PeerReadHandle`] objects,
+/// each identified by a connection ID that is assigned when the handle is added.
+/// [`Self::recv_message()`] receives the next message from any of the peers,
+/// and tags it with the connection ID of the peer it came from.
+///
+/// This allows a server to process all connections in a single task, instead of spawning a task per connection.
+/// Connection IDs are never reused by the same merged read handle.
+///
+/// Peers are polled in a rotating order, so a busy peer can not starve the others.
+/// When the connection of a peer is closed, the peer is removed automatically.
+pub struct MergedReadHandle<Body> {
+	/// The read handles of the peers, with their connection ID.
+	peers: Vec<(u64, PeerReadHandle<Body>)>,
+
+	/// The connection ID for the next added peer.
+	next_id: u64,
+
+	/// The index of the peer to poll first.
+	next_poll: usize,
+}
+
+impl<Body> MergedReadHandle<Body> {
+	/// Create a new merged read handle without any peers.
+	pub fn new() -> Self {
+		Self {
+			peers: Vec::new(),
+			next_id: 0,
+			next_poll: 0,
+		}
+	}
+
+	/// Add a peer, and get the connection ID assigned to it.
+	pub fn add(&mut self, peer: PeerReadHandle<Body>) -> u64 {
+		let id = self.next_id;
+		self.next_id += 1;
+		self.peers.push((id, peer));
+		id
+	}
+
+	/// Remove a peer and get back the read handle.
+	///
+	/// Returns `None` if there is no peer with the given connection ID.
+	pub fn remove(&mut self, connection_id: u64) -> Option<PeerReadHandle<Body>> {
+		let index = self.peers.iter().position(|(id, _)| *id == connection_id)?;
+		Some(self.peers.remove(index).1)
+	}
+
+	/// Check if a peer with the given connection ID is part of the merged read handle.
+	pub fn contains(&self, connection_id: u64) -> bool {
+		self.peers.iter().any(|(id, _)| *id == connection_id)
+	}
+
+	/// Get the read handle of a peer by connection ID.
+	pub fn get(&self, connection_id: u64) -> Option<&PeerReadHandle<Body>> {
+		self.peers.iter().find(|(id, _)| *id == connection_id).map(|(_, peer)| peer)
+	}
+
+	/// Get the connection IDs of all peers.
+	pub fn connection_ids(&self) -> impl Iterator<Item = u64> + '_ {
+		self.peers.iter().map(|(id, _)| *id)
+	}
+
+	/// Get the number of peers.
+	pub fn len(&self) -> usize {
+		self.peers.len()
+	}
+
+	/// Check if the merged read handle has no peers.
+	pub fn is_empty(&self) -> bool {
+		self.peers.is_empty()
+	}
+
+	/// Receive the next request or stream message from any of the peers.
+	///
+	/// The message or error is returned together with the connection ID of the peer it came from.
+	/// Like [`PeerReadHandle::recv_message()`], errors for invalid incoming messages are also reported by this function.
+	///
+	/// When the connection of a peer is closed, a connection aborted error is returned for the peer,
+	/// and the peer is removed from the merged read handle.
+	///
+	/// Returns `None` if there are no peers left.
+	pub async fn recv_message(&mut self) -> Option<TaggedMessage<Body>> {
+		poll_fn(|context| self.poll_recv_message(context)).await
+	}
+
+	/// Try to receive the next request or stream message from any of the peers without blocking.
+	///
+	/// See [`Self::recv_message()`] for more details.
+	///
+	/// If this function returns [`Poll::Pending`], the current task is scheduled to wake when a message is received from any peer.
+	pub fn poll_recv_message(&mut self, context: &mut Context) -> Poll<Option<TaggedMessage<Body>>> {
+		if self.peers.is_empty() {
+			return Poll::Ready(None);
+		}
+
+		let start = self.next_poll % self.peers.len();
+		for i in 0..self.peers.len() {
+			let index = (start + i) % self.peers.len();
+			let (id, peer) = &mut self.peers[index];
+			if let Poll::Ready(incoming) = peer.poll_recv_message(context) {
+				let id = *id;
+				if matches!(&incoming, Err(e) if e.is_connection_aborted()) {
+					trace_event!(debug, connection_id = id, "removing closed peer from merged read handle");
+					self.peers.remove(index);
+					self.next_poll = index;
+				} else {
+					self.next_poll = index + 1;
+				}
+				return Poll::Ready(Some((id, incoming)));
+			}
+		}
+
+		Poll::Pending
+	}
+}
+
+impl<Body> Default for MergedReadHandle<Body> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl<Body> std::fmt::Debug for MergedReadHandle<Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		let connection_ids: Vec<u64> = self.connection_ids().collect();
+		f.debug_struct(core::any::type_name::<Self>())
+			.field("connection_ids", &connection_ids)
+			.finish_non_exhaustive()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+
+	use crate::{StreamBody, UnixStreamPeer, UnixStreamTransport};
+
+	#[tokio::test]
+	async fn receive_from_multiple_peers() {
+		let mut merged = MergedReadHandle::<StreamBody>::new();
+		let mut clients = Vec::new();
+		for _ in 0..3 {
+			let_assert!(Ok((peer_a, peer_b)) = tokio::net::UnixStream::pair());
+			clients.push(UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, Default::default())));
+			let (read_b, _write_b) = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default())).split();
+			merged.add(read_b);
+		}
+		assert!(merged.connection_ids().collect::<Vec<_>>() == [0, 1, 2]);
+
+		// Messages are tagged with the connection they came from.
+		for (i, client) in clients.iter().enumerate() {
+			assert!(let Ok(()) = client.send_stream(i as i32, &b"hello"[..]).await);
+		}
+		let mut received = Vec::new();
+		for _ in 0..3 {
+			let_assert!(Some((id, Ok(ReceivedMessage::Stream(message)))) = merged.recv_message().await);
+			received.push((id, message.header.service_id));
+		}
+		received.sort();
+		assert!(received == [(0, 0), (1, 1), (2, 2)]);
+
+		// Closed connections are reported once and then removed.
+		clients.remove(1).close();
+		let_assert!(Some((1, Err(e))) = merged.recv_message().await);
+		assert!(e.is_connection_aborted());
+		assert!(!merged.contains(1));
+		assert!(merged.len() == 2);
+
+		// Removed peers are handed back.
+		assert!(let Some(_) = merged.remove(0));
+		assert!(let Some(_) = merged.remove(2));
+		assert!(let None = merged.recv_message().await);
+	}
+}
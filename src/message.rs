@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use crate::Error;
 use crate::error::private::InnerError;
 use crate::transport::Endian;
@@ -32,12 +34,168 @@ pub trait Body: Send + Sync + Sized + 'static {
 	///
 	/// You should only call this if you know that the body represent an error message.
 	fn into_error(self) -> Result<String, std::string::FromUtf8Error>;
+
+	/// Get the length of the data in the body in bytes.
+	///
+	/// This is used to enforce size limits, like [`DecodeContext::max_body_len`][crate::format::DecodeContext::max_body_len],
+	/// and by utilities that make decisions based on the size of a message.
+	fn data_len(&self) -> usize;
+}
+
+/// Message body that can be cloned in constant time.
+///
+/// The clone shares the underlying data with the original body.
+/// This allows handing the same message to multiple consumers without copying the data,
+/// for example in a fan-out dispatcher.
+///
+/// The [`StreamBody`][crate::StreamBody] implements this trait.
+/// The [`UnixBody`][crate::UnixBody] does not, because the file descriptors it holds can not be shared.
+pub trait SharedBody: Body + Clone {
+	/// Clone the body without copying the data.
+	///
+	/// This must run in constant time, regardless of the size of the body.
+	fn clone_shared(&self) -> Self;
 }
 
 /// Well-known service IDs.
 pub mod service_id {
 	/// The service ID used for error responses.
 	pub const ERROR: i32 = -1;
+
+	/// The service ID used for responses that indicate the service is temporarily unavailable.
+	///
+	/// The body of the response is a UTF-8 string with the time after which the request may be retried,
+	/// in milliseconds, as a decimal integer.
+	/// It may be followed by a single space and a human readable error message.
+	pub const RETRY_AFTER: i32 = -2;
+
+	/// The service ID used for responder updates that contain a chunk of the response body.
+	///
+	/// A responder can split a large response body over multiple update messages with this service ID.
+	/// The complete response body is the concatenation of the body of all chunks and the body of the final response.
+	pub const RESPONSE_CHUNK: i32 = -3;
+
+	/// The service ID used for stream messages that announce the compression algorithms supported by a transport.
+	///
+	/// The body is a list of algorithm identifiers, one byte each, in order of preference.
+	/// These messages are consumed by the transport and never delivered to the application.
+	pub const COMPRESSION: i32 = -4;
+
+	/// The service ID used for requests that negotiate the interface version.
+	///
+	/// See the [`negotiation`][crate::negotiation] module for the format of the request and response body.
+	pub const NEGOTIATE_VERSION: i32 = -5;
+
+	/// The service ID used for request updates that carry the sequence number of a sequenced request.
+	///
+	/// See the [`exactly_once`][crate::exactly_once] module for details.
+	pub const SEQUENCE: i32 = -6;
+
+	/// The service ID used for requests that query which sequenced requests have been processed.
+	///
+	/// See the [`exactly_once`][crate::exactly_once] module for details.
+	pub const QUERY_PROCESSED: i32 = -7;
+
+	/// The service ID used for responses that carry a structured service error.
+	///
+	/// The body is the error value, encoded with the same format as regular response bodies.
+	/// Generated clients decode it as the error type declared for the service.
+	/// [`Message::check_error_response()`][crate::Message::check_error_response] turns these responses into a [service error][crate::Error::service_error],
+	/// so check for this service ID first if the service has an error type.
+	pub const SERVICE_ERROR: i32 = -8;
+
+	/// The service ID used for requests that subscribe to a publish/subscribe topic.
+	///
+	/// See the [`pubsub`][crate::pubsub] module for details.
+	pub const SUBSCRIBE: i32 = -9;
+
+	/// The service ID used for requests that unsubscribe from a publish/subscribe topic.
+	///
+	/// See the [`pubsub`][crate::pubsub] module for details.
+	pub const UNSUBSCRIBE: i32 = -10;
+
+	/// The service ID used for requests that look up the ID of a publish/subscribe topic.
+	///
+	/// See the [`pubsub`][crate::pubsub] module for details.
+	pub const TOPIC: i32 = -11;
+
+	/// The service ID used for stream messages that are published to a publish/subscribe topic.
+	///
+	/// See the [`pubsub`][crate::pubsub] module for details.
+	pub const PUBLISH: i32 = -12;
+
+	/// The service ID used for stream messages that carry the deadline of a request.
+	///
+	/// The message is sent right before the request it applies to, and has the same request ID as the request.
+	/// The body is the time remaining until the deadline, in milliseconds, as a UTF-8 decimal integer.
+	/// These messages are consumed by the peer and never delivered to the application.
+	pub const DEADLINE: i32 = -13;
+
+	/// The service ID used for stream messages that carry the trace ID of a request.
+	///
+	/// The message is sent right before the request it applies to, and has the same request ID as the request.
+	/// The body is the trace ID as 16 hexadecimal digits in UTF-8.
+	/// These messages are consumed by the peer and never delivered to the application.
+	///
+	/// Trace IDs are only sent to peers that announced support for them with a [`TRACE_ID_SUPPORT`] message.
+	pub const TRACE_ID: i32 = -14;
+
+	/// The service ID used for stream messages that announce support for header flags.
+	///
+	/// The body is empty.
+	/// After a peer received the announcement, it sends the flags of outgoing messages to the remote peer.
+	/// These messages are consumed by the peer and never delivered to the application.
+	///
+	/// See [`MessageHeader::flags`][crate::MessageHeader::flags] for more details.
+	pub const HEADER_FLAGS: i32 = -15;
+
+	/// The service ID used for error responses that carry a numeric error code.
+	///
+	/// The body is a UTF-8 string with the error code as a decimal integer,
+	/// optionally followed by a colon and a structured payload encoded as lowercase hexadecimal string.
+	/// That may be followed by a single space and a human readable error message.
+	/// For example: `42:0a0b camera busy`.
+	///
+	/// The payload is a message body encoded with the format of the interface.
+	/// See [`Error::coded_remote_error()`][crate::Error::coded_remote_error] for more details.
+	pub const CODED_ERROR: i32 = -16;
+
+	/// The service ID used for stream messages that carry the type fingerprint of a request.
+	///
+	/// The message is sent right before the request it applies to, and has the same request ID as the request.
+	/// The body is the fingerprint as 16 hexadecimal digits in UTF-8.
+	/// These messages are consumed by the peer and never delivered to the application.
+	///
+	/// See [`ReceivedRequestHandle::type_fingerprint()`][crate::ReceivedRequestHandle::type_fingerprint] for more details.
+	pub const TYPE_FINGERPRINT: i32 = -17;
+
+	/// The service ID used for heartbeat stream messages.
+	///
+	/// The body is empty and the request ID is 0.
+	/// Unlike most other reserved stream messages, heartbeats are delivered to the application.
+	/// Generated servers of interfaces with the `#[heartbeat]` attribute consume them.
+	///
+	/// See the [`heartbeat`][crate::heartbeat] module for more details.
+	pub const HEARTBEAT: i32 = -18;
+
+	/// The service ID used for stream messages that announce support for trace IDs.
+	///
+	/// The body is empty and the request ID is 0.
+	/// After a peer received the announcement, it sends [`TRACE_ID`] messages to the remote peer.
+	/// These messages are consumed by the peer and never delivered to the application.
+	pub const TRACE_ID_SUPPORT: i32 = -19;
+}
+
+/// Allocation of the bits of [`MessageHeader::flags`].
+///
+/// The lower 8 bits are reserved for protocol extensions of this library.
+/// The upper 8 bits are free for applications.
+pub mod header_flags {
+	/// The flag bits reserved for protocol extensions of this library.
+	pub const RESERVED: u16 = 0x00FF;
+
+	/// The flag bits that applications can use for their own purposes.
+	pub const APPLICATION: u16 = 0xFF00;
 }
 
 /// A complete RPC message, including header and body.
@@ -55,6 +213,38 @@ impl<Body> Message<Body> {
 		Self { header, body }
 	}
 
+	/// Clone the message in constant time, sharing the body data with the original message.
+	///
+	/// This is only available for body types that implement [`SharedBody`].
+	pub fn clone_shared(&self) -> Self
+	where
+		Body: SharedBody,
+	{
+		Self::new(self.header, self.body.clone_shared())
+	}
+
+	/// Get the flags of the message header.
+	///
+	/// See [`MessageHeader::flags`] for more details.
+	pub fn flags(&self) -> u16 {
+		self.header.flags
+	}
+
+	/// Set the flags of the message header.
+	///
+	/// See [`MessageHeader::flags`] for more details.
+	pub fn set_flags(&mut self, flags: u16) {
+		self.header.flags = flags;
+	}
+
+	/// Set the flags of the message header.
+	///
+	/// See [`MessageHeader::flags`] for more details.
+	pub fn with_flags(mut self, flags: u16) -> Self {
+		self.header.flags = flags;
+		self
+	}
+
 	/// Create a new request message.
 	pub fn request(request_id: u32, service_id: i32, body: Body) -> Self {
 		Self::new(MessageHeader::request(request_id, service_id), body)
@@ -73,6 +263,116 @@ impl<Body> Message<Body> {
 		Self::new(MessageHeader::response(request_id, service_id::ERROR), Body::from_error(message))
 	}
 
+	/// Create a new error response message with an error code and an optional encoded payload.
+	///
+	/// See [`service_id::CODED_ERROR`] for the format of the message body.
+	pub fn coded_error_response(request_id: u32, code: i32, message: &str, payload: Option<&[u8]>) -> Self
+	where
+		Body: crate::Body,
+	{
+		let mut body = code.to_string();
+		if let Some(payload) = payload {
+			body.push(':');
+			for byte in payload {
+				body.push_str(&format!("{byte:02x}"));
+			}
+		}
+		if !message.is_empty() {
+			body.push(' ');
+			body.push_str(message);
+		}
+		Self::new(MessageHeader::coded_error_response(request_id), Body::from_error(&body))
+	}
+
+	/// Create a new response message indicating that the service is temporarily unavailable.
+	///
+	/// See [`service_id::RETRY_AFTER`] for the format of the message body.
+	pub fn retry_after_response(request_id: u32, retry_after: Duration, message: &str) -> Self
+	where
+		Body: crate::Body,
+	{
+		let retry_after = retry_after.as_millis();
+		let body = match message.is_empty() {
+			true => Body::from_error(&retry_after.to_string()),
+			false => Body::from_error(&format!("{retry_after} {message}")),
+		};
+		Self::new(MessageHeader::retry_after_response(request_id), body)
+	}
+
+	/// Create a new message that carries the deadline of the request with the given ID.
+	///
+	/// See [`service_id::DEADLINE`] for the format of the message.
+	/// The remaining time is sent with millisecond precision.
+	pub fn deadline(request_id: u32, remaining: Duration) -> Self
+	where
+		Body: crate::Body,
+	{
+		Self::new(MessageHeader::stream(request_id, service_id::DEADLINE), Body::from_error(&remaining.as_millis().to_string()))
+	}
+
+	/// Parse the remaining time of a deadline message.
+	///
+	/// See [`service_id::DEADLINE`] for the format of the message.
+	pub fn parse_deadline(&self) -> Result<Duration, Error>
+	where
+		Body: crate::Body,
+	{
+		let body = self.body
+			.as_error()
+			.map_err(|e| Error::decode_failed(Box::new(e)))?;
+		let remaining: u64 = body.parse()
+			.map_err(|e| Error::decode_failed(Box::new(e)))?;
+		Ok(Duration::from_millis(remaining))
+	}
+
+	/// Create a new message that carries the trace ID of the request with the given ID.
+	///
+	/// See [`service_id::TRACE_ID`] for the format of the message.
+	pub fn trace_id(request_id: u32, trace_id: u64) -> Self
+	where
+		Body: crate::Body,
+	{
+		Self::new(MessageHeader::stream(request_id, service_id::TRACE_ID), Body::from_error(&format!("{:016x}", trace_id)))
+	}
+
+	/// Parse the trace ID of a trace ID message.
+	///
+	/// See [`service_id::TRACE_ID`] for the format of the message.
+	pub fn parse_trace_id(&self) -> Result<u64, Error>
+	where
+		Body: crate::Body,
+	{
+		let body = self.body
+			.as_error()
+			.map_err(|e| Error::decode_failed(Box::new(e)))?;
+		u64::from_str_radix(body, 16)
+			.map_err(|e| Error::decode_failed(Box::new(e)))
+	}
+
+	/// Create a new message that carries the type fingerprint of the request with the given ID.
+	///
+	/// See [`service_id::TYPE_FINGERPRINT`] for the format of the message.
+	pub fn type_fingerprint(request_id: u32, fingerprint: u64) -> Self
+	where
+		Body: crate::Body,
+	{
+		Self::new(MessageHeader::stream(request_id, service_id::TYPE_FINGERPRINT), Body::from_error(&format!("{:016x}", fingerprint)))
+	}
+
+	/// Parse the fingerprint of a type fingerprint message.
+	///
+	/// See [`service_id::TYPE_FINGERPRINT`] for the format of the message.
+	pub fn parse_type_fingerprint(&self) -> Result<u64, Error>
+	where
+		Body: crate::Body,
+	{
+		let body = self.body
+			.as_error()
+			.map_err(|e| Error::decode_failed(Box::new(e)))?;
+		u64::from_str_radix(body, 16)
+			.map_err(|e| Error::decode_failed(Box::new(e)))
+	}
+
 	/// Create a new requester update message.
 	pub fn requester_update(request_id: u32, service_id: i32, body: Body) -> Self {
 		Self::new(MessageHeader::requester_update(request_id, service_id), body)
@@ -87,6 +387,64 @@ impl<Body> Message<Body> {
 	pub fn stream(request_id: u32, service_id: i32, body: Body) -> Self {
 		Self::new(MessageHeader::stream(request_id, service_id), body)
 	}
+
+	/// Create a new stream message that must be acknowledged by the remote peer.
+	pub fn acked_stream(ack_id: u32, service_id: i32, body: Body) -> Self {
+		Self::new(MessageHeader::acked_stream(ack_id, service_id), body)
+	}
+
+	/// Create a new acknowledgement for an acknowledged stream message.
+	pub fn stream_ack(ack_id: u32, service_id: i32) -> Self
+	where
+		Body: crate::Body,
+	{
+		Self::new(MessageHeader::stream_ack(ack_id, service_id), Body::empty())
+	}
+
+	/// Convert error responses into an [`Error`].
+	///
+	/// Regular error responses are converted into a [remote error][Error::remote_error],
+	/// error responses with an error code into a [coded remote error][Error::coded_remote_error],
+	/// retry-after responses into a [retry-after error][Error::retry_after],
+	/// and structured service errors into a [service error][Error::service_error] that holds the raw body.
+	/// Other messages are returned unmodified.
+	pub fn check_error_response(self) -> Result<Self, Error>
+	where
+		Body: crate::Body,
+	{
+		match self.header.service_id {
+			service_id::ERROR => {
+				let message = self.body
+					.into_error()
+					.map_err(|e| Error::decode_failed(Box::new(e)))?;
+				Err(Error::remote_error(message))
+			},
+			service_id::CODED_ERROR => {
+				let body = self.body
+					.into_error()
+					.map_err(|e| Error::decode_failed(Box::new(e)))?;
+				let (code, message) = body.split_once(' ').unwrap_or((&body, ""));
+				let (code, payload) = match code.split_once(':') {
+					Some((code, payload)) => (code, Some(decode_hex(payload)?)),
+					None => (code, None),
+				};
+				let code: i32 = code.parse()
+					.map_err(|e| Error::decode_failed(Box::new(e)))?;
+				Err(Error::coded_remote_error(code, message.into(), payload))
+			},
+			service_id::RETRY_AFTER => {
+				let body = self.body
+					.into_error()
+					.map_err(|e| Error::decode_failed(Box::new(e)))?;
+				let (retry_after, message) = body.split_once(' ').unwrap_or((&body, ""));
+				let retry_after: u64 = retry_after.parse()
+					.map_err(|e| Error::decode_failed(Box::new(e)))?;
+				Err(Error::retry_after(Duration::from_millis(retry_after), message.into()))
+			},
+			service_id::SERVICE_ERROR => Err(Error::service_error(self.body)),
+			_ => Ok(self),
+		}
+	}
 }
 
 /// The type of a message.
@@ -107,6 +465,16 @@ pub enum MessageType {
 
 	/// A stream message that is sent outside of the context of a request.
 	Stream = 4,
+
+	/// A stream message that must be acknowledged by the remote peer.
+	///
+	/// The request ID field holds an ID chosen by the sender to match the acknowledgement.
+	AckedStream = 5,
+
+	/// The acknowledgement of an [`Self::AckedStream`] message.
+	///
+	/// The request ID and service ID are copied from the acknowledged message.
+	StreamAck = 6,
 }
 
 impl MessageType {
@@ -118,6 +486,8 @@ impl MessageType {
 			2 => Ok(Self::RequesterUpdate),
 			3 => Ok(Self::ResponderUpdate),
 			4 => Ok(Self::Stream),
+			5 => Ok(Self::AckedStream),
+			6 => Ok(Self::StreamAck),
 			value => Err(InnerError::InvalidMessageType { value }.into()),
 		}
 	}
@@ -146,6 +516,16 @@ impl MessageType {
 	pub fn is_stream(self) -> bool {
 		self == MessageType::Stream
 	}
+
+	/// Check if this message type is [`Self::AckedStream`].
+	pub fn is_acked_stream(self) -> bool {
+		self == MessageType::AckedStream
+	}
+
+	/// Check if this message type is [`Self::StreamAck`].
+	pub fn is_stream_ack(self) -> bool {
+		self == MessageType::StreamAck
+	}
 }
 
 /// A message header.
@@ -168,6 +548,18 @@ pub struct MessageHeader {
 	///
 	/// For update messages this indicates the type of update.
 	pub service_id: i32,
+
+	/// Flags for optional protocol extensions.
+	///
+	/// The flags are encoded in the upper 16 bits of the message type field.
+	/// They are only sent to remote peers that announced support for them,
+	/// see [`Peer::with_header_flags()`][crate::Peer::with_header_flags].
+	/// Peers must ignore flags they do not know.
+	///
+	/// Flags can be set on outgoing messages with [`PeerWriteHandle::send_stream_with_flags()`][crate::PeerWriteHandle::send_stream_with_flags],
+	/// or with an [`Interceptor`][crate::Interceptor].
+	/// See the [`header_flags`] module for the allocation of the flag bits.
+	pub flags: u16,
 }
 
 impl MessageHeader {
@@ -177,6 +569,7 @@ impl MessageHeader {
 			message_type: MessageType::Request,
 			request_id,
 			service_id,
+			flags: 0,
 		}
 	}
 
@@ -186,6 +579,7 @@ impl MessageHeader {
 			message_type: MessageType::Response,
 			request_id,
 			service_id,
+			flags: 0,
 		}
 	}
 
@@ -194,12 +588,23 @@ impl MessageHeader {
 		Self::response(request_id, service_id::ERROR)
 	}
 
+	/// Create a new coded error response message header.
+	pub fn coded_error_response(request_id: u32) -> Self {
+		Self::response(request_id, service_id::CODED_ERROR)
+	}
+
+	/// Create a new retry-after response message header.
+	pub fn retry_after_response(request_id: u32) -> Self {
+		Self::response(request_id, service_id::RETRY_AFTER)
+	}
+
 	/// Create a new requester update message header.
 	pub fn requester_update(request_id: u32, service_id: i32) -> Self {
 		Self {
 			message_type: MessageType::RequesterUpdate,
 			request_id,
 			service_id,
+			flags: 0,
 		}
 	}
 
@@ -209,6 +614,7 @@ impl MessageHeader {
 			message_type: MessageType::ResponderUpdate,
 			request_id,
 			service_id,
+			flags: 0,
 		}
 	}
 
@@ -218,9 +624,36 @@ impl MessageHeader {
 			message_type: MessageType::Stream,
 			request_id,
 			service_id,
+			flags: 0,
+		}
+	}
+
+	/// Create a new acknowledged stream message header.
+	pub fn acked_stream(ack_id: u32, service_id: i32) -> Self {
+		Self {
+			message_type: MessageType::AckedStream,
+			request_id: ack_id,
+			service_id,
+			flags: 0,
 		}
 	}
 
+	/// Create a new stream acknowledgement message header.
+	pub fn stream_ack(ack_id: u32, service_id: i32) -> Self {
+		Self {
+			message_type: MessageType::StreamAck,
+			request_id: ack_id,
+			service_id,
+			flags: 0,
+		}
+	}
+
+	/// Set the flags of the message header.
+	pub fn with_flags(mut self, flags: u16) -> Self {
+		self.flags = flags;
+		self
+	}
+
 	/// Decode a message header from a byte slice using the given endianness for the header fields.
 	///
 	/// The byte slice should NOT contain the message size.
@@ -232,11 +665,13 @@ impl MessageHeader {
 		let request_id = endian.read_u32(&buffer[4..]);
 		let service_id = endian.read_i32(&buffer[8..]);
 
-		let message_type = MessageType::from_u32(message_type)?;
+		let flags = (message_type >> 16) as u16;
+		let message_type = MessageType::from_u32(message_type & 0xFFFF)?;
 		Ok(Self {
 			message_type,
 			request_id,
 			service_id,
+			flags,
 		})
 	}
 
@@ -248,7 +683,7 @@ impl MessageHeader {
 	/// This function panics if the buffer is not large enough to hold a full header.
 	pub fn encode(&self, buffer: &mut [u8], endian: Endian) {
 		assert!(buffer.len() >= 12);
-		endian.write_u32(&mut buffer[0..], self.message_type as u32);
+		endian.write_u32(&mut buffer[0..], self.message_type as u32 | u32::from(self.flags) << 16);
 		endian.write_u32(&mut buffer[4..], self.request_id);
 		endian.write_i32(&mut buffer[8..], self.service_id);
 	}
@@ -261,3 +696,158 @@ impl<Body> std::fmt::Debug for Message<Body> {
 			.finish_non_exhaustive()
 	}
 }
+
+/// Decode the hexadecimal payload of a coded error response.
+fn decode_hex(data: &str) -> Result<Vec<u8>, Error> {
+	let invalid = || Error::decode_failed(Box::new(Error::custom(format!("invalid hexadecimal payload: {data:?}"))));
+	if data.len() % 2 != 0 {
+		return Err(invalid());
+	}
+	(0..data.len())
+		.step_by(2)
+		.map(|i| data.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()).ok_or_else(invalid))
+		.collect()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+
+	use crate::StreamBody;
+
+	#[test]
+	fn check_error_response() {
+		let message = Message::response(3, 10, StreamBody::from(&b"hello"[..]));
+		let_assert!(Ok(message) = message.check_error_response());
+		assert!(message.body.as_ref() == b"hello");
+
+		let message = Message::<StreamBody>::error_response(3, "oh no");
+		let_assert!(Err(e) = message.check_error_response());
+		assert!(e.as_remote_error() == Some("oh no"));
+		assert!(e.as_retry_after() == None);
+	}
+
+	#[test]
+	fn coded_error_response() {
+		let message = Message::<StreamBody>::coded_error_response(3, 42, "camera busy", Some(&[0x0a, 0xff]));
+		assert!(message.header == MessageHeader::response(3, service_id::CODED_ERROR));
+		assert!(message.body.as_ref() == b"42:0aff camera busy");
+		let_assert!(Err(e) = message.check_error_response());
+		assert!(e.remote_error_code() == Some(42));
+		assert!(e.remote_error_payload() == Some(&[0x0a, 0xff][..]));
+		assert!(e.as_remote_error() == Some("camera busy"));
+
+		let message = Message::<StreamBody>::coded_error_response(3, -7, "", None);
+		assert!(message.body.as_ref() == b"-7");
+		let_assert!(Err(e) = message.check_error_response());
+		assert!(e.remote_error_code() == Some(-7));
+		assert!(e.remote_error_payload() == None);
+		assert!(e.as_remote_error() == Some(""));
+
+		for body in [&b"busy"[..], b"42:0 busy", b"42:zz busy"] {
+			let message = Message::response(3, service_id::CODED_ERROR, StreamBody::from(body));
+			let_assert!(Err(e) = message.check_error_response());
+			assert!(e.remote_error_code() == None);
+		}
+	}
+
+	#[test]
+	fn retry_after_response() {
+		let message = Message::<StreamBody>::retry_after_response(3, Duration::from_millis(1500), "too busy");
+		assert!(message.header == MessageHeader::response(3, service_id::RETRY_AFTER));
+		assert!(message.body.as_ref() == b"1500 too busy");
+		let_assert!(Err(e) = message.check_error_response());
+		assert!(e.as_retry_after() == Some(Duration::from_millis(1500)));
+		assert!(e.as_remote_error() == Some("too busy"));
+
+		let message = Message::<StreamBody>::retry_after_response(3, Duration::from_secs(2), "");
+		assert!(message.body.as_ref() == b"2000");
+		let_assert!(Err(e) = message.check_error_response());
+		assert!(e.as_retry_after() == Some(Duration::from_secs(2)));
+		assert!(e.as_remote_error() == Some(""));
+
+		let message = Message::response(3, service_id::RETRY_AFTER, StreamBody::from(&b"soon"[..]));
+		let_assert!(Err(e) = message.check_error_response());
+		assert!(e.as_retry_after() == None);
+	}
+
+	#[test]
+	fn service_error_response() {
+		let message = Message::response(3, service_id::SERVICE_ERROR, StreamBody::from(&b"broken"[..]));
+		let_assert!(Err(e) = message.check_error_response());
+		assert!(e.is_remote_error());
+		assert!(e.as_remote_error() == Some("service error"));
+		let_assert!(Some(body) = e.service_error_body::<StreamBody>());
+		assert!(body.as_ref() == b"broken");
+		assert!(let None = e.service_error_body::<crate::UnixBody>());
+	}
+
+	#[test]
+	fn deadline() {
+		let message = Message::<StreamBody>::deadline(3, Duration::from_micros(1500));
+		assert!(message.header == MessageHeader::stream(3, service_id::DEADLINE));
+		assert!(message.body.as_ref() == b"1");
+		let_assert!(Ok(remaining) = message.parse_deadline());
+		assert!(remaining == Duration::from_millis(1));
+
+		let message = Message::<StreamBody>::deadline(3, Duration::from_micros(999));
+		let_assert!(Ok(remaining) = message.parse_deadline());
+		assert!(remaining == Duration::ZERO);
+
+		let message = Message::stream(3, service_id::DEADLINE, StreamBody::from(&b"soon"[..]));
+		assert!(let Err(_) = message.parse_deadline());
+	}
+
+	#[test]
+	fn trace_id() {
+		let message = Message::<StreamBody>::trace_id(3, 0x0123_4567_89ab_cdef);
+		assert!(message.header == MessageHeader::stream(3, service_id::TRACE_ID));
+		assert!(message.body.as_ref() == b"0123456789abcdef");
+		assert!(let Ok(0x0123_4567_89ab_cdef) = message.parse_trace_id());
+
+		let message = Message::stream(3, service_id::TRACE_ID, StreamBody::from(&b"trace"[..]));
+		assert!(let Err(_) = message.parse_trace_id());
+	}
+
+	#[test]
+	fn type_fingerprint() {
+		let message = Message::<StreamBody>::type_fingerprint(4, 0x00a1_b2c3_d4e5_f607);
+		assert!(message.header == MessageHeader::stream(4, service_id::TYPE_FINGERPRINT));
+		assert!(message.body.as_ref() == b"00a1b2c3d4e5f607");
+		assert!(let Ok(0x00a1_b2c3_d4e5_f607) = message.parse_type_fingerprint());
+
+		let message = Message::stream(4, service_id::TYPE_FINGERPRINT, StreamBody::from(&b"fingerprint"[..]));
+		assert!(let Err(_) = message.parse_type_fingerprint());
+	}
+
+	#[test]
+	fn header_flags() {
+		let header = MessageHeader::stream(3, 7).with_flags(0x0102);
+		let mut buffer = [0u8; HEADER_LEN as usize];
+		header.encode(&mut buffer, Endian::LittleEndian);
+		assert!(buffer[0..4] == [4, 0, 0x02, 0x01]);
+		let_assert!(Ok(decoded) = MessageHeader::decode(&buffer, Endian::LittleEndian));
+		assert!(decoded == header);
+		assert!(decoded.flags == 0x0102);
+
+		// The byte next to the message type is not part of the flags.
+		buffer[1] = 1;
+		assert!(let Err(_) = MessageHeader::decode(&buffer, Endian::LittleEndian));
+
+		let mut message = Message::stream(3, 7, StreamBody::from(&b"hello"[..])).with_flags(header_flags::APPLICATION);
+		assert!(message.flags() == 0xFF00);
+		message.set_flags(0);
+		assert!(message.header == MessageHeader::stream(3, 7));
+	}
+
+	#[test]
+	fn clone_shared() {
+		let message = Message::stream(0, 7, StreamBody::from(vec![1; 1 << 20]));
+		let copy = message.clone_shared();
+		assert!(copy.header == message.header);
+		assert!(copy.body.data.as_ptr() == message.body.data.as_ptr());
+		assert!(copy.body.as_ref() == message.body.as_ref());
+	}
+}
@@ -0,0 +1,392 @@
+//! Version negotiation for interfaces.
+//!
+//! Interfaces generated with the [`interface!`][crate::interface] macro have a version hash.
+//! The hash is computed from the name of the interface and the names, service IDs and body types of all services, updates and streams.
+//! Any change to the interface definition, except for documentation, results in a different hash.
+//!
+//! A client can send a version negotiation request to the server with the generated `Client::negotiate_version()` function.
+//! The generated `Server` answers these requests automatically.
+//! If the interface name or version hash of the server does not match the client, the negotiation fails with a descriptive error.
+//!
+//! Negotiating the version right after connecting turns a mismatch between client and server
+//! into a clear error, instead of failures for individual requests later on.
+//!
+//! The negotiation can also be used as handshake to exchange application data, like authentication tokens or client version strings.
+//! Both sides can attach a [`HandshakePayload`] that is delivered to the other side together with the version.
+//! The generated `Client::negotiate_version_with_payload()` function sends a payload and returns the [`Negotiation`] with the payload of the server.
+//! The generated `Server` sends the payload set with `Server::set_handshake_payload()`,
+//! and makes the payload of the client available with `Server::remote_handshake_payload()`.
+//!
+//! The negotiation request has service ID [`service_id::NEGOTIATE_VERSION`].
+//! The request and response body are a UTF-8 string with the interface name and the version hash as 16 hexadecimal digits, separated by a space.
+//! The version is followed by a random nonce of the sender as 32 hexadecimal digits on a new line.
+//! If a payload is attached, it follows the nonce on another new line.
+//!
+//! # Security
+//! Each side picks a fresh random nonce for every negotiation, so no two negotiations have the same transcript.
+//! Both sides compute the [transcript hash][Negotiation::transcript_hash]: a SHA-256 hash of the request and response body,
+//! which includes the versions, the nonces and the handshake payloads.
+//! The transcript hash binds the payloads to the negotiation they were sent in, and can be recorded in audit logs.
+//!
+//! The handshake payloads themselves are still sent as plain text.
+//! Without an encrypted and authenticated transport, a man-in-the-middle can read and reuse a secret that is sent as payload.
+//! To protect a shared secret against replay, do not send the secret itself.
+//! Instead, let the client prove knowledge of the secret after the negotiation,
+//! for example with an HMAC of the transcript hash that the server verifies with its own transcript hash.
+//! A recorded proof is useless on another connection, because the nonce of the server is different.
+//! Otherwise, only send secrets over a transport that protects them, like the [`QuicTransport`][crate::QuicTransport],
+//! or a TCP connection tunneled through TLS or a VPN.
+
+use sha2::{Digest, Sha256};
+
+use crate::{service_id, Error, PeerWriteHandle, ReceivedRequestHandle};
+
+/// The length of the nonces in bytes.
+pub const NONCE_LEN: usize = 16;
+
+/// The length of the transcript hash in bytes.
+pub const TRANSCRIPT_HASH_LEN: usize = 32;
+
+/// Domain separator for the transcript hash.
+const TRANSCRIPT_CONTEXT: &[u8] = b"fizyr-rpc version negotiation v1";
+
+/// Compute the version hash of an interface from a textual signature.
+///
+/// This is used by the [`interface!`][crate::interface] macro.
+/// The hash is a 64 bit FNV-1a hash of the signature.
+pub const fn interface_hash(signature: &str) -> u64 {
+	let bytes = signature.as_bytes();
+	let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+	let mut i = 0;
+	while i < bytes.len() {
+		hash ^= bytes[i] as u64;
+		hash = hash.wrapping_mul(0x0100_0000_01b3);
+		i += 1;
+	}
+	hash
+}
+
+/// Application data that can be attached to the version negotiation.
+///
+/// The payload is sent as text, so it can be used with any message body type.
+pub trait HandshakePayload: Sized {
+	/// Encode the payload as text.
+	fn encode_payload(&self) -> String;
+
+	/// Decode the payload from text.
+	fn decode_payload(payload: &str) -> Result<Self, Error>;
+}
+
+impl HandshakePayload for String {
+	fn encode_payload(&self) -> String {
+		self.clone()
+	}
+
+	fn decode_payload(payload: &str) -> Result<Self, Error> {
+		Ok(payload.into())
+	}
+}
+
+/// The result of a successful version negotiation.
+///
+/// See the [module documentation][self] for the security properties of the negotiation.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Negotiation {
+	/// The nonce of the client.
+	client_nonce: [u8; NONCE_LEN],
+
+	/// The nonce of the server.
+	server_nonce: [u8; NONCE_LEN],
+
+	/// The encoded handshake payload of the remote peer, if it sent one.
+	remote_payload: Option<String>,
+
+	/// The hash of the request and response body.
+	transcript_hash: [u8; TRANSCRIPT_HASH_LEN],
+}
+
+impl Negotiation {
+	/// Get the random nonce that the client sent in the negotiation request.
+	pub fn client_nonce(&self) -> &[u8; NONCE_LEN] {
+		&self.client_nonce
+	}
+
+	/// Get the random nonce that the server sent in the negotiation response.
+	pub fn server_nonce(&self) -> &[u8; NONCE_LEN] {
+		&self.server_nonce
+	}
+
+	/// Get the encoded handshake payload of the remote peer, if it sent one.
+	pub fn raw_remote_payload(&self) -> Option<&str> {
+		self.remote_payload.as_deref()
+	}
+
+	/// Decode the handshake payload of the remote peer.
+	///
+	/// Returns `Ok(None)` if the remote peer did not send a payload.
+	pub fn remote_payload<P: HandshakePayload>(&self) -> Result<Option<P>, Error> {
+		self.remote_payload.as_deref().map(P::decode_payload).transpose()
+	}
+
+	/// Get the SHA-256 hash of the transcript of the negotiation.
+	///
+	/// The transcript consists of the request and response body, including the versions, nonces and payloads of both sides.
+	/// The client and the server compute the same hash, unless the messages were modified in transit.
+	pub fn transcript_hash(&self) -> &[u8; TRANSCRIPT_HASH_LEN] {
+		&self.transcript_hash
+	}
+}
+
+/// Negotiate the interface version with the remote peer, acting as the client.
+///
+/// Returns an error if the remote peer does not implement the same version of the interface.
+pub async fn negotiate_version<Body: crate::Body>(peer: &PeerWriteHandle<Body>, name: &str, hash: u64) -> Result<(), Error> {
+	negotiate_version_with_payload(peer, name, hash, None).await?;
+	Ok(())
+}
+
+/// Negotiate the interface version with the remote peer and exchange handshake payloads, acting as the client.
+///
+/// The encoded `payload` is sent to the remote peer together with the version.
+/// On success, the [`Negotiation`] is returned, with the encoded payload of the remote peer and the transcript hash.
+///
+/// Returns an error if the remote peer does not implement the same version of the interface.
+pub async fn negotiate_version_with_payload<Body: crate::Body>(peer: &PeerWriteHandle<Body>, name: &str, hash: u64, payload: Option<&str>) -> Result<Negotiation, Error> {
+	let local = encode_version(name, hash);
+	let client_nonce = random_nonce()?;
+	let request_body = encode_body(&local, &client_nonce, payload);
+	let mut request = peer.send_request(service_id::NEGOTIATE_VERSION, Body::from_error(&request_body)).await?;
+	let response = request.recv_response().await?.check_error_response()?;
+
+	let response_body = response.body.as_error()
+		.map_err(|_| Error::custom(String::from("received invalid version negotiation response: body is not valid UTF-8")))?;
+	let (remote, server_nonce, remote_payload) = decode_body(response_body);
+	if remote != local {
+		return Err(Error::custom(format!("interface version mismatch: server implements {}, client uses {}", remote, local)));
+	}
+	let server_nonce = server_nonce.ok_or_else(|| Error::custom(String::from("received invalid version negotiation response: invalid nonce")))?;
+	Ok(Negotiation {
+		client_nonce,
+		server_nonce,
+		remote_payload: remote_payload.map(String::from),
+		transcript_hash: transcript_hash(&request_body, response_body),
+	})
+}
+
+/// Respond to a version negotiation request from the remote peer, acting as the server.
+///
+/// If the remote peer uses a different interface or version, an error response is sent.
+/// The returned error only indicates a failure to send the response.
+pub async fn respond_to_negotiation<Body: crate::Body>(request: ReceivedRequestHandle<Body>, body: Body, name: &str, hash: u64) -> Result<(), Error> {
+	respond_to_negotiation_with_payload(request, body, name, hash, None).await?;
+	Ok(())
+}
+
+/// Respond to a version negotiation request from the remote peer and exchange handshake payloads, acting as the server.
+///
+/// If the version matches, the encoded `payload` is sent in the response,
+/// and the [`Negotiation`] is returned, with the encoded payload of the remote peer and the transcript hash.
+/// If the remote peer uses a different interface or version, an error response is sent and `None` is returned.
+/// The returned error indicates a failure to send the response or to generate a nonce.
+pub async fn respond_to_negotiation_with_payload<Body: crate::Body>(
+	request: ReceivedRequestHandle<Body>,
+	body: Body,
+	name: &str,
+	hash: u64,
+	payload: Option<&str>,
+) -> Result<Option<Negotiation>, Error> {
+	let local = encode_version(name, hash);
+	let request_body = match body.as_error() {
+		Ok(x) => x,
+		Err(_) => {
+			request.send_error_response("invalid version negotiation request: body is not valid UTF-8").await?;
+			return Ok(None);
+		},
+	};
+
+	let (remote, client_nonce, remote_payload) = decode_body(request_body);
+	if remote != local {
+		let message = format!("interface version mismatch: server implements {}, client uses {}", local, remote);
+		request.send_error_response(&message).await?;
+		return Ok(None);
+	}
+	let Some(client_nonce) = client_nonce else {
+		request.send_error_response("invalid version negotiation request: invalid nonce").await?;
+		return Ok(None);
+	};
+	let server_nonce = match random_nonce() {
+		Ok(x) => x,
+		Err(e) => {
+			request.send_error_response("failed to generate nonce for version negotiation").await?;
+			return Err(e);
+		},
+	};
+
+	let response_body = encode_body(&local, &server_nonce, payload);
+	request.send_response(service_id::NEGOTIATE_VERSION, Body::from_error(&response_body)).await?;
+	Ok(Some(Negotiation {
+		client_nonce,
+		server_nonce,
+		remote_payload: remote_payload.map(String::from),
+		transcript_hash: transcript_hash(request_body, &response_body),
+	}))
+}
+
+/// Encode an interface name and version hash.
+fn encode_version(name: &str, hash: u64) -> String {
+	format!("{} {:016x}", name, hash)
+}
+
+/// Encode the body of a negotiation message with an encoded version, a nonce and an optional payload.
+fn encode_body(version: &str, nonce: &[u8; NONCE_LEN], payload: Option<&str>) -> String {
+	let nonce = encode_hex(nonce);
+	match payload {
+		Some(payload) => format!("{}\n{}\n{}", version, nonce, payload),
+		None => format!("{}\n{}", version, nonce),
+	}
+}
+
+/// Split the body of a negotiation message in the encoded version, the nonce and the optional payload.
+///
+/// The nonce is `None` if it is missing or invalid.
+fn decode_body(body: &str) -> (&str, Option<[u8; NONCE_LEN]>, Option<&str>) {
+	let mut parts = body.splitn(3, '\n');
+	let version = parts.next().unwrap_or_default();
+	let nonce = parts.next().and_then(decode_nonce);
+	(version, nonce, parts.next())
+}
+
+/// Generate a random nonce.
+fn random_nonce() -> Result<[u8; NONCE_LEN], Error> {
+	let mut nonce = [0; NONCE_LEN];
+	getrandom::getrandom(&mut nonce)
+		.map_err(|e| Error::custom(format!("failed to generate nonce: {e}")))?;
+	Ok(nonce)
+}
+
+/// Encode bytes as lowercase hexadecimal digits.
+fn encode_hex(data: &[u8]) -> String {
+	data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Decode a nonce from hexadecimal digits.
+fn decode_nonce(input: &str) -> Option<[u8; NONCE_LEN]> {
+	if input.len() != 2 * NONCE_LEN || !input.is_ascii() {
+		return None;
+	}
+	let mut nonce = [0; NONCE_LEN];
+	for (byte, digits) in nonce.iter_mut().zip(input.as_bytes().chunks(2)) {
+		let digits = std::str::from_utf8(digits).ok()?;
+		*byte = u8::from_str_radix(digits, 16).ok()?;
+	}
+	Some(nonce)
+}
+
+/// Compute the transcript hash of a negotiation from the request and response body.
+fn transcript_hash(request_body: &str, response_body: &str) -> [u8; TRANSCRIPT_HASH_LEN] {
+	let mut hasher = Sha256::new();
+	for part in [TRANSCRIPT_CONTEXT, request_body.as_bytes(), response_body.as_bytes()] {
+		hasher.update((part.len() as u64).to_be_bytes());
+		hasher.update(part);
+	}
+	hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+
+	use crate::{ReceivedMessage, UnixStreamPeer, UnixStreamTransport};
+
+	#[test]
+	fn hash_is_fnv1a() {
+		assert!(interface_hash("") == 0xcbf2_9ce4_8422_2325);
+		assert!(interface_hash("a") == 0xaf63_dc4c_8601_ec8c);
+		assert!(interface_hash("interface Camera") != interface_hash("interface Camera2"));
+	}
+
+	#[tokio::test]
+	async fn negotiate() {
+		let_assert!(Ok((peer_a, peer_b)) = tokio::net::UnixStream::pair());
+		let (_read_a, write_a) = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, Default::default())).split();
+		let mut peer_b = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default()));
+
+		let server = tokio::spawn(async move {
+			for hash in [1, 2] {
+				let_assert!(Ok(ReceivedMessage::Request(request, body)) = peer_b.recv_message().await);
+				assert!(request.service_id() == service_id::NEGOTIATE_VERSION);
+				assert!(let Ok(()) = respond_to_negotiation(request, body, "Camera", hash).await);
+			}
+		});
+
+		assert!(let Ok(()) = negotiate_version(&write_a, "Camera", 1).await);
+		let_assert!(Err(e) = negotiate_version(&write_a, "Camera", 1).await);
+		assert!(e.as_remote_error() == Some("interface version mismatch: server implements Camera 0000000000000002, client uses Camera 0000000000000001"));
+		assert!(let Ok(()) = server.await);
+	}
+
+	#[tokio::test]
+	async fn negotiate_with_payload() {
+		let_assert!(Ok((peer_a, peer_b)) = tokio::net::UnixStream::pair());
+		let (_read_a, write_a) = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, Default::default())).split();
+		let mut peer_b = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default()));
+
+		let server = tokio::spawn(async move {
+			let mut negotiations = Vec::new();
+			for payload in [Some("server 1.0"), None] {
+				let_assert!(Ok(ReceivedMessage::Request(request, body)) = peer_b.recv_message().await);
+				let_assert!(Ok(Some(negotiation)) = respond_to_negotiation_with_payload(request, body, "Camera", 1, payload).await);
+				negotiations.push(negotiation);
+			}
+			negotiations
+		});
+
+		let_assert!(Ok(first) = negotiate_version_with_payload(&write_a, "Camera", 1, Some("token\nwith newline")).await);
+		assert!(first.raw_remote_payload() == Some("server 1.0"));
+		let_assert!(Ok(second) = negotiate_version_with_payload(&write_a, "Camera", 1, None).await);
+		assert!(second.raw_remote_payload() == None);
+
+		// Both sides agree on the nonces and the transcript, but every negotiation is unique.
+		let_assert!(Ok(negotiations) = server.await);
+		let_assert!([server_first, server_second] = negotiations.as_slice());
+		assert!(server_first.raw_remote_payload() == Some("token\nwith newline"));
+		assert!(server_second.raw_remote_payload() == None);
+		for (client, server) in [(&first, server_first), (&second, server_second)] {
+			assert!(client.client_nonce() == server.client_nonce());
+			assert!(client.server_nonce() == server.server_nonce());
+			assert!(client.transcript_hash() == server.transcript_hash());
+		}
+		assert!(first.client_nonce() != second.client_nonce());
+		assert!(first.server_nonce() != second.server_nonce());
+		assert!(first.transcript_hash() != second.transcript_hash());
+	}
+
+	#[tokio::test]
+	async fn reject_missing_nonce() {
+		let_assert!(Ok((peer_a, peer_b)) = tokio::net::UnixStream::pair());
+		let (_read_a, write_a) = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, Default::default())).split();
+		let mut peer_b = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default()));
+
+		let server = tokio::spawn(async move {
+			let_assert!(Ok(ReceivedMessage::Request(request, body)) = peer_b.recv_message().await);
+			let_assert!(Ok(None) = respond_to_negotiation_with_payload(request, body, "Camera", 1, None).await);
+		});
+
+		let version = encode_version("Camera", 1);
+		let_assert!(Ok(mut request) = write_a.send_request(service_id::NEGOTIATE_VERSION, format!("{version}\ntoken").as_bytes()).await);
+		let_assert!(Ok(response) = request.recv_response().await);
+		let_assert!(Err(e) = response.check_error_response());
+		assert!(e.as_remote_error() == Some("invalid version negotiation request: invalid nonce"));
+		assert!(let Ok(()) = server.await);
+	}
+
+	#[test]
+	fn transcript_hash_covers_both_bodies() {
+		let hash = transcript_hash("a\nb", "c");
+		assert!(hash != transcript_hash("a", "b\nc"));
+		assert!(hash != transcript_hash("c", "a\nb"));
+		assert!(hash == transcript_hash("a\nb", "c"));
+	}
+}
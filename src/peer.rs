@@ -1,25 +1,183 @@
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::{Duration, Instant};
 use tokio::sync::{mpsc, oneshot};
 
 use crate::{
 	util,
+	Annotations,
+	ConnectionContext,
+	ConnectionLabels,
+	EgressPolicy,
 	Error,
+	HeaderPolicy,
+	Interceptor,
 	Message,
 	PeerHandle,
 	ReceivedMessage,
 	SentRequestHandle,
+	TrackerAudit,
 };
+use crate::error::private::{bad_request_message, truncate_error_message, write_half_finished, InnerError, INCOMING_QUEUE_FULL_MESSAGE, REQUEST_EXPIRED_MESSAGE};
 use crate::request_tracker::RequestTracker;
+use crate::runtime::{Runtime, TokioRuntime};
+use crate::stats::StatsCounters;
+use crate::util::channel::{self, SendError};
 use crate::util::{select, Either};
 
 /// Message for the internal peer command loop.
 pub enum Command<Body> {
 	SendRequest(SendRequest<Body>),
+	SendOneshotRequest(SendOneshotRequest<Body>),
 	SendRawMessage(SendRawMessage<Body>),
+	SendAckedStream(SendAckedStream<Body>),
+	SendStreamBatch(SendStreamBatch<Body>),
 	ProcessReceivedMessage(ProcessReceivedMessage<Body>),
+	Flush(Flush),
+	Finish(Finish),
+	Audit(Audit),
 	Stop,
-	UnregisterReadHandle,
-	RegisterWriteHandle,
-	UnregisterWriteHandle,
+}
+
+/// Shared state used by the handles to control the peer loop.
+///
+/// These are kept out of the command channel, so that they keep working when the channel is full.
+#[derive(Clone)]
+pub(crate) struct PeerControl {
+	/// Channel to ask the peer loop to stop.
+	///
+	/// A full channel means that a stop was already requested.
+	pub stop_tx: mpsc::Sender<()>,
+
+	/// Channel that is never used, but it is closed when all read and write handles are dropped.
+	pub _alive_tx: mpsc::Sender<()>,
+
+	/// The policy to check outgoing messages against, shared with the peer loop.
+	pub egress_policy: Arc<Mutex<Option<Box<dyn EgressPolicy>>>>,
+
+	/// The labels of the connection, shared with the peer.
+	pub labels: Arc<Mutex<ConnectionLabels>>,
+
+	/// The application context of the connection, shared with the peer.
+	pub context: Arc<Mutex<ConnectionContext>>,
+
+	/// The connection ID of the peer.
+	pub connection_id: u64,
+}
+
+/// The interval of the periodic audit of the request tracker in debug builds.
+#[cfg(all(debug_assertions, feature = "tracing"))]
+const AUDIT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The connection ID for the next peer.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Capacities of the internal queues of a peer.
+///
+/// The capacities are only used with the `strict-memory` feature.
+/// Without that feature, all internal queues are unbounded.
+///
+/// With the `strict-memory` feature, operations never wait for room in a full queue.
+/// Instead, they fail with an error for which [`Error::is_capacity_exceeded()`] returns true.
+/// Together with the maximum body size of the transport, this puts an upper bound on the memory used for queued messages.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct ChannelCapacities {
+	/// The maximum number of queued commands from the handles to the peer loop, like messages to send.
+	///
+	/// Messages read from the transport are queued separately with the same capacity,
+	/// and take turns with other commands.
+	/// If that queue is full, the peer stops reading from the transport until there is room again.
+	///
+	/// Stream messages held back by a [`BoundedLatencyPolicy`] are also limited to this capacity.
+	/// If that limit is reached, the peer takes no new commands from the queue until a held back stream message is written or dropped.
+	pub commands: usize,
+
+	/// The maximum number of received requests, stream messages and errors waiting to be picked up by the read handle.
+	///
+	/// If the queue is full, new requests are answered with an error response and new stream messages are dropped.
+	pub incoming: usize,
+
+	/// The maximum number of received update messages waiting to be picked up for each open request.
+	///
+	/// Room for the response of a request is reserved in addition to this.
+	/// If the queue is full, new update messages are dropped and reported as error to the read handle.
+	pub request_updates: usize,
+}
+
+impl Default for ChannelCapacities {
+	fn default() -> Self {
+		Self {
+			commands: 1024,
+			incoming: 1024,
+			request_updates: 128,
+		}
+	}
+}
+
+/// Policy to retry writing messages after a non-fatal transport error.
+///
+/// Without a retry policy, a non-fatal error from the transport immediately fails the send operation that caused it.
+/// With a retry policy, the peer retries the write up to [`Self::max_retries`] times, waiting [`Self::delay`] before each retry.
+/// If all retries fail, the last error is reported to the caller.
+///
+/// Fatal errors and errors for message bodies that are too large for the transport are never retried,
+/// because retrying can not make them succeed.
+/// While the peer waits to retry a message, it does not send any other messages.
+///
+/// Retries are counted in [`PeerStats::write_retries`][crate::PeerStats::write_retries].
+/// With the `metrics` feature, they are also counted in the `fizyr_rpc_write_retries_total` counter.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct WriteRetryPolicy {
+	/// The maximum number of retries for a single message or batch of messages.
+	pub max_retries: u32,
+
+	/// The time to wait before each retry.
+	pub delay: Duration,
+}
+
+impl WriteRetryPolicy {
+	/// Create a new retry policy.
+	pub fn new(max_retries: u32, delay: Duration) -> Self {
+		Self { max_retries, delay }
+	}
+}
+
+/// Scheduling policy that bounds the latency of requests, responses and update messages on a saturated connection.
+///
+/// Without a policy, the peer writes all messages in the order they were queued by the handles,
+/// so a backlog of stream messages delays every request queued after it.
+/// With a policy, requests, responses and update messages are always written before queued stream messages.
+/// Stream messages are only written when no other messages are waiting,
+/// and stream messages that were queued for longer than [`Self::max_stream_age`] by then are dropped instead.
+/// The send function of a dropped stream message returns an error for which [`Error::is_stream_dropped()`] returns true.
+///
+/// Stream messages keep their order relative to each other,
+/// and all queued stream messages are written (or dropped) before a flush or finish command is processed.
+/// Acknowledged stream messages are never deferred or dropped.
+///
+/// With the `strict-memory` feature, the number of held back stream messages is limited to [`ChannelCapacities::commands`].
+/// When that limit is reached, held back stream messages are written before messages queued after them, until there is room again.
+///
+/// Dropped stream messages are counted in [`PeerStats::dropped_stream_messages`][crate::PeerStats::dropped_stream_messages].
+/// With the `metrics` feature, they are also counted in the `fizyr_rpc_dropped_stream_messages_total` counter.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct BoundedLatencyPolicy {
+	/// The maximum time a stream message may be queued before it is dropped.
+	pub max_stream_age: Duration,
+}
+
+impl BoundedLatencyPolicy {
+	/// Create a new bounded latency policy.
+	pub fn new(max_stream_age: Duration) -> Self {
+		Self { max_stream_age }
+	}
 }
 
 /// Peer read/write loop.
@@ -39,21 +197,89 @@ pub struct Peer<Transport: crate::transport::Transport> {
 	/// This is used to have the read loop inject things into the command loop.
 	/// That way, the read loop doesn't need a mutable reference to the request tracker,
 	/// which simplifies the implementation.
-	command_tx: mpsc::UnboundedSender<Command<Transport::Body>>,
+	command_tx: channel::Sender<Command<Transport::Body>>,
 
 	/// Receiving end of the command channel.
 	///
 	/// Used to make the command loop do the things we want.
-	command_rx: mpsc::UnboundedReceiver<Command<Transport::Body>>,
+	command_rx: channel::Receiver<Command<Transport::Body>>,
+
+	/// Sending end of the channel for messages read from the transport.
+	///
+	/// The command loop takes turns between this channel and other commands,
+	/// so error responses to received messages are not queued behind all pending writes.
+	received_tx: channel::Sender<ProcessReceivedMessage<Transport::Body>>,
+
+	/// Receiving end of the channel for messages read from the transport.
+	received_rx: channel::Receiver<ProcessReceivedMessage<Transport::Body>>,
 
 	/// Sending end of the channel for incoming requests and stream messages.
-	incoming_tx: mpsc::UnboundedSender<Result<ReceivedMessage<Transport::Body>, Error>>,
+	incoming_tx: channel::Sender<Result<ReceivedMessage<Transport::Body>, Error>>,
+
+	/// Receiving end of the stop channel.
+	stop_rx: mpsc::Receiver<()>,
 
-	/// The number of [`PeerWriteHandle`][crate::PeerWriteHandle] objects for this peer.
+	/// Receiving end of the channel that is closed when all read and write handles are dropped.
 	///
-	/// When it hits zero, and the [`PeerReadHandle`][crate::PeerReadHandle] is dropped,
-	/// the internal loops are stopped.
-	write_handles: usize,
+	/// When that happens, the internal loops are stopped.
+	alive_rx: mpsc::Receiver<()>,
+
+	/// The policy to check outgoing messages against.
+	egress_policy: Arc<Mutex<Option<Box<dyn EgressPolicy>>>>,
+
+	/// The interceptor for incoming and outgoing messages.
+	interceptor: Option<Box<dyn Interceptor<Transport::Body>>>,
+
+	/// Sent acknowledged stream messages that are waiting for an acknowledgement.
+	pending_acks: PendingAcks,
+
+	/// If true, requests rejected by the peer are answered with a "bad request" error response.
+	bad_request_responses: bool,
+
+	/// The time after which received requests that were not picked up by the application expire.
+	request_expiry: Option<Duration>,
+
+	/// If true, a trace ID is generated for each sent request that does not have one.
+	trace_ids: bool,
+
+	/// If true, support for header flags is announced to the remote peer.
+	header_flags: bool,
+
+	/// The maximum length of the message of outgoing error responses.
+	max_error_len: Option<usize>,
+
+	/// The policy to retry writing messages after non-fatal errors.
+	write_retry: Option<WriteRetryPolicy>,
+
+	/// The policy to prefer other messages over stream messages, and to drop old stream messages.
+	bounded_latency: Option<BoundedLatencyPolicy>,
+
+	/// The maximum number of stream messages held back by the bounded latency policy.
+	max_deferred_streams: usize,
+
+	/// If true, the connection is closed when the read handle is dropped.
+	close_on_read_handle_drop: bool,
+
+	/// The policy to check the headers of received messages against.
+	header_policy: HeaderPolicy,
+
+	/// The async runtime for the timers of the peer.
+	runtime: Arc<dyn Runtime>,
+
+	/// Performance counters shared with the handles.
+	stats: Arc<StatsCounters>,
+
+	/// The labels of the connection, shared with the handles.
+	labels: Arc<Mutex<ConnectionLabels>>,
+
+	/// The application context of the connection, shared with the handles.
+	context: Arc<Mutex<ConnectionContext>>,
+
+	/// The process-wide unique ID of the connection.
+	connection_id: u64,
+
+	/// Description of the remote peer from the transport, if available.
+	remote_description: Option<String>,
 }
 
 impl<Transport: crate::transport::Transport> Peer<Transport> {
@@ -70,20 +296,68 @@ impl<Transport: crate::transport::Transport> Peer<Transport> {
 	/// and only get a [`PeerHandle`].
 	/// You should only use [`Self::spawn()`] if you do not need full control over the execution of the read/write loop.
 	pub fn new(transport: Transport) -> (Self, PeerHandle<Transport::Body>) {
-		let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
-		let (command_tx, command_rx) = mpsc::unbounded_channel();
-		let request_tracker = RequestTracker::new(command_tx.clone());
+		Self::new_with_capacities(transport, ChannelCapacities::default())
+	}
+
+	/// Create a new peer and a handle to it, with custom capacities for the internal queues.
+	///
+	/// The capacities are only used with the `strict-memory` feature.
+	/// See [`ChannelCapacities`] for more details.
+	pub fn new_with_capacities(transport: Transport, capacities: ChannelCapacities) -> (Self, PeerHandle<Transport::Body>) {
+		let (incoming_tx, incoming_rx) = channel::channel(capacities.incoming);
+		let (command_tx, command_rx) = channel::channel(capacities.commands);
+		let (received_tx, received_rx) = channel::channel(capacities.commands);
+		let (stop_tx, stop_rx) = mpsc::channel(1);
+		let (alive_tx, alive_rx) = mpsc::channel(1);
+		let request_tracker = RequestTracker::new(command_tx.clone(), capacities.request_updates);
+		let egress_policy = Arc::new(Mutex::new(None));
+		let stats = Arc::new(StatsCounters::default());
+		let labels = Arc::new(Mutex::new(ConnectionLabels::new()));
+		let context = Arc::new(Mutex::new(ConnectionContext::empty()));
+		let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+		let remote_description = transport.describe_remote();
+
+		let control = PeerControl {
+			stop_tx,
+			_alive_tx: alive_tx,
+			egress_policy: egress_policy.clone(),
+			labels: labels.clone(),
+			context: context.clone(),
+			connection_id,
+		};
 
 		let peer = Self {
 			transport,
 			request_tracker,
 			command_tx: command_tx.clone(),
 			command_rx,
+			received_tx,
+			received_rx,
 			incoming_tx,
-			write_handles: 1,
+			stop_rx,
+			alive_rx,
+			egress_policy,
+			interceptor: None,
+			pending_acks: PendingAcks::new(if cfg!(feature = "strict-memory") { capacities.commands } else { usize::MAX }),
+			bad_request_responses: false,
+			request_expiry: None,
+			trace_ids: false,
+			header_flags: false,
+			max_error_len: None,
+			write_retry: None,
+			bounded_latency: None,
+			max_deferred_streams: if cfg!(feature = "strict-memory") { capacities.commands } else { usize::MAX },
+			close_on_read_handle_drop: false,
+			header_policy: HeaderPolicy::none(),
+			runtime: Arc::new(TokioRuntime),
+			stats: stats.clone(),
+			labels,
+			context,
+			connection_id,
+			remote_description,
 		};
 
-		let handle = PeerHandle::new(incoming_rx, command_tx);
+		let handle = PeerHandle::new(incoming_rx, command_tx, control, stats);
 
 		(peer, handle)
 	}
@@ -97,12 +371,46 @@ impl<Transport: crate::transport::Transport> Peer<Transport> {
 	///
 	/// If you need more control of the execution of the peer read/write loop,
 	/// you should use [`Self::new()`] instead.
+	///
+	/// With the `tokio-console` feature, the task is named after the connection ID
+	/// and the description of the remote peer from the transport.
 	pub fn spawn(transport: Transport) -> PeerHandle<Transport::Body> {
 		let (peer, handle) = Self::new(transport);
-		tokio::spawn(peer.run());
+		peer.spawn_run();
+		handle
+	}
+
+	/// Spawn a peer in a new task on a custom runtime, and get a handle to the peer.
+	///
+	/// The peer uses the runtime for its timers too, see [`Self::with_runtime()`].
+	/// Otherwise, this is the same as [`Self::spawn()`].
+	pub fn spawn_on(transport: Transport, runtime: impl Runtime) -> PeerHandle<Transport::Body> {
+		let (peer, handle) = Self::new(transport);
+		let peer = peer.with_runtime(runtime);
+		let runtime = peer.runtime.clone();
+		runtime.spawn(Box::pin(peer.run()));
 		handle
 	}
 
+	/// Get the process-wide unique ID of the connection.
+	///
+	/// The same ID is available from the handles of the peer,
+	/// and it is used in the names of spawned tasks and in tracing spans.
+	pub fn connection_id(&self) -> u64 {
+		self.connection_id
+	}
+
+	/// Run the read/write loop in a new named task.
+	pub(crate) fn spawn_run(self) {
+		let connection_id = self.connection_id;
+		let remote_description = self.remote_description.clone();
+		let task_name = move || match remote_description {
+			Some(description) => format!("fizyr-rpc peer #{} ({})", connection_id, description),
+			None => format!("fizyr-rpc peer #{}", connection_id),
+		};
+		util::spawn_named(task_name, self.run());
+	}
+
 	/// Connect to a remote server.
 	///
 	/// Similar to [`Self::spawn()`], this spawns a background task for the peer.
@@ -122,15 +430,291 @@ impl<Transport: crate::transport::Transport> Peer<Transport> {
 		Ok((Self::spawn(transport), info))
 	}
 
+	/// Connect to a remote server, and attach an application context to the connection.
+	///
+	/// This is the same as [`Self::connect()`],
+	/// except that the context is attached to the peer before it starts processing messages.
+	/// See [`Self::with_context()`] for more details.
+	pub async fn connect_with_context<'a, Address, T>(address: Address, config: Transport::Config, context: Arc<T>) -> std::io::Result<(PeerHandle<Transport::Body>, Transport::Info)>
+	where
+		Address: 'a,
+		Transport: util::Connect<'a, Address>,
+		T: Send + Sync + 'static,
+	{
+		let transport = Transport::connect(address, config).await?;
+		let info = transport.info()?;
+		let (peer, handle) = Self::new(transport);
+		peer.with_context(context).spawn_run();
+		Ok((handle, info))
+	}
+
+	/// Install an interceptor for all incoming and outgoing messages of the peer.
+	///
+	/// This replaces any previously installed interceptor.
+	/// See [`Interceptor`] for more details.
+	pub fn with_interceptor(mut self, interceptor: impl Interceptor<Transport::Body>) -> Self {
+		self.interceptor = Some(Box::new(interceptor));
+		self
+	}
+
+	/// Automatically answer requests rejected by the peer with a "bad request" error response.
+	///
+	/// The peer itself rejects incoming requests that re-use the request ID of a request that is still open.
+	/// Normally, such requests are only reported to the [`PeerReadHandle`][crate::PeerReadHandle] as error,
+	/// and the remote peer never receives a response.
+	/// If enabled, the peer also sends a standardized error response to the remote peer,
+	/// which it can recognize with [`Error::is_bad_request()`].
+	/// The error response carries the re-used request ID, so it also closes the original request on the remote side.
+	/// The peer therefore closes the original request locally as well: its [`ReceivedRequestHandle`][crate::ReceivedRequestHandle] can no longer send messages.
+	///
+	/// Requests with an unknown service ID or an invalid body can be answered with
+	/// [`RecvMessageError::send_bad_request_response()`][crate::RecvMessageError::send_bad_request_response],
+	/// or automatically by generated servers.
+	///
+	/// This is disabled by default.
+	pub fn with_bad_request_responses(mut self, enabled: bool) -> Self {
+		self.bad_request_responses = enabled;
+		self
+	}
+
+	/// Limit the number of received requests that can be open at the same time.
+	///
+	/// A received request is open until a response is sent for it, or until its [`ReceivedRequestHandle`][crate::ReceivedRequestHandle] is dropped
+	/// and the peer notices that.
+	/// If the limit is reached, new incoming requests are answered with an error response right away,
+	/// and the error is reported to the [`PeerReadHandle`][crate::PeerReadHandle].
+	/// It can be recognized with [`ErrorKind::TooManyOpenRequests`][crate::ErrorKind::TooManyOpenRequests].
+	///
+	/// This protects a peer against remote peers that open an unbounded number of requests to exhaust its memory.
+	///
+	/// By default, there is no limit.
+	pub fn with_max_open_received_requests(mut self, limit: Option<usize>) -> Self {
+		self.request_tracker.set_max_open_received_requests(limit);
+		self
+	}
+
+	/// Expire received requests that are not picked up by the application in time.
+	///
+	/// Normally, received requests wait in a queue until the application calls [`PeerReadHandle::recv_message()`][crate::PeerReadHandle::recv_message].
+	/// If the application is overloaded, the requests can wait indefinitely.
+	/// With an expiry time, requests that are not picked up within `expiry` after they were read from the transport
+	/// are answered with a retry-after error response, and they are never returned to the application.
+	/// The remote peer can recognize the response with [`Error::is_retry_after()`],
+	/// and [`Error::as_retry_after()`] returns the expiry time.
+	///
+	/// This is disabled by default.
+	pub fn with_request_expiry(mut self, expiry: Option<Duration>) -> Self {
+		self.request_expiry = expiry;
+		self
+	}
+
+	/// Generate a trace ID for each sent request that does not have one yet.
+	///
+	/// The trace ID is sent along with the request, and is available to both peers through
+	/// [`SentRequestHandle::trace_id()`] and [`ReceivedRequestHandle::trace_id()`][crate::ReceivedRequestHandle::trace_id].
+	/// Requests sent with an explicit trace ID using [`PeerWriteHandle::send_request_with_trace_id()`][crate::PeerWriteHandle::send_request_with_trace_id]
+	/// keep that trace ID, so trace IDs can be propagated across multiple peers.
+	///
+	/// Enabling this also announces support for trace IDs to the remote peer when the peer starts,
+	/// with a stream message with service ID [`service_id::TRACE_ID_SUPPORT`][crate::service_id::TRACE_ID_SUPPORT].
+	/// Remote peers that do not support trace IDs will receive the announcement as a regular stream message,
+	/// so only enable this if the remote peer supports them or ignores unknown stream messages.
+	///
+	/// Independent of this setting, trace IDs are only sent after the remote peer announced support for them.
+	/// Until then, no trace IDs are generated, and explicit trace IDs are only available locally.
+	///
+	/// This is disabled by default.
+	pub fn with_trace_ids(mut self, enabled: bool) -> Self {
+		self.trace_ids = enabled;
+		self
+	}
+
+	/// Announce support for header flags to the remote peer.
+	///
+	/// When the peer starts, it sends a stream message with service ID [`service_id::HEADER_FLAGS`][crate::service_id::HEADER_FLAGS]
+	/// to tell the remote peer that it can send messages with [header flags][crate::MessageHeader::flags].
+	/// Remote peers that do not support header flags will receive the announcement as a regular stream message,
+	/// so only enable this if the remote peer supports them.
+	///
+	/// Independent of this setting, the flags of outgoing messages are only sent after the remote peer announced support for them.
+	/// Until then, the flags are cleared, so messages with flags can safely be sent to older peers.
+	/// Messages sent before the announcement of the remote peer is received are also sent without flags.
+	///
+	/// This is disabled by default.
+	pub fn with_header_flags(mut self, enabled: bool) -> Self {
+		self.header_flags = enabled;
+		self
+	}
+
+	/// Truncate the message of outgoing error responses to at most `max_len` bytes.
+	///
+	/// Longer messages are cut at a character boundary and end with `...` to show that they were truncated.
+	///
+	/// Independent of this setting, an error response that is rejected by the transport because its body is too large
+	/// is truncated to the maximum body size of the transport and sent again,
+	/// so that the request is always terminated on the remote side.
+	///
+	/// This is disabled by default.
+	pub fn with_max_error_len(mut self, max_len: Option<usize>) -> Self {
+		self.max_error_len = max_len;
+		self
+	}
+
+	/// Retry writing messages after non-fatal transport errors.
+	///
+	/// See [`WriteRetryPolicy`] for which errors are retried.
+	///
+	/// This is disabled by default.
+	pub fn with_write_retry_policy(mut self, policy: Option<WriteRetryPolicy>) -> Self {
+		self.write_retry = policy;
+		self
+	}
+
+	/// Write requests, responses and update messages before stream messages, and drop stream messages that were queued for too long.
+	///
+	/// This gives predictable latency for requests on a saturated connection, at the cost of losing stream messages.
+	/// See [`BoundedLatencyPolicy`] for more details.
+	///
+	/// This is disabled by default.
+	pub fn with_bounded_latency_policy(mut self, policy: Option<BoundedLatencyPolicy>) -> Self {
+		self.bounded_latency = policy;
+		self
+	}
+
+	/// Close the connection when the read handle is dropped.
+	///
+	/// Without a read handle, nobody can pick up incoming requests anymore.
+	/// Normally the peer keeps running as long as there are write handles, and answers all incoming requests with an error response.
+	/// With this option enabled, the peer processes all commands that were already queued, flushes the transport and then stops,
+	/// so that the remote peer notices that the connection is closed instead of receiving errors forever.
+	///
+	/// This is disabled by default.
+	pub fn with_close_on_read_handle_drop(mut self, enabled: bool) -> Self {
+		self.close_on_read_handle_drop = enabled;
+		self
+	}
+
+	/// Check the headers of received messages against a policy.
+	///
+	/// Messages that violate the policy are not processed, but reported to the read handle as an error.
+	/// Requests that violate the policy are also answered with an error response.
+	/// See [`HeaderPolicy`] for more details.
+	///
+	/// By default, all well-formed headers are accepted.
+	pub fn with_header_policy(mut self, policy: HeaderPolicy) -> Self {
+		self.header_policy = policy;
+		self
+	}
+
+	/// Use a different async runtime for the timers of the peer.
+	///
+	/// The runtime is used to expire received requests, to wait before retrying failed writes,
+	/// and to spawn the peer with [`Self::spawn_on()`].
+	/// See the [`runtime`][crate::runtime] module for more details.
+	///
+	/// By default, the peer uses the [`TokioRuntime`].
+	pub fn with_runtime(mut self, runtime: impl Runtime) -> Self {
+		self.runtime = Arc::new(runtime);
+		self
+	}
+
+	/// Attach labels to the connection.
+	///
+	/// The labels are available from the handles of the peer, and they are attached to the [`Annotations`] of all received requests.
+	/// With the `tracing` feature, they are added to the span of the peer,
+	/// and with the `metrics` feature, they are added to the metrics recorded by the peer.
+	/// See [`ConnectionLabels`] for more details.
+	///
+	/// By default, a peer has no labels.
+	pub fn with_labels(self, labels: ConnectionLabels) -> Self {
+		*self.labels.lock().unwrap() = labels;
+		self
+	}
+
+	/// Attach an application context to the connection.
+	///
+	/// The context is available from the handles of the peer with `context::<T>()`,
+	/// and it is attached to the [`Annotations`] of all received requests.
+	/// This can be used to keep per-connection state like the authorization of the remote peer,
+	/// instead of in a separate table indexed by connection.
+	/// See [`ConnectionContext`] for more details.
+	///
+	/// By default, a peer has no context.
+	pub fn with_context<T: Send + Sync + 'static>(self, context: Arc<T>) -> Self {
+		self.with_connection_context(ConnectionContext::new(context))
+	}
+
+	/// Attach a type-erased application context to the connection.
+	///
+	/// See [`Self::with_context()`] for more details.
+	pub fn with_connection_context(self, context: ConnectionContext) -> Self {
+		*self.context.lock().unwrap() = context;
+		self
+	}
+
 	/// Run the read/write loop.
-	pub async fn run(mut self) {
+	pub async fn run(self) {
+		self.run_until(std::future::pending()).await
+	}
+
+	/// Run the read/write loop until the `shutdown` future completes.
+	///
+	/// When the shutdown future completes, the peer stops reading messages.
+	/// Commands that were already queued by the handles are still processed, and the transport is flushed.
+	/// Then the peer stops, and open sent requests and the read handle receive an error for which [`Error::is_shutdown()`] returns true,
+	/// instead of the connection aborted error reported when the connection is lost.
+	///
+	/// If the peer stops for another reason first, the shutdown future is dropped.
+	///
+	/// This makes it easy to stop a peer from a structured concurrency setup.
+	/// For example, you can pass `token.cancelled_owned()` of a `tokio_util::sync::CancellationToken`,
+	/// or a future that waits for a signal.
+	pub async fn run_until(self, shutdown: impl Future<Output = ()> + Send) {
+		#[cfg(feature = "tracing")]
+		{
+			use tracing::Instrument;
+			let labels = self.labels.lock().unwrap().clone();
+			let span = tracing::debug_span!("peer", connection_id = self.connection_id, remote = self.remote_description.as_deref(), labels = %labels);
+			self.run_loops(shutdown).instrument(span).await
+		}
+
+		#[cfg(not(feature = "tracing"))]
+		self.run_loops(shutdown).await
+	}
+
+	/// Run the read and command loops until they stop.
+	async fn run_loops(mut self, shutdown: impl Future<Output = ()> + Send) {
+		trace_event!(debug, "peer started");
+		tokio::pin!(shutdown);
 		let Self {
 			transport,
 			request_tracker,
 			command_tx,
 			command_rx,
+			received_tx,
+			received_rx,
 			incoming_tx,
-			write_handles,
+			stop_rx,
+			alive_rx,
+			egress_policy,
+			interceptor,
+			pending_acks,
+			bad_request_responses,
+			request_expiry,
+			trace_ids,
+			header_flags,
+			max_error_len,
+			write_retry,
+			bounded_latency,
+			max_deferred_streams,
+			close_on_read_handle_drop,
+			header_policy,
+			runtime,
+			stats,
+			labels,
+			context,
+			connection_id: _,
+			remote_description: _,
 		} = &mut self;
 
 		let (read_half, write_half) = transport.split();
@@ -138,15 +722,48 @@ impl<Transport: crate::transport::Transport> Peer<Transport> {
 		let mut read_loop = ReadLoop {
 			read_half,
 			command_tx: command_tx.clone(),
+			received_tx: received_tx.clone(),
 		};
 
 		let mut command_loop = CommandLoop {
 			write_half,
 			request_tracker,
 			command_rx,
+			received_rx,
+			prefer_commands: false,
 			incoming_tx,
-			read_handle_dropped: &mut false,
-			write_handles,
+			stop_rx,
+			alive_rx,
+			egress_policy,
+			interceptor,
+			pending_acks,
+			bad_request_responses: *bad_request_responses,
+			pending_deadline: None,
+			pending_trace_id: None,
+			pending_type_fingerprint: None,
+			trace_id_generator: trace_ids.then(TraceIdGenerator::new),
+			request_expiry: *request_expiry,
+			expiring_requests: VecDeque::new(),
+			max_error_len: *max_error_len,
+			write_retry: *write_retry,
+			bounded_latency: *bounded_latency,
+			deferred_streams: VecDeque::new(),
+			max_deferred_streams: *max_deferred_streams,
+			close_on_read_handle_drop: *close_on_read_handle_drop,
+			read_handle_dropped: false,
+			header_policy,
+			runtime: &**runtime,
+			shutdown: Some(shutdown),
+			stats,
+			labels: labels.lock().unwrap().clone(),
+			context: context.lock().unwrap().clone(),
+			write_finished: false,
+			announce_header_flags: *header_flags,
+			remote_header_flags: false,
+			announce_trace_ids: *trace_ids,
+			remote_trace_ids: false,
+			#[cfg(all(debug_assertions, feature = "tracing"))]
+			audit_timer: runtime.sleep_until(Instant::now() + AUDIT_INTERVAL),
 		};
 
 		let read_loop = read_loop.run();
@@ -158,11 +775,8 @@ impl<Transport: crate::transport::Transport> Peer<Transport> {
 
 		match select(read_loop, command_loop).await {
 			Either::Left(((), command_loop)) => {
-				// If the read loop stopped we should still flush all queued incoming messages, then stop.
-				command_tx
-					.send(Command::Stop)
-					.map_err(drop)
-					.expect("command loop did not stop yet but command channel is closed");
+				// If the read loop stopped we should still flush all queued incoming messages.
+				// The read loop queued a stop command after the last message.
 				command_loop.await;
 			},
 			Either::Right((_read_loop, ())) => {
@@ -171,6 +785,7 @@ impl<Transport: crate::transport::Transport> Peer<Transport> {
 				// The read loop is dropped here.
 			},
 		}
+		trace_event!(debug, "peer stopped");
 	}
 
 	/// Get direct access to the underlying transport.
@@ -193,7 +808,10 @@ where
 	read_half: R,
 
 	/// The channel used to inject things into the peer read/write loop.
-	command_tx: mpsc::UnboundedSender<Command<R::Body>>,
+	command_tx: channel::Sender<Command<R::Body>>,
+
+	/// The channel used to pass received messages to the command loop, separate from other commands.
+	received_tx: channel::Sender<ProcessReceivedMessage<R::Body>>,
 }
 
 impl<R> ReadLoop<R>
@@ -205,16 +823,25 @@ where
 		loop {
 			// Read a message, and stop the read loop on errors.
 			let message = self.read_half.read_msg().await;
+			let received_at = Instant::now();
 			let stop = matches!(&message, Err(e) if e.is_fatal());
+			#[cfg(feature = "tracing")]
+			if let Err(e) = &message {
+				tracing::debug!(error = %e, fatal = e.is_fatal(), "failed to read message");
+			}
 			let message = message.map_err(|e| e.into_inner());
 
 			// But first send the error to the command loop so it can be delivered to the peer.
+			// If the channel is full, wait for room instead of reading more messages from the transport.
 			// If that fails the command loop already closed, so just stop the read loop.
-			if self.command_tx.send(crate::peer::ProcessReceivedMessage { message }.into()).is_err() {
+			if self.received_tx.send_wait(ProcessReceivedMessage { message, received_at }).await.is_err() {
 				break;
 			}
 
 			if stop {
+				// Stop the command loop after it processed all queued messages.
+				// The command loop processes the remaining received messages before it stops, so they are not lost either.
+				let _: Result<_, _> = self.command_tx.send_wait(crate::peer::Command::Stop).await;
 				break;
 			}
 		}
@@ -233,16 +860,128 @@ where
 	request_tracker: &'a mut RequestTracker<W::Body>,
 
 	/// The channel for incoming commands.
-	command_rx: &'a mut mpsc::UnboundedReceiver<Command<W::Body>>,
+	command_rx: &'a mut channel::Receiver<Command<W::Body>>,
+
+	/// The channel for messages read from the transport, which take turns with other commands.
+	received_rx: &'a mut channel::Receiver<ProcessReceivedMessage<W::Body>>,
+
+	/// If true, the next queued command is processed before the next received message.
+	prefer_commands: bool,
+
+	/// The shutdown signal from [`Peer::run_until()`], or `None` after it completed.
+	shutdown: Option<Pin<&'a mut (dyn Future<Output = ()> + Send)>>,
 
 	/// The channel for sending incoming messages to the [`PeerHandle`].
-	incoming_tx: &'a mut mpsc::UnboundedSender<Result<ReceivedMessage<W::Body>, Error>>,
+	incoming_tx: &'a mut channel::Sender<Result<ReceivedMessage<W::Body>, Error>>,
+
+	/// The channel for stop requests.
+	stop_rx: &'a mut mpsc::Receiver<()>,
+
+	/// The channel that is closed when all read and write handles are dropped.
+	alive_rx: &'a mut mpsc::Receiver<()>,
+
+	/// The policy to check outgoing messages against.
+	egress_policy: &'a Mutex<Option<Box<dyn EgressPolicy>>>,
+
+	/// The interceptor for incoming and outgoing messages.
+	interceptor: &'a mut Option<Box<dyn Interceptor<W::Body>>>,
+
+	/// Sent acknowledged stream messages that are waiting for an acknowledgement.
+	pending_acks: &'a mut PendingAcks,
+
+	/// If true, requests rejected by the peer are answered with a "bad request" error response.
+	bad_request_responses: bool,
+
+	/// The request ID and deadline from the last received deadline message.
+	///
+	/// The deadline applies only to the message that follows it.
+	pending_deadline: Option<(u32, Instant)>,
+
+	/// The request ID and trace ID from the last received trace ID message.
+	///
+	/// The trace ID applies only to the message that follows it.
+	pending_trace_id: Option<(u32, u64)>,
+
+	/// The request ID and fingerprint from the last received type fingerprint message.
+	///
+	/// The fingerprint applies only to the message that follows it.
+	pending_type_fingerprint: Option<(u32, u64)>,
+
+	/// The generator for trace IDs of sent requests, if trace IDs are enabled.
+	trace_id_generator: Option<TraceIdGenerator>,
+
+	/// The time after which received requests that were not picked up by the application expire.
+	request_expiry: Option<Duration>,
+
+	/// Received requests waiting to be picked up by the application, in order of expiry.
+	expiring_requests: VecDeque<ExpiringRequest>,
+
+	/// The maximum length of the message of outgoing error responses.
+	max_error_len: Option<usize>,
+
+	/// The policy to retry writing messages after non-fatal errors.
+	write_retry: Option<WriteRetryPolicy>,
+
+	/// The policy to prefer other messages over stream messages, and to drop old stream messages.
+	bounded_latency: Option<BoundedLatencyPolicy>,
+
+	/// Stream messages held back by the bounded latency policy until no other messages are waiting.
+	deferred_streams: VecDeque<DeferredStream<W::Body>>,
+
+	/// The maximum number of held back stream messages, after which no more commands are taken from the command channel.
+	max_deferred_streams: usize,
+
+	/// If true, the loop stops when the read handle is dropped.
+	close_on_read_handle_drop: bool,
+
+	/// If true, an incoming message was already rejected because the read handle was dropped.
+	read_handle_dropped: bool,
+
+	/// The policy to check the headers of received messages against.
+	header_policy: &'a HeaderPolicy,
 
-	/// Flag to indicate if the peer read handle has already been stopped.
-	read_handle_dropped: &'a mut bool,
+	/// The async runtime for the timers of the peer.
+	runtime: &'a dyn Runtime,
 
-	/// Number of open write handles.
-	write_handles: &'a mut usize,
+	/// Performance counters shared with the handles.
+	stats: &'a StatsCounters,
+
+	/// The labels of the connection, attached to all received requests.
+	labels: ConnectionLabels,
+
+	/// The application context of the connection, attached to all received requests.
+	context: ConnectionContext,
+
+	/// If true, the write half of the transport has been shut down and no more messages can be sent.
+	write_finished: bool,
+
+	/// If true, support for header flags is announced to the remote peer when the loop starts.
+	announce_header_flags: bool,
+
+	/// If true, the remote peer announced support for header flags.
+	remote_header_flags: bool,
+
+	/// If true, support for trace IDs is announced to the remote peer when the loop starts.
+	announce_trace_ids: bool,
+
+	/// If true, the remote peer announced support for trace IDs.
+	remote_trace_ids: bool,
+
+	/// Timer for the next periodic audit of the request tracker.
+	#[cfg(all(debug_assertions, feature = "tracing"))]
+	audit_timer: crate::runtime::BoxFuture<'static, ()>,
+}
+
+/// A received request that expires if it is not picked up by the application in time.
+struct ExpiringRequest {
+	/// The time when the request expires.
+	expires_at: Instant,
+
+	/// The ID of the request.
+	request_id: u32,
+
+	/// Flag shared with the request handle, set by whoever picks up or expires the request first.
+	picked_up: Arc<AtomicBool>,
 }
 
 impl<W> CommandLoop<'_, W>
@@ -251,37 +990,33 @@ where
 {
 	/// Run the command loop.
 	async fn run(&mut self) {
-		loop {
-			// Stop the command loop if both halves of the PeerHandle are dropped.
-			if *self.read_handle_dropped && *self.write_handles == 0 {
-				break;
+		if self.announce_header_flags {
+			let announcement = Message::stream(0, crate::service_id::HEADER_FLAGS, crate::Body::empty());
+			if let Err((_e, LoopFlow::Stop)) = self.write_message(&announcement).await {
+				return;
+			}
+		}
+		if self.announce_trace_ids {
+			let announcement = Message::stream(0, crate::service_id::TRACE_ID_SUPPORT, crate::Body::empty());
+			if let Err((_e, LoopFlow::Stop)) = self.write_message(&announcement).await {
+				return;
 			}
+		}
 
-			// Get the next command from the channel.
-			let command = self
-				.command_rx
-				.recv()
-				.await
-				.expect("all command channels closed, but we keep one open ourselves");
-
-			// Process the command.
-			let flow = match command {
-				Command::SendRequest(command) => self.send_request(command).await,
-				Command::SendRawMessage(command) => self.send_raw_message(command).await,
-				Command::ProcessReceivedMessage(command) => self.process_incoming_message(command).await,
-				Command::Stop => LoopFlow::Stop,
-				Command::UnregisterReadHandle => {
-					*self.read_handle_dropped = true;
-					LoopFlow::Continue
-				},
-				Command::RegisterWriteHandle => {
-					*self.write_handles += 1;
-					LoopFlow::Continue
-				},
-				Command::UnregisterWriteHandle => {
-					*self.write_handles -= 1;
-					LoopFlow::Continue
-				},
+		loop {
+			self.stats.set_open_requests(self.request_tracker.sent_requests_len(), self.request_tracker.received_requests_len());
+			self.stats.set_command_queue_len(self.command_rx.len() + self.deferred_streams.len());
+
+			let flow = match self.next_event().await {
+				Event::Command(command) => self.process_command(command).await,
+				Event::DeferredStream(stream) => self.send_deferred_stream(stream).await,
+				Event::Expired => self.expire_requests().await,
+				Event::ReadHandleDropped => self.close_after_read_handle_drop().await,
+				Event::Shutdown => self.shutdown().await,
+				#[cfg(all(debug_assertions, feature = "tracing"))]
+				Event::Audit => self.log_audit(),
+				Event::HandlesDropped => self.stop_after_handles_dropped().await,
+				Event::Stop => LoopFlow::Stop,
 			};
 
 			// Stop the loop if the command dictates it.
@@ -292,36 +1027,314 @@ where
 		}
 	}
 
-	/// Process a SendRequest command.
-	async fn send_request(&mut self, command: crate::peer::SendRequest<W::Body>) -> LoopFlow {
-		let request = match self.request_tracker.allocate_sent_request(command.service_id) {
-			Ok(x) => x,
-			Err(e) => {
-				let _: Result<_, _> = command.result_tx.send(Err(e));
-				return LoopFlow::Continue;
-			},
+	/// Wait for the next event for the command loop.
+	///
+	/// A stop request stops the loop immediately.
+	/// Dropping all read and write handles stops the loop after the commands that were already queued.
+	/// Otherwise, the next command is processed, or received requests are expired when the first one is due.
+	/// If enabled, dropping the read handle closes the connection once all queued commands are processed.
+	async fn next_event(&mut self) -> Event<W::Body> {
+		let mut expired = self.expiring_requests.front().map(|x| self.runtime.sleep_until(x.expires_at));
+		let incoming_tx = self.close_on_read_handle_drop.then(|| self.incoming_tx.clone());
+		let read_handle_dropped = async {
+			match &incoming_tx {
+				Some(incoming_tx) => incoming_tx.closed().await,
+				None => std::future::pending().await,
+			}
 		};
+		tokio::pin!(read_handle_dropped);
+
+		std::future::poll_fn(|context| {
+			// The stop channel is also closed when all handles are dropped, including close handles.
+			match self.stop_rx.poll_recv(context) {
+				Poll::Ready(Some(())) => return Poll::Ready(Event::Stop),
+				Poll::Ready(None) => return Poll::Ready(Event::HandlesDropped),
+				Poll::Pending => (),
+			}
+			if self.alive_rx.poll_recv(context).is_ready() {
+				return Poll::Ready(Event::HandlesDropped);
+			}
+			if let Some(shutdown) = &mut self.shutdown {
+				if shutdown.as_mut().poll(context).is_ready() {
+					self.shutdown = None;
+					return Poll::Ready(Event::Shutdown);
+				}
+			}
+			// Received messages and queued commands take turns,
+			// so error responses for received messages are not queued behind all pending writes,
+			// and a busy remote peer can not starve the queued commands either.
+			if self.prefer_commands {
+				if let Some(command) = self.poll_queued_command(context) {
+					return Poll::Ready(command);
+				}
+			}
+			if let Poll::Ready(Some(command)) = self.received_rx.poll_recv(context) {
+				self.prefer_commands = true;
+				return Poll::Ready(Event::Command(command.into()));
+			}
+			if let Some(command) = self.poll_queued_command(context) {
+				return Poll::Ready(command);
+			}
+			if let Some(expired) = &mut expired {
+				if expired.as_mut().poll(context).is_ready() {
+					return Poll::Ready(Event::Expired);
+				}
+			}
+			// Deferred stream messages are only written when nothing else is waiting.
+			let deferred_streams_full = self.deferred_streams_full();
+			if let Some(stream) = self.next_deferred_stream() {
+				return Poll::Ready(Event::DeferredStream(stream));
+			}
+			if deferred_streams_full {
+				// All deferred stream messages were dropped, so the command channel has to be polled again.
+				context.waker().wake_by_ref();
+				return Poll::Pending;
+			}
+			if std::future::Future::poll(read_handle_dropped.as_mut(), context).is_ready() {
+				return Poll::Ready(Event::ReadHandleDropped);
+			}
+			#[cfg(all(debug_assertions, feature = "tracing"))]
+			if self.audit_timer.as_mut().poll(context).is_ready() {
+				self.audit_timer = self.runtime.sleep_until(Instant::now() + AUDIT_INTERVAL);
+				return Poll::Ready(Event::Audit);
+			}
+			Poll::Pending
+		}).await
+	}
 
-		let request_id = request.request_id();
-
-		let message = Message::request(request.request_id(), request.service_id(), command.body);
-		if let Err((e, flow)) = self.write_message(&message).await {
-			let _: Result<_, _> = command.result_tx.send(Err(e));
-			let _: Result<_, _> = self.request_tracker.remove_sent_request(request_id);
-			return flow;
-		}
-
-		// If sending fails, the result_rx was dropped.
-		// Then remove the request from the tracker.
-		if command.result_tx.send(Ok(request)).is_err() {
-			let _: Result<_, _> = self.request_tracker.remove_sent_request(request_id);
+	/// Poll the command channel for the next command that is not deferred.
+	///
+	/// If the queue of deferred stream messages is full, the command channel is not polled at all.
+	fn poll_queued_command(&mut self, context: &mut std::task::Context) -> Option<Event<W::Body>> {
+		while !self.deferred_streams_full() {
+			let Poll::Ready(command) = self.command_rx.poll_recv(context) else {
+				break;
+			};
+			let command = command.expect("all command channels closed, but we keep one open ourselves");
+			if let Some(command) = self.defer_stream(command) {
+				self.prefer_commands = false;
+				return Some(Event::Command(command));
+			}
 		}
-
-		LoopFlow::Continue
+		None
 	}
 
-	/// Process a SendRawMessage command.
-	async fn send_raw_message(&mut self, command: crate::peer::SendRawMessage<W::Body>) -> LoopFlow {
+	/// Process a command.
+	async fn process_command(&mut self, command: Command<W::Body>) -> LoopFlow {
+		match command {
+			Command::SendRequest(command) => self.send_request(command).await,
+			Command::SendOneshotRequest(command) => self.send_oneshot_request(command).await,
+			Command::SendRawMessage(command) => self.send_raw_message(command).await,
+			Command::SendAckedStream(command) => self.send_acked_stream(command).await,
+			Command::SendStreamBatch(command) => self.send_stream_batch(command).await,
+			Command::ProcessReceivedMessage(command) => self.process_incoming_message(command).await,
+			Command::Flush(command) => {
+				if self.write_deferred_streams().await == LoopFlow::Stop {
+					return LoopFlow::Stop;
+				}
+				self.flush(command).await
+			},
+			Command::Finish(command) => {
+				if self.write_deferred_streams().await == LoopFlow::Stop {
+					return LoopFlow::Stop;
+				}
+				self.finish(command).await
+			},
+			Command::Audit(command) => {
+				let _: Result<_, _> = command.result_tx.send(self.request_tracker.audit());
+				LoopFlow::Continue
+			},
+			Command::Stop => {
+				// The read loop sends the stop command after the last received message, which may still be queued.
+				while self.received_rx.len() > 0 {
+					let Some(command) = self.received_rx.recv().await else { break };
+					if self.process_incoming_message(command).await == LoopFlow::Stop {
+						break;
+					}
+				}
+				LoopFlow::Stop
+			},
+		}
+	}
+
+	/// Audit the request tracker and log orphaned requests.
+	///
+	/// Orphaned received requests are never answered, so they are logged as warning.
+	/// Orphaned sent requests are normal until the response arrives, so they are only logged for debugging.
+	#[cfg(all(debug_assertions, feature = "tracing"))]
+	fn log_audit(&mut self) -> LoopFlow {
+		let audit = self.request_tracker.audit();
+		if !audit.orphaned_received_requests.is_empty() {
+			tracing::warn!(request_ids = ?audit.orphaned_received_requests, "received requests were dropped without sending a response");
+		}
+		if !audit.orphaned_sent_requests.is_empty() {
+			tracing::debug!(request_ids = ?audit.orphaned_sent_requests, "sent requests were dropped before receiving a response");
+		}
+		LoopFlow::Continue
+	}
+
+	/// Process a Flush command.
+	///
+	/// All earlier commands have been processed already, so all queued messages have been written.
+	async fn flush(&mut self, command: crate::peer::Flush) -> LoopFlow {
+		if let Err(e) = self.write_half.flush().await {
+			trace_event!(debug, error = %e, fatal = e.is_fatal(), "failed to flush write half");
+			let flow = if e.is_fatal() {
+				LoopFlow::Stop
+			} else {
+				LoopFlow::Continue
+			};
+			let _: Result<_, _> = command.result_tx.send(Err(e.into_inner()));
+			return flow;
+		}
+
+		let _: Result<_, _> = command.result_tx.send(Ok(()));
+		LoopFlow::Continue
+	}
+
+	/// Process a Finish command.
+	///
+	/// All earlier commands have been processed already, so all queued messages have been written.
+	async fn finish(&mut self, command: crate::peer::Finish) -> LoopFlow {
+		if !self.write_finished {
+			self.write_finished = true;
+			if let Err(e) = self.write_half.shutdown().await {
+				trace_event!(debug, error = %e, fatal = e.is_fatal(), "failed to shut down write half");
+				let flow = if e.is_fatal() {
+					LoopFlow::Stop
+				} else {
+					LoopFlow::Continue
+				};
+				let _: Result<_, _> = command.result_tx.send(Err(e.into_inner()));
+				return flow;
+			}
+			trace_event!(debug, "finished writing messages");
+		}
+
+		let _: Result<_, _> = command.result_tx.send(Ok(()));
+		LoopFlow::Continue
+	}
+
+	/// Process a SendRequest command.
+	async fn send_request(&mut self, command: crate::peer::SendRequest<W::Body>) -> LoopFlow {
+		let mut request = match self.request_tracker.allocate_sent_request(command.service_id) {
+			Ok(x) => x,
+			Err(e) => {
+				let _: Result<_, _> = command.result_tx.send(Err(e));
+				return LoopFlow::Continue;
+			},
+		};
+
+		let request_id = request.request_id();
+
+		let mut message = Message::request(request.request_id(), request.service_id(), command.body);
+		if let Err(e) = self.check_outgoing(&mut message) {
+			let _: Result<_, _> = command.result_tx.send(Err(e));
+			let _: Result<_, _> = self.request_tracker.remove_sent_request(request_id);
+			return LoopFlow::Continue;
+		}
+
+		// The deadline is sent right before the request, so the remote peer can attach it to the request.
+		if let Some(deadline) = command.deadline {
+			let remaining = deadline.saturating_duration_since(Instant::now());
+			if let Err((e, flow)) = self.write_message(&Message::deadline(request_id, remaining)).await {
+				let _: Result<_, _> = command.result_tx.send(Err(e));
+				let _: Result<_, _> = self.request_tracker.remove_sent_request(request_id);
+				return flow;
+			}
+		}
+
+		// The trace ID is also sent right before the request, if the remote peer supports them.
+		let trace_id = command.trace_id.or_else(|| self.next_trace_id());
+		if let Some(trace_id) = trace_id {
+			if self.remote_trace_ids {
+				if let Err((e, flow)) = self.write_message(&Message::trace_id(request_id, trace_id)).await {
+					let _: Result<_, _> = command.result_tx.send(Err(e));
+					let _: Result<_, _> = self.request_tracker.remove_sent_request(request_id);
+					return flow;
+				}
+			}
+			request.set_trace_id(Some(trace_id));
+		}
+
+		// And so is the type fingerprint.
+		if let Some(type_fingerprint) = command.type_fingerprint {
+			if let Err((e, flow)) = self.write_message(&Message::type_fingerprint(request_id, type_fingerprint)).await {
+				let _: Result<_, _> = command.result_tx.send(Err(e));
+				let _: Result<_, _> = self.request_tracker.remove_sent_request(request_id);
+				return flow;
+			}
+		}
+
+		if let Err((e, flow)) = self.write_message(&message).await {
+			let _: Result<_, _> = command.result_tx.send(Err(e));
+			let _: Result<_, _> = self.request_tracker.remove_sent_request(request_id);
+			return flow;
+		}
+
+		// If sending fails, the result_rx was dropped.
+		// Then remove the request from the tracker.
+		if command.result_tx.send(Ok(request)).is_err() {
+			let _: Result<_, _> = self.request_tracker.remove_sent_request(request_id);
+		}
+
+		LoopFlow::Continue
+	}
+
+	/// Generate a trace ID for a new request.
+	///
+	/// Returns `None` if trace IDs are disabled, or if the remote peer did not announce support for them.
+	fn next_trace_id(&mut self) -> Option<u64> {
+		if !self.remote_trace_ids {
+			return None;
+		}
+		self.trace_id_generator.as_mut().map(TraceIdGenerator::next)
+	}
+
+	/// Process a SendOneshotRequest command.
+	async fn send_oneshot_request(&mut self, command: crate::peer::SendOneshotRequest<W::Body>) -> LoopFlow {
+		let request_id = match self.request_tracker.allocate_oneshot_request(command.service_id, command.response_tx) {
+			Ok(x) => x,
+			Err((e, response_tx)) => {
+				let _: Result<_, _> = response_tx.send(Err(e));
+				return LoopFlow::Continue;
+			},
+		};
+
+		let mut message = Message::request(request_id, command.service_id, command.body);
+		if let Err(e) = self.check_outgoing(&mut message) {
+			self.request_tracker.fail_oneshot_request(request_id, e);
+			return LoopFlow::Continue;
+		}
+
+		// Generated trace IDs are sent right before the request, like for other requests.
+		if let Some(trace_id) = self.next_trace_id() {
+			if let Err((e, flow)) = self.write_message(&Message::trace_id(request_id, trace_id)).await {
+				self.request_tracker.fail_oneshot_request(request_id, e);
+				return flow;
+			}
+		}
+
+		if let Err((e, flow)) = self.write_message(&message).await {
+			self.request_tracker.fail_oneshot_request(request_id, e);
+			return flow;
+		}
+
+		LoopFlow::Continue
+	}
+
+	/// Process a SendRawMessage command.
+	async fn send_raw_message(&mut self, mut command: crate::peer::SendRawMessage<W::Body>) -> LoopFlow {
+		if let Some(max_len) = self.max_error_len {
+			truncate_error_response(&mut command.message, max_len);
+		}
+
+		// Check the message first, so a rejected response leaves the received request open.
+		if let Err(e) = self.check_outgoing(&mut command.message) {
+			let _: Result<_, _> = command.result_tx.send(Err(e));
+			return LoopFlow::Continue;
+		}
+
 		// Remove tracked received requests when we send a response.
 		if command.message.header.message_type.is_response() {
 			let _: Result<_, _> = self.request_tracker.remove_received_request(command.message.header.request_id);
@@ -334,7 +1347,19 @@ where
 		// Actually, should we remove the request if result_tx is dropped?
 		// Needs more thought.
 
-		if let Err((e, flow)) = self.write_message(&command.message).await {
+		let mut result = self.write_message(&command.message).await;
+
+		// An error response that is too large would leave the request open on the remote side forever.
+		// So truncate it to fit in the body limit of the transport and try again.
+		if let Err((e, LoopFlow::Continue)) = &result {
+			if let Some((_body_len, max_len)) = e.as_payload_too_large() {
+				if truncate_error_response(&mut command.message, max_len) {
+					result = self.write_message(&command.message).await;
+				}
+			}
+		}
+
+		if let Err((e, flow)) = result {
 			let _: Result<_, _> = command.result_tx.send(Err(e));
 			return flow;
 		}
@@ -343,281 +1368,2263 @@ where
 		LoopFlow::Continue
 	}
 
+	/// Hold back a stream message if the bounded latency policy is enabled.
+	///
+	/// Returns the command if it should be processed right away.
+	fn defer_stream(&mut self, command: Command<W::Body>) -> Option<Command<W::Body>> {
+		if self.bounded_latency.is_none() {
+			return Some(command);
+		}
+		match command {
+			Command::SendRawMessage(command) if command.message.header.message_type.is_stream() => {
+				self.deferred_streams.push_back(DeferredStream::Message(command));
+				None
+			},
+			Command::SendStreamBatch(command) => {
+				self.deferred_streams.push_back(DeferredStream::Batch(command));
+				None
+			},
+			command => Some(command),
+		}
+	}
+
+	/// Check if no more stream messages can be deferred.
+	fn deferred_streams_full(&self) -> bool {
+		self.deferred_streams.len() >= self.max_deferred_streams
+	}
+
+	/// Take the next deferred stream message that is not too old to send.
+	///
+	/// Deferred stream messages that were queued for longer than allowed by the bounded latency policy are dropped.
+	fn next_deferred_stream(&mut self) -> Option<DeferredStream<W::Body>> {
+		let max_age = self.bounded_latency?.max_stream_age;
+		let now = Instant::now();
+		while let Some(stream) = self.deferred_streams.pop_front() {
+			if now.saturating_duration_since(stream.queued_at()) <= max_age {
+				return Some(stream);
+			}
+			self.drop_stream(stream, max_age);
+		}
+		None
+	}
+
+	/// Drop a deferred stream message and report the error to the sender.
+	fn drop_stream(&mut self, stream: DeferredStream<W::Body>, max_age: Duration) {
+		let (count, result_tx) = match stream {
+			DeferredStream::Message(command) => (1, command.result_tx),
+			DeferredStream::Batch(command) => (command.messages.len(), command.result_tx),
+		};
+		self.stats.streams_dropped(count);
+		#[cfg(feature = "metrics")]
+		{
+			let labels: Vec<_> = self.labels.iter().map(|(key, value)| metrics::Label::new(key.to_owned(), value.to_owned())).collect();
+			metrics::counter!("fizyr_rpc_dropped_stream_messages_total", labels).increment(count as u64);
+		}
+		trace_event!(debug, count, max_age = ?max_age, "dropping stream messages that were queued for too long");
+		let _: Result<_, _> = result_tx.send(Err(InnerError::StreamDropped { max_age }.into()));
+	}
+
+	/// Write a deferred stream message.
+	async fn send_deferred_stream(&mut self, stream: DeferredStream<W::Body>) -> LoopFlow {
+		match stream {
+			DeferredStream::Message(command) => self.send_raw_message(command).await,
+			DeferredStream::Batch(command) => self.send_stream_batch(command).await,
+		}
+	}
+
+	/// Write all deferred stream messages, dropping the ones that were queued for too long.
+	async fn write_deferred_streams(&mut self) -> LoopFlow {
+		while let Some(stream) = self.next_deferred_stream() {
+			if self.send_deferred_stream(stream).await == LoopFlow::Stop {
+				return LoopFlow::Stop;
+			}
+		}
+		LoopFlow::Continue
+	}
+
+	/// Process a SendAckedStream command.
+	async fn send_acked_stream(&mut self, command: crate::peer::SendAckedStream<W::Body>) -> LoopFlow {
+		let ack_id = match self.pending_acks.allocate_id() {
+			Ok(x) => x,
+			Err(e) => {
+				trace_event!(warn, service_id = command.service_id, error = %e, "failed to allocate ID for acknowledged stream message");
+				let _: Result<_, _> = command.result_tx.send(Err(e));
+				return LoopFlow::Continue;
+			},
+		};
+
+		let mut message = Message::acked_stream(ack_id, command.service_id, command.body);
+		if let Err(e) = self.check_outgoing(&mut message) {
+			let _: Result<_, _> = command.result_tx.send(Err(e));
+			return LoopFlow::Continue;
+		}
+
+		if let Err((e, flow)) = self.write_message(&message).await {
+			let _: Result<_, _> = command.result_tx.send(Err(e));
+			return flow;
+		}
+
+		self.pending_acks.senders.insert(ack_id, command.result_tx);
+		LoopFlow::Continue
+	}
+
 	/// Process an incoming message.
 	async fn process_incoming_message(&mut self, command: crate::peer::ProcessReceivedMessage<W::Body>) -> LoopFlow {
+		use crate::Body;
 		// Forward errors to the peer read handle.
-		let message = match command.message {
+		let mut message = match command.message {
 			Ok(x) => x,
 			Err(e) => {
 				let _: Result<_, _> = self.send_incoming(Err(e)).await;
 				return LoopFlow::Continue;
 			},
 		};
+		self.stats.message_received(crate::HEADER_LEN as usize + message.body.data_len());
+
+		// Reject messages with nonsensical headers before they can affect the state of the peer.
+		if let Err(e) = self.header_policy.check(&message.header) {
+			trace_event!(debug, error = %e, message_type = ?message.header.message_type, request_id = message.header.request_id, service_id = message.header.service_id, "received message with invalid header");
+			let mut flow = LoopFlow::Continue;
+			if message.header.message_type.is_request() {
+				let response = Message::error_response(message.header.request_id, &e.to_string());
+				if let Err((_e, write_flow)) = self.write_message(&response).await {
+					flow = write_flow;
+				}
+			}
+			let _: Result<_, _> = self.send_incoming(Err(e)).await;
+			return flow;
+		}
+
+		// Acknowledgements complete a pending acknowledged stream message, they are not delivered to the read handle.
+		if message.header.message_type.is_stream_ack() {
+			let result_tx = self.pending_acks.senders.remove(&message.header.request_id);
+			#[cfg(feature = "tracing")]
+			if result_tx.is_none() {
+				tracing::debug!(ack_id = message.header.request_id, "received acknowledgement for unknown stream message");
+			}
+			if let Some(result_tx) = result_tx {
+				let _: Result<_, _> = result_tx.send(Ok(()));
+			}
+			return LoopFlow::Continue;
+		}
+
+		// Deadline messages apply to the request that follows them, they are not delivered to the read handle.
+		if message.header.message_type.is_stream() && message.header.service_id == crate::service_id::DEADLINE {
+			match message.parse_deadline() {
+				// A deadline too far in the future to represent is no deadline at all.
+				Ok(remaining) => self.pending_deadline = command.received_at.checked_add(remaining).map(|deadline| (message.header.request_id, deadline)),
+				Err(_e) => {
+					trace_event!(debug, error = %_e, request_id = message.header.request_id, "received invalid deadline message");
+					self.pending_deadline = None;
+				},
+			}
+			return LoopFlow::Continue;
+		}
+
+		// Trace ID messages also apply to the request that follows them.
+		if message.header.message_type.is_stream() && message.header.service_id == crate::service_id::TRACE_ID {
+			match message.parse_trace_id() {
+				Ok(trace_id) => self.pending_trace_id = Some((message.header.request_id, trace_id)),
+				Err(_e) => {
+					trace_event!(debug, error = %_e, request_id = message.header.request_id, "received invalid trace ID message");
+					self.pending_trace_id = None;
+				},
+			}
+			return LoopFlow::Continue;
+		}
+
+		// Type fingerprint messages also apply to the request that follows them.
+		if message.header.message_type.is_stream() && message.header.service_id == crate::service_id::TYPE_FINGERPRINT {
+			match message.parse_type_fingerprint() {
+				Ok(type_fingerprint) => self.pending_type_fingerprint = Some((message.header.request_id, type_fingerprint)),
+				Err(_e) => {
+					trace_event!(debug, error = %_e, request_id = message.header.request_id, "received invalid type fingerprint message");
+					self.pending_type_fingerprint = None;
+				},
+			}
+			return LoopFlow::Continue;
+		}
+
+		// Header flag announcements enable sending header flags to the remote peer.
+		if message.header.message_type.is_stream() && message.header.service_id == crate::service_id::HEADER_FLAGS {
+			trace_event!(debug, "remote peer supports header flags");
+			self.remote_header_flags = true;
+			return LoopFlow::Continue;
+		}
+
+		// Trace ID announcements enable sending trace IDs to the remote peer.
+		if message.header.message_type.is_stream() && message.header.service_id == crate::service_id::TRACE_ID_SUPPORT {
+			trace_event!(debug, "remote peer supports trace IDs");
+			self.remote_trace_ids = true;
+			return LoopFlow::Continue;
+		}
+		let pending_deadline = self.pending_deadline.take();
+		let pending_trace_id = self.pending_trace_id.take();
+		let pending_type_fingerprint = self.pending_type_fingerprint.take();
+
+		// Acknowledge the receipt of acknowledged stream messages right away,
+		// then process them like any other stream message.
+		if message.header.message_type.is_acked_stream() {
+			let ack = Message::stream_ack(message.header.request_id, message.header.service_id);
+			if let Err((_e, flow)) = self.write_message(&ack).await {
+				if flow == LoopFlow::Stop {
+					return flow;
+				}
+			}
+			message.header.message_type = crate::MessageType::Stream;
+		}
+
+		// Let the interceptor drop the message before it reaches the request tracker.
+		if let Some(interceptor) = self.interceptor.as_mut() {
+			if let Err(e) = interceptor.incoming(&mut message) {
+				trace_event!(debug, error = %e, service_id = message.header.service_id, "interceptor rejected incoming message");
+				if !message.header.message_type.is_request() {
+					return LoopFlow::Continue;
+				}
+				let response = Message::error_response(message.header.request_id, &e.to_string());
+				return match self.write_message(&response).await {
+					Ok(()) => LoopFlow::Continue,
+					Err((_e, flow)) => flow,
+				};
+			}
+		}
+
+		// Annotate requests with the labels and context of the connection, and let the interceptor add more annotations.
+		let mut annotations = None;
+		if message.header.message_type.is_request() && (!self.labels.is_empty() || !self.context.is_empty() || self.interceptor.is_some()) {
+			let annotations = annotations.insert(Annotations::new());
+			if !self.labels.is_empty() {
+				annotations.insert(self.labels.clone());
+			}
+			if !self.context.is_empty() {
+				annotations.insert(self.context.clone());
+			}
+			if let Some(interceptor) = self.interceptor.as_mut() {
+				interceptor.annotate(&message, annotations);
+			}
+		}
 
 		// Forward errors from the request tracker too.
-		let incoming = match self.request_tracker.process_incoming_message(message).await {
+		let header = message.header;
+		let mut incoming = match self.request_tracker.process_incoming_message(message, command.received_at).await {
 			Ok(None) => return LoopFlow::Continue,
 			Ok(Some(x)) => x,
 			Err(e) => {
+				trace_event!(debug, error = %e, "failed to process incoming message");
+				let mut flow = LoopFlow::Continue;
+				// Requests over the limit are always answered, so the remote peer does not wait for them forever.
+				let response = if e.kind() == crate::ErrorKind::TooManyOpenRequests {
+					Some(Message::error_response(header.request_id, &e.to_string()))
+				} else if self.bad_request_responses && header.message_type.is_request() {
+					// The response carries the re-used request ID, so it also closes the original request on the remote side.
+					// Close the local request too, so that it can not be answered a second time.
+					if e.kind() == crate::ErrorKind::DuplicateRequestId {
+						let _: Result<_, _> = self.request_tracker.remove_received_request(header.request_id);
+					}
+					Some(Message::error_response(header.request_id, &bad_request_message(&e)))
+				} else {
+					None
+				};
+				if let Some(response) = response {
+					if let Err((_e, write_flow)) = self.write_message(&response).await {
+						flow = write_flow;
+					}
+				}
 				let _: Result<_, _> = self.send_incoming(Err(e)).await;
-				return LoopFlow::Continue;
+				return flow;
 			},
 		};
 
+		let mut expiring_request = None;
+		if let ReceivedMessage::Request(request, _body) = &mut incoming {
+			match pending_deadline {
+				Some((request_id, deadline)) if request_id == request.request_id() => request.set_deadline(Some(deadline)),
+				_ => (),
+			}
+			match pending_trace_id {
+				Some((request_id, trace_id)) if request_id == request.request_id() => request.set_trace_id(Some(trace_id)),
+				_ => (),
+			}
+			match pending_type_fingerprint {
+				Some((request_id, type_fingerprint)) if request_id == request.request_id() => request.set_type_fingerprint(Some(type_fingerprint)),
+				_ => (),
+			}
+			if let Some(annotations) = annotations {
+				request.set_annotations(annotations);
+			}
+			if let Some(expires_at) = self.request_expiry.and_then(|expiry| command.received_at.checked_add(expiry)) {
+				let picked_up = Arc::new(AtomicBool::new(false));
+				request.set_picked_up_flag(picked_up.clone());
+				expiring_request = Some(ExpiringRequest {
+					expires_at,
+					request_id: request.request_id(),
+					picked_up,
+				});
+			}
+		}
+
 		// Deliver the message to the peer read handle.
+		// The message is counted before sending it, so the read handle never sees a negative queue length.
+		self.stats.incoming_queued();
 		match self.incoming_tx.send(Ok(incoming)) {
-			Ok(()) => LoopFlow::Continue,
+			Ok(()) => {
+				self.expiring_requests.extend(expiring_request);
+				LoopFlow::Continue
+			},
 
-			// The read handle was dropped.
+			// The read handle was dropped, or the incoming queue is full.
 			// `msg` must be Ok(), because we checked it before.
-			Err(mpsc::error::SendError(msg)) => match msg.unwrap() {
-				// Respond to requests with an error.
-				ReceivedMessage::Request(request, _body) => {
-					let error_msg = format!("unexpected request for service {}", request.service_id());
-					let response = Message::error_response(request.request_id(), &error_msg);
-					if self.write_message(&response).await.is_err() {
-						// If we can't send the error to the remote peer, just close the connection.
-						// Even if the transport doesn't say that the write error is fatal.
-						LoopFlow::Stop
-					} else {
+			Err(e) => {
+				self.stats.incoming_dequeued();
+				let queue_full = matches!(e, SendError::Full(_));
+				match e.into_inner().unwrap() {
+					// Respond to requests with an error.
+					ReceivedMessage::Request(request, _body) => {
+						let error_msg = if queue_full {
+							trace_event!(debug, request_id = request.request_id(), service_id = request.service_id(), "incoming queue is full, rejecting incoming request");
+							let _: Result<_, _> = self.request_tracker.remove_received_request(request.request_id());
+							INCOMING_QUEUE_FULL_MESSAGE.to_owned()
+						} else {
+							if !self.read_handle_dropped {
+								self.read_handle_dropped = true;
+								trace_event!(warn, "read handle was dropped but the connection is still open, incoming requests are rejected with an error response");
+							}
+							trace_event!(debug, request_id = request.request_id(), service_id = request.service_id(), "read handle was dropped, rejecting incoming request");
+							format!("unexpected request for service {}", request.service_id())
+						};
+						let response = Message::error_response(request.request_id(), &error_msg);
+						if self.write_message(&response).await.is_err() {
+							// If we can't send the error to the remote peer, just close the connection.
+							// Even if the transport doesn't say that the write error is fatal.
+							LoopFlow::Stop
+						} else {
+							LoopFlow::Continue
+						}
+					},
+					ReceivedMessage::Stream(_message) => {
+						if queue_full {
+							trace_event!(debug, service_id = _message.header.service_id, "incoming queue is full, dropping stream message");
+						}
 						LoopFlow::Continue
-					}
-				},
-				ReceivedMessage::Stream(_) => LoopFlow::Continue,
+					},
+				}
 			},
 		}
 	}
 
-	/// Send an incoming message to the PeerHandle.
-	async fn send_incoming(&mut self, incoming: Result<ReceivedMessage<W::Body>, Error>) -> Result<(), ()> {
-		if self.incoming_tx.send(incoming).is_err() {
-			*self.read_handle_dropped = true;
-			Err(())
-		} else {
-			Ok(())
+	/// Flush the transport and stop the loop after the read handle was dropped.
+	async fn close_after_read_handle_drop(&mut self) -> LoopFlow {
+		trace_event!(info, "read handle was dropped, closing connection");
+		if let Err(_e) = self.write_half.flush().await {
+			trace_event!(debug, error = %_e, fatal = _e.is_fatal(), "failed to flush write half");
+		}
+		LoopFlow::Stop
+	}
+
+	/// Stop the loop after all read and write handles were dropped.
+	///
+	/// Commands that were already queued, for example responses sent through request handles, are processed first.
+	/// Then the transport is flushed.
+	async fn stop_after_handles_dropped(&mut self) -> LoopFlow {
+		trace_event!(debug, "all handles dropped, closing connection");
+		self.process_queued_commands().await;
+		LoopFlow::Stop
+	}
+
+	/// Stop the loop after the shutdown signal completed.
+	///
+	/// Commands that were already queued are processed first, and the transport is flushed.
+	/// Then open requests and the read handle are notified of the shutdown.
+	async fn shutdown(&mut self) -> LoopFlow {
+		trace_event!(info, "shutdown requested, closing connection");
+		self.process_queued_commands().await;
+		self.request_tracker.shutdown();
+		let _: Result<_, _> = self.send_incoming(Err(Error::shutdown())).await;
+		LoopFlow::Stop
+	}
+
+	/// Close the command channel, process the commands that are already queued and flush the transport.
+	async fn process_queued_commands(&mut self) {
+		// Close the command channel so no new commands are queued, but process the commands that are already queued.
+		self.command_rx.close();
+		loop {
+			// Make room for deferred stream messages before taking more commands.
+			if self.deferred_streams_full() {
+				if let Some(stream) = self.next_deferred_stream() {
+					if self.send_deferred_stream(stream).await == LoopFlow::Stop {
+						break;
+					}
+					continue;
+				}
+			}
+			let Some(command) = self.command_rx.recv().await else {
+				break;
+			};
+			let Some(command) = self.defer_stream(command) else {
+				continue;
+			};
+			if self.process_command(command).await == LoopFlow::Stop {
+				break;
+			}
+		}
+		let _: LoopFlow = self.write_deferred_streams().await;
+		if let Err(_e) = self.write_half.flush().await {
+			trace_event!(debug, error = %_e, fatal = _e.is_fatal(), "failed to flush write half");
+		}
+	}
+
+	/// Answer all received requests that expired before the application picked them up.
+	async fn expire_requests(&mut self) -> LoopFlow {
+		let now = Instant::now();
+		while let Some(request) = self.expiring_requests.front() {
+			if request.expires_at > now {
+				break;
+			}
+			let request = self.expiring_requests.pop_front().unwrap();
+
+			// If the flag was already set, the application picked up the request in time.
+			if request.picked_up.swap(true, Ordering::AcqRel) {
+				continue;
+			}
+
+			trace_event!(debug, request_id = request.request_id, "received request expired before it was picked up");
+			let _: Result<_, _> = self.request_tracker.remove_received_request(request.request_id);
+			let expiry = self.request_expiry.unwrap_or_default();
+			let response = Message::retry_after_response(request.request_id, expiry, REQUEST_EXPIRED_MESSAGE);
+			if let Err((_e, flow)) = self.write_message(&response).await {
+				if flow == LoopFlow::Stop {
+					return flow;
+				}
+			}
+		}
+		LoopFlow::Continue
+	}
+
+	/// Send an incoming message to the PeerHandle.
+	///
+	/// Fails if the read handle was dropped or the incoming queue is full.
+	async fn send_incoming(&mut self, incoming: Result<ReceivedMessage<W::Body>, Error>) -> Result<(), ()> {
+		self.stats.incoming_queued();
+		if self.incoming_tx.send(incoming).is_err() {
+			self.stats.incoming_dequeued();
+			Err(())
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Pass an outgoing message through the interceptor and the egress policy.
+	fn check_outgoing(&mut self, message: &mut Message<W::Body>) -> Result<(), Error> {
+		if let Some(interceptor) = self.interceptor.as_mut() {
+			let result = interceptor.outgoing(message);
+			#[cfg(feature = "tracing")]
+			if let Err(e) = &result {
+				tracing::debug!(error = %e, service_id = message.header.service_id, "interceptor rejected outgoing message");
+			}
+			result?;
+		}
+		self.check_egress_policy(message)
+	}
+
+	/// Process a SendStreamBatch command.
+	async fn send_stream_batch(&mut self, command: crate::peer::SendStreamBatch<W::Body>) -> LoopFlow {
+		// Check all messages first, and cut the batch short at the first rejected message.
+		let mut messages = Vec::with_capacity(command.messages.len());
+		let mut result = Ok(());
+		for mut message in command.messages {
+			if let Err(e) = self.check_outgoing(&mut message) {
+				result = Err(e);
+				break;
+			}
+			messages.push(message);
+		}
+
+		if self.write_finished {
+			let _: Result<_, _> = command.result_tx.send(Err(write_half_finished()));
+			return LoopFlow::Continue;
+		}
+
+		if !self.remote_header_flags {
+			for message in &mut messages {
+				message.header.flags = 0;
+			}
+		}
+
+		// Write the accepted messages in one go, so the transport can coalesce them.
+		let mut written = 0;
+		let mut retries = 0;
+		while let Err(e) = self.write_half.write_msgs(&messages, &mut written).await {
+			trace_event!(debug, error = %e, fatal = e.is_fatal(), written, "failed to write batch of messages");
+			if let Some(delay) = self.retry_delay(&e, &mut retries) {
+				self.runtime.sleep_until(Instant::now() + delay).await;
+				continue;
+			}
+			let flow = if e.is_fatal() {
+				LoopFlow::Stop
+			} else {
+				LoopFlow::Continue
+			};
+			let _: Result<_, _> = command.result_tx.send(Err(e.into_inner()));
+			return flow;
+		}
+
+		let _: Result<_, _> = command.result_tx.send(result);
+		LoopFlow::Continue
+	}
+
+	/// Check an outgoing message against the egress policy, if there is one.
+	fn check_egress_policy(&mut self, message: &Message<W::Body>) -> Result<(), Error> {
+		use crate::Body;
+		// A panicking policy poisons the mutex, but the policy itself is still usable.
+		let mut policy = match self.egress_policy.lock() {
+			Ok(x) => x,
+			Err(e) => e.into_inner(),
+		};
+		let policy = match policy.as_mut() {
+			Some(x) => x,
+			None => return Ok(()),
+		};
+
+		let result = policy.check(&message.header, message.body.data_len());
+		#[cfg(feature = "tracing")]
+		if let Err(e) = &result {
+			tracing::debug!(error = %e, service_id = message.header.service_id, "egress policy rejected message");
+		}
+		result
+	}
+
+	async fn write_message(&mut self, message: &Message<W::Body>) -> Result<(), (Error, LoopFlow)> {
+		use crate::Body;
+		if self.write_finished {
+			return Err((write_half_finished(), LoopFlow::Continue));
+		}
+
+		// Only send header flags to peers that announced support for them.
+		let mut header = message.header;
+		if !self.remote_header_flags {
+			header.flags = 0;
+		}
+
+		let mut retries = 0;
+		loop {
+			match self.write_half.write_msg(&header, &message.body).await {
+				Ok(()) => {
+					self.stats.message_sent(crate::HEADER_LEN as usize + message.body.data_len());
+					return Ok(());
+				},
+				Err(e) => {
+					trace_event!(debug, error = %e, fatal = e.is_fatal(), "failed to write message");
+					if let Some(delay) = self.retry_delay(&e, &mut retries) {
+						self.runtime.sleep_until(Instant::now() + delay).await;
+						continue;
+					}
+					let flow = if e.is_fatal() {
+						LoopFlow::Stop
+					} else {
+						LoopFlow::Continue
+					};
+					return Err((e.into_inner(), flow));
+				},
+			}
+		}
+	}
+
+	/// Get the delay before retrying a failed write, if the retry policy allows another retry.
+	///
+	/// Returns `None` if the error should be reported instead.
+	fn retry_delay(&self, error: &crate::transport::TransportError, retries: &mut u32) -> Option<Duration> {
+		let policy = self.write_retry?;
+		if error.is_fatal() || error.inner().as_payload_too_large().is_some() || *retries >= policy.max_retries {
+			return None;
+		}
+
+		*retries += 1;
+		self.stats.write_retried();
+		#[cfg(feature = "metrics")]
+		{
+			let labels: Vec<_> = self.labels.iter().map(|(key, value)| metrics::Label::new(key.to_owned(), value.to_owned())).collect();
+			metrics::counter!("fizyr_rpc_write_retries_total", labels).increment(1);
+		}
+		trace_event!(debug, retry = *retries, delay = ?policy.delay, "retrying failed write");
+		Some(policy.delay)
+	}
+}
+
+/// Truncate the message of an error response to at most `max_len` bytes.
+///
+/// Returns true if the message is an error response that was truncated.
+/// Other messages are never modified.
+fn truncate_error_response<Body: crate::Body>(message: &mut Message<Body>, max_len: usize) -> bool {
+	let is_error_response = message.header.message_type.is_response() && message.header.service_id == crate::service_id::ERROR;
+	if !is_error_response || message.body.data_len() <= max_len {
+		return false;
+	}
+
+	// Invalid UTF-8 can not be truncated safely, so it is replaced entirely.
+	let truncated = match message.body.as_error() {
+		Ok(error) => truncate_error_message(error, max_len),
+		Err(_) => truncate_error_message("error message with invalid UTF-8 was too long", max_len),
+	};
+	message.body = Body::from_error(&truncated);
+	true
+}
+
+/// Acknowledged stream messages that are waiting for an acknowledgement.
+struct PendingAcks {
+	/// The ID to try for the next acknowledged stream message.
+	next_id: u32,
+
+	/// Channels to report the acknowledgement on, by acknowledgement ID.
+	senders: HashMap<u32, oneshot::Sender<Result<(), Error>>>,
+
+	/// The maximum number of acknowledged stream messages waiting for an acknowledgement.
+	max_len: usize,
+}
+
+impl PendingAcks {
+	/// Create an empty set of pending acknowledgements.
+	fn new(max_len: usize) -> Self {
+		Self {
+			next_id: 0,
+			senders: HashMap::new(),
+			max_len,
+		}
+	}
+
+	/// Allocate an acknowledgement ID that is not in use.
+	///
+	/// Entries of acknowledged stream messages that are no longer awaited are discarded first,
+	/// so that dropped or timed out futures do not leak.
+	fn allocate_id(&mut self) -> Result<u32, Error> {
+		self.senders.retain(|_, result_tx| !result_tx.is_closed());
+		if self.senders.len() >= self.max_len {
+			return Err(InnerError::CapacityExceeded.into());
+		}
+
+		// Try to find a free ID a bunch of times, but eventually give up.
+		for _ in 0..100 {
+			let id = self.next_id;
+			self.next_id = self.next_id.wrapping_add(1);
+			if !self.senders.contains_key(&id) {
+				return Ok(id);
+			}
+		}
+		Err(InnerError::NoFreeRequestIdFound.into())
+	}
+}
+
+/// Generator for random trace IDs.
+struct TraceIdGenerator {
+	/// The randomly seeded hash state to generate trace IDs with.
+	state: RandomState,
+
+	/// Counter to make each generated trace ID unique.
+	counter: u64,
+}
+
+impl TraceIdGenerator {
+	/// Create a new generator with a random seed.
+	fn new() -> Self {
+		Self {
+			state: RandomState::new(),
+			counter: 0,
+		}
+	}
+
+	/// Generate the next trace ID.
+	fn next(&mut self) -> u64 {
+		let mut hasher = self.state.build_hasher();
+		hasher.write_u64(self.counter);
+		self.counter = self.counter.wrapping_add(1);
+		hasher.finish()
+	}
+}
+
+/// Loop control flow command.
+///
+/// Allows other methods to make decisions on loop control flow.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum LoopFlow {
+	/// Keep the loop running.
+	Continue,
+
+	/// Stop the loop.
+	Stop,
+}
+
+/// Event for the command loop.
+enum Event<Body> {
+	/// A command was received.
+	Command(Command<Body>),
+
+	/// A deferred stream message can be written, because no other commands are waiting.
+	DeferredStream(DeferredStream<Body>),
+
+	/// The first expiring request is due.
+	Expired,
+
+	/// The read handle was dropped and the connection should be closed.
+	ReadHandleDropped,
+
+	/// The shutdown signal passed to [`Peer::run_until()`] completed.
+	Shutdown,
+
+	/// The periodic audit of the request tracker is due.
+	#[cfg(all(debug_assertions, feature = "tracing"))]
+	Audit,
+
+	/// All read and write handles were dropped, so the loop should stop after the queued commands.
+	HandlesDropped,
+
+	/// The loop should stop.
+	Stop,
+}
+
+/// A stream message held back by the [`BoundedLatencyPolicy`].
+enum DeferredStream<Body> {
+	/// A single stream message.
+	Message(SendRawMessage<Body>),
+
+	/// A batch of stream messages.
+	Batch(SendStreamBatch<Body>),
+}
+
+impl<Body> DeferredStream<Body> {
+	/// Get the time when the stream message was queued.
+	fn queued_at(&self) -> Instant {
+		match self {
+			Self::Message(command) => command.queued_at,
+			Self::Batch(command) => command.queued_at,
+		}
+	}
+}
+
+/// Command to send a request to the remote peer.
+pub struct SendRequest<Body> {
+	/// The service ID for the request.
+	pub service_id: i32,
+
+	/// The body for the request.
+	pub body: Body,
+
+	/// The deadline to send along with the request.
+	pub deadline: Option<Instant>,
+
+	/// The trace ID to send along with the request.
+	pub trace_id: Option<u64>,
+
+	/// The type fingerprint to send along with the request.
+	pub type_fingerprint: Option<u64>,
+
+	/// One-shot channel to transmit back the created [`SentRequestHandle`] object, or an error.
+	pub result_tx: oneshot::Sender<Result<SentRequestHandle<Body>, Error>>,
+}
+
+/// Command to send a request to the remote peer and wait only for the response.
+pub struct SendOneshotRequest<Body> {
+	/// The service ID for the request.
+	pub service_id: i32,
+
+	/// The body for the request.
+	pub body: Body,
+
+	/// One-shot channel to transmit back the response, or an error.
+	pub response_tx: oneshot::Sender<Result<Message<Body>, Error>>,
+}
+
+/// Command to send a raw message to the remote peer.
+pub struct SendRawMessage<Body> {
+	/// The message to send.
+	pub message: Message<Body>,
+
+	/// The time when the message was queued.
+	pub queued_at: Instant,
+
+	/// One-shot channel to receive the result of sending the message.
+	pub result_tx: oneshot::Sender<Result<(), Error>>,
+}
+
+/// Command to send an acknowledged stream message to the remote peer.
+pub struct SendAckedStream<Body> {
+	/// The service ID for the stream message.
+	pub service_id: i32,
+
+	/// The body for the stream message.
+	pub body: Body,
+
+	/// One-shot channel to receive the acknowledgement, or an error.
+	pub result_tx: oneshot::Sender<Result<(), Error>>,
+}
+
+/// Command to send a batch of stream messages to the remote peer.
+pub struct SendStreamBatch<Body> {
+	/// The stream messages to send.
+	pub messages: Vec<Message<Body>>,
+
+	/// The time when the messages were queued.
+	pub queued_at: Instant,
+
+	/// One-shot channel to receive the result of sending the messages.
+	pub result_tx: oneshot::Sender<Result<(), Error>>,
+}
+
+/// Command to flush all queued messages to the transport.
+pub struct Flush {
+	/// One-shot channel to receive the result of the flush.
+	pub result_tx: oneshot::Sender<Result<(), Error>>,
+}
+
+/// Command to flush all queued messages and shut down the write half of the transport.
+pub struct Finish {
+	/// One-shot channel to receive the result of the shutdown.
+	pub result_tx: oneshot::Sender<Result<(), Error>>,
+}
+
+/// Command to audit the request tracker.
+pub struct Audit {
+	/// One-shot channel to receive the result of the audit.
+	pub result_tx: oneshot::Sender<TrackerAudit>,
+}
+
+/// Command to process an incoming message from the remote peer.
+pub struct ProcessReceivedMessage<Body> {
+	/// The message from the remote peer, or an error.
+	pub message: Result<Message<Body>, Error>,
+
+	/// The time when the message was read from the transport.
+	pub received_at: Instant,
+}
+
+impl<Body> std::fmt::Debug for Command<Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		let mut debug = f.debug_struct("Command");
+		match self {
+			Self::SendRequest(x) => debug.field("SendRequest", x),
+			Self::SendOneshotRequest(x) => debug.field("SendOneshotRequest", x),
+			Self::SendRawMessage(x) => debug.field("SendRawMessage", x),
+			Self::SendAckedStream(x) => debug.field("SendAckedStream", x),
+			Self::SendStreamBatch(x) => debug.field("SendStreamBatch", x),
+			Self::ProcessReceivedMessage(x) => debug.field("ProcessReceivedMessage", x),
+			Self::Flush(x) => debug.field("Flush", x),
+			Self::Finish(x) => debug.field("Finish", x),
+			Self::Audit(x) => debug.field("Audit", x),
+			Self::Stop => debug.field("Stop", &()),
+		}.finish()
+	}
+}
+
+impl<Body> std::fmt::Debug for SendRequest<Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("SendRequest")
+			.field("service_id", &self.service_id)
+			.field("deadline", &self.deadline)
+			.field("trace_id", &self.trace_id)
+			.field("type_fingerprint", &self.type_fingerprint)
+			.finish()
+	}
+}
+
+impl<Body> std::fmt::Debug for SendOneshotRequest<Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("SendOneshotRequest")
+			.field("service_id", &self.service_id)
+			.finish()
+	}
+}
+
+impl<Body> std::fmt::Debug for SendRawMessage<Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("SendRawMessage").field("message", &self.message).finish()
+	}
+}
+
+impl<Body> std::fmt::Debug for SendAckedStream<Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("SendAckedStream").field("service_id", &self.service_id).finish()
+	}
+}
+
+impl<Body> std::fmt::Debug for SendStreamBatch<Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("SendStreamBatch").field("messages", &self.messages.len()).finish()
+	}
+}
+
+impl<Body> std::fmt::Debug for ProcessReceivedMessage<Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("ProcessReceivedMessage")
+			.field("message", &self.message)
+			.field("received_at", &self.received_at)
+			.finish()
+	}
+}
+
+impl std::fmt::Debug for Flush {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("Flush").finish()
+	}
+}
+
+impl std::fmt::Debug for Audit {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("Audit").finish()
+	}
+}
+
+impl std::fmt::Debug for Finish {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("Finish").finish()
+	}
+}
+
+impl<Body> From<SendRequest<Body>> for Command<Body> {
+	fn from(other: SendRequest<Body>) -> Self {
+		Self::SendRequest(other)
+	}
+}
+
+impl<Body> From<SendOneshotRequest<Body>> for Command<Body> {
+	fn from(other: SendOneshotRequest<Body>) -> Self {
+		Self::SendOneshotRequest(other)
+	}
+}
+
+impl<Body> From<SendRawMessage<Body>> for Command<Body> {
+	fn from(other: SendRawMessage<Body>) -> Self {
+		Self::SendRawMessage(other)
+	}
+}
+
+impl<Body> From<SendAckedStream<Body>> for Command<Body> {
+	fn from(other: SendAckedStream<Body>) -> Self {
+		Self::SendAckedStream(other)
+	}
+}
+
+impl<Body> From<SendStreamBatch<Body>> for Command<Body> {
+	fn from(other: SendStreamBatch<Body>) -> Self {
+		Self::SendStreamBatch(other)
+	}
+}
+
+impl<Body> From<ProcessReceivedMessage<Body>> for Command<Body> {
+	fn from(other: ProcessReceivedMessage<Body>) -> Self {
+		Self::ProcessReceivedMessage(other)
+	}
+}
+
+impl<Body> From<Flush> for Command<Body> {
+	fn from(other: Flush) -> Self {
+		Self::Flush(other)
+	}
+}
+
+impl<Body> From<Finish> for Command<Body> {
+	fn from(other: Finish) -> Self {
+		Self::Finish(other)
+	}
+}
+
+impl<Body> From<Audit> for Command<Body> {
+	fn from(other: Audit) -> Self {
+		Self::Audit(other)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+
+	use crate::MessageHeader;
+	use crate::transport::StreamTransport;
+	use std::time::Duration;
+	use tokio::net::UnixStream;
+
+	#[tokio::test]
+	async fn test_peer() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+
+		let (peer_a, handle_a) = Peer::new(StreamTransport::new(peer_a, Default::default()));
+		let (peer_b, mut handle_b) = Peer::new(StreamTransport::new(peer_b, Default::default()));
+
+		let task_a = tokio::spawn(peer_a.run());
+		let task_b = tokio::spawn(peer_b.run());
+
+		// Send a request from A.
+		let_assert!(Ok(mut sent_request) = handle_a.send_request(1, &[2][..]).await);
+		let request_id = sent_request.request_id();
+
+		// Receive the request on B.
+		let_assert!(Ok(ReceivedMessage::Request(mut received_request, _body)) = handle_b.recv_message().await);
+
+		// Send an update from A and receive it on B.
+		let_assert!(Ok(()) = sent_request.send_update(3, &[4][..]).await);
+		let_assert!(Some(update) = received_request.recv_update().await);
+		assert!(update.header == MessageHeader::requester_update(request_id, 3));
+		assert!(update.body.as_ref() == &[4]);
+
+		// Send an update from B and receive it on A.
+		let_assert!(Ok(()) = received_request.send_update(5, &[6][..]).await);
+		let_assert!(Some(update) = sent_request.recv_update().await);
+		assert!(update.header == MessageHeader::responder_update(request_id, 5));
+		assert!(update.body.as_ref() == &[6]);
+
+		// Send the response from B and receive it on A.
+		let_assert!(Ok(()) = received_request.send_response(7, &[8][..]).await);
+		let_assert!(Ok(response) = sent_request.recv_response().await);
+		assert!(response.header == MessageHeader::response(request_id, 7));
+		assert!(response.body.as_ref() == &[8]);
+
+		drop(handle_a);
+		drop(handle_b);
+		drop(sent_request);
+
+		assert!(let Ok(()) = task_a.await);
+		assert!(let Ok(()) = task_b.await);
+	}
+
+	#[tokio::test]
+	async fn peeked_response_is_not_gone() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
+
+		// Send a request from A.
+		let_assert!(Ok(mut sent_request) = handle_a.send_request(1, &[2][..]).await);
+		let request_id = sent_request.request_id();
+
+		// Receive the request on B.
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
+
+		// Send two updates and a response from B to A.
+		let_assert!(Ok(()) = received_request.send_update(5, &b"Hello world!"[..]).await);
+		let_assert!(Ok(()) = received_request.send_update(6, &b"Hello world!"[..]).await);
+		let_assert!(Ok(()) = received_request.send_response(7, &b"Goodbye!"[..]).await);
+
+		// Try to receive three responses.
+		// This should stuff the response in the internal peek buffer.
+		assert!(let Some(_) = sent_request.recv_update().await);
+		assert!(let Some(_) = sent_request.recv_update().await);
+		assert!(let None = sent_request.recv_update().await);
+
+		// Now receive the response, which should be returned intact from the peek buffer exactly once.
+		let_assert!(Ok(response) = sent_request.recv_response().await);
+		assert!(let Err(_) = sent_request.recv_response().await);
+
+		assert!(response.header == MessageHeader::response(request_id, 7));
+		assert!(response.body.as_ref() == b"Goodbye!");
+	}
+
+	#[tokio::test]
+	async fn peeked_update_is_not_gone() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
+
+		// Send a request from A.
+		let_assert!(Ok(mut sent_request) = handle_a.send_request(1, &[2][..]).await);
+		let request_id = sent_request.request_id();
+
+		// Receive the request on B.
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
+
+		// Send one update and a response from B to A.
+		let_assert!(Ok(()) = received_request.send_update(5, &b"Hello world!"[..]).await);
+		let_assert!(Ok(()) = received_request.send_response(6, &b"Goodbye!"[..]).await);
+
+		// Trying to read a response should stuff the update in the internal peek buffer.
+		assert!(let Err(_) = sent_request.recv_response().await);
+
+		// Now we should receive the update intact from the peek buffer exactly once.
+		let_assert!(Some(update) = sent_request.recv_update().await);
+		assert!(update.header == MessageHeader::responder_update(request_id, 5));
+		assert!(update.body.as_ref() == b"Hello world!");
+		assert!(let None = sent_request.recv_update().await);
+
+		// Now receive the response.
+		let_assert!(Ok(response) = sent_request.recv_response().await);
+		assert!(response.header == MessageHeader::response(request_id, 6));
+		assert!(response.body.as_ref() == b"Goodbye!");
+	}
+
+	#[tokio::test]
+	async fn egress_policy() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let mut handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
+
+		// Reject stream messages and responses with service ID 5.
+		handle_a.set_egress_policy(|header: &MessageHeader, _body_len: usize| {
+			if header.service_id == 5 && header.message_type != crate::MessageType::Request {
+				Err(Error::custom("service 5 is blocked".into()))
+			} else {
+				Ok(())
+			}
+		});
+
+		let_assert!(Err(e) = handle_a.send_stream(5, &b"blocked"[..]).await);
+		assert!(e.to_string() == "service 5 is blocked");
+		let_assert!(Ok(()) = handle_a.send_stream(6, &b"allowed"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_b.recv_message().await);
+		assert!(message.header.service_id == 6);
+
+		// A rejected response leaves the request open, so a different response can still be sent.
+		let_assert!(Ok(mut sent_request) = handle_b.send_request(5, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_a.recv_message().await);
+		assert!(let Err(_) = received_request.send_response(5, &b"blocked"[..]).await);
+		let_assert!(Ok(()) = received_request.send_response(7, &b"allowed"[..]).await);
+		let_assert!(Ok(response) = sent_request.recv_response().await);
+		assert!(response.header.service_id == 7);
+
+		// After removing the policy, everything is allowed again.
+		handle_a.remove_egress_policy();
+		let_assert!(Ok(()) = handle_a.send_stream(5, &b"allowed"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_b.recv_message().await);
+		assert!(message.header.service_id == 5);
+	}
+
+	#[tokio::test]
+	async fn egress_policy_panic_does_not_poison_handles() {
+		let_assert!(Ok((peer_a, _peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+
+		// The panic stops the peer, but the handles can still replace or remove the policy.
+		handle_a.set_egress_policy(|_header: &MessageHeader, _body_len: usize| -> Result<(), Error> {
+			panic!("policy panicked");
+		});
+		assert!(let Err(_) = handle_a.send_stream(5, &b"hello"[..]).await);
+		handle_a.set_egress_policy(|_header: &MessageHeader, _body_len: usize| Ok(()));
+		handle_a.remove_egress_policy();
+	}
+
+	/// Interceptor that tags outgoing stream messages and rejects incoming requests for service 9.
+	///
+	/// Accepted incoming requests are annotated with a [`Principal`].
+	struct TagAndBlock;
+
+	/// Annotation added by the [`TagAndBlock`] interceptor.
+	#[derive(Debug, Eq, PartialEq)]
+	struct Principal(&'static str);
+
+	impl Interceptor<crate::StreamBody> for TagAndBlock {
+		fn outgoing(&mut self, message: &mut Message<crate::StreamBody>) -> Result<(), Error> {
+			if message.header.message_type.is_stream() {
+				let mut data = b"tagged:".to_vec();
+				data.extend_from_slice(&message.body);
+				message.body = data.into();
+			}
+			Ok(())
+		}
+
+		fn incoming(&mut self, message: &mut Message<crate::StreamBody>) -> Result<(), Error> {
+			if message.header.message_type.is_request() && message.header.service_id == 9 {
+				Err(Error::custom("access denied to service 9".into()))
+			} else {
+				Ok(())
+			}
+		}
+
+		fn annotate(&mut self, _message: &Message<crate::StreamBody>, annotations: &mut Annotations) {
+			annotations.insert(Principal("alice"));
+		}
+	}
+
+	#[tokio::test]
+	async fn interceptor() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let (peer_a, mut handle_a) = Peer::new(StreamTransport::new(peer_a, Default::default()));
+		tokio::spawn(peer_a.with_interceptor(TagAndBlock).run());
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
+
+		// Outgoing messages can be rewritten.
+		let_assert!(Ok(()) = handle_a.send_stream(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_b.recv_message().await);
+		assert!(message.body.as_ref() == b"tagged:hello");
+
+		// Rejected incoming requests are answered with an error, and never reach the read handle.
+		let_assert!(Ok(mut sent_request) = handle_b.send_request(9, &b"hello"[..]).await);
+		let_assert!(Ok(response) = sent_request.recv_response().await);
+		let_assert!(Err(e) = response.check_error_response());
+		assert!(e.as_remote_error() == Some("access denied to service 9"));
+
+		// Other incoming requests are delivered as usual, with the annotations from the interceptor.
+		let_assert!(Ok(mut sent_request) = handle_b.send_request(8, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_request, body)) = handle_a.recv_message().await);
+		assert!(received_request.service_id() == 8);
+		assert!(body.as_ref() == b"hello");
+		assert!(received_request.annotations().get::<Principal>() == Some(&Principal("alice")));
+		let_assert!(Ok(()) = received_request.send_response(8, &b"bye"[..]).await);
+		let_assert!(Ok(response) = sent_request.recv_response().await);
+		assert!(response.body.as_ref() == b"bye");
+	}
+
+	#[tokio::test]
+	async fn acked_stream() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
+
+		// Messages are acknowledged on receipt, before the application reads them.
+		let_assert!(Ok(_rtt) = handle_a.send_stream_acked(1, &b"hello"[..]).await);
+		let_assert!(Ok(_rtt) = handle_a.send_stream_acked(2, &b"world"[..]).await);
+
+		// The application receives them as regular stream messages.
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_b.recv_message().await);
+		assert!(message.header.message_type == crate::MessageType::Stream);
+		assert!(message.header.service_id == 1);
+		assert!(message.body.as_ref() == b"hello");
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_b.recv_message().await);
+		assert!(message.header.service_id == 2);
+		assert!(message.body.as_ref() == b"world");
+	}
+
+	#[tokio::test]
+	async fn stream_batch() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
+
+		let batch = (0..1000).map(|i| (i, format!("record {}", i).into_bytes().into()));
+		let_assert!(Ok(()) = handle_a.send_stream_batch(batch).await);
+		for i in 0..1000 {
+			let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_b.recv_message().await);
+			assert!(message.header.service_id == i);
+			assert!(message.body.as_ref() == format!("record {}", i).as_bytes());
+		}
+	}
+
+	#[tokio::test]
+	async fn finish() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let mut handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
+
+		let_assert!(Ok(()) = handle_b.send_stream(1, &b"from b"[..]).await);
+		let_assert!(Ok(()) = handle_a.send_stream(2, &b"from a"[..]).await);
+		let_assert!(Ok(()) = handle_a.finish().await);
+		let_assert!(Ok(()) = handle_a.finish().await);
+
+		// Nothing can be sent after the write side is shut down.
+		let_assert!(Err(e) = handle_a.send_stream(3, &b"too late"[..]).await);
+		let_assert!(Some(e) = e.as_io_error());
+		assert!(e.kind() == std::io::ErrorKind::BrokenPipe);
+
+		// The remote peer receives all messages sent before the shutdown, followed by the end of the stream.
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_b.recv_message().await);
+		assert!(message.body.as_ref() == b"from a");
+		let_assert!(Err(e) = handle_b.recv_message().await);
+		assert!(e.is_connection_aborted());
+
+		// The read side keeps working until the remote peer closes the connection.
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_a.recv_message().await);
+		assert!(message.body.as_ref() == b"from b");
+		let_assert!(Err(e) = handle_a.recv_message().await);
+		assert!(e.is_connection_aborted());
+	}
+
+	#[tokio::test]
+	async fn send_guarantees() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
+
+		let_assert!(Ok(()) = handle_a.send_stream_flushed(1, &b"hello"[..]).await);
+		let_assert!(Ok(()) = handle_a.send_stream(2, &b"world"[..]).await);
+		let_assert!(Ok(()) = handle_a.flush().await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_b.recv_message().await);
+		assert!(message.body.as_ref() == b"hello");
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_b.recv_message().await);
+		assert!(message.body.as_ref() == b"world");
+
+		// Messages that are not written after the peer is closed are reported as errors.
+		handle_a.close_handle().close();
+		let_assert!(Err(e) = handle_a.send_stream(3, &b"dropped"[..]).await);
+		assert!(e.is_connection_aborted());
+		let_assert!(Err(e) = handle_a.send_stream_flushed(4, &b"dropped"[..]).await);
+		assert!(e.is_connection_aborted());
+		let_assert!(Err(e) = handle_a.flush().await);
+		assert!(e.is_connection_aborted());
+		let_assert!(Err(e) = handle_b.recv_message().await);
+		assert!(e.is_connection_aborted());
+	}
+
+	#[tokio::test]
+	async fn header_flags() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let (peer_a, mut handle_a) = Peer::new(StreamTransport::new(peer_a, Default::default()));
+		let (peer_b, mut handle_b) = Peer::new(StreamTransport::new(peer_b, Default::default()));
+		tokio::spawn(peer_a.with_header_flags(true).run());
+		tokio::spawn(peer_b.run());
+
+		// Peer B did not announce support for header flags, so they are cleared.
+		let_assert!(Ok(()) = handle_a.send_stream_with_flags(1, 0x0100, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_b.recv_message().await);
+		assert!(message.body.as_ref() == b"hello");
+		assert!(message.flags() == 0);
+
+		// Peer B received the announcement of peer A before the stream message, so it sends header flags.
+		let_assert!(Ok(()) = handle_b.send_stream_with_flags(2, 0x0100, &b"world"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_a.recv_message().await);
+		assert!(message.body.as_ref() == b"world");
+		assert!(message.flags() == 0x0100);
+	}
+
+	#[tokio::test]
+	async fn audit() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
+		let_assert!(Ok(audit) = handle_b.audit().await);
+		assert!(audit.is_clean());
+
+		// A received request that is dropped without a response is reported.
+		let_assert!(Ok(sent_request) = handle_a.send_request(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
+		let request_id = received_request.request_id();
+		drop(received_request);
+		let_assert!(Ok(audit) = handle_b.audit().await);
+		assert!(audit.orphaned_received_requests == [request_id]);
+		assert!(audit.orphaned_sent_requests.is_empty());
+
+		// The sent request is still open on the other side.
+		drop(sent_request);
+		let_assert!(Ok(audit) = handle_a.audit().await);
+		assert!(audit.orphaned_sent_requests == [request_id]);
+	}
+
+	#[tokio::test]
+	async fn acked_stream_connection_closed() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+
+		// The remote peer loop never runs, so it never acknowledges the message.
+		let (peer_b, _handle_b) = Peer::new(StreamTransport::new(peer_b, Default::default()));
+		let sent = tokio::spawn(async move { handle_a.send_stream_acked(1, &b"hello"[..]).await });
+		tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+		drop(peer_b);
+
+		let_assert!(Ok(Err(e)) = sent.await);
+		assert!(e.is_connection_aborted());
+	}
+
+	#[test]
+	fn pending_acks_discard_dropped_futures() {
+		let mut pending_acks = PendingAcks::new(2);
+		for _ in 0..10 {
+			// Dropping the future of `send_stream_acked()` drops the receiving end of the channel.
+			let_assert!(Ok(id) = pending_acks.allocate_id());
+			let (result_tx, result_rx) = oneshot::channel();
+			pending_acks.senders.insert(id, result_tx);
+			drop(result_rx);
+		}
+		let_assert!(Ok(_) = pending_acks.allocate_id());
+		assert!(pending_acks.senders.len() == 0);
+
+		// Awaited acknowledgements are kept, and limit the number of pending acknowledgements.
+		let mut receivers = Vec::new();
+		for _ in 0..2 {
+			let_assert!(Ok(id) = pending_acks.allocate_id());
+			let (result_tx, result_rx) = oneshot::channel();
+			pending_acks.senders.insert(id, result_tx);
+			receivers.push(result_rx);
+		}
+		let_assert!(Err(e) = pending_acks.allocate_id());
+		assert!(e.is_capacity_exceeded());
+		assert!(pending_acks.senders.len() == 2);
+	}
+
+	#[tokio::test]
+	async fn bad_request_responses() {
+		use crate::transport::{Transport, TransportReadHalf, TransportWriteHalf};
+
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let (peer_a, mut handle_a) = Peer::new(StreamTransport::new(peer_a, Default::default()));
+		tokio::spawn(peer_a.with_bad_request_responses(true).run());
+		let mut transport_b = StreamTransport::new(peer_b, Default::default());
+		let (mut read_b, mut write_b) = transport_b.split();
+
+		// A request that re-uses an open request ID is rejected with a bad request response.
+		assert!(let Ok(()) = write_b.write_msg(&MessageHeader::request(1, 5), &b"hello"[..].into()).await);
+		assert!(let Ok(()) = write_b.write_msg(&MessageHeader::request(1, 6), &b"hello"[..].into()).await);
+		let_assert!(Ok(response) = read_b.read_msg().await);
+		assert!(response.header == MessageHeader::error_response(1));
+		let_assert!(Err(e) = response.check_error_response());
+		assert!(e.is_bad_request());
+		assert!(let Some("bad request: duplicate request ID: request ID 1 is already associated with an open request") = e.as_remote_error());
+
+		// The rejected request is still reported locally.
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_a.recv_message().await);
+		assert!(received_request.service_id() == 5);
+		let_assert!(Err(e) = handle_a.recv_message().await);
+		assert!(e.to_string() == "duplicate request ID: request ID 1 is already associated with an open request");
+
+		// The error response closed the original request on the remote side, so it is closed locally too.
+		let_assert!(Err(e) = received_request.send_response(5, &b"world"[..]).await);
+		assert!(e.kind() == crate::ErrorKind::RequestClosed);
+
+		// The request ID can be used again by the remote peer.
+		assert!(let Ok(()) = write_b.write_msg(&MessageHeader::request(1, 7), &b"hello"[..].into()).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_a.recv_message().await);
+		assert!(received_request.service_id() == 7);
+		assert!(let Ok(()) = received_request.send_response(7, &b"world"[..]).await);
+		let_assert!(Ok(response) = read_b.read_msg().await);
+		assert!(response.header == MessageHeader::response(1, 7));
+	}
+
+	#[tokio::test]
+	async fn max_open_received_requests() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let (peer_b, mut handle_b) = Peer::new(StreamTransport::new(peer_b, Default::default()));
+		tokio::spawn(peer_b.with_max_open_received_requests(Some(1)).run());
+
+		let_assert!(Ok(mut sent_1) = handle_a.send_request(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_1, _body)) = handle_b.recv_message().await);
+
+		// A second request exceeds the limit and is answered with an error response right away.
+		let_assert!(Ok(mut sent_2) = handle_a.send_request(2, &b"hello"[..]).await);
+		let_assert!(Ok(response) = sent_2.recv_response().await);
+		let_assert!(Err(e) = response.check_error_response());
+		assert!(let Some("too many open requests: at most 1 received requests may be open at the same time") = e.as_remote_error());
+		let_assert!(Err(e) = handle_b.recv_message().await);
+		assert!(e.kind() == crate::ErrorKind::TooManyOpenRequests);
+
+		// Once the open request is answered, new requests are accepted again.
+		assert!(let Ok(()) = received_1.send_response(1, &b"world"[..]).await);
+		let_assert!(Ok(_response) = sent_1.recv_response().await);
+		let_assert!(Ok(_sent_3) = handle_a.send_request(3, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_3, _body)) = handle_b.recv_message().await);
+		assert!(received_3.service_id() == 3);
+	}
+
+	#[tokio::test]
+	async fn request_deadline() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
+
+		let deadline = Instant::now() + Duration::from_secs(10);
+		let_assert!(Ok(_sent_request) = handle_a.send_request_with_deadline(1, &b"hello"[..], Some(deadline)).await);
+		let_assert!(Ok(_sent_request) = handle_a.send_request(2, &b"hello"[..]).await);
+
+		// The deadline is sent as remaining time, so it shifts by the time it takes to deliver the message.
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
+		assert!(received_request.service_id() == 1);
+		let_assert!(Some(received_deadline) = received_request.deadline());
+		assert!(received_deadline > deadline - Duration::from_secs(1));
+		assert!(received_deadline < deadline + Duration::from_secs(1));
+		assert!(received_request.write_handle().deadline() == Some(received_deadline));
+
+		// The deadline only applies to a single request.
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
+		assert!(received_request.service_id() == 2);
+		assert!(received_request.deadline() == None);
+
+		// Deadlines that already passed are sent as zero remaining time.
+		let_assert!(Ok(_sent_request) = handle_a.send_request_with_deadline(3, &b"hello"[..], Some(Instant::now())).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
+		let_assert!(Some(received_deadline) = received_request.deadline());
+		assert!(received_deadline <= Instant::now());
+	}
+
+	#[tokio::test]
+	async fn request_expiry() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let (peer_b, mut handle_b) = Peer::new(StreamTransport::new(peer_b, Default::default()));
+		tokio::spawn(peer_b.with_request_expiry(Some(Duration::from_millis(20))).run());
+
+		// A request that is not picked up in time gets a retry-after response.
+		let_assert!(Ok(mut sent_request) = handle_a.send_request(1, &b"hello"[..]).await);
+		let_assert!(Ok(response) = sent_request.recv_response().await);
+		let_assert!(Err(e) = response.check_error_response());
+		assert!(e.as_retry_after() == Some(Duration::from_millis(20)));
+
+		// The expired request is skipped, requests that are picked up in time can be answered normally.
+		let_assert!(Ok(mut sent_request) = handle_a.send_request(2, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
+		assert!(received_request.service_id() == 2);
+		tokio::time::sleep(Duration::from_millis(40)).await;
+		assert!(let Ok(()) = received_request.send_response(3, &b"world"[..]).await);
+		let_assert!(Ok(response) = sent_request.recv_response().await);
+		assert!(response.header.service_id == 3);
+		assert!(response.body.as_ref() == b"world");
+	}
+
+	#[tokio::test]
+	async fn blocking() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
+
+		// Use plain threads without a runtime context to send the request and the response.
+		let client = std::thread::spawn(move || {
+			let_assert!(Ok(mut sent_request) = handle_a.blocking_send_request(1, &b"hello"[..]));
+			sent_request.blocking_recv_response()
+		});
+
+		let_assert!(Ok(ReceivedMessage::Request(received_request, body)) = handle_b.recv_message().await);
+		assert!(body.as_ref() == b"hello");
+		let write_handle = received_request.write_handle();
+		let server = std::thread::spawn(move || write_handle.blocking_send_response(2, &b"world"[..]));
+		let_assert!(Ok(Ok(Ok(()))) = tokio::task::spawn_blocking(move || server.join()).await);
+
+		let_assert!(Ok(Ok(Ok(response))) = tokio::task::spawn_blocking(move || client.join()).await);
+		assert!(response.header.service_id == 2);
+		assert!(response.body.as_ref() == b"world");
+	}
+
+	#[tokio::test]
+	async fn request_trace_id() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let (peer_a, mut handle_a) = Peer::new(StreamTransport::new(peer_a, Default::default()));
+		let (peer_b, mut handle_b) = Peer::new(StreamTransport::new(peer_b, Default::default()));
+		tokio::spawn(peer_a.with_trace_ids(true).run());
+		tokio::spawn(peer_b.with_trace_ids(true).run());
+
+		// Wait until both peers processed the announcement of the other peer.
+		let_assert!(Ok(()) = handle_a.send_stream(1, &b"sync"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(_)) = handle_b.recv_message().await);
+		let_assert!(Ok(()) = handle_b.send_stream(1, &b"sync"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(_)) = handle_a.recv_message().await);
+
+		// A trace ID is generated for requests without one.
+		let_assert!(Ok(sent_request) = handle_a.send_request(1, &b"hello"[..]).await);
+		let_assert!(Some(trace_id) = sent_request.trace_id());
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
+		assert!(received_request.trace_id() == Some(trace_id));
+		assert!(received_request.write_handle().trace_id() == Some(trace_id));
+
+		// Explicit trace IDs are propagated, also together with a deadline.
+		let_assert!(Ok(sent_request) = handle_a.send_request_with_trace_id(2, &b"hello"[..], Some(trace_id)).await);
+		assert!(sent_request.trace_id() == Some(trace_id));
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
+		assert!(received_request.trace_id() == Some(trace_id));
+
+		let deadline = Instant::now() + Duration::from_secs(10);
+		let_assert!(Ok(_sent_request) = handle_a.send_request_with_deadline(3, &b"hello"[..], Some(deadline)).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
+		assert!(received_request.deadline().is_some());
+		let_assert!(Some(other_trace_id) = received_request.trace_id());
+		assert!(other_trace_id != trace_id);
+	}
+
+	#[tokio::test]
+	async fn request_trace_id_without_remote_support() {
+		use crate::transport::{Transport, TransportReadHalf, TransportWriteHalf};
+
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let (peer_a, mut handle_a) = Peer::new(StreamTransport::new(peer_a, Default::default()));
+		tokio::spawn(peer_a.with_trace_ids(true).run());
+		let mut transport_b = StreamTransport::new(peer_b, Default::default());
+		let (mut read_b, mut write_b) = transport_b.split();
+
+		// Peer A announces support for trace IDs.
+		let_assert!(Ok(message) = read_b.read_msg().await);
+		assert!(message.header == MessageHeader::stream(0, crate::service_id::TRACE_ID_SUPPORT));
+
+		// Peer B did not announce support for trace IDs, so none are generated or sent.
+		let_assert!(Ok(sent_request) = handle_a.send_request(1, &b"hello"[..]).await);
+		assert!(sent_request.trace_id() == None);
+		let_assert!(Ok(message) = read_b.read_msg().await);
+		assert!(message.header == MessageHeader::request(sent_request.request_id(), 1));
+
+		// Explicit trace IDs are only kept locally.
+		let_assert!(Ok(sent_request) = handle_a.send_request_with_trace_id(2, &b"hello"[..], Some(42)).await);
+		assert!(sent_request.trace_id() == Some(42));
+		let_assert!(Ok(message) = read_b.read_msg().await);
+		assert!(message.header == MessageHeader::request(sent_request.request_id(), 2));
+
+		// After peer B announced support, trace IDs are sent right before the request.
+		assert!(let Ok(()) = write_b.write_msg(&MessageHeader::stream(0, crate::service_id::TRACE_ID_SUPPORT), &b""[..].into()).await);
+		assert!(let Ok(()) = write_b.write_msg(&MessageHeader::stream(0, 3), &b"sync"[..].into()).await);
+		let_assert!(Ok(ReceivedMessage::Stream(_)) = handle_a.recv_message().await);
+		let_assert!(Ok(sent_request) = handle_a.send_request(4, &b"hello"[..]).await);
+		let_assert!(Some(trace_id) = sent_request.trace_id());
+		let_assert!(Ok(message) = read_b.read_msg().await);
+		assert!(message.header == MessageHeader::stream(sent_request.request_id(), crate::service_id::TRACE_ID));
+		let_assert!(Ok(received_trace_id) = message.parse_trace_id());
+		assert!(received_trace_id == trace_id);
+		let_assert!(Ok(message) = read_b.read_msg().await);
+		assert!(message.header == MessageHeader::request(sent_request.request_id(), 4));
+	}
+
+	#[tokio::test]
+	async fn ordering_of_outgoing_messages() {
+		use crate::transport::{Transport, TransportReadHalf, TransportWriteHalf};
+		use crate::StreamBody;
+
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let mut handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let mut transport_b = StreamTransport::new(peer_b, Default::default());
+		let (mut read_b, mut write_b) = transport_b.split();
+
+		// Receive a request, so it can be answered in between the other messages.
+		assert!(let Ok(()) = write_b.write_msg(&MessageHeader::request(100, 1), &b"hello"[..].into()).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_a.recv_message().await);
+
+		// Messages sent one after the other are written in the same order, regardless of their type.
+		let deadline = Instant::now() + Duration::from_secs(10);
+		let_assert!(Ok(sent_request) = handle_a.send_request_with_type_fingerprint(2, &b"hello"[..], Some(deadline), Some(0x1234)).await);
+		let request_id = sent_request.request_id();
+		assert!(let Ok(()) = handle_a.send_stream(10, &b"stream"[..]).await);
+		assert!(let Ok(()) = sent_request.send_update(11, &b"update"[..]).await);
+		assert!(let Ok(()) = received_request.send_update(12, &b"update"[..]).await);
+		assert!(let Ok(()) = handle_a.send_stream_batch([(13, StreamBody::from(&b"a"[..])), (14, StreamBody::from(&b"b"[..]))]).await);
+		assert!(let Ok(()) = received_request.send_response(15, &b"response"[..]).await);
+
+		let expected = [
+			MessageHeader::stream(request_id, crate::service_id::DEADLINE),
+			MessageHeader::stream(request_id, crate::service_id::TYPE_FINGERPRINT),
+			MessageHeader::request(request_id, 2),
+			MessageHeader::stream(0, 10),
+			MessageHeader::requester_update(request_id, 11),
+			MessageHeader::responder_update(100, 12),
+			MessageHeader::stream(0, 13),
+			MessageHeader::stream(0, 14),
+			MessageHeader::response(100, 15),
+		];
+		for expected in expected {
+			let_assert!(Ok(message) = read_b.read_msg().await);
+			assert!(message.header == expected);
+		}
+	}
+
+	#[tokio::test]
+	async fn ordering_between_write_handles() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let (_read_a, write_a) = Peer::spawn(StreamTransport::new(peer_a, Default::default())).split();
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
+
+		// Each task sends a numbered sequence of stream messages through its own clone of the write handle.
+		let tasks: Vec<_> = (0..4).map(|task| {
+			let write_a = write_a.clone();
+			tokio::spawn(async move {
+				for i in 0..100u32 {
+					write_a.send_stream(task, i.to_le_bytes().to_vec()).await?;
+				}
+				Ok::<_, Error>(())
+			})
+		}).collect();
+
+		// The messages of different tasks are interleaved, but the messages of each task keep their order.
+		let mut next = [0u32; 4];
+		for _ in 0..400 {
+			let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_b.recv_message().await);
+			let task = message.header.service_id as usize;
+			let_assert!(Ok(i) = <[u8; 4]>::try_from(message.body.as_ref()));
+			assert!(u32::from_le_bytes(i) == next[task]);
+			next[task] += 1;
+		}
+		assert!(next == [100; 4]);
+		for task in tasks {
+			assert!(let Ok(Ok(())) = task.await);
+		}
+	}
+
+	#[tokio::test]
+	async fn ordering_of_incoming_messages() {
+		use crate::transport::{Transport, TransportReadHalf, TransportWriteHalf};
+
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let mut handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let mut transport_b = StreamTransport::new(peer_b, Default::default());
+		let (mut read_b, mut write_b) = transport_b.split();
+
+		let_assert!(Ok(mut sent_request) = handle_a.send_request(2, &b"hello"[..]).await);
+		let_assert!(Ok(message) = read_b.read_msg().await);
+		let request_id = message.header.request_id;
+
+		// Interleave a received request, its updates, stream messages and updates and the response for the sent request.
+		let messages = [
+			MessageHeader::request(1, 5),
+			MessageHeader::stream(0, 10),
+			MessageHeader::requester_update(1, 11),
+			MessageHeader::responder_update(request_id, 20),
+			MessageHeader::stream(0, 12),
+			MessageHeader::requester_update(1, 13),
+			MessageHeader::responder_update(request_id, 21),
+			MessageHeader::response(request_id, 22),
+			MessageHeader::stream(0, 14),
+		];
+		for header in messages {
+			assert!(let Ok(()) = write_b.write_msg(&header, &b"hello"[..].into()).await);
 		}
-	}
 
-	async fn write_message(&mut self, message: &Message<W::Body>) -> Result<(), (Error, LoopFlow)> {
-		match self.write_half.write_msg(&message.header, &message.body).await {
-			Ok(()) => Ok(()),
-			Err(e) => {
-				let flow = if e.is_fatal() {
-					LoopFlow::Stop
-				} else {
-					LoopFlow::Continue
-				};
-				Err((e.into_inner(), flow))
-			},
+		// Each channel delivers its messages in the order they were received.
+		let_assert!(Ok(ReceivedMessage::Request(mut received_request, _body)) = handle_a.recv_message().await);
+		for service_id in [10, 12, 14] {
+			let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_a.recv_message().await);
+			assert!(message.header.service_id == service_id);
 		}
+		for service_id in [11, 13] {
+			let_assert!(Some(update) = received_request.recv_update().await);
+			assert!(update.header.service_id == service_id);
+		}
+		for service_id in [20, 21] {
+			let_assert!(Some(update) = sent_request.recv_update().await);
+			assert!(update.header.service_id == service_id);
+		}
+		let_assert!(Ok(response) = sent_request.recv_response().await);
+		assert!(response.header.service_id == 22);
 	}
-}
 
-/// Loop control flow command.
-///
-/// Allows other methods to make decisions on loop control flow.
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-enum LoopFlow {
-	/// Keep the loop running.
-	Continue,
+	#[tokio::test]
+	async fn send_request_oneshot() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
 
-	/// Stop the loop.
-	Stop,
-}
+		let server = tokio::spawn(async move {
+			let_assert!(Ok(ReceivedMessage::Request(received_request, body)) = handle_b.recv_message().await);
+			assert!(body.as_ref() == b"ping");
+			assert!(let Ok(()) = received_request.send_response(2, &b"pong"[..]).await);
 
-/// Command to send a request to the remote peer.
-pub struct SendRequest<Body> {
-	/// The service ID for the request.
-	pub service_id: i32,
+			let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
+			assert!(let Ok(()) = received_request.send_error_response("oh no").await);
 
-	/// The body for the request.
-	pub body: Body,
+			// Close the connection without answering the last request.
+			let_assert!(Ok(ReceivedMessage::Request(_received_request, _body)) = handle_b.recv_message().await);
+		});
 
-	/// One-shot channel to transmit back the created [`SentRequestHandle`] object, or an error.
-	pub result_tx: oneshot::Sender<Result<SentRequestHandle<Body>, Error>>,
-}
+		let_assert!(Ok(response) = handle_a.send_request_oneshot(1, &b"ping"[..]).await);
+		assert!(response.header.service_id == 2);
+		assert!(response.body.as_ref() == b"pong");
 
-/// Command to send a raw message to the remote peer.
-pub struct SendRawMessage<Body> {
-	/// The message to send.
-	pub message: Message<Body>,
+		let_assert!(Ok(response) = handle_a.send_request_oneshot(1, &b"ping"[..]).await);
+		let_assert!(Err(e) = response.check_error_response());
+		assert!(e.as_remote_error() == Some("oh no"));
 
-	/// One-shot channel to receive the result of sending the message.
-	pub result_tx: oneshot::Sender<Result<(), Error>>,
-}
+		// The requests are closed after the response.
+		let_assert!(Ok(audit) = handle_a.audit().await);
+		assert!(audit.is_clean());
 
-/// Command to process an incoming message from the remote peer.
-pub struct ProcessReceivedMessage<Body> {
-	/// The message from the remote peer, or an error.
-	pub message: Result<Message<Body>, Error>,
-}
+		// The request fails when the connection is closed before the response arrives.
+		let_assert!(Err(e) = handle_a.send_request_oneshot(1, &b"ping"[..]).await);
+		assert!(e.is_connection_aborted());
+		assert!(let Ok(()) = server.await);
+	}
 
-impl<Body> std::fmt::Debug for Command<Body> {
-	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-		let mut debug = f.debug_struct("Command");
-		match self {
-			Self::SendRequest(x) => debug.field("SendRequest", x),
-			Self::SendRawMessage(x) => debug.field("SendRawMessage", x),
-			Self::ProcessReceivedMessage(x) => debug.field("ProcessReceivedMessage", x),
-			Self::Stop => debug.field("Stop", &()),
-			Self::UnregisterReadHandle => debug.field("UnregisterReadHandle", &()),
-			Self::RegisterWriteHandle => debug.field("RegisterWriteHandle", &()),
-			Self::UnregisterWriteHandle => debug.field("UnregisterWriteHandle", &()),
+	#[tokio::test]
+	async fn request_type_fingerprint() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
 
-		}.finish()
+		// The fingerprint is attached to the request that follows it, together with the deadline.
+		let deadline = Instant::now() + Duration::from_secs(10);
+		let_assert!(Ok(_sent_request) = handle_a.send_request_with_type_fingerprint(1, &b"hello"[..], Some(deadline), Some(0x1234)).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_request, body)) = handle_b.recv_message().await);
+		assert!(body.as_ref() == b"hello");
+		assert!(received_request.deadline().is_some());
+		assert!(received_request.type_fingerprint() == Some(0x1234));
+		assert!(let Ok(()) = received_request.check_type_fingerprint(0x1234));
+		let_assert!(Err(e) = received_request.check_type_fingerprint(0x5678));
+		assert!(e.kind() == crate::ErrorKind::TypeFingerprintMismatch);
+		assert!(e.is_type_fingerprint_mismatch());
+
+		// Requests without a fingerprint pass any check.
+		let_assert!(Ok(_sent_request) = handle_a.send_request(2, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
+		assert!(received_request.type_fingerprint() == None);
+		assert!(let Ok(()) = received_request.check_type_fingerprint(0x5678));
 	}
-}
 
-impl<Body> std::fmt::Debug for SendRequest<Body> {
-	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-		f.debug_struct("SendRequest").field("service_id", &self.service_id).finish()
+	#[tokio::test]
+	async fn error_response_truncation() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let config = crate::StreamConfig {
+			max_body_len_write: 16,
+			..Default::default()
+		};
+		let mut handle_a = Peer::spawn(StreamTransport::new(peer_a, config));
+		let (peer_b, mut handle_b) = Peer::new(StreamTransport::new(peer_b, Default::default()));
+		tokio::spawn(peer_b.with_max_error_len(Some(12)).run());
+
+		// Error responses that exceed the body limit of the transport are truncated to fit.
+		let_assert!(Ok(mut sent_request) = handle_b.send_request(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_a.recv_message().await);
+		let_assert!(Ok(()) = received_request.send_error_response("this message is way too long").await);
+		let_assert!(Ok(response) = sent_request.recv_response().await);
+		let_assert!(Err(e) = response.check_error_response());
+		assert!(e.as_remote_error() == Some("this message ..."));
+
+		// The maximum error length of the peer is applied before sending.
+		let_assert!(Ok(mut sent_request) = handle_a.send_request(2, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
+		let_assert!(Ok(()) = received_request.send_error_response("also a long message").await);
+		let_assert!(Ok(response) = sent_request.recv_response().await);
+		let_assert!(Err(e) = response.check_error_response());
+		assert!(e.as_remote_error() == Some("also a lo..."));
 	}
-}
 
-impl<Body> std::fmt::Debug for SendRawMessage<Body> {
-	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-		f.debug_struct("SendRawMessage").field("message", &self.message).finish()
+	#[tokio::test]
+	async fn connection_id() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let transport_a = StreamTransport::new(peer_a, Default::default());
+		let_assert!(Some(description) = crate::transport::Transport::describe_remote(&transport_a));
+		assert!(description.starts_with("unix pid "));
+
+		let (peer_a, handle_a) = Peer::new(transport_a);
+		let handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
+		assert!(peer_a.connection_id() == handle_a.connection_id());
+		assert!(handle_a.connection_id() != handle_b.connection_id());
+
+		let (read_a, write_a) = handle_a.split();
+		assert!(read_a.connection_id() == peer_a.connection_id());
+		assert!(write_a.connection_id() == peer_a.connection_id());
 	}
-}
 
-impl<Body> std::fmt::Debug for ProcessReceivedMessage<Body> {
-	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-		f.debug_struct("ProcessReceivedMessage").field("message", &self.message).finish()
+	#[tokio::test]
+	async fn stats() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
+		assert!(handle_a.stats() == Default::default());
+
+		// Messages wait in the incoming queue until they are picked up.
+		let_assert!(Ok(mut sent_request) = handle_a.send_request(1, &b"hello"[..]).await);
+		let_assert!(Ok(()) = handle_a.send_stream(2, &b"world"[..]).await);
+		let_assert!(Ok(()) = handle_a.send_stream(3, &b"!"[..]).await);
+		let stats = handle_a.stats();
+		assert!(stats.messages_sent == 3);
+		assert!(stats.bytes_sent == 3 * crate::HEADER_LEN as u64 + 11);
+		assert!(stats.open_sent_requests == 1);
+
+		while handle_b.stats().incoming_queue_len < 3 {
+			tokio::task::yield_now().await;
+		}
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
+		let stats = handle_b.stats();
+		assert!(stats.messages_received == 3);
+		assert!(stats.bytes_received == 3 * crate::HEADER_LEN as u64 + 11);
+		assert!(stats.open_received_requests == 1);
+		assert!(stats.incoming_queue_len == 2);
+
+		let_assert!(Ok(()) = received_request.send_response(1, &b"bye"[..]).await);
+		let_assert!(Ok(_response) = sent_request.recv_response().await);
+		let_assert!(Ok(ReceivedMessage::Stream(_)) = handle_b.recv_message().await);
+		let_assert!(Ok(ReceivedMessage::Stream(_)) = handle_b.recv_message().await);
+
+		// The open request counts are updated by the peer loop before it processes the next command.
+		let_assert!(Ok(()) = handle_b.send_stream(4, &b""[..]).await);
+		let stats = handle_b.stats();
+		assert!(stats.messages_sent == 2);
+		assert!(stats.open_received_requests == 0);
+		assert!(stats.incoming_queue_len == 0);
 	}
-}
 
-impl<Body> From<SendRequest<Body>> for Command<Body> {
-	fn from(other: SendRequest<Body>) -> Self {
-		Self::SendRequest(other)
+	#[tokio::test]
+	async fn diagnose() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let (peer_a, handle_a) = Peer::new(StreamTransport::new(peer_a, Default::default()));
+		let peer_a = peer_a.with_labels(ConnectionLabels::new().with("network", "internal"));
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
+		let run_a = tokio::spawn(peer_a.run());
+
+		let report = handle_a.diagnose().await;
+		assert!(report.is_running());
+		assert!(report.since_last_sent == None);
+		assert!(report.since_last_received == None);
+		assert!(report.to_string().starts_with(&format!("connection {} (network=internal)\npeer loop: running", handle_a.connection_id())));
+
+		let_assert!(Ok(_sent_request) = handle_a.send_request(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(_received_request, _body)) = handle_b.recv_message().await);
+		let report = handle_a.diagnose().await;
+		assert!(let Some(_) = report.since_last_sent);
+		assert!(report.stats.open_sent_requests == 1);
+		let_assert!(Some(audit) = report.audit);
+		assert!(audit.is_clean());
+
+		// A stopped peer is still reported, but without a response from the peer loop.
+		handle_a.close_handle().close();
+		let_assert!(Ok(()) = run_a.await);
+		let report = handle_a.diagnose().await;
+		assert!(!report.is_running());
+		assert!(let None = report.audit);
+		assert!(report.to_string().contains("peer loop: stopped"));
 	}
-}
 
-impl<Body> From<SendRawMessage<Body>> for Command<Body> {
-	fn from(other: SendRawMessage<Body>) -> Self {
-		Self::SendRawMessage(other)
+	/// Transport that fails a number of writes with a non-fatal error before writing to the wrapped transport.
+	struct FlakyTransport {
+		inner: StreamTransport<UnixStream>,
+		failures: usize,
 	}
-}
 
-impl<Body> From<ProcessReceivedMessage<Body>> for Command<Body> {
-	fn from(other: ProcessReceivedMessage<Body>) -> Self {
-		Self::ProcessReceivedMessage(other)
+	struct FlakyWriteHalf<'a> {
+		inner: <StreamTransport<UnixStream> as crate::transport::Transport>::WriteHalf<'a>,
+		failures: &'a mut usize,
 	}
-}
 
-#[cfg(test)]
-mod test {
-	use super::*;
-	use assert2::assert;
-	use assert2::let_assert;
+	impl crate::transport::Transport for FlakyTransport {
+		type Body = crate::StreamBody;
+		type Info = <StreamTransport<UnixStream> as crate::transport::Transport>::Info;
+		type Config = <StreamTransport<UnixStream> as crate::transport::Transport>::Config;
+		type ReadHalf<'a> = <StreamTransport<UnixStream> as crate::transport::Transport>::ReadHalf<'a>;
+		type WriteHalf<'a> = FlakyWriteHalf<'a>;
 
-	use crate::MessageHeader;
-	use crate::transport::StreamTransport;
-	use tokio::net::UnixStream;
+		fn split(&mut self) -> (Self::ReadHalf<'_>, Self::WriteHalf<'_>) {
+			let (read_half, write_half) = self.inner.split();
+			(read_half, FlakyWriteHalf { inner: write_half, failures: &mut self.failures })
+		}
 
-	#[tokio::test]
-	async fn test_peer() {
-		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		fn info(&self) -> std::io::Result<Self::Info> {
+			self.inner.info()
+		}
+	}
 
-		let (peer_a, handle_a) = Peer::new(StreamTransport::new(peer_a, Default::default()));
-		let (peer_b, mut handle_b) = Peer::new(StreamTransport::new(peer_b, Default::default()));
+	impl crate::transport::TransportWriteHalf for FlakyWriteHalf<'_> {
+		type Body = crate::StreamBody;
 
-		let task_a = tokio::spawn(peer_a.run());
-		let task_b = tokio::spawn(peer_b.run());
+		fn poll_write_msg(self: std::pin::Pin<&mut Self>, context: &mut std::task::Context, header: &MessageHeader, body: &Self::Body) -> Poll<Result<(), crate::transport::TransportError>> {
+			let this = self.get_mut();
+			if *this.failures > 0 {
+				*this.failures -= 1;
+				let error = std::io::Error::new(std::io::ErrorKind::WouldBlock, "congested");
+				return Poll::Ready(Err(crate::transport::TransportError::new_non_fatal(error)));
+			}
+			std::pin::Pin::new(&mut this.inner).poll_write_msg(context, header, body)
+		}
 
-		// Send a request from A.
-		let_assert!(Ok(mut sent_request) = handle_a.send_request(1, &[2][..]).await);
-		let request_id = sent_request.request_id();
+		fn poll_flush(self: std::pin::Pin<&mut Self>, context: &mut std::task::Context) -> Poll<Result<(), crate::transport::TransportError>> {
+			std::pin::Pin::new(&mut self.get_mut().inner).poll_flush(context)
+		}
 
-		// Receive the request on B.
-		let_assert!(Ok(ReceivedMessage::Request(mut received_request, _body)) = handle_b.recv_message().await);
+		fn poll_shutdown(self: std::pin::Pin<&mut Self>, context: &mut std::task::Context) -> Poll<Result<(), crate::transport::TransportError>> {
+			std::pin::Pin::new(&mut self.get_mut().inner).poll_shutdown(context)
+		}
+	}
 
-		// Send an update from A and receive it on B.
-		let_assert!(Ok(()) = sent_request.send_update(3, &[4][..]).await);
-		let_assert!(Some(update) = received_request.recv_update().await);
-		assert!(update.header == MessageHeader::requester_update(request_id, 3));
-		assert!(update.body.as_ref() == &[4]);
+	#[tokio::test]
+	async fn write_retry_policy() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let transport = FlakyTransport {
+			inner: StreamTransport::new(peer_a, Default::default()),
+			failures: 2,
+		};
+		let (peer_a, handle_a) = Peer::new(transport);
+		let peer_a = peer_a.with_write_retry_policy(Some(WriteRetryPolicy::new(2, Duration::from_millis(1))));
+		tokio::spawn(peer_a.run());
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
 
-		// Send an update from B and receive it on A.
-		let_assert!(Ok(()) = received_request.send_update(5, &[6][..]).await);
-		let_assert!(Some(update) = sent_request.recv_update().await);
-		assert!(update.header == MessageHeader::responder_update(request_id, 5));
-		assert!(update.body.as_ref() == &[6]);
+		// Both failures are retried, so the message arrives.
+		let_assert!(Ok(()) = handle_a.send_stream(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_b.recv_message().await);
+		assert!(message.body.as_ref() == b"hello");
+		assert!(handle_a.stats().write_retries == 2);
+	}
 
-		// Send the response from B and receive it on A.
-		let_assert!(Ok(()) = received_request.send_response(7, &[8][..]).await);
-		let_assert!(Ok(response) = sent_request.recv_response().await);
-		assert!(response.header == MessageHeader::response(request_id, 7));
-		assert!(response.body.as_ref() == &[8]);
+	#[tokio::test]
+	async fn write_retry_policy_exhausted() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let transport = FlakyTransport {
+			inner: StreamTransport::new(peer_a, Default::default()),
+			failures: 3,
+		};
+		let (peer_a, handle_a) = Peer::new(transport);
+		let peer_a = peer_a.with_write_retry_policy(Some(WriteRetryPolicy::new(1, Duration::from_millis(1))));
+		tokio::spawn(peer_a.run());
+		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
 
-		drop(handle_a);
-		drop(handle_b);
-		drop(sent_request);
+		// The second failure is reported, and the third failure is not retried at all.
+		let_assert!(Err(e) = handle_a.send_stream(1, &b"hello"[..]).await);
+		assert!(let Some(_) = e.as_io_error());
+		assert!(handle_a.stats().write_retries == 1);
+		let_assert!(Ok(()) = handle_a.send_stream(2, &b"hello"[..]).await);
+		assert!(handle_a.stats().write_retries == 2);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_b.recv_message().await);
+		assert!(message.header.service_id == 2);
+	}
 
-		assert!(let Ok(()) = task_a.await);
-		assert!(let Ok(()) = task_b.await);
+	#[tokio::test]
+	async fn write_without_retry_policy() {
+		let_assert!(Ok((peer_a, _peer_b)) = UnixStream::pair());
+		let transport = FlakyTransport {
+			inner: StreamTransport::new(peer_a, Default::default()),
+			failures: 1,
+		};
+		let handle_a = Peer::spawn(transport);
+
+		// Non-fatal errors fail the send, but the peer keeps running.
+		let_assert!(Err(_) = handle_a.send_stream(1, &b"hello"[..]).await);
+		let_assert!(Ok(()) = handle_a.send_stream(1, &b"hello"[..]).await);
+		assert!(handle_a.stats().write_retries == 0);
 	}
 
 	#[tokio::test]
-	async fn peeked_response_is_not_gone() {
+	async fn bounded_latency_policy() {
 		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
-		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let (peer_a, handle_a) = Peer::new(StreamTransport::new(peer_a, Default::default()));
+		let (_read_a, write_a) = handle_a.split();
+		let peer_a = peer_a.with_bounded_latency_policy(Some(BoundedLatencyPolicy::new(Duration::from_millis(50))));
 		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
 
-		// Send a request from A.
-		let_assert!(Ok(mut sent_request) = handle_a.send_request(1, &[2][..]).await);
-		let request_id = sent_request.request_id();
-
-		// Receive the request on B.
-		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
-
-		// Send two updates and a response from B to A.
-		let_assert!(Ok(()) = received_request.send_update(5, &b"Hello world!"[..]).await);
-		let_assert!(Ok(()) = received_request.send_update(6, &b"Hello world!"[..]).await);
-		let_assert!(Ok(()) = received_request.send_response(7, &b"Goodbye!"[..]).await);
+		// Queue an old stream message, a fresh stream message and a request before the peer runs.
+		let old = tokio::spawn({
+			let write_a = write_a.clone();
+			async move { write_a.send_stream(1, &b"old"[..]).await }
+		});
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		let new = tokio::spawn({
+			let write_a = write_a.clone();
+			async move { write_a.send_stream(2, &b"new"[..]).await }
+		});
+		tokio::time::sleep(Duration::from_millis(1)).await;
+		let request = tokio::spawn({
+			let write_a = write_a.clone();
+			async move { write_a.send_request(3, &b"request"[..]).await }
+		});
+		tokio::time::sleep(Duration::from_millis(1)).await;
+		tokio::spawn(peer_a.run());
+
+		// The request is sent first, the old stream message is dropped, and the fresh stream message is sent last.
+		let_assert!(Ok(Ok(_request)) = request.await);
+		let_assert!(Ok(ReceivedMessage::Request(received, _body)) = handle_b.recv_message().await);
+		assert!(received.service_id() == 3);
+		let_assert!(Ok(Ok(())) = new.await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_b.recv_message().await);
+		assert!(message.header.service_id == 2);
+
+		let_assert!(Ok(Err(e)) = old.await);
+		assert!(e.is_stream_dropped());
+		assert!(write_a.stats().dropped_stream_messages == 1);
+	}
 
-		// Try to receive three responses.
-		// This should stuff the response in the internal peek buffer.
-		assert!(let Some(_) = sent_request.recv_update().await);
-		assert!(let Some(_) = sent_request.recv_update().await);
-		assert!(let None = sent_request.recv_update().await);
+	#[cfg(all(feature = "strict-memory", feature = "generic-stream"))]
+	#[tokio::test]
+	async fn bounded_latency_policy_limits_deferred_streams() {
+		use crate::transport::GenericStream;
+
+		// The stream buffer is smaller than one message, so writes wait until the other side reads.
+		let (peer_a, peer_b) = tokio::io::duplex(64);
+		let capacities = ChannelCapacities { commands: 2, ..Default::default() };
+		let (peer_a, handle_a) = Peer::new_with_capacities(StreamTransport::new(GenericStream::new(peer_a), Default::default()), capacities);
+		let (_read_a, write_a) = handle_a.split();
+		let peer_a = peer_a.with_bounded_latency_policy(Some(BoundedLatencyPolicy::new(Duration::from_secs(60))));
+
+		let body = vec![0; 1024];
+		let send_stream = |service_id| {
+			let write_a = write_a.clone();
+			let body = body.clone();
+			tokio::spawn(async move { write_a.send_stream(service_id, body).await })
+		};
 
-		// Now receive the response, which should be returned intact from the peek buffer exactly once.
-		let_assert!(Ok(response) = sent_request.recv_response().await);
-		assert!(let Err(_) = sent_request.recv_response().await);
+		// Fill the command queue, and let the peer get stuck writing the first stream message.
+		let first = send_stream(1);
+		let second = send_stream(2);
+		tokio::task::yield_now().await;
+		tokio::spawn(peer_a.run());
+		tokio::task::yield_now().await;
+
+		// The peer took both stream messages from the command queue, so there is room for two more commands.
+		let third = send_stream(3);
+		tokio::task::yield_now().await;
+		let request = tokio::spawn({
+			let write_a = write_a.clone();
+			async move { write_a.send_request(10, &b"request"[..]).await }
+		});
+		tokio::task::yield_now().await;
+
+		// The queue of deferred stream messages is full after the third stream message,
+		// so the second stream message is written before the request is taken from the command queue.
+		let mut handle_b = Peer::spawn(StreamTransport::new(GenericStream::new(peer_b), Default::default()));
+		let mut service_ids = Vec::new();
+		for _ in 0..4 {
+			let_assert!(Ok(message) = handle_b.recv_message().await);
+			service_ids.push(match message {
+				ReceivedMessage::Request(request, _body) => request.service_id(),
+				ReceivedMessage::Stream(message) => message.header.service_id,
+			});
+		}
+		assert!(service_ids == [1, 2, 10, 3]);
+		let_assert!(Ok(Ok(())) = first.await);
+		let_assert!(Ok(Ok(())) = second.await);
+		let_assert!(Ok(Ok(())) = third.await);
+		let_assert!(Ok(Ok(_request)) = request.await);
+	}
 
-		assert!(response.header == MessageHeader::response(request_id, 7));
-		assert!(response.body.as_ref() == b"Goodbye!");
+	#[tokio::test]
+	async fn close_on_read_handle_drop() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let mut handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let (peer_b, handle_b) = Peer::new(StreamTransport::new(peer_b, Default::default()));
+		tokio::spawn(peer_b.with_close_on_read_handle_drop(true).run());
+
+		// Messages queued before the read handle is dropped are still sent.
+		let (read_b, write_b) = handle_b.split();
+		let_assert!(Ok(()) = write_b.send_stream(1, &b"hello"[..]).await);
+		drop(read_b);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_a.recv_message().await);
+		assert!(message.body.as_ref() == b"hello");
+
+		// Then the connection is closed, even though the write handle is still alive.
+		let_assert!(Err(e) = handle_a.recv_message().await);
+		assert!(e.is_connection_aborted());
+		let_assert!(Err(_) = write_b.send_stream(2, &b"hello"[..]).await);
 	}
 
 	#[tokio::test]
-	async fn peeked_update_is_not_gone() {
+	async fn drop_handles_sends_queued_messages() {
 		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
 		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
 		let mut handle_b = Peer::spawn(StreamTransport::new(peer_b, Default::default()));
+		let_assert!(Ok(mut sent_request) = handle_a.send_request(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
 
-		// Send a request from A.
-		let_assert!(Ok(mut sent_request) = handle_a.send_request(1, &[2][..]).await);
-		let request_id = sent_request.request_id();
+		// Request handles do not keep the peer running, but a response queued before the peer notices is still sent.
+		drop(handle_b);
+		let_assert!(Ok(()) = received_request.send_response(1, &b"bye"[..]).await);
+		let_assert!(Ok(response) = sent_request.recv_response().await);
+		assert!(response.body.as_ref() == b"bye");
+	}
 
-		// Receive the request on B.
-		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
+	#[tokio::test]
+	async fn close_discards_queued_messages() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let mut handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let (peer_b, handle_b) = Peer::new(StreamTransport::new(peer_b, Default::default()));
+
+		// Queue a message and close the connection before the peer runs.
+		let (read_b, write_b) = handle_b.split();
+		let sent = tokio::spawn(async move { write_b.send_stream(1, &b"hello"[..]).await });
+		tokio::task::yield_now().await;
+		read_b.close();
+		tokio::spawn(peer_b.run());
+
+		// The close request is handled before the queued message.
+		let_assert!(Ok(Err(e)) = sent.await);
+		assert!(e.is_connection_aborted());
+		let_assert!(Err(e) = handle_a.recv_message().await);
+		assert!(e.is_connection_aborted());
+	}
 
-		// Send one update and a response from B to A.
-		let_assert!(Ok(()) = received_request.send_update(5, &b"Hello world!"[..]).await);
-		let_assert!(Ok(()) = received_request.send_response(6, &b"Goodbye!"[..]).await);
+	#[tokio::test]
+	async fn run_until_shutdown() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let mut handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let (peer_b, mut handle_b) = Peer::new(StreamTransport::new(peer_b, Default::default()));
+		let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+		let task_b = tokio::spawn(peer_b.run_until(async move {
+			shutdown_rx.await.ok();
+		}));
+
+		// Open a request that will never get a response.
+		let_assert!(Ok(mut sent_request) = handle_b.send_request(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(_received_request, _body)) = handle_a.recv_message().await);
+
+		// Messages queued before the shutdown are still sent.
+		let_assert!(Ok(()) = handle_b.send_stream(2, &b"goodbye"[..]).await);
+		let_assert!(Ok(()) = shutdown_tx.send(()));
+		let_assert!(Ok(()) = task_b.await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_a.recv_message().await);
+		assert!(message.body.as_ref() == b"goodbye");
+		let_assert!(Err(e) = handle_a.recv_message().await);
+		assert!(e.is_connection_aborted());
+
+		// The local handles know the peer was shut down on purpose.
+		let_assert!(Err(e) = sent_request.recv_response().await);
+		assert!(e.is_shutdown());
+		let_assert!(Err(e) = handle_b.recv_message().await);
+		assert!(e.is_shutdown());
+		let_assert!(Err(e) = handle_b.recv_message().await);
+		assert!(e.is_connection_aborted());
+	}
 
-		// Trying to read a response should stuff the update in the internal peek buffer.
-		assert!(let Err(_) = sent_request.recv_response().await);
+	#[cfg(feature = "generic-stream")]
+	#[tokio::test]
+	async fn received_messages_take_turns_with_queued_writes() {
+		use crate::transport::{Transport, TransportReadHalf, TransportWriteHalf};
+		use crate::transport::GenericStream;
+
+		// An in-memory stream is readable as soon as data is written to it, without waiting for the I/O driver.
+		let (peer_a, peer_b) = tokio::io::duplex(1 << 16);
+		let mut transport_a = StreamTransport::new(GenericStream::new(peer_a), Default::default());
+		let (mut read_a, mut write_a) = transport_a.split();
+		let (peer_b, handle_b) = Peer::new(StreamTransport::new(GenericStream::new(peer_b), Default::default()));
+		let (read_b, write_b) = handle_b.split();
+		drop(read_b);
+
+		// Queue stream messages on B before the peer loop runs.
+		let mut sends = Vec::new();
+		for i in 1..=4 {
+			let write_b = write_b.clone();
+			sends.push(tokio::spawn(async move { write_b.send_stream(i, &b"bulk"[..]).await }));
+			tokio::task::yield_now().await;
+		}
 
-		// Now we should receive the update intact from the peek buffer exactly once.
-		let_assert!(Some(update) = sent_request.recv_update().await);
-		assert!(update.header == MessageHeader::responder_update(request_id, 5));
-		assert!(update.body.as_ref() == b"Hello world!");
-		assert!(let None = sent_request.recv_update().await);
+		// Queue unexpected requests for B in the socket, so B answers them with error responses.
+		for i in 1..=4 {
+			let_assert!(Ok(()) = write_a.write_msg(&MessageHeader::request(i as u32, 7), &b"hello"[..].into()).await);
+		}
+		let_assert!(Ok(()) = write_a.flush().await);
+
+		// The error responses and the queued messages take turns.
+		tokio::spawn(peer_b.run());
+		let mut headers = Vec::new();
+		for _ in 0..8 {
+			let_assert!(Ok(message) = read_a.read_msg().await);
+			headers.push(message.header);
+		}
+		let expected: Vec<_> = (1..=4)
+			.flat_map(|i| [MessageHeader::response(i as u32, crate::service_id::ERROR), MessageHeader::stream(0, i)])
+			.collect();
+		assert!(headers == expected);
+		for send in sends {
+			let_assert!(Ok(Ok(())) = send.await);
+		}
+	}
 
-		// Now receive the response.
-		let_assert!(Ok(response) = sent_request.recv_response().await);
-		assert!(response.header == MessageHeader::response(request_id, 6));
-		assert!(response.body.as_ref() == b"Goodbye!");
+	#[tokio::test]
+	async fn header_policy() {
+		use crate::transport::{Transport, TransportReadHalf, TransportWriteHalf};
+
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let mut transport_a = StreamTransport::new(peer_a, Default::default());
+		let (mut read_a, mut write_a) = transport_a.split();
+		let (peer_b, mut handle_b) = Peer::new(StreamTransport::new(peer_b, Default::default()));
+		tokio::spawn(peer_b.with_header_policy(HeaderPolicy::strict()).run());
+
+		let_assert!(Ok(()) = write_a.write_msg(&MessageHeader::request(1, 7).with_flags(0x0001), &b"hello"[..].into()).await);
+		let_assert!(Ok(()) = write_a.write_msg(&MessageHeader::stream(3, 8), &b"hello"[..].into()).await);
+		let_assert!(Ok(()) = write_a.write_msg(&MessageHeader::stream(0, 9), &b"hello"[..].into()).await);
+		let_assert!(Ok(()) = write_a.flush().await);
+
+		// The invalid request is answered with an error response.
+		let_assert!(Ok(response) = read_a.read_msg().await);
+		assert!(response.header == MessageHeader::error_response(1));
+
+		// Both invalid messages are reported to the read handle, valid messages are delivered as usual.
+		let_assert!(Err(e) = handle_b.recv_message().await);
+		assert!(e.kind() == crate::ErrorKind::InvalidHeader);
+		let_assert!(Err(e) = handle_b.recv_message().await);
+		assert!(e.kind() == crate::ErrorKind::InvalidHeader);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = handle_b.recv_message().await);
+		assert!(message.header.service_id == 9);
+	}
+
+	#[cfg(feature = "strict-memory")]
+	#[tokio::test]
+	async fn strict_memory_incoming_queue_full() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(StreamTransport::new(peer_a, Default::default()));
+		let capacities = ChannelCapacities { incoming: 1, ..Default::default() };
+		let (peer_b, mut handle_b) = Peer::new_with_capacities(StreamTransport::new(peer_b, Default::default()), capacities);
+		tokio::spawn(peer_b.run());
+
+		// The first request fills the incoming queue of B, the second one is rejected.
+		let_assert!(Ok(_first) = handle_a.send_request(1, &b"hello"[..]).await);
+		let_assert!(Ok(mut second) = handle_a.send_request(2, &b"hello"[..]).await);
+		let_assert!(Ok(response) = second.recv_response().await);
+		let_assert!(Err(e) = response.check_error_response());
+		assert!(let Some("incoming queue is full") = e.as_remote_error());
+
+		// After the queue is emptied, new requests are accepted again.
+		let_assert!(Ok(ReceivedMessage::Request(received, _body)) = handle_b.recv_message().await);
+		assert!(received.service_id() == 1);
+		let_assert!(Ok(_third) = handle_a.send_request(3, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received, _body)) = handle_b.recv_message().await);
+		assert!(received.service_id() == 3);
 	}
 }
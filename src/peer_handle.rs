@@ -1,9 +1,15 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 
 use crate::error::private::connection_aborted;
-use crate::peer::{Command, SendRawMessage, SendRequest};
-use crate::{Error, Message, ReceivedMessage, SentRequestHandle};
+use crate::peer::{Audit, Command, Finish, Flush, PeerControl, SendAckedStream, SendOneshotRequest, SendRawMessage, SendRequest, SendStreamBatch};
+use crate::stats::StatsCounters;
+use crate::util::channel::{self, SendError};
+use crate::{ConnectionLabels, EgressPolicy, Error, Message, PeerDiagnostics, PeerStats, ReceivedMessage, SentRequestHandle, TrackerAudit};
 
 /// Handle to a peer.
 ///
@@ -12,6 +18,8 @@ use crate::{Error, Message, ReceivedMessage, SentRequestHandle};
 ///
 /// When the handle is dropped, the peer loop is stopped.
 /// Any open requests will also be terminated.
+///
+/// See [`PeerWriteHandle`] and [`PeerReadHandle`] for the ordering guarantees of outgoing and incoming messages.
 pub struct PeerHandle<Body> {
 	/// The read handle for receiving incoming requests and stream messages,
 	read_handle: PeerReadHandle<Body>,
@@ -24,58 +32,124 @@ pub struct PeerHandle<Body> {
 ///
 /// The read handle can be used to receive incoming requests and stream messages.
 ///
-/// When all read and write handles are dropped, the peer loop is stopped.
+/// When all read and write handles are dropped, the peer loop is stopped after writing the messages that are already queued.
 /// Any open requests will also be terminated.
+///
+/// # Ordering guarantees
+/// Incoming messages are delivered in the order they were read from the transport,
+/// but they are spread over multiple channels:
+/// * Requests and stream messages are delivered to the read handle in the order they were received.
+/// * Update messages and the response of a request are delivered to the handle of that request in the order they were received.
+///   The response is always delivered after all earlier update messages of the request,
+///   and it is never dropped because the update queue of the request is full.
+/// * A received request is delivered to the read handle before any of its update messages are delivered to the request handle.
+///
+/// There is no ordering between different channels.
+/// For example, a stream message that was received after an update message may be read from the read handle
+/// before the update message is read from the request handle.
+/// If your protocol relies on the relative order of stream messages and update messages, send them all as stream messages or all as updates.
+///
+/// Generated servers keep this order, except for messages of streams marked as `#[unordered]`,
+/// which may be decoded in parallel and delivered out of order.
 pub struct PeerReadHandle<Body> {
 	/// Channel for incoming request and stream messages.
-	incoming_rx: mpsc::UnboundedReceiver<Result<ReceivedMessage<Body>, Error>>,
+	incoming_rx: channel::Receiver<Result<ReceivedMessage<Body>, Error>>,
 
-	/// Channel for sending commands to the peer loop.
+	/// Shared state to control the peer loop.
 	///
-	/// Used by [`ReceivedRequestHandle`][crate::ReceivedRequestHandle] for sending updates and the response,
-	/// and to notify the peer loop when the read handle is dropped.
-	command_tx: mpsc::UnboundedSender<Command<Body>>,
+	/// Also keeps the peer loop running while the read handle exists.
+	control: PeerControl,
+
+	/// Performance counters of the peer.
+	stats: Arc<StatsCounters>,
 }
 
 /// Handle to send messages to a peer.
 ///
 /// The write handle can be used to send requests and stream messages.
 ///
-/// When all read and write handles are dropped, the peer loop is stopped.
+/// When all read and write handles are dropped, the peer loop is stopped after writing the messages that are already queued.
 /// Any open requests will also be terminated.
+///
+/// # Delivery guarantees
+/// The peer loop writes messages to the transport one by one, in the order they were queued by all handles of the peer.
+/// The send functions wait for the peer loop to process the message, and guarantee the following when they return `Ok`:
+/// * [`Self::send_request()`], [`Self::send_stream()`] and [`Self::send_stream_batch()`]:
+///   the message has been written to the transport.
+///   It may still be buffered by the transport or the operating system, and it may not have reached the remote peer yet.
+/// * [`Self::send_stream_flushed()`]: the message has been written to the transport,
+///   and the transport flushed all buffered data.
+/// * [`Self::send_stream_acked()`]: the remote peer received the message.
+///
+/// A send function never returns `Ok` for a message that was not written.
+/// If the peer loop stops before the message is written, the send function returns an error
+/// for which [`Error::is_connection_aborted()`] returns true.
+/// [`Self::close()`] stops the peer loop as soon as possible, so messages that are still queued are not written.
+/// Use [`Self::finish()`] to write all queued messages before closing the write side of the connection.
+///
+/// # Ordering guarantees
+/// All handles of a peer share a single queue, including cloned write handles and the write handles of requests.
+/// Messages are queued when the send function is first polled, and written in the order they were queued:
+/// * Messages sent from a single task, one after the other, are written in the order of the calls,
+///   regardless of their type: requests, update messages, responses and stream messages keep their relative order.
+/// * A request is always written before the update messages and the response sent through its handle.
+/// * The deadline, trace ID and type fingerprint of a request are written right before the request, with no other message in between.
+/// * The messages of a single [`Self::send_stream_batch()`] call are written consecutively, with no other message in between.
+/// * Messages from different handles or tasks are interleaved in the order they were queued.
+///   Each message is written as a whole, so messages never interleave on the transport.
+///
+/// The queue does not prioritize any handle or message type.
+/// A handle that queues many messages delays the messages of all other handles that are queued after them.
+///
+/// There are two exceptions:
+/// * Error responses that the peer sends by itself, for example for requests over the limit of [`Peer::with_max_open_received_requests()`][crate::Peer::with_max_open_received_requests],
+///   do not wait for all messages that are already queued.
+///   Messages read from the transport take turns with queued messages, so at most one queued message is written first.
+/// * With a [`BoundedLatencyPolicy`][crate::BoundedLatencyPolicy], stream messages sent with [`Self::send_stream()`] and [`Self::send_stream_batch()`]
+///   are held back while other messages are queued, so they can be overtaken by requests, update messages and responses.
+///   Stream messages still keep their order relative to each other.
 pub struct PeerWriteHandle<Body> {
 	/// Channel for sending commands to the peer loop.
 	///
 	/// Use amongst others to send outoing requests and stream messages,
 	/// and copied into [`SentRequestHandle`] to send update messages.
+	command_tx: channel::Sender<Command<Body>>,
+
+	/// Shared state to control the peer loop.
 	///
-	/// Also used to register and unregister the cloned/dropped write handles with the peer.
-	command_tx: mpsc::UnboundedSender<Command<Body>>,
+	/// Also keeps the peer loop running while any write handle exists.
+	control: PeerControl,
+
+	/// Performance counters of the peer.
+	stats: Arc<StatsCounters>,
 }
 
 /// Handle to close the connection with a peer.
 ///
 /// The peer handle can be cloned and moved independent from the [`PeerReadHandle`] or [`PeerWriteHandle`] it was created from.
 /// It does not keep the peer loop running if all other handle types are dropped.
-#[derive(Clone)]
 pub struct PeerCloseHandle<Body> {
-	/// Channel for sending commands to the peer loop.
-	///
-	/// Used to stop the peer loop.
-	command_tx: mpsc::UnboundedSender<Command<Body>>,
+	/// Channel to ask the peer loop to stop.
+	stop_tx: mpsc::Sender<()>,
+
+	/// The close handle is tied to the body type of the peer.
+	_body: PhantomData<fn() -> Body>,
 }
 
 impl<Body> PeerHandle<Body> {
 	/// Create a new peer handle from the separate channels.
 	pub(crate) fn new(
-		incoming_rx: mpsc::UnboundedReceiver<Result<ReceivedMessage<Body>, Error>>,
-		command_tx: mpsc::UnboundedSender<Command<Body>>,
+		incoming_rx: channel::Receiver<Result<ReceivedMessage<Body>, Error>>,
+		command_tx: channel::Sender<Command<Body>>,
+		control: PeerControl,
+		stats: Arc<StatsCounters>,
 	) -> Self {
 		let read_handle = PeerReadHandle {
 			incoming_rx,
-			command_tx: command_tx.clone(),
+			control: control.clone(),
+			stats: stats.clone(),
 		};
-		let write_handle = PeerWriteHandle { command_tx };
+		let write_handle = PeerWriteHandle { command_tx, control, stats };
 		Self { read_handle, write_handle }
 	}
 
@@ -92,6 +166,8 @@ impl<Body> PeerHandle<Body> {
 	///
 	/// Errors for invalid incoming messages are also reported by this function.
 	/// For example: incoming update messages that are not associated with a received request will be reported as an error here.
+	///
+	/// This function is cancel safe, see [`PeerReadHandle::recv_message()`].
 	pub async fn recv_message(&mut self) -> Result<ReceivedMessage<Body>, Error> {
 		self.read_handle.recv_message().await
 	}
@@ -101,12 +177,148 @@ impl<Body> PeerHandle<Body> {
 		self.write_handle.send_request(service_id, body).await
 	}
 
+	/// Send a new request to the remote peer, blocking the current thread.
+	///
+	/// See [`PeerWriteHandle::blocking_send_request()`] for more details.
+	#[track_caller]
+	pub fn blocking_send_request(&self, service_id: i32, body: impl Into<Body>) -> Result<SentRequestHandle<Body>, Error> {
+		self.write_handle.blocking_send_request(service_id, body)
+	}
+
+	/// Send a new request to the remote peer and wait for the response.
+	///
+	/// See [`PeerWriteHandle::send_request_oneshot()`] for more details.
+	pub async fn send_request_oneshot(&self, service_id: i32, body: impl Into<Body>) -> Result<Message<Body>, Error> {
+		self.write_handle.send_request_oneshot(service_id, body).await
+	}
+
+	/// Send a new request with a deadline to the remote peer.
+	///
+	/// See [`PeerWriteHandle::send_request_with_deadline()`] for more details.
+	pub async fn send_request_with_deadline(&self, service_id: i32, body: impl Into<Body>, deadline: Option<Instant>) -> Result<SentRequestHandle<Body>, Error> {
+		self.write_handle.send_request_with_deadline(service_id, body, deadline).await
+	}
+
+	/// Send a new request with a trace ID to the remote peer.
+	///
+	/// See [`PeerWriteHandle::send_request_with_trace_id()`] for more details.
+	pub async fn send_request_with_trace_id(&self, service_id: i32, body: impl Into<Body>, trace_id: Option<u64>) -> Result<SentRequestHandle<Body>, Error> {
+		self.write_handle.send_request_with_trace_id(service_id, body, trace_id).await
+	}
+
+	/// Send a new request with a deadline and a type fingerprint to the remote peer.
+	///
+	/// See [`PeerWriteHandle::send_request_with_type_fingerprint()`] for more details.
+	pub async fn send_request_with_type_fingerprint(&self, service_id: i32, body: impl Into<Body>, deadline: Option<Instant>, type_fingerprint: Option<u64>) -> Result<SentRequestHandle<Body>, Error> {
+		self.write_handle.send_request_with_type_fingerprint(service_id, body, deadline, type_fingerprint).await
+	}
+
 	/// Send a stream message to the remote peer.
+	///
+	/// See [`PeerWriteHandle::send_stream()`] for more details.
 	pub async fn send_stream(&self, service_id: i32, body: impl Into<Body>) -> Result<(), Error> {
 		self.write_handle.send_stream(service_id, body).await
 	}
 
+	/// Send a stream message with header flags to the remote peer.
+	///
+	/// See [`PeerWriteHandle::send_stream_with_flags()`] for more details.
+	pub async fn send_stream_with_flags(&self, service_id: i32, flags: u16, body: impl Into<Body>) -> Result<(), Error> {
+		self.write_handle.send_stream_with_flags(service_id, flags, body).await
+	}
+
+	/// Send a stream message to the remote peer, and wait until the transport flushed it.
+	///
+	/// See [`PeerWriteHandle::send_stream_flushed()`] for more details.
+	pub async fn send_stream_flushed(&self, service_id: i32, body: impl Into<Body>) -> Result<(), Error> {
+		self.write_handle.send_stream_flushed(service_id, body).await
+	}
+
+	/// Wait until all queued messages are written to the transport and flushed.
+	///
+	/// See [`PeerWriteHandle::flush()`] for more details.
+	pub async fn flush(&self) -> Result<(), Error> {
+		self.write_handle.flush().await
+	}
+
+	/// Send an acknowledged stream message to the remote peer, and wait for the acknowledgement.
+	///
+	/// See [`PeerWriteHandle::send_stream_acked()`] for more details.
+	pub async fn send_stream_acked(&self, service_id: i32, body: impl Into<Body>) -> Result<Duration, Error> {
+		self.write_handle.send_stream_acked(service_id, body).await
+	}
+
+	/// Send a batch of stream messages to the remote peer.
+	///
+	/// See [`PeerWriteHandle::send_stream_batch()`] for more details.
+	pub async fn send_stream_batch(&self, messages: impl IntoIterator<Item = (i32, Body)>) -> Result<(), Error> {
+		self.write_handle.send_stream_batch(messages).await
+	}
+
+	/// Audit the open requests of the peer and report the requests whose handle has been dropped.
+	///
+	/// See [`PeerWriteHandle::audit()`] for more details.
+	pub async fn audit(&self) -> Result<TrackerAudit, Error> {
+		self.write_handle.audit().await
+	}
+
+	/// Run a quick self-test of the peer and report the state of the connection.
+	///
+	/// See [`PeerWriteHandle::diagnose()`] for more details.
+	pub async fn diagnose(&self) -> PeerDiagnostics {
+		self.write_handle.diagnose().await
+	}
+
+	/// Flush all queued messages and shut down the write side of the connection.
+	///
+	/// See [`PeerWriteHandle::finish()`] for more details.
+	pub async fn finish(&self) -> Result<(), Error> {
+		self.write_handle.finish().await
+	}
+
+	/// Set the egress policy of the peer.
+	///
+	/// See [`PeerWriteHandle::set_egress_policy()`] for more details.
+	pub fn set_egress_policy(&self, policy: impl EgressPolicy) {
+		self.write_handle.set_egress_policy(policy)
+	}
+
+	/// Remove the egress policy of the peer.
+	pub fn remove_egress_policy(&self) {
+		self.write_handle.remove_egress_policy()
+	}
+
+	/// Get a snapshot of the performance counters of the peer.
+	///
+	/// See [`PeerStats`] for the available counters.
+	pub fn stats(&self) -> PeerStats {
+		self.read_handle.stats()
+	}
+
+	/// Get the process-wide unique ID of the connection.
+	///
+	/// See [`Peer::connection_id()`][crate::Peer::connection_id] for more details.
+	pub fn connection_id(&self) -> u64 {
+		self.read_handle.connection_id()
+	}
+
+	/// Get the labels of the connection.
+	///
+	/// See [`ConnectionLabels`] for more details.
+	pub fn labels(&self) -> ConnectionLabels {
+		self.read_handle.labels()
+	}
+
+	/// Get the application context of the connection, if it has type `T`.
+	///
+	/// See [`ConnectionContext`][crate::ConnectionContext] for more details.
+	pub fn context<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+		self.read_handle.context()
+	}
+
 	/// Close the connection with the remote peer.
+	///
+	/// The peer loop stops as soon as possible, so messages that are still queued are not written.
 	pub fn close(self) {
 		self.read_handle.close()
 	}
@@ -125,15 +337,71 @@ impl<Body> PeerReadHandle<Body> {
 	///
 	/// Errors for invalid incoming messages are also reported by this function.
 	/// For example: incoming update messages that are not associated with a received request will be reported as an error here.
+	///
+	/// Requests that expired before they were received are skipped,
+	/// see [`Peer::with_request_expiry()`][crate::Peer::with_request_expiry].
+	///
+	/// Messages are returned in the order they were read from the transport.
+	/// In particular, stream messages with the same service ID are always returned in the order they were sent by the remote peer.
+	///
+	/// # Cancel safety
+	/// This function is cancel safe: it can be used in `tokio::select!` without losing messages.
+	/// A message is only taken from the queue when the future completes.
 	pub async fn recv_message(&mut self) -> Result<ReceivedMessage<Body>, Error> {
-		self.incoming_rx.recv()
-			.await
-			.ok_or_else(connection_aborted)?
+		std::future::poll_fn(|context| self.poll_recv_message(context)).await
+	}
+
+	/// Try to receive the next request or stream message from the remote peer without blocking.
+	///
+	/// If this function returns [`Poll::Pending`], the current task is scheduled to wake when a message is received.
+	pub fn poll_recv_message(&mut self, context: &mut Context) -> Poll<Result<ReceivedMessage<Body>, Error>> {
+		loop {
+			let incoming = match ready!(self.incoming_rx.poll_recv(context)) {
+				None => return Poll::Ready(Err(connection_aborted())),
+				Some(incoming) => incoming,
+			};
+			self.stats.incoming_dequeued();
+			match incoming {
+				Ok(ReceivedMessage::Request(request, _body)) if !request.pick_up() => continue,
+				incoming => return Poll::Ready(incoming),
+			}
+		}
+	}
+
+	/// Get a snapshot of the performance counters of the peer.
+	///
+	/// See [`PeerStats`] for the available counters.
+	pub fn stats(&self) -> PeerStats {
+		self.stats.snapshot()
+	}
+
+	/// Get the process-wide unique ID of the connection.
+	///
+	/// See [`Peer::connection_id()`][crate::Peer::connection_id] for more details.
+	pub fn connection_id(&self) -> u64 {
+		self.control.connection_id
+	}
+
+	/// Get the labels of the connection.
+	///
+	/// See [`ConnectionLabels`] for more details.
+	pub fn labels(&self) -> ConnectionLabels {
+		self.control.labels.lock().unwrap().clone()
+	}
+
+	/// Get the application context of the connection, if it has type `T`.
+	///
+	/// See [`ConnectionContext`][crate::ConnectionContext] for more details.
+	pub fn context<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+		self.control.context.lock().unwrap().get()
 	}
 
 	/// Close the connection with the remote peer.
+	///
+	/// The peer loop stops as soon as possible, so messages that are still queued are not written.
 	pub fn close(&self) {
-		let _: Result<_, _> = self.command_tx.send(Command::Stop);
+		// If the channel is full, a stop was already requested.
+		let _: Result<_, _> = self.control.stop_tx.try_send(());
 	}
 
 	/// Make a close handle for the peer.
@@ -141,45 +409,315 @@ impl<Body> PeerReadHandle<Body> {
 	/// The close handle can be used to close the connection with the remote peer.
 	/// It can be cloned and moved around independently.
 	pub fn close_handle(&self) -> PeerCloseHandle<Body> {
-		PeerCloseHandle {
-			command_tx: self.command_tx.clone(),
-		}
-	}
-}
-
-impl<Body> Drop for PeerReadHandle<Body> {
-	fn drop(&mut self) {
-		let _: Result<_, _> = self.command_tx.send(Command::UnregisterReadHandle);
+		PeerCloseHandle::new(self.control.stop_tx.clone())
 	}
 }
 
 impl<Body> PeerWriteHandle<Body> {
 	/// Send a new request to the remote peer.
+	///
+	/// This returns when the request has been written to the transport,
+	/// see the [delivery guarantees](Self#delivery-guarantees) for details.
 	pub async fn send_request(&self, service_id: i32, body: impl Into<Body>) -> Result<SentRequestHandle<Body>, Error> {
-		let body = body.into();
+		self.send_request_with_deadline(service_id, body, None).await
+	}
+
+	/// Send a new request to the remote peer, blocking the current thread.
+	///
+	/// This allows non-async code, like callbacks from C libraries or GUI threads, to send requests.
+	/// It does not require a tokio runtime on the current thread,
+	/// but the peer itself must be running on a runtime in a different thread.
+	///
+	/// # Panics
+	/// This function panics if it is called from within a tokio runtime, because it would block the thread of the runtime.
+	#[track_caller]
+	pub fn blocking_send_request(&self, service_id: i32, body: impl Into<Body>) -> Result<SentRequestHandle<Body>, Error> {
+		crate::util::block_on(self.send_request(service_id, body))
+	}
+
+	/// Send a new request to the remote peer and wait for the response.
+	///
+	/// This is a leaner alternative to [`Self::send_request()`] followed by [`SentRequestHandle::recv_response()`],
+	/// for services that do not use update messages.
+	/// No request handle is created, so the peer does not need to allocate a channel for update messages.
+	///
+	/// Like [`SentRequestHandle::recv_response()`], this returns the raw response message,
+	/// use [`Message::check_error_response()`] to turn error responses into an [`Error`].
+	/// If the remote peer sends an update message instead of the response, this returns an error.
+	///
+	/// If the returned future is dropped before the response arrives, the request stays open until the response is received.
+	pub async fn send_request_oneshot(&self, service_id: i32, body: impl Into<Body>) -> Result<Message<Body>, Error> {
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx
+			.send(SendOneshotRequest { service_id, body: body.into(), response_tx }.into())
+			.map_err(SendError::into_error)?;
+
+		response_rx.await.map_err(|_| connection_aborted())?
+	}
+
+	/// Send a new request with a deadline to the remote peer.
+	///
+	/// The deadline tells the remote peer how long you are willing to wait for the response.
+	/// It is available to the remote peer through [`ReceivedRequestHandle::deadline()`][crate::ReceivedRequestHandle::deadline],
+	/// and generated servers automatically reject requests if the deadline passed before they are handled.
+	/// To propagate the deadline of a received request to the requests made to handle it,
+	/// simply pass the deadline of the received request.
+	///
+	/// The deadline is sent as the remaining time with millisecond precision,
+	/// so it does not depend on the clocks of both peers being synchronized.
+	/// If `deadline` is `None`, this is the same as [`Self::send_request()`].
+	pub async fn send_request_with_deadline(&self, service_id: i32, body: impl Into<Body>, deadline: Option<Instant>) -> Result<SentRequestHandle<Body>, Error> {
+		self.send_request_with_options(service_id, body.into(), deadline, None, None).await
+	}
+
+	/// Send a new request with a trace ID to the remote peer.
+	///
+	/// The trace ID is available to the remote peer through [`ReceivedRequestHandle::trace_id()`][crate::ReceivedRequestHandle::trace_id],
+	/// and to the sender through [`SentRequestHandle::trace_id()`].
+	/// It can be used to correlate the logs of both peers.
+	/// To propagate the trace ID of a received request to the requests made to handle it,
+	/// simply pass the trace ID of the received request.
+	///
+	/// If `trace_id` is `None`, the peer generates a new trace ID if it was created with [`Peer::with_trace_ids()`][crate::Peer::with_trace_ids],
+	/// otherwise the request is sent without trace ID.
+	///
+	/// The trace ID is only sent if the remote peer announced support for trace IDs.
+	/// Otherwise, an explicit trace ID is only available through [`SentRequestHandle::trace_id()`].
+	pub async fn send_request_with_trace_id(&self, service_id: i32, body: impl Into<Body>, trace_id: Option<u64>) -> Result<SentRequestHandle<Body>, Error> {
+		self.send_request_with_options(service_id, body.into(), None, trace_id, None).await
+	}
+
+	/// Send a new request with a deadline and a type fingerprint to the remote peer.
+	///
+	/// The type fingerprint identifies the request and response body types of the service,
+	/// as computed by the [`interface!`][crate::interface] macro.
+	/// It is available to the remote peer through [`ReceivedRequestHandle::type_fingerprint()`][crate::ReceivedRequestHandle::type_fingerprint],
+	/// and generated servers reject requests with a fingerprint that does not match their own interface definition.
+	///
+	/// This is used by generated clients, see `Client::set_type_fingerprints()`.
+	/// See [`Self::send_request_with_deadline()`] for the meaning of the `deadline`.
+	/// If both `deadline` and `type_fingerprint` are `None`, this is the same as [`Self::send_request()`].
+	pub async fn send_request_with_type_fingerprint(&self, service_id: i32, body: impl Into<Body>, deadline: Option<Instant>, type_fingerprint: Option<u64>) -> Result<SentRequestHandle<Body>, Error> {
+		self.send_request_with_options(service_id, body.into(), deadline, None, type_fingerprint).await
+	}
+
+	/// Send a new request with an optional deadline, trace ID and type fingerprint to the remote peer.
+	async fn send_request_with_options(&self, service_id: i32, body: Body, deadline: Option<Instant>, trace_id: Option<u64>, type_fingerprint: Option<u64>) -> Result<SentRequestHandle<Body>, Error> {
 		let (result_tx, result_rx) = oneshot::channel();
 		self.command_tx
-			.send(SendRequest { service_id, body, result_tx }.into())
-			.map_err(|_| connection_aborted())?;
+			.send(SendRequest { service_id, body, deadline, trace_id, type_fingerprint, result_tx }.into())
+			.map_err(SendError::into_error)?;
 
 		result_rx.await.map_err(|_| connection_aborted())?
 	}
 
 	/// Send a stream message to the remote peer.
+	///
+	/// This returns when the message has been written to the transport,
+	/// see the [delivery guarantees](Self#delivery-guarantees) for details.
 	pub async fn send_stream(&self, service_id: i32, body: impl Into<Body>) -> Result<(), Error> {
+		let result_rx = self.queue_stream(service_id, body.into())?;
+		result_rx.await.map_err(|_| connection_aborted())?
+	}
+
+	/// Send a stream message with [header flags][crate::MessageHeader::flags] to the remote peer.
+	///
+	/// The flags are cleared if the remote peer did not announce support for them,
+	/// see [`Peer::with_header_flags()`][crate::Peer::with_header_flags].
+	/// To set flags on other messages, use an [`Interceptor`][crate::Interceptor].
+	pub async fn send_stream_with_flags(&self, service_id: i32, flags: u16, body: impl Into<Body>) -> Result<(), Error> {
+		let result_rx = self.queue_raw_message(Message::stream(0, service_id, body.into()).with_flags(flags))?;
+		result_rx.await.map_err(|_| connection_aborted())?
+	}
+
+	/// Send a stream message to the remote peer, and wait until the transport flushed it.
+	///
+	/// This is the same as [`Self::send_stream()`] followed by [`Self::flush()`],
+	/// except that no other message can be queued in between.
+	pub async fn send_stream_flushed(&self, service_id: i32, body: impl Into<Body>) -> Result<(), Error> {
+		let result_rx = self.queue_stream(service_id, body.into())?;
+		let flush_rx = self.queue_flush()?;
+		result_rx.await.map_err(|_| connection_aborted())??;
+		flush_rx.await.map_err(|_| connection_aborted())?
+	}
+
+	/// Wait until all messages queued before this call are written to the transport and flushed.
+	///
+	/// Transports that do not buffer outgoing data have nothing to flush,
+	/// so this only waits for the queued messages to be written.
+	pub async fn flush(&self) -> Result<(), Error> {
+		let flush_rx = self.queue_flush()?;
+		flush_rx.await.map_err(|_| connection_aborted())?
+	}
+
+	/// Queue a flush command for the peer loop.
+	fn queue_flush(&self) -> Result<oneshot::Receiver<Result<(), Error>>, Error> {
+		let (result_tx, result_rx) = oneshot::channel();
+		self.command_tx
+			.send(Flush { result_tx }.into())
+			.map_err(SendError::into_error)?;
+		Ok(result_rx)
+	}
+
+	/// Send an acknowledged stream message to the remote peer, and wait for the acknowledgement.
+	///
+	/// The peer loop of the remote peer acknowledges the message as soon as it is received,
+	/// before it is delivered to the application.
+	/// The remote application receives the message as a regular stream message.
+	///
+	/// On success, the round trip time from calling this function until the acknowledgement arrived is returned.
+	/// Waiting for the acknowledgement before sending the next message can be used to apply backpressure.
+	/// Use a timeout to detect lost messages: if the connection is closed before the acknowledgement arrives,
+	/// an error is returned, but the acknowledgement can also be delayed indefinitely by a misbehaving remote peer.
+	///
+	/// Unlike a request, an acknowledged stream message is not tracked by the remote peer.
+	///
+	/// With the `strict-memory` feature, the number of acknowledged stream messages waiting for an acknowledgement is limited to
+	/// [`ChannelCapacities::commands`][crate::ChannelCapacities::commands].
+	/// If the limit is reached, this returns an error for which [`Error::is_capacity_exceeded()`] is true.
+	pub async fn send_stream_acked(&self, service_id: i32, body: impl Into<Body>) -> Result<Duration, Error> {
+		let start = Instant::now();
 		let body = body.into();
 		let (result_tx, result_rx) = oneshot::channel();
-		let message = Message::stream(0, service_id, body);
 		self.command_tx
-			.send(SendRawMessage { message, result_tx }.into())
-			.map_err(|_| connection_aborted())?;
+			.send(SendAckedStream { service_id, body, result_tx }.into())
+			.map_err(SendError::into_error)?;
+
+		result_rx.await.map_err(|_| connection_aborted())??;
+		Ok(start.elapsed())
+	}
+
+	/// Send a batch of stream messages to the remote peer.
+	///
+	/// Each item of the batch is a service ID and the body for a stream message.
+	/// The whole batch is passed to the peer loop at once, and the transport may coalesce the messages into fewer writes.
+	/// This has much less overhead per message than calling [`Self::send_stream()`] for each message,
+	/// which is useful for sending many small messages.
+	///
+	/// The messages are sent in order.
+	/// If a message can not be sent, for example because it is rejected by the egress policy,
+	/// the remaining messages are not sent and the error is returned.
+	/// On success, all messages have been written to the transport,
+	/// see the [delivery guarantees](Self#delivery-guarantees) for details.
+	pub async fn send_stream_batch(&self, messages: impl IntoIterator<Item = (i32, Body)>) -> Result<(), Error> {
+		let messages = messages.into_iter()
+			.map(|(service_id, body)| Message::stream(0, service_id, body))
+			.collect();
+		let (result_tx, result_rx) = oneshot::channel();
+		self.command_tx
+			.send(SendStreamBatch { messages, queued_at: Instant::now(), result_tx }.into())
+			.map_err(SendError::into_error)?;
+
+		result_rx.await.map_err(|_| connection_aborted())?
+	}
+
+	/// Queue a stream message for the peer loop without waiting for it to be written.
+	///
+	/// The returned channel receives the result of writing the message to the transport.
+	pub(crate) fn queue_stream(&self, service_id: i32, body: Body) -> Result<oneshot::Receiver<Result<(), Error>>, Error> {
+		self.queue_raw_message(Message::stream(0, service_id, body))
+	}
+
+	/// Queue a raw message for the peer loop without waiting for it to be written.
+	///
+	/// The message is written as-is, so it must not interfere with the requests tracked by the peer.
+	/// The returned channel receives the result of writing the message to the transport.
+	pub(crate) fn queue_raw_message(&self, message: Message<Body>) -> Result<oneshot::Receiver<Result<(), Error>>, Error> {
+		let (result_tx, result_rx) = oneshot::channel();
+		self.command_tx
+			.send(SendRawMessage { message, queued_at: Instant::now(), result_tx }.into())
+			.map_err(SendError::into_error)?;
+		Ok(result_rx)
+	}
+
+	/// Set the egress policy of the peer.
+	///
+	/// The policy is shared by all handles of the peer and replaces any previously set policy.
+	/// It applies to all messages queued after this call, including updates and responses for existing requests.
+	/// See [`EgressPolicy`] for more details.
+	pub fn set_egress_policy(&self, policy: impl EgressPolicy) {
+		let mut egress_policy = match self.control.egress_policy.lock() {
+			Ok(x) => x,
+			Err(e) => e.into_inner(),
+		};
+		*egress_policy = Some(Box::new(policy));
+	}
+
+	/// Remove the egress policy of the peer.
+	pub fn remove_egress_policy(&self) {
+		let mut egress_policy = match self.control.egress_policy.lock() {
+			Ok(x) => x,
+			Err(e) => e.into_inner(),
+		};
+		*egress_policy = None;
+	}
+
+	/// Audit the open requests of the peer and report the requests whose handle has been dropped.
+	///
+	/// This is mainly useful in tests, to check that no requests are leaked.
+	/// The audit is performed by the peer loop after it processed all commands queued before this call.
+	/// See [`TrackerAudit`] for more details.
+	pub async fn audit(&self) -> Result<TrackerAudit, Error> {
+		let (result_tx, result_rx) = oneshot::channel();
+		self.command_tx
+			.send(Audit { result_tx }.into())
+			.map_err(SendError::into_error)?;
+
+		result_rx.await.map_err(|_| connection_aborted())
+	}
+
+	/// Run a quick self-test of the peer and report the state of the connection.
+	///
+	/// The self-test queues an [audit][Self::audit] for the peer loop and measures how long it takes to be processed.
+	/// The report also contains the queue lengths, the time since the last activity on the transport, and the [`PeerStats`] of the peer.
+	/// See [`PeerDiagnostics`] for more details.
+	///
+	/// The audit is processed after all commands queued before it.
+	/// If the peer loop is blocked, for example on a write to a saturated transport, this waits until it is unblocked.
+	/// Wrap the call in a timeout if you need an answer regardless.
+	pub async fn diagnose(&self) -> PeerDiagnostics {
+		let start = Instant::now();
+		let audit = self.audit().await.ok();
+		let command_loop_latency = audit.is_some().then(|| start.elapsed());
+		let (since_last_sent, since_last_received) = self.stats.since_last_activity();
+		PeerDiagnostics {
+			connection_id: self.connection_id(),
+			labels: self.labels(),
+			command_loop_latency,
+			audit,
+			since_last_sent,
+			since_last_received,
+			stats: self.stats(),
+		}
+	}
+
+	/// Flush all queued messages and shut down the write side of the connection.
+	///
+	/// All messages queued before this call are written to the transport before it is shut down.
+	/// For stream transports like TCP and Unix streams, the remote peer sees the end of the stream after the last message.
+	/// Transports that do not support a write-side shutdown only flush the queued messages.
+	///
+	/// Unlike [`Self::close()`], this leaves the read side of the connection open.
+	/// You can still receive messages that the remote peer sends before it closes the connection.
+	///
+	/// After the shutdown, sending messages fails with an I/O error of kind [`BrokenPipe`][std::io::ErrorKind::BrokenPipe].
+	/// This includes responses to received requests.
+	/// Calling this function again has no effect.
+	pub async fn finish(&self) -> Result<(), Error> {
+		let (result_tx, result_rx) = oneshot::channel();
+		self.command_tx
+			.send(Finish { result_tx }.into())
+			.map_err(SendError::into_error)?;
 
 		result_rx.await.map_err(|_| connection_aborted())?
 	}
 
 	/// Close the connection with the remote peer.
+	///
+	/// The peer loop stops as soon as possible, so messages that are still queued are not written.
 	pub fn close(&self) {
-		let _: Result<_, _> = self.command_tx.send(Command::Stop);
+		// If the channel is full, a stop was already requested.
+		let _: Result<_, _> = self.control.stop_tx.try_send(());
 	}
 
 	/// Make a close handle for the peer.
@@ -187,35 +725,81 @@ impl<Body> PeerWriteHandle<Body> {
 	/// The close handle can be used to close the connection with the remote peer.
 	/// It can be cloned and moved around independently.
 	pub fn close_handle(&self) -> PeerCloseHandle<Body> {
-		PeerCloseHandle {
-			command_tx: self.command_tx.clone(),
-		}
+		PeerCloseHandle::new(self.control.stop_tx.clone())
+	}
+
+	/// Get a snapshot of the performance counters of the peer.
+	///
+	/// See [`PeerStats`] for the available counters.
+	pub fn stats(&self) -> PeerStats {
+		self.stats.snapshot()
+	}
+
+	/// Get the process-wide unique ID of the connection.
+	///
+	/// See [`Peer::connection_id()`][crate::Peer::connection_id] for more details.
+	pub fn connection_id(&self) -> u64 {
+		self.control.connection_id
+	}
+
+	/// Get the labels of the connection.
+	///
+	/// See [`ConnectionLabels`] for more details.
+	pub fn labels(&self) -> ConnectionLabels {
+		self.control.labels.lock().unwrap().clone()
+	}
+
+	/// Get the application context of the connection, if it has type `T`.
+	///
+	/// See [`ConnectionContext`][crate::ConnectionContext] for more details.
+	pub fn context<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+		self.control.context.lock().unwrap().get()
 	}
 
 	/// Check if this handle has the same underlying channel as `other`.
 	pub fn same_peer(&self, other: &Self) -> bool {
 		self.command_tx.same_channel(&other.command_tx)
 	}
+
+	/// Check if the peer loop has stopped.
+	///
+	/// If this returns true, all attempts to send messages will fail.
+	pub(crate) fn is_closed(&self) -> bool {
+		self.command_tx.is_closed()
+	}
 }
 
 impl<Body> Clone for PeerWriteHandle<Body> {
 	fn clone(&self) -> Self {
-		let command_tx = self.command_tx.clone();
-		let _: Result<_, _> = command_tx.send(Command::RegisterWriteHandle);
-		Self { command_tx }
+		Self {
+			command_tx: self.command_tx.clone(),
+			control: self.control.clone(),
+			stats: self.stats.clone(),
+		}
 	}
 }
 
-impl<Body> Drop for PeerWriteHandle<Body> {
-	fn drop(&mut self) {
-		let _: Result<_, _> = self.command_tx.send(Command::UnregisterWriteHandle);
+impl<Body> PeerCloseHandle<Body> {
+	/// Create a new close handle from the stop channel of a peer.
+	fn new(stop_tx: mpsc::Sender<()>) -> Self {
+		Self {
+			stop_tx,
+			_body: PhantomData,
+		}
 	}
-}
 
-impl<Body> PeerCloseHandle<Body> {
 	/// Close the connection with the remote peer.
+	///
+	/// The peer loop stops as soon as possible, so messages that are still queued are not written.
 	pub fn close(&self) {
-		let _: Result<_, _> = self.command_tx.send(Command::Stop);
+		// If the channel is full, a stop was already requested.
+		let _: Result<_, _> = self.stop_tx.try_send(());
+	}
+}
+
+impl<Body> Clone for PeerCloseHandle<Body> {
+	fn clone(&self) -> Self {
+		Self::new(self.stop_tx.clone())
 	}
 }
 
@@ -248,6 +832,34 @@ mod test {
 	use assert2::let_assert;
 	use tokio_seqpacket::UnixSeqpacket;
 
+	#[tokio::test]
+	async fn recv_message_is_cancel_safe() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixSeqpacket::pair());
+		let mut handle_a = fizyr_rpc::UnixSeqpacketPeer::spawn(UnixSeqpacketTransport::new(peer_a, Default::default()));
+		let handle_b = fizyr_rpc::UnixSeqpacketPeer::spawn(UnixSeqpacketTransport::new(peer_b, Default::default()));
+
+		let sender = tokio::spawn(async move {
+			for i in 0..20 {
+				assert!(let Ok(()) = handle_b.send_stream(i, &[][..]).await);
+				tokio::time::sleep(std::time::Duration::from_micros(200)).await;
+			}
+		});
+
+		// Keep cancelling the future with a short timer.
+		let mut received = Vec::new();
+		while received.len() < 20 {
+			tokio::select! {
+				message = handle_a.recv_message() => {
+					let_assert!(Ok(fizyr_rpc::ReceivedMessage::Stream(message)) = message);
+					received.push(message.header.service_id);
+				},
+				_ = tokio::time::sleep(std::time::Duration::from_micros(50)) => (),
+			}
+		}
+		assert!(received == (0..20).collect::<Vec<_>>());
+		assert!(let Ok(()) = sender.await);
+	}
+
 	#[tokio::test]
 	async fn test_same_peer() {
 		let_assert!(Ok((peer_a, peer_b)) = UnixSeqpacket::pair());
@@ -0,0 +1,289 @@
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Mutex;
+
+use crate::{Error, PeerHandle, PeerWriteHandle, SentRequestHandle};
+
+/// Future returned by the connect function of a [`PeerPool`].
+type ConnectFuture<Body> = Pin<Box<dyn Future<Output = std::io::Result<PeerHandle<Body>>> + Send>>;
+
+/// Pool of connections to the same remote server.
+///
+/// The pool spreads outgoing requests and stream messages over multiple connections in a round-robin fashion.
+/// This prevents a single connection from becoming a bottleneck when many large messages are sent concurrently.
+///
+/// Requests that must be delivered in order can be sent with a key, for example the name of the robot arm they control.
+/// All messages with the same key use the same connection, while different keys are spread over the connections.
+///
+/// Connections are created on demand with the connect function given to [`PeerPool::new()`].
+/// When a connection is closed, it is replaced transparently the next time it is selected.
+///
+/// The pool only keeps the write half of each connection.
+/// Incoming requests from the server are rejected and incoming stream messages are discarded.
+/// The pool can be shared between tasks, for example by wrapping it in an [`Arc`][std::sync::Arc].
+pub struct PeerPool<Body> {
+	/// Function to create a new connection.
+	connect: Box<dyn Fn() -> ConnectFuture<Body> + Send + Sync>,
+
+	/// The slots of the pool, each holding one connection if it is connected.
+	slots: Vec<Mutex<Option<PeerWriteHandle<Body>>>>,
+
+	/// The index of the next slot to use.
+	next_slot: AtomicUsize,
+}
+
+impl<Body: crate::Body> PeerPool<Body> {
+	/// Create a new pool with `size` connections.
+	///
+	/// The `connect` function is called whenever a new connection is needed.
+	/// No connections are made until the pool is used.
+	///
+	/// # Panics
+	/// This function panics if `size` is zero.
+	pub fn new<F, R>(size: usize, connect: F) -> Self
+	where
+		F: Fn() -> R + Send + Sync + 'static,
+		R: Future<Output = std::io::Result<PeerHandle<Body>>> + Send + 'static,
+	{
+		assert!(size > 0, "a peer pool needs at least one connection");
+		Self {
+			connect: Box::new(move || Box::pin(connect())),
+			slots: (0..size).map(|_| Mutex::new(None)).collect(),
+			next_slot: AtomicUsize::new(0),
+		}
+	}
+
+	/// Get the number of connections in the pool.
+	pub fn size(&self) -> usize {
+		self.slots.len()
+	}
+
+	/// Get a write handle for the next connection in the pool.
+	///
+	/// Connections that have been closed are replaced by a new connection.
+	/// If a new connection can not be made, the next connection in the pool is tried.
+	/// An error is returned only if no connection could be made at all.
+	pub async fn write_handle(&self) -> Result<PeerWriteHandle<Body>, Error> {
+		let start = self.next_slot.fetch_add(1, Ordering::Relaxed);
+		let mut error = None;
+		for i in 0..self.slots.len() {
+			match self.slot_write_handle((start + i) % self.slots.len()).await {
+				Ok(write_handle) => return Ok(write_handle),
+				Err(e) => error = Some(e),
+			}
+		}
+
+		// There is at least one slot, so if we get here, we must have a connection error.
+		Err(Error::io_error(error.unwrap()))
+	}
+
+	/// Get a write handle for the connection assigned to a key.
+	///
+	/// All calls with the same key use the same connection,
+	/// so messages sent with the same key are delivered in the order they were sent.
+	/// Different keys are spread over the connections in the pool.
+	///
+	/// If the assigned connection has been closed, it is replaced by a new connection.
+	/// To preserve the ordering guarantee, other connections are never used for the key.
+	/// If a new connection can not be made, an error is returned.
+	pub async fn write_handle_for_key<K: Hash + ?Sized>(&self, key: &K) -> Result<PeerWriteHandle<Body>, Error> {
+		self.slot_write_handle(self.slot_for_key(key)).await
+			.map_err(Error::io_error)
+	}
+
+	/// Send a new request over the next connection in the pool.
+	pub async fn send_request(&self, service_id: i32, body: impl Into<Body>) -> Result<SentRequestHandle<Body>, Error> {
+		self.write_handle().await?.send_request(service_id, body).await
+	}
+
+	/// Send a new request over the connection assigned to a key.
+	///
+	/// See [`Self::write_handle_for_key()`] for details on how keys are assigned to connections.
+	pub async fn send_request_with_key<K: Hash + ?Sized>(&self, key: &K, service_id: i32, body: impl Into<Body>) -> Result<SentRequestHandle<Body>, Error> {
+		self.write_handle_for_key(key).await?.send_request(service_id, body).await
+	}
+
+	/// Send a stream message over the next connection in the pool.
+	pub async fn send_stream(&self, service_id: i32, body: impl Into<Body>) -> Result<(), Error> {
+		self.write_handle().await?.send_stream(service_id, body).await
+	}
+
+	/// Send a stream message over the connection assigned to a key.
+	///
+	/// See [`Self::write_handle_for_key()`] for details on how keys are assigned to connections.
+	pub async fn send_stream_with_key<K: Hash + ?Sized>(&self, key: &K, service_id: i32, body: impl Into<Body>) -> Result<(), Error> {
+		self.write_handle_for_key(key).await?.send_stream(service_id, body).await
+	}
+
+	/// Close all connections in the pool.
+	///
+	/// Requests that are still open will be terminated.
+	/// The pool remains usable: new connections will be made when it is used again.
+	pub async fn close(&self) {
+		for slot in &self.slots {
+			if let Some(peer) = slot.lock().await.take() {
+				peer.close();
+			}
+		}
+	}
+}
+
+impl<Body> PeerPool<Body> {
+	/// Get a write handle for the connection in a slot, connecting if needed.
+	async fn slot_write_handle(&self, index: usize) -> std::io::Result<PeerWriteHandle<Body>> {
+		let mut slot = self.slots[index].lock().await;
+		if let Some(peer) = slot.as_ref() {
+			if !peer.is_closed() {
+				return Ok(peer.clone());
+			}
+		}
+
+		match (self.connect)().await {
+			Ok(peer) => {
+				let (_read_handle, write_handle) = peer.split();
+				*slot = Some(write_handle.clone());
+				Ok(write_handle)
+			},
+			Err(e) => {
+				*slot = None;
+				Err(e)
+			},
+		}
+	}
+
+	/// Get the index of the slot assigned to a key.
+	///
+	/// The assignment is stable for the lifetime of the process.
+	fn slot_for_key<K: Hash + ?Sized>(&self, key: &K) -> usize {
+		let mut hasher = DefaultHasher::new();
+		key.hash(&mut hasher);
+		(hasher.finish() % self.slots.len() as u64) as usize
+	}
+}
+
+impl<Body> std::fmt::Debug for PeerPool<Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct(core::any::type_name::<Self>())
+			.field("size", &self.slots.len())
+			.finish_non_exhaustive()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+	use std::sync::Arc;
+
+	use crate::{ReceivedMessage, StreamBody, UnixStreamPeer, UnixStreamTransport};
+
+	/// Create a pool where each connection is served by a task that responds with the connection number.
+	///
+	/// The server closes a connection after responding to a request with body `close`.
+	fn make_pool(size: usize) -> PeerPool<StreamBody> {
+		let connections = Arc::new(AtomicUsize::new(0));
+		PeerPool::new(size, move || {
+			let connections = connections.clone();
+			async move {
+				let (client, server) = tokio::net::UnixStream::pair()?;
+				let index = connections.fetch_add(1, Ordering::Relaxed) as u8;
+				let mut server = UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default()));
+				tokio::spawn(async move {
+					while let Ok(ReceivedMessage::Request(request, body)) = server.recv_message().await {
+						let _: Result<_, _> = request.send_response(1, vec![index]).await;
+						if body.as_ref() == b"close" {
+							server.close();
+							break;
+						}
+					}
+				});
+				Ok(UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default())))
+			}
+		})
+	}
+
+	async fn request(pool: &PeerPool<StreamBody>, body: &[u8]) -> Result<u8, Error> {
+		let mut request = pool.send_request(1, body).await?;
+		let response = request.recv_response().await?;
+		Ok(response.body[0])
+	}
+
+	#[tokio::test]
+	async fn round_robin() {
+		let pool = make_pool(3);
+		assert!(pool.size() == 3);
+		assert!(let Ok(0) = request(&pool, b"hello").await);
+		assert!(let Ok(1) = request(&pool, b"hello").await);
+		assert!(let Ok(2) = request(&pool, b"hello").await);
+		assert!(let Ok(0) = request(&pool, b"hello").await);
+
+		// The pool can be shared with other tasks.
+		let pool = Arc::new(pool);
+		let task = tokio::spawn({
+			let pool = pool.clone();
+			async move { request(&pool, b"hello").await }
+		});
+		assert!(let Ok(Ok(1)) = task.await);
+	}
+
+	#[tokio::test]
+	async fn replace_closed_connection() {
+		let pool = make_pool(2);
+		assert!(let Ok(0) = request(&pool, b"close").await);
+		assert!(let Ok(1) = request(&pool, b"hello").await);
+
+		// Wait for the pool to notice the closed connection and replace it.
+		let mut replaced = false;
+		for _ in 0..100 {
+			if let Ok(2) = request(&pool, b"hello").await {
+				replaced = true;
+				break;
+			}
+			tokio::task::yield_now().await;
+		}
+		assert!(replaced);
+	}
+
+	async fn request_with_key(pool: &PeerPool<StreamBody>, key: &str) -> Result<u8, Error> {
+		let mut request = pool.send_request_with_key(key, 1, &b"hello"[..]).await?;
+		let response = request.recv_response().await?;
+		Ok(response.body[0])
+	}
+
+	#[tokio::test]
+	async fn key_affinity() {
+		let pool = make_pool(4);
+		let keys = ["arm-1", "arm-2", "arm-3", "arm-4", "arm-5", "arm-6", "arm-7", "arm-8"];
+
+		let mut connections = Vec::new();
+		for key in keys {
+			let_assert!(Ok(connection) = request_with_key(&pool, key).await);
+			connections.push(connection);
+		}
+
+		// The same key always uses the same connection, regardless of other traffic.
+		for (key, &connection) in keys.iter().zip(&connections) {
+			assert!(let Ok(_) = request(&pool, b"hello").await);
+			assert!(request_with_key(&pool, key).await.ok() == Some(connection));
+			assert!(pool.slot_for_key(key) == pool.slot_for_key(&String::from(*key)));
+		}
+
+		// Different keys are spread over multiple connections.
+		connections.sort_unstable();
+		connections.dedup();
+		assert!(connections.len() > 1);
+	}
+
+	#[tokio::test]
+	async fn connect_failure() {
+		let pool = PeerPool::<StreamBody>::new(2, || async {
+			Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+		});
+		let_assert!(Err(e) = pool.send_stream(1, vec![]).await);
+		assert!(e.to_string() == std::io::Error::from(std::io::ErrorKind::ConnectionRefused).to_string());
+	}
+}
@@ -0,0 +1,66 @@
+//! Commonly used traits and types.
+//!
+//! Import the prelude to bring the traits into scope that are needed to use transports and message formats,
+//! together with the peer and request handle types:
+//!
+//! ```
+//! use fizyr_rpc::prelude::*;
+//!
+//! # async fn foo(peer: PeerHandle<fizyr_rpc::StreamBody>) -> Result<(), fizyr_rpc::Error> {
+//! let (mut read_handle, write_handle): (PeerReadHandle<_>, PeerWriteHandle<_>) = peer.split();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Items are only added to the prelude in new major versions,
+//! so a glob import of the prelude will not break when you update to a new minor version.
+
+pub use crate::format::{DecodeBody, EncodeBody, Format};
+pub use crate::transport::{Transport, TransportReadHalf, TransportWriteHalf};
+pub use crate::util::{Bind, Connect, IntoTransport};
+pub use crate::{
+	Body,
+	Peer,
+	PeerCloseHandle,
+	PeerHandle,
+	PeerReadHandle,
+	PeerWriteHandle,
+	ReceivedMessage,
+	ReceivedRequestHandle,
+	ReceivedRequestWriteHandle,
+	SentRequestHandle,
+	SentRequestWriteHandle,
+};
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::{assert, let_assert};
+
+	/// Test that the transport and format traits are usable through the prelude alone.
+	#[tokio::test]
+	async fn traits_in_scope() {
+		struct Raw;
+
+		impl Format for Raw {
+			type Body = crate::StreamBody;
+		}
+
+		impl EncodeBody<[u8]> for Raw {
+			fn encode_body(value: &[u8]) -> Result<Self::Body, Box<dyn std::error::Error + Send>> {
+				Ok(value.into())
+			}
+		}
+
+		let_assert!(Ok((stream_a, stream_b)) = tokio::net::UnixStream::pair());
+		let mut transport_a = crate::UnixStreamTransport::new(stream_a, Default::default());
+		let mut transport_b = crate::UnixStreamTransport::new(stream_b, Default::default());
+		let (_read_a, mut write_a) = transport_a.split();
+		let (mut read_b, _write_b) = transport_b.split();
+
+		let_assert!(Ok(body) = Raw::encode_body(b"hello"));
+		let_assert!(Ok(()) = write_a.write_msg(&crate::MessageHeader::stream(0, 1), &body).await);
+		let_assert!(Ok(message) = read_b.read_msg().await);
+		assert!(message.body.as_ref() == b"hello");
+	}
+}
@@ -0,0 +1,523 @@
+//! Publish/subscribe on top of stream messages.
+//!
+//! Peers can subscribe to named topics on a server and publish messages to a topic.
+//! The server keeps track of the subscriptions with a [`PubSubServer`],
+//! and only forwards each published message to the peers subscribed to the topic.
+//! Filtering by topic happens on the server, so no bandwidth is wasted on messages that a peer is not interested in.
+//!
+//! Clients use a [`PubSubClient`] to manage subscriptions and to publish messages.
+//! The server can also publish messages itself with [`PubSubServer::publish()`].
+//!
+//! Payloads are regular message bodies.
+//! Typed payloads can be encoded with a [`Format`][crate::format::Format],
+//! using [`PubSubClient::publish_encoded()`] or [`PubSubServer::publish_encoded()`].
+//!
+//! # Protocol
+//! The server assigns a numeric topic ID to each topic name.
+//! Topic IDs are unique for the lifetime of the server and are never reused.
+//!
+//! To subscribe to a topic, the client sends a request with service ID [`service_id::SUBSCRIBE`].
+//! To unsubscribe, it sends a request with service ID [`service_id::UNSUBSCRIBE`].
+//! To look up the ID of a topic without subscribing, it sends a request with service ID [`service_id::TOPIC`].
+//! The body of these requests is the topic name as UTF-8 string.
+//! The response has the same service ID, and the body is the topic ID as UTF-8 decimal integer.
+//!
+//! Topics are created by the first subscription to the topic.
+//! Unsubscribing from or looking up a topic that does not exist yet is answered with an error response.
+//!
+//! A published message is a stream message with service ID [`service_id::PUBLISH`].
+//! The request ID field of the message header holds the topic ID, and the body is the payload.
+//! Published messages from clients are forwarded by the server to all subscribers of the topic,
+//! including the publisher if it is subscribed itself.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::error::private::connection_aborted;
+use crate::{service_id, Error, Message, PeerWriteHandle, ReceivedMessage, ReceivedRequestHandle};
+
+/// Client for the publish/subscribe protocol.
+///
+/// The client sends subscriptions and published messages over a [`PeerWriteHandle`].
+/// Published messages for subscribed topics are received as regular stream messages on the read handle of the same peer.
+/// Use [`Self::topic_of()`] to find the topic of a received message.
+///
+/// The client can be cloned cheaply.
+/// All clones share the same topic IDs.
+pub struct PubSubClient<Body> {
+	/// The write handle of the peer.
+	peer: PeerWriteHandle<Body>,
+
+	/// The known topic IDs by name.
+	topics: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+/// Server side state for the publish/subscribe protocol.
+///
+/// Pass all incoming messages of a connection to [`Self::handle_message()`] to handle subscriptions and published messages.
+/// The same server should be used for all connections, so that messages are forwarded between them.
+///
+/// The server can be cloned cheaply.
+/// All clones share the same topics and subscriptions.
+pub struct PubSubServer<Body> {
+	/// The shared state of the server.
+	state: Arc<Mutex<ServerState<Body>>>,
+}
+
+/// Shared state of a [`PubSubServer`].
+struct ServerState<Body> {
+	/// The topic IDs by name.
+	topic_ids: HashMap<String, u32>,
+
+	/// The subscribed peers by topic ID.
+	subscribers: HashMap<u32, Vec<PeerWriteHandle<Body>>>,
+
+	/// The ID for the next new topic.
+	next_topic_id: u32,
+
+	/// The maximum number of topics, if limited.
+	max_topics: Option<usize>,
+}
+
+impl<Body: crate::Body> PubSubClient<Body> {
+	/// Create a new client that uses the given peer.
+	pub fn new(peer: PeerWriteHandle<Body>) -> Self {
+		Self {
+			peer,
+			topics: Arc::new(Mutex::new(HashMap::new())),
+		}
+	}
+
+	/// Subscribe to a topic.
+	///
+	/// After this function returns, the peer receives all messages published to the topic.
+	///
+	/// Subscribing to a new topic fails with a remote error if the server reached its [maximum number of topics][PubSubServer::with_max_topics].
+	pub async fn subscribe(&self, topic: &str) -> Result<(), Error> {
+		self.topic_request(service_id::SUBSCRIBE, topic).await?;
+		Ok(())
+	}
+
+	/// Unsubscribe from a topic.
+	///
+	/// Messages that were already sent by the server may still be received after this function returns.
+	/// Unsubscribing from a topic that no peer ever subscribed to fails with a remote error.
+	pub async fn unsubscribe(&self, topic: &str) -> Result<(), Error> {
+		self.topic_request(service_id::UNSUBSCRIBE, topic).await?;
+		Ok(())
+	}
+
+	/// Publish a message to a topic.
+	///
+	/// The server forwards the message to all peers subscribed to the topic.
+	/// Publishing does not require a subscription to the topic,
+	/// but the server rejects it with a remote error if no peer ever subscribed to the topic.
+	pub async fn publish(&self, topic: &str, body: impl Into<Body>) -> Result<(), Error> {
+		let topic_id = match self.known_topic_id(topic) {
+			Some(x) => x,
+			None => self.topic_request(service_id::TOPIC, topic).await?,
+		};
+		let result_rx = self.peer.queue_raw_message(Message::stream(topic_id, service_id::PUBLISH, body.into()))?;
+		result_rx.await.map_err(|_| connection_aborted())?
+	}
+
+	/// Encode a value and publish it to a topic.
+	pub async fn publish_encoded<F, T>(&self, topic: &str, value: &T) -> Result<(), Error>
+	where
+		F: crate::format::EncodeBody<T, Body = Body>,
+		T: ?Sized,
+	{
+		let body = F::encode_body(value).map_err(Error::encode_failed)?;
+		self.publish(topic, body).await
+	}
+
+	/// Get the topic of a received message.
+	///
+	/// Returns [`None`] if the message is not a published message,
+	/// or if it is for a topic that this client has not subscribed to or published to.
+	pub fn topic_of(&self, message: &Message<Body>) -> Option<String> {
+		if !message.header.message_type.is_stream() || message.header.service_id != service_id::PUBLISH {
+			return None;
+		}
+		let topic_id = message.header.request_id;
+		lock(&self.topics).iter()
+			.find(|(_name, &id)| id == topic_id)
+			.map(|(name, _id)| name.clone())
+	}
+
+	/// Get the topic ID of a topic if it is already known.
+	fn known_topic_id(&self, topic: &str) -> Option<u32> {
+		lock(&self.topics).get(topic).copied()
+	}
+
+	/// Send a request with a topic name and remember the topic ID from the response.
+	async fn topic_request(&self, service_id: i32, topic: &str) -> Result<u32, Error> {
+		let mut request = self.peer.send_request(service_id, Body::from_error(topic)).await?;
+		let response = request.recv_response().await?.check_error_response()?;
+		let topic_id = parse_topic_id(&response.body)?;
+		lock(&self.topics).insert(topic.to_owned(), topic_id);
+		Ok(topic_id)
+	}
+}
+
+impl<Body: crate::SharedBody> PubSubServer<Body> {
+	/// Create a new server without any topics.
+	pub fn new() -> Self {
+		Self {
+			state: Arc::new(Mutex::new(ServerState {
+				topic_ids: HashMap::new(),
+				subscribers: HashMap::new(),
+				next_topic_id: 0,
+				max_topics: None,
+			})),
+		}
+	}
+
+	/// Limit the number of topics of the server.
+	///
+	/// Topics are created when a peer subscribes to a topic that is not known yet.
+	/// Topics are never removed, so remote peers could exhaust the memory of the server by subscribing to many different topics.
+	/// If the limit is reached, subscriptions to new topics are answered with an error response.
+	/// Subscriptions to existing topics are still accepted.
+	///
+	/// By default, there is no limit.
+	pub fn with_max_topics(self, limit: Option<usize>) -> Self {
+		lock(&self.state).max_topics = limit;
+		self
+	}
+
+	/// Handle an incoming message from a peer.
+	///
+	/// The `peer` must be the write handle of the peer that received the message.
+	/// It is used to send published messages to the peer when it subscribes to a topic.
+	///
+	/// Publish/subscribe messages are handled and `None` is returned.
+	/// Other messages are returned unmodified, so they can be processed by the application.
+	///
+	/// The returned error only indicates a failure to send a response.
+	pub async fn handle_message(&self, peer: &PeerWriteHandle<Body>, message: ReceivedMessage<Body>) -> Result<Option<ReceivedMessage<Body>>, Error> {
+		match message {
+			ReceivedMessage::Request(request, body) => {
+				match request.service_id() {
+					service_id::SUBSCRIBE | service_id::UNSUBSCRIBE | service_id::TOPIC => {
+						self.handle_topic_request(peer, request, body).await?;
+						Ok(None)
+					},
+					_ => Ok(Some(ReceivedMessage::Request(request, body))),
+				}
+			},
+			ReceivedMessage::Stream(message) => {
+				if message.header.service_id != service_id::PUBLISH {
+					return Ok(Some(ReceivedMessage::Stream(message)));
+				}
+				self.publish_to(message.header.request_id, &message.body).await;
+				Ok(None)
+			},
+		}
+	}
+
+	/// Publish a message to all peers subscribed to a topic.
+	///
+	/// Returns the number of peers that the message was written to.
+	/// Peers with a closed connection are removed from all topics.
+	pub async fn publish(&self, topic: &str, body: &Body) -> usize {
+		let topic_id = lock(&self.state).topic_ids.get(topic).copied();
+		match topic_id {
+			Some(topic_id) => self.publish_to(topic_id, body).await,
+			None => 0,
+		}
+	}
+
+	/// Encode a value once and publish it to all peers subscribed to a topic.
+	///
+	/// Returns the number of peers that the message was written to,
+	/// or an error if the value could not be encoded.
+	pub async fn publish_encoded<F, T>(&self, topic: &str, value: &T) -> Result<usize, Error>
+	where
+		F: crate::format::EncodeBody<T, Body = Body>,
+		T: ?Sized,
+	{
+		let body = F::encode_body(value).map_err(Error::encode_failed)?;
+		Ok(self.publish(topic, &body).await)
+	}
+
+	/// Get the number of peers subscribed to a topic.
+	///
+	/// This may include peers that disconnected since the last message was published to the topic.
+	pub fn subscriber_count(&self, topic: &str) -> usize {
+		let state = lock(&self.state);
+		state.topic_ids.get(topic)
+			.and_then(|topic_id| state.subscribers.get(topic_id))
+			.map_or(0, |subscribers| subscribers.len())
+	}
+
+	/// Remove all subscriptions of a peer.
+	///
+	/// Peers with a closed connection are also removed automatically when a message is published to one of their topics.
+	pub fn remove_peer(&self, peer: &PeerWriteHandle<Body>) {
+		let mut state = lock(&self.state);
+		for subscribers in state.subscribers.values_mut() {
+			subscribers.retain(|x| !x.same_peer(peer));
+		}
+	}
+
+	/// Handle a subscribe, unsubscribe or topic lookup request.
+	async fn handle_topic_request(&self, peer: &PeerWriteHandle<Body>, request: ReceivedRequestHandle<Body>, body: Body) -> Result<(), Error> {
+		let topic = match body.as_error() {
+			Ok(x) => x,
+			Err(_) => return request.send_error_response("invalid topic name: not valid UTF-8").await,
+		};
+
+		let result = {
+			let mut state = lock(&self.state);
+			match request.service_id() {
+				// Only subscriptions create new topics, so other requests can not fill the server with topics.
+				service_id::SUBSCRIBE => state.new_or_existing_topic_id(topic).map(|topic_id| {
+					let subscribers = state.subscribers.entry(topic_id).or_default();
+					if !subscribers.iter().any(|x| x.same_peer(peer)) {
+						subscribers.push(peer.clone());
+					}
+					topic_id
+				}),
+				service_id::UNSUBSCRIBE => state.existing_topic_id(topic).map(|topic_id| {
+					if let Some(subscribers) = state.subscribers.get_mut(&topic_id) {
+						subscribers.retain(|x| !x.same_peer(peer));
+					}
+					topic_id
+				}),
+				_ => state.existing_topic_id(topic),
+			}
+		};
+
+		match result {
+			Ok(topic_id) => {
+				let service_id = request.service_id();
+				request.send_response(service_id, Body::from_error(&topic_id.to_string())).await
+			},
+			Err(message) => request.send_error_response(&message).await,
+		}
+	}
+
+	/// Publish a message to all subscribers of a topic ID.
+	async fn publish_to(&self, topic_id: u32, body: &Body) -> usize {
+		let subscribers = lock(&self.state).subscribers.get(&topic_id).cloned().unwrap_or_default();
+
+		// Queue the message for all subscribers first, so they can all write it concurrently.
+		let pending: Vec<_> = subscribers.iter()
+			.map(|peer| peer.queue_raw_message(Message::stream(topic_id, service_id::PUBLISH, body.clone_shared())))
+			.collect();
+
+		let mut written = 0;
+		let mut closed = Vec::new();
+		for (peer, result_rx) in subscribers.iter().zip(pending) {
+			let result = match result_rx {
+				Ok(result_rx) => result_rx.await.unwrap_or_else(|_| Err(connection_aborted())),
+				Err(e) => Err(e),
+			};
+			match result {
+				Ok(()) => written += 1,
+				Err(e) if e.is_connection_aborted() => closed.push(peer),
+				Err(_) => (),
+			}
+		}
+
+		for peer in closed {
+			self.remove_peer(peer);
+		}
+
+		written
+	}
+}
+
+impl<Body> ServerState<Body> {
+	/// Get the ID of a known topic.
+	///
+	/// Returns an error message for the remote peer if the topic is not known.
+	fn existing_topic_id(&self, topic: &str) -> Result<u32, String> {
+		self.topic_ids.get(topic)
+			.copied()
+			.ok_or_else(|| format!("unknown topic: {topic}"))
+	}
+
+	/// Get the ID of a topic, assigning a new ID if the topic is not known yet.
+	///
+	/// Returns an error message for the remote peer if the topic is not known and the maximum number of topics is reached.
+	fn new_or_existing_topic_id(&mut self, topic: &str) -> Result<u32, String> {
+		if let Some(&topic_id) = self.topic_ids.get(topic) {
+			return Ok(topic_id);
+		}
+		if let Some(max_topics) = self.max_topics {
+			if self.topic_ids.len() >= max_topics {
+				return Err(format!("too many topics: at most {max_topics} topics are allowed"));
+			}
+		}
+		let topic_id = self.next_topic_id;
+		self.next_topic_id += 1;
+		self.topic_ids.insert(topic.to_owned(), topic_id);
+		Ok(topic_id)
+	}
+}
+
+impl<Body: crate::SharedBody> Default for PubSubServer<Body> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl<Body> Clone for PubSubClient<Body> {
+	fn clone(&self) -> Self {
+		Self {
+			peer: self.peer.clone(),
+			topics: self.topics.clone(),
+		}
+	}
+}
+
+impl<Body> Clone for PubSubServer<Body> {
+	fn clone(&self) -> Self {
+		Self {
+			state: self.state.clone(),
+		}
+	}
+}
+
+impl<Body> std::fmt::Debug for PubSubClient<Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct(core::any::type_name::<Self>())
+			.field("topics", &*lock(&self.topics))
+			.finish_non_exhaustive()
+	}
+}
+
+impl<Body> std::fmt::Debug for PubSubServer<Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		let state = lock(&self.state);
+		f.debug_struct(core::any::type_name::<Self>())
+			.field("topics", &state.topic_ids)
+			.finish_non_exhaustive()
+	}
+}
+
+/// Parse a topic ID from a response body.
+fn parse_topic_id<Body: crate::Body>(body: &Body) -> Result<u32, Error> {
+	let topic_id = body.as_error().map_err(|e| Error::decode_failed(Box::new(e)))?;
+	topic_id.parse().map_err(|e| Error::decode_failed(Box::new(e)))
+}
+
+/// Lock a mutex, ignoring poisoning.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	match mutex.lock() {
+		Ok(x) => x,
+		Err(e) => e.into_inner(),
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+
+	use crate::{PeerHandle, StreamBody, UnixStreamPeer, UnixStreamTransport};
+
+	/// Connect a new client to the server, and handle the messages of the client in a background task.
+	fn connect(server: &PubSubServer<StreamBody>) -> PeerHandle<StreamBody> {
+		let (client, connection) = tokio::net::UnixStream::pair().unwrap();
+		let (mut read, write) = UnixStreamPeer::spawn(UnixStreamTransport::new(connection, Default::default())).split();
+		let server = server.clone();
+		tokio::spawn(async move {
+			while let Ok(message) = read.recv_message().await {
+				let_assert!(Ok(None) = server.handle_message(&write, message).await);
+			}
+		});
+		UnixStreamPeer::spawn(UnixStreamTransport::new(client, Default::default()))
+	}
+
+	/// Receive the next published message and return the topic and payload.
+	async fn recv_published(client: &PubSubClient<StreamBody>, peer: &mut crate::PeerReadHandle<StreamBody>) -> (Option<String>, Vec<u8>) {
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = peer.recv_message().await);
+		(client.topic_of(&message), message.body.data.to_vec())
+	}
+
+	#[tokio::test]
+	async fn publish_to_subscribers_only() {
+		let server = PubSubServer::new();
+		let (mut read_a, write_a) = connect(&server).split();
+		let (mut read_b, write_b) = connect(&server).split();
+		let client_a = PubSubClient::new(write_a);
+		let client_b = PubSubClient::new(write_b);
+
+		assert!(let Ok(()) = client_a.subscribe("arm/state").await);
+		assert!(let Ok(()) = client_b.subscribe("arm/state").await);
+		assert!(let Ok(()) = client_b.subscribe("camera/state").await);
+		assert!(server.subscriber_count("arm/state") == 2);
+		assert!(server.subscriber_count("camera/state") == 1);
+
+		// Client A publishes to a topic it is not subscribed to.
+		assert!(let Ok(()) = client_a.publish("camera/state", &b"recording"[..]).await);
+		assert!(recv_published(&client_b, &mut read_b).await == (Some("camera/state".into()), b"recording".to_vec()));
+
+		// The server publishes to a topic both clients are subscribed to.
+		assert!(server.publish("arm/state", &StreamBody::from(&b"moving"[..])).await == 2);
+		assert!(recv_published(&client_a, &mut read_a).await == (Some("arm/state".into()), b"moving".to_vec()));
+		assert!(recv_published(&client_b, &mut read_b).await == (Some("arm/state".into()), b"moving".to_vec()));
+
+		// After unsubscribing, client A only receives messages for the remaining topics.
+		assert!(let Ok(()) = client_a.unsubscribe("arm/state").await);
+		assert!(server.publish("arm/state", &StreamBody::from(&b"idle"[..])).await == 1);
+		assert!(server.publish("unknown", &StreamBody::from(&b"ignored"[..])).await == 0);
+		assert!(recv_published(&client_b, &mut read_b).await == (Some("arm/state".into()), b"idle".to_vec()));
+		assert!(server.subscriber_count("arm/state") == 1);
+	}
+
+	#[tokio::test]
+	async fn only_subscriptions_create_topics() {
+		let server = PubSubServer::new().with_max_topics(Some(1));
+		let (_read, write) = connect(&server).split();
+		let client = PubSubClient::new(write);
+
+		// Unknown topics are rejected without creating them.
+		let_assert!(Err(e) = client.publish("arm/state", &b"moving"[..]).await);
+		assert!(let Some("unknown topic: arm/state") = e.as_remote_error());
+		let_assert!(Err(e) = client.unsubscribe("arm/state").await);
+		assert!(let Some("unknown topic: arm/state") = e.as_remote_error());
+		assert!(format!("{server:?}").contains("topics: {}"));
+
+		// Subscribing creates the topic, up to the maximum number of topics.
+		assert!(let Ok(()) = client.subscribe("arm/state").await);
+		let_assert!(Err(e) = client.subscribe("camera/state").await);
+		assert!(let Some("too many topics: at most 1 topics are allowed") = e.as_remote_error());
+		assert!(server.subscriber_count("camera/state") == 0);
+
+		// Existing topics can still be used.
+		assert!(let Ok(()) = client.publish("arm/state", &b"moving"[..]).await);
+		assert!(let Ok(()) = client.unsubscribe("arm/state").await);
+		assert!(let Ok(()) = client.subscribe("arm/state").await);
+		assert!(server.subscriber_count("arm/state") == 1);
+	}
+
+	#[tokio::test]
+	async fn closed_subscribers_are_removed() {
+		let server = PubSubServer::new();
+		let (read_a, write_a) = connect(&server).split();
+		let client_a = PubSubClient::new(write_a.clone());
+		assert!(let Ok(()) = client_a.subscribe("arm/state").await);
+		assert!(server.subscriber_count("arm/state") == 1);
+
+		drop(client_a);
+		drop(read_a);
+		write_a.close();
+
+		// Wait for the server to notice the closed connection.
+		let mut removed = false;
+		for _ in 0..100 {
+			server.publish("arm/state", &StreamBody::from(&b"moving"[..])).await;
+			if server.subscriber_count("arm/state") == 0 {
+				removed = true;
+				break;
+			}
+			tokio::task::yield_now().await;
+		}
+		assert!(removed);
+	}
+}
@@ -0,0 +1,524 @@
+//! Capture the messages of a peer to a file and replay them later.
+//!
+//! A [`RecordingTransport`] wraps a transport with [`StreamBody`] messages,
+//! and writes every message that it reads or writes to a [`CaptureWriter`].
+//! The resulting capture can be read back with a [`CaptureReader`],
+//! and the messages sent by the recorded peer can be sent again with [`replay()`].
+//! This allows you to reproduce protocol bugs from production deterministically.
+//!
+//! Unlike a [wire trace][crate::transport::trace], a capture contains complete messages instead of raw frames,
+//! so it does not depend on the transport that was used to record it.
+//! File descriptors attached to a message are not part of the capture.
+//!
+//! # File format
+//!
+//! A capture starts with the 8 byte magic value `FZRPCCAP`, followed by the format version as 32 bit little endian integer.
+//! The current version is 1.
+//!
+//! The header is followed by any number of records.
+//! Each record consists of:
+//!
+//! * the time the message was completely read or written,
+//!   as seconds since the Unix epoch in a 64 bit little endian integer,
+//!   followed by the nanoseconds in a 32 bit little endian integer,
+//! * the direction of the message as a single byte: 0 for received and 1 for sent messages,
+//! * the message as serialized by [`Message::to_bytes()`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use fizyr_rpc::recording::{CaptureReader, CaptureWriter, RecordingTransport, ReplayTiming};
+//! # use fizyr_rpc::transport::Transport;
+//! # use fizyr_rpc::{Peer, UnixStreamTransport};
+//! # async fn foo(stream: tokio::net::UnixStream, server: tokio::net::UnixStream) -> Result<(), Box<dyn std::error::Error>> {
+//! // Record all traffic of a peer.
+//! let transport = UnixStreamTransport::new(stream, Default::default());
+//! let transport = RecordingTransport::new(transport, CaptureWriter::create("traffic.cap")?);
+//! let peer = Peer::spawn(transport);
+//!
+//! // Later, send the recorded messages to a server again.
+//! let records = CaptureReader::open("traffic.cap")?.collect::<Result<Vec<_>, _>>()?;
+//! let mut transport = UnixStreamTransport::new(server, Default::default());
+//! let (_read_half, mut write_half) = transport.split();
+//! fizyr_rpc::recording::replay(&mut write_half, records, ReplayTiming::Original).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io::{Read, Write};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use crate::transport::trace::TraceDirection;
+use crate::transport::{Transport, TransportError, TransportReadHalf, TransportWriteHalf};
+use crate::{Error, Message, MessageHeader, StreamBody};
+
+/// The magic value at the start of a capture.
+const MAGIC: &[u8; 8] = b"FZRPCCAP";
+
+/// The version of the capture format.
+const VERSION: u32 = 1;
+
+/// Length of the timestamp and direction at the start of each record.
+const RECORD_HEADER_LEN: usize = 8 + 4 + 1;
+
+/// Writer for captures.
+///
+/// The writer can be cloned cheaply.
+/// All clones write to the same underlying output,
+/// so one writer can be shared by multiple transports.
+#[derive(Clone)]
+pub struct CaptureWriter {
+	/// The output for the capture.
+	output: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+/// Reader for captures.
+///
+/// The reader is an iterator over the records in the capture.
+pub struct CaptureReader<R> {
+	/// The input of the capture.
+	input: R,
+}
+
+/// A single message from a capture.
+#[derive(Debug)]
+pub struct CaptureRecord {
+	/// The time the message was completely read or written.
+	pub timestamp: SystemTime,
+
+	/// The direction of the message.
+	pub direction: TraceDirection,
+
+	/// The message.
+	pub message: Message<StreamBody>,
+}
+
+/// The timing to use when replaying a capture.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ReplayTiming {
+	/// Send all messages as fast as possible.
+	Immediate,
+
+	/// Keep the same time between messages as in the capture.
+	Original,
+}
+
+/// Transport wrapper that records all messages read from and written to the inner transport.
+///
+/// Errors while writing the capture are ignored,
+/// so that recording can never break the communication with a peer.
+pub struct RecordingTransport<T> {
+	/// The wrapped transport.
+	inner: T,
+
+	/// The writer for the capture.
+	capture: CaptureWriter,
+}
+
+/// The read half of a [`RecordingTransport`].
+pub struct RecordingReadHalf<'a, R> {
+	/// The read half of the wrapped transport.
+	inner: R,
+
+	/// The writer for the capture.
+	capture: &'a CaptureWriter,
+}
+
+/// The write half of a [`RecordingTransport`].
+pub struct RecordingWriteHalf<'a, W> {
+	/// The write half of the wrapped transport.
+	inner: W,
+
+	/// The writer for the capture.
+	capture: &'a CaptureWriter,
+}
+
+impl CaptureWriter {
+	/// Create a new capture writer that writes to the given output.
+	///
+	/// The file header is written immediately.
+	pub fn new(mut output: impl Write + Send + 'static) -> std::io::Result<Self> {
+		output.write_all(MAGIC)?;
+		output.write_all(&VERSION.to_le_bytes())?;
+		output.flush()?;
+		Ok(Self {
+			output: Arc::new(Mutex::new(Box::new(output))),
+		})
+	}
+
+	/// Create a new capture writer that writes to a file.
+	///
+	/// If the file already exists, it is truncated.
+	pub fn create(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+		Self::new(std::fs::File::create(path)?)
+	}
+
+	/// Add a message to the capture with the current time as timestamp.
+	pub fn record(&self, direction: TraceDirection, message: &Message<StreamBody>) -> Result<(), Error> {
+		self.record_parts(direction, &message.header, &message.body)
+	}
+
+	/// Add a message to the capture from its header and body.
+	fn record_parts(&self, direction: TraceDirection, header: &MessageHeader, body: &[u8]) -> Result<(), Error> {
+		let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+		let mut record = Vec::with_capacity(RECORD_HEADER_LEN + crate::transport::frame::FRAMED_HEADER_LEN + body.len());
+		record.extend_from_slice(&timestamp.as_secs().to_le_bytes());
+		record.extend_from_slice(&timestamp.subsec_nanos().to_le_bytes());
+		record.push(match direction {
+			TraceDirection::Received => 0,
+			TraceDirection::Sent => 1,
+		});
+		crate::transport::frame::encode_frame(&mut record, header, body, crate::transport::Endian::LittleEndian)?;
+
+		let mut output = match self.output.lock() {
+			Ok(x) => x,
+			Err(e) => e.into_inner(),
+		};
+		output.write_all(&record).map_err(Error::io_error)?;
+		output.flush().map_err(Error::io_error)
+	}
+}
+
+impl<R: Read> CaptureReader<R> {
+	/// Create a new capture reader that reads from the given input.
+	///
+	/// The file header is read and checked immediately.
+	pub fn new(mut input: R) -> Result<Self, Error> {
+		let mut header = [0u8; 12];
+		input.read_exact(&mut header).map_err(Error::io_error)?;
+		if &header[..8] != MAGIC {
+			return Err(Error::custom("input is not a capture: invalid magic value".into()));
+		}
+		let version = u32::from_le_bytes(header[8..].try_into().unwrap());
+		if version != VERSION {
+			return Err(Error::custom(format!("unsupported capture version: {}", version)));
+		}
+		Ok(Self { input })
+	}
+
+	/// Read the next record from the capture.
+	///
+	/// Returns `Ok(None)` at the end of the capture.
+	pub fn read_record(&mut self) -> Result<Option<CaptureRecord>, Error> {
+		let mut header = [0u8; RECORD_HEADER_LEN + 4];
+		if !read_exact_or_eof(&mut self.input, &mut header).map_err(Error::io_error)? {
+			return Ok(None);
+		}
+		let seconds = u64::from_le_bytes(header[0..8].try_into().unwrap());
+		let nanos = u32::from_le_bytes(header[8..12].try_into().unwrap());
+		if nanos >= 1_000_000_000 {
+			return Err(Error::custom(format!("invalid timestamp in capture record: {} nanoseconds", nanos)));
+		}
+		let direction = match header[12] {
+			0 => TraceDirection::Received,
+			1 => TraceDirection::Sent,
+			x => return Err(Error::custom(format!("invalid direction in capture record: {}", x))),
+		};
+
+		// Read the complete frame and let the frame parser check it.
+		let frame_len = u32::from_le_bytes(header[13..17].try_into().unwrap());
+		let mut frame = vec![0u8; 4 + frame_len as usize];
+		frame[..4].copy_from_slice(&header[13..17]);
+		self.input.read_exact(&mut frame[4..]).map_err(Error::io_error)?;
+
+		Ok(Some(CaptureRecord {
+			timestamp: SystemTime::UNIX_EPOCH + Duration::new(seconds, nanos),
+			direction,
+			message: Message::from_bytes(&frame)?,
+		}))
+	}
+
+	/// Consume the reader to get the input.
+	pub fn into_inner(self) -> R {
+		self.input
+	}
+}
+
+impl CaptureReader<std::io::BufReader<std::fs::File>> {
+	/// Create a new capture reader that reads from a file.
+	pub fn open(path: impl AsRef<std::path::Path>) -> Result<Self, Error> {
+		let file = std::fs::File::open(path).map_err(Error::io_error)?;
+		Self::new(std::io::BufReader::new(file))
+	}
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+	type Item = Result<CaptureRecord, Error>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.read_record().transpose()
+	}
+}
+
+/// Send the messages that were sent by the recorded peer again.
+///
+/// Received messages in the capture are skipped.
+/// The messages are sent unmodified, including their request IDs.
+/// That means the messages should be replayed over a fresh connection that is not used by a [`Peer`][crate::Peer],
+/// or the request IDs may conflict with the requests of the peer.
+///
+/// To process the responses of the remote peer, read them from the read half of the same transport in a concurrent task.
+///
+/// Returns the number of messages sent.
+pub async fn replay<W>(write_half: &mut W, records: impl IntoIterator<Item = CaptureRecord>, timing: ReplayTiming) -> Result<usize, Error>
+where
+	W: TransportWriteHalf<Body = StreamBody> + ?Sized,
+{
+	let start = tokio::time::Instant::now();
+	let mut first_timestamp = None;
+	let mut sent = 0;
+
+	for record in records {
+		if record.direction != TraceDirection::Sent {
+			continue;
+		}
+		if timing == ReplayTiming::Original {
+			let first_timestamp = *first_timestamp.get_or_insert(record.timestamp);
+			let offset = record.timestamp.duration_since(first_timestamp).unwrap_or_default();
+			tokio::time::sleep_until(start + offset).await;
+		}
+		write_half.write_msg(&record.message.header, &record.message.body)
+			.await
+			.map_err(|e| e.into_inner())?;
+		sent += 1;
+	}
+
+	write_half.flush()
+		.await
+		.map_err(|e| e.into_inner())?;
+	Ok(sent)
+}
+
+impl<T> RecordingTransport<T> {
+	/// Wrap a transport to record all messages to a capture.
+	pub fn new(inner: T, capture: CaptureWriter) -> Self {
+		Self { inner, capture }
+	}
+
+	/// Get a shared reference to the wrapped transport.
+	pub fn inner(&self) -> &T {
+		&self.inner
+	}
+
+	/// Get an exclusive reference to the wrapped transport.
+	pub fn inner_mut(&mut self) -> &mut T {
+		&mut self.inner
+	}
+
+	/// Consume the wrapper to get the wrapped transport.
+	pub fn into_inner(self) -> T {
+		self.inner
+	}
+
+	/// Get the writer for the capture.
+	pub fn capture(&self) -> &CaptureWriter {
+		&self.capture
+	}
+}
+
+impl<T: Transport<Body = StreamBody>> Transport for RecordingTransport<T> {
+	type Body = StreamBody;
+	type Info = T::Info;
+	type Config = T::Config;
+	type ReadHalf<'a> = RecordingReadHalf<'a, T::ReadHalf<'a>>;
+	type WriteHalf<'a> = RecordingWriteHalf<'a, T::WriteHalf<'a>>;
+
+	fn split(&mut self) -> (Self::ReadHalf<'_>, Self::WriteHalf<'_>) {
+		let (read_half, write_half) = self.inner.split();
+		let read_half = RecordingReadHalf {
+			inner: read_half,
+			capture: &self.capture,
+		};
+		let write_half = RecordingWriteHalf {
+			inner: write_half,
+			capture: &self.capture,
+		};
+		(read_half, write_half)
+	}
+
+	fn info(&self) -> std::io::Result<Self::Info> {
+		self.inner.info()
+	}
+
+	fn describe_remote(&self) -> Option<String> {
+		self.inner.describe_remote()
+	}
+}
+
+impl<R> TransportReadHalf for RecordingReadHalf<'_, R>
+where
+	R: TransportReadHalf<Body = StreamBody>,
+{
+	type Body = StreamBody;
+
+	fn poll_read_msg(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<Message<Self::Body>, TransportError>> {
+		let this = self.get_mut();
+		let message = ready!(Pin::new(&mut this.inner).poll_read_msg(context))?;
+		let _: Result<_, _> = this.capture.record(TraceDirection::Received, &message);
+		Poll::Ready(Ok(message))
+	}
+}
+
+impl<W> TransportWriteHalf for RecordingWriteHalf<'_, W>
+where
+	W: TransportWriteHalf<Body = StreamBody>,
+{
+	type Body = StreamBody;
+
+	fn poll_write_msg(self: Pin<&mut Self>, context: &mut Context, header: &MessageHeader, body: &Self::Body) -> Poll<Result<(), TransportError>> {
+		let this = self.get_mut();
+		ready!(Pin::new(&mut this.inner).poll_write_msg(context, header, body))?;
+		let _: Result<_, _> = this.capture.record_parts(TraceDirection::Sent, header, body);
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_write_msgs(self: Pin<&mut Self>, context: &mut Context, messages: &[Message<Self::Body>], written: &mut usize) -> Poll<Result<(), TransportError>> {
+		let this = self.get_mut();
+		let before = *written;
+		let result = Pin::new(&mut this.inner).poll_write_msgs(context, messages, written);
+		// Record the messages that were written, even if the batch is not complete yet.
+		for message in &messages[before..*written] {
+			let _: Result<_, _> = this.capture.record(TraceDirection::Sent, message);
+		}
+		result
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), TransportError>> {
+		Pin::new(&mut self.get_mut().inner).poll_flush(context)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), TransportError>> {
+		Pin::new(&mut self.get_mut().inner).poll_shutdown(context)
+	}
+}
+
+impl std::fmt::Debug for CaptureWriter {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct(core::any::type_name::<Self>())
+			.finish_non_exhaustive()
+	}
+}
+
+impl<R> std::fmt::Debug for CaptureReader<R> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct(core::any::type_name::<Self>())
+			.finish_non_exhaustive()
+	}
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for RecordingTransport<T> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct(core::any::type_name::<Self>())
+			.field("inner", &self.inner)
+			.finish_non_exhaustive()
+	}
+}
+
+/// Fill a buffer completely, or return `Ok(false)` if the input ends before the first byte.
+fn read_exact_or_eof(input: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<bool> {
+	let mut filled = 0;
+	while filled < buffer.len() {
+		match input.read(&mut buffer[filled..]) {
+			Ok(0) if filled == 0 => return Ok(false),
+			Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+			Ok(n) => filled += n,
+			Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+
+	use crate::{ReceivedMessage, UnixStreamPeer, UnixStreamTransport};
+
+	/// Capture output that can be inspected by the test.
+	#[derive(Clone, Default)]
+	struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+	impl Write for SharedBuffer {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			self.0.lock().unwrap().write(buf)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn write_and_read_capture() {
+		let output = SharedBuffer::default();
+		let_assert!(Ok(writer) = CaptureWriter::new(output.clone()));
+		let_assert!(Ok(()) = writer.record(TraceDirection::Sent, &Message::request(1, 10, b"hello".into())));
+		let_assert!(Ok(()) = writer.record(TraceDirection::Received, &Message::response(1, 10, b"world".into())));
+
+		let data = output.0.lock().unwrap().clone();
+		assert!(&data[..8] == b"FZRPCCAP");
+		let_assert!(Ok(reader) = CaptureReader::new(&data[..]));
+		let_assert!(Ok(records) = reader.collect::<Result<Vec<_>, _>>());
+		assert!(records.len() == 2);
+		assert!(records[0].direction == TraceDirection::Sent);
+		assert!(records[0].message.header == MessageHeader::request(1, 10));
+		assert!(records[0].message.body.as_ref() == b"hello");
+		assert!(records[1].direction == TraceDirection::Received);
+		assert!(records[1].message.header == MessageHeader::response(1, 10));
+		assert!(records[1].timestamp >= records[0].timestamp);
+
+		// A truncated record is an error, not the end of the capture.
+		let_assert!(Ok(mut reader) = CaptureReader::new(&data[..data.len() - 1]));
+		assert!(let Ok(Some(_)) = reader.read_record());
+		assert!(let Err(_) = reader.read_record());
+
+		assert!(let Err(_) = CaptureReader::new(&b"FZRPCCAX\x01\x00\x00\x00"[..]));
+		assert!(let Err(_) = CaptureReader::new(&b"FZRPCCAP\x02\x00\x00\x00"[..]));
+	}
+
+	#[tokio::test]
+	async fn record_and_replay() {
+		let output = SharedBuffer::default();
+		let_assert!(Ok(capture) = CaptureWriter::new(output.clone()));
+
+		// Record a request and a response from the side of the client.
+		let_assert!(Ok((client, server)) = tokio::net::UnixStream::pair());
+		let client = crate::Peer::spawn(RecordingTransport::new(UnixStreamTransport::new(client, Default::default()), capture));
+		let mut server = UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default()));
+
+		let_assert!(Ok(mut sent_request) = client.send_request(5, &b"ping"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_request, body)) = server.recv_message().await);
+		assert!(body.as_ref() == b"ping");
+		let_assert!(Ok(()) = received_request.send_response(5, &b"pong"[..]).await);
+		let_assert!(Ok(_response) = sent_request.recv_response().await);
+
+		let data = output.0.lock().unwrap().clone();
+		let_assert!(Ok(reader) = CaptureReader::new(&data[..]));
+		let_assert!(Ok(records) = reader.collect::<Result<Vec<_>, _>>());
+		assert!(records.len() == 2);
+		assert!(records[0].direction == TraceDirection::Sent);
+		assert!(records[1].direction == TraceDirection::Received);
+		assert!(records[1].message.body.as_ref() == b"pong");
+
+		// Replay the request against a new server.
+		let_assert!(Ok((replay_stream, server)) = tokio::net::UnixStream::pair());
+		let mut server = UnixStreamPeer::spawn(UnixStreamTransport::new(server, Default::default()));
+		let mut transport = UnixStreamTransport::new(replay_stream, Default::default());
+		let (mut read_half, mut write_half) = transport.split();
+		let_assert!(Ok(1) = replay(&mut write_half, records, ReplayTiming::Original).await);
+
+		let_assert!(Ok(ReceivedMessage::Request(received_request, body)) = server.recv_message().await);
+		assert!(received_request.service_id() == 5);
+		assert!(body.as_ref() == b"ping");
+		let_assert!(Ok(()) = received_request.send_response(5, &b"pong"[..]).await);
+		let_assert!(Ok(response) = read_half.read_msg().await);
+		assert!(response.header.service_id == 5);
+		assert!(response.body.as_ref() == b"pong");
+	}
+}
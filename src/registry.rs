@@ -0,0 +1,229 @@
+//! Central registry of service IDs.
+//!
+//! When many teams define interfaces that are served on the same connections,
+//! hard-coded service IDs are easily reused by accident.
+//! Instead, you can assign all service IDs in a single registry module with the [`service_registry!`][crate::service_registry] macro.
+//! The macro checks at compile time that each ID is assigned only once.
+//!
+//! Interfaces then refer to the constants of the registry instead of using integer literals:
+//! ```
+//! fizyr_rpc::service_registry! {
+//!     /// Service IDs of the camera interfaces.
+//!     pub mod ids {
+//!         /// Take a picture.
+//!         TAKE_PICTURE = 1,
+//!
+//!         /// Notification that a picture was taken.
+//!         PICTURE_TAKEN = 2,
+//!     }
+//! }
+//!
+//! assert!(ids::TAKE_PICTURE == 1);
+//! assert!(ids::ALL == [("TAKE_PICTURE", 1), ("PICTURE_TAKEN", 2)]);
+//! assert!(ids::name_of(2) == Some("PICTURE_TAKEN"));
+//! ```
+//!
+//! With the `macros` feature, the constants can be used directly as service ID in the [`interface!`][crate::interface] macro,
+//! for example `service ids::TAKE_PICTURE take_picture: () -> Picture`.
+//!
+//! Use [`check_interfaces()`] to verify at runtime that interfaces used in the same program do not share service IDs,
+//! for example in a unit test.
+
+use std::collections::BTreeMap;
+
+use crate::introspection::InterfaceDefinition;
+use crate::Error;
+
+/// Define a module with a registry of service IDs.
+///
+/// Each entry defines a public `i32` constant with the given name and value.
+/// The module also gets a constant `ALL` with the name and value of all entries,
+/// and a function `name_of(i32) -> Option<&'static str>` to look up the name of a service ID.
+///
+/// Compilation fails if two entries have the same value,
+/// or if an entry has a negative value, because negative service IDs are reserved for the protocol.
+///
+/// See the [`registry`][crate::registry] module for an example.
+///
+/// ```compile_fail
+/// fizyr_rpc::service_registry! {
+///     mod ids {
+///         PING = 1,
+///         PONG = 1,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! service_registry {
+	(
+		$(#[$mod_attr:meta])*
+		$vis:vis mod $mod_name:ident {
+			$(
+				$(#[$attr:meta])*
+				$name:ident = $value:expr
+			),* $(,)?
+		}
+	) => {
+		$(#[$mod_attr])*
+		$vis mod $mod_name {
+			$(
+				$(#[$attr])*
+				pub const $name: i32 = $value;
+			)*
+
+			/// The name and value of all service IDs in the registry.
+			pub const ALL: &[(&str, i32)] = &[$((::core::stringify!($name), $name)),*];
+
+			const _: () = $crate::registry::check_registry(ALL);
+
+			/// Get the name of a service ID in the registry.
+			#[allow(dead_code)]
+			pub fn name_of(service_id: i32) -> ::core::option::Option<&'static str> {
+				$crate::registry::name_of(ALL, service_id)
+			}
+		}
+	};
+}
+
+/// Check the entries of a service ID registry.
+///
+/// Used by the [`service_registry!`][crate::service_registry] macro to check the registry at compile time.
+///
+/// # Panics
+/// This function panics if two entries have the same value, or if an entry has a negative value.
+#[doc(hidden)]
+pub const fn check_registry(entries: &[(&str, i32)]) {
+	let mut i = 0;
+	while i < entries.len() {
+		if entries[i].1 < 0 {
+			panic!("negative service IDs are reserved for the protocol");
+		}
+		let mut j = i + 1;
+		while j < entries.len() {
+			if entries[i].1 == entries[j].1 {
+				panic!("duplicate service ID in registry");
+			}
+			j += 1;
+		}
+		i += 1;
+	}
+}
+
+/// Get the name of a service ID in a registry.
+#[doc(hidden)]
+pub fn name_of(entries: &[(&'static str, i32)], service_id: i32) -> Option<&'static str> {
+	entries.iter().find(|(_, value)| *value == service_id).map(|(name, _)| *name)
+}
+
+/// Check that interfaces do not share service IDs.
+///
+/// The services and streams of all interfaces are compared with each other.
+/// Services and streams within a single interface are already checked by the [`interface!`][crate::interface] macro,
+/// and are not compared here.
+/// Update messages are scoped to their service, so they are never compared.
+///
+/// If a service ID is used by more than one interface,
+/// an error is returned that lists all conflicting services and streams.
+pub fn check_interfaces<TypeInfo>(interfaces: &[&InterfaceDefinition<TypeInfo>]) -> Result<(), Error> {
+	// Collect the interface index and a description of all services and streams, by service ID.
+	let mut users: BTreeMap<i32, Vec<(usize, String)>> = BTreeMap::new();
+	for (index, interface) in interfaces.iter().enumerate() {
+		for service in &interface.services {
+			let description = format!("service {}.{}", interface.name, service.name);
+			users.entry(service.service_id).or_default().push((index, description));
+		}
+		for stream in &interface.streams {
+			let description = format!("stream {}.{}", interface.name, stream.name);
+			users.entry(stream.service_id).or_default().push((index, description));
+		}
+	}
+
+	let mut conflicts = Vec::new();
+	for (service_id, users) in &users {
+		if users.iter().any(|(index, _)| *index != users[0].0) {
+			let descriptions: Vec<&str> = users.iter().map(|(_, description)| description.as_str()).collect();
+			conflicts.push(format!("service ID {} is used by {}", service_id, descriptions.join(", ")));
+		}
+	}
+
+	if conflicts.is_empty() {
+		Ok(())
+	} else {
+		Err(Error::custom(conflicts.join("; ")))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+
+	use crate::introspection::{ServiceDefinition, StreamDefinition};
+
+	crate::service_registry! {
+		mod ids {
+			/// The first service.
+			FIRST = 1,
+			SECOND = 2,
+		}
+	}
+
+	fn interface(name: &str, services: &[(&str, i32)], streams: &[(&str, i32)]) -> InterfaceDefinition<()> {
+		InterfaceDefinition {
+			name: name.into(),
+			doc: String::new(),
+			hidden: false,
+			services: services.iter().map(|(name, service_id)| ServiceDefinition {
+				name: (*name).into(),
+				doc: String::new(),
+				hidden: false,
+				service_id: *service_id,
+				request_body: (),
+				response_body: (),
+				error_body: None,
+				request_updates: Vec::new(),
+				response_updates: Vec::new(),
+			}).collect(),
+			streams: streams.iter().map(|(name, service_id)| StreamDefinition {
+				name: (*name).into(),
+				doc: String::new(),
+				hidden: false,
+				ordered: true,
+				service_id: *service_id,
+				body: (),
+			}).collect(),
+		}
+	}
+
+	#[test]
+	fn registry() {
+		assert!(ids::FIRST == 1);
+		assert!(ids::ALL == [("FIRST", 1), ("SECOND", 2)]);
+		assert!(ids::name_of(2) == Some("SECOND"));
+		assert!(ids::name_of(3) == None);
+	}
+
+	#[test]
+	#[should_panic(expected = "duplicate service ID in registry")]
+	fn duplicate_in_registry() {
+		check_registry(&[("A", 1), ("B", 2), ("C", 1)]);
+	}
+
+	#[test]
+	#[should_panic(expected = "negative service IDs are reserved for the protocol")]
+	fn negative_in_registry() {
+		check_registry(&[("A", 1), ("B", -1)]);
+	}
+
+	#[test]
+	fn conflicting_interfaces() {
+		let a = interface("A", &[("ping", 1), ("pong", 2)], &[("event", 3)]);
+		let b = interface("B", &[("other", 4)], &[("other_event", 5)]);
+		assert!(let Ok(()) = check_interfaces(&[&a, &b]));
+
+		let c = interface("C", &[("reset", 3)], &[("tick", 1)]);
+		let_assert!(Err(e) = check_interfaces(&[&a, &b, &c]));
+		assert!(e.to_string() == "service ID 1 is used by service A.ping, stream C.tick; service ID 3 is used by stream A.event, service C.reset");
+	}
+}
@@ -1,6 +1,10 @@
-use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::any::Any;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, ReadBuf};
 
 use crate::error::private::{
 	connection_aborted,
@@ -8,10 +12,12 @@ use crate::error::private::{
 	UnexpectedMessageType,
 };
 use crate::peer::Command;
-use crate::{Error, Message};
+use crate::util::channel::{self, SendError};
+use crate::{Annotations, Error, Message, ResponseReader};
 
 pub(crate) enum RequestHandleCommand<Body> {
 	Close,
+	Shutdown,
 	Message(Message<Body>),
 }
 
@@ -21,8 +27,10 @@ pub(crate) enum RequestHandleCommand<Body> {
 /// and to send update messages to the remote peer.
 pub struct SentRequestHandle<Body> {
 	write_handle: SentRequestWriteHandle<Body>,
-	incoming_rx: mpsc::UnboundedReceiver<RequestHandleCommand<Body>>,
+	incoming_rx: channel::Receiver<RequestHandleCommand<Body>>,
 	peek_buffer: Option<Message<Body>>,
+	shutdown: bool,
+	user_data: UserData,
 }
 
 /// A write handle for a sent request.
@@ -36,8 +44,9 @@ pub struct SentRequestHandle<Body> {
 pub struct SentRequestWriteHandle<Body> {
 	request_id: u32,
 	service_id: i32,
+	trace_id: Option<u64>,
 	closed: Arc<AtomicBool>,
-	command_tx: mpsc::UnboundedSender<Command<Body>>,
+	command_tx: channel::Sender<Command<Body>>,
 }
 
 /// A handle for a received request.
@@ -46,7 +55,12 @@ pub struct SentRequestWriteHandle<Body> {
 /// and to send updates and the response to the remote peer.
 pub struct ReceivedRequestHandle<Body> {
 	write_handle: ReceivedRequestWriteHandle<Body>,
-	incoming_rx: mpsc::UnboundedReceiver<RequestHandleCommand<Body>>,
+	incoming_rx: channel::Receiver<RequestHandleCommand<Body>>,
+	received_at: Instant,
+	type_fingerprint: Option<u64>,
+	picked_up: Option<Arc<AtomicBool>>,
+	annotations: Annotations,
+	user_data: UserData,
 }
 
 /// A write handle for a received request.
@@ -60,8 +74,10 @@ pub struct ReceivedRequestHandle<Body> {
 pub struct ReceivedRequestWriteHandle<Body> {
 	request_id: u32,
 	service_id: i32,
+	deadline: Option<Instant>,
+	trace_id: Option<u64>,
 	closed: Arc<AtomicBool>,
-	command_tx: mpsc::UnboundedSender<Command<Body>>,
+	command_tx: channel::Sender<Command<Body>>,
 }
 
 /// An incoming request or stream message.
@@ -79,12 +95,13 @@ impl<Body> SentRequestHandle<Body> {
 		request_id: u32,
 		service_id: i32,
 		closed: Arc<AtomicBool>,
-		incoming_rx: mpsc::UnboundedReceiver<RequestHandleCommand<Body>>,
-		command_tx: mpsc::UnboundedSender<Command<Body>>,
+		incoming_rx: channel::Receiver<RequestHandleCommand<Body>>,
+		command_tx: channel::Sender<Command<Body>>,
 	) -> Self {
 		let write_handle = SentRequestWriteHandle {
 			request_id,
 			service_id,
+			trace_id: None,
 			closed,
 			command_tx,
 		};
@@ -92,6 +109,8 @@ impl<Body> SentRequestHandle<Body> {
 			write_handle,
 			incoming_rx,
 			peek_buffer: None,
+			shutdown: false,
+			user_data: UserData::default(),
 		}
 	}
 
@@ -105,6 +124,45 @@ impl<Body> SentRequestHandle<Body> {
 		self.write_handle.service_id()
 	}
 
+	/// Get the trace ID that was sent along with the request, if any.
+	///
+	/// The same trace ID is available to the remote peer through [`ReceivedRequestHandle::trace_id()`],
+	/// so it can be used to correlate the logs of both peers.
+	pub fn trace_id(&self) -> Option<u64> {
+		self.write_handle.trace_id()
+	}
+
+	/// Set the trace ID of the request.
+	pub(crate) fn set_trace_id(&mut self, trace_id: Option<u64>) {
+		self.write_handle.trace_id = trace_id;
+	}
+
+	/// Attach application data to the request handle.
+	///
+	/// The handle holds at most one value, so any previously attached data is dropped.
+	/// This can be used to keep state like timers or tracing spans together with the request,
+	/// instead of in a separate table indexed by request ID.
+	pub fn set_user_data<T: Send + Sync + 'static>(&mut self, data: T) {
+		self.user_data.set(data)
+	}
+
+	/// Get a reference to the attached application data, if it has type `T`.
+	pub fn user_data<T: Send + Sync + 'static>(&self) -> Option<&T> {
+		self.user_data.get()
+	}
+
+	/// Get a mutable reference to the attached application data, if it has type `T`.
+	pub fn user_data_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+		self.user_data.get_mut()
+	}
+
+	/// Remove the attached application data and return it, if it has type `T`.
+	///
+	/// If the attached data has a different type, it is left in place and `None` is returned.
+	pub fn take_user_data<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+		self.user_data.take()
+	}
+
 	/// Create a write handle for this request.
 	///
 	/// The write handle can be cloned and used even while this handle is mutably borrowed.
@@ -116,13 +174,28 @@ impl<Body> SentRequestHandle<Body> {
 	///
 	/// This function returns `None` if the final response is received instead of an update message.
 	/// If that happens, the response message can be read using [`Self::recv_response`].
+	///
+	/// # Cancel safety
+	/// This function is cancel safe: it can be used in `tokio::select!` without losing messages.
+	/// A message is only taken from the handle when the future completes.
 	pub async fn recv_update(&mut self) -> Option<Message<Body>> {
-		let message = self.recv_message().await?;
+		std::future::poll_fn(|context| self.poll_recv_update(context)).await
+	}
+
+	/// Try to receive the next update message of the request from the remote peer without blocking.
+	///
+	/// If this function returns [`Poll::Pending`], the current task is scheduled to wake when a message is received.
+	/// See [`Self::recv_update()`] for more details.
+	pub fn poll_recv_update(&mut self, context: &mut Context) -> Poll<Option<Message<Body>>> {
+		let message = match ready!(self.poll_recv_message(context)) {
+			Some(x) => x,
+			None => return Poll::Ready(None),
+		};
 		if message.header.message_type.is_responder_update() {
-			Some(message)
+			Poll::Ready(Some(message))
 		} else {
 			self.peek_buffer = Some(message);
-			None
+			Poll::Ready(None)
 		}
 	}
 
@@ -132,51 +205,107 @@ impl<Body> SentRequestHandle<Body> {
 	/// You can detect this situation using [`Error::is_unexpected_message_type()`].
 	/// Afterwards, the update message can be read using [`Self::recv_update`].
 	/// To ensure that there are no update messages left, keep calling [`Self::recv_update`] untill it returns `Ok(None)`.
+	///
+	/// # Cancel safety
+	/// This function is cancel safe: it can be used in `tokio::select!` without losing messages.
+	/// A message is only taken from the handle when the future completes,
+	/// and an unexpected update message stays available for [`Self::recv_update()`].
 	pub async fn recv_response(&mut self) -> Result<Message<Body>, Error> {
-		let message = self.recv_message()
-			.await
-			.ok_or_else(connection_aborted)?;
+		std::future::poll_fn(|context| self.poll_recv_response(context)).await
+	}
+
+	/// Try to receive the final response of the request from the remote peer without blocking.
+	///
+	/// If this function returns [`Poll::Pending`], the current task is scheduled to wake when a message is received.
+	/// See [`Self::recv_response()`] for more details.
+	pub fn poll_recv_response(&mut self, context: &mut Context) -> Poll<Result<Message<Body>, Error>> {
+		let message = match ready!(self.poll_recv_message(context)) {
+			Some(x) => x,
+			None => return Poll::Ready(Err(self.closed_error())),
+		};
 		let kind = message.header.message_type;
 		if kind.is_response() {
-			Ok(message)
+			Poll::Ready(Ok(message))
 		} else {
 			self.peek_buffer = Some(message);
-			Err(
+			Poll::Ready(Err(
 				InnerError::from(
 					UnexpectedMessageType {
 						value: kind,
 						expected: crate::MessageType::Response,
 					}
 				).into()
-			)
+			))
 		}
 	}
 
-	/// Receive the next message of the request from the remote peer.
+	/// Receive the final response of the request from the remote peer, blocking the current thread.
+	///
+	/// This works on threads without a tokio runtime, as long as the peer runs on a runtime in another thread.
+	/// See [`Self::recv_response()`] for more details.
+	///
+	/// # Panics
+	/// This function panics if it is called from within a tokio runtime, because it would block the thread of the runtime.
+	#[track_caller]
+	pub fn blocking_recv_response(&mut self) -> Result<Message<Body>, Error> {
+		crate::util::block_on(self.recv_response())
+	}
+
+	/// Receive the response as a stream of chunks.
+	///
+	/// The returned [`ResponseReader`] receives the response body in chunks,
+	/// so that very large responses do not need to be held in memory at once.
+	/// The remote peer must send the response with [`ReceivedRequestHandle::send_response_streaming()`]
+	/// or [`ReceivedRequestHandle::send_response_chunk()`].
+	///
+	/// Note that the request handle is consumed,
+	/// so regular update messages can no longer be received.
+	/// Any update message that does not contain a response chunk is reported as an error by the reader.
+	pub fn recv_response_streaming(self) -> ResponseReader<Body> {
+		ResponseReader::new(self)
+	}
+
+	/// Try to receive the next message of the request from the remote peer without blocking.
 	///
 	/// This could be an update message or a response message.
-	async fn recv_message(&mut self) -> Option<Message<Body>> {
+	pub(crate) fn poll_recv_message(&mut self, context: &mut Context) -> Poll<Option<Message<Body>>> {
 		if let Some(message) = self.peek_buffer.take() {
-			Some(message)
+			Poll::Ready(Some(message))
 		} else {
-			match self.incoming_rx.recv().await? {
-				RequestHandleCommand::Message(message) => {
+			match ready!(self.incoming_rx.poll_recv(context)) {
+				None => Poll::Ready(None),
+				Some(RequestHandleCommand::Message(message)) => {
 					// Close the channel when reading a response message.
 					if message.header.message_type.is_response() {
 						self.incoming_rx.close();
 					}
-					Some(message)
+					Poll::Ready(Some(message))
 				},
 				// Close the channel when instructed to do so.
 				// This is sent by the request tracker when unregistering the request.
-				RequestHandleCommand::Close => {
+				Some(RequestHandleCommand::Close) => {
+					self.incoming_rx.close();
+					Poll::Ready(None)
+				},
+				// Remember that the peer was shut down, so we can report the right error.
+				Some(RequestHandleCommand::Shutdown) => {
+					self.shutdown = true;
 					self.incoming_rx.close();
-					None
+					Poll::Ready(None)
 				},
 			}
 		}
 	}
 
+	/// Get the error to report when the request was closed without a response.
+	pub(crate) fn closed_error(&self) -> Error {
+		if self.shutdown {
+			Error::shutdown()
+		} else {
+			connection_aborted()
+		}
+	}
+
 	/// Send an update for the request to the remote peer.
 	pub async fn send_update(&self, service_id: i32, body: impl Into<Body>) -> Result<(), Error> {
 		self.write_handle.send_update(service_id, body).await
@@ -194,6 +323,13 @@ impl<Body> SentRequestWriteHandle<Body> {
 		self.service_id
 	}
 
+	/// Get the trace ID that was sent along with the request, if any.
+	///
+	/// See [`SentRequestHandle::trace_id()`] for more details.
+	pub fn trace_id(&self) -> Option<u64> {
+		self.trace_id
+	}
+
 	/// Send an update for the request to the remote peer.
 	pub async fn send_update(&self, service_id: i32, body: impl Into<Body>) -> Result<(), Error> {
 		use crate::peer::SendRawMessage;
@@ -208,8 +344,8 @@ impl<Body> SentRequestWriteHandle<Body> {
 		let (result_tx, result_rx) = oneshot::channel();
 		let message = Message::requester_update(self.request_id, service_id, body);
 		self.command_tx
-			.send(SendRawMessage { message, result_tx }.into())
-			.map_err(|_| connection_aborted())?;
+			.send(SendRawMessage { message, queued_at: Instant::now(), result_tx }.into())
+			.map_err(SendError::into_error)?;
 		result_rx.await.map_err(|_| connection_aborted())??;
 		Ok(())
 	}
@@ -220,19 +356,27 @@ impl<Body> ReceivedRequestHandle<Body> {
 	pub(crate) fn new(
 		request_id: u32,
 		service_id: i32,
+		received_at: Instant,
 		closed: Arc<AtomicBool>,
-		incoming_rx: mpsc::UnboundedReceiver<RequestHandleCommand<Body>>,
-		command_tx: mpsc::UnboundedSender<Command<Body>>,
+		incoming_rx: channel::Receiver<RequestHandleCommand<Body>>,
+		command_tx: channel::Sender<Command<Body>>,
 	) -> Self {
 		let write_handle = ReceivedRequestWriteHandle {
 			request_id,
 			service_id,
+			deadline: None,
+			trace_id: None,
 			closed,
 			command_tx,
 		};
 		Self {
 			write_handle,
 			incoming_rx,
+			received_at,
+			type_fingerprint: None,
+			picked_up: None,
+			annotations: Annotations::new(),
+			user_data: UserData::default(),
 		}
 	}
 
@@ -246,6 +390,140 @@ impl<Body> ReceivedRequestHandle<Body> {
 		self.write_handle.service_id()
 	}
 
+	/// Get the time when the request message was read from the transport.
+	///
+	/// This can be used to measure how long the request was queued before it was handled.
+	pub fn received_at(&self) -> Instant {
+		self.received_at
+	}
+
+	/// Get the deadline of the request, if the remote peer sent one.
+	///
+	/// After the deadline, the remote peer is no longer interested in the response.
+	/// Pass the deadline on to requests that are sent to handle this request,
+	/// for example with [`PeerWriteHandle::send_request_with_deadline()`][crate::PeerWriteHandle::send_request_with_deadline].
+	pub fn deadline(&self) -> Option<Instant> {
+		self.write_handle.deadline()
+	}
+
+	/// Set the deadline of the request.
+	pub(crate) fn set_deadline(&mut self, deadline: Option<Instant>) {
+		self.write_handle.deadline = deadline;
+	}
+
+	/// Get the trace ID of the request, if the remote peer sent one.
+	///
+	/// The trace ID identifies the request in the logs of both peers.
+	/// Pass it on to requests that are sent to handle this request,
+	/// for example with [`PeerWriteHandle::send_request_with_trace_id()`][crate::PeerWriteHandle::send_request_with_trace_id],
+	/// to correlate the logs of all involved processes.
+	pub fn trace_id(&self) -> Option<u64> {
+		self.write_handle.trace_id()
+	}
+
+	/// Set the trace ID of the request.
+	pub(crate) fn set_trace_id(&mut self, trace_id: Option<u64>) {
+		self.write_handle.trace_id = trace_id;
+	}
+
+	/// Get the type fingerprint of the request, if the remote peer sent one.
+	///
+	/// The fingerprint identifies the request and response body types of the service in the interface definition of the remote peer.
+	/// Generated servers compare it with their own interface definition before decoding the request body,
+	/// to detect peers that were built with a different version of the interface.
+	/// See [`Self::check_type_fingerprint()`].
+	pub fn type_fingerprint(&self) -> Option<u64> {
+		self.type_fingerprint
+	}
+
+	/// Set the type fingerprint of the request.
+	pub(crate) fn set_type_fingerprint(&mut self, type_fingerprint: Option<u64>) {
+		self.type_fingerprint = type_fingerprint;
+	}
+
+	/// Check the type fingerprint of the request against the expected fingerprint.
+	///
+	/// Requests without a type fingerprint are always accepted.
+	/// If the fingerprints differ, this returns an error for which [`Error::is_type_fingerprint_mismatch()`] returns true.
+	pub fn check_type_fingerprint(&self, expected: u64) -> Result<(), Error> {
+		match self.type_fingerprint {
+			Some(received) if received != expected => Err(InnerError::TypeFingerprintMismatch {
+				service_id: self.service_id(),
+				expected,
+				received,
+			}.into()),
+			_ => Ok(()),
+		}
+	}
+
+	/// Get the annotations that were attached to the request by the interceptor of the peer.
+	///
+	/// See [`Interceptor::annotate()`][crate::Interceptor::annotate] for more details.
+	pub fn annotations(&self) -> &Annotations {
+		&self.annotations
+	}
+
+	/// Get mutable access to the annotations of the request.
+	///
+	/// This can be used to pass more information to the next layer that handles the request.
+	pub fn annotations_mut(&mut self) -> &mut Annotations {
+		&mut self.annotations
+	}
+
+	/// Get the application context of the connection the request was received on, if it has type `T`.
+	///
+	/// See [`ConnectionContext`][crate::ConnectionContext] for more details.
+	pub fn context<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+		self.annotations.get::<crate::ConnectionContext>()?.get()
+	}
+
+	/// Set the annotations of the request.
+	pub(crate) fn set_annotations(&mut self, annotations: Annotations) {
+		self.annotations = annotations;
+	}
+
+	/// Attach application data to the request handle.
+	///
+	/// The handle holds at most one value, so any previously attached data is dropped.
+	/// This can be used to keep state like timers or tracing spans together with the request,
+	/// instead of in a separate table indexed by request ID.
+	pub fn set_user_data<T: Send + Sync + 'static>(&mut self, data: T) {
+		self.user_data.set(data)
+	}
+
+	/// Get a reference to the attached application data, if it has type `T`.
+	pub fn user_data<T: Send + Sync + 'static>(&self) -> Option<&T> {
+		self.user_data.get()
+	}
+
+	/// Get a mutable reference to the attached application data, if it has type `T`.
+	pub fn user_data_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+		self.user_data.get_mut()
+	}
+
+	/// Remove the attached application data and return it, if it has type `T`.
+	///
+	/// If the attached data has a different type, it is left in place and `None` is returned.
+	pub fn take_user_data<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+		self.user_data.take()
+	}
+
+	/// Set the flag that is shared with the peer loop to decide if the request was picked up before it expired.
+	pub(crate) fn set_picked_up_flag(&mut self, picked_up: Arc<AtomicBool>) {
+		self.picked_up = Some(picked_up);
+	}
+
+	/// Mark the request as picked up by the application.
+	///
+	/// Returns false if the peer loop already expired the request.
+	/// This must be called only once for each request.
+	pub(crate) fn pick_up(&self) -> bool {
+		match &self.picked_up {
+			Some(picked_up) => !picked_up.swap(true, Ordering::AcqRel),
+			None => true,
+		}
+	}
+
 	/// Create a write handle for this request.
 	///
 	/// The write handle can be cloned and used even while this handle is mutably borrowed.
@@ -254,14 +532,25 @@ impl<Body> ReceivedRequestHandle<Body> {
 	}
 
 	/// Receive the next update message of the request from the remote peer.
+	///
+	/// # Cancel safety
+	/// This function is cancel safe: it can be used in `tokio::select!` without losing update messages.
 	pub async fn recv_update(&mut self) -> Option<Message<Body>> {
-		match self.incoming_rx.recv().await? {
-			RequestHandleCommand::Message(x) => Some(x),
+		std::future::poll_fn(|context| self.poll_recv_update(context)).await
+	}
+
+	/// Try to receive the next update message of the request from the remote peer without blocking.
+	///
+	/// If this function returns [`Poll::Pending`], the current task is scheduled to wake when a message is received.
+	pub fn poll_recv_update(&mut self, context: &mut Context) -> Poll<Option<Message<Body>>> {
+		match ready!(self.incoming_rx.poll_recv(context)) {
+			None => Poll::Ready(None),
+			Some(RequestHandleCommand::Message(x)) => Poll::Ready(Some(x)),
 			// Close the channel when instructed to do so.
 			// This is sent by the request tracker when unregistering the request.
-			RequestHandleCommand::Close => {
+			Some(RequestHandleCommand::Close | RequestHandleCommand::Shutdown) => {
 				self.incoming_rx.close();
-				None
+				Poll::Ready(None)
 			},
 		}
 	}
@@ -276,6 +565,13 @@ impl<Body> ReceivedRequestHandle<Body> {
 		self.write_handle.send_response(service_id, body).await
 	}
 
+	/// Send the final response for the request to the remote peer, blocking the current thread.
+	///
+	/// See [`ReceivedRequestWriteHandle::blocking_send_response()`] for more details.
+	pub fn blocking_send_response(&self, service_id: i32, body: impl Into<Body>) -> Result<(), Error> {
+		self.write_handle.blocking_send_response(service_id, body)
+	}
+
 	/// Send the final response with an error message.
 	pub async fn send_error_response(&self, message: &str) -> Result<(), Error>
 	where
@@ -283,6 +579,62 @@ impl<Body> ReceivedRequestHandle<Body> {
 	{
 		self.write_handle.send_error_response(message).await
 	}
+
+	/// Send the final response with an error code, an error message and an optional encoded payload.
+	///
+	/// See [`ReceivedRequestWriteHandle::send_coded_error_response()`] for more details.
+	pub async fn send_coded_error_response(&self, code: i32, message: &str, payload: Option<&[u8]>) -> Result<(), Error>
+	where
+		Body: crate::Body,
+	{
+		self.write_handle.send_coded_error_response(code, message, payload).await
+	}
+
+	/// Send the final response indicating that the service is temporarily unavailable.
+	///
+	/// The remote peer may retry the request after the `retry_after` duration.
+	/// The duration is sent with millisecond precision.
+	pub async fn send_retry_after(&self, retry_after: Duration, message: &str) -> Result<(), Error>
+	where
+		Body: crate::Body,
+	{
+		self.write_handle.send_retry_after(retry_after, message).await
+	}
+
+	/// Send the final response indicating that the deadline of the request has passed.
+	///
+	/// The remote peer can recognize the response with [`Error::is_deadline_exceeded()`].
+	pub async fn send_deadline_exceeded(&self) -> Result<(), Error>
+	where
+		Body: crate::Body,
+	{
+		self.write_handle.send_deadline_exceeded().await
+	}
+
+	/// Send a chunk of the response body to the remote peer.
+	///
+	/// The chunk is sent as an update message with service ID [`service_id::RESPONSE_CHUNK`][crate::service_id::RESPONSE_CHUNK].
+	/// After all chunks have been sent, you must still send the final response.
+	///
+	/// The remote peer can receive the chunks with [`SentRequestHandle::recv_response_streaming()`].
+	pub async fn send_response_chunk(&self, body: impl Into<Body>) -> Result<(), Error> {
+		self.write_handle.send_response_chunk(body).await
+	}
+
+	/// Send the final response by streaming the body from a reader.
+	///
+	/// The data from the reader is sent in chunks of up to `chunk_size` bytes,
+	/// followed by a final response with service ID `service_id` and an empty body.
+	/// If `chunk_size` is zero, an I/O error of kind [`std::io::ErrorKind::InvalidInput`] is returned without sending anything.
+	///
+	/// The remote peer can receive the chunks with [`SentRequestHandle::recv_response_streaming()`].
+	pub async fn send_response_streaming<R>(&self, service_id: i32, reader: R, chunk_size: usize) -> Result<(), Error>
+	where
+		R: AsyncRead + Unpin,
+		Body: From<Vec<u8>>,
+	{
+		self.write_handle.send_response_streaming(service_id, reader, chunk_size).await
+	}
 }
 
 impl<Body> ReceivedRequestWriteHandle<Body> {
@@ -296,6 +648,20 @@ impl<Body> ReceivedRequestWriteHandle<Body> {
 		self.service_id
 	}
 
+	/// Get the deadline of the request, if the remote peer sent one.
+	///
+	/// See [`ReceivedRequestHandle::deadline()`] for more details.
+	pub fn deadline(&self) -> Option<Instant> {
+		self.deadline
+	}
+
+	/// Get the trace ID of the request, if the remote peer sent one.
+	///
+	/// See [`ReceivedRequestHandle::trace_id()`] for more details.
+	pub fn trace_id(&self) -> Option<u64> {
+		self.trace_id
+	}
+
 	/// Send an update for the request to the remote peer.
 	pub async fn send_update(&self, service_id: i32, body: impl Into<Body>) -> Result<(), Error> {
 		let body = body.into();
@@ -308,6 +674,18 @@ impl<Body> ReceivedRequestWriteHandle<Body> {
 		self.send_raw_message(Message::response(self.request_id, service_id, body)).await
 	}
 
+	/// Send the final response for the request to the remote peer, blocking the current thread.
+	///
+	/// This can be used to respond from non-async code, like callbacks from C libraries.
+	/// See [`PeerWriteHandle::blocking_send_request()`][crate::PeerWriteHandle::blocking_send_request] for the restrictions.
+	///
+	/// # Panics
+	/// This function panics if it is called from within a tokio runtime, because it would block the thread of the runtime.
+	#[track_caller]
+	pub fn blocking_send_response(&self, service_id: i32, body: impl Into<Body>) -> Result<(), Error> {
+		crate::util::block_on(self.send_response(service_id, body))
+	}
+
 	/// Send the final response with an error message.
 	pub async fn send_error_response(&self, message: &str) -> Result<(), Error>
 	where
@@ -316,6 +694,90 @@ impl<Body> ReceivedRequestWriteHandle<Body> {
 		self.send_raw_message(Message::error_response(self.request_id, message)).await
 	}
 
+	/// Send the final response with an error code, an error message and an optional encoded payload.
+	///
+	/// The remote peer receives a [coded remote error][Error::coded_remote_error],
+	/// so it can branch on the error code instead of parsing the message.
+	/// The payload is typically a message body encoded with the format of the interface.
+	pub async fn send_coded_error_response(&self, code: i32, message: &str, payload: Option<&[u8]>) -> Result<(), Error>
+	where
+		Body: crate::Body,
+	{
+		self.send_raw_message(Message::coded_error_response(self.request_id, code, message, payload)).await
+	}
+
+	/// Send the final response indicating that the service is temporarily unavailable.
+	///
+	/// The remote peer may retry the request after the `retry_after` duration.
+	/// The duration is sent with millisecond precision.
+	pub async fn send_retry_after(&self, retry_after: Duration, message: &str) -> Result<(), Error>
+	where
+		Body: crate::Body,
+	{
+		self.send_raw_message(Message::retry_after_response(self.request_id, retry_after, message)).await
+	}
+
+	/// Send the final response indicating that the deadline of the request has passed.
+	///
+	/// The remote peer can recognize the response with [`Error::is_deadline_exceeded()`].
+	pub async fn send_deadline_exceeded(&self) -> Result<(), Error>
+	where
+		Body: crate::Body,
+	{
+		self.send_error_response(crate::error::private::DEADLINE_EXCEEDED_MESSAGE).await
+	}
+
+	/// Send a chunk of the response body to the remote peer.
+	///
+	/// The chunk is sent as an update message with service ID [`service_id::RESPONSE_CHUNK`][crate::service_id::RESPONSE_CHUNK].
+	/// After all chunks have been sent, you must still send the final response.
+	///
+	/// The remote peer can receive the chunks with [`SentRequestHandle::recv_response_streaming()`].
+	pub async fn send_response_chunk(&self, body: impl Into<Body>) -> Result<(), Error> {
+		self.send_update(crate::service_id::RESPONSE_CHUNK, body).await
+	}
+
+	/// Send the final response by streaming the body from a reader.
+	///
+	/// The data from the reader is sent in chunks of up to `chunk_size` bytes,
+	/// followed by a final response with service ID `service_id` and an empty body.
+	/// If `chunk_size` is zero, an I/O error of kind [`std::io::ErrorKind::InvalidInput`] is returned without sending anything.
+	///
+	/// The remote peer can receive the chunks with [`SentRequestHandle::recv_response_streaming()`].
+	pub async fn send_response_streaming<R>(&self, service_id: i32, mut reader: R, chunk_size: usize) -> Result<(), Error>
+	where
+		R: AsyncRead + Unpin,
+		Body: From<Vec<u8>>,
+	{
+		if chunk_size == 0 {
+			return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "chunk size must be larger than zero").into());
+		}
+		loop {
+			// Fill a complete chunk, unless the reader reaches the end of the data.
+			let mut chunk = vec![0; chunk_size];
+			let mut filled = 0;
+			while filled < chunk_size {
+				let read = std::future::poll_fn(|context| {
+					let mut buf = ReadBuf::new(&mut chunk[filled..]);
+					ready!(Pin::new(&mut reader).poll_read(context, &mut buf))?;
+					Poll::Ready(Ok::<_, std::io::Error>(buf.filled().len()))
+				}).await?;
+				if read == 0 {
+					break;
+				}
+				filled += read;
+			}
+
+			if filled > 0 {
+				chunk.truncate(filled);
+				self.send_response_chunk(chunk).await?;
+			}
+			if filled < chunk_size {
+				return self.send_response(service_id, Vec::new()).await;
+			}
+		}
+	}
+
 	/// Send a raw message.
 	async fn send_raw_message(&self, message: Message<Body>) -> Result<(), Error> {
 		use crate::peer::SendRawMessage;
@@ -328,13 +790,43 @@ impl<Body> ReceivedRequestWriteHandle<Body> {
 
 		let (result_tx, result_rx) = oneshot::channel();
 		self.command_tx
-			.send(SendRawMessage { message, result_tx }.into())
-			.map_err(|_| connection_aborted())?;
+			.send(SendRawMessage { message, queued_at: Instant::now(), result_tx }.into())
+			.map_err(SendError::into_error)?;
 		result_rx.await.map_err(|_| connection_aborted())??;
 		Ok(())
 	}
 }
 
+/// Application data attached to a request handle.
+#[derive(Default)]
+struct UserData {
+	value: Option<Box<dyn Any + Send + Sync>>,
+}
+
+impl UserData {
+	fn set<T: Send + Sync + 'static>(&mut self, value: T) {
+		self.value = Some(Box::new(value));
+	}
+
+	fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+		self.value.as_ref()?.downcast_ref()
+	}
+
+	fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+		self.value.as_mut()?.downcast_mut()
+	}
+
+	fn take<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+		match self.value.take()?.downcast() {
+			Ok(value) => Some(*value),
+			Err(value) => {
+				self.value = Some(value);
+				None
+			},
+		}
+	}
+}
+
 impl<Body> std::fmt::Debug for SentRequestHandle<Body> {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
 		f.debug_struct("SentRequestHandle")
@@ -385,6 +877,7 @@ impl<Body> Clone for SentRequestWriteHandle<Body> {
 		Self {
 			request_id: self.request_id,
 			service_id: self.service_id,
+			trace_id: self.trace_id,
 			closed: self.closed.clone(),
 			command_tx: self.command_tx.clone(),
 		}
@@ -396,6 +889,8 @@ impl<Body> Clone for ReceivedRequestWriteHandle<Body> {
 		Self {
 			request_id: self.request_id,
 			service_id: self.service_id,
+			deadline: self.deadline,
+			trace_id: self.trace_id,
 			closed: self.closed.clone(),
 			command_tx: self.command_tx.clone(),
 		}
@@ -452,4 +947,67 @@ mod test {
 		assert!(let Ok(()) = task_a.await);
 		assert!(let Ok(()) = task_b.await);
 	}
+
+	/// Test that receiving updates and the response does not lose messages when the futures are cancelled.
+	#[tokio::test]
+	async fn recv_is_cancel_safe() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let handle_a = Peer::spawn(UnixStreamTransport::new(peer_a, Default::default()));
+		let mut handle_b = Peer::spawn(UnixStreamTransport::new(peer_b, Default::default()));
+
+		let_assert!(Ok(mut sent_request) = handle_a.send_request(1, &[][..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = handle_b.recv_message().await);
+		let responder = tokio::spawn(async move {
+			for i in 0..20u8 {
+				assert!(let Ok(()) = received_request.send_update(2, vec![i]).await);
+				tokio::time::sleep(Duration::from_micros(200)).await;
+			}
+			assert!(let Ok(()) = received_request.send_response(3, vec![]).await);
+		});
+
+		// Keep cancelling the futures with a short timer.
+		let mut updates = Vec::new();
+		loop {
+			tokio::select! {
+				update = sent_request.recv_update() => match update {
+					Some(update) => updates.push(update.body[0]),
+					None => break,
+				},
+				_ = tokio::time::sleep(Duration::from_micros(50)) => (),
+			}
+		}
+		assert!(updates == (0..20).collect::<Vec<_>>());
+
+		// The response that ended the updates is still available.
+		let response = loop {
+			tokio::select! {
+				response = sent_request.recv_response() => break response,
+				_ = tokio::time::sleep(Duration::from_micros(50)) => (),
+			}
+		};
+		let_assert!(Ok(response) = response);
+		assert!(response.header.service_id == 3);
+		assert!(let Ok(()) = responder.await);
+	}
+
+	#[test]
+	fn user_data() {
+		let mut user_data = UserData::default();
+		assert!(user_data.get::<u32>() == None);
+
+		user_data.set(5u32);
+		assert!(user_data.get::<u32>() == Some(&5));
+		assert!(user_data.get::<u64>() == None);
+		let_assert!(Some(value) = user_data.get_mut::<u32>());
+		*value += 1;
+
+		// Taking the data with the wrong type leaves it in place.
+		assert!(user_data.take::<u64>() == None);
+		assert!(user_data.take::<u32>() == Some(6));
+		assert!(user_data.take::<u32>() == None);
+
+		user_data.set(String::from("hello"));
+		user_data.set(String::from("world"));
+		assert!(user_data.get::<String>().map(String::as_str) == Some("world"));
+	}
 }
@@ -1,9 +1,10 @@
 use std::collections::BTreeMap;
+use std::time::Instant;
 use std::collections::btree_map::Entry;
-use tokio::sync::mpsc;
 use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use tokio::sync::oneshot;
 
-use crate::error::private::InnerError;
+use crate::error::private::{InnerError, UnexpectedMessageType};
 use crate::peer::Command;
 use crate::{
 	Error,
@@ -14,10 +15,73 @@ use crate::{
 	SentRequestHandle,
 };
 use crate::request::RequestHandleCommand;
+use crate::util::channel::{self, SendError};
 
 struct TrackedRequest<Body> {
-	incoming_tx: mpsc::UnboundedSender<RequestHandleCommand<Body>>,
+	incoming_tx: channel::Sender<RequestHandleCommand<Body>>,
 	closed: Arc<AtomicBool>,
+
+	/// Span that covers the lifetime of the request.
+	#[cfg(feature = "tracing")]
+	span: tracing::Span,
+}
+
+/// A sent request that only waits for the response.
+///
+/// These requests have no [`SentRequestHandle`], so they need no channel for update messages.
+struct OneshotRequest<Body> {
+	response_tx: oneshot::Sender<Result<Message<Body>, Error>>,
+
+	/// Span that covers the lifetime of the request.
+	#[cfg(feature = "tracing")]
+	span: tracing::Span,
+}
+
+/// A tracked sent request.
+enum SentRequest<Body> {
+	/// A request with a [`SentRequestHandle`] that can send and receive updates.
+	Handle(TrackedRequest<Body>),
+
+	/// A request that only waits for the response.
+	Oneshot(OneshotRequest<Body>),
+}
+
+impl<Body> SentRequest<Body> {
+	/// Check if the receiving end of the request was dropped.
+	fn is_orphaned(&self) -> bool {
+		match self {
+			Self::Handle(request) => request.incoming_tx.is_closed(),
+			Self::Oneshot(request) => request.response_tx.is_closed(),
+		}
+	}
+}
+
+/// Result of an audit of the open requests of a peer.
+///
+/// Use [`PeerHandle::audit()`][crate::PeerHandle::audit] to audit a peer.
+/// In debug builds, the peer also audits itself periodically and logs a warning for orphaned requests if the `tracing` feature is enabled.
+///
+/// An orphaned request is an open request whose handle has been dropped.
+/// These are not always bugs, but a growing number of orphaned requests indicates a leak.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct TrackerAudit {
+	/// The IDs of open sent requests whose [`SentRequestHandle`] has been dropped.
+	///
+	/// These requests are removed when the response arrives, so they only leak if the remote peer never responds.
+	pub orphaned_sent_requests: Vec<u32>,
+
+	/// The IDs of open received requests whose [`ReceivedRequestHandle`] has been dropped without sending a response.
+	///
+	/// The remote peer is still waiting for a response to these requests, but it will never be sent.
+	pub orphaned_received_requests: Vec<u32>,
+}
+
+impl TrackerAudit {
+	/// Check if the audit found no orphaned requests.
+	pub fn is_clean(&self) -> bool {
+		self.orphaned_sent_requests.is_empty() && self.orphaned_received_requests.is_empty()
+	}
 }
 
 /// Tracker that manages open requests.
@@ -31,13 +95,21 @@ pub struct RequestTracker<Body> {
 	/// Sender of the channel for command messages.
 	///
 	/// It is kept around here to prevent the channel from closing and so that we can clone it.
-	command_tx: mpsc::UnboundedSender<Command<Body>>,
+	command_tx: channel::Sender<Command<Body>>,
+
+	/// The maximum number of queued update messages for each request.
+	///
+	/// Only used with the `strict-memory` feature.
+	request_updates_capacity: usize,
 
 	/// Map of channels for incoming messages for sent requests.
-	sent_requests: BTreeMap<u32, TrackedRequest<Body>>,
+	sent_requests: BTreeMap<u32, SentRequest<Body>>,
 
 	/// Map of channels for incoming messages for received requests.
 	received_requests: BTreeMap<u32, TrackedRequest<Body>>,
+
+	/// The maximum number of open received requests.
+	max_open_received_requests: Option<usize>,
 }
 
 impl<Body> RequestTracker<Body> {
@@ -45,35 +117,104 @@ impl<Body> RequestTracker<Body> {
 	///
 	/// The `command_tx` channel is used for command messages.
 	/// All messages on the channel should be sent to the remote peer by a task with the receiving end of the channel.
-	pub fn new(command_tx: mpsc::UnboundedSender<Command<Body>>) -> Self {
+	///
+	/// With the `strict-memory` feature, each request can queue at most `request_updates_capacity` update messages.
+	pub fn new(command_tx: channel::Sender<Command<Body>>, request_updates_capacity: usize) -> Self {
 		Self {
 			next_sent_request_id: 0,
 			command_tx,
+			request_updates_capacity,
 			sent_requests: BTreeMap::new(),
 			received_requests: BTreeMap::new(),
+			max_open_received_requests: None,
+		}
+	}
+
+	/// Set the maximum number of open received requests.
+	///
+	/// If the limit is reached, [`Self::register_received_request()`] rejects new requests until an open request is removed.
+	/// Use `None` to allow an unlimited number of open received requests (the default).
+	pub fn set_max_open_received_requests(&mut self, limit: Option<usize>) {
+		self.max_open_received_requests = limit;
+	}
+
+	/// Get the number of open sent requests.
+	pub fn sent_requests_len(&self) -> usize {
+		self.sent_requests.len()
+	}
+
+	/// Get the number of open received requests.
+	pub fn received_requests_len(&self) -> usize {
+		self.received_requests.len()
+	}
+
+	/// Find open requests whose handle has been dropped.
+	pub fn audit(&self) -> TrackerAudit {
+		TrackerAudit {
+			orphaned_sent_requests: self.sent_requests.iter()
+				.filter(|(_, request)| request.is_orphaned())
+				.map(|(&request_id, _)| request_id)
+				.collect(),
+			orphaned_received_requests: self.received_requests.iter()
+				.filter(|(_, request)| request.incoming_tx.is_closed())
+				.map(|(&request_id, _)| request_id)
+				.collect(),
 		}
 	}
 
 	/// Allocate a request ID and register a new sent request.
 	pub fn allocate_sent_request(&mut self, service_id: i32) -> Result<SentRequestHandle<Body>, Error> {
+		let request_id = self.free_sent_request_id(service_id)?;
+		let (incoming_tx, incoming_rx) = request_channel(self.request_updates_capacity);
+		let closed = Arc::new(AtomicBool::new(false));
+		let tracked_request = TrackedRequest {
+			incoming_tx,
+			closed: closed.clone(),
+			#[cfg(feature = "tracing")]
+			span: tracing::debug_span!("sent_request", request_id, service_id),
+		};
+		trace_event!(debug, parent: &tracked_request.span, "sent request opened");
+		self.sent_requests.insert(request_id, SentRequest::Handle(tracked_request));
+		Ok(SentRequestHandle::new(request_id, service_id, closed, incoming_rx, self.command_tx.clone()))
+	}
+
+	/// Allocate a request ID and register a new sent request that only waits for the response.
+	///
+	/// The response or an error is delivered through `response_tx`.
+	/// If no request ID is available, the error is returned together with `response_tx`.
+	#[allow(clippy::type_complexity)]
+	pub fn allocate_oneshot_request(
+		&mut self,
+		service_id: i32,
+		response_tx: oneshot::Sender<Result<Message<Body>, Error>>,
+	) -> Result<u32, (Error, oneshot::Sender<Result<Message<Body>, Error>>)> {
+		let request_id = match self.free_sent_request_id(service_id) {
+			Ok(x) => x,
+			Err(e) => return Err((e, response_tx)),
+		};
+		let oneshot_request = OneshotRequest {
+			response_tx,
+			#[cfg(feature = "tracing")]
+			span: tracing::debug_span!("sent_request", request_id, service_id),
+		};
+		trace_event!(debug, parent: &oneshot_request.span, "oneshot request opened");
+		self.sent_requests.insert(request_id, SentRequest::Oneshot(oneshot_request));
+		Ok(request_id)
+	}
+
+	/// Find a request ID that is not used by an open sent request.
+	fn free_sent_request_id(&mut self, _service_id: i32) -> Result<u32, Error> {
 		// Try to find a free ID a bunch of times.
 		for _ in 0..100 {
 			let request_id = self.next_sent_request_id;
 			self.next_sent_request_id = self.next_sent_request_id.wrapping_add(1);
-
-			if let Entry::Vacant(entry) = self.sent_requests.entry(request_id) {
-				let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
-				let closed = Arc::new(AtomicBool::new(false));
-				let tracked_request = TrackedRequest {
-					incoming_tx,
-					closed: closed.clone(),
-				};
-				entry.insert(tracked_request);
-				return Ok(SentRequestHandle::new(request_id, service_id, closed, incoming_rx, self.command_tx.clone()));
+			if !self.sent_requests.contains_key(&request_id) {
+				return Ok(request_id);
 			}
 		}
 
 		// But eventually give up.
+		trace_event!(warn, service_id = _service_id, "no free request ID found for sent request");
 		Err(InnerError::NoFreeRequestIdFound.into())
 	}
 
@@ -83,7 +224,14 @@ impl<Body> RequestTracker<Body> {
 	/// Note that sent requests are also removed internally when they receive a response,
 	/// or when they would receive a message but the [`SentRequestHandle`] was dropped.
 	pub fn remove_sent_request(&mut self, request_id: u32) -> Result<(), Error> {
-		let tracked_request = self.sent_requests.remove(&request_id).ok_or(InnerError::UnknownRequestId { request_id })?;
+		let tracked_request = match self.sent_requests.remove(&request_id).ok_or(InnerError::UnknownRequestId { request_id })? {
+			SentRequest::Handle(x) => x,
+			SentRequest::Oneshot(_oneshot_request) => {
+				trace_event!(debug, parent: &_oneshot_request.span, "oneshot request closed");
+				return Ok(());
+			},
+		};
+		trace_event!(debug, parent: &tracked_request.span, "sent request closed");
 
 		// Set the `closed` flag so that existing request write handles will refuse to send more messages.
 		tracked_request.closed.store(true, Ordering::Release);
@@ -93,17 +241,41 @@ impl<Body> RequestTracker<Body> {
 		Ok(())
 	}
 
-	/// Register a new sent request.
+	/// Remove a sent request that only waits for the response, and deliver an error instead of the response.
+	///
+	/// This should be called when sending the request failed.
+	pub fn fail_oneshot_request(&mut self, request_id: u32, error: Error) {
+		match self.sent_requests.remove(&request_id) {
+			Some(SentRequest::Oneshot(oneshot_request)) => {
+				trace_event!(debug, parent: &oneshot_request.span, error = %error, "oneshot request failed");
+				let _: Result<_, _> = oneshot_request.response_tx.send(Err(error));
+			},
+			Some(request) => {
+				self.sent_requests.insert(request_id, request);
+			},
+			None => (),
+		}
+	}
+
+	/// Register a new received request.
 	///
-	/// Returns an error if the request ID is already in use.
+	/// The `received_at` parameter is the time the request message was read from the transport.
+	///
+	/// Returns an error if the request ID is already in use,
+	/// or if the maximum number of open received requests is reached.
 	pub fn register_received_request(
 		&mut self,
 		request_id: u32,
 		service_id: i32,
 		body: Body,
+		received_at: Instant,
 	) -> Result<(ReceivedRequestHandle<Body>, Body), Error> {
-		match self.received_requests.entry(request_id) {
-			Entry::Occupied(_entry) => {
+		// The limit on open requests, if it has been reached.
+		let limit_reached = self.max_open_received_requests.filter(|&limit| self.received_requests.len() >= limit);
+
+		match (self.received_requests.entry(request_id), limit_reached) {
+			(Entry::Occupied(_entry), _) => {
+				trace_event!(debug, request_id, service_id, "received request with duplicate request ID");
 				// TODO: Check if the channel is closed so we don't error out unneccesarily.
 				// Requires https://github.com/tokio-rs/tokio/pull/2726
 				// if !entry.get().is_closed() {
@@ -118,16 +290,26 @@ impl<Body> RequestTracker<Body> {
 				// }
 			},
 
+			// The request ID is available, but there may be too many open requests already.
+			(Entry::Vacant(_entry), Some(limit)) => {
+				trace_event!(debug, request_id, service_id, "too many open received requests, rejecting request");
+				Err(InnerError::TooManyOpenRequests { limit }.into())
+			},
+
 			// The request ID is available.
-			Entry::Vacant(entry) => {
-				let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+			(Entry::Vacant(entry), None) => {
+				let (incoming_tx, incoming_rx) = request_channel(self.request_updates_capacity);
 				let closed = Arc::new(AtomicBool::new(false));
 				let tracked_request = TrackedRequest {
 					incoming_tx,
 					closed: closed.clone(),
+					#[cfg(feature = "tracing")]
+					span: tracing::debug_span!("received_request", request_id, service_id),
 				};
+				trace_event!(debug, parent: &tracked_request.span, "received request opened");
 				entry.insert(tracked_request);
-				Ok((ReceivedRequestHandle::new(request_id, service_id, closed, incoming_rx, self.command_tx.clone()), body))
+				let request = ReceivedRequestHandle::new(request_id, service_id, received_at, closed, incoming_rx, self.command_tx.clone());
+				Ok((request, body))
 			},
 		}
 	}
@@ -138,6 +320,7 @@ impl<Body> RequestTracker<Body> {
 	/// Note that received requests are also removed internally when they would receive a message but the [`ReceivedRequestHandle`] was dropped.
 	pub fn remove_received_request(&mut self, request_id: u32) -> Result<(), Error> {
 		let tracked_request = self.received_requests.remove(&request_id).ok_or(InnerError::UnknownRequestId { request_id })?;
+		trace_event!(debug, parent: &tracked_request.span, "received request closed");
 
 		// Set the `closed` flag so that existing request write handles will refuse to send more messages.
 		tracked_request.closed.store(true, Ordering::Release);
@@ -147,17 +330,42 @@ impl<Body> RequestTracker<Body> {
 		Ok(())
 	}
 
+	/// Close all open requests because the peer is shutting down.
+	///
+	/// Sent requests that are still waiting for a response report [`Error::shutdown()`] instead of a connection aborted error.
+	pub fn shutdown(&mut self) {
+		for request in std::mem::take(&mut self.sent_requests).into_values() {
+			match request {
+				SentRequest::Handle(tracked_request) => {
+					trace_event!(debug, parent: &tracked_request.span, "sent request closed by shutdown");
+					tracked_request.closed.store(true, Ordering::Release);
+					let _: Result<_, _> = tracked_request.incoming_tx.send(RequestHandleCommand::Shutdown);
+				},
+				SentRequest::Oneshot(oneshot_request) => {
+					trace_event!(debug, parent: &oneshot_request.span, "oneshot request closed by shutdown");
+					let _: Result<_, _> = oneshot_request.response_tx.send(Err(Error::shutdown()));
+				},
+			}
+		}
+		for tracked_request in std::mem::take(&mut self.received_requests).into_values() {
+			trace_event!(debug, parent: &tracked_request.span, "received request closed by shutdown");
+			tracked_request.closed.store(true, Ordering::Release);
+			let _: Result<_, _> = tracked_request.incoming_tx.send(RequestHandleCommand::Close);
+		}
+	}
+
 	/// Process an incoming message.
 	///
 	/// This will pass the message on to an open request if any matches.
+	/// The `received_at` parameter is the time the message was read from the transport.
 	///
 	/// Returns an error
 	///  * if an incoming request message uses an already claimed request ID
 	///  * if an incoming update or response message does not match an open request
-	pub async fn process_incoming_message(&mut self, message: Message<Body>) -> Result<Option<ReceivedMessage<Body>>, Error> {
+	pub async fn process_incoming_message(&mut self, message: Message<Body>, received_at: Instant) -> Result<Option<ReceivedMessage<Body>>, Error> {
 		match message.header.message_type {
 			MessageType::Request => {
-				let (received_request, body) = self.register_received_request(message.header.request_id, message.header.service_id, message.body)?;
+				let (received_request, body) = self.register_received_request(message.header.request_id, message.header.service_id, message.body, received_at)?;
 				Ok(Some(ReceivedMessage::Request(received_request, body)))
 			},
 			MessageType::Response => {
@@ -173,6 +381,8 @@ impl<Body> RequestTracker<Body> {
 				Ok(None)
 			},
 			MessageType::Stream => Ok(Some(ReceivedMessage::Stream(message))),
+			// Acknowledged stream messages and acknowledgements are handled by the peer loop.
+			MessageType::AckedStream | MessageType::StreamAck => Ok(None),
 		}
 	}
 
@@ -181,7 +391,15 @@ impl<Body> RequestTracker<Body> {
 		match self.sent_requests.entry(request_id) {
 			Entry::Vacant(_) => Err(InnerError::UnknownRequestId { request_id }.into()),
 			Entry::Occupied(entry) => {
-				let tracked_request = entry.remove();
+				let tracked_request = match entry.remove() {
+					SentRequest::Handle(x) => x,
+					SentRequest::Oneshot(oneshot_request) => {
+						trace_event!(debug, parent: &oneshot_request.span, "received response for oneshot request");
+						let _: Result<_, _> = oneshot_request.response_tx.send(Ok(message));
+						return Ok(());
+					},
+				};
+				trace_event!(debug, parent: &tracked_request.span, "received response");
 
 				// Forward the message to the sent_request.
 				let _: Result<_, _> = tracked_request.incoming_tx.send(RequestHandleCommand::Message(message));
@@ -201,12 +419,21 @@ impl<Body> RequestTracker<Body> {
 		match self.received_requests.entry(request_id) {
 			Entry::Vacant(_) => Err(InnerError::UnknownRequestId { request_id }.into()),
 			Entry::Occupied(mut entry) => {
-				// If the received_request is dropped, clear the entry.
-				if entry.get_mut().incoming_tx.send(RequestHandleCommand::Message(message)).is_err() {
-					entry.remove();
-					Err(InnerError::UnknownRequestId { request_id }.into())
-				} else {
-					Ok(())
+				trace_event!(trace, parent: &entry.get().span, "received requester update");
+
+				// Keep room for the response, so that it can always be delivered.
+				match entry.get_mut().incoming_tx.send_keep_one(RequestHandleCommand::Message(message)) {
+					Ok(()) => Ok(()),
+					// If the received_request is dropped, clear the entry.
+					Err(SendError::Closed(_)) => {
+						trace_event!(debug, parent: &entry.get().span, "received request handle was dropped, discarding update");
+						entry.remove();
+						Err(InnerError::UnknownRequestId { request_id }.into())
+					},
+					Err(SendError::Full(_)) => {
+						trace_event!(debug, parent: &entry.get().span, "update queue of request is full, discarding update");
+						Err(InnerError::CapacityExceeded.into())
+					},
 				}
 			},
 		}
@@ -214,21 +441,51 @@ impl<Body> RequestTracker<Body> {
 
 	async fn process_incoming_responder_update(&mut self, message: Message<Body>) -> Result<(), Error> {
 		let request_id = message.header.request_id;
-		match self.sent_requests.entry(request_id) {
-			Entry::Vacant(_) => Err(InnerError::UnknownRequestId { request_id }.into()),
-			Entry::Occupied(mut entry) => {
-				// If the sent_request is dropped, clear the entry.
-				if entry.get_mut().incoming_tx.send(RequestHandleCommand::Message(message)).is_err() {
-					entry.remove();
-					Err(InnerError::UnknownRequestId { request_id }.into())
-				} else {
-					Ok(())
-				}
+		let mut entry = match self.sent_requests.entry(request_id) {
+			Entry::Vacant(_) => return Err(InnerError::UnknownRequestId { request_id }.into()),
+			Entry::Occupied(entry) => entry,
+		};
+
+		let tracked_request = match entry.get_mut() {
+			SentRequest::Handle(x) => x,
+			// Oneshot requests can not receive updates, so they fail like a request handle waiting for the response.
+			SentRequest::Oneshot(_) => {
+				let SentRequest::Oneshot(oneshot_request) = entry.remove() else { unreachable!() };
+				trace_event!(debug, parent: &oneshot_request.span, "received responder update for oneshot request");
+				let error = UnexpectedMessageType {
+					value: message.header.message_type,
+					expected: MessageType::Response,
+				};
+				let _: Result<_, _> = oneshot_request.response_tx.send(Err(InnerError::from(error).into()));
+				return Ok(());
+			},
+		};
+		trace_event!(trace, parent: &tracked_request.span, "received responder update");
+
+		// Keep room for the response, so that it can always be delivered.
+		match tracked_request.incoming_tx.send_keep_one(RequestHandleCommand::Message(message)) {
+			Ok(()) => Ok(()),
+			// If the sent_request is dropped, clear the entry.
+			Err(SendError::Closed(_)) => {
+				trace_event!(debug, parent: &tracked_request.span, "sent request handle was dropped, discarding update");
+				entry.remove();
+				Err(InnerError::UnknownRequestId { request_id }.into())
+			},
+			Err(SendError::Full(_)) => {
+				trace_event!(debug, parent: &tracked_request.span, "update queue of request is full, discarding update");
+				Err(InnerError::CapacityExceeded.into())
 			},
 		}
 	}
 }
 
+/// Create the channel for incoming messages of a new request.
+///
+/// The channel has room for `updates_capacity` update messages, plus the response.
+fn request_channel<Body>(updates_capacity: usize) -> (channel::Sender<RequestHandleCommand<Body>>, channel::Receiver<RequestHandleCommand<Body>>) {
+	channel::channel(updates_capacity.max(1) + 1)
+}
+
 #[cfg(test)]
 mod test {
 	use assert2::assert;
@@ -255,12 +512,16 @@ mod test {
 		fn into_error(self) -> Result<String, std::string::FromUtf8Error> {
 			Ok(String::new())
 		}
+
+		fn data_len(&self) -> usize {
+			0
+		}
 	}
 
 	#[tokio::test]
 	async fn test_incoming_request() {
-		let (command_tx, mut command_rx) = mpsc::unbounded_channel();
-		let mut tracker = RequestTracker::new(command_tx);
+		let (command_tx, mut command_rx) = channel::channel(16);
+		let mut tracker = RequestTracker::new(command_tx, 16);
 
 		let command_task = tokio::spawn(async move {
 			// Check that we get the command to send an update.
@@ -278,8 +539,8 @@ mod test {
 		});
 
 		// Simulate an incoming request and an update.
-		let_assert!(Ok(Some(ReceivedMessage::Request(mut received_request, _body))) = tracker.process_incoming_message(Message::request(1, 2, Body)).await);
-		assert!(let Ok(None) = tracker.process_incoming_message(Message::requester_update(1, 10, Body)).await);
+		let_assert!(Ok(Some(ReceivedMessage::Request(mut received_request, _body))) = tracker.process_incoming_message(Message::request(1, 2, Body), Instant::now()).await);
+		assert!(let Ok(None) = tracker.process_incoming_message(Message::requester_update(1, 10, Body), Instant::now()).await);
 
 		// Receive the update.
 		let_assert!(Some(update) = received_request.recv_update().await);
@@ -291,7 +552,7 @@ mod test {
 		let_assert!(Ok(()) = tracker.remove_received_request(received_request.request_id()));
 
 		// The received request is now dropped, so lets check that new incoming message cause an error.
-		assert!(let Err(_) = tracker.process_incoming_message(Message::requester_update(1, 11, Body)).await);
+		assert!(let Err(_) = tracker.process_incoming_message(Message::requester_update(1, 11, Body), Instant::now()).await);
 
 		drop(received_request);
 		drop(tracker);
@@ -300,8 +561,8 @@ mod test {
 
 	#[tokio::test]
 	async fn test_outgoing_request() {
-		let (command_tx, mut command_rx) = mpsc::unbounded_channel();
-		let mut tracker = RequestTracker::new(command_tx);
+		let (command_tx, mut command_rx) = channel::channel(16);
+		let mut tracker = RequestTracker::new(command_tx, 16);
 
 		// Simulate an command request.
 		let_assert!(Ok(mut sent_request) = tracker.allocate_sent_request(3));
@@ -318,7 +579,7 @@ mod test {
 		});
 
 		// Simulate and receive a responder update.
-		assert!(let Ok(None) = tracker.process_incoming_message(Message::responder_update(sent_request.request_id(), 12, Body)).await);
+		assert!(let Ok(None) = tracker.process_incoming_message(Message::responder_update(sent_request.request_id(), 12, Body), Instant::now()).await);
 		let_assert!(Some(update) = sent_request.recv_update().await);
 		assert!(update.header == MessageHeader::responder_update(sent_request.request_id(), 12));
 
@@ -326,14 +587,14 @@ mod test {
 		let_assert!(Ok(()) = sent_request.send_update(13, Body).await);
 
 		// Simulate and receive a response update.
-		assert!(let Ok(None) = tracker.process_incoming_message(Message::response(sent_request.request_id(), 14, Body)).await);
+		assert!(let Ok(None) = tracker.process_incoming_message(Message::response(sent_request.request_id(), 14, Body), Instant::now()).await);
 		let_assert!(Ok(update) = sent_request.recv_response().await);
 		assert!(update.header == MessageHeader::response(sent_request.request_id(), 14));
 
 		// After receiving the response, the entry should be removed from the tracker.
 		// So no more incoming messages for the request should be accepted.
 		assert!(let Err(_) = tracker
-				.process_incoming_message(Message::responder_update(sent_request.request_id(), 15, Body))
+				.process_incoming_message(Message::responder_update(sent_request.request_id(), 15, Body), Instant::now())
 				.await
 		);
 
@@ -341,4 +602,89 @@ mod test {
 		drop(sent_request);
 		assert!(let Ok(()) = command_task.await);
 	}
+
+	#[tokio::test]
+	async fn oneshot_request() {
+		let (command_tx, _command_rx) = channel::channel(16);
+		let mut tracker = RequestTracker::new(command_tx, 16);
+
+		// The response is delivered through the oneshot channel and closes the request.
+		let (response_tx, response_rx) = oneshot::channel();
+		let_assert!(Ok(request_id) = tracker.allocate_oneshot_request(3, response_tx));
+		assert!(tracker.sent_requests_len() == 1);
+		assert!(let Ok(None) = tracker.process_incoming_message(Message::response(request_id, 14, Body), Instant::now()).await);
+		let_assert!(Ok(Ok(response)) = response_rx.await);
+		assert!(response.header == MessageHeader::response(request_id, 14));
+		assert!(tracker.sent_requests_len() == 0);
+
+		// Oneshot requests can not receive updates.
+		let (response_tx, response_rx) = oneshot::channel();
+		let_assert!(Ok(request_id) = tracker.allocate_oneshot_request(3, response_tx));
+		assert!(let Ok(None) = tracker.process_incoming_message(Message::responder_update(request_id, 12, Body), Instant::now()).await);
+		let_assert!(Ok(Err(e)) = response_rx.await);
+		assert!(e.kind() == crate::ErrorKind::UnexpectedMessageType);
+		assert!(let Err(_) = tracker.process_incoming_message(Message::response(request_id, 14, Body), Instant::now()).await);
+
+		// Dropped receivers are reported by the audit, and open requests fail on shutdown.
+		let (response_tx, response_rx) = oneshot::channel();
+		let_assert!(Ok(orphaned_id) = tracker.allocate_oneshot_request(3, response_tx));
+		drop(response_rx);
+		let (response_tx, response_rx) = oneshot::channel();
+		let_assert!(Ok(_) = tracker.allocate_oneshot_request(3, response_tx));
+		assert!(tracker.audit().orphaned_sent_requests == [orphaned_id]);
+		tracker.shutdown();
+		let_assert!(Ok(Err(e)) = response_rx.await);
+		assert!(e.is_shutdown());
+	}
+
+	#[tokio::test]
+	async fn max_open_received_requests() {
+		let (command_tx, _command_rx) = channel::channel(16);
+		let mut tracker = RequestTracker::new(command_tx, 16);
+		tracker.set_max_open_received_requests(Some(2));
+
+		let now = Instant::now();
+		let_assert!(Ok(_) = tracker.register_received_request(1, 10, Body, now));
+		let_assert!(Ok(_) = tracker.register_received_request(2, 10, Body, now));
+		let_assert!(Err(e) = tracker.register_received_request(3, 10, Body, now));
+		assert!(e.kind() == crate::ErrorKind::TooManyOpenRequests);
+		assert!(tracker.received_requests_len() == 2);
+
+		// Duplicate request IDs are still reported as such.
+		let_assert!(Err(e) = tracker.register_received_request(2, 10, Body, now));
+		assert!(e.kind() == crate::ErrorKind::DuplicateRequestId);
+
+		// Closing a request makes room for a new one.
+		assert!(let Ok(()) = tracker.remove_received_request(1));
+		let_assert!(Ok(_) = tracker.register_received_request(3, 10, Body, now));
+	}
+
+	#[tokio::test]
+	async fn audit() {
+		let (command_tx, _command_rx) = channel::channel(16);
+		let mut tracker = RequestTracker::new(command_tx, 16);
+		let now = Instant::now();
+		assert!(tracker.audit().is_clean());
+
+		let_assert!(Ok(sent_a) = tracker.allocate_sent_request(1));
+		let_assert!(Ok(sent_b) = tracker.allocate_sent_request(1));
+		let_assert!(Ok((received_a, _body)) = tracker.register_received_request(7, 2, Body, now));
+		let_assert!(Ok((received_b, _body)) = tracker.register_received_request(8, 2, Body, now));
+		assert!(tracker.audit().is_clean());
+
+		// Dropped handles of open requests are reported.
+		let sent_b_id = sent_b.request_id();
+		drop(sent_b);
+		drop(received_b);
+		let audit = tracker.audit();
+		assert!(!audit.is_clean());
+		assert!(audit.orphaned_sent_requests == [sent_b_id]);
+		assert!(audit.orphaned_received_requests == [8]);
+
+		// Removed requests are no longer reported.
+		assert!(let Ok(()) = tracker.remove_sent_request(sent_b_id));
+		assert!(let Ok(()) = tracker.remove_received_request(8));
+		assert!(tracker.audit().is_clean());
+		drop((sent_a, received_a));
+	}
 }
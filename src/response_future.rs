@@ -0,0 +1,317 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+use crate::error::private::connection_aborted;
+use crate::{Error, Message, SentRequestHandle, SentRequestWriteHandle};
+
+/// Future for the final response of a sent request.
+///
+/// The future resolves to the response message,
+/// or to an error if the request was closed without receiving a response.
+/// Like [`SentRequestHandle::recv_response()`], error responses are returned as regular messages.
+/// You can use [`Message::check_error_response()`] to turn them into an error.
+///
+/// Use [`SentRequestHandle::split_response()`] to create a response future.
+pub struct ResponseFuture<Body> {
+	/// The state shared with the update receiver.
+	state: Arc<Mutex<SplitState<Body>>>,
+}
+
+/// Receiver for the update messages of a sent request.
+///
+/// The receiver can be used in a different task than the [`ResponseFuture`] of the same request.
+///
+/// Use [`SentRequestHandle::split_response()`] to create an update receiver.
+pub struct SentRequestUpdates<Body> {
+	/// The write handle of the request.
+	write_handle: SentRequestWriteHandle<Body>,
+
+	/// The state shared with the response future.
+	state: Arc<Mutex<SplitState<Body>>>,
+}
+
+/// State shared by a [`ResponseFuture`] and [`SentRequestUpdates`].
+///
+/// Both halves receive messages from the request handle, and pass on the messages meant for the other half.
+struct SplitState<Body> {
+	/// The request handle.
+	request: SentRequestHandle<Body>,
+
+	/// Updates received by the response future, waiting to be consumed by the update receiver.
+	updates: VecDeque<Message<Body>>,
+
+	/// The final response, if it has been received but not yet consumed by the response future.
+	response: Option<Message<Body>>,
+
+	/// If true, no more messages will be received for the request.
+	finished: bool,
+
+	/// If true, the update receiver has been dropped.
+	updates_dropped: bool,
+
+	/// If true, the response future has been dropped or has completed.
+	response_dropped: bool,
+
+	/// The waker of the task waiting for an update.
+	update_waker: Option<Waker>,
+
+	/// The waker of the task waiting for the response.
+	response_waker: Option<Waker>,
+}
+
+impl<Body> SentRequestHandle<Body> {
+	/// Split the request into a receiver for update messages and a future for the final response.
+	///
+	/// This allows one task to wait for the final response while another task consumes the update messages.
+	/// Both halves can be polled concurrently, and each half only receives the messages meant for it.
+	///
+	/// If the update receiver is dropped, later update messages are discarded.
+	/// The response future still resolves when the response is received.
+	pub fn split_response(self) -> (SentRequestUpdates<Body>, ResponseFuture<Body>) {
+		let write_handle = self.write_handle();
+		let state = Arc::new(Mutex::new(SplitState {
+			request: self,
+			updates: VecDeque::new(),
+			response: None,
+			finished: false,
+			updates_dropped: false,
+			response_dropped: false,
+			update_waker: None,
+			response_waker: None,
+		}));
+		let updates = SentRequestUpdates {
+			write_handle,
+			state: state.clone(),
+		};
+		(updates, ResponseFuture { state })
+	}
+}
+
+impl<Body> SplitState<Body> {
+	/// Receive all available messages and sort them into updates and the response.
+	///
+	/// Wakes the other half if it received a message that it is waiting for.
+	fn poll_incoming(&mut self, context: &mut Context) {
+		while !self.finished {
+			match self.request.poll_recv_message(context) {
+				Poll::Pending => break,
+				Poll::Ready(None) => {
+					self.finished = true;
+					wake(&mut self.update_waker);
+					wake(&mut self.response_waker);
+				},
+				Poll::Ready(Some(message)) => {
+					if message.header.message_type.is_response() {
+						self.finished = true;
+						if !self.response_dropped {
+							self.response = Some(message);
+						}
+						wake(&mut self.update_waker);
+						wake(&mut self.response_waker);
+					} else if !self.updates_dropped {
+						self.updates.push_back(message);
+						wake(&mut self.update_waker);
+					}
+				},
+			}
+		}
+	}
+}
+
+impl<Body> SentRequestUpdates<Body> {
+	/// Get the request ID of the sent request.
+	pub fn request_id(&self) -> u32 {
+		self.write_handle.request_id()
+	}
+
+	/// Get the service ID of the initial request message.
+	pub fn service_id(&self) -> i32 {
+		self.write_handle.service_id()
+	}
+
+	/// Create a write handle for this request.
+	pub fn write_handle(&self) -> SentRequestWriteHandle<Body> {
+		self.write_handle.clone()
+	}
+
+	/// Receive the next update message of the request from the remote peer.
+	///
+	/// This function returns `None` when the final response has been received,
+	/// or when the request was closed for a different reason.
+	pub async fn recv_update(&mut self) -> Option<Message<Body>> {
+		std::future::poll_fn(|context| self.poll_recv_update(context)).await
+	}
+
+	/// Try to receive the next update message without blocking.
+	///
+	/// If this function returns [`Poll::Pending`],
+	/// the current task is scheduled to wake when an update message or the final response is received.
+	pub fn poll_recv_update(&mut self, context: &mut Context) -> Poll<Option<Message<Body>>> {
+		let mut state = lock(&self.state);
+		state.poll_incoming(context);
+		if let Some(update) = state.updates.pop_front() {
+			Poll::Ready(Some(update))
+		} else if state.finished {
+			Poll::Ready(None)
+		} else {
+			set_waker(&mut state.update_waker, context);
+			Poll::Pending
+		}
+	}
+
+	/// Send an update for the request to the remote peer.
+	pub async fn send_update(&self, service_id: i32, body: impl Into<Body>) -> Result<(), Error> {
+		self.write_handle.send_update(service_id, body).await
+	}
+}
+
+impl<Body> Future for ResponseFuture<Body> {
+	type Output = Result<Message<Body>, Error>;
+
+	fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+		let mut state = lock(&self.state);
+		if state.response_dropped {
+			panic!("ResponseFuture polled after completion");
+		}
+		state.poll_incoming(context);
+		if let Some(response) = state.response.take() {
+			state.response_dropped = true;
+			Poll::Ready(Ok(response))
+		} else if state.finished {
+			state.response_dropped = true;
+			Poll::Ready(Err(connection_aborted()))
+		} else {
+			set_waker(&mut state.response_waker, context);
+			Poll::Pending
+		}
+	}
+}
+
+impl<Body> Drop for SentRequestUpdates<Body> {
+	fn drop(&mut self) {
+		let mut state = lock(&self.state);
+		state.updates_dropped = true;
+		state.updates.clear();
+		// The dropped task may have been the one registered to receive new messages.
+		wake(&mut state.response_waker);
+	}
+}
+
+impl<Body> Drop for ResponseFuture<Body> {
+	fn drop(&mut self) {
+		let mut state = lock(&self.state);
+		state.response_dropped = true;
+		state.response = None;
+		// The dropped task may have been the one registered to receive new messages.
+		wake(&mut state.update_waker);
+	}
+}
+
+impl<Body> std::fmt::Debug for SentRequestUpdates<Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct(core::any::type_name::<Self>())
+			.field("request_id", &self.request_id())
+			.field("service_id", &self.service_id())
+			.finish_non_exhaustive()
+	}
+}
+
+impl<Body> std::fmt::Debug for ResponseFuture<Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		let state = lock(&self.state);
+		f.debug_struct(core::any::type_name::<Self>())
+			.field("request_id", &state.request.request_id())
+			.field("service_id", &state.request.service_id())
+			.finish_non_exhaustive()
+	}
+}
+
+/// Lock the shared state.
+fn lock<Body>(state: &Mutex<SplitState<Body>>) -> MutexGuard<'_, SplitState<Body>> {
+	match state.lock() {
+		Ok(x) => x,
+		Err(e) => e.into_inner(),
+	}
+}
+
+/// Wake and clear a waker, if it is set.
+fn wake(waker: &mut Option<Waker>) {
+	if let Some(waker) = waker.take() {
+		waker.wake();
+	}
+}
+
+/// Store the waker of the current task.
+fn set_waker(waker: &mut Option<Waker>, context: &Context) {
+	match waker {
+		Some(waker) if waker.will_wake(context.waker()) => (),
+		_ => *waker = Some(context.waker().clone()),
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use assert2::assert;
+	use assert2::let_assert;
+
+	use crate::{ReceivedMessage, UnixStreamPeer, UnixStreamTransport};
+
+	#[tokio::test]
+	async fn updates_and_response_in_separate_tasks() {
+		let_assert!(Ok((peer_a, peer_b)) = tokio::net::UnixStream::pair());
+		let peer_a = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, Default::default()));
+		let mut peer_b = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default()));
+
+		let_assert!(Ok(sent_request) = peer_a.send_request(1, &b"start"[..]).await);
+		let (mut updates, response) = sent_request.split_response();
+		assert!(updates.request_id() == 0);
+
+		let response = tokio::spawn(response);
+		let updates = tokio::spawn(async move {
+			let mut received = Vec::new();
+			while let Some(update) = updates.recv_update().await {
+				received.push(update.body.data.to_vec());
+			}
+			received
+		});
+
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = peer_b.recv_message().await);
+		for i in 0..3u8 {
+			assert!(let Ok(()) = received_request.send_update(2, vec![i]).await);
+		}
+		assert!(let Ok(()) = received_request.send_response(1, &b"done"[..]).await);
+
+		let_assert!(Ok(Ok(response)) = response.await);
+		assert!(response.body.as_ref() == b"done");
+		let_assert!(Ok(received) = updates.await);
+		assert!(received == [[0], [1], [2]]);
+	}
+
+	#[tokio::test]
+	async fn response_without_update_receiver() {
+		let_assert!(Ok((peer_a, peer_b)) = tokio::net::UnixStream::pair());
+		let peer_a = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, Default::default()));
+		let mut peer_b = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default()));
+
+		let_assert!(Ok(sent_request) = peer_a.send_request(1, &b"start"[..]).await);
+		let (updates, response) = sent_request.split_response();
+		drop(updates);
+
+		let_assert!(Ok(ReceivedMessage::Request(received_request, _body)) = peer_b.recv_message().await);
+		assert!(let Ok(()) = received_request.send_update(2, &b"ignored"[..]).await);
+		assert!(let Ok(()) = received_request.send_response(1, &b"done"[..]).await);
+		let_assert!(Ok(response) = response.await);
+		assert!(response.body.as_ref() == b"done");
+
+		// The response future reports an error if the request is closed without response.
+		let_assert!(Ok(sent_request) = peer_a.send_request(1, &b"start"[..]).await);
+		let (mut updates, response) = sent_request.split_response();
+		drop(peer_b);
+		assert!(let None = updates.recv_update().await);
+		let_assert!(Err(e) = response.await);
+		assert!(e.is_connection_aborted());
+	}
+}
@@ -0,0 +1,209 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::error::private::InnerError;
+use crate::{service_id, Error, MessageHeader, MessageType, SentRequestHandle};
+
+/// Reader for a response that is sent in multiple chunks.
+///
+/// A responder can split a large response body over multiple responder updates with service ID [`service_id::RESPONSE_CHUNK`],
+/// followed by the final response message.
+/// The complete response body is the concatenation of all chunks and the body of the final response.
+///
+/// You can receive the chunks one by one with [`Self::recv_chunk()`].
+/// If the body type implements `AsRef<[u8]>`, the reader also implements [`AsyncRead`].
+///
+/// Use [`SentRequestHandle::recv_response_streaming()`] to create a response reader.
+pub struct ResponseReader<Body> {
+	/// The request to read the response of.
+	request: SentRequestHandle<Body>,
+
+	/// The chunk that is currently being read through the [`AsyncRead`] implementation, and the read offset.
+	current: Option<(Body, usize)>,
+
+	/// The header of the final response message, if it has been received.
+	response_header: Option<MessageHeader>,
+}
+
+impl<Body> ResponseReader<Body> {
+	/// Create a new response reader for a sent request.
+	pub(crate) fn new(request: SentRequestHandle<Body>) -> Self {
+		Self {
+			request,
+			current: None,
+			response_header: None,
+		}
+	}
+
+	/// Get the request ID of the sent request.
+	pub fn request_id(&self) -> u32 {
+		self.request.request_id()
+	}
+
+	/// Get the service ID of the initial request message.
+	pub fn service_id(&self) -> i32 {
+		self.request.service_id()
+	}
+
+	/// Get the header of the final response message.
+	///
+	/// Returns [`None`] if the final response has not been received yet.
+	pub fn response_header(&self) -> Option<&MessageHeader> {
+		self.response_header.as_ref()
+	}
+
+	/// Receive the next chunk of the response body.
+	///
+	/// The body of the final response message is returned as the last chunk, and may be empty.
+	/// After the final response has been received, this function returns `Ok(None)`.
+	///
+	/// If the final response is an error response, it is returned as an [`Error`].
+	/// An error is also returned if a responder update with a different service ID is received.
+	pub async fn recv_chunk(&mut self) -> Result<Option<Body>, Error>
+	where
+		Body: crate::Body,
+	{
+		std::future::poll_fn(|context| self.poll_recv_chunk(context)).await
+	}
+
+	/// Try to receive the next chunk of the response body without blocking.
+	///
+	/// See [`Self::recv_chunk()`] for more details.
+	///
+	/// If the function returns [`Poll::Pending`],
+	/// the current task is scheduled to wake when the next chunk is available.
+	pub fn poll_recv_chunk(&mut self, context: &mut Context) -> Poll<Result<Option<Body>, Error>>
+	where
+		Body: crate::Body,
+	{
+		if self.response_header.is_some() {
+			return Poll::Ready(Ok(None));
+		}
+
+		let message = match ready!(self.request.poll_recv_message(context)) {
+			Some(x) => x,
+			None => return Poll::Ready(Err(self.request.closed_error())),
+		};
+
+		match message.header.message_type {
+			MessageType::ResponderUpdate if message.header.service_id == service_id::RESPONSE_CHUNK => {
+				Poll::Ready(Ok(Some(message.body)))
+			},
+			MessageType::ResponderUpdate => {
+				Poll::Ready(Err(Error::unexpected_service_id(message.header.service_id)))
+			},
+			_ => {
+				self.response_header = Some(message.header);
+				let message = message.check_error_response()?;
+				Poll::Ready(Ok(Some(message.body)))
+			},
+		}
+	}
+}
+
+impl<Body> AsyncRead for ResponseReader<Body>
+where
+	Body: crate::Body + AsRef<[u8]> + Unpin,
+{
+	fn poll_read(self: Pin<&mut Self>, context: &mut Context, buf: &mut ReadBuf) -> Poll<std::io::Result<()>> {
+		let this = self.get_mut();
+		loop {
+			// Copy data from the current chunk if there is any left.
+			if let Some((chunk, offset)) = &mut this.current {
+				let data = &chunk.as_ref()[*offset..];
+				if !data.is_empty() {
+					let len = data.len().min(buf.remaining());
+					buf.put_slice(&data[..len]);
+					*offset += len;
+					return Poll::Ready(Ok(()));
+				}
+				this.current = None;
+			}
+
+			// Get the next chunk, or signal the end of the response.
+			match ready!(this.poll_recv_chunk(context)) {
+				Ok(Some(chunk)) => this.current = Some((chunk, 0)),
+				Ok(None) => return Poll::Ready(Ok(())),
+				Err(e) => return Poll::Ready(Err(into_io_error(e))),
+			}
+		}
+	}
+}
+
+impl<Body> std::fmt::Debug for ResponseReader<Body> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct(core::any::type_name::<Self>())
+			.field("request_id", &self.request_id())
+			.field("service_id", &self.service_id())
+			.field("response_header", &self.response_header)
+			.finish_non_exhaustive()
+	}
+}
+
+/// Convert an [`Error`] into an [`std::io::Error`] for the [`AsyncRead`] implementation.
+fn into_io_error(error: Error) -> std::io::Error {
+	match error.inner {
+		InnerError::Io(e) => e,
+		other => std::io::Error::new(std::io::ErrorKind::Other, other.to_string()),
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use assert2::assert;
+	use assert2::let_assert;
+	use tokio::io::AsyncReadExt;
+	use tokio::net::UnixStream;
+
+	use crate::{ReceivedMessage, StreamBody, UnixStreamPeer, UnixStreamTransport};
+
+	#[tokio::test]
+	async fn read_streamed_response() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let peer_a = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, Default::default()));
+		let mut peer_b = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default()));
+
+		let server = tokio::spawn(async move {
+			let_assert!(Ok(ReceivedMessage::Request(request, _body)) = peer_b.recv_message().await);
+			let data: Vec<u8> = (0..100).collect();
+			let_assert!(Err(e) = request.send_response_streaming(5, &data[..], 0).await);
+			assert!(let Some(std::io::ErrorKind::InvalidInput) = e.as_io_error().map(|e| e.kind()));
+			assert!(let Ok(()) = request.send_response_streaming(5, &data[..], 16).await);
+		});
+
+		let_assert!(Ok(request) = peer_a.send_request(5, &b"get"[..]).await);
+		let mut reader = request.recv_response_streaming();
+		let mut data = Vec::new();
+		assert!(let Ok(100) = reader.read_to_end(&mut data).await);
+		assert!(data == (0..100).collect::<Vec<u8>>());
+		let_assert!(Some(header) = reader.response_header());
+		assert!(header.service_id == 5);
+		assert!(let Ok(()) = server.await);
+	}
+
+	#[tokio::test]
+	async fn receive_chunks() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+		let peer_a = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, Default::default()));
+		let mut peer_b = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default()));
+
+		let server = tokio::spawn(async move {
+			let_assert!(Ok(ReceivedMessage::Request(request, _body)) = peer_b.recv_message().await);
+			assert!(let Ok(()) = request.send_response_chunk(&b"Hello "[..]).await);
+			assert!(let Ok(()) = request.send_response_chunk(&b"world"[..]).await);
+			assert!(let Ok(()) = request.send_error_response("out of cheese").await);
+		});
+
+		let_assert!(Ok(request) = peer_a.send_request(5, &b"get"[..]).await);
+		let mut reader = request.recv_response_streaming();
+		let_assert!(Ok(Some(chunk)) = reader.recv_chunk().await);
+		assert!(chunk.as_ref() == b"Hello ");
+		let_assert!(Ok(Some(chunk)) = reader.recv_chunk().await);
+		assert!(chunk.as_ref() == b"world");
+		let_assert!(Err(e) = reader.recv_chunk().await);
+		assert!(e.as_remote_error() == Some("out of cheese"));
+		assert!(let Ok(None::<StreamBody>) = reader.recv_chunk().await);
+		assert!(let Ok(()) = server.await);
+	}
+}
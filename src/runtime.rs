@@ -0,0 +1,197 @@
+//! Abstraction over the async runtime used by peers.
+//!
+//! The read/write loop of a [`Peer`][crate::Peer] only needs an async runtime for timers,
+//! for example to expire received requests or to wait before retrying a failed write.
+//! By default, these timers use [`tokio`], through the [`TokioRuntime`].
+//!
+//! To run a peer on a different executor, such as `async-std` or `smol`,
+//! implement the [`Runtime`] trait for that executor and pass it to [`Peer::with_runtime()`][crate::Peer::with_runtime].
+//! Then run the peer with [`Peer::spawn_on()`][crate::Peer::spawn_on],
+//! or poll the future returned by [`Peer::run()`][crate::Peer::run] on the executor yourself.
+//! The handles of the peer do not depend on a runtime at all.
+//!
+//! Note that the transport must also be usable on the chosen executor.
+//! The socket based transports in this crate use [`tokio`] sockets, which need a tokio runtime.
+//!
+//! ```
+//! # use fizyr_rpc::runtime::{BoxFuture, Runtime};
+//! # use std::time::Instant;
+//! /// A runtime that uses `smol`.
+//! struct Smol;
+//!
+//! # mod smol {
+//! #     pub fn spawn(_: impl std::future::Future) -> Task { Task }
+//! #     pub struct Task;
+//! #     impl Task { pub fn detach(self) {} }
+//! #     pub struct Timer;
+//! #     impl Timer { pub async fn at(_: std::time::Instant) {} }
+//! # }
+//! impl Runtime for Smol {
+//!     fn spawn(&self, future: BoxFuture<'static, ()>) {
+//!         smol::spawn(future).detach();
+//!     }
+//!
+//!     fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+//!         Box::pin(async move {
+//!             smol::Timer::at(deadline).await;
+//!         })
+//!     }
+//! }
+//! ```
+
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Instant;
+
+/// A boxed future that can be sent between threads.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Trait for async runtimes that can run peers.
+pub trait Runtime: Send + Sync + 'static {
+	/// Spawn a detached task that runs a future to completion.
+	fn spawn(&self, future: BoxFuture<'static, ()>);
+
+	/// Create a future that completes at the given deadline.
+	fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+}
+
+/// The [`tokio`] runtime.
+///
+/// This is the default runtime for peers.
+/// Tasks are spawned on the tokio runtime of the current thread,
+/// so using it outside of a tokio runtime panics.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioRuntime;
+
+impl Runtime for TokioRuntime {
+	fn spawn(&self, future: BoxFuture<'static, ()>) {
+		tokio::spawn(future);
+	}
+
+	fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+		Box::pin(tokio::time::sleep_until(deadline.into()))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::{assert, let_assert};
+	use std::pin::Pin;
+	use std::task::{Context, Poll};
+	use std::time::Duration;
+	use tokio::sync::{mpsc, oneshot};
+
+	use crate::transport::{Transport, TransportError, TransportReadHalf, TransportWriteHalf};
+	use crate::{Message, MessageHeader, Peer, ReceivedMessage, StreamBody};
+
+	/// A runtime that runs tasks and timers on plain threads.
+	struct ThreadRuntime;
+
+	impl Runtime for ThreadRuntime {
+		fn spawn(&self, future: BoxFuture<'static, ()>) {
+			std::thread::spawn(move || crate::util::block_on(future));
+		}
+
+		fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+			let (wake, done) = oneshot::channel();
+			std::thread::spawn(move || {
+				std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+				wake.send(()).ok();
+			});
+			Box::pin(async move {
+				done.await.ok();
+			})
+		}
+	}
+
+	/// An in-memory transport that does not need a tokio runtime.
+	struct ChannelTransport {
+		tx: mpsc::UnboundedSender<Message<StreamBody>>,
+		rx: mpsc::UnboundedReceiver<Message<StreamBody>>,
+	}
+
+	struct ChannelReadHalf<'a>(&'a mut mpsc::UnboundedReceiver<Message<StreamBody>>);
+
+	struct ChannelWriteHalf<'a>(&'a mpsc::UnboundedSender<Message<StreamBody>>);
+
+	fn channel_transport_pair() -> (ChannelTransport, ChannelTransport) {
+		let (tx_a, rx_b) = mpsc::unbounded_channel();
+		let (tx_b, rx_a) = mpsc::unbounded_channel();
+		(ChannelTransport { tx: tx_a, rx: rx_a }, ChannelTransport { tx: tx_b, rx: rx_b })
+	}
+
+	impl Transport for ChannelTransport {
+		type Body = StreamBody;
+		type Info = ();
+		type Config = ();
+		type ReadHalf<'a> = ChannelReadHalf<'a>;
+		type WriteHalf<'a> = ChannelWriteHalf<'a>;
+
+		fn split(&mut self) -> (Self::ReadHalf<'_>, Self::WriteHalf<'_>) {
+			(ChannelReadHalf(&mut self.rx), ChannelWriteHalf(&self.tx))
+		}
+
+		fn info(&self) -> std::io::Result<Self::Info> {
+			Ok(())
+		}
+	}
+
+	impl TransportReadHalf for ChannelReadHalf<'_> {
+		type Body = StreamBody;
+
+		fn poll_read_msg(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<Message<Self::Body>, TransportError>> {
+			match std::task::ready!(self.get_mut().0.poll_recv(context)) {
+				Some(message) => Poll::Ready(Ok(message)),
+				None => Poll::Ready(Err(TransportError::new_fatal(std::io::Error::from(std::io::ErrorKind::ConnectionAborted)))),
+			}
+		}
+	}
+
+	impl TransportWriteHalf for ChannelWriteHalf<'_> {
+		type Body = StreamBody;
+
+		fn poll_write_msg(self: Pin<&mut Self>, _context: &mut Context, header: &MessageHeader, body: &Self::Body) -> Poll<Result<(), TransportError>> {
+			match self.0.send(Message::new(*header, body.clone())) {
+				Ok(()) => Poll::Ready(Ok(())),
+				Err(_) => Poll::Ready(Err(TransportError::new_fatal(std::io::Error::from(std::io::ErrorKind::BrokenPipe)))),
+			}
+		}
+
+		fn poll_flush(self: Pin<&mut Self>, _context: &mut Context) -> Poll<Result<(), TransportError>> {
+			Poll::Ready(Ok(()))
+		}
+
+		fn poll_shutdown(self: Pin<&mut Self>, _context: &mut Context) -> Poll<Result<(), TransportError>> {
+			Poll::Ready(Ok(()))
+		}
+	}
+
+	/// Test that peers and their timers work without a tokio runtime.
+	#[test]
+	fn peer_without_tokio() {
+		let (transport_a, transport_b) = channel_transport_pair();
+		let handle_a = Peer::spawn_on(transport_a, ThreadRuntime);
+		let (peer_b, mut handle_b) = Peer::new(transport_b);
+		let peer_b = peer_b
+			.with_request_expiry(Some(Duration::from_millis(20)))
+			.with_runtime(ThreadRuntime);
+		ThreadRuntime.spawn(Box::pin(peer_b.run()));
+
+		// A request that is not picked up in time gets a retry-after response.
+		let_assert!(Ok(mut sent_request) = handle_a.blocking_send_request(1, &b"hello"[..]));
+		let_assert!(Ok(response) = sent_request.blocking_recv_response());
+		let_assert!(Err(e) = response.check_error_response());
+		assert!(e.as_retry_after() == Some(Duration::from_millis(20)));
+
+		// Requests that are picked up in time can be answered normally.
+		let_assert!(Ok(mut sent_request) = handle_a.blocking_send_request(2, &b"hello"[..]));
+		let_assert!(Ok(ReceivedMessage::Request(received_request, body)) = crate::util::block_on(handle_b.recv_message()));
+		assert!(received_request.service_id() == 2);
+		assert!(body.as_ref() == b"hello");
+		let_assert!(Ok(()) = received_request.blocking_send_response(3, &b"world"[..]));
+		let_assert!(Ok(response) = sent_request.blocking_recv_response());
+		assert!(response.header.service_id == 3);
+		assert!(response.body.as_ref() == b"world");
+	}
+}
@@ -0,0 +1,105 @@
+//! Socket activation by a service manager, like systemd.
+//!
+//! With socket activation, the service manager creates and binds the listening sockets,
+//! and passes them to the daemon when it is started.
+//! This lets the service manager start the daemon on demand, and keeps the sockets open while the daemon restarts.
+//!
+//! The sockets are passed as file descriptors starting at [`LISTEN_FDS_START`],
+//! and described by the `LISTEN_PID` and `LISTEN_FDS` environment variables.
+//! See the [`sd_listen_fds(3)`](https://www.freedesktop.org/software/systemd/man/sd_listen_fds.html) manual page for the details.
+//!
+//! Use [`listen_fds()`] to take the file descriptors,
+//! and [`Listener::from_fd()`][crate::Listener::from_fd] to create a listener for each of them:
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use fizyr_rpc::UnixStreamListener;
+//!
+//! let mut fds = fizyr_rpc::socket_activation::listen_fds()?;
+//! let fd = fds.pop().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no socket passed by the service manager"))?;
+//! let mut listener = UnixStreamListener::from_fd(fd, Default::default())?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::os::unix::io::{OwnedFd, RawFd};
+
+/// The first file descriptor passed by the service manager.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// Take the file descriptors of the listening sockets passed by the service manager.
+///
+/// Returns an empty list if the process was not started with socket activation.
+/// Returns an error if the environment variables are set, but they are not valid.
+///
+/// The `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES` environment variables are removed,
+/// so that child processes do not try to use the file descriptors,
+/// and the file descriptors are marked close-on-exec.
+/// Because this modifies the environment, you should call it early in `main()`, before any other threads are started.
+///
+/// The file descriptors are owned by the returned values,
+/// so this function should be called only once.
+/// Later calls return an empty list, because the environment variables have been removed.
+pub fn listen_fds() -> std::io::Result<Vec<OwnedFd>> {
+	let listen_pid = std::env::var("LISTEN_PID").ok();
+	let listen_fds = std::env::var("LISTEN_FDS").ok();
+	std::env::remove_var("LISTEN_PID");
+	std::env::remove_var("LISTEN_FDS");
+	std::env::remove_var("LISTEN_FDNAMES");
+
+	let count = listen_fds_count(listen_pid.as_deref(), listen_fds.as_deref(), std::process::id())?;
+	let mut fds = Vec::with_capacity(count);
+	for fd in LISTEN_FDS_START..LISTEN_FDS_START + count as RawFd {
+		// SAFETY: The service manager passed the file descriptors to this process.
+		// The environment variables have been removed, so nothing else takes ownership of them.
+		let fd = unsafe { filedesc::FileDesc::from_raw_fd(fd) };
+		fd.set_close_on_exec(true)?;
+		fds.push(fd.into_fd());
+	}
+	Ok(fds)
+}
+
+/// Get the number of file descriptors passed to the process with the given PID from the environment variables.
+fn listen_fds_count(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> std::io::Result<usize> {
+	let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+		return Ok(0);
+	};
+	let listen_pid: u32 = listen_pid.parse()
+		.map_err(|_| invalid_variable("LISTEN_PID", listen_pid))?;
+	// The variables were meant for a different process, for example our parent.
+	if listen_pid != pid {
+		return Ok(0);
+	}
+	let count: usize = listen_fds.parse()
+		.map_err(|_| invalid_variable("LISTEN_FDS", listen_fds))?;
+	if count > (RawFd::MAX - LISTEN_FDS_START) as usize {
+		return Err(invalid_variable("LISTEN_FDS", listen_fds));
+	}
+	Ok(count)
+}
+
+/// Create an error for an environment variable with an invalid value.
+fn invalid_variable(name: &str, value: &str) -> std::io::Error {
+	std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid value for {name} environment variable: {value:?}"))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+
+	#[test]
+	fn count_listen_fds() {
+		assert!(let Ok(0) = listen_fds_count(None, None, 10));
+		assert!(let Ok(0) = listen_fds_count(Some("10"), None, 10));
+		assert!(let Ok(2) = listen_fds_count(Some("10"), Some("2"), 10));
+		assert!(let Ok(0) = listen_fds_count(Some("11"), Some("2"), 10));
+
+		let_assert!(Err(e) = listen_fds_count(Some("ten"), Some("2"), 10));
+		assert!(e.kind() == std::io::ErrorKind::InvalidData);
+		assert!(e.to_string() == "invalid value for LISTEN_PID environment variable: \"ten\"");
+		let_assert!(Err(e) = listen_fds_count(Some("10"), Some("-1"), 10));
+		assert!(e.to_string() == "invalid value for LISTEN_FDS environment variable: \"-1\"");
+	}
+}
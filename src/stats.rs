@@ -0,0 +1,276 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::{ConnectionLabels, TrackerAudit};
+
+/// Snapshot of the performance counters of a peer.
+///
+/// Use [`PeerHandle::stats()`][crate::PeerHandle::stats] or one of the similar functions on the other handles to get the counters.
+///
+/// The counters are updated by the peer loop while it runs, so a snapshot may be slightly out of date.
+/// Compare two snapshots to compute rates, like messages per second.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct PeerStats {
+	/// The number of messages written to the transport.
+	///
+	/// This includes messages generated by the peer itself, like acknowledgements and error responses.
+	pub messages_sent: u64,
+
+	/// The number of messages read from the transport.
+	pub messages_received: u64,
+
+	/// The number of bytes written to the transport, including message headers.
+	///
+	/// This is the size of the messages before any compression by the transport.
+	pub bytes_sent: u64,
+
+	/// The number of bytes read from the transport, including message headers.
+	///
+	/// This is the size of the messages after any decompression by the transport.
+	pub bytes_received: u64,
+
+	/// The number of sent requests that are still waiting for a response.
+	pub open_sent_requests: usize,
+
+	/// The number of received requests that have not been answered yet.
+	pub open_received_requests: usize,
+
+	/// The number of received requests and stream messages waiting to be picked up by the read handle.
+	///
+	/// A steadily growing queue means that the application can not keep up with the incoming messages.
+	pub incoming_queue_len: usize,
+
+	/// The number of commands from the handles waiting to be processed by the peer loop.
+	///
+	/// This includes stream messages held back by a [`BoundedLatencyPolicy`][crate::BoundedLatencyPolicy].
+	/// The peer loop updates this before it processes each command,
+	/// so it is out of date while the peer loop is blocked, for example on a saturated transport.
+	pub command_queue_len: usize,
+
+	/// The number of times writing a message or batch of messages was retried after a non-fatal error.
+	///
+	/// See [`WriteRetryPolicy`][crate::WriteRetryPolicy].
+	pub write_retries: u64,
+
+	/// The number of stream messages that were dropped because they were queued for too long.
+	///
+	/// See [`BoundedLatencyPolicy`][crate::BoundedLatencyPolicy].
+	pub dropped_stream_messages: u64,
+}
+
+/// Report of the state of a connection, for diagnosing misbehaving connections.
+///
+/// Use [`PeerHandle::diagnose()`][crate::PeerHandle::diagnose] or [`PeerWriteHandle::diagnose()`][crate::PeerWriteHandle::diagnose] to get a report.
+/// The [`Display`][std::fmt::Display] implementation gives a human readable summary that can be attached to a bug report.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PeerDiagnostics {
+	/// The process-wide unique ID of the connection.
+	pub connection_id: u64,
+
+	/// The labels of the connection.
+	pub labels: ConnectionLabels,
+
+	/// The time it took the peer loop to process a command queued by the diagnosis.
+	///
+	/// This includes the time to process all commands that were queued before it.
+	/// It is [`None`] if the peer loop is no longer running.
+	pub command_loop_latency: Option<Duration>,
+
+	/// The orphaned requests found by an [audit][crate::PeerWriteHandle::audit] of the peer loop.
+	///
+	/// It is [`None`] if the peer loop is no longer running.
+	pub audit: Option<TrackerAudit>,
+
+	/// The time since the last message was written to the transport, or [`None`] if no message was written yet.
+	pub since_last_sent: Option<Duration>,
+
+	/// The time since the last message was read from the transport, or [`None`] if no message was read yet.
+	pub since_last_received: Option<Duration>,
+
+	/// The performance counters of the peer, taken after the audit.
+	pub stats: PeerStats,
+}
+
+impl PeerDiagnostics {
+	/// Check if the peer loop was running when the report was made.
+	pub fn is_running(&self) -> bool {
+		self.command_loop_latency.is_some()
+	}
+}
+
+impl std::fmt::Display for PeerDiagnostics {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "connection {}", self.connection_id)?;
+		if !self.labels.is_empty() {
+			write!(f, " ({})", self.labels)?;
+		}
+		writeln!(f)?;
+
+		match self.command_loop_latency {
+			Some(latency) => writeln!(f, "peer loop: running, responded in {latency:?}")?,
+			None => writeln!(f, "peer loop: stopped")?,
+		}
+
+		let stats = &self.stats;
+		write!(f, "open requests: {} sent, {} received", stats.open_sent_requests, stats.open_received_requests)?;
+		if let Some(audit) = &self.audit {
+			write!(
+				f,
+				" ({} orphaned sent, {} orphaned received)",
+				audit.orphaned_sent_requests.len(),
+				audit.orphaned_received_requests.len()
+			)?;
+		}
+		writeln!(f)?;
+		writeln!(f, "queues: {} commands, {} incoming", stats.command_queue_len, stats.incoming_queue_len)?;
+
+		write!(f, "last message sent: ")?;
+		write_since(f, self.since_last_sent)?;
+		write!(f, ", last message received: ")?;
+		write_since(f, self.since_last_received)?;
+		writeln!(f)?;
+
+		write!(
+			f,
+			"messages: {} sent ({} bytes), {} received ({} bytes), {} write retries, {} dropped stream messages",
+			stats.messages_sent,
+			stats.bytes_sent,
+			stats.messages_received,
+			stats.bytes_received,
+			stats.write_retries,
+			stats.dropped_stream_messages
+		)
+	}
+}
+
+/// Write the time since an event for the [`PeerDiagnostics`] report.
+fn write_since(f: &mut std::fmt::Formatter<'_>, since: Option<Duration>) -> std::fmt::Result {
+	match since {
+		Some(since) => write!(f, "{since:?} ago"),
+		None => write!(f, "never"),
+	}
+}
+
+/// Performance counters shared between the peer loop and the handles.
+#[derive(Debug)]
+pub(crate) struct StatsCounters {
+	messages_sent: AtomicU64,
+	messages_received: AtomicU64,
+	bytes_sent: AtomicU64,
+	bytes_received: AtomicU64,
+	open_sent_requests: AtomicUsize,
+	open_received_requests: AtomicUsize,
+	incoming_queue_len: AtomicUsize,
+	write_retries: AtomicU64,
+	dropped_stream_messages: AtomicU64,
+	command_queue_len: AtomicUsize,
+
+	/// The time the counters were created, used as reference for the activity timestamps.
+	created: Instant,
+
+	/// Microseconds after `created` plus one at which the last message was sent, or zero if none was sent yet.
+	last_sent: AtomicU64,
+
+	/// Microseconds after `created` plus one at which the last message was received, or zero if none was received yet.
+	last_received: AtomicU64,
+}
+
+impl Default for StatsCounters {
+	fn default() -> Self {
+		Self {
+			messages_sent: AtomicU64::new(0),
+			messages_received: AtomicU64::new(0),
+			bytes_sent: AtomicU64::new(0),
+			bytes_received: AtomicU64::new(0),
+			open_sent_requests: AtomicUsize::new(0),
+			open_received_requests: AtomicUsize::new(0),
+			incoming_queue_len: AtomicUsize::new(0),
+			write_retries: AtomicU64::new(0),
+			dropped_stream_messages: AtomicU64::new(0),
+			command_queue_len: AtomicUsize::new(0),
+			created: Instant::now(),
+			last_sent: AtomicU64::new(0),
+			last_received: AtomicU64::new(0),
+		}
+	}
+}
+
+impl StatsCounters {
+	/// Take a snapshot of the counters.
+	pub fn snapshot(&self) -> PeerStats {
+		PeerStats {
+			messages_sent: self.messages_sent.load(Ordering::Relaxed),
+			messages_received: self.messages_received.load(Ordering::Relaxed),
+			bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+			bytes_received: self.bytes_received.load(Ordering::Relaxed),
+			open_sent_requests: self.open_sent_requests.load(Ordering::Relaxed),
+			open_received_requests: self.open_received_requests.load(Ordering::Relaxed),
+			incoming_queue_len: self.incoming_queue_len.load(Ordering::Relaxed),
+			write_retries: self.write_retries.load(Ordering::Relaxed),
+			dropped_stream_messages: self.dropped_stream_messages.load(Ordering::Relaxed),
+			command_queue_len: self.command_queue_len.load(Ordering::Relaxed),
+		}
+	}
+
+	/// Get the time since the last message was sent and received.
+	pub fn since_last_activity(&self) -> (Option<Duration>, Option<Duration>) {
+		let now = self.created.elapsed();
+		let since = |timestamp: &AtomicU64| match timestamp.load(Ordering::Relaxed) {
+			0 => None,
+			micros => Some(now.saturating_sub(Duration::from_micros(micros - 1))),
+		};
+		(since(&self.last_sent), since(&self.last_received))
+	}
+
+	/// Get the current time as activity timestamp.
+	fn timestamp(&self) -> u64 {
+		self.created.elapsed().as_micros() as u64 + 1
+	}
+
+	/// Count a message written to the transport.
+	pub fn message_sent(&self, len: usize) {
+		self.messages_sent.fetch_add(1, Ordering::Relaxed);
+		self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+		self.last_sent.store(self.timestamp(), Ordering::Relaxed);
+	}
+
+	/// Count a message read from the transport.
+	pub fn message_received(&self, len: usize) {
+		self.messages_received.fetch_add(1, Ordering::Relaxed);
+		self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+		self.last_received.store(self.timestamp(), Ordering::Relaxed);
+	}
+
+	/// Set the number of open requests.
+	pub fn set_open_requests(&self, sent: usize, received: usize) {
+		self.open_sent_requests.store(sent, Ordering::Relaxed);
+		self.open_received_requests.store(received, Ordering::Relaxed);
+	}
+
+	/// Set the number of commands waiting to be processed by the peer loop.
+	pub fn set_command_queue_len(&self, len: usize) {
+		self.command_queue_len.store(len, Ordering::Relaxed);
+	}
+
+	/// Count a message added to the incoming queue.
+	pub fn incoming_queued(&self) {
+		self.incoming_queue_len.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Count a message removed from the incoming queue.
+	pub fn incoming_dequeued(&self) {
+		self.incoming_queue_len.fetch_sub(1, Ordering::Relaxed);
+	}
+
+	/// Count a retried write.
+	pub fn write_retried(&self) {
+		self.write_retries.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Count stream messages dropped because they were queued for too long.
+	pub fn streams_dropped(&self, count: usize) {
+		self.dropped_stream_messages.fetch_add(count as u64, Ordering::Relaxed);
+	}
+}
@@ -1,3 +1,6 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
 /// The endianness to use for encoding header fields.
 ///
 /// The encoding and serialization of message bodies is up to the application code,
@@ -60,6 +63,102 @@ impl Endian {
 	}
 }
 
+/// The endianness of a transport, shared by the read and write half.
+///
+/// If detection is enabled, the endianness is detected from the first unambiguous message received from the remote peer.
+/// After that, the detected endianness is used to encode and decode all messages.
+#[derive(Debug, Clone)]
+#[allow(dead_code)] // Not used when transports are disabled.
+pub(crate) struct EndianState {
+	/// The configured endianness, used until the endianness of the remote peer is detected.
+	configured: Endian,
+
+	/// If true, detect the endianness of the remote peer.
+	detect: bool,
+
+	/// The detected endianness: 0 if not detected yet, 1 for little endian and 2 for big endian.
+	detected: Arc<AtomicU8>,
+}
+
+#[allow(dead_code)] // Not used when transports are disabled.
+impl EndianState {
+	/// Create a new endian state.
+	pub fn new(configured: Endian, detect: bool) -> Self {
+		Self {
+			configured,
+			detect,
+			detected: Arc::new(AtomicU8::new(0)),
+		}
+	}
+
+	/// Get the endianness to use for encoding and decoding messages.
+	pub fn current(&self) -> Endian {
+		match self.detected.load(Ordering::Relaxed) {
+			1 => Endian::LittleEndian,
+			2 => Endian::BigEndian,
+			_ => self.configured,
+		}
+	}
+
+	/// Detect the endianness of the remote peer from a received message, if it was not detected yet.
+	///
+	/// The `header` must hold the encoded message header.
+	/// The bits in `type_mask` are the only bits of the message type field that hold the message type.
+	/// Byte-stream transports also pass the raw frame length field and the maximum body length to check it against.
+	///
+	/// Returns the endianness to use for decoding the message.
+	pub fn detect(&self, header: &[u8], type_mask: u32, frame: Option<(&[u8], u32)>) -> Endian {
+		if !self.detect || self.detected.load(Ordering::Relaxed) != 0 {
+			return self.current();
+		}
+
+		// Check which endianness gives a valid message, and prefer the one with smaller IDs.
+		// If both are equally plausible, the message does not tell us anything yet.
+		let little = plausibility(Endian::LittleEndian, header, type_mask, frame);
+		let big = plausibility(Endian::BigEndian, header, type_mask, frame);
+		let detected = match (little, big) {
+			(Some(little), Some(big)) if little < big => Endian::LittleEndian,
+			(Some(little), Some(big)) if big < little => Endian::BigEndian,
+			(Some(_), None) => Endian::LittleEndian,
+			(None, Some(_)) => Endian::BigEndian,
+			_ => return self.current(),
+		};
+
+		trace_event!(debug, endian = ?detected, "detected endianness of remote peer");
+		let value = match detected {
+			Endian::BigEndian => 2,
+			_ => 1,
+		};
+		self.detected.store(value, Ordering::Relaxed);
+		detected
+	}
+}
+
+/// Check if a message header is valid when decoded with the given endianness.
+///
+/// Returns `None` if the header is not valid.
+/// Otherwise, returns the largest of the request ID and the absolute service ID.
+/// Decoding with the wrong endianness normally gives much larger IDs.
+#[allow(dead_code)] // Not used when transports are disabled.
+fn plausibility(endian: Endian, header: &[u8], type_mask: u32, frame: Option<(&[u8], u32)>) -> Option<u32> {
+	// Header flags are not taken into account, so messages with flags are never used for detection.
+	let message_type = endian.read_u32(&header[0..]);
+	if message_type & !type_mask != 0 || crate::MessageType::from_u32(message_type & type_mask).is_err() {
+		return None;
+	}
+
+	if let Some((length, max_body_len)) = frame {
+		let body_len = endian.read_u32(length).checked_sub(crate::HEADER_LEN)?;
+		if body_len > max_body_len {
+			return None;
+		}
+	}
+
+	let request_id = endian.read_u32(&header[4..]);
+	let service_id = endian.read_i32(&header[8..]);
+	Some(request_id.max(service_id.unsigned_abs()))
+}
+
 #[cfg(test)]
 mod test {
 	use super::Endian;
@@ -173,4 +272,36 @@ mod test {
 		#[cfg(target_endian = "big")]
 		assert!(Endian::NativeEndian.read_i32(&[0x81, 0x02, 0x03, 0x05]) == -0x7efdfcfb);
 	}
+
+	#[test]
+	fn detect_endian() {
+		use super::EndianState;
+		use crate::MessageHeader;
+
+		let mut header = [0u8; 12];
+		let mut length = [0u8; 4];
+
+		// A big endian request for service 3.
+		MessageHeader::request(0, 3).encode(&mut header, Endian::BigEndian);
+		Endian::BigEndian.write_u32(&mut length, 20);
+		let state = EndianState::new(Endian::LittleEndian, true);
+		assert!(state.detect(&header, !0xFF00, Some((&length, 1024))) == Endian::BigEndian);
+		assert!(state.current() == Endian::BigEndian);
+
+		// Once detected, the endianness does not change anymore.
+		MessageHeader::request(0, 3).encode(&mut header, Endian::LittleEndian);
+		assert!(state.detect(&header, !0, None) == Endian::BigEndian);
+
+		// Without detection, the configured endianness is always used.
+		let state = EndianState::new(Endian::LittleEndian, false);
+		MessageHeader::response(1, 2).encode(&mut header, Endian::BigEndian);
+		assert!(state.detect(&header, !0, None) == Endian::LittleEndian);
+
+		// A message that reads the same in both endiannesses does not tell us anything.
+		let state = EndianState::new(Endian::LittleEndian, true);
+		MessageHeader::request(0, 0).encode(&mut header, Endian::BigEndian);
+		assert!(state.detect(&header, !0, None) == Endian::LittleEndian);
+		MessageHeader::response(7, -1).encode(&mut header, Endian::BigEndian);
+		assert!(state.detect(&header, !0, None) == Endian::BigEndian);
+	}
 }
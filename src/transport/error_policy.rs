@@ -0,0 +1,202 @@
+use crate::{service_id, MessageHeader, MessageType};
+
+/// Validation for error messages received from a remote peer.
+///
+/// Error responses contain arbitrary strings chosen by the remote peer.
+/// When the remote peer is not trusted, these messages should be sanitized before they end up in logs or on a terminal.
+///
+/// The policy is applied by the transport to the body of all error responses and retry-after responses,
+/// before they are turned into a [remote error][crate::Error::remote_error].
+/// For error responses with an error code and for retry-after responses, the policy is only applied to the error message,
+/// so that the error code, payload and retry delay stay intact.
+///
+/// The default policy does not modify error messages.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct RemoteErrorPolicy {
+	/// The maximum length of an error message in bytes.
+	///
+	/// Longer messages are truncated.
+	/// If the message is valid UTF-8, it is truncated at a character boundary.
+	pub max_len: Option<usize>,
+
+	/// Replace invalid UTF-8 sequences with the Unicode replacement character.
+	///
+	/// If this is disabled, error messages with invalid UTF-8 are rejected when they are decoded.
+	pub replace_invalid_utf8: bool,
+
+	/// Remove all control characters from error messages.
+	///
+	/// This removes line breaks and the escape character,
+	/// which prevents log injection and terminal escape sequences.
+	///
+	/// Control characters can only be removed from valid UTF-8 messages.
+	pub strip_control_characters: bool,
+}
+
+impl RemoteErrorPolicy {
+	/// Create a policy that does not modify error messages.
+	pub fn none() -> Self {
+		Self::default()
+	}
+
+	/// Create a strict policy for untrusted peers.
+	///
+	/// The strict policy replaces invalid UTF-8, removes control characters
+	/// and limits error messages to `max_len` bytes.
+	pub fn strict(max_len: usize) -> Self {
+		Self {
+			max_len: Some(max_len),
+			replace_invalid_utf8: true,
+			strip_control_characters: true,
+		}
+	}
+
+	/// Check if the policy leaves all error messages untouched.
+	pub fn is_none(&self) -> bool {
+		self == &Self::none()
+	}
+
+	/// Apply the policy to an error message.
+	pub fn apply(&self, message: Vec<u8>) -> Vec<u8> {
+		let message = match String::from_utf8(message) {
+			Ok(message) => message,
+			Err(e) if self.replace_invalid_utf8 => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+			Err(e) => {
+				let mut message = e.into_bytes();
+				if let Some(max_len) = self.max_len {
+					message.truncate(max_len);
+				}
+				return message;
+			},
+		};
+
+		let mut message = if self.strip_control_characters {
+			message.chars().filter(|c| !c.is_control()).collect()
+		} else {
+			message
+		};
+
+		if let Some(max_len) = self.max_len {
+			if message.len() > max_len {
+				let mut len = max_len;
+				while !message.is_char_boundary(len) {
+					len -= 1;
+				}
+				message.truncate(len);
+			}
+		}
+
+		message.into_bytes()
+	}
+
+	/// Apply the policy to the body of a message if it is an error response or retry-after response.
+	#[allow(dead_code)] // Not used when transports are disabled.
+	pub(crate) fn apply_to_message(&self, header: &MessageHeader, body: &mut Vec<u8>) {
+		if header.message_type != MessageType::Response || self.is_none() {
+			return;
+		}
+		match header.service_id {
+			service_id::ERROR => {
+				*body = self.apply(std::mem::take(body));
+			},
+			service_id::CODED_ERROR | service_id::RETRY_AFTER => {
+				// Keep the error code and payload or the retry delay, they are validated when the response is parsed.
+				if let Some(space) = body.iter().position(|&byte| byte == b' ') {
+					let message = self.apply(body.split_off(space + 1));
+					body.extend_from_slice(&message);
+				}
+			},
+			_ => (),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+
+	#[test]
+	fn default_policy_does_nothing() {
+		let policy = RemoteErrorPolicy::default();
+		assert!(policy.is_none());
+		assert!(policy.apply(b"line 1\nline 2\x1b[31m".to_vec()) == b"line 1\nline 2\x1b[31m");
+		assert!(policy.apply(b"invalid \xff".to_vec()) == b"invalid \xff");
+	}
+
+	#[test]
+	fn replace_invalid_utf8() {
+		let policy = RemoteErrorPolicy {
+			replace_invalid_utf8: true,
+			..Default::default()
+		};
+		assert!(policy.apply(b"invalid \xff".to_vec()) == "invalid \u{FFFD}".as_bytes());
+	}
+
+	#[test]
+	fn strip_control_characters() {
+		let policy = RemoteErrorPolicy {
+			strip_control_characters: true,
+			..Default::default()
+		};
+		assert!(policy.apply(b"line 1\nline 2\x1b[31m\x07".to_vec()) == b"line 1line 2[31m");
+	}
+
+	#[test]
+	fn truncate_at_char_boundary() {
+		let policy = RemoteErrorPolicy {
+			max_len: Some(5),
+			..Default::default()
+		};
+		assert!(policy.apply(b"abc".to_vec()) == b"abc");
+		assert!(policy.apply("abcd\u{00e9}".as_bytes().to_vec()) == b"abcd");
+		assert!(policy.apply(b"abcd\xff\xff".to_vec()) == b"abcd\xff");
+	}
+
+	#[test]
+	fn only_error_responses_are_modified() {
+		let policy = RemoteErrorPolicy::strict(4);
+		let mut body = b"hello world".to_vec();
+		policy.apply_to_message(&MessageHeader::response(1, 2), &mut body);
+		assert!(body == b"hello world");
+		policy.apply_to_message(&MessageHeader::responder_update(1, service_id::ERROR), &mut body);
+		assert!(body == b"hello world");
+		policy.apply_to_message(&MessageHeader::error_response(1), &mut body);
+		assert!(body == b"hell");
+
+		let mut body = b"42:0aff camera\nbusy".to_vec();
+		policy.apply_to_message(&MessageHeader::coded_error_response(1), &mut body);
+		assert!(body == b"42:0aff came");
+
+		let mut body = b"123456 camera\nbusy".to_vec();
+		policy.apply_to_message(&MessageHeader::retry_after_response(1), &mut body);
+		assert!(body == b"123456 came");
+		let mut body = b"123456".to_vec();
+		policy.apply_to_message(&MessageHeader::retry_after_response(1), &mut body);
+		assert!(body == b"123456");
+	}
+
+	#[tokio::test]
+	async fn sanitize_received_error_response() {
+		use crate::{ReceivedMessage, StreamConfig, UnixStreamPeer, UnixStreamTransport};
+		use assert2::let_assert;
+
+		let config = StreamConfig {
+			error_policy: RemoteErrorPolicy::strict(32),
+			..Default::default()
+		};
+
+		let_assert!(Ok((peer_a, peer_b)) = tokio::net::UnixStream::pair());
+		let peer_a = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, config));
+		let mut peer_b = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default()));
+
+		let_assert!(Ok(mut request) = peer_a.send_request(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received, _body)) = peer_b.recv_message().await);
+		assert!(let Ok(()) = received.send_error_response("failed\n\x1b[2Jto\x00 comply").await);
+
+		let_assert!(Ok(response) = request.recv_response().await);
+		let_assert!(Err(e) = response.check_error_response());
+		assert!(e.as_remote_error() == Some("failed[2Jto comply"));
+	}
+}
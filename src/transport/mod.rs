@@ -14,22 +14,43 @@ use crate::{Error, Message, MessageHeader};
 
 mod endian;
 pub use endian::Endian;
+pub(crate) use endian::EndianState;
+
+mod error_policy;
+pub use error_policy::RemoteErrorPolicy;
+
+mod rate_limit;
+pub use rate_limit::{RateLimit, RateLimitedReadHalf, RateLimitedTransport, RateLimitedWriteHalf};
+
+pub mod trace;
+pub use trace::WireTrace;
+
+pub mod util;
 
 pub(crate) mod stream;
-pub use stream::StreamTransport;
+pub use stream::{frame, BodySink, Compression, StreamTransport};
 
 #[cfg(feature = "tcp")]
-pub use stream::TcpStreamInfo;
+pub use stream::{Proxy, ProxyProtocol, TcpStreamInfo};
 
 #[cfg(feature = "unix-stream")]
 pub use stream::UnixStreamInfo;
 
+#[cfg(feature = "generic-stream")]
+pub use stream::{GenericReadHalf, GenericStream, GenericWriteHalf};
+
+#[cfg(feature = "quic")]
+pub use stream::{QuicEndpoint, QuicStream, QuicStreamInfo};
+
 pub(crate) mod unix;
-pub use unix::UnixTransport;
+pub use unix::{FdPolicy, UnixTransport};
 
 #[cfg(feature = "unix-seqpacket")]
 pub use unix::UnixSeqpacketInfo;
 
+#[cfg(feature = "unix-datagram")]
+pub use unix::{UnixDatagramInfo, UnixDatagramReadHalf, UnixDatagramTransport, UnixDatagramWriteHalf};
+
 /// Trait for types that represent a bi-direction message transport.
 ///
 /// Note that you can not use the transport itself directly.
@@ -58,6 +79,14 @@ pub trait Transport: Send + 'static {
 	/// For TCP streams, this includes a socket address with an IP address and port number.
 	/// For Unix streams and seqpacket streams this includes the credentials of the remote process.
 	fn info(&self) -> std::io::Result<Self::Info>;
+
+	/// Get a short human readable description of the peer on the other end of the transport.
+	///
+	/// The description is used in the names of spawned tasks and in tracing spans.
+	/// The default implementation returns [`None`].
+	fn describe_remote(&self) -> Option<String> {
+		None
+	}
 }
 
 /// An error from the transport layer.
@@ -77,7 +106,7 @@ impl TransportError {
 	/// Create a new fatal transport error from an inner error.
 	///
 	/// After a transport returns a fatal error, the transport should not be used anymore.
-	fn new_fatal(inner: impl Into<Error>) -> Self {
+	pub(crate) fn new_fatal(inner: impl Into<Error>) -> Self {
 		Self {
 			inner: inner.into(),
 			is_fatal: true,
@@ -87,7 +116,7 @@ impl TransportError {
 	/// Create a new non-fatal transport error from an inner error.
 	///
 	/// A transport may still be used after returning a non-fatal error.
-	fn new_non_fatal(inner: impl Into<Error>) -> Self {
+	pub(crate) fn new_non_fatal(inner: impl Into<Error>) -> Self {
 		Self {
 			inner: inner.into(),
 			is_fatal: false,
@@ -135,7 +164,7 @@ pub trait TransportReadHalf: Send + Unpin {
 	fn poll_read_msg(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<Message<Self::Body>, TransportError>>;
 
 	/// Asynchronously read a complete message from the transport.
-	fn read_msg(&mut self) -> ReadMsg<Self>
+	fn read_msg(&mut self) -> ReadMsg<'_, Self>
 	where
 		Self: Unpin,
 	{
@@ -164,9 +193,73 @@ pub trait TransportWriteHalf: Send + Unpin {
 	fn poll_write_msg(self: Pin<&mut Self>, context: &mut Context, header: &MessageHeader, body: &Self::Body) -> Poll<Result<(), TransportError>>;
 
 	/// Asynchronously write a message to the transport.
-	fn write_msg<'c>(&'c mut self, header: &'c MessageHeader, body: &'c Self::Body) -> WriteMsg<Self> {
+	fn write_msg<'c>(&'c mut self, header: &'c MessageHeader, body: &'c Self::Body) -> WriteMsg<'c, Self> {
 		WriteMsg { inner: self, header, body }
 	}
+
+	/// Try to write a batch of messages to the transport without blocking.
+	///
+	/// The `written` parameter holds the number of messages from the batch that have been written completely.
+	/// Writing starts at `messages[*written]`, and `written` is updated as messages are written.
+	/// If an error is returned, the message at `messages[*written]` is the one that failed.
+	///
+	/// Implementations may coalesce the messages into fewer writes to the underlying transport.
+	/// The default implementation writes the messages one by one with [`Self::poll_write_msg()`].
+	///
+	/// As with [`Self::poll_write_msg()`], it is an error to change the messages between invocations
+	/// as long as the function returns [`Poll::Pending`].
+	fn poll_write_msgs(self: Pin<&mut Self>, context: &mut Context, messages: &[Message<Self::Body>], written: &mut usize) -> Poll<Result<(), TransportError>> {
+		let this = self.get_mut();
+		while let Some(message) = messages.get(*written) {
+			ready!(Pin::new(&mut *this).poll_write_msg(context, &message.header, &message.body))?;
+			*written += 1;
+		}
+		Poll::Ready(Ok(()))
+	}
+
+	/// Asynchronously write a batch of messages to the transport.
+	///
+	/// See [`Self::poll_write_msgs()`] for the meaning of the `written` parameter.
+	fn write_msgs<'c>(&'c mut self, messages: &'c [Message<Self::Body>], written: &'c mut usize) -> WriteMsgs<'c, Self> {
+		WriteMsgs { inner: self, messages, written }
+	}
+
+	/// Try to flush all buffered data to the underlying transport without blocking.
+	///
+	/// A message written with [`Self::poll_write_msg()`] may still be buffered by the transport,
+	/// for example by an encryption layer.
+	/// After this function returns [`Poll::Ready`] with `Ok(())`, all messages written before have been passed to the underlying transport.
+	///
+	/// The default implementation does nothing, for transports that do not buffer outgoing data.
+	fn poll_flush(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), TransportError>> {
+		let _ = context;
+		Poll::Ready(Ok(()))
+	}
+
+	/// Asynchronously flush all buffered data to the underlying transport.
+	///
+	/// See [`Self::poll_flush()`] for more details.
+	fn flush(&mut self) -> Flush<'_, Self> {
+		Flush { inner: self }
+	}
+
+	/// Try to shut down the write side of the transport without blocking.
+	///
+	/// This flushes any buffered data and signals the remote peer that no more messages will be sent,
+	/// while the read half can still be used to receive messages.
+	///
+	/// The default implementation does nothing, for transports that do not support a write-side shutdown.
+	fn poll_shutdown(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), TransportError>> {
+		let _ = context;
+		Poll::Ready(Ok(()))
+	}
+
+	/// Asynchronously shut down the write side of the transport.
+	///
+	/// See [`Self::poll_shutdown()`] for more details.
+	fn shutdown(&mut self) -> Shutdown<'_, Self> {
+		Shutdown { inner: self }
+	}
 }
 
 /// Future type for [`TransportReadHalf::read_msg`].
@@ -187,6 +280,32 @@ where
 	body: &'c T::Body,
 }
 
+/// Future type for [`TransportWriteHalf::write_msgs`].
+pub struct WriteMsgs<'c, T>
+where
+	T: TransportWriteHalf + ?Sized,
+{
+	inner: &'c mut T,
+	messages: &'c [Message<T::Body>],
+	written: &'c mut usize,
+}
+
+/// Future type for [`TransportWriteHalf::flush`].
+pub struct Flush<'c, T>
+where
+	T: TransportWriteHalf + ?Sized,
+{
+	inner: &'c mut T,
+}
+
+/// Future type for [`TransportWriteHalf::shutdown`].
+pub struct Shutdown<'c, T>
+where
+	T: TransportWriteHalf + ?Sized,
+{
+	inner: &'c mut T,
+}
+
 impl<T> Future for ReadMsg<'_, T>
 where
 	T: TransportReadHalf + ?Sized + Unpin,
@@ -211,6 +330,40 @@ where
 	}
 }
 
+impl<T> Future for WriteMsgs<'_, T>
+where
+	T: TransportWriteHalf + ?Sized + Unpin,
+{
+	type Output = Result<(), TransportError>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = self.get_mut();
+		Pin::new(&mut *this.inner).poll_write_msgs(cx, this.messages, this.written)
+	}
+}
+
+impl<T> Future for Flush<'_, T>
+where
+	T: TransportWriteHalf + ?Sized + Unpin,
+{
+	type Output = Result<(), TransportError>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+	}
+}
+
+impl<T> Future for Shutdown<'_, T>
+where
+	T: TransportWriteHalf + ?Sized + Unpin,
+{
+	type Output = Result<(), TransportError>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+	}
+}
+
 impl<T> TransportReadHalf for &'_ mut T
 where
 	T: TransportReadHalf + Unpin + ?Sized,
@@ -259,6 +412,18 @@ where
 	) -> Poll<Result<(), TransportError>> {
 		T::poll_write_msg(Pin::new(*self.get_mut()), context, header, body)
 	}
+
+	fn poll_write_msgs(self: Pin<&mut Self>, context: &mut Context, messages: &[Message<Self::Body>], written: &mut usize) -> Poll<Result<(), TransportError>> {
+		T::poll_write_msgs(Pin::new(*self.get_mut()), context, messages, written)
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), TransportError>> {
+		T::poll_flush(Pin::new(*self.get_mut()), context)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), TransportError>> {
+		T::poll_shutdown(Pin::new(*self.get_mut()), context)
+	}
 }
 
 impl<T> TransportWriteHalf for Box<T>
@@ -275,6 +440,18 @@ where
 	) -> Poll<Result<(), TransportError>> {
 		T::poll_write_msg(Pin::new(&mut *self.get_mut()), context, header, body)
 	}
+
+	fn poll_write_msgs(self: Pin<&mut Self>, context: &mut Context, messages: &[Message<Self::Body>], written: &mut usize) -> Poll<Result<(), TransportError>> {
+		T::poll_write_msgs(Pin::new(&mut *self.get_mut()), context, messages, written)
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), TransportError>> {
+		T::poll_flush(Pin::new(&mut *self.get_mut()), context)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), TransportError>> {
+		T::poll_shutdown(Pin::new(&mut *self.get_mut()), context)
+	}
 }
 
 impl<P> TransportWriteHalf for Pin<P>
@@ -287,4 +464,16 @@ where
 	fn poll_write_msg(self: Pin<&mut Self>, context: &mut Context, header: &MessageHeader, body: &Self::Body) -> Poll<Result<(), TransportError>> {
 		P::Target::poll_write_msg(Pin::new(&mut *self.get_mut()), context, header, body)
 	}
+
+	fn poll_write_msgs(self: Pin<&mut Self>, context: &mut Context, messages: &[Message<Self::Body>], written: &mut usize) -> Poll<Result<(), TransportError>> {
+		P::Target::poll_write_msgs(Pin::new(&mut *self.get_mut()), context, messages, written)
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), TransportError>> {
+		P::Target::poll_flush(Pin::new(&mut *self.get_mut()), context)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), TransportError>> {
+		P::Target::poll_shutdown(Pin::new(&mut *self.get_mut()), context)
+	}
 }
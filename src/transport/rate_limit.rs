@@ -0,0 +1,424 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use super::{Transport, TransportError, TransportReadHalf, TransportWriteHalf};
+use crate::{Body, Message, MessageHeader, HEADER_LEN};
+
+/// Limits for the rate of messages passing through a transport in one direction.
+///
+/// The limits are enforced with a token bucket.
+/// The bucket is refilled continuously at the configured rate,
+/// and can hold at most `burst` worth of tokens.
+///
+/// The default value does not limit anything.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct RateLimit {
+	/// The maximum number of messages per second.
+	pub messages_per_second: Option<u32>,
+
+	/// The maximum number of bytes per second.
+	///
+	/// The size of a message is the size of the message header plus the size of the body data.
+	pub bytes_per_second: Option<u64>,
+
+	/// The maximum amount of time worth of tokens that can be saved up for a burst of messages.
+	pub burst: Duration,
+}
+
+impl RateLimit {
+	/// Create a rate limit that does not limit anything.
+	pub fn none() -> Self {
+		Self::default()
+	}
+
+	/// Create a rate limit with a maximum number of messages per second.
+	pub fn messages_per_second(messages_per_second: u32) -> Self {
+		Self {
+			messages_per_second: Some(messages_per_second),
+			..Self::default()
+		}
+	}
+
+	/// Create a rate limit with a maximum number of bytes per second.
+	pub fn bytes_per_second(bytes_per_second: u64) -> Self {
+		Self {
+			bytes_per_second: Some(bytes_per_second),
+			..Self::default()
+		}
+	}
+
+	/// Set the maximum number of messages per second.
+	pub fn with_messages_per_second(mut self, messages_per_second: u32) -> Self {
+		self.messages_per_second = Some(messages_per_second);
+		self
+	}
+
+	/// Set the maximum number of bytes per second.
+	pub fn with_bytes_per_second(mut self, bytes_per_second: u64) -> Self {
+		self.bytes_per_second = Some(bytes_per_second);
+		self
+	}
+
+	/// Set the maximum amount of time worth of tokens that can be saved up for a burst of messages.
+	pub fn with_burst(mut self, burst: Duration) -> Self {
+		self.burst = burst;
+		self
+	}
+
+	/// Check if the rate limit does not limit anything.
+	pub fn is_none(&self) -> bool {
+		self.messages_per_second.is_none() && self.bytes_per_second.is_none()
+	}
+}
+
+impl Default for RateLimit {
+	fn default() -> Self {
+		Self {
+			messages_per_second: None,
+			bytes_per_second: None,
+			burst: Duration::from_secs(1),
+		}
+	}
+}
+
+/// Transport wrapper that limits the rate of messages read from and written to the inner transport.
+///
+/// Reading is throttled by not polling the inner transport while the read limit is exceeded.
+/// This applies back-pressure to the remote peer, so a misbehaving peer can not starve the local process.
+/// Writing is throttled by delaying outgoing messages until the write limit allows them.
+///
+/// The size of a received message is only known after it has been read,
+/// so a message that exceeds the remaining byte budget is still accepted.
+/// The excess is paid back by delaying the following messages.
+/// Outgoing messages are accounted for the same way.
+///
+/// The wrapper can be combined with any other transport:
+///
+/// ```no_run
+/// # use fizyr_rpc::transport::{RateLimit, RateLimitedTransport};
+/// # use fizyr_rpc::{Peer, UnixStreamTransport};
+/// # async fn foo(stream: tokio::net::UnixStream) {
+/// let transport = UnixStreamTransport::new(stream, Default::default());
+/// let transport = RateLimitedTransport::new(transport, RateLimit::messages_per_second(100), RateLimit::none());
+/// let peer = Peer::spawn(transport);
+/// # }
+/// ```
+pub struct RateLimitedTransport<T> {
+	/// The wrapped transport.
+	inner: T,
+
+	/// The token bucket for reading messages.
+	read_bucket: TokenBucket,
+
+	/// The token bucket for writing messages.
+	write_bucket: TokenBucket,
+}
+
+/// The read half of a [`RateLimitedTransport`].
+pub struct RateLimitedReadHalf<'a, R> {
+	/// The read half of the wrapped transport.
+	inner: R,
+
+	/// The token bucket for reading messages.
+	bucket: &'a mut TokenBucket,
+}
+
+/// The write half of a [`RateLimitedTransport`].
+pub struct RateLimitedWriteHalf<'a, W> {
+	/// The write half of the wrapped transport.
+	inner: W,
+
+	/// The token bucket for writing messages.
+	bucket: &'a mut TokenBucket,
+}
+
+impl<T> RateLimitedTransport<T> {
+	/// Wrap a transport with rate limits for reading and writing.
+	pub fn new(inner: T, read_limit: RateLimit, write_limit: RateLimit) -> Self {
+		Self {
+			inner,
+			read_bucket: TokenBucket::new(read_limit),
+			write_bucket: TokenBucket::new(write_limit),
+		}
+	}
+
+	/// Get a shared reference to the wrapped transport.
+	pub fn inner(&self) -> &T {
+		&self.inner
+	}
+
+	/// Get an exclusive reference to the wrapped transport.
+	pub fn inner_mut(&mut self) -> &mut T {
+		&mut self.inner
+	}
+
+	/// Consume the wrapper to get the wrapped transport.
+	pub fn into_inner(self) -> T {
+		self.inner
+	}
+
+	/// Get the rate limit for reading messages.
+	pub fn read_limit(&self) -> &RateLimit {
+		&self.read_bucket.limit
+	}
+
+	/// Get the rate limit for writing messages.
+	pub fn write_limit(&self) -> &RateLimit {
+		&self.write_bucket.limit
+	}
+}
+
+impl<T: Transport> Transport for RateLimitedTransport<T> {
+	type Body = T::Body;
+	type Info = T::Info;
+	type Config = T::Config;
+	type ReadHalf<'a> = RateLimitedReadHalf<'a, T::ReadHalf<'a>>;
+	type WriteHalf<'a> = RateLimitedWriteHalf<'a, T::WriteHalf<'a>>;
+
+	fn split(&mut self) -> (Self::ReadHalf<'_>, Self::WriteHalf<'_>) {
+		let (read_half, write_half) = self.inner.split();
+		let read_half = RateLimitedReadHalf {
+			inner: read_half,
+			bucket: &mut self.read_bucket,
+		};
+		let write_half = RateLimitedWriteHalf {
+			inner: write_half,
+			bucket: &mut self.write_bucket,
+		};
+		(read_half, write_half)
+	}
+
+	fn info(&self) -> std::io::Result<Self::Info> {
+		self.inner.info()
+	}
+
+	fn describe_remote(&self) -> Option<String> {
+		self.inner.describe_remote()
+	}
+}
+
+impl<R> TransportReadHalf for RateLimitedReadHalf<'_, R>
+where
+	R: TransportReadHalf,
+{
+	type Body = R::Body;
+
+	fn poll_read_msg(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<Message<Self::Body>, TransportError>> {
+		let this = self.get_mut();
+		ready!(this.bucket.poll_ready(context));
+		let message = ready!(Pin::new(&mut this.inner).poll_read_msg(context))?;
+		this.bucket.consume(message_len(&message.body));
+		Poll::Ready(Ok(message))
+	}
+}
+
+impl<W> TransportWriteHalf for RateLimitedWriteHalf<'_, W>
+where
+	W: TransportWriteHalf,
+{
+	type Body = W::Body;
+
+	fn poll_write_msg(self: Pin<&mut Self>, context: &mut Context, header: &MessageHeader, body: &Self::Body) -> Poll<Result<(), TransportError>> {
+		let this = self.get_mut();
+		ready!(this.bucket.poll_ready(context));
+		ready!(Pin::new(&mut this.inner).poll_write_msg(context, header, body))?;
+		this.bucket.consume(message_len(body));
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), TransportError>> {
+		Pin::new(&mut self.get_mut().inner).poll_flush(context)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), TransportError>> {
+		Pin::new(&mut self.get_mut().inner).poll_shutdown(context)
+	}
+}
+
+/// Get the size of a message for the byte rate limit.
+fn message_len(body: &impl Body) -> u64 {
+	(HEADER_LEN as usize + body.data_len()) as u64
+}
+
+/// Token bucket for the message and byte rate limits of one direction.
+struct TokenBucket {
+	/// The configured rate limit.
+	limit: RateLimit,
+
+	/// The available message tokens.
+	///
+	/// This can become negative if more messages are consumed than available.
+	messages: f64,
+
+	/// The available byte tokens.
+	///
+	/// This can become negative if more bytes are consumed than available.
+	bytes: f64,
+
+	/// The last time the bucket was refilled.
+	last_refill: Instant,
+
+	/// Timer to wake the task when enough tokens are available.
+	sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl TokenBucket {
+	/// Create a new token bucket that starts full.
+	fn new(limit: RateLimit) -> Self {
+		let burst = limit.burst.as_secs_f64();
+		Self {
+			messages: limit.messages_per_second.map_or(0.0, |rate| rate as f64 * burst),
+			bytes: limit.bytes_per_second.map_or(0.0, |rate| rate as f64 * burst),
+			limit,
+			last_refill: Instant::now(),
+			sleep: None,
+		}
+	}
+
+	/// Add the tokens that accumulated since the last refill.
+	fn refill(&mut self, now: Instant) {
+		let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+		let burst = self.limit.burst.as_secs_f64();
+		self.last_refill = now;
+		if let Some(rate) = self.limit.messages_per_second {
+			let rate = rate as f64;
+			self.messages = (self.messages + rate * elapsed).min(rate * burst);
+		}
+		if let Some(rate) = self.limit.bytes_per_second {
+			let rate = rate as f64;
+			self.bytes = (self.bytes + rate * elapsed).min(rate * burst);
+		}
+	}
+
+	/// Compute how long to wait until the next message is allowed.
+	///
+	/// Returns [`None`] if the next message is allowed right away.
+	fn wait_time(&self) -> Option<Duration> {
+		let mut wait = 0.0f64;
+		if let Some(rate) = self.limit.messages_per_second {
+			if self.messages < 1.0 {
+				wait = wait.max((1.0 - self.messages) / rate as f64);
+			}
+		}
+		if let Some(rate) = self.limit.bytes_per_second {
+			if self.bytes < 0.0 {
+				wait = wait.max(-self.bytes / rate as f64);
+			}
+		}
+		if wait > 0.0 {
+			Some(Duration::from_secs_f64(wait.min(Duration::MAX.as_secs_f64())))
+		} else {
+			None
+		}
+	}
+
+	/// Wait until the next message is allowed.
+	///
+	/// If the function returns [`Poll::Pending`],
+	/// the current task is scheduled to wake when enough tokens are available.
+	fn poll_ready(&mut self, context: &mut Context) -> Poll<()> {
+		if self.limit.is_none() {
+			return Poll::Ready(());
+		}
+
+		loop {
+			if let Some(sleep) = &mut self.sleep {
+				ready!(sleep.as_mut().poll(context));
+				self.sleep = None;
+			}
+
+			let now = Instant::now();
+			self.refill(now);
+			match self.wait_time() {
+				None => return Poll::Ready(()),
+				Some(wait) => self.sleep = Some(Box::pin(tokio::time::sleep_until((now + wait).into()))),
+			}
+		}
+	}
+
+	/// Consume the tokens for a message of the given size.
+	fn consume(&mut self, len: u64) {
+		if self.limit.messages_per_second.is_some() {
+			self.messages -= 1.0;
+		}
+		if self.limit.bytes_per_second.is_some() {
+			self.bytes -= len as f64;
+		}
+	}
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for RateLimitedTransport<T> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct(core::any::type_name::<Self>())
+			.field("inner", &self.inner)
+			.field("read_limit", &self.read_bucket.limit)
+			.field("write_limit", &self.write_bucket.limit)
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+
+	use crate::{UnixStreamPeer, UnixStreamTransport};
+
+	#[test]
+	fn token_bucket_limits_messages() {
+		let mut bucket = TokenBucket::new(RateLimit::messages_per_second(10).with_burst(Duration::from_millis(200)));
+		assert!(bucket.wait_time() == None);
+		bucket.consume(100);
+		bucket.consume(100);
+		let_assert!(Some(wait) = bucket.wait_time());
+		assert!(wait > Duration::from_millis(90));
+		assert!(wait <= Duration::from_millis(100));
+	}
+
+	#[test]
+	fn token_bucket_limits_bytes() {
+		let mut bucket = TokenBucket::new(RateLimit::bytes_per_second(1000));
+		bucket.consume(1000);
+		assert!(bucket.wait_time() == None);
+		bucket.consume(500);
+		let_assert!(Some(wait) = bucket.wait_time());
+		assert!(wait > Duration::from_millis(490));
+		assert!(wait <= Duration::from_millis(500));
+	}
+
+	#[test]
+	fn token_bucket_refill_is_capped() {
+		let mut bucket = TokenBucket::new(RateLimit::messages_per_second(10).with_burst(Duration::from_millis(100)));
+		bucket.consume(0);
+		let now = bucket.last_refill + Duration::from_secs(10);
+		bucket.refill(now);
+		assert!(bucket.messages == 1.0);
+	}
+
+	#[tokio::test]
+	async fn rate_limited_reads_are_delayed() {
+		let_assert!(Ok((peer_a, peer_b)) = tokio::net::UnixStream::pair());
+		let peer_a = UnixStreamTransport::new(peer_a, Default::default());
+		let peer_b = UnixStreamTransport::new(peer_b, Default::default());
+		let limit = RateLimit::messages_per_second(20).with_burst(Duration::from_millis(50));
+		let peer_a = UnixStreamPeer::spawn(peer_a);
+		let mut peer_b = crate::Peer::spawn(RateLimitedTransport::new(peer_b, limit, RateLimit::none()));
+
+		for i in 0..4 {
+			let_assert!(Ok(()) = peer_a.send_stream(i, &b"hello"[..]).await);
+		}
+
+		let start = Instant::now();
+		for i in 0..4 {
+			let_assert!(Ok(crate::ReceivedMessage::Stream(message)) = peer_b.recv_message().await);
+			assert!(message.header.service_id == i);
+		}
+
+		// One message is allowed right away, the other three have to wait 50 ms each.
+		assert!(start.elapsed() >= Duration::from_millis(100));
+	}
+}
@@ -1,13 +1,23 @@
+use bytes::Bytes;
+
 /// The body of a stream message.
+///
+/// The data is stored in a reference counted [`Bytes`] buffer,
+/// so cloning a body is cheap and does not copy the data.
 #[derive(Debug, Clone)]
 pub struct StreamBody {
 	/// The message data.
-	pub data: Vec<u8>,
+	pub data: Bytes,
 }
 
 impl StreamBody {
 	/// Create a new stream body.
 	fn new(data: Vec<u8>) -> Self {
+		Self { data: data.into() }
+	}
+
+	/// Create a new stream body from a [`Bytes`] buffer without copying the data.
+	pub fn from_bytes(data: Bytes) -> Self {
 		Self { data }
 	}
 }
@@ -26,7 +36,17 @@ impl crate::Body for StreamBody {
 	}
 
 	fn into_error(self) -> Result<String, std::string::FromUtf8Error> {
-		String::from_utf8(self.data)
+		String::from_utf8(self.data.into())
+	}
+
+	fn data_len(&self) -> usize {
+		self.data.len()
+	}
+}
+
+impl crate::SharedBody for StreamBody {
+	fn clone_shared(&self) -> Self {
+		self.clone()
 	}
 }
 
@@ -35,7 +55,7 @@ where
 	Vec<u8>: From<T>,
 {
 	fn from(other: T) -> Self {
-		Self { data: other.into() }
+		Self::new(other.into())
 	}
 }
 
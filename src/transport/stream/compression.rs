@@ -0,0 +1,331 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::{service_id, Error, MessageHeader, MessageType};
+
+/// Compression algorithm for message bodies sent over a stream transport.
+///
+/// Each algorithm is only available if the feature with the same name is enabled.
+///
+/// Compression is negotiated per connection.
+/// A transport with compression enabled announces the algorithms it supports to the remote peer,
+/// together with its first outgoing message.
+/// Message bodies are only compressed with an algorithm that the remote peer has announced.
+/// If both peers announce multiple algorithms, the local order of preference is used.
+///
+/// The announcement is a stream message with service ID [`service_id::COMPRESSION`].
+/// Peers that do not support compression will see it as a regular stream message with an unknown service ID,
+/// and never receive compressed messages.
+/// Depending on the application, such a peer may treat the announcement as an error,
+/// so compression should only be enabled if all remote peers support it or ignore unknown stream messages.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Compression {
+	/// LZ4 block compression.
+	///
+	/// LZ4 is very fast, but has a lower compression ratio than Zstandard.
+	#[cfg(feature = "lz4")]
+	Lz4,
+
+	/// Zstandard compression with the given compression level.
+	///
+	/// The level only affects outgoing messages.
+	/// Level 0 selects the default level of the zstd library.
+	#[cfg(feature = "zstd")]
+	Zstd {
+		/// The compression level.
+		level: i32,
+	},
+}
+
+impl Compression {
+	/// Get the identifier of the algorithm used on the wire.
+	fn id(self) -> u8 {
+		match self {
+			#[cfg(feature = "lz4")]
+			Self::Lz4 => 1,
+			#[cfg(feature = "zstd")]
+			Self::Zstd { .. } => 2,
+		}
+	}
+
+	/// Compress a message body.
+	fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+		let _ = data;
+		match self {
+			#[cfg(feature = "lz4")]
+			Self::Lz4 => Ok(lz4_flex::block::compress_prepend_size(data)),
+			#[cfg(feature = "zstd")]
+			Self::Zstd { level } => zstd::bulk::compress(data, level),
+		}
+	}
+
+	/// Decompress a message body, rejecting bodies that would decompress to more than `max_len` bytes.
+	fn decompress(self, data: &[u8], max_len: usize) -> Result<Vec<u8>, Error> {
+		let _ = (data, max_len);
+		match self {
+			#[cfg(feature = "lz4")]
+			Self::Lz4 => {
+				let (len, _) = lz4_flex::block::uncompressed_size(data)
+					.map_err(|e| Error::decode_failed(Box::new(e)))?;
+				crate::error::private::check_payload_too_large(len, max_len)?;
+				lz4_flex::block::decompress_size_prepended(data)
+					.map_err(|e| Error::decode_failed(Box::new(e)))
+			},
+			#[cfg(feature = "zstd")]
+			Self::Zstd { .. } => {
+				let content_size = zstd::zstd_safe::get_frame_content_size(data)
+					.map_err(|e| Error::decode_failed(Box::new(std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))))?;
+				match content_size {
+					// The frame declares its size, so only allocate what is needed.
+					Some(len) => {
+						let len = usize::try_from(len).unwrap_or(usize::MAX);
+						crate::error::private::check_payload_too_large(len, max_len)?;
+						zstd::bulk::decompress(data, len)
+							.map_err(|e| Error::decode_failed(Box::new(e)))
+					},
+					// Otherwise, decompress incrementally and stop as soon as the limit is exceeded.
+					None => {
+						use std::io::Read;
+						let decoder = zstd::stream::read::Decoder::with_buffer(data)
+							.map_err(|e| Error::decode_failed(Box::new(e)))?;
+						let mut decompressed = Vec::new();
+						decoder.take(max_len as u64 + 1).read_to_end(&mut decompressed)
+							.map_err(|e| Error::decode_failed(Box::new(e)))?;
+						crate::error::private::check_payload_too_large(decompressed.len(), max_len)?;
+						Ok(decompressed)
+					},
+				}
+			},
+		}
+	}
+}
+
+/// Compression state shared by the read and write half of a stream transport.
+#[derive(Debug, Clone)]
+pub(super) struct CompressionState {
+	/// The algorithms supported by the local peer, in order of preference.
+	algorithms: Vec<Compression>,
+
+	/// The minimum body size to compress.
+	threshold: usize,
+
+	/// The index plus one of the algorithm to use for outgoing messages, or zero if compression was not negotiated (yet).
+	selected: Arc<AtomicUsize>,
+}
+
+#[allow(dead_code)] // Not used when transports are disabled.
+impl CompressionState {
+	/// Create a new compression state.
+	pub(super) fn new(algorithms: Vec<Compression>, threshold: usize) -> Self {
+		Self {
+			algorithms,
+			threshold,
+			selected: Arc::new(AtomicUsize::new(0)),
+		}
+	}
+
+	/// Check if compression is enabled for the local peer.
+	pub(super) fn is_enabled(&self) -> bool {
+		!self.algorithms.is_empty()
+	}
+
+	/// Make the announcement message for the remote peer.
+	pub(super) fn announcement(&self) -> (MessageHeader, Vec<u8>) {
+		let header = MessageHeader::stream(0, service_id::COMPRESSION);
+		let body = self.algorithms.iter().map(|x| x.id()).collect();
+		(header, body)
+	}
+
+	/// Check if a message is a compression announcement.
+	pub(super) fn is_announcement(header: &MessageHeader) -> bool {
+		header.message_type == MessageType::Stream && header.service_id == service_id::COMPRESSION
+	}
+
+	/// Process an announcement from the remote peer.
+	pub(super) fn process_announcement(&self, body: &[u8]) {
+		let selected = self.algorithms.iter()
+			.position(|x| body.contains(&x.id()))
+			.map_or(0, |i| i + 1);
+		self.selected.store(selected, Ordering::Relaxed);
+	}
+
+	/// Compress an outgoing message body, if compression has been negotiated and the body is large enough.
+	///
+	/// Returns the algorithm identifier and the compressed body,
+	/// or `None` if the body should be sent uncompressed.
+	pub(super) fn compress(&self, data: &[u8]) -> Option<(u8, Vec<u8>)> {
+		if data.len() < self.threshold {
+			return None;
+		}
+		let selected = self.selected.load(Ordering::Relaxed).checked_sub(1)?;
+		let algorithm = self.algorithms[selected];
+		let compressed = algorithm.compress(data).ok()?;
+		if compressed.len() < data.len() {
+			Some((algorithm.id(), compressed))
+		} else {
+			None
+		}
+	}
+
+	/// Decompress an incoming message body.
+	///
+	/// Only the algorithms announced to the remote peer are accepted.
+	pub(super) fn decompress(&self, id: u8, data: &[u8], max_len: usize) -> Result<Vec<u8>, Error> {
+		let algorithm = self.algorithms.iter()
+			.find(|x| x.id() == id)
+			.ok_or_else(|| {
+				let message = format!("received message with unsupported compression algorithm {}", id);
+				Error::io_error(std::io::Error::new(std::io::ErrorKind::InvalidData, message))
+			})?;
+		algorithm.decompress(data, max_len)
+	}
+}
+
+#[cfg(test)]
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+	use std::sync::Mutex;
+
+	use crate::transport::stream::{StreamBody, StreamReadHalf, StreamWriteHalf};
+	use crate::transport::stream::pool::BufferPool;
+	use crate::transport::trace::{parse_trace, TraceDirection, WireTrace};
+	use crate::transport::{Endian, EndianState, RemoteErrorPolicy, TransportReadHalf, TransportWriteHalf};
+
+	type ReadHalf = StreamReadHalf<tokio::io::ReadHalf<tokio::io::DuplexStream>>;
+	type WriteHalf = StreamWriteHalf<tokio::io::WriteHalf<tokio::io::DuplexStream>>;
+
+	#[derive(Clone, Default)]
+	struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+	impl std::io::Write for SharedBuffer {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			self.0.lock().unwrap().extend_from_slice(buf);
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	fn algorithms() -> Vec<Compression> {
+		vec![
+			#[cfg(feature = "zstd")]
+			Compression::Zstd { level: 0 },
+			#[cfg(feature = "lz4")]
+			Compression::Lz4,
+		]
+	}
+
+	/// Create the read and write half of a stream transport over one end of a duplex stream.
+	fn transport(stream: tokio::io::DuplexStream, algorithms: Vec<Compression>, trace: Option<WireTrace>) -> (ReadHalf, WriteHalf) {
+		let (read, write) = tokio::io::split(stream);
+		let compression = CompressionState::new(algorithms, 100);
+		let endian = EndianState::new(Endian::LittleEndian, false);
+		let read = StreamReadHalf::new(read, 1 << 20, endian.clone(), trace.clone(), RemoteErrorPolicy::none(), compression.clone(), BufferPool::new(0));
+		let write = StreamWriteHalf::new(write, 1 << 20, endian, trace, compression);
+		(read, write)
+	}
+
+	/// Get the sizes of all frames sent according to a wire trace.
+	fn sent_frame_sizes(wire_trace: &WireTrace, trace: &SharedBuffer) -> Vec<usize> {
+		let_assert!(Ok(()) = wire_trace.flush());
+		let trace = trace.0.lock().unwrap().clone();
+		let_assert!(Ok(entries) = parse_trace(&trace[..]));
+		entries.iter()
+			.filter(|x| x.direction == TraceDirection::Sent)
+			.map(|x| x.frame.len())
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn compress_after_announcement() {
+		for algorithm in algorithms() {
+			let trace = SharedBuffer::default();
+			let (stream_a, stream_b) = tokio::io::duplex(1 << 20);
+			let wire_trace = WireTrace::new(trace.clone());
+			let (mut read_a, mut write_a) = transport(stream_a, vec![algorithm], Some(wire_trace.clone()));
+			let (mut read_b, mut write_b) = transport(stream_b, algorithms(), None);
+			let large: StreamBody = vec![b'a'; 10_000].into();
+
+			// Nothing is compressed before the remote peer announced its algorithms.
+			assert!(let Ok(()) = write_a.write_msg(&MessageHeader::stream(0, 1), &large).await);
+			let_assert!(Ok(message) = read_b.read_msg().await);
+			assert!(message.header == MessageHeader::stream(0, 1));
+			assert!(message.body.as_ref() == large.as_ref());
+
+			// The announcement is consumed by the read half.
+			assert!(let Ok(()) = write_b.write_msg(&MessageHeader::stream(0, 2), &b"hello"[..].into()).await);
+			let_assert!(Ok(message) = read_a.read_msg().await);
+			assert!(message.header == MessageHeader::stream(0, 2));
+			assert!(message.body.as_ref() == b"hello");
+
+			// Large bodies are compressed now, small bodies are not.
+			assert!(let Ok(()) = write_a.write_msg(&MessageHeader::request(1, 3), &large).await);
+			assert!(let Ok(()) = write_a.write_msg(&MessageHeader::request(2, 4), &b"small"[..].into()).await);
+			let_assert!(Ok(message) = read_b.read_msg().await);
+			assert!(message.header == MessageHeader::request(1, 3));
+			assert!(message.body.as_ref() == large.as_ref());
+			let_assert!(Ok(message) = read_b.read_msg().await);
+			assert!(message.header == MessageHeader::request(2, 4));
+			assert!(message.body.as_ref() == b"small");
+
+			let sizes = sent_frame_sizes(&wire_trace, &trace);
+			assert!(sizes.len() == 4);
+			assert!(sizes[0] == 16 + 1, "announcement with one algorithm");
+			assert!(sizes[1] == 16 + 10_000);
+			assert!(sizes[2] < 1_000);
+			assert!(sizes[3] == 16 + 5);
+		}
+	}
+
+	#[tokio::test]
+	async fn no_compression_without_remote_support() {
+		let trace = SharedBuffer::default();
+		let (stream_a, stream_b) = tokio::io::duplex(1 << 20);
+		let wire_trace = WireTrace::new(trace.clone());
+		let (mut read_a, mut write_a) = transport(stream_a, algorithms(), Some(wire_trace.clone()));
+		let (mut read_b, mut write_b) = transport(stream_b, Vec::new(), None);
+		let large: StreamBody = vec![b'a'; 10_000].into();
+
+		assert!(let Ok(()) = write_b.write_msg(&MessageHeader::stream(0, 2), &b"hello"[..].into()).await);
+		let_assert!(Ok(_) = read_a.read_msg().await);
+
+		assert!(let Ok(()) = write_a.write_msg(&MessageHeader::stream(0, 1), &large).await);
+		let_assert!(Ok(message) = read_b.read_msg().await);
+		assert!(message.body.as_ref() == large.as_ref());
+		assert!(sent_frame_sizes(&wire_trace, &trace).last() == Some(&(16 + 10_000)));
+	}
+
+	#[test]
+	fn reject_unsupported_or_oversized_bodies() {
+		let state = CompressionState::new(algorithms(), 0);
+		state.process_announcement(&[1, 2]);
+		let_assert!(Some((id, compressed)) = state.compress(&[0; 1000]));
+		let_assert!(Ok(decompressed) = state.decompress(id, &compressed, 1000));
+		assert!(decompressed == [0; 1000]);
+		assert!(let Err(_) = state.decompress(id, &compressed, 999));
+		assert!(let Err(_) = state.decompress(3, &compressed, 1000));
+
+		let state = CompressionState::new(Vec::new(), 0);
+		assert!(let Err(_) = state.decompress(id, &compressed, 1000));
+	}
+
+	#[cfg(feature = "zstd")]
+	#[test]
+	fn zstd_frame_without_content_size() {
+		// The streaming encoder does not know the size up front, so it does not store it in the frame.
+		let_assert!(Ok(compressed) = zstd::stream::encode_all(&[0; 1000][..], 0));
+		assert!(let Ok(None) = zstd::zstd_safe::get_frame_content_size(&compressed));
+
+		let algorithm = Compression::Zstd { level: 0 };
+		let_assert!(Ok(decompressed) = algorithm.decompress(&compressed, 1000));
+		assert!(decompressed == [0; 1000]);
+		let_assert!(Err(e) = algorithm.decompress(&compressed, 999));
+		assert!(let Some((_, 999)) = e.as_payload_too_large());
+	}
+}
@@ -1,4 +1,9 @@
-use crate::transport::Endian;
+use std::sync::Arc;
+
+use crate::transport::{BodySink, Compression, Endian, RemoteErrorPolicy, WireTrace};
+
+#[cfg(feature = "tcp")]
+use crate::transport::Proxy;
 
 /// Configuration for a byte-stream transport.
 #[derive(Debug, Clone)]
@@ -22,6 +27,69 @@ pub struct StreamConfig {
 	/// The encoding and serialization of message bodies is up to the application code,
 	/// and it not affected by this configuration parameter.
 	pub endian: Endian,
+
+	/// Detect the endianness of the remote peer from the received messages.
+	///
+	/// If enabled, the endianness is detected from the first received message that is unambiguous.
+	/// From then on, the detected endianness is used to decode incoming messages and to encode outgoing messages,
+	/// so that peers with a different [`Self::endian`] configuration can still communicate.
+	/// Until the endianness is detected, the configured endianness is used.
+	///
+	/// Only one side of the connection should enable detection, and it should wait for the remote peer to send the first message.
+	pub detect_endian: bool,
+
+	/// Record a byte-accurate trace of all frames read and written by the transport.
+	///
+	/// See the [`trace`][crate::transport::trace] module for the format of the trace.
+	pub trace: Option<WireTrace>,
+
+	/// The validation to apply to error messages received from the remote peer.
+	///
+	/// By default, error messages are not modified.
+	pub error_policy: RemoteErrorPolicy,
+
+	/// The compression algorithms supported by the transport, in order of preference.
+	///
+	/// If the list is empty (the default), compression is disabled.
+	/// Otherwise, the algorithms are announced to the remote peer and outgoing messages are compressed
+	/// once the remote peer has announced support for one of them.
+	/// See [`Compression`] for more details.
+	///
+	/// Peers without compression support receive the announcement as a stream message with an unknown service ID.
+	/// Only enable compression if the remote peer supports it, or if it ignores unknown stream messages.
+	pub compression: Vec<Compression>,
+
+	/// The minimum body size in bytes for outgoing messages to be compressed.
+	///
+	/// Compressing small messages is rarely worth the effort.
+	/// Compressed bodies that are not smaller than the original are always sent uncompressed.
+	pub compression_threshold: usize,
+
+	/// The maximum number of body buffers to keep for re-use by incoming messages.
+	///
+	/// When the body of a received message is dropped, its buffer is returned to a pool,
+	/// so the next incoming message does not need a new allocation.
+	/// Each pooled buffer may hold up to [`Self::max_body_len_read`] bytes.
+	///
+	/// If set to zero (the default), buffers are not pooled.
+	pub buffer_pool_size: usize,
+
+	/// The hook to stream the bodies of selected incoming messages into a writer instead of memory.
+	///
+	/// See [`BodySink`] for more details.
+	/// Frames with a body that was streamed into a sink are not recorded in the wire trace.
+	///
+	/// By default, all message bodies are read into memory.
+	pub body_sink: Option<Arc<dyn BodySink>>,
+
+	/// The proxy server to connect through.
+	///
+	/// This is only used to connect TCP transports.
+	/// See [`Proxy`] for more details.
+	///
+	/// By default, connections are made directly to the remote peer.
+	#[cfg(feature = "tcp")]
+	pub proxy: Option<Proxy>,
 }
 
 impl Default for StreamConfig {
@@ -30,6 +98,15 @@ impl Default for StreamConfig {
 			max_body_len_read: 8 * 1024,
 			max_body_len_write: 8 * 1024,
 			endian: Endian::LittleEndian,
+			detect_endian: false,
+			trace: None,
+			error_policy: RemoteErrorPolicy::none(),
+			compression: Vec::new(),
+			compression_threshold: 1024,
+			buffer_pool_size: 0,
+			body_sink: None,
+			#[cfg(feature = "tcp")]
+			proxy: None,
 		}
 	}
 }
@@ -0,0 +1,520 @@
+//! Low-level helpers to read and write framed messages.
+//!
+//! Stream transports send each message as a frame: a 32 bit frame length, followed by the message header and the body.
+//! The frame length counts the header and the body, but not itself.
+//!
+//! The functions in this module read and write such frames directly from byte streams or in-memory buffers,
+//! without a [`Peer`][crate::Peer] or [`StreamTransport`][super::StreamTransport].
+//! This is useful for tooling, like programs that inspect recorded traffic or generate test traffic.
+//!
+//! These functions do not support compressed frames.
+//! The compression algorithm is encoded in the message type of a frame,
+//! so reading a compressed frame results in an "invalid message type" error.
+//!
+//! The [`FrameDecoder`] and [`FrameEncoder`] do not perform any I/O at all.
+//! You push received bytes into a decoder and take decoded messages out of it,
+//! or encode messages into an encoder and take the bytes to send out of it.
+//! This allows you to drive the framing from a custom event loop or outside of a tokio runtime,
+//! and to fuzz the frame parser directly.
+//! The decoder uses the same parser as the read half of a [`StreamTransport`][super::StreamTransport].
+//!
+//! To serialize a single message, for example to store it in a file, you can use [`Message::to_bytes()`] and [`Message::from_bytes()`].
+
+use bytes::BytesMut;
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::compression::CompressionState;
+use super::{Compression, StreamBody};
+use crate::error::private::{check_message_too_short, check_payload_too_large};
+use crate::transport::util::{poll_read_exact, poll_write_all_vectored};
+use crate::transport::Endian;
+use crate::{Error, Message, MessageHeader};
+
+/// Length of the frame length and message header at the start of each frame.
+pub const FRAMED_HEADER_LEN: usize = 4 + crate::HEADER_LEN as usize;
+
+/// Append a single frame to a buffer.
+///
+/// Returns an error if the body is too large to fit in a frame.
+pub fn encode_frame(buffer: &mut Vec<u8>, header: &MessageHeader, body: &[u8], endian: Endian) -> Result<(), Error> {
+	check_payload_too_large(body.len(), crate::MAX_PAYLOAD_LEN as usize)?;
+	let mut framed_header = [0u8; FRAMED_HEADER_LEN];
+	encode_framed_header(&mut framed_header, header, body.len(), endian);
+	buffer.reserve(FRAMED_HEADER_LEN + body.len());
+	buffer.extend_from_slice(&framed_header);
+	buffer.extend_from_slice(body);
+	Ok(())
+}
+
+/// Decode a single frame from the start of a buffer.
+///
+/// Returns the decoded message and the total length of the frame,
+/// so that the caller can remove the frame from the buffer.
+/// Returns `Ok(None)` if the buffer does not contain a complete frame yet.
+///
+/// Returns an error if the frame is invalid, or if the body is larger than `max_body_len`.
+/// The error is reported as soon as the frame header is available, even if the body is still incomplete.
+pub fn decode_frame(data: &[u8], endian: Endian, max_body_len: u32) -> Result<Option<(Message<StreamBody>, usize)>, Error> {
+	if data.len() < FRAMED_HEADER_LEN {
+		return Ok(None);
+	}
+	let (header, body_len) = decode_framed_header(&data[..FRAMED_HEADER_LEN], endian, max_body_len)?;
+	let frame_len = FRAMED_HEADER_LEN + body_len;
+	if data.len() < frame_len {
+		return Ok(None);
+	}
+	let body = StreamBody::from(&data[FRAMED_HEADER_LEN..frame_len]);
+	Ok(Some((Message::new(header, body), frame_len)))
+}
+
+/// Read a single frame from a byte stream.
+///
+/// Returns an error if reading from the stream fails, if the frame is invalid, or if the body is larger than `max_body_len`.
+///
+/// This function is not cancellation safe:
+/// if the future is dropped before it completes, part of a frame may have been consumed from the stream.
+pub async fn read_message<R>(stream: &mut R, endian: Endian, max_body_len: u32) -> Result<Message<StreamBody>, Error>
+where
+	R: AsyncRead + Unpin + ?Sized,
+{
+	let mut framed_header = [0u8; FRAMED_HEADER_LEN];
+	let mut filled = 0;
+	std::future::poll_fn(|context| poll_read_exact(Pin::new(&mut *stream), context, &mut framed_header, &mut filled))
+		.await
+		.map_err(Error::io_error)?;
+	let (header, body_len) = decode_framed_header(&framed_header, endian, max_body_len)?;
+
+	let mut body = vec![0u8; body_len];
+	let mut filled = 0;
+	std::future::poll_fn(|context| poll_read_exact(Pin::new(&mut *stream), context, &mut body, &mut filled))
+		.await
+		.map_err(Error::io_error)?;
+	Ok(Message::new(header, body.into()))
+}
+
+/// Write a single frame to a byte stream.
+///
+/// The frame header and the body are written with vectored writes, and the stream is not flushed.
+///
+/// Returns an error if the body is too large to fit in a frame, or if writing to the stream fails.
+pub async fn write_message<W>(stream: &mut W, header: &MessageHeader, body: &[u8], endian: Endian) -> Result<(), Error>
+where
+	W: AsyncWrite + Unpin + ?Sized,
+{
+	check_payload_too_large(body.len(), crate::MAX_PAYLOAD_LEN as usize)?;
+	let mut framed_header = [0u8; FRAMED_HEADER_LEN];
+	encode_framed_header(&mut framed_header, header, body.len(), endian);
+	let mut written = 0;
+	std::future::poll_fn(|context| poll_write_all_vectored(Pin::new(&mut *stream), context, &[&framed_header, body], &mut written))
+		.await
+		.map_err(Error::io_error)
+}
+
+/// Sans-io decoder for a stream of frames.
+///
+/// Push received bytes into the decoder with [`Self::push()`],
+/// and call [`Self::next_message()`] until it returns `Ok(None)` to get the decoded messages.
+///
+/// Compressed frames are only accepted for the algorithms passed to [`Self::with_compression()`].
+/// Compression announcements are not interpreted by the decoder:
+/// they are returned as regular stream messages with service ID [`service_id::COMPRESSION`][crate::service_id::COMPRESSION].
+#[derive(Debug)]
+pub struct FrameDecoder {
+	/// The endianness to use for decoding header fields.
+	endian: Endian,
+
+	/// The maximum body length to accept.
+	max_body_len: u32,
+
+	/// The compression algorithms to accept.
+	compression: CompressionState,
+
+	/// The received bytes that have not been decoded yet.
+	buffer: BytesMut,
+}
+
+impl FrameDecoder {
+	/// Create a new decoder that does not accept compressed frames.
+	pub fn new(endian: Endian, max_body_len: u32) -> Self {
+		Self {
+			endian,
+			max_body_len,
+			compression: CompressionState::new(Vec::new(), 0),
+			buffer: BytesMut::new(),
+		}
+	}
+
+	/// Accept frames compressed with the given algorithms.
+	pub fn with_compression(mut self, algorithms: Vec<Compression>) -> Self {
+		self.compression = CompressionState::new(algorithms, 0);
+		self
+	}
+
+	/// Add received bytes to the decoder.
+	pub fn push(&mut self, data: &[u8]) {
+		self.buffer.extend_from_slice(data);
+	}
+
+	/// Get the number of bytes that have been pushed into the decoder but not decoded yet.
+	pub fn buffered_len(&self) -> usize {
+		self.buffer.len()
+	}
+
+	/// Decode the next message from the received bytes.
+	///
+	/// Returns `Ok(None)` if the decoder does not hold a complete frame yet.
+	///
+	/// If the frame header is invalid or the body is larger than the maximum body length,
+	/// the frame is not consumed and the same error is returned on every call.
+	/// The start of the next frame can not be found in that case, so the byte stream should be closed.
+	///
+	/// If only the body of a compressed frame can not be decompressed,
+	/// the frame is consumed and you can continue decoding the next frame.
+	pub fn next_message(&mut self) -> Result<Option<Message<StreamBody>>, Error> {
+		if self.buffer.len() < FRAMED_HEADER_LEN {
+			return Ok(None);
+		}
+		let framed_header = decode_raw_framed_header(&self.buffer[..FRAMED_HEADER_LEN], self.endian)?;
+		check_payload_too_large(framed_header.body_len, self.max_body_len as usize)?;
+		if self.buffer.len() < FRAMED_HEADER_LEN + framed_header.body_len {
+			return Ok(None);
+		}
+
+		let _ = self.buffer.split_to(FRAMED_HEADER_LEN);
+		let body = self.buffer.split_to(framed_header.body_len).freeze();
+		let body = if framed_header.compression == 0 {
+			StreamBody::from_bytes(body)
+		} else {
+			StreamBody::from(self.compression.decompress(framed_header.compression, &body, self.max_body_len as usize)?)
+		};
+		Ok(Some(Message::new(framed_header.header, body)))
+	}
+}
+
+/// Sans-io encoder for a stream of frames.
+///
+/// Encode messages into the encoder with [`Self::encode()`],
+/// and take the encoded bytes out of it with [`Self::data()`] and [`Self::consume()`], or with [`Self::take()`].
+///
+/// The encoder never compresses message bodies.
+#[derive(Debug)]
+pub struct FrameEncoder {
+	/// The endianness to use for encoding header fields.
+	endian: Endian,
+
+	/// The maximum body length to enforce.
+	max_body_len: u32,
+
+	/// The encoded bytes that have not been taken out of the encoder yet.
+	buffer: Vec<u8>,
+}
+
+impl FrameEncoder {
+	/// Create a new encoder.
+	pub fn new(endian: Endian, max_body_len: u32) -> Self {
+		Self {
+			endian,
+			max_body_len,
+			buffer: Vec::new(),
+		}
+	}
+
+	/// Encode a message as a frame.
+	///
+	/// Returns an error if the body is larger than the maximum body length.
+	/// Nothing is added to the encoder in that case.
+	pub fn encode(&mut self, header: &MessageHeader, body: &[u8]) -> Result<(), Error> {
+		check_payload_too_large(body.len(), self.max_body_len as usize)?;
+		encode_frame(&mut self.buffer, header, body, self.endian)
+	}
+
+	/// Get the encoded bytes that have not been consumed yet.
+	pub fn data(&self) -> &[u8] {
+		&self.buffer
+	}
+
+	/// Remove the first `len` encoded bytes, after they have been sent.
+	///
+	/// # Panics
+	/// This function panics if `len` is larger than the number of encoded bytes.
+	pub fn consume(&mut self, len: usize) {
+		self.buffer.drain(..len);
+	}
+
+	/// Take all encoded bytes out of the encoder.
+	pub fn take(&mut self) -> Vec<u8> {
+		std::mem::take(&mut self.buffer)
+	}
+}
+
+impl Message<StreamBody> {
+	/// Serialize the message as a single frame with little endian header fields.
+	///
+	/// The result can be turned back into a message with [`Self::from_bytes()`].
+	/// It is also exactly what a [`StreamTransport`][super::StreamTransport] configured for little endian sends for an uncompressed message.
+	///
+	/// Returns an error if the body is too large to fit in a frame.
+	pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+		let mut buffer = Vec::new();
+		encode_frame(&mut buffer, &self.header, &self.body, Endian::LittleEndian)?;
+		Ok(buffer)
+	}
+
+	/// Deserialize a message from a single frame with little endian header fields.
+	///
+	/// Returns an error if the data is not exactly one complete frame.
+	pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+		let (message, frame_len) = match decode_frame(data, Endian::LittleEndian, crate::MAX_PAYLOAD_LEN)? {
+			Some(x) => x,
+			None => return Err(Error::custom(format!("incomplete frame: got only {} bytes", data.len()))),
+		};
+		if frame_len != data.len() {
+			return Err(Error::custom(format!("unexpected data after frame: frame is {} bytes, got {} bytes", frame_len, data.len())));
+		}
+		Ok(message)
+	}
+}
+
+/// The frame length and message header of a frame that may be compressed.
+pub(super) struct RawFramedHeader {
+	/// The message header, with the compression algorithm removed from the message type.
+	pub(super) header: MessageHeader,
+
+	/// The length of the body on the wire.
+	pub(super) body_len: usize,
+
+	/// The compression algorithm of the body, or zero if the body is not compressed.
+	pub(super) compression: u8,
+}
+
+/// Decode the frame length and message header of a frame that may be compressed.
+///
+/// The second byte of the message type holds the compression algorithm of the body.
+/// The body length is not checked against a maximum.
+pub(super) fn decode_raw_framed_header(buffer: &[u8], endian: Endian) -> Result<RawFramedHeader, Error> {
+	let frame_len = endian.read_u32(&buffer[0..]) as usize;
+	check_message_too_short(frame_len)?;
+	let mut header = [0u8; crate::HEADER_LEN as usize];
+	header.copy_from_slice(&buffer[4..FRAMED_HEADER_LEN]);
+	let message_type = endian.read_u32(&header[0..]);
+	endian.write_u32(&mut header[0..], message_type & !0xFF00);
+	Ok(RawFramedHeader {
+		header: MessageHeader::decode(&header, endian)?,
+		body_len: frame_len - crate::HEADER_LEN as usize,
+		compression: (message_type >> 8) as u8,
+	})
+}
+
+/// Encode the frame length and message header for a body of the given length.
+pub(super) fn encode_framed_header(buffer: &mut [u8; FRAMED_HEADER_LEN], header: &MessageHeader, body_len: usize, endian: Endian) {
+	endian.write_u32(&mut buffer[0..], body_len as u32 + crate::HEADER_LEN);
+	header.encode(&mut buffer[4..], endian);
+}
+
+/// Decode the frame length and message header, and check the body length.
+///
+/// Returns the message header and the length of the body.
+fn decode_framed_header(buffer: &[u8], endian: Endian, max_body_len: u32) -> Result<(MessageHeader, usize), Error> {
+	let frame_len = endian.read_u32(&buffer[0..]) as usize;
+	check_message_too_short(frame_len)?;
+	let body_len = frame_len - crate::HEADER_LEN as usize;
+	check_payload_too_large(body_len, max_body_len as usize)?;
+	let header = MessageHeader::decode(&buffer[4..], endian)?;
+	Ok((header, body_len))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::{assert, let_assert};
+
+	#[test]
+	fn encode_decode_buffer() {
+		let mut buffer = Vec::new();
+		assert!(let Ok(()) = encode_frame(&mut buffer, &MessageHeader::request(1, 10), b"hello", Endian::BigEndian));
+		assert!(let Ok(()) = encode_frame(&mut buffer, &MessageHeader::stream(0, 11), b"", Endian::BigEndian));
+		assert!(buffer.len() == 2 * FRAMED_HEADER_LEN + 5);
+		assert!(buffer[..4] == [0, 0, 0, 17]);
+
+		// Incomplete frames are not decoded.
+		assert!(let Ok(None) = decode_frame(&buffer[..FRAMED_HEADER_LEN - 1], Endian::BigEndian, 100));
+		assert!(let Ok(None) = decode_frame(&buffer[..FRAMED_HEADER_LEN + 4], Endian::BigEndian, 100));
+
+		let_assert!(Ok(Some((message, len))) = decode_frame(&buffer, Endian::BigEndian, 100));
+		assert!(message.header == MessageHeader::request(1, 10));
+		assert!(message.body.as_ref() == b"hello");
+		let_assert!(Ok(Some((message, rest))) = decode_frame(&buffer[len..], Endian::BigEndian, 100));
+		assert!(message.header == MessageHeader::stream(0, 11));
+		assert!(message.body.is_empty());
+		assert!(len + rest == buffer.len());
+
+		// The body length is checked before the body is complete.
+		assert!(let Err(_) = decode_frame(&buffer[..FRAMED_HEADER_LEN], Endian::BigEndian, 4));
+	}
+
+	#[test]
+	fn decode_invalid_frame() {
+		// The frame length must include the message header.
+		let buffer = [0, 0, 0, 11, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+		assert!(let Err(_) = decode_frame(&buffer, Endian::BigEndian, 100));
+
+		// The message type must be valid.
+		let buffer = [0, 0, 0, 12, 0, 0, 0, 99, 0, 0, 0, 0, 0, 0, 0, 0];
+		let_assert!(Err(e) = decode_frame(&buffer, Endian::BigEndian, 100));
+		assert!(e.to_string().contains("invalid message type"));
+	}
+
+	#[test]
+	fn message_to_from_bytes() {
+		let message = Message::request(7, 10, StreamBody::from(&b"hello"[..])).with_flags(0x0100);
+		let_assert!(Ok(bytes) = message.to_bytes());
+		assert!(bytes[..4] == [17, 0, 0, 0]);
+		let_assert!(Ok(parsed) = Message::from_bytes(&bytes));
+		assert!(parsed.header == message.header);
+		assert!(parsed.body.as_ref() == b"hello");
+
+		// The data must be exactly one frame.
+		assert!(let Err(_) = Message::from_bytes(&bytes[..bytes.len() - 1]));
+		let mut extended = bytes.clone();
+		extended.push(0);
+		assert!(let Err(_) = Message::from_bytes(&extended));
+	}
+
+	#[tokio::test]
+	async fn read_write_stream() {
+		let_assert!(Ok((mut stream_a, mut stream_b)) = tokio::net::UnixStream::pair());
+		assert!(let Ok(()) = write_message(&mut stream_a, &MessageHeader::response(2, 12), b"world", Endian::LittleEndian).await);
+		let_assert!(Ok(message) = read_message(&mut stream_b, Endian::LittleEndian, 100).await);
+		assert!(message.header == MessageHeader::response(2, 12));
+		assert!(message.body.as_ref() == b"world");
+
+		// The frames are compatible with the stream transport.
+		use crate::transport::{Transport, TransportReadHalf};
+		let mut transport_b = super::super::StreamTransport::new(stream_b, Default::default());
+		let (mut read_b, _write_b) = transport_b.split();
+		assert!(let Ok(()) = write_message(&mut stream_a, &MessageHeader::stream(0, 13), b"!", Endian::LittleEndian).await);
+		let_assert!(Ok(message) = read_b.read_msg().await);
+		assert!(message.header == MessageHeader::stream(0, 13));
+		assert!(message.body.as_ref() == b"!");
+	}
+
+	#[test]
+	fn sans_io_encoder_decoder() {
+		let mut encoder = FrameEncoder::new(Endian::LittleEndian, 10);
+		assert!(let Ok(()) = encoder.encode(&MessageHeader::request(1, 10), b"hello"));
+		assert!(let Err(_) = encoder.encode(&MessageHeader::request(2, 10), b"way too large"));
+		assert!(let Ok(()) = encoder.encode(&MessageHeader::stream(0, 11), b"world"));
+		assert!(encoder.data().len() == 2 * (FRAMED_HEADER_LEN + 5));
+
+		// Messages are decoded when the last byte of their frame is pushed.
+		let mut decoder = FrameDecoder::new(Endian::LittleEndian, 10);
+		let mut messages = Vec::new();
+		for &byte in encoder.data() {
+			decoder.push(&[byte]);
+			while let Some(message) = decoder.next_message().unwrap() {
+				messages.push((decoder.buffered_len(), message));
+			}
+		}
+		assert!(messages.len() == 2);
+		assert!(messages[0].0 == 0);
+		assert!(messages[0].1.header == MessageHeader::request(1, 10));
+		assert!(messages[0].1.body.as_ref() == b"hello");
+		assert!(messages[1].1.header == MessageHeader::stream(0, 11));
+		assert!(messages[1].1.body.as_ref() == b"world");
+
+		encoder.consume(FRAMED_HEADER_LEN + 5);
+		assert!(encoder.take().len() == FRAMED_HEADER_LEN + 5);
+		assert!(encoder.data().is_empty());
+	}
+
+	#[test]
+	fn sans_io_decoder_errors() {
+		// A frame that is too large is rejected before the body is complete, and is not consumed.
+		let mut buffer = Vec::new();
+		assert!(let Ok(()) = encode_frame(&mut buffer, &MessageHeader::stream(0, 1), b"too large", Endian::BigEndian));
+		let mut decoder = FrameDecoder::new(Endian::BigEndian, 4);
+		decoder.push(&buffer[..FRAMED_HEADER_LEN]);
+		assert!(let Err(_) = decoder.next_message());
+		assert!(let Err(_) = decoder.next_message());
+		assert!(decoder.buffered_len() == FRAMED_HEADER_LEN);
+
+		// The frame length must include the message header.
+		let mut decoder = FrameDecoder::new(Endian::BigEndian, 100);
+		decoder.push(&[0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+		assert!(let Err(_) = decoder.next_message());
+
+		// A compressed frame with an algorithm that is not accepted is consumed.
+		let mut buffer = Vec::new();
+		assert!(let Ok(()) = encode_frame(&mut buffer, &MessageHeader::stream(0, 1), b"compressed", Endian::BigEndian));
+		buffer[6] = 1;
+		assert!(let Ok(()) = encode_frame(&mut buffer, &MessageHeader::stream(0, 2), b"plain", Endian::BigEndian));
+		let mut decoder = FrameDecoder::new(Endian::BigEndian, 100);
+		decoder.push(&buffer);
+		let_assert!(Err(e) = decoder.next_message());
+		assert!(e.to_string().contains("unsupported compression algorithm"));
+		let_assert!(Ok(Some(message)) = decoder.next_message());
+		assert!(message.header == MessageHeader::stream(0, 2));
+		assert!(let Ok(None) = decoder.next_message());
+	}
+
+	#[tokio::test]
+	async fn stream_transport_rejects_short_frames() {
+		use tokio::io::AsyncWriteExt;
+		use crate::transport::{Transport, TransportReadHalf};
+
+		let_assert!(Ok((mut stream_a, stream_b)) = tokio::net::UnixStream::pair());
+		let mut transport_b = super::super::StreamTransport::new(stream_b, Default::default());
+		let (mut read_b, _write_b) = transport_b.split();
+		assert!(let Ok(()) = stream_a.write_all(&[4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).await);
+		let_assert!(Err(e) = read_b.read_msg().await);
+		assert!(e.is_fatal());
+	}
+
+	#[cfg(any(feature = "lz4", feature = "zstd"))]
+	#[tokio::test]
+	async fn sans_io_decoder_decompresses() {
+		use crate::transport::{Transport, TransportReadHalf, TransportWriteHalf};
+		use crate::transport::stream::{StreamConfig, StreamTransport};
+		use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+		#[cfg(feature = "lz4")]
+		let (algorithm, algorithm_id) = (super::super::Compression::Lz4, 1);
+		#[cfg(not(feature = "lz4"))]
+		let (algorithm, algorithm_id) = (super::super::Compression::Zstd { level: 0 }, 2);
+		let config = StreamConfig {
+			compression: vec![algorithm],
+			compression_threshold: 0,
+			..Default::default()
+		};
+		let_assert!(Ok((stream_a, mut stream_b)) = tokio::net::UnixStream::pair());
+		let mut transport_a = StreamTransport::new(stream_a, config);
+		let (mut read_a, mut write_a) = transport_a.split();
+
+		// Announce support for the algorithm, so the transport starts compressing.
+		let mut encoder = FrameEncoder::new(Endian::LittleEndian, 100);
+		assert!(let Ok(()) = encoder.encode(&MessageHeader::stream(0, crate::service_id::COMPRESSION), &[algorithm_id]));
+		assert!(let Ok(()) = encoder.encode(&MessageHeader::stream(0, 1), b"go"));
+		assert!(let Ok(()) = stream_b.write_all(encoder.data()).await);
+		let_assert!(Ok(message) = read_a.read_msg().await);
+		assert!(message.header == MessageHeader::stream(0, 1));
+
+		let body = vec![b'a'; 4_000];
+		assert!(let Ok(()) = write_a.write_msg(&MessageHeader::stream(0, 2), &body[..].into()).await);
+
+		let mut decoder = FrameDecoder::new(Endian::LittleEndian, 1 << 20).with_compression(vec![algorithm]);
+		let mut messages = Vec::new();
+		while messages.len() < 2 {
+			let mut buffer = [0u8; 1024];
+			let_assert!(Ok(len) = stream_b.read(&mut buffer).await);
+			assert!(len > 0);
+			decoder.push(&buffer[..len]);
+			while let Some(message) = decoder.next_message().unwrap() {
+				messages.push(message);
+			}
+		}
+		assert!(messages[0].header == MessageHeader::stream(0, crate::service_id::COMPRESSION));
+		assert!(messages[1].header == MessageHeader::stream(0, 2));
+		assert!(messages[1].body.as_ref() == body.as_slice());
+	}
+}
@@ -0,0 +1,161 @@
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::compression::CompressionState;
+use super::pool::BufferPool;
+use super::{StreamBody, StreamConfig, StreamReadHalf, StreamTransport, StreamWriteHalf};
+use crate::transport::EndianState;
+
+/// Any byte stream that implements [`AsyncRead`] and [`AsyncWrite`], for use with a [`StreamTransport`].
+///
+/// This can be used to run peers over TLS streams, serial ports or custom tunnels,
+/// without implementing a transport from scratch:
+/// wrap the stream in a [`GenericStream`] and pass it to [`StreamTransport::new()`].
+///
+/// The read and write half of the transport share the stream through a mutex,
+/// similar to [`tokio::io::split()`](https://docs.rs/tokio/latest/tokio/io/fn.split.html).
+/// The mutex is only held while the stream is polled, so the halves never block each other.
+///
+/// The transport has no information about the remote peer, so [`Transport::Info`][crate::transport::Transport::Info] is `()`.
+pub struct GenericStream<S> {
+	/// The wrapped stream.
+	stream: Mutex<S>,
+}
+
+impl<S> GenericStream<S> {
+	/// Wrap a byte stream.
+	pub fn new(stream: S) -> Self {
+		Self {
+			stream: Mutex::new(stream),
+		}
+	}
+
+	/// Get direct mutable access to the wrapped stream.
+	pub fn get_mut(&mut self) -> &mut S {
+		self.stream.get_mut().unwrap()
+	}
+
+	/// Consume the wrapper to retrieve the wrapped stream.
+	pub fn into_inner(self) -> S {
+		self.stream.into_inner().unwrap()
+	}
+}
+
+impl<S> std::fmt::Debug for GenericStream<S> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("GenericStream").finish_non_exhaustive()
+	}
+}
+
+/// The read half of a [`GenericStream`].
+pub struct GenericReadHalf<'a, S> {
+	/// The shared stream.
+	stream: &'a Mutex<S>,
+}
+
+/// The write half of a [`GenericStream`].
+pub struct GenericWriteHalf<'a, S> {
+	/// The shared stream.
+	stream: &'a Mutex<S>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for GenericReadHalf<'_, S> {
+	fn poll_read(self: Pin<&mut Self>, context: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+		let mut stream = self.stream.lock().unwrap();
+		Pin::new(&mut *stream).poll_read(context, buf)
+	}
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for GenericWriteHalf<'_, S> {
+	fn poll_write(self: Pin<&mut Self>, context: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+		let mut stream = self.stream.lock().unwrap();
+		Pin::new(&mut *stream).poll_write(context, buf)
+	}
+
+	fn poll_write_vectored(self: Pin<&mut Self>, context: &mut Context<'_>, bufs: &[std::io::IoSlice<'_>]) -> Poll<std::io::Result<usize>> {
+		let mut stream = self.stream.lock().unwrap();
+		Pin::new(&mut *stream).poll_write_vectored(context, bufs)
+	}
+
+	fn is_write_vectored(&self) -> bool {
+		self.stream.lock().unwrap().is_write_vectored()
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		let mut stream = self.stream.lock().unwrap();
+		Pin::new(&mut *stream).poll_flush(context)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		let mut stream = self.stream.lock().unwrap();
+		Pin::new(&mut *stream).poll_shutdown(context)
+	}
+}
+
+impl<S> crate::transport::Transport for StreamTransport<GenericStream<S>>
+where
+	S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+	type Body = StreamBody;
+	type Info = ();
+	type Config = StreamConfig;
+	type ReadHalf<'a> = StreamReadHalf<GenericReadHalf<'a, S>>;
+	type WriteHalf<'a> = StreamWriteHalf<GenericWriteHalf<'a, S>>;
+
+	fn split(&mut self) -> (StreamReadHalf<GenericReadHalf<'_, S>>, StreamWriteHalf<GenericWriteHalf<'_, S>>) {
+		let read_half = GenericReadHalf { stream: &self.stream.stream };
+		let write_half = GenericWriteHalf { stream: &self.stream.stream };
+		let compression = CompressionState::new(self.config.compression.clone(), self.config.compression_threshold);
+		let endian = EndianState::new(self.config.endian, self.config.detect_endian);
+		let read_half = StreamReadHalf::new(read_half, self.config.max_body_len_read, endian.clone(), self.config.trace.clone(), self.config.error_policy.clone(), compression.clone(), BufferPool::new(self.config.buffer_pool_size))
+			.with_body_sink(self.config.body_sink.clone());
+		let write_half = StreamWriteHalf::new(write_half, self.config.max_body_len_write, endian, self.config.trace.clone(), compression);
+		(read_half, write_half)
+	}
+
+	fn info(&self) -> std::io::Result<Self::Info> {
+		Ok(())
+	}
+}
+
+impl<S> crate::util::IntoTransport for GenericStream<S>
+where
+	S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+	type Body = StreamBody;
+	type Config = StreamConfig;
+	type Transport = StreamTransport<GenericStream<S>>;
+
+	fn into_transport(self, config: Self::Config) -> Self::Transport {
+		StreamTransport::new(self, config)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+
+	use crate::{Peer, ReceivedMessage};
+
+	#[tokio::test]
+	async fn peer_over_duplex_stream() {
+		let (stream_a, stream_b) = tokio::io::duplex(64);
+		let handle_a = Peer::spawn(StreamTransport::new(GenericStream::new(stream_a), StreamConfig::default()));
+		let mut handle_b = Peer::spawn(StreamTransport::new(GenericStream::new(stream_b), StreamConfig::default()));
+
+		// The body is larger than the buffer of the duplex stream, so reads and writes interleave.
+		let body = vec![7u8; 1000];
+		let_assert!(Ok(mut sent_request) = handle_a.send_request(1, &body[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received_request, received_body)) = handle_b.recv_message().await);
+		assert!(received_body.as_ref() == &body[..]);
+
+		let_assert!(Ok(()) = received_request.send_response(2, &b"done"[..]).await);
+		let_assert!(Ok(response) = sent_request.recv_response().await);
+		assert!(response.header.service_id == 2);
+		assert!(response.body.as_ref() == b"done");
+	}
+}
@@ -1,11 +1,37 @@
 mod body;
+mod compression;
 mod config;
+pub mod frame;
+mod pool;
+mod sink;
 mod transport;
 
+#[cfg(feature = "tcp")]
+mod proxy;
+
+#[cfg(feature = "generic-stream")]
+mod generic;
+
+#[cfg(feature = "quic")]
+mod quic;
+
 pub use body::StreamBody;
+pub use compression::Compression;
 pub use config::StreamConfig;
+pub use sink::BodySink;
+// The read and write halves are only used by the transports enabled with feature flags.
+#[allow(unused_imports)]
 pub use transport::{StreamReadHalf, StreamTransport, StreamWriteHalf};
 
+#[cfg(feature = "tcp")]
+pub use proxy::{Proxy, ProxyProtocol};
+
+#[cfg(feature = "generic-stream")]
+pub use generic::{GenericReadHalf, GenericStream, GenericWriteHalf};
+
+#[cfg(feature = "quic")]
+pub use quic::{QuicEndpoint, QuicStream, QuicStreamInfo};
+
 /// Information about the remote peer of a Unix stream.
 #[derive(Debug, Clone)]
 #[cfg(feature = "unix-stream")]
@@ -43,6 +69,9 @@ mod impl_unix_stream {
 	use std::future::Future;
 	use std::pin::Pin;
 	use super::*;
+	use super::compression::CompressionState;
+	use super::pool::BufferPool;
+	use crate::transport::EndianState;
 
 	impl crate::transport::Transport for StreamTransport<tokio::net::UnixStream> {
 		type Body = StreamBody;
@@ -51,10 +80,13 @@ mod impl_unix_stream {
 		type ReadHalf<'a> = StreamReadHalf<tokio::net::unix::ReadHalf<'a>>;
 		type WriteHalf<'a> = StreamWriteHalf<tokio::net::unix::WriteHalf<'a>>;
 
-		fn split(&mut self) -> (StreamReadHalf<tokio::net::unix::ReadHalf>, StreamWriteHalf<tokio::net::unix::WriteHalf>) {
+		fn split(&mut self) -> (StreamReadHalf<tokio::net::unix::ReadHalf<'_>>, StreamWriteHalf<tokio::net::unix::WriteHalf<'_>>) {
 			let (read_half, write_half) = self.stream.split();
-			let read_half = StreamReadHalf::new(read_half, self.config.max_body_len_read, self.config.endian);
-			let write_half = StreamWriteHalf::new(write_half, self.config.max_body_len_write, self.config.endian);
+			let compression = CompressionState::new(self.config.compression.clone(), self.config.compression_threshold);
+			let endian = EndianState::new(self.config.endian, self.config.detect_endian);
+			let read_half = StreamReadHalf::new(read_half, self.config.max_body_len_read, endian.clone(), self.config.trace.clone(), self.config.error_policy.clone(), compression.clone(), BufferPool::new(self.config.buffer_pool_size))
+				.with_body_sink(self.config.body_sink.clone());
+			let write_half = StreamWriteHalf::new(write_half, self.config.max_body_len_write, endian, self.config.trace.clone(), compression);
 			(read_half, write_half)
 		}
 
@@ -66,6 +98,14 @@ mod impl_unix_stream {
 				process_id: creds.pid(),
 			})
 		}
+
+		fn describe_remote(&self) -> Option<String> {
+			let creds = self.stream.peer_cred().ok()?;
+			match creds.pid() {
+				Some(pid) => Some(format!("unix pid {} uid {}", pid, creds.uid())),
+				None => Some(format!("unix uid {}", creds.uid())),
+			}
+		}
 	}
 
 	impl crate::util::IntoTransport for tokio::net::UnixStream {
@@ -114,6 +154,21 @@ mod impl_unix_stream {
 			})
 		}
 	}
+
+	impl crate::util::FromStd for tokio::net::UnixListener {
+		type Std = std::os::unix::net::UnixListener;
+
+		fn from_std(listener: Self::Std) -> std::io::Result<Self> {
+			listener.set_nonblocking(true)?;
+			Self::from_std(listener)
+		}
+	}
+
+	impl crate::util::FromFd for tokio::net::UnixListener {
+		fn from_fd(fd: std::os::unix::io::OwnedFd) -> std::io::Result<Self> {
+			crate::util::FromStd::from_std(std::os::unix::net::UnixListener::from(fd))
+		}
+	}
 }
 
 /// Information about the remote peer of a Unix stream.
@@ -145,6 +200,9 @@ mod impl_tcp {
 	use std::future::Future;
 	use std::pin::Pin;
 	use super::*;
+	use super::compression::CompressionState;
+	use super::pool::BufferPool;
+	use crate::transport::EndianState;
 
 	impl crate::transport::Transport for StreamTransport<tokio::net::TcpStream> {
 		type Body = StreamBody;
@@ -153,10 +211,13 @@ mod impl_tcp {
 		type ReadHalf<'a> = StreamReadHalf<tokio::net::tcp::ReadHalf<'a>>;
 		type WriteHalf<'a> = StreamWriteHalf<tokio::net::tcp::WriteHalf<'a>>;
 
-		fn split(&mut self) -> (StreamReadHalf<tokio::net::tcp::ReadHalf>, StreamWriteHalf<tokio::net::tcp::WriteHalf>) {
+		fn split(&mut self) -> (StreamReadHalf<tokio::net::tcp::ReadHalf<'_>>, StreamWriteHalf<tokio::net::tcp::WriteHalf<'_>>) {
 			let (read_half, write_half) = self.stream.split();
-			let read_half = StreamReadHalf::new(read_half, self.config.max_body_len_read, self.config.endian);
-			let write_half = StreamWriteHalf::new(write_half, self.config.max_body_len_write, self.config.endian);
+			let compression = CompressionState::new(self.config.compression.clone(), self.config.compression_threshold);
+			let endian = EndianState::new(self.config.endian, self.config.detect_endian);
+			let read_half = StreamReadHalf::new(read_half, self.config.max_body_len_read, endian.clone(), self.config.trace.clone(), self.config.error_policy.clone(), compression.clone(), BufferPool::new(self.config.buffer_pool_size))
+				.with_body_sink(self.config.body_sink.clone());
+			let write_half = StreamWriteHalf::new(write_half, self.config.max_body_len_write, endian, self.config.trace.clone(), compression);
 			(read_half, write_half)
 		}
 
@@ -166,6 +227,11 @@ mod impl_tcp {
 				remote_address: self.stream.peer_addr()?,
 			})
 		}
+
+		fn describe_remote(&self) -> Option<String> {
+			let address = self.stream.peer_addr().ok()?;
+			Some(format!("tcp {}", address))
+		}
 	}
 
 	impl crate::util::IntoTransport for tokio::net::TcpStream {
@@ -186,7 +252,10 @@ mod impl_tcp {
 
 		fn connect(address: Address, config: Self::Config) -> Self::Future {
 			Box::pin(async {
-				let socket = tokio::net::TcpStream::connect(address).await?;
+				let socket = match &config.proxy {
+					Some(proxy) => proxy.connect_tcp(address).await?,
+					None => tokio::net::TcpStream::connect(address).await?,
+				};
 				Ok(Self::new(socket, config))
 			})
 		}
@@ -202,6 +271,21 @@ mod impl_tcp {
 			Box::pin(Self::bind(address))
 		}
 	}
+
+	impl crate::util::FromStd for tokio::net::TcpListener {
+		type Std = std::net::TcpListener;
+
+		fn from_std(listener: Self::Std) -> std::io::Result<Self> {
+			listener.set_nonblocking(true)?;
+			Self::from_std(listener)
+		}
+	}
+
+	impl crate::util::FromFd for tokio::net::TcpListener {
+		fn from_fd(fd: std::os::unix::io::OwnedFd) -> std::io::Result<Self> {
+			crate::util::FromStd::from_std(std::net::TcpListener::from(fd))
+		}
+	}
 }
 
 #[cfg(test)]
@@ -237,4 +321,110 @@ mod test {
 			assert!(message.body.as_ref() == b"Hello peer_a!");
 		}
 	}
+
+	#[tokio::test]
+	async fn buffer_pool() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+
+		let config = StreamConfig {
+			buffer_pool_size: 2,
+			..Default::default()
+		};
+		let mut transport_a = StreamTransport::new(peer_a, StreamConfig::default());
+		let mut transport_b = StreamTransport::new(peer_b, config);
+
+		use crate::transport::{Transport, TransportReadHalf, TransportWriteHalf};
+		let (_read_a, mut write_a) = transport_a.split();
+		let (mut read_b, _write_b) = transport_b.split();
+
+		// The buffer of a dropped body is re-used for the next message.
+		assert!(let Ok(()) = write_a.write_msg(&MessageHeader::stream(0, 1), &b"Hello peer_b!"[..].into()).await);
+		let_assert!(Ok(message) = read_b.read_msg().await);
+		assert!(message.body.as_ref() == b"Hello peer_b!");
+		let address = message.body.as_ptr();
+		drop(message);
+
+		assert!(let Ok(()) = write_a.write_msg(&MessageHeader::stream(0, 2), &b"Hello!"[..].into()).await);
+		let_assert!(Ok(message) = read_b.read_msg().await);
+		assert!(message.header == MessageHeader::stream(0, 2));
+		assert!(message.body.as_ref() == b"Hello!");
+		assert!(message.body.as_ptr() == address);
+
+		// A body that is still alive is not overwritten.
+		assert!(let Ok(()) = write_a.write_msg(&MessageHeader::stream(0, 3), &b"Bye!"[..].into()).await);
+		let_assert!(Ok(next) = read_b.read_msg().await);
+		assert!(next.body.as_ref() == b"Bye!");
+		assert!(next.body.as_ptr() != address);
+		assert!(message.body.as_ref() == b"Hello!");
+	}
+
+	#[tokio::test]
+	async fn write_batch() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+
+		let config = StreamConfig {
+			max_body_len_write: 10,
+			..Default::default()
+		};
+		let mut transport_a = StreamTransport::new(peer_a, config);
+		let mut transport_b = StreamTransport::new(peer_b, StreamConfig::default());
+
+		use crate::transport::{Transport, TransportReadHalf, TransportWriteHalf};
+		let (_read_a, mut write_a) = transport_a.split();
+		let (mut read_b, _write_b) = transport_b.split();
+
+		let messages = [
+			crate::Message::stream(0, 1, b"one"[..].into()),
+			crate::Message::stream(0, 2, b"two"[..].into()),
+			crate::Message::stream(0, 3, b"way too large"[..].into()),
+			crate::Message::stream(0, 4, b"four"[..].into()),
+		];
+
+		// The batch stops at the message that is too large.
+		let mut written = 0;
+		let_assert!(Err(e) = write_a.write_msgs(&messages, &mut written).await);
+		assert!(!e.is_fatal());
+		assert!(written == 2);
+
+		// Skip the failed message and write the rest.
+		written += 1;
+		assert!(let Ok(()) = write_a.write_msgs(&messages, &mut written).await);
+		assert!(written == 4);
+
+		for expected in [&messages[0], &messages[1], &messages[3]] {
+			let_assert!(Ok(message) = read_b.read_msg().await);
+			assert!(message.header == expected.header);
+			assert!(message.body.as_ref() == expected.body.as_ref());
+		}
+	}
+
+	#[tokio::test]
+	async fn detect_endian() {
+		let_assert!(Ok((peer_a, peer_b)) = UnixStream::pair());
+
+		let config_a = StreamConfig {
+			endian: crate::transport::Endian::BigEndian,
+			..Default::default()
+		};
+		let config_b = StreamConfig {
+			endian: crate::transport::Endian::LittleEndian,
+			detect_endian: true,
+			..Default::default()
+		};
+		let mut transport_a = StreamTransport::new(peer_a, config_a);
+		let mut transport_b = StreamTransport::new(peer_b, config_b);
+
+		use crate::transport::{Transport, TransportReadHalf, TransportWriteHalf};
+		let (mut read_a, mut write_a) = transport_a.split();
+		let (mut read_b, mut write_b) = transport_b.split();
+
+		// Peer B detects that peer A uses big endian, and answers in big endian.
+		assert!(let Ok(()) = write_a.write_msg(&MessageHeader::request(0, 10), &b"Hello peer_b!"[..].into()).await);
+		let_assert!(Ok(message) = read_b.read_msg().await);
+		assert!(message.header == MessageHeader::request(0, 10));
+		assert!(let Ok(()) = write_b.write_msg(&MessageHeader::response(0, 10), &b"Hello peer_a!"[..].into()).await);
+		let_assert!(Ok(message) = read_a.read_msg().await);
+		assert!(message.header == MessageHeader::response(0, 10));
+		assert!(message.body.as_ref() == b"Hello peer_a!");
+	}
 }
@@ -0,0 +1,151 @@
+use bytes::Bytes;
+use std::sync::{Arc, Mutex, Weak};
+
+/// A pool of body buffers for incoming messages.
+///
+/// Buffers handed out by the pool are returned to it when the last [`Bytes`] referring to them is dropped.
+/// The pool keeps at most `max_buffers` buffers, additional buffers are simply deallocated.
+/// If `max_buffers` is zero, pooling is disabled entirely.
+#[derive(Clone)]
+#[allow(dead_code)] // Not used when transports are disabled.
+pub(super) struct BufferPool {
+	inner: Arc<PoolInner>,
+}
+
+/// The shared state of a [`BufferPool`].
+struct PoolInner {
+	/// The maximum number of buffers to keep in the pool.
+	max_buffers: usize,
+
+	/// The buffers available for re-use.
+	buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+/// A buffer that returns itself to the pool when dropped.
+struct PooledBuffer {
+	/// The buffer itself.
+	buffer: Vec<u8>,
+
+	/// The pool to return the buffer to.
+	pool: Weak<PoolInner>,
+}
+
+#[allow(dead_code)] // Not used when transports are disabled.
+impl BufferPool {
+	/// Create a new pool that keeps at most `max_buffers` buffers.
+	pub(super) fn new(max_buffers: usize) -> Self {
+		Self {
+			inner: Arc::new(PoolInner {
+				max_buffers,
+				buffers: Mutex::new(Vec::new()),
+			}),
+		}
+	}
+
+	/// Get a zero-filled buffer of the given length, re-using a buffer from the pool if possible.
+	pub(super) fn take(&self, len: usize) -> Vec<u8> {
+		let mut buffer = self.inner.lock().pop().unwrap_or_default();
+		buffer.clear();
+		buffer.resize(len, 0);
+		buffer
+	}
+
+	/// Give a buffer back to the pool directly.
+	pub(super) fn give_back(&self, buffer: Vec<u8>) {
+		self.inner.give_back(buffer);
+	}
+
+	/// Convert a buffer into [`Bytes`] that returns the buffer to the pool when dropped.
+	pub(super) fn wrap(&self, buffer: Vec<u8>) -> Bytes {
+		if self.inner.max_buffers == 0 {
+			return buffer.into();
+		}
+		Bytes::from_owner(PooledBuffer {
+			buffer,
+			pool: Arc::downgrade(&self.inner),
+		})
+	}
+
+	/// Get the number of buffers currently available in the pool.
+	#[cfg(test)]
+	fn available(&self) -> usize {
+		self.inner.lock().len()
+	}
+}
+
+impl PoolInner {
+	/// Lock the list of available buffers.
+	fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Vec<u8>>> {
+		match self.buffers.lock() {
+			Ok(x) => x,
+			Err(e) => e.into_inner(),
+		}
+	}
+
+	/// Add a buffer to the pool if it is not full yet.
+	fn give_back(&self, buffer: Vec<u8>) {
+		if buffer.capacity() == 0 {
+			return;
+		}
+		let mut buffers = self.lock();
+		if buffers.len() < self.max_buffers {
+			buffers.push(buffer);
+		}
+	}
+}
+
+impl AsRef<[u8]> for PooledBuffer {
+	fn as_ref(&self) -> &[u8] {
+		&self.buffer
+	}
+}
+
+impl Drop for PooledBuffer {
+	fn drop(&mut self) {
+		if let Some(pool) = self.pool.upgrade() {
+			pool.give_back(std::mem::take(&mut self.buffer));
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+
+	#[test]
+	fn buffers_are_reused() {
+		let pool = BufferPool::new(2);
+		let buffer = pool.take(100);
+		assert!(buffer == [0; 100]);
+		let address = buffer.as_ptr();
+
+		// The buffer returns to the pool only when all references are dropped.
+		let bytes = pool.wrap(buffer);
+		let clone = bytes.clone();
+		drop(bytes);
+		assert!(pool.available() == 0);
+		drop(clone);
+		assert!(pool.available() == 1);
+
+		// The buffer is cleared before it is re-used.
+		let mut buffer = pool.take(50);
+		assert!(buffer.as_ptr() == address);
+		assert!(buffer == [0; 50]);
+		buffer[0] = 1;
+		pool.give_back(buffer);
+		assert!(pool.take(10) == [0; 10]);
+	}
+
+	#[test]
+	fn pool_size_is_limited() {
+		let pool = BufferPool::new(2);
+		let buffers: Vec<_> = (0..3).map(|_| pool.wrap(pool.take(10))).collect();
+		drop(buffers);
+		assert!(pool.available() == 2);
+
+		let pool = BufferPool::new(0);
+		drop(pool.wrap(pool.take(10)));
+		assert!(pool.available() == 0);
+	}
+}
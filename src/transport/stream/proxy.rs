@@ -0,0 +1,435 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::transport::util::{poll_read_exact, poll_write_all_vectored};
+
+/// The maximum size of the response header of an HTTP proxy.
+const MAX_HTTP_RESPONSE_LEN: usize = 8 * 1024;
+
+/// A proxy server to connect to the remote peer through.
+///
+/// Set [`StreamConfig::proxy`][super::StreamConfig::proxy] to connect TCP transports through a proxy,
+/// for example when devices can only reach the server through a corporate proxy.
+/// The proxy is only used when connecting, not when accepting connections.
+///
+/// The address of the remote peer is resolved locally,
+/// and the proxy is asked to connect to the resulting IP address.
+#[derive(Clone, Eq, PartialEq)]
+pub struct Proxy {
+	/// The protocol to talk to the proxy server.
+	protocol: ProxyProtocol,
+
+	/// The address of the proxy server, as `host:port`.
+	address: String,
+
+	/// The username and password to authenticate with the proxy server.
+	credentials: Option<(String, String)>,
+}
+
+/// The protocol used to talk to a proxy server.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ProxyProtocol {
+	/// An HTTP/1.1 proxy that supports the `CONNECT` method.
+	HttpConnect,
+
+	/// A SOCKS5 proxy.
+	Socks5,
+}
+
+impl Proxy {
+	/// Create a new proxy configuration for an HTTP/1.1 proxy that supports the `CONNECT` method.
+	///
+	/// The address must be in the form `host:port`.
+	pub fn http_connect(address: impl Into<String>) -> Self {
+		Self {
+			protocol: ProxyProtocol::HttpConnect,
+			address: address.into(),
+			credentials: None,
+		}
+	}
+
+	/// Create a new proxy configuration for a SOCKS5 proxy.
+	///
+	/// The address must be in the form `host:port`.
+	pub fn socks5(address: impl Into<String>) -> Self {
+		Self {
+			protocol: ProxyProtocol::Socks5,
+			address: address.into(),
+			credentials: None,
+		}
+	}
+
+	/// Authenticate with the proxy server using a username and password.
+	///
+	/// HTTP proxies receive the credentials with basic authentication,
+	/// SOCKS5 proxies with the username/password authentication method.
+	/// Note that basic authentication sends the credentials in plain text.
+	pub fn with_credentials(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+		self.credentials = Some((username.into(), password.into()));
+		self
+	}
+
+	/// Get the protocol used to talk to the proxy server.
+	pub fn protocol(&self) -> ProxyProtocol {
+		self.protocol
+	}
+
+	/// Get the address of the proxy server.
+	pub fn address(&self) -> &str {
+		&self.address
+	}
+
+	/// Connect to a remote address through the proxy.
+	///
+	/// Like [`tokio::net::TcpStream::connect()`], all resolved addresses are tried until one succeeds.
+	#[cfg(feature = "tcp")]
+	pub(crate) async fn connect_tcp(&self, address: impl tokio::net::ToSocketAddrs) -> std::io::Result<tokio::net::TcpStream> {
+		let mut last_error = None;
+		for target in tokio::net::lookup_host(address).await? {
+			let result = async {
+				let mut stream = tokio::net::TcpStream::connect(self.address.as_str()).await?;
+				self.handshake(&mut stream, target).await?;
+				Ok(stream)
+			};
+			match result.await {
+				Ok(stream) => return Ok(stream),
+				Err(e) => last_error = Some(e),
+			}
+		}
+		Err(last_error.unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "could not resolve to any address")))
+	}
+
+	/// Ask the proxy server to connect to the target address.
+	///
+	/// When this function returns successfully, the stream is connected to the target.
+	pub(crate) async fn handshake<S>(&self, stream: &mut S, target: SocketAddr) -> std::io::Result<()>
+	where
+		S: AsyncRead + AsyncWrite + Unpin,
+	{
+		match self.protocol {
+			ProxyProtocol::HttpConnect => self.http_connect_handshake(stream, target).await,
+			ProxyProtocol::Socks5 => self.socks5_handshake(stream, target).await,
+		}
+	}
+
+	/// Perform the handshake with an HTTP proxy.
+	async fn http_connect_handshake<S>(&self, stream: &mut S, target: SocketAddr) -> std::io::Result<()>
+	where
+		S: AsyncRead + AsyncWrite + Unpin,
+	{
+		let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+		if let Some((username, password)) = &self.credentials {
+			let credentials = base64_encode(format!("{username}:{password}").as_bytes());
+			request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+		}
+		request.push_str("\r\n");
+		write_all(stream, request.as_bytes()).await?;
+
+		// Read the response one byte at a time, so we do not consume data sent by the remote peer after the response.
+		let mut response = Vec::new();
+		while !response.ends_with(b"\r\n\r\n") {
+			if response.len() >= MAX_HTTP_RESPONSE_LEN {
+				return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "response header of HTTP proxy is too large"));
+			}
+			let mut byte = [0u8];
+			read_exact(stream, &mut byte).await?;
+			response.push(byte[0]);
+		}
+
+		let response = String::from_utf8_lossy(&response);
+		let status_line = response.lines().next().unwrap_or_default();
+		let mut fields = status_line.split(' ');
+		let version = fields.next().unwrap_or_default();
+		let status = fields.next().and_then(|status| status.parse::<u16>().ok());
+		match status {
+			_ if !version.starts_with("HTTP/1.") => {
+				Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid response from HTTP proxy: {status_line}")))
+			},
+			Some(200..=299) => Ok(()),
+			_ => Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, format!("HTTP proxy refused to connect: {status_line}"))),
+		}
+	}
+
+	/// Perform the handshake with a SOCKS5 proxy.
+	async fn socks5_handshake<S>(&self, stream: &mut S, target: SocketAddr) -> std::io::Result<()>
+	where
+		S: AsyncRead + AsyncWrite + Unpin,
+	{
+		// Offer username/password authentication only if we have credentials.
+		let method = match &self.credentials {
+			Some(_) => SOCKS5_AUTH_PASSWORD,
+			None => SOCKS5_AUTH_NONE,
+		};
+		write_all(stream, &[SOCKS5_VERSION, 1, method]).await?;
+		let mut reply = [0u8; 2];
+		read_exact(stream, &mut reply).await?;
+		if reply[0] != SOCKS5_VERSION {
+			return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid response from SOCKS5 proxy"));
+		}
+		if reply[1] != method {
+			return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "SOCKS5 proxy does not accept the authentication method"));
+		}
+
+		if let Some((username, password)) = &self.credentials {
+			let (Ok(username_len), Ok(password_len)) = (u8::try_from(username.len()), u8::try_from(password.len())) else {
+				return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "SOCKS5 username and password can be at most 255 bytes"));
+			};
+			let mut request = vec![1, username_len];
+			request.extend_from_slice(username.as_bytes());
+			request.push(password_len);
+			request.extend_from_slice(password.as_bytes());
+			write_all(stream, &request).await?;
+			read_exact(stream, &mut reply).await?;
+			if reply[1] != 0 {
+				return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "SOCKS5 proxy rejected the username and password"));
+			}
+		}
+
+		let mut request = vec![SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0];
+		match target {
+			SocketAddr::V4(address) => {
+				request.push(SOCKS5_ADDR_IPV4);
+				request.extend_from_slice(&address.ip().octets());
+			},
+			SocketAddr::V6(address) => {
+				request.push(SOCKS5_ADDR_IPV6);
+				request.extend_from_slice(&address.ip().octets());
+			},
+		}
+		request.extend_from_slice(&target.port().to_be_bytes());
+		write_all(stream, &request).await?;
+
+		// The reply contains the address bound by the proxy, which we do not need.
+		let mut reply = [0u8; 4];
+		read_exact(stream, &mut reply).await?;
+		if reply[0] != SOCKS5_VERSION {
+			return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid response from SOCKS5 proxy"));
+		}
+		if reply[1] != 0 {
+			return Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, format!("SOCKS5 proxy refused to connect: {}", socks5_reply_message(reply[1]))));
+		}
+		let address_len = match reply[3] {
+			SOCKS5_ADDR_IPV4 => 4,
+			SOCKS5_ADDR_IPV6 => 16,
+			SOCKS5_ADDR_DOMAIN => {
+				let mut len = [0u8];
+				read_exact(stream, &mut len).await?;
+				usize::from(len[0])
+			},
+			_ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid address type in response from SOCKS5 proxy")),
+		};
+		let mut bound_address = vec![0u8; address_len + 2];
+		read_exact(stream, &mut bound_address).await
+	}
+}
+
+impl std::fmt::Debug for Proxy {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		// Do not leak the password in debug output.
+		f.debug_struct("Proxy")
+			.field("protocol", &self.protocol)
+			.field("address", &self.address)
+			.field("username", &self.credentials.as_ref().map(|(username, _)| username))
+			.finish()
+	}
+}
+
+const SOCKS5_VERSION: u8 = 5;
+const SOCKS5_AUTH_NONE: u8 = 0;
+const SOCKS5_AUTH_PASSWORD: u8 = 2;
+const SOCKS5_CMD_CONNECT: u8 = 1;
+const SOCKS5_ADDR_IPV4: u8 = 1;
+const SOCKS5_ADDR_DOMAIN: u8 = 3;
+const SOCKS5_ADDR_IPV6: u8 = 4;
+
+/// Get a description of an error reply from a SOCKS5 proxy.
+fn socks5_reply_message(reply: u8) -> &'static str {
+	match reply {
+		1 => "general failure",
+		2 => "connection not allowed by ruleset",
+		3 => "network unreachable",
+		4 => "host unreachable",
+		5 => "connection refused",
+		6 => "TTL expired",
+		7 => "command not supported",
+		8 => "address type not supported",
+		_ => "unknown error",
+	}
+}
+
+/// Write all data to a stream and flush it.
+async fn write_all<S: AsyncWrite + Unpin>(stream: &mut S, data: &[u8]) -> std::io::Result<()> {
+	let mut written = 0;
+	std::future::poll_fn(|context| poll_write_all_vectored(Pin::new(&mut *stream), context, &[data], &mut written)).await?;
+	std::future::poll_fn(|context| Pin::new(&mut *stream).poll_flush(context)).await
+}
+
+/// Fill a buffer completely with data from a stream.
+async fn read_exact<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut [u8]) -> std::io::Result<()> {
+	let mut filled = 0;
+	std::future::poll_fn(|context| poll_read_exact(Pin::new(&mut *stream), context, buf, &mut filled)).await
+}
+
+/// Encode data with the standard base64 alphabet, with padding.
+fn base64_encode(data: &[u8]) -> String {
+	const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+	let mut output = String::with_capacity((data.len() + 2) / 3 * 4);
+	for chunk in data.chunks(3) {
+		let bytes = [chunk[0], chunk.get(1).copied().unwrap_or(0), chunk.get(2).copied().unwrap_or(0)];
+		let value = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+		for i in 0..4 {
+			if i <= chunk.len() {
+				output.push(ALPHABET[(value >> (18 - 6 * i) & 0x3F) as usize] as char);
+			} else {
+				output.push('=');
+			}
+		}
+	}
+	output
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::{assert, let_assert};
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+	use tokio::net::TcpListener;
+
+	use crate::{ReceivedMessage, StreamConfig, TcpPeer};
+
+	#[test]
+	fn base64() {
+		assert!(base64_encode(b"") == "");
+		assert!(base64_encode(b"f") == "Zg==");
+		assert!(base64_encode(b"fo") == "Zm8=");
+		assert!(base64_encode(b"foo") == "Zm9v");
+		assert!(base64_encode(b"user:secret") == "dXNlcjpzZWNyZXQ=");
+	}
+
+	#[test]
+	fn debug_hides_password() {
+		let proxy = Proxy::socks5("proxy:1080").with_credentials("user", "secret");
+		let debug = format!("{proxy:?}");
+		assert!(debug.contains("user"));
+		assert!(!debug.contains("secret"));
+	}
+
+	/// Accept a single connection on a fake proxy server, and forward it to the target after the handshake.
+	async fn run_proxy(listener: TcpListener, protocol: ProxyProtocol) -> Vec<u8> {
+		let_assert!(Ok((mut client, _address)) = listener.accept().await);
+		let mut handshake = Vec::new();
+		let target = match protocol {
+			ProxyProtocol::HttpConnect => {
+				while !handshake.ends_with(b"\r\n\r\n") {
+					let_assert!(Ok(byte) = client.read_u8().await);
+					handshake.push(byte);
+				}
+				let request = String::from_utf8_lossy(&handshake);
+				let_assert!(Some(target) = request.split(' ').nth(1));
+				let_assert!(Ok(target) = target.parse::<SocketAddr>());
+				let_assert!(Ok(()) = client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await);
+				target
+			},
+			ProxyProtocol::Socks5 => {
+				let mut greeting = [0u8; 3];
+				let_assert!(Ok(_) = client.read_exact(&mut greeting).await);
+				handshake.extend_from_slice(&greeting);
+				let_assert!(Ok(()) = client.write_all(&[5, greeting[2]]).await);
+				if greeting[2] == SOCKS5_AUTH_PASSWORD {
+					let mut auth = vec![0u8; 2];
+					let_assert!(Ok(_) = client.read_exact(&mut auth).await);
+					let mut username = vec![0u8; auth[1] as usize + 1];
+					let_assert!(Ok(_) = client.read_exact(&mut username).await);
+					let mut password = vec![0u8; username.pop().unwrap() as usize];
+					let_assert!(Ok(_) = client.read_exact(&mut password).await);
+					handshake.extend_from_slice(&username);
+					handshake.push(b':');
+					handshake.extend_from_slice(&password);
+					let_assert!(Ok(()) = client.write_all(&[1, 0]).await);
+				}
+				let mut request = [0u8; 10];
+				let_assert!(Ok(_) = client.read_exact(&mut request).await);
+				assert!(request[..4] == [5, 1, 0, SOCKS5_ADDR_IPV4]);
+				let ip = std::net::Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+				let port = u16::from_be_bytes([request[8], request[9]]);
+				let_assert!(Ok(()) = client.write_all(&[5, 0, 0, SOCKS5_ADDR_IPV4, 127, 0, 0, 1, 0, 0]).await);
+				SocketAddr::from((ip, port))
+			},
+		};
+
+		let_assert!(Ok(mut server) = tokio::net::TcpStream::connect(target).await);
+		tokio::spawn(async move {
+			let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+		});
+		handshake
+	}
+
+	async fn connect_through_proxy(protocol: ProxyProtocol, credentials: Option<(&str, &str)>) -> Vec<u8> {
+		let_assert!(Ok(server) = TcpListener::bind("127.0.0.1:0").await);
+		let_assert!(Ok(server_address) = server.local_addr());
+		let_assert!(Ok(proxy) = TcpListener::bind("127.0.0.1:0").await);
+		let_assert!(Ok(proxy_address) = proxy.local_addr());
+		let proxy_task = tokio::spawn(run_proxy(proxy, protocol));
+
+		let mut proxy = match protocol {
+			ProxyProtocol::HttpConnect => Proxy::http_connect(proxy_address.to_string()),
+			ProxyProtocol::Socks5 => Proxy::socks5(proxy_address.to_string()),
+		};
+		if let Some((username, password)) = credentials {
+			proxy = proxy.with_credentials(username, password);
+		}
+		let config = StreamConfig {
+			proxy: Some(proxy),
+			..Default::default()
+		};
+		let_assert!(Ok((client, _info)) = TcpPeer::connect(server_address, config).await);
+		let_assert!(Ok((server, _address)) = server.accept().await);
+		let mut server = TcpPeer::spawn(crate::TcpTransport::new(server, Default::default()));
+
+		let_assert!(Ok(()) = client.send_stream(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = server.recv_message().await);
+		assert!(message.body.as_ref() == b"hello");
+
+		let_assert!(Ok(handshake) = proxy_task.await);
+		handshake
+	}
+
+	#[tokio::test]
+	async fn http_connect_proxy() {
+		let handshake = connect_through_proxy(ProxyProtocol::HttpConnect, None).await;
+		assert!(handshake.starts_with(b"CONNECT 127.0.0.1:"));
+		let handshake = connect_through_proxy(ProxyProtocol::HttpConnect, Some(("user", "secret"))).await;
+		let handshake = String::from_utf8_lossy(&handshake);
+		assert!(handshake.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+	}
+
+	#[tokio::test]
+	async fn socks5_proxy() {
+		let handshake = connect_through_proxy(ProxyProtocol::Socks5, None).await;
+		assert!(handshake == [5, 1, SOCKS5_AUTH_NONE]);
+		let handshake = connect_through_proxy(ProxyProtocol::Socks5, Some(("user", "secret"))).await;
+		assert!(handshake == b"\x05\x01\x02user:secret");
+	}
+
+	#[tokio::test]
+	async fn http_proxy_refuses() {
+		let_assert!(Ok(proxy) = TcpListener::bind("127.0.0.1:0").await);
+		let_assert!(Ok(proxy_address) = proxy.local_addr());
+		tokio::spawn(async move {
+			let_assert!(Ok((mut client, _address)) = proxy.accept().await);
+			let mut buffer = [0u8; 1024];
+			let_assert!(Ok(_) = client.read(&mut buffer).await);
+			let_assert!(Ok(()) = client.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n").await);
+		});
+
+		let config = StreamConfig {
+			proxy: Some(Proxy::http_connect(proxy_address.to_string())),
+			..Default::default()
+		};
+		let_assert!(Err(e) = TcpPeer::connect("127.0.0.1:1", config).await);
+		assert!(e.kind() == std::io::ErrorKind::ConnectionRefused);
+		assert!(e.to_string() == "HTTP proxy refused to connect: HTTP/1.1 403 Forbidden");
+	}
+}
@@ -0,0 +1,392 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::compression::CompressionState;
+use super::pool::BufferPool;
+use super::{StreamBody, StreamConfig, StreamReadHalf, StreamTransport, StreamWriteHalf};
+use crate::transport::EndianState;
+
+/// Preamble sent by the connecting side when it opens the QUIC stream.
+///
+/// A QUIC stream only becomes visible to the remote peer once data is sent on it.
+/// The preamble makes sure the accepting side sees the stream even if the connecting side does not send a message right away.
+const PREAMBLE: &[u8; 4] = b"FRPC";
+
+/// A bidirectional QUIC stream used to transfer RPC messages.
+///
+/// All messages of a peer are sent over a single bidirectional stream of a QUIC connection,
+/// with the same framing as the other stream transports.
+#[derive(Debug)]
+pub struct QuicStream {
+	/// The connection the stream belongs to.
+	connection: quinn::Connection,
+
+	/// The send half of the stream.
+	send: quinn::SendStream,
+
+	/// The receive half of the stream.
+	recv: quinn::RecvStream,
+}
+
+impl QuicStream {
+	/// Connect to a remote endpoint and open a new stream on the connection.
+	///
+	/// The `server_name` is used to verify the certificate of the remote endpoint.
+	pub async fn connect(endpoint: &quinn::Endpoint, address: SocketAddr, server_name: &str) -> std::io::Result<Self> {
+		let connection = endpoint.connect(address, server_name)
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+			.await
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::ConnectionRefused, e))?;
+		Self::open(connection).await
+	}
+
+	/// Open a new stream on an established connection.
+	///
+	/// The remote peer must accept the stream with [`Self::accept()`].
+	pub async fn open(connection: quinn::Connection) -> std::io::Result<Self> {
+		let (mut send, recv) = connection.open_bi()
+			.await
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::ConnectionAborted, e))?;
+		send.write_all(PREAMBLE).await?;
+		Ok(Self { connection, send, recv })
+	}
+
+	/// Accept a stream opened by the remote peer with [`Self::open()`].
+	pub async fn accept(connection: quinn::Connection) -> std::io::Result<Self> {
+		let (send, mut recv) = connection.accept_bi()
+			.await
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::ConnectionAborted, e))?;
+		let mut preamble = [0; 4];
+		recv.read_exact(&mut preamble)
+			.await
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+		if &preamble != PREAMBLE {
+			return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid preamble on QUIC stream"));
+		}
+		Ok(Self { connection, send, recv })
+	}
+
+	/// Get the connection the stream belongs to.
+	pub fn connection(&self) -> &quinn::Connection {
+		&self.connection
+	}
+}
+
+/// Information about the remote peer of a QUIC stream.
+#[derive(Debug, Clone)]
+pub struct QuicStreamInfo {
+	/// The connection of the stream.
+	connection: quinn::Connection,
+}
+
+impl QuicStreamInfo {
+	/// Get the remote address of the QUIC connection.
+	///
+	/// The address may change during the lifetime of the connection if the remote peer migrates to a different network.
+	pub fn remote_address(&self) -> SocketAddr {
+		self.connection.remote_address()
+	}
+
+	/// Get the QUIC connection.
+	///
+	/// The connection can be used to inspect statistics like the round-trip time,
+	/// or to open additional streams next to the one used by the peer.
+	pub fn connection(&self) -> &quinn::Connection {
+		&self.connection
+	}
+}
+
+impl crate::transport::Transport for StreamTransport<QuicStream> {
+	type Body = StreamBody;
+	type Info = QuicStreamInfo;
+	type Config = StreamConfig;
+	type ReadHalf<'a> = StreamReadHalf<&'a mut quinn::RecvStream>;
+	type WriteHalf<'a> = StreamWriteHalf<&'a mut quinn::SendStream>;
+
+	fn split(&mut self) -> (StreamReadHalf<&mut quinn::RecvStream>, StreamWriteHalf<&mut quinn::SendStream>) {
+		let compression = CompressionState::new(self.config.compression.clone(), self.config.compression_threshold);
+		let endian = EndianState::new(self.config.endian, self.config.detect_endian);
+		let read_half = StreamReadHalf::new(&mut self.stream.recv, self.config.max_body_len_read, endian.clone(), self.config.trace.clone(), self.config.error_policy.clone(), compression.clone(), BufferPool::new(self.config.buffer_pool_size))
+			.with_body_sink(self.config.body_sink.clone());
+		let write_half = StreamWriteHalf::new(&mut self.stream.send, self.config.max_body_len_write, endian, self.config.trace.clone(), compression);
+		(read_half, write_half)
+	}
+
+	fn info(&self) -> std::io::Result<Self::Info> {
+		Ok(Self::Info {
+			connection: self.stream.connection.clone(),
+		})
+	}
+
+	fn describe_remote(&self) -> Option<String> {
+		Some(format!("quic {}", self.stream.connection.remote_address()))
+	}
+}
+
+impl crate::util::IntoTransport for QuicStream {
+	type Body = StreamBody;
+	type Config = StreamConfig;
+	type Transport = StreamTransport<QuicStream>;
+
+	fn into_transport(self, config: Self::Config) -> Self::Transport {
+		StreamTransport::new(self, config)
+	}
+}
+
+/// Connect to the given address with a client endpoint.
+///
+/// The address is a tuple with the local endpoint, the remote address and the server name used to verify the certificate of the remote endpoint.
+impl<'a> crate::util::Connect<'a, (&'a quinn::Endpoint, SocketAddr, &'a str)> for StreamTransport<QuicStream> {
+	type Future = Pin<Box<dyn Future<Output = std::io::Result<Self>> + 'a>>;
+
+	fn connect((endpoint, address, server_name): (&'a quinn::Endpoint, SocketAddr, &'a str), config: Self::Config) -> Self::Future {
+		Box::pin(async move {
+			let stream = QuicStream::connect(endpoint, address, server_name).await?;
+			Ok(Self::new(stream, config))
+		})
+	}
+}
+
+/// The future type used by [`QuicEndpoint`] to wait for a new incoming connection.
+type IncomingFuture = Pin<Box<dyn Future<Output = Option<quinn::Incoming>> + Send>>;
+
+/// The default time allowed for the handshake of an incoming connection, see [`QuicEndpoint::set_handshake_timeout()`].
+const DEFAULT_HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// A QUIC endpoint that accepts incoming connections.
+///
+/// For each accepted connection, the stream opened by the remote peer is accepted and used as transport.
+/// The handshake and the opening of the stream run in a separate task for each incoming connection,
+/// so a slow or malicious client does not hold up other clients.
+/// Connections that do not complete the handshake and open the stream within the handshake timeout are rejected.
+pub struct QuicEndpoint {
+	/// The endpoint to accept connections on.
+	endpoint: quinn::Endpoint,
+
+	/// The future waiting for the next incoming connection, or `None` if the endpoint is closed.
+	incoming: Option<IncomingFuture>,
+
+	/// The tasks performing the handshake of incoming connections.
+	handshakes: tokio::task::JoinSet<std::io::Result<(QuicStream, SocketAddr)>>,
+
+	/// The time allowed for the handshake of an incoming connection.
+	handshake_timeout: std::time::Duration,
+}
+
+impl QuicEndpoint {
+	/// Create a new listener for an endpoint.
+	///
+	/// The endpoint must have a server configuration to accept connections.
+	pub fn new(endpoint: quinn::Endpoint) -> Self {
+		Self {
+			incoming: Some(next_incoming(endpoint.clone())),
+			endpoint,
+			handshakes: tokio::task::JoinSet::new(),
+			handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+		}
+	}
+
+	/// Get the underlying endpoint.
+	pub fn endpoint(&self) -> &quinn::Endpoint {
+		&self.endpoint
+	}
+
+	/// Set the time allowed for the handshake of an incoming connection.
+	///
+	/// The handshake includes the QUIC handshake and the opening of the stream by the remote peer.
+	/// Connections that do not complete it in time are closed, and the error is returned from the accept function.
+	///
+	/// The default is 10 seconds.
+	pub fn set_handshake_timeout(&mut self, timeout: std::time::Duration) {
+		self.handshake_timeout = timeout;
+	}
+
+	/// Get the time allowed for the handshake of an incoming connection.
+	pub fn handshake_timeout(&self) -> std::time::Duration {
+		self.handshake_timeout
+	}
+}
+
+impl From<quinn::Endpoint> for QuicEndpoint {
+	fn from(endpoint: quinn::Endpoint) -> Self {
+		Self::new(endpoint)
+	}
+}
+
+impl std::fmt::Debug for QuicEndpoint {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("QuicEndpoint")
+			.field("endpoint", &self.endpoint)
+			.field("pending_handshakes", &self.handshakes.len())
+			.field("handshake_timeout", &self.handshake_timeout)
+			.finish_non_exhaustive()
+	}
+}
+
+/// Wait for the next incoming connection on an endpoint.
+///
+/// Resolves to `None` if the endpoint was closed.
+fn next_incoming(endpoint: quinn::Endpoint) -> IncomingFuture {
+	Box::pin(async move { endpoint.accept().await })
+}
+
+/// Perform the handshake of an incoming connection and accept the stream opened on it.
+async fn accept_stream(incoming: quinn::Incoming, timeout: std::time::Duration) -> std::io::Result<(QuicStream, SocketAddr)> {
+	let handshake = async {
+		let connection = incoming.await
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::ConnectionAborted, e))?;
+		let address = connection.remote_address();
+		let stream = QuicStream::accept(connection).await?;
+		Ok((stream, address))
+	};
+	match tokio::time::timeout(timeout, handshake).await {
+		Ok(result) => result,
+		Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "QUIC handshake timed out")),
+	}
+}
+
+impl crate::util::Listener for QuicEndpoint {
+	type Address = SocketAddr;
+	type Connection = QuicStream;
+
+	fn poll_accept(self: Pin<&mut Self>, context: &mut Context) -> Poll<std::io::Result<(Self::Connection, Self::Address)>> {
+		let this = self.get_mut();
+
+		// Start a handshake task for all new incoming connections.
+		while let Some(incoming) = &mut this.incoming {
+			match incoming.as_mut().poll(context) {
+				Poll::Pending => break,
+				Poll::Ready(None) => this.incoming = None,
+				Poll::Ready(Some(incoming)) => {
+					this.handshakes.spawn(accept_stream(incoming, this.handshake_timeout));
+					this.incoming = Some(next_incoming(this.endpoint.clone()));
+				},
+			}
+		}
+
+		// Return the first handshake that finished.
+		match this.handshakes.poll_join_next(context) {
+			Poll::Ready(Some(Ok(result))) => Poll::Ready(result),
+			Poll::Ready(Some(Err(e))) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+			Poll::Ready(Some(Err(e))) => Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::Other, e))),
+			Poll::Ready(None) if this.incoming.is_none() => {
+				Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::NotConnected, "QUIC endpoint is closed")))
+			},
+			Poll::Ready(None) | Poll::Pending => Poll::Pending,
+		}
+	}
+}
+
+/// Create a server endpoint bound to the given address.
+///
+/// The address is a tuple with the local socket address and the server configuration of the endpoint.
+impl<'a> crate::util::Bind<'a, (SocketAddr, quinn::ServerConfig)> for QuicEndpoint {
+	type Future = Pin<Box<dyn Future<Output = std::io::Result<Self>> + 'a>>;
+
+	fn bind((address, server_config): (SocketAddr, quinn::ServerConfig)) -> Self::Future {
+		Box::pin(async move {
+			let endpoint = quinn::Endpoint::server(server_config, address)?;
+			Ok(Self::new(endpoint))
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+	use std::sync::Arc;
+
+	use crate::util::Bind;
+	use crate::{Listener, Peer, ReceivedMessage};
+
+	/// Create a server and client configuration with a self-signed certificate for `localhost`.
+	fn configs() -> (quinn::ServerConfig, quinn::ClientConfig) {
+		let_assert!(Ok(certified) = rcgen::generate_simple_self_signed(vec!["localhost".into()]));
+		let certificate = certified.cert.der().clone();
+		let key = quinn::rustls::pki_types::PrivateKeyDer::Pkcs8(certified.key_pair.serialize_der().into());
+
+		let mut roots = quinn::rustls::RootCertStore::empty();
+		assert!(let Ok(()) = roots.add(certificate.clone()));
+		let_assert!(Ok(client_config) = quinn::ClientConfig::with_root_certificates(Arc::new(roots)));
+		let_assert!(Ok(server_config) = quinn::ServerConfig::with_single_cert(vec![certificate], key));
+		(server_config, client_config)
+	}
+
+	#[tokio::test]
+	async fn quic_peer() {
+		let (server_config, client_config) = configs();
+		let_assert!(Ok(endpoint) = QuicEndpoint::bind(("127.0.0.1:0".parse().unwrap(), server_config)).await);
+		let_assert!(Ok(server_address) = endpoint.endpoint().local_addr());
+		let mut listener = Listener::new(endpoint, StreamConfig::default());
+
+		let_assert!(Ok(mut client_endpoint) = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()));
+		client_endpoint.set_default_client_config(client_config);
+
+		let server = tokio::spawn(async move {
+			let_assert!(Ok((mut peer, info)) = listener.accept().await);
+			assert!(info.remote_address().ip() == std::net::Ipv4Addr::LOCALHOST);
+			let_assert!(Ok(ReceivedMessage::Request(request, body)) = peer.recv_message().await);
+			assert!(request.service_id() == 1);
+			assert!(body.as_ref() == b"Hello server!");
+			assert!(let Ok(()) = request.send_update(2, &b"Hello update!"[..]).await);
+			assert!(let Ok(()) = request.send_response(3, &b"Hello client!"[..]).await);
+			let_assert!(Err(e) = peer.recv_message().await);
+			assert!(e.is_connection_aborted());
+		});
+
+		let_assert!(Ok((peer, info)) = Peer::<StreamTransport<QuicStream>>::connect((&client_endpoint, server_address, "localhost"), StreamConfig::default()).await);
+		assert!(info.remote_address() == server_address);
+		let_assert!(Ok(mut request) = peer.send_request(1, &b"Hello server!"[..]).await);
+		let_assert!(Some(update) = request.recv_update().await);
+		assert!(update.header.service_id == 2);
+		assert!(update.body.as_ref() == b"Hello update!");
+		let_assert!(Ok(response) = request.recv_response().await);
+		assert!(response.header.service_id == 3);
+		assert!(response.body.as_ref() == b"Hello client!");
+
+		drop(request);
+		drop(peer);
+		assert!(let Ok(()) = server.await);
+	}
+
+	#[tokio::test]
+	async fn stalled_handshake_does_not_block_accept() {
+		let (server_config, client_config) = configs();
+		let_assert!(Ok(mut endpoint) = QuicEndpoint::bind(("127.0.0.1:0".parse().unwrap(), server_config)).await);
+		endpoint.set_handshake_timeout(std::time::Duration::from_millis(200));
+		let_assert!(Ok(server_address) = endpoint.endpoint().local_addr());
+		let mut listener = Listener::new(endpoint, StreamConfig::default());
+
+		let_assert!(Ok(mut client_endpoint) = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()));
+		client_endpoint.set_default_client_config(client_config);
+
+		// The first client completes the QUIC handshake, but never opens the stream.
+		// The listener must be polled to complete the handshake.
+		let mut accept = Box::pin(listener.accept());
+		let_assert!(Ok(connecting) = client_endpoint.connect(server_address, "localhost"));
+		let stalled = tokio::select! {
+			_ = &mut accept => panic!("stalled connection was accepted"),
+			stalled = connecting => stalled,
+		};
+		let_assert!(Ok(_stalled) = stalled);
+
+		// The second client is accepted anyway.
+		let (client, server) = tokio::join!(
+			Peer::<StreamTransport<QuicStream>>::connect((&client_endpoint, server_address, "localhost"), StreamConfig::default()),
+			accept,
+		);
+		let_assert!(Ok((client, _info)) = client);
+		let_assert!(Ok((mut server, _info)) = server);
+		assert!(let Ok(()) = client.send_stream(1, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = server.recv_message().await);
+		assert!(message.body.as_ref() == b"hello");
+
+		// The stalled handshake times out.
+		let_assert!(Err(e) = listener.accept().await);
+		assert!(e.kind() == std::io::ErrorKind::TimedOut);
+	}
+}
@@ -0,0 +1,211 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::MessageHeader;
+
+/// Size of the buffer used to move body data from the stream to a sink.
+const SINK_BUFFER_LEN: usize = 16 * 1024;
+
+/// Hook to receive the body of incoming messages directly into an [`AsyncWrite`].
+///
+/// Normally, a stream transport reads the whole body of a message into memory before it is passed to the application.
+/// With a body sink, the body of selected messages is written to a user supplied writer (for example a file) while it is being read,
+/// so the body never has to fit in memory at once.
+///
+/// The sink is consulted for every incoming message with a non-negative service ID.
+/// Messages with a negative service ID are used by the protocol itself and always received in memory,
+/// and so are messages with a compressed body.
+///
+/// If the sink returns a writer, the body is written to it in chunks and the writer is shut down after the last chunk.
+/// The message is passed to the application with an empty body once the writer has been shut down successfully.
+/// The body of such a message is not limited by [`StreamConfig::max_body_len_read`][super::StreamConfig::max_body_len_read],
+/// so the sink should check the `body_len` parameter itself.
+///
+/// If writing to the sink fails, the rest of the body is discarded and a non-fatal error is returned instead of the message.
+///
+/// The hook is implemented for all functions with the signature of [`BodySink::open()`].
+pub trait BodySink: Send + Sync + 'static {
+	/// Get the writer to stream the body of an incoming message into.
+	///
+	/// The `body_len` parameter is the length of the message body in bytes.
+	/// Return [`None`] to receive the message body in memory as usual.
+	fn open(&self, header: &MessageHeader, body_len: usize) -> Option<Pin<Box<dyn AsyncWrite + Send>>>;
+}
+
+impl<F> BodySink for F
+where
+	F: Fn(&MessageHeader, usize) -> Option<Pin<Box<dyn AsyncWrite + Send>>> + Send + Sync + 'static,
+{
+	fn open(&self, header: &MessageHeader, body_len: usize) -> Option<Pin<Box<dyn AsyncWrite + Send>>> {
+		(self)(header, body_len)
+	}
+}
+
+impl std::fmt::Debug for dyn BodySink {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("BodySink").finish_non_exhaustive()
+	}
+}
+
+/// The state of a message body that is being streamed into a sink.
+#[allow(dead_code)] // Not used when transports are disabled.
+pub(super) struct SinkState {
+	/// The writer to stream the body into.
+	sink: Pin<Box<dyn AsyncWrite + Send>>,
+
+	/// The number of body bytes that still have to be read from the stream.
+	remaining: usize,
+
+	/// Buffer for data read from the stream that has not been written to the sink yet.
+	buffer: Box<[u8]>,
+
+	/// The start of the data in the buffer that still has to be written.
+	start: usize,
+
+	/// The end of the data in the buffer.
+	end: usize,
+
+	/// The error that occurred while writing to the sink, if any.
+	///
+	/// After an error, the rest of the body is read from the stream and discarded.
+	error: Option<std::io::Error>,
+}
+
+#[allow(dead_code)] // Not used when transports are disabled.
+impl SinkState {
+	/// Create a new state to stream a body of `body_len` bytes into a sink.
+	pub(super) fn new(sink: Pin<Box<dyn AsyncWrite + Send>>, body_len: usize) -> Self {
+		Self {
+			sink,
+			remaining: body_len,
+			buffer: vec![0; SINK_BUFFER_LEN.min(body_len.max(1))].into_boxed_slice(),
+			start: 0,
+			end: 0,
+			error: None,
+		}
+	}
+
+	/// Try to stream the rest of the body from `stream` into the sink without blocking.
+	///
+	/// The outer result holds errors from reading the stream, which are fatal for the transport.
+	/// The inner result holds errors from writing to the sink.
+	pub(super) fn poll_transfer<R>(&mut self, mut stream: Pin<&mut R>, context: &mut Context) -> Poll<std::io::Result<std::io::Result<()>>>
+	where
+		R: AsyncRead + ?Sized,
+	{
+		loop {
+			if self.start < self.end {
+				match ready!(self.sink.as_mut().poll_write(context, &self.buffer[self.start..self.end])) {
+					Ok(0) => self.fail(std::io::ErrorKind::WriteZero.into()),
+					Ok(written) => self.start += written,
+					Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+					Err(e) => self.fail(e),
+				}
+				continue;
+			}
+
+			if self.remaining == 0 {
+				if self.error.is_none() {
+					if let Err(e) = ready!(self.sink.as_mut().poll_shutdown(context)) {
+						self.fail(e);
+					}
+				}
+				return Poll::Ready(Ok(self.error.take().map_or(Ok(()), Err)));
+			}
+
+			let len = self.remaining.min(self.buffer.len());
+			let mut read_buf = ReadBuf::new(&mut self.buffer[..len]);
+			match ready!(stream.as_mut().poll_read(context, &mut read_buf)) {
+				Ok(()) if read_buf.filled().is_empty() => {
+					return Poll::Ready(Err(std::io::ErrorKind::ConnectionAborted.into()));
+				},
+				Ok(()) => {
+					let read = read_buf.filled().len();
+					self.remaining -= read;
+					self.start = 0;
+					self.end = if self.error.is_none() { read } else { 0 };
+				},
+				Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+				Err(e) => return Poll::Ready(Err(e)),
+			}
+		}
+	}
+
+	/// Record an error from the sink and discard the buffered data.
+	fn fail(&mut self, error: std::io::Error) {
+		self.error = Some(error);
+		self.start = 0;
+		self.end = 0;
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+	use std::sync::{Arc, Mutex};
+
+	use crate::{ReceivedMessage, StreamConfig, UnixStreamPeer, UnixStreamTransport};
+
+	/// Sink that collects the written data in a shared buffer.
+	#[derive(Clone, Default)]
+	struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+	impl AsyncWrite for SharedBuffer {
+		fn poll_write(self: Pin<&mut Self>, _context: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+			// Accept at most 1000 bytes at a time to exercise partial writes.
+			let len = buf.len().min(1000);
+			self.0.lock().unwrap().extend_from_slice(&buf[..len]);
+			Poll::Ready(Ok(len))
+		}
+
+		fn poll_flush(self: Pin<&mut Self>, _context: &mut Context) -> Poll<std::io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+
+		fn poll_shutdown(self: Pin<&mut Self>, _context: &mut Context) -> Poll<std::io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+	}
+
+	#[tokio::test]
+	async fn stream_body_into_sink() {
+		let output = SharedBuffer::default();
+		let sink = output.clone();
+		let body_sink = move |header: &MessageHeader, _body_len: usize| -> Option<Pin<Box<dyn AsyncWrite + Send>>> {
+			if header.service_id == 7 {
+				Some(Box::pin(sink.clone()))
+			} else {
+				None
+			}
+		};
+		let config = StreamConfig {
+			max_body_len_read: 16,
+			body_sink: Some(Arc::new(body_sink)),
+			..Default::default()
+		};
+		let upload = vec![0xAB; 100_000];
+		let config_a = StreamConfig {
+			max_body_len_write: upload.len() as u32,
+			..Default::default()
+		};
+
+		let_assert!(Ok((peer_a, peer_b)) = tokio::net::UnixStream::pair());
+		let peer_a = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, config_a));
+		let mut peer_b = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, config));
+
+		// The body of service 7 is streamed into the sink, even though it exceeds the read limit.
+		let_assert!(Ok(_request) = peer_a.send_request(7, &upload[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(request, body)) = peer_b.recv_message().await);
+		assert!(request.service_id() == 7);
+		assert!(body.is_empty());
+		assert!(*output.0.lock().unwrap() == upload);
+
+		// Other messages are received in memory as usual.
+		let_assert!(Ok(()) = peer_a.send_stream(8, &b"hello"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Stream(message)) = peer_b.recv_message().await);
+		assert!(message.body.as_ref() == b"hello");
+	}
+}
@@ -1,15 +1,21 @@
-use std::io::IoSlice;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite};
 
+use super::compression::CompressionState;
+use super::frame::{self, encode_framed_header, FRAMED_HEADER_LEN};
+use super::pool::BufferPool;
+use super::sink::{BodySink, SinkState};
 use super::{StreamBody, StreamConfig};
 use crate::error::private::check_payload_too_large;
-use crate::transport::{TransportError, Endian};
+use crate::transport::trace::{TraceDirection, WireTrace};
+use crate::transport::util::{poll_read_exact, poll_write_all_vectored};
+use crate::transport::{EndianState, RemoteErrorPolicy, TransportError};
 use crate::{Message, MessageHeader};
 
-/// Length of a message frame and header.
-const FRAMED_HEADER_LEN: usize = 4 + crate::HEADER_LEN as usize;
+/// Size in bytes after which no more messages are added to a coalesced batch.
+const MAX_BATCH_LEN: usize = 64 * 1024;
 
 /// Transport layer for byte-stream sockets.
 #[allow(dead_code)] // Fields are not used when transports are disabled.
@@ -31,7 +37,7 @@ pub struct StreamReadHalf<ReadStream> {
 	pub(super) max_body_len: u32,
 
 	/// The endianness to use for decoding header fields.
-	pub(super) endian: Endian,
+	pub(super) endian: EndianState,
 
 	/// The number of bytes read for the current message.
 	pub(super) bytes_read: usize,
@@ -44,6 +50,24 @@ pub struct StreamReadHalf<ReadStream> {
 
 	/// The buffer for reading the message body.
 	pub(super) body_buffer: Vec<u8>,
+
+	/// The pool to take body buffers from.
+	pub(super) pool: BufferPool,
+
+	/// The wire trace to record received frames in.
+	pub(super) trace: Option<WireTrace>,
+
+	/// The validation to apply to received error messages.
+	pub(super) error_policy: RemoteErrorPolicy,
+
+	/// The compression state shared with the write half.
+	pub(super) compression: CompressionState,
+
+	/// The hook to stream message bodies into a writer instead of reading them into memory.
+	pub(super) body_sink: Option<Arc<dyn BodySink>>,
+
+	/// The state of the message body that is currently being streamed into a sink.
+	pub(super) sink_state: Option<SinkState>,
 }
 
 /// The write half of a [`StreamTransport`].
@@ -56,13 +80,44 @@ pub struct StreamWriteHalf<WriteStream> {
 	pub(super) max_body_len: u32,
 
 	/// The endianness to use for encoding header fields.
-	pub(super) endian: Endian,
+	pub(super) endian: EndianState,
 
 	/// The number of bytes written for the current message.
 	pub(super) bytes_written: usize,
 
 	/// The buffer for the encoded message size and header.
 	pub(super) header_buffer: Option<[u8; FRAMED_HEADER_LEN]>,
+
+	/// The wire trace to record sent frames in.
+	pub(super) trace: Option<WireTrace>,
+
+	/// The compression state shared with the read half.
+	pub(super) compression: CompressionState,
+
+	/// The compressed body of the current message, if it is compressed.
+	pub(super) compressed_body: Option<Vec<u8>>,
+
+	/// If true, the compression announcement must still be sent.
+	pub(super) announcement_pending: bool,
+
+	/// The encoded compression announcement, while it is being sent.
+	pub(super) announcement: Option<Vec<u8>>,
+
+	/// The coalesced frames of a batch of messages, while they are being sent.
+	pub(super) batch: Option<EncodedBatch>,
+}
+
+/// A batch of messages encoded into a single buffer.
+#[derive(Default)]
+pub(super) struct EncodedBatch {
+	/// The encoded frames.
+	data: Vec<u8>,
+
+	/// The end offset of each frame in the buffer.
+	frame_ends: Vec<usize>,
+
+	/// The number of messages in the batch, not counting the compression announcement.
+	messages: usize,
 }
 
 impl<Stream> StreamTransport<Stream>
@@ -97,7 +152,7 @@ where
 
 impl<ReadStream> StreamReadHalf<ReadStream> {
 	#[allow(dead_code)] // Not used when transports are disabled.
-	pub(super) fn new(stream: ReadStream, max_body_len: u32, endian: Endian) -> Self {
+	pub(super) fn new(stream: ReadStream, max_body_len: u32, endian: EndianState, trace: Option<WireTrace>, error_policy: RemoteErrorPolicy, compression: CompressionState, pool: BufferPool) -> Self {
 		Self {
 			stream,
 			max_body_len,
@@ -106,9 +161,22 @@ impl<ReadStream> StreamReadHalf<ReadStream> {
 			bytes_read: 0,
 			parsed_header: MessageHeader::request(0, 0),
 			body_buffer: Vec::new(),
+			pool,
+			trace,
+			error_policy,
+			compression,
+			body_sink: None,
+			sink_state: None,
 		}
 	}
 
+	/// Stream the bodies of incoming messages selected by `body_sink` into a writer.
+	#[allow(dead_code)] // Not used when transports are disabled.
+	pub(super) fn with_body_sink(mut self, body_sink: Option<Arc<dyn BodySink>>) -> Self {
+		self.body_sink = body_sink;
+		self
+	}
+
 	/// Get direct access to the underlying stream.
 	#[allow(dead_code)] // Not used when transports are disabled.
 	pub fn stream(&self) -> &ReadStream {
@@ -124,13 +192,19 @@ impl<ReadStream> StreamReadHalf<ReadStream> {
 
 impl<WriteStream> StreamWriteHalf<WriteStream> {
 	#[allow(dead_code)] // Not used when transports are disabled.
-	pub(super) fn new(stream: WriteStream, max_body_len: u32, endian: Endian) -> Self {
+	pub(super) fn new(stream: WriteStream, max_body_len: u32, endian: EndianState, trace: Option<WireTrace>, compression: CompressionState) -> Self {
 		Self {
 			stream,
 			max_body_len,
 			endian,
+			announcement_pending: compression.is_enabled(),
 			header_buffer: None,
 			bytes_written: 0,
+			trace,
+			compression,
+			compressed_body: None,
+			announcement: None,
+			batch: None,
 		}
 	}
 
@@ -147,17 +221,6 @@ impl<WriteStream> StreamWriteHalf<WriteStream> {
 	}
 }
 
-/// Wrapper around [`AsyncRead::poll_read`] that turns zero-sized reads into ConnectionAborted errors.
-fn poll_read<R: AsyncRead>(stream: Pin<&mut R>, context: &mut Context, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
-	let mut buf = tokio::io::ReadBuf::new(buf);
-	ready!(stream.poll_read(context, &mut buf))?;
-	if buf.filled().is_empty() {
-		Poll::Ready(Err(std::io::ErrorKind::ConnectionAborted.into()))
-	} else {
-		Poll::Ready(Ok(buf.filled().len()))
-	}
-}
-
 impl<R> crate::transport::TransportReadHalf for StreamReadHalf<R>
 where
 	R: AsyncRead + Send + Unpin,
@@ -168,45 +231,96 @@ where
 		// Get the original &mut Self from the pin.
 		let this = self.get_mut();
 
+		loop {
+			let (header, body) = ready!(this.poll_read_frame(context))?;
+
+			// Compression announcements are meant for the transport itself.
+			if CompressionState::is_announcement(&header) {
+				this.compression.process_announcement(&body);
+				this.pool.give_back(body);
+				continue;
+			}
+
+			let body = StreamBody::from_bytes(this.pool.wrap(body));
+			return Poll::Ready(Ok(Message::new(header, body)));
+		}
+	}
+}
+
+impl<R> StreamReadHalf<R>
+where
+	R: AsyncRead + Send + Unpin,
+{
+	/// Try to read a single frame from the stream without blocking.
+	fn poll_read_frame(&mut self, context: &mut Context) -> Poll<Result<(MessageHeader, Vec<u8>), TransportError>> {
 		// Keep polling until the whole frame + header is received.
-		while this.bytes_read < FRAMED_HEADER_LEN {
-			// Read more header data.
-			let stream = Pin::new(&mut this.stream);
-			this.bytes_read += ready!(poll_read(stream, context, &mut this.header_buffer[this.bytes_read..]))
+		if self.bytes_read < FRAMED_HEADER_LEN {
+			let stream = Pin::new(&mut self.stream);
+			ready!(poll_read_exact(stream, context, &mut self.header_buffer, &mut self.bytes_read))
 				.map_err(TransportError::new_fatal)?;
-			assert!(this.bytes_read <= FRAMED_HEADER_LEN);
 
-			// Check if we have the whole frame + header.
-			if this.bytes_read == FRAMED_HEADER_LEN {
-				// Parse frame and header.
-				let length = this.endian.read_u32(&this.header_buffer[0..]);
-				this.parsed_header = MessageHeader::decode(&this.header_buffer[4..], this.endian)
-					.map_err(TransportError::new_fatal)?;
+			// Parse frame and header.
+			// The second byte of the message type holds the compression algorithm of the body.
+			let endian = self.endian.detect(&self.header_buffer[4..], !0xFF00, Some((&self.header_buffer[0..4], self.max_body_len)));
+			let framed_header = frame::decode_raw_framed_header(&self.header_buffer, endian)
+				.map_err(TransportError::new_fatal)?;
+			self.parsed_header = framed_header.header;
+
+			// Stream the body into a sink if requested.
+			// Compressed bodies and messages used by the protocol itself are always read into memory.
+			let body_len = framed_header.body_len;
+			if framed_header.compression == 0 && self.parsed_header.service_id >= 0 {
+				if let Some(sink) = self.body_sink.as_ref().and_then(|x| x.open(&self.parsed_header, body_len)) {
+					self.sink_state = Some(SinkState::new(sink, body_len));
+				}
+			}
 
-				// Check body length and create body buffer.
-				let body_len = length - crate::HEADER_LEN;
-				check_payload_too_large(body_len as usize, this.max_body_len as usize)
+			// Check body length and create body buffer.
+			if self.sink_state.is_none() {
+				check_payload_too_large(body_len, self.max_body_len as usize)
 					.map_err(TransportError::new_fatal)?;
-				this.body_buffer = vec![0; body_len as usize];
+				self.body_buffer = self.pool.take(body_len);
 			}
 		}
 
+		if let Some(sink_state) = &mut self.sink_state {
+			let stream = Pin::new(&mut self.stream);
+			let result = ready!(sink_state.poll_transfer(stream, context))
+				.map_err(TransportError::new_fatal)?;
+			self.sink_state = None;
+			self.bytes_read = 0;
+
+			let header = self.parsed_header;
+			trace_event!(trace, message_type = ?header.message_type, request_id = header.request_id, service_id = header.service_id, "read message into body sink");
+			result.map_err(TransportError::new_non_fatal)?;
+			return Poll::Ready(Ok((header, Vec::new())));
+		}
+
 		// Keep polling until we have the whole body.
-		while this.bytes_read - FRAMED_HEADER_LEN < this.body_buffer.len() {
-			// Read body data.
-			let stream = Pin::new(&mut this.stream);
-			let body_read = this.bytes_read - FRAMED_HEADER_LEN;
-			this.bytes_read += ready!(poll_read(stream, context, &mut this.body_buffer[body_read..]))
-					.map_err(TransportError::new_fatal)?;
-			let body_read = this.bytes_read - FRAMED_HEADER_LEN;
-			assert!(body_read <= this.body_buffer.len());
+		let stream = Pin::new(&mut self.stream);
+		let mut body_read = self.bytes_read - FRAMED_HEADER_LEN;
+		let result = poll_read_exact(stream, context, &mut self.body_buffer, &mut body_read);
+		self.bytes_read = FRAMED_HEADER_LEN + body_read;
+		ready!(result).map_err(TransportError::new_fatal)?;
+
+		if let Some(trace) = &self.trace {
+			trace.record(TraceDirection::Received, &[&self.header_buffer, &self.body_buffer]);
+		}
+
+		// Reset internal state and decompress the body if needed.
+		let header = self.parsed_header;
+		let compression = (self.endian.current().read_u32(&self.header_buffer[4..]) >> 8) as u8;
+		let mut body = std::mem::take(&mut self.body_buffer);
+		self.bytes_read = 0;
+		if compression != 0 {
+			let decompressed = self.compression.decompress(compression, &body, self.max_body_len as usize);
+			self.pool.give_back(body);
+			body = decompressed.map_err(TransportError::new_non_fatal)?;
 		}
 
-		// Reset internal state and return the read message.
-		let header = this.parsed_header;
-		let body = std::mem::take(&mut this.body_buffer);
-		this.bytes_read = 0;
-		Poll::Ready(Ok(Message::new(header, body.into())))
+		trace_event!(trace, message_type = ?header.message_type, request_id = header.request_id, service_id = header.service_id, body_len = body.len(), "read message");
+		self.error_policy.apply_to_message(&header, &mut body);
+		Poll::Ready(Ok((header, body)))
 	}
 }
 
@@ -223,29 +337,143 @@ where
 		check_payload_too_large(body.len(), this.max_body_len as usize)
 			.map_err(TransportError::new_non_fatal)?;
 
-		// Encode the header if we haven't done that yet.
-		let header_buffer = this.header_buffer.get_or_insert_with(|| {
-			let mut buffer = [0u8; FRAMED_HEADER_LEN];
-			this.endian.write_u32(&mut buffer[0..], body.len() as u32 + crate::HEADER_LEN);
-			header.encode(&mut buffer[4..], this.endian);
-			buffer
-		});
+		// Encode the header and compress the body if we haven't done that yet.
+		if this.header_buffer.is_none() {
+			this.announcement = this.encode_announcement();
+			let (header_buffer, compressed_body) = this.encode_frame_header(header, body);
+			this.header_buffer = Some(header_buffer);
+			this.compressed_body = compressed_body;
+		}
+		let header_buffer = this.header_buffer.as_ref().unwrap();
+		let body_data = this.compressed_body.as_deref().unwrap_or(&body.data);
 
-		// Keep writing the header until it is done.
-		while this.bytes_written < FRAMED_HEADER_LEN + body.len() {
-			let stream = Pin::new(&mut this.stream);
-			if this.bytes_written < FRAMED_HEADER_LEN {
-				this.bytes_written = ready!(stream.poll_write_vectored(context, &[IoSlice::new(&header_buffer[this.bytes_written..]), IoSlice::new(&body.data)]))
-					.map_err(TransportError::new_fatal)?;
-			} else {
-				this.bytes_written = ready!(stream.poll_write_vectored(context, &[IoSlice::new(&body.data[this.bytes_written - FRAMED_HEADER_LEN..])]))
-					.map_err(TransportError::new_fatal)?;
+		// Send the compression announcement together with the first message.
+		let announcement = this.announcement.as_deref().unwrap_or(&[]);
+
+		// Keep writing until the header and body are done.
+		let stream = Pin::new(&mut this.stream);
+		ready!(poll_write_all_vectored(stream, context, &[announcement, &header_buffer[..], body_data], &mut this.bytes_written))
+			.map_err(TransportError::new_fatal)?;
+
+		if let Some(trace) = &this.trace {
+			if !announcement.is_empty() {
+				trace.record(TraceDirection::Sent, &[announcement]);
 			}
+			trace.record(TraceDirection::Sent, &[header_buffer, body_data]);
 		}
 
+		trace_event!(trace, message_type = ?header.message_type, request_id = header.request_id, service_id = header.service_id, body_len = body.len(), "wrote message");
+
 		// Reset internal state and return success.
 		this.bytes_written = 0;
 		this.header_buffer = None;
+		this.compressed_body = None;
+		this.announcement_pending = false;
+		this.announcement = None;
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_write_msgs(self: Pin<&mut Self>, context: &mut Context, messages: &[Message<Self::Body>], written: &mut usize) -> Poll<Result<(), TransportError>> {
+		let this = self.get_mut();
+
+		loop {
+			// Coalesce as many messages as possible into a single buffer, up to a size limit.
+			if this.batch.is_none() {
+				let remaining = messages.get(*written..).unwrap_or(&[]);
+				let first = match remaining.first() {
+					Some(x) => x,
+					None => return Poll::Ready(Ok(())),
+				};
+
+				// Report the error for the first message if it is too large.
+				// Later messages that are too large end up first in the next batch.
+				check_payload_too_large(first.body.len(), this.max_body_len as usize)
+					.map_err(TransportError::new_non_fatal)?;
+
+				let mut batch = EncodedBatch::default();
+				if let Some(announcement) = this.encode_announcement() {
+					batch.data.extend_from_slice(&announcement);
+					batch.frame_ends.push(batch.data.len());
+				}
+				for message in remaining {
+					if batch.messages > 0 && (batch.data.len() >= MAX_BATCH_LEN || message.body.len() > this.max_body_len as usize) {
+						break;
+					}
+					let (header_buffer, compressed_body) = this.encode_frame_header(&message.header, &message.body);
+					batch.data.extend_from_slice(&header_buffer);
+					batch.data.extend_from_slice(compressed_body.as_deref().unwrap_or(&message.body.data));
+					batch.frame_ends.push(batch.data.len());
+					batch.messages += 1;
+				}
+				this.batch = Some(batch);
+			}
+			let batch = this.batch.as_ref().unwrap();
+
+			// Keep writing until the whole batch is done.
+			let stream = Pin::new(&mut this.stream);
+			ready!(poll_write_all_vectored(stream, context, &[&batch.data], &mut this.bytes_written))
+				.map_err(TransportError::new_fatal)?;
+
+			if let Some(trace) = &this.trace {
+				let mut start = 0;
+				for &end in &batch.frame_ends {
+					trace.record(TraceDirection::Sent, &[&batch.data[start..end]]);
+					start = end;
+				}
+			}
+
+			trace_event!(trace, messages = batch.messages, len = batch.data.len(), "wrote batch of messages");
+
+			// Reset internal state and continue with the next batch.
+			*written += batch.messages;
+			this.bytes_written = 0;
+			this.batch = None;
+			this.announcement_pending = false;
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), TransportError>> {
+		ready!(Pin::new(&mut self.get_mut().stream).poll_flush(context))
+			.map_err(TransportError::new_fatal)?;
 		Poll::Ready(Ok(()))
 	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), TransportError>> {
+		ready!(Pin::new(&mut self.get_mut().stream).poll_shutdown(context))
+			.map_err(TransportError::new_fatal)?;
+		trace_event!(trace, "shut down write half");
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl<W> StreamWriteHalf<W> {
+	/// Encode the compression announcement if it still needs to be sent.
+	fn encode_announcement(&self) -> Option<Vec<u8>> {
+		if !self.announcement_pending {
+			return None;
+		}
+		let endian = self.endian.current();
+		let (header, body) = self.compression.announcement();
+		let mut frame = Vec::new();
+		frame::encode_frame(&mut frame, &header, &body, endian).ok()?;
+		Some(frame)
+	}
+
+	/// Encode the frame size and header of a message, and compress the body if needed.
+	///
+	/// The second byte of the message type holds the compression algorithm of the body.
+	/// Returns the encoded frame header and the compressed body, if the body is compressed.
+	fn encode_frame_header(&self, header: &MessageHeader, body: &StreamBody) -> ([u8; FRAMED_HEADER_LEN], Option<Vec<u8>>) {
+		let endian = self.endian.current();
+		let (compression, compressed_body) = match self.compression.compress(&body.data) {
+			Some((compression, data)) => (compression, Some(data)),
+			None => (0, None),
+		};
+		let body_len = compressed_body.as_ref().map_or(body.len(), |x| x.len());
+		let mut buffer = [0u8; FRAMED_HEADER_LEN];
+		encode_framed_header(&mut buffer, header, body_len, endian);
+		let message_type = endian.read_u32(&buffer[4..]);
+		endian.write_u32(&mut buffer[4..], message_type | u32::from(compression) << 8);
+		(buffer, compressed_body)
+	}
 }
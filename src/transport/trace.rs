@@ -0,0 +1,373 @@
+//! Byte-accurate traces of the frames sent and received by a transport.
+//!
+//! A wire trace records every complete frame that a transport reads or writes,
+//! exactly as it appears on the wire.
+//! For stream transports, this includes the frame length prefix.
+//! For Unix seqpacket transports, each frame is a single datagram.
+//! File descriptors attached to a message are not part of the trace.
+//!
+//! The trace format is line based and shared with the other implementations of the protocol,
+//! so that traces captured by different implementations can be compared directly.
+//! Each frame is written on a single line:
+//!
+//! ```text
+//! <timestamp> <direction> <frame>
+//! ```
+//!
+//! * `timestamp` is the time the frame was completely read or written,
+//!   as seconds since the Unix epoch with exactly 6 decimals.
+//! * `direction` is `rx` for received frames and `tx` for sent frames.
+//! * `frame` is the raw frame data encoded as lowercase hexadecimal without separators.
+//!
+//! Empty lines and lines starting with `#` are ignored by the parser.
+//!
+//! To record a trace, set the `trace` field of the [`StreamConfig`][crate::StreamConfig]
+//! or [`UnixConfig`][crate::UnixConfig] used to create the transport.
+
+use std::io::{BufWriter, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Writer for wire traces.
+///
+/// The writer can be cloned cheaply.
+/// All clones write to the same underlying output,
+/// so one writer can be shared by multiple transports.
+///
+/// The output is buffered, so recording a frame does not wait for the output in most cases.
+/// Buffered entries are written when the buffer is full, when [`Self::flush()`] is called,
+/// and when the last clone of the writer is dropped.
+///
+/// Errors while writing the trace are ignored,
+/// so that tracing can never break the communication with a peer.
+#[derive(Clone)]
+pub struct WireTrace {
+	/// The buffered output for the trace.
+	output: Arc<Mutex<BufWriter<Box<dyn Write + Send>>>>,
+}
+
+/// The direction of a traced frame.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum TraceDirection {
+	/// The frame was received from the remote peer.
+	Received,
+
+	/// The frame was sent to the remote peer.
+	Sent,
+}
+
+/// A single frame from a wire trace.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TraceEntry {
+	/// The time the frame was completely read or written.
+	pub timestamp: SystemTime,
+
+	/// The direction of the frame.
+	pub direction: TraceDirection,
+
+	/// The raw frame data.
+	pub frame: Vec<u8>,
+}
+
+/// Error that can occur when parsing a wire trace.
+#[derive(Debug)]
+pub enum ParseTraceError {
+	/// Reading the trace failed.
+	Io(std::io::Error),
+
+	/// A line in the trace is not a valid trace entry.
+	InvalidLine {
+		/// The line number of the invalid line, starting at 1.
+		line: usize,
+
+		/// The reason why the line is invalid.
+		reason: &'static str,
+	},
+}
+
+impl WireTrace {
+	/// Create a new wire trace that writes to the given output.
+	pub fn new(output: impl Write + Send + 'static) -> Self {
+		Self {
+			output: Arc::new(Mutex::new(BufWriter::new(Box::new(output)))),
+		}
+	}
+
+	/// Create a new wire trace that writes to a file.
+	///
+	/// If the file already exists, it is truncated.
+	pub fn create(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+		Ok(Self::new(std::fs::File::create(path)?))
+	}
+
+	/// Write all buffered entries to the output and flush it.
+	pub fn flush(&self) -> std::io::Result<()> {
+		self.lock_output().flush()
+	}
+
+	/// Record a complete frame.
+	///
+	/// The frame may be given in multiple parts, which are concatenated in the trace.
+	pub(crate) fn record(&self, direction: TraceDirection, frame: &[&[u8]]) {
+		let entry = format_entry(SystemTime::now(), direction, frame);
+		let _: Result<_, _> = self.lock_output().write_all(entry.as_bytes());
+	}
+
+	/// Lock the output, even if another thread panicked while holding the lock.
+	fn lock_output(&self) -> std::sync::MutexGuard<'_, BufWriter<Box<dyn Write + Send>>> {
+		match self.output.lock() {
+			Ok(x) => x,
+			Err(e) => e.into_inner(),
+		}
+	}
+}
+
+impl TraceDirection {
+	/// Get the representation of the direction in a trace.
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Self::Received => "rx",
+			Self::Sent => "tx",
+		}
+	}
+}
+
+impl TraceEntry {
+	/// Parse a single line of a wire trace.
+	///
+	/// The line must not contain the trailing newline.
+	pub fn parse_line(line: &str) -> Result<Self, &'static str> {
+		let mut fields = line.split(' ');
+		let timestamp = fields.next().ok_or("missing timestamp")?;
+		let direction = fields.next().ok_or("missing direction")?;
+		let frame = fields.next().ok_or("missing frame data")?;
+		if fields.next().is_some() {
+			return Err("unexpected data after frame");
+		}
+
+		Ok(Self {
+			timestamp: parse_timestamp(timestamp)?,
+			direction: match direction {
+				"rx" => TraceDirection::Received,
+				"tx" => TraceDirection::Sent,
+				_ => return Err("invalid direction, expected `rx` or `tx`"),
+			},
+			frame: parse_hex(frame)?,
+		})
+	}
+}
+
+impl std::fmt::Display for TraceEntry {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let line = format_entry(self.timestamp, self.direction, &[&self.frame]);
+		f.write_str(line.trim_end())
+	}
+}
+
+/// Parse a complete wire trace.
+///
+/// Empty lines and lines starting with `#` are skipped.
+pub fn parse_trace(input: impl std::io::BufRead) -> Result<Vec<TraceEntry>, ParseTraceError> {
+	let mut entries = Vec::new();
+	for (i, line) in input.lines().enumerate() {
+		let line = line.map_err(ParseTraceError::Io)?;
+		let line = line.trim_end();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+		let entry = TraceEntry::parse_line(line)
+			.map_err(|reason| ParseTraceError::InvalidLine { line: i + 1, reason })?;
+		entries.push(entry);
+	}
+	Ok(entries)
+}
+
+impl std::error::Error for ParseTraceError {}
+
+impl std::fmt::Display for ParseTraceError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Io(e) => write!(f, "failed to read trace: {}", e),
+			Self::InvalidLine { line, reason } => write!(f, "invalid trace entry on line {}: {}", line, reason),
+		}
+	}
+}
+
+impl std::fmt::Debug for WireTrace {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct(core::any::type_name::<Self>())
+			.finish_non_exhaustive()
+	}
+}
+
+/// Format a trace entry as a single line, including the trailing newline.
+fn format_entry(timestamp: SystemTime, direction: TraceDirection, frame: &[&[u8]]) -> String {
+	use std::fmt::Write;
+
+	// Timestamps before the Unix epoch are clamped to the epoch.
+	let timestamp = timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+	let frame_len: usize = frame.iter().map(|x| x.len()).sum();
+
+	let mut line = String::with_capacity(32 + 2 * frame_len);
+	write!(line, "{}.{:06} {} ", timestamp.as_secs(), timestamp.subsec_micros(), direction.as_str()).unwrap();
+	for byte in frame.iter().flat_map(|x| x.iter()) {
+		write!(line, "{:02x}", byte).unwrap();
+	}
+	line.push('\n');
+	line
+}
+
+/// Parse a timestamp with exactly 6 decimals.
+fn parse_timestamp(input: &str) -> Result<SystemTime, &'static str> {
+	const INVALID: &str = "invalid timestamp, expected seconds with 6 decimals";
+	let (seconds, micros) = input.split_once('.').ok_or(INVALID)?;
+	if seconds.is_empty() || micros.len() != 6 || !seconds.bytes().chain(micros.bytes()).all(|x| x.is_ascii_digit()) {
+		return Err(INVALID);
+	}
+	let seconds: u64 = seconds.parse().map_err(|_| INVALID)?;
+	let micros: u32 = micros.parse().map_err(|_| INVALID)?;
+	Ok(SystemTime::UNIX_EPOCH + Duration::new(seconds, micros * 1000))
+}
+
+/// Parse lowercase or uppercase hexadecimal data without separators.
+fn parse_hex(input: &str) -> Result<Vec<u8>, &'static str> {
+	fn nibble(byte: u8) -> Result<u8, &'static str> {
+		match byte {
+			b'0'..=b'9' => Ok(byte - b'0'),
+			b'a'..=b'f' => Ok(byte - b'a' + 10),
+			b'A'..=b'F' => Ok(byte - b'A' + 10),
+			_ => Err("invalid hexadecimal frame data"),
+		}
+	}
+
+	let input = input.as_bytes();
+	if input.len() % 2 != 0 {
+		return Err("frame data has an odd number of hexadecimal digits");
+	}
+	input.chunks(2)
+		.map(|pair| Ok(nibble(pair[0])? << 4 | nibble(pair[1])?))
+		.collect()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+
+	use crate::{StreamConfig, UnixStreamPeer, UnixStreamTransport};
+
+	/// Trace output that can be inspected by the test.
+	#[derive(Clone, Default)]
+	struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+	impl Write for SharedBuffer {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			self.0.lock().unwrap().write(buf)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn format_and_parse_entry() {
+		let entry = TraceEntry {
+			timestamp: SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 1_234_000),
+			direction: TraceDirection::Sent,
+			frame: vec![0x0c, 0x00, 0x00, 0x00, 0xab, 0xff],
+		};
+		assert!(entry.to_string() == "1700000000.001234 tx 0c000000abff");
+		let_assert!(Ok(parsed) = TraceEntry::parse_line(&entry.to_string()));
+		assert!(parsed == entry);
+	}
+
+	#[test]
+	fn parse_invalid_entries() {
+		assert!(let Err(_) = TraceEntry::parse_line("1700000000.001234 tx"));
+		assert!(let Err(_) = TraceEntry::parse_line("1700000000.1234 tx 00"));
+		assert!(let Err(_) = TraceEntry::parse_line("1700000000.001234 up 00"));
+		assert!(let Err(_) = TraceEntry::parse_line("1700000000.001234 rx 0"));
+		assert!(let Err(_) = TraceEntry::parse_line("1700000000.001234 rx 0g"));
+		assert!(let Err(_) = TraceEntry::parse_line("1700000000.001234 rx 00 00"));
+	}
+
+	#[test]
+	fn parse_trace_skips_comments() {
+		let input = "# trace from peer A\n\n1.000000 rx 01\n2.500000 tx 0203\n";
+		let_assert!(Ok(entries) = parse_trace(input.as_bytes()));
+		assert!(entries.len() == 2);
+		assert!(entries[0].direction == TraceDirection::Received);
+		assert!(entries[0].frame == [0x01]);
+		assert!(entries[1].timestamp == SystemTime::UNIX_EPOCH + Duration::from_millis(2500));
+		assert!(entries[1].frame == [0x02, 0x03]);
+
+		let_assert!(Err(ParseTraceError::InvalidLine { line: 2, .. }) = parse_trace("1.000000 rx 01\nfoo\n".as_bytes()));
+	}
+
+	#[test]
+	fn trace_is_buffered() {
+		let output = SharedBuffer::default();
+		let trace = WireTrace::new(output.clone());
+		trace.record(TraceDirection::Sent, &[&[0x01], &[0x02]]);
+		assert!(output.0.lock().unwrap().is_empty());
+
+		// Entries are written on flush, and when the last clone is dropped.
+		let_assert!(Ok(()) = trace.flush());
+		let_assert!(Ok(entries) = parse_trace(&output.0.lock().unwrap()[..]));
+		assert!(entries.len() == 1);
+		assert!(entries[0].frame == [0x01, 0x02]);
+
+		trace.record(TraceDirection::Received, &[&[0x03]]);
+		drop(trace.clone());
+		let_assert!(Ok(entries) = parse_trace(&output.0.lock().unwrap()[..]));
+		assert!(entries.len() == 1);
+		drop(trace);
+		let_assert!(Ok(entries) = parse_trace(&output.0.lock().unwrap()[..]));
+		assert!(entries.len() == 2);
+		assert!(entries[1].direction == TraceDirection::Received);
+	}
+
+	#[tokio::test]
+	async fn trace_stream_transport() {
+		let output = SharedBuffer::default();
+		let wire_trace = WireTrace::new(output.clone());
+		let config = StreamConfig {
+			trace: Some(wire_trace.clone()),
+			..Default::default()
+		};
+
+		let_assert!(Ok((peer_a, peer_b)) = tokio::net::UnixStream::pair());
+		let mut peer_a = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_a, config));
+		let mut peer_b = UnixStreamPeer::spawn(UnixStreamTransport::new(peer_b, Default::default()));
+
+		let_assert!(Ok(()) = peer_a.send_stream(3, &b"ping"[..]).await);
+		let_assert!(Ok(_) = peer_b.recv_message().await);
+		let_assert!(Ok(()) = peer_b.send_stream(4, &b"pong"[..]).await);
+		let_assert!(Ok(_) = peer_a.recv_message().await);
+
+		let_assert!(Ok(()) = wire_trace.flush());
+		let trace = output.0.lock().unwrap().clone();
+		let_assert!(Ok(entries) = parse_trace(&trace[..]));
+		assert!(entries.len() == 2);
+
+		assert!(entries[0].direction == TraceDirection::Sent);
+		assert!(entries[0].frame == [
+			0x10, 0x00, 0x00, 0x00, // frame length
+			0x04, 0x00, 0x00, 0x00, // message type
+			0x00, 0x00, 0x00, 0x00, // request ID
+			0x03, 0x00, 0x00, 0x00, // service ID
+			b'p', b'i', b'n', b'g',
+		]);
+
+		assert!(entries[1].direction == TraceDirection::Received);
+		assert!(entries[1].frame == [
+			0x10, 0x00, 0x00, 0x00, // frame length
+			0x04, 0x00, 0x00, 0x00, // message type
+			0x00, 0x00, 0x00, 0x00, // request ID
+			0x04, 0x00, 0x00, 0x00, // service ID
+			b'p', b'o', b'n', b'g',
+		]);
+	}
+}
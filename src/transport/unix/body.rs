@@ -1,5 +1,7 @@
 use filedesc::FileDesc;
 
+use crate::{Error, StreamBody};
+
 /// Body for the unix tranport.
 ///
 /// The body includes data for a datagram,
@@ -26,6 +28,29 @@ impl UnixBody {
 	}
 }
 
+/// What to do with file descriptors when a [`UnixBody`] is converted to a body type that can not hold them.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FdPolicy {
+	/// Close the file descriptors and convert only the data.
+	Drop,
+
+	/// Fail the conversion if the body has file descriptors attached.
+	Reject,
+}
+
+impl UnixBody {
+	/// Convert the body into a [`StreamBody`], for example to relay it to a peer using a stream transport.
+	///
+	/// A stream body can not hold file descriptors,
+	/// so attached file descriptors are closed or result in an error, depending on the `fds` policy.
+	pub fn into_stream_body(self, fds: FdPolicy) -> Result<StreamBody, Error> {
+		if fds == FdPolicy::Reject && !self.fds.is_empty() {
+			return Err(Error::custom(format!("message body has {} file descriptors attached, which can not be converted to a stream body", self.fds.len())));
+		}
+		Ok(StreamBody::from(self.data))
+	}
+}
+
 impl crate::Body for UnixBody {
 	fn empty() -> Self {
 		Self::from(Vec::new())
@@ -42,6 +67,33 @@ impl crate::Body for UnixBody {
 	fn into_error(self) -> Result<String, std::string::FromUtf8Error> {
 		String::from_utf8(self.data)
 	}
+
+	fn data_len(&self) -> usize {
+		self.data.len()
+	}
+}
+
+impl AsRef<[u8]> for UnixBody {
+	fn as_ref(&self) -> &[u8] {
+		&self.data
+	}
+}
+
+impl From<StreamBody> for UnixBody {
+	fn from(other: StreamBody) -> Self {
+		Vec::from(other.data).into()
+	}
+}
+
+/// Convert a body to a [`StreamBody`], failing if it has file descriptors attached.
+///
+/// See [`UnixBody::into_stream_body()`] to close the file descriptors instead.
+impl TryFrom<UnixBody> for StreamBody {
+	type Error = Error;
+
+	fn try_from(other: UnixBody) -> Result<Self, Error> {
+		other.into_stream_body(FdPolicy::Reject)
+	}
 }
 
 impl From<Vec<u8>> for UnixBody {
@@ -1,4 +1,4 @@
-use crate::transport::Endian;
+use crate::transport::{Endian, RemoteErrorPolicy, WireTrace};
 
 /// Configuration for Unix datagram transports.
 #[derive(Debug, Clone)]
@@ -31,6 +31,26 @@ pub struct UnixConfig {
 	/// The encoding and serialization of message bodies is up to the application code,
 	/// and it not affected by this configuration parameter.
 	pub endian: Endian,
+
+	/// Detect the endianness of the remote peer from the received messages.
+	///
+	/// If enabled, the endianness is detected from the first received message that is unambiguous.
+	/// From then on, the detected endianness is used to decode incoming messages and to encode outgoing messages,
+	/// so that peers with a different [`Self::endian`] configuration can still communicate.
+	/// Until the endianness is detected, the configured endianness is used.
+	///
+	/// Only one side of the connection should enable detection, and it should wait for the remote peer to send the first message.
+	pub detect_endian: bool,
+
+	/// Record a byte-accurate trace of all frames read and written by the transport.
+	///
+	/// See the [`trace`][crate::transport::trace] module for the format of the trace.
+	pub trace: Option<WireTrace>,
+
+	/// The validation to apply to error messages received from the remote peer.
+	///
+	/// By default, error messages are not modified.
+	pub error_policy: RemoteErrorPolicy,
 }
 
 impl Default for UnixConfig {
@@ -41,6 +61,9 @@ impl Default for UnixConfig {
 			max_fds_read: 10,
 			max_fds_write: 10,
 			endian: Endian::NativeEndian,
+			detect_endian: false,
+			trace: None,
+			error_policy: RemoteErrorPolicy::none(),
 		}
 	}
 }
@@ -0,0 +1,391 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::ReadBuf;
+use tokio::net::UnixDatagram;
+
+use crate::error::private::{check_message_too_short, check_payload_too_large};
+use crate::transport::trace::TraceDirection;
+use crate::transport::{EndianState, RemoteErrorPolicy, TransportError, WireTrace};
+use crate::{Message, MessageHeader, StreamBody, UnixConfig};
+
+/// Transport layer for Unix datagram sockets.
+///
+/// Each message is sent as a single datagram, holding the message header followed by the body.
+/// Messages are limited by the maximum datagram size of the socket, and file descriptors can not be attached to them.
+///
+/// The transport uses the [`UnixConfig`] of the seqpacket transport.
+/// The `max_fds_read` and `max_fds_write` options are ignored.
+///
+/// Datagram sockets have no connection, so a peer using this transport never sees the remote peer disconnect.
+/// Use [`Self::new()`] for a socket that is connected to the remote socket,
+/// or [`Self::with_peer_address()`] to send messages to an address with an unconnected socket.
+pub struct UnixDatagramTransport {
+	/// The socket to use for sending/receiving messages.
+	socket: UnixDatagram,
+
+	/// The address to send messages to, if the socket is not connected.
+	peer_address: Option<PathBuf>,
+
+	/// The configuration of the transport.
+	config: UnixConfig,
+}
+
+/// The read half of a [`UnixDatagramTransport`].
+pub struct UnixDatagramReadHalf<'a> {
+	/// The underlying socket.
+	socket: &'a UnixDatagram,
+
+	/// The address to accept messages from, if the socket is not connected.
+	peer_address: Option<&'a Path>,
+
+	/// The maximum body length to accept when reading messages.
+	max_body_len: u32,
+
+	/// The endianness to use for decoding header fields.
+	endian: EndianState,
+
+	/// Buffer for reading the datagram.
+	buffer: Vec<u8>,
+
+	/// The wire trace to record received frames in.
+	trace: Option<WireTrace>,
+
+	/// The validation to apply to received error messages.
+	error_policy: RemoteErrorPolicy,
+}
+
+/// The write half of a [`UnixDatagramTransport`].
+pub struct UnixDatagramWriteHalf<'a> {
+	/// The underlying socket.
+	socket: &'a UnixDatagram,
+
+	/// The address to send messages to, if the socket is not connected.
+	peer_address: Option<&'a Path>,
+
+	/// The maximum body length to enforce for messages.
+	max_body_len: u32,
+
+	/// The endianness to use for encoding header fields.
+	endian: EndianState,
+
+	/// The wire trace to record sent frames in.
+	trace: Option<WireTrace>,
+
+	/// The encoded datagram of the current message, while it is being sent.
+	datagram: Option<Vec<u8>>,
+}
+
+/// Information about the remote peer of a Unix datagram socket.
+#[derive(Debug, Clone)]
+pub struct UnixDatagramInfo {
+	/// The address of the remote socket.
+	peer_address: Option<PathBuf>,
+}
+
+impl UnixDatagramInfo {
+	/// Get the filesystem address of the remote socket.
+	///
+	/// Returns [`None`] if the remote socket is not bound to a filesystem path.
+	pub fn peer_address(&self) -> Option<&Path> {
+		self.peer_address.as_deref()
+	}
+}
+
+impl UnixDatagramTransport {
+	/// Create a new transport for a connected socket.
+	///
+	/// Messages are sent to and received from the address the socket is connected to.
+	pub fn new(socket: UnixDatagram, config: UnixConfig) -> Self {
+		Self {
+			socket,
+			peer_address: None,
+			config,
+		}
+	}
+
+	/// Create a new transport using the default configuration.
+	pub fn new_default(socket: UnixDatagram) -> Self {
+		Self::new(socket, UnixConfig::default())
+	}
+
+	/// Create a new transport that sends messages to a specific address with an unconnected socket.
+	///
+	/// Datagrams received from other addresses are discarded.
+	/// To receive replies, the socket must be bound to an address that the remote peer sends its messages to.
+	pub fn with_peer_address(socket: UnixDatagram, peer_address: impl Into<PathBuf>, config: UnixConfig) -> Self {
+		Self {
+			socket,
+			peer_address: Some(peer_address.into()),
+			config,
+		}
+	}
+
+	/// Get the address messages are sent to, if the socket is not connected.
+	pub fn peer_address(&self) -> Option<&Path> {
+		self.peer_address.as_deref()
+	}
+
+	/// Get direct access to the underlying socket.
+	pub fn socket(&self) -> &UnixDatagram {
+		&self.socket
+	}
+
+	/// Consume the transport to retrieve the underlying socket.
+	pub fn into_socket(self) -> UnixDatagram {
+		self.socket
+	}
+}
+
+impl crate::transport::Transport for UnixDatagramTransport {
+	type Body = StreamBody;
+	type Info = UnixDatagramInfo;
+	type Config = UnixConfig;
+	type ReadHalf<'a> = UnixDatagramReadHalf<'a>;
+	type WriteHalf<'a> = UnixDatagramWriteHalf<'a>;
+
+	fn split(&mut self) -> (UnixDatagramReadHalf<'_>, UnixDatagramWriteHalf<'_>) {
+		let endian = EndianState::new(self.config.endian, self.config.detect_endian);
+		let read_half = UnixDatagramReadHalf {
+			socket: &self.socket,
+			peer_address: self.peer_address.as_deref(),
+			max_body_len: self.config.max_body_len_read,
+			endian: endian.clone(),
+			buffer: Vec::new(),
+			trace: self.config.trace.clone(),
+			error_policy: self.config.error_policy.clone(),
+		};
+		let write_half = UnixDatagramWriteHalf {
+			socket: &self.socket,
+			peer_address: self.peer_address.as_deref(),
+			max_body_len: self.config.max_body_len_write,
+			endian,
+			trace: self.config.trace.clone(),
+			datagram: None,
+		};
+		(read_half, write_half)
+	}
+
+	fn info(&self) -> std::io::Result<Self::Info> {
+		let peer_address = match &self.peer_address {
+			Some(address) => Some(address.clone()),
+			None => self.socket.peer_addr()?.as_pathname().map(Path::to_path_buf),
+		};
+		Ok(Self::Info { peer_address })
+	}
+
+	fn describe_remote(&self) -> Option<String> {
+		let info = self.info().ok()?;
+		match info.peer_address() {
+			Some(address) => Some(format!("unix-datagram {}", address.display())),
+			None => Some(String::from("unix-datagram (unnamed)")),
+		}
+	}
+}
+
+impl crate::transport::TransportReadHalf for UnixDatagramReadHalf<'_> {
+	type Body = StreamBody;
+
+	fn poll_read_msg(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<Message<Self::Body>, TransportError>> {
+		let this = self.get_mut();
+
+		loop {
+			// Reserve one byte more than the maximum message size to detect oversized datagrams,
+			// since the kernel silently truncates datagrams that do not fit in the buffer.
+			this.buffer.resize(crate::HEADER_LEN as usize + this.max_body_len as usize + 1, 0);
+			let mut read_buf = ReadBuf::new(&mut this.buffer);
+			match this.peer_address {
+				None => ready!(this.socket.poll_recv(context, &mut read_buf)).map_err(TransportError::new_fatal)?,
+				Some(peer_address) => {
+					let address = ready!(this.socket.poll_recv_from(context, &mut read_buf)).map_err(TransportError::new_fatal)?;
+					if address.as_pathname() != Some(peer_address) {
+						trace_event!(debug, address = ?address, "discarding datagram from unknown address");
+						continue;
+					}
+				},
+			}
+			let bytes_read = read_buf.filled().len();
+
+			// A malformed datagram does not affect the messages after it.
+			check_message_too_short(bytes_read)
+				.map_err(TransportError::new_non_fatal)?;
+			check_payload_too_large(bytes_read - crate::HEADER_LEN as usize, this.max_body_len as usize)
+				.map_err(TransportError::new_non_fatal)?;
+
+			// Parse the header.
+			let endian = this.endian.detect(&this.buffer[..crate::HEADER_LEN as usize], !0, None);
+			let header = MessageHeader::decode(&this.buffer[..crate::HEADER_LEN as usize], endian)
+				.map_err(TransportError::new_non_fatal)?;
+
+			let mut buffer = std::mem::take(&mut this.buffer);
+			buffer.truncate(bytes_read);
+			if let Some(trace) = &this.trace {
+				trace.record(TraceDirection::Received, &[&buffer]);
+			}
+
+			let mut body = buffer.split_off(crate::HEADER_LEN as usize);
+			this.error_policy.apply_to_message(&header, &mut body);
+			trace_event!(trace, message_type = ?header.message_type, request_id = header.request_id, service_id = header.service_id, body_len = body.len(), "read message");
+			return Poll::Ready(Ok(Message::new(header, StreamBody::from(body))));
+		}
+	}
+}
+
+impl crate::transport::TransportWriteHalf for UnixDatagramWriteHalf<'_> {
+	type Body = StreamBody;
+
+	fn poll_write_msg(self: Pin<&mut Self>, context: &mut Context, header: &MessageHeader, body: &Self::Body) -> Poll<Result<(), TransportError>> {
+		let this = self.get_mut();
+
+		// Check the outgoing body size.
+		check_payload_too_large(body.len(), this.max_body_len as usize)
+			.map_err(TransportError::new_non_fatal)?;
+
+		// Encode the header and body into a single datagram if we haven't done that yet.
+		let datagram = this.datagram.get_or_insert_with(|| {
+			let mut datagram = vec![0; crate::HEADER_LEN as usize];
+			header.encode(&mut datagram, this.endian.current());
+			datagram.extend_from_slice(&body.data);
+			datagram
+		});
+
+		// A datagram is always sent completely or not at all.
+		let result = match this.peer_address {
+			None => ready!(this.socket.poll_send(context, datagram)),
+			Some(peer_address) => ready!(this.socket.poll_send_to(context, datagram, peer_address)),
+		};
+		let datagram = this.datagram.take().unwrap_or_default();
+		result.map_err(TransportError::new_fatal)?;
+
+		if let Some(trace) = &this.trace {
+			trace.record(TraceDirection::Sent, &[&datagram]);
+		}
+		trace_event!(trace, message_type = ?header.message_type, request_id = header.request_id, service_id = header.service_id, body_len = body.len(), "wrote message");
+
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl crate::util::IntoTransport for UnixDatagram {
+	type Body = StreamBody;
+	type Config = UnixConfig;
+	type Transport = UnixDatagramTransport;
+
+	fn into_transport(self, config: Self::Config) -> Self::Transport {
+		UnixDatagramTransport::new(self, config)
+	}
+}
+
+impl<'a, Address> crate::util::Connect<'a, Address> for UnixDatagramTransport
+where
+	Address: AsRef<Path> + 'a,
+{
+	type Future = Pin<Box<dyn Future<Output = std::io::Result<Self>> + 'a>>;
+
+	/// Connect an unbound socket to the given address.
+	///
+	/// The remote peer can only reply to an unbound socket if it uses a connected socket too.
+	/// Otherwise, bind the socket to an address yourself and use [`UnixDatagramTransport::new()`].
+	fn connect(address: Address, config: Self::Config) -> Self::Future {
+		Box::pin(async move {
+			let socket = UnixDatagram::unbound()?;
+			socket.connect(address)?;
+			Ok(Self::new(socket, config))
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+
+	use crate::transport::{Transport, TransportReadHalf, TransportWriteHalf};
+	use crate::util::IntoTransport;
+	use crate::{ReceivedMessage, UnixDatagramPeer};
+
+	#[tokio::test]
+	async fn test_unix_datagram_transport() {
+		let_assert!(Ok((socket_a, socket_b)) = UnixDatagram::pair());
+		let mut transport_a = socket_a.into_default_transport();
+		let mut transport_b = socket_b.into_default_transport();
+		let (mut read_a, mut write_a) = transport_a.split();
+		let (mut read_b, mut write_b) = transport_b.split();
+
+		for i in 0..10 {
+			assert!(let Ok(()) = write_a.write_msg(&MessageHeader::request(i * 2, 10), &b"Hello peer_b!"[..].into()).await);
+			let_assert!(Ok(message) = read_b.read_msg().await);
+			assert!(message.header == MessageHeader::request(i * 2, 10));
+			assert!(message.body.as_ref() == b"Hello peer_b!");
+
+			assert!(let Ok(()) = write_b.write_msg(&MessageHeader::request(i * 2 + 1, 11), &b"Hello peer_a!"[..].into()).await);
+			let_assert!(Ok(message) = read_a.read_msg().await);
+			assert!(message.header == MessageHeader::request(i * 2 + 1, 11));
+			assert!(message.body.as_ref() == b"Hello peer_a!");
+		}
+	}
+
+	#[tokio::test]
+	async fn malformed_datagrams_are_not_fatal() {
+		let_assert!(Ok((socket_a, socket_b)) = UnixDatagram::pair());
+		let config = UnixConfig {
+			max_body_len_read: 4,
+			..Default::default()
+		};
+		let mut transport_b = UnixDatagramTransport::new(socket_b, config);
+		let (mut read_b, _write_b) = transport_b.split();
+
+		assert!(let Ok(_) = socket_a.send(b"short").await);
+		let_assert!(Err(e) = read_b.read_msg().await);
+		assert!(!e.is_fatal());
+
+		let mut datagram = vec![0; crate::HEADER_LEN as usize];
+		MessageHeader::stream(0, 1).encode(&mut datagram, crate::transport::Endian::NativeEndian);
+		datagram.extend_from_slice(b"too long");
+		assert!(let Ok(_) = socket_a.send(&datagram).await);
+		let_assert!(Err(e) = read_b.read_msg().await);
+		assert!(!e.is_fatal());
+
+		datagram.truncate(crate::HEADER_LEN as usize + 4);
+		assert!(let Ok(_) = socket_a.send(&datagram).await);
+		let_assert!(Ok(message) = read_b.read_msg().await);
+		assert!(message.header == MessageHeader::stream(0, 1));
+		assert!(message.body.as_ref() == b"too ");
+	}
+
+	#[tokio::test]
+	async fn peer_address() {
+		let dir = std::env::temp_dir().join(format!("fizyr-rpc-datagram-test-{}", std::process::id()));
+		let_assert!(Ok(()) = std::fs::create_dir_all(&dir));
+		let path_a = dir.join("a.sock");
+		let path_b = dir.join("b.sock");
+		let path_c = dir.join("c.sock");
+		let_assert!(Ok(socket_a) = UnixDatagram::bind(&path_a));
+		let_assert!(Ok(socket_b) = UnixDatagram::bind(&path_b));
+		let_assert!(Ok(socket_c) = UnixDatagram::bind(&path_c));
+
+		let transport_a = UnixDatagramTransport::with_peer_address(socket_a, &path_b, Default::default());
+		let transport_b = UnixDatagramTransport::with_peer_address(socket_b, &path_a, Default::default());
+		let_assert!(Ok(info) = transport_b.info());
+		assert!(info.peer_address() == Some(path_a.as_path()));
+
+		let peer_a = UnixDatagramPeer::spawn(transport_a);
+		let mut peer_b = UnixDatagramPeer::spawn(transport_b);
+
+		// Datagrams from other addresses are discarded.
+		let mut datagram = vec![0; crate::HEADER_LEN as usize];
+		MessageHeader::stream(0, 2).encode(&mut datagram, crate::transport::Endian::NativeEndian);
+		assert!(let Ok(_) = socket_c.send_to(&datagram, &path_b).await);
+
+		let_assert!(Ok(mut request) = peer_a.send_request(1, &b"ping"[..]).await);
+		let_assert!(Ok(ReceivedMessage::Request(received, body)) = peer_b.recv_message().await);
+		assert!(body.as_ref() == b"ping");
+		assert!(let Ok(()) = received.send_response(1, &b"pong"[..]).await);
+		let_assert!(Ok(response) = request.recv_response().await);
+		assert!(response.body.as_ref() == b"pong");
+
+		let _ = std::fs::remove_dir_all(&dir);
+	}
+}
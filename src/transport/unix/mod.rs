@@ -2,11 +2,19 @@ mod body;
 mod config;
 mod transport;
 
-pub use body::UnixBody;
+#[cfg(feature = "unix-datagram")]
+mod datagram;
+
+pub use body::{FdPolicy, UnixBody};
 pub use config::UnixConfig;
+// The read and write halves are only used by the transports enabled with feature flags.
+#[allow(unused_imports)]
 pub use transport::{UnixReadHalf, UnixTransport, UnixWriteHalf};
 
-/// Information about the remote peer of a Unix stream.
+#[cfg(feature = "unix-datagram")]
+pub use datagram::{UnixDatagramInfo, UnixDatagramReadHalf, UnixDatagramTransport, UnixDatagramWriteHalf};
+
+/// Information about the remote peer of a Unix seqpacket socket.
 #[derive(Debug, Clone)]
 #[cfg(feature = "unix-seqpacket")]
 pub struct UnixSeqpacketInfo {
@@ -22,12 +30,12 @@ pub struct UnixSeqpacketInfo {
 
 #[cfg(feature = "unix-seqpacket")]
 impl UnixSeqpacketInfo {
-	/// Get the user ID of the process.
+	/// Get the user ID of the remote process.
 	pub fn user_id(&self) -> u32 {
 		self.user_id
 	}
 
-	/// Get the group ID of the process.
+	/// Get the group ID of the remote process.
 	pub fn group_id(&self) -> u32 {
 		self.group_id
 	}
@@ -43,6 +51,7 @@ mod impl_unix_seqpacket {
 	use std::future::Future;
 	use std::pin::Pin;
 	use super::*;
+	use crate::transport::EndianState;
 
 	impl crate::transport::Transport for UnixTransport<tokio_seqpacket::UnixSeqpacket> {
 		type Body = UnixBody;
@@ -53,8 +62,9 @@ mod impl_unix_seqpacket {
 
 		fn split(&mut self) -> (UnixReadHalf<&tokio_seqpacket::UnixSeqpacket>, UnixWriteHalf<&tokio_seqpacket::UnixSeqpacket>) {
 			let (read_half, write_half) = (&self.socket, &self.socket);
-			let read_half = UnixReadHalf::new(read_half, self.config.max_body_len_read, self.config.max_fds_read, self.config.endian);
-			let write_half = UnixWriteHalf::new(write_half, self.config.max_body_len_write, self.config.max_fds_write, self.config.endian);
+			let endian = EndianState::new(self.config.endian, self.config.detect_endian);
+			let read_half = UnixReadHalf::new(read_half, self.config.max_body_len_read, self.config.max_fds_read, endian.clone(), self.config.trace.clone(), self.config.error_policy.clone());
+			let write_half = UnixWriteHalf::new(write_half, self.config.max_body_len_write, self.config.max_fds_write, endian, self.config.trace.clone());
 			(read_half, write_half)
 		}
 
@@ -66,6 +76,14 @@ mod impl_unix_seqpacket {
 				process_id: creds.pid(),
 			})
 		}
+
+		fn describe_remote(&self) -> Option<String> {
+			let creds = self.socket.peer_cred().ok()?;
+			match creds.pid() {
+				Some(pid) => Some(format!("unix-seqpacket pid {} uid {}", pid, creds.uid())),
+				None => Some(format!("unix-seqpacket uid {}", creds.uid())),
+			}
+		}
 	}
 
 	impl crate::util::IntoTransport for tokio_seqpacket::UnixSeqpacket {
@@ -114,6 +132,15 @@ mod impl_unix_seqpacket {
 			})
 		}
 	}
+
+	impl crate::util::FromFd for tokio_seqpacket::UnixSeqpacketListener {
+		fn from_fd(fd: std::os::unix::io::OwnedFd) -> std::io::Result<Self> {
+			// The standard library has no seqpacket listener, but setting the non-blocking flag works the same for all sockets.
+			let listener = std::os::unix::net::UnixListener::from(fd);
+			listener.set_nonblocking(true)?;
+			Self::try_from(std::os::unix::io::OwnedFd::from(listener))
+		}
+	}
 }
 
 #[cfg(test)]
@@ -129,6 +156,26 @@ mod test {
 	use crate::MessageHeader;
 	use crate::UnixBody;
 
+	#[tokio::test]
+	async fn peer_credentials() {
+		use crate::transport::Transport;
+
+		let_assert!(Ok((socket_a, _socket_b)) = UnixSeqpacket::pair());
+		let transport = socket_a.into_default_transport();
+		let_assert!(Ok(info) = transport.info());
+
+		// Both ends of the socket pair belong to this process,
+		// so the credentials must match those reported for a Unix stream pair.
+		let_assert!(Ok((stream_a, _stream_b)) = tokio::net::UnixStream::pair());
+		let_assert!(Ok(expected) = crate::UnixStreamTransport::new_default(stream_a).info());
+		assert!(info.user_id() == expected.user_id());
+		assert!(info.group_id() == expected.group_id());
+		assert!(info.process_id() == expected.process_id());
+		if let Some(process_id) = info.process_id() {
+			assert!(process_id as u32 == std::process::id());
+		}
+	}
+
 	#[tokio::test]
 	async fn test_unix_transport() {
 		let_assert!(Ok((socket_a, socket_b)) = UnixSeqpacket::pair());
@@ -162,6 +209,36 @@ mod test {
 		}
 	}
 
+	#[tokio::test]
+	async fn detect_endian() {
+		let_assert!(Ok((socket_a, socket_b)) = UnixSeqpacket::pair());
+
+		let config_a = crate::UnixConfig {
+			endian: crate::transport::Endian::BigEndian,
+			..Default::default()
+		};
+		let config_b = crate::UnixConfig {
+			endian: crate::transport::Endian::LittleEndian,
+			detect_endian: true,
+			..Default::default()
+		};
+		let mut transport_a = socket_a.into_transport(config_a);
+		let mut transport_b = socket_b.into_transport(config_b);
+
+		use crate::transport::{Transport, TransportReadHalf, TransportWriteHalf};
+		let (mut read_a, mut write_a) = transport_a.split();
+		let (mut read_b, mut write_b) = transport_b.split();
+
+		// Peer B detects that peer A uses big endian, and answers in big endian.
+		assert!(let Ok(()) = write_a.write_msg(&MessageHeader::request(1, 10), &b"Hello peer_b!"[..].into()).await);
+		let_assert!(Ok(message) = read_b.read_msg().await);
+		assert!(message.header == MessageHeader::request(1, 10));
+		assert!(let Ok(()) = write_b.write_msg(&MessageHeader::response(1, 10), &b"Hello peer_a!"[..].into()).await);
+		let_assert!(Ok(message) = read_a.read_msg().await);
+		assert!(message.header == MessageHeader::response(1, 10));
+		assert!(message.body.data == b"Hello peer_a!");
+	}
+
 	fn make_blob(name: &str, data: &[u8]) -> filedesc::FileDesc {
 		use std::io::{Seek, Write};
 		let_assert!(Ok(fd) = memfile::MemFile::create_default(name));
@@ -1,5 +1,6 @@
 use crate::UnixConfig;
-use crate::transport::Endian;
+use crate::transport::EndianState;
+use crate::transport::{RemoteErrorPolicy, WireTrace};
 
 /// Transport layer for Unix datagram/seqpacket sockets.
 #[allow(dead_code)] // Fields are not used when transports are disabled.
@@ -24,10 +25,16 @@ pub struct UnixReadHalf<SocketReadHalf> {
 	pub(super) max_fds: u32,
 
 	/// The endianness to use for decoding header fields.
-	pub(super) endian: Endian,
+	pub(super) endian: EndianState,
 
 	/// Buffer for reading the message body.
 	pub(super) body_buffer: Vec<u8>,
+
+	/// The wire trace to record received frames in.
+	pub(super) trace: Option<WireTrace>,
+
+	/// The validation to apply to received error messages.
+	pub(super) error_policy: RemoteErrorPolicy,
 }
 
 /// The write half of a [`UnixTransport`].
@@ -43,7 +50,10 @@ pub struct UnixWriteHalf<SocketWriteHalf> {
 	pub(super) max_fds: u32,
 
 	/// The endianness to use for encoding header fields.
-	pub(super) endian: Endian,
+	pub(super) endian: EndianState,
+
+	/// The wire trace to record sent frames in.
+	pub(super) trace: Option<WireTrace>,
 }
 
 impl<Socket> UnixTransport<Socket>
@@ -78,13 +88,15 @@ where
 
 impl<SocketReadHalf> UnixReadHalf<SocketReadHalf> {
 	#[allow(dead_code)] // Not used when transports are disabled.
-	pub(super) fn new(socket: SocketReadHalf, max_body_len: u32, max_fds: u32, endian: Endian) -> Self {
+	pub(super) fn new(socket: SocketReadHalf, max_body_len: u32, max_fds: u32, endian: EndianState, trace: Option<WireTrace>, error_policy: RemoteErrorPolicy) -> Self {
 		Self {
 			socket,
 			max_body_len,
 			max_fds,
 			endian,
 			body_buffer: Vec::new(),
+			trace,
+			error_policy,
 		}
 	}
 
@@ -103,12 +115,13 @@ impl<SocketReadHalf> UnixReadHalf<SocketReadHalf> {
 
 impl<SocketWriteHalf> UnixWriteHalf<SocketWriteHalf> {
 	#[allow(dead_code)] // Not used when transports are disabled.
-	pub(super) fn new(socket: SocketWriteHalf, max_body_len: u32, max_fds: u32, endian: Endian) -> Self {
+	pub(super) fn new(socket: SocketWriteHalf, max_body_len: u32, max_fds: u32, endian: EndianState, trace: Option<WireTrace>) -> Self {
 		Self {
 			socket,
 			max_body_len,
 			max_fds,
 			endian,
+			trace,
 		}
 	}
 
@@ -140,6 +153,7 @@ mod implementation {
 		check_payload_too_large, connection_aborted,
 	};
 	use crate::transport::TransportError;
+	use crate::transport::trace::TraceDirection;
 	use crate::{Message, MessageHeader, UnixBody};
 
 	impl crate::transport::TransportReadHalf for UnixReadHalf<&tokio_seqpacket::UnixSeqpacket> {
@@ -179,13 +193,20 @@ mod implementation {
 				.map_err(TransportError::new_fatal)?;
 
 			// Parse the header.
-			let header = MessageHeader::decode(&header_buffer, this.endian)
+			let endian = this.endian.detect(&header_buffer, !0, None);
+			let header = MessageHeader::decode(&header_buffer, endian)
 				.map_err(TransportError::new_fatal)?;
 
 			// Resize the body buffer to the actual body size.
 			let mut body = std::mem::take(&mut this.body_buffer);
 			body.resize(bytes_read - crate::HEADER_LEN as usize, 0);
 
+			if let Some(trace) = &this.trace {
+				trace.record(TraceDirection::Received, &[&header_buffer, &body]);
+			}
+
+			this.error_policy.apply_to_message(&header, &mut body);
+			trace_event!(trace, message_type = ?header.message_type, request_id = header.request_id, service_id = header.service_id, body_len = body.len(), fds = fds.len(), "read message");
 			Poll::Ready(Ok(Message::new(header, UnixBody::new(body, fds))))
 		}
 	}
@@ -202,7 +223,7 @@ mod implementation {
 
 			// Prepare a buffer for the message header.
 			let mut header_buffer = [0; crate::HEADER_LEN as usize];
-			header.encode(&mut header_buffer, this.endian);
+			header.encode(&mut header_buffer, this.endian.current());
 
 			// Prepare a buffer for the ancillary data.
 			// TODO: properly compute size of ancillary buffer.
@@ -220,6 +241,18 @@ mod implementation {
 			ready!(this.socket.poll_send_vectored_with_ancillary(context, &buffers, &mut ancillary))
 				.map_err(TransportError::new_fatal)?;
 
+			if let Some(trace) = &this.trace {
+				trace.record(TraceDirection::Sent, &[&header_buffer, &body.data]);
+			}
+			trace_event!(trace, message_type = ?header.message_type, request_id = header.request_id, service_id = header.service_id, body_len = body.data.len(), fds = body.fds.len(), "wrote message");
+
+			Poll::Ready(Ok(()))
+		}
+
+		fn poll_shutdown(self: Pin<&mut Self>, _context: &mut Context) -> Poll<Result<(), TransportError>> {
+			// Messages are sent as a whole, so there is nothing to flush.
+			self.socket.shutdown(std::net::Shutdown::Write)
+				.map_err(TransportError::new_fatal)?;
 			Poll::Ready(Ok(()))
 		}
 	}
@@ -0,0 +1,285 @@
+//! Helpers for implementing custom transports.
+//!
+//! The [`TransportReadHalf::poll_read_msg()`][super::TransportReadHalf::poll_read_msg] and
+//! [`TransportWriteHalf::poll_write_msg()`][super::TransportWriteHalf::poll_write_msg] functions
+//! must be able to resume a partially transferred message after returning [`Poll::Pending`].
+//! The functions in this module implement that bookkeeping for byte streams.
+//!
+//! They track the progress of a transfer in a `usize` that is owned by the caller and must be stored in the transport between calls.
+//! Interrupted reads and writes are retried, and reads or writes that make no progress are reported as errors.
+
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Try to fill a buffer completely without blocking.
+///
+/// The `filled` parameter holds the number of bytes of `buf` that have already been read.
+/// It is updated with every successful read, even if this function returns [`Poll::Pending`] or an error.
+/// Once `filled` equals the length of `buf`, the function returns `Poll::Ready(Ok(()))`.
+///
+/// Reads that fail with [`std::io::ErrorKind::Interrupted`] are retried.
+/// If the stream reaches end-of-file before the buffer is full,
+/// an error with kind [`std::io::ErrorKind::ConnectionAborted`] is returned.
+///
+/// If the function returns [`Poll::Pending`],
+/// the current task is scheduled to wake when the stream is ready for reading.
+pub fn poll_read_exact<R>(mut stream: Pin<&mut R>, context: &mut Context, buf: &mut [u8], filled: &mut usize) -> Poll<std::io::Result<()>>
+where
+	R: AsyncRead + ?Sized,
+{
+	assert!(*filled <= buf.len(), "filled ({}) exceeds buffer length ({})", *filled, buf.len());
+	while *filled < buf.len() {
+		let mut read_buf = ReadBuf::new(&mut buf[*filled..]);
+		match ready!(stream.as_mut().poll_read(context, &mut read_buf)) {
+			Ok(()) if read_buf.filled().is_empty() => {
+				return Poll::Ready(Err(std::io::ErrorKind::ConnectionAborted.into()));
+			},
+			Ok(()) => *filled += read_buf.filled().len(),
+			Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+			Err(e) => return Poll::Ready(Err(e)),
+		}
+	}
+	Poll::Ready(Ok(()))
+}
+
+/// Try to write the concatenation of multiple buffers completely without blocking.
+///
+/// The `written` parameter holds the number of bytes that have already been written.
+/// It is updated with every successful write, even if this function returns [`Poll::Pending`] or an error.
+/// Once all bytes have been written, the function returns `Poll::Ready(Ok(()))`.
+///
+/// The buffers are written with [`AsyncWrite::poll_write_vectored()`],
+/// so a frame header and message body can be written without copying them into a single buffer first.
+/// The caller must pass the same buffers to every call until the write has completed.
+///
+/// Writes that fail with [`std::io::ErrorKind::Interrupted`] are retried.
+/// If the stream accepts zero bytes, an error with kind [`std::io::ErrorKind::WriteZero`] is returned.
+///
+/// If the function returns [`Poll::Pending`],
+/// the current task is scheduled to wake when the stream is ready for writing.
+pub fn poll_write_all_vectored<W>(mut stream: Pin<&mut W>, context: &mut Context, bufs: &[&[u8]], written: &mut usize) -> Poll<std::io::Result<()>>
+where
+	W: AsyncWrite + ?Sized,
+{
+	let total: usize = bufs.iter().map(|x| x.len()).sum();
+	assert!(*written <= total, "written ({}) exceeds total length ({})", *written, total);
+	while *written < total {
+		let slices = remaining_slices(bufs, *written);
+		match ready!(stream.as_mut().poll_write_vectored(context, &slices)) {
+			Ok(0) => return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into())),
+			Ok(n) => *written += n,
+			Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+			Err(e) => return Poll::Ready(Err(e)),
+		}
+	}
+	Poll::Ready(Ok(()))
+}
+
+/// Get the non-empty remainders of `bufs` after skipping the first `skip` bytes.
+fn remaining_slices<'a>(bufs: &[&'a [u8]], mut skip: usize) -> Vec<IoSlice<'a>> {
+	let mut slices = Vec::with_capacity(bufs.len());
+	for buf in bufs {
+		if skip >= buf.len() {
+			skip -= buf.len();
+		} else {
+			slices.push(IoSlice::new(&buf[skip..]));
+			skip = 0;
+		}
+	}
+	slices
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use assert2::assert;
+	use assert2::let_assert;
+	use std::collections::VecDeque;
+
+	/// A single step in the behaviour of a [`ScriptedStream`].
+	#[derive(Debug, Clone, Copy)]
+	enum Step {
+		/// Transfer at most this many bytes.
+		Partial(usize),
+
+		/// Fail with an interrupted error.
+		Interrupted,
+
+		/// Return `Poll::Pending` once, after waking the task.
+		Pending,
+
+		/// Fail with a different error.
+		Fail,
+	}
+
+	/// Stream that reads from and writes to an in-memory buffer according to a script.
+	///
+	/// When the script runs out, all reads and writes are partial transfers of at most 3 bytes.
+	struct ScriptedStream {
+		script: VecDeque<Step>,
+		read_data: Vec<u8>,
+		read_pos: usize,
+		written: Vec<u8>,
+	}
+
+	impl ScriptedStream {
+		fn new(script: &[Step], read_data: &[u8]) -> Self {
+			Self {
+				script: script.iter().copied().collect(),
+				read_data: read_data.to_vec(),
+				read_pos: 0,
+				written: Vec::new(),
+			}
+		}
+
+		fn next_step(&mut self, context: &mut Context) -> Poll<std::io::Result<usize>> {
+			match self.script.pop_front().unwrap_or(Step::Partial(3)) {
+				Step::Partial(n) => Poll::Ready(Ok(n)),
+				Step::Interrupted => Poll::Ready(Err(std::io::ErrorKind::Interrupted.into())),
+				Step::Fail => Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into())),
+				Step::Pending => {
+					context.waker().wake_by_ref();
+					Poll::Pending
+				},
+			}
+		}
+	}
+
+	impl AsyncRead for ScriptedStream {
+		fn poll_read(self: Pin<&mut Self>, context: &mut Context, buf: &mut ReadBuf) -> Poll<std::io::Result<()>> {
+			let this = self.get_mut();
+			let max = ready!(this.next_step(context))?;
+			let data = &this.read_data[this.read_pos..];
+			let len = data.len().min(buf.remaining()).min(max);
+			buf.put_slice(&data[..len]);
+			this.read_pos += len;
+			Poll::Ready(Ok(()))
+		}
+	}
+
+	impl AsyncWrite for ScriptedStream {
+		fn poll_write(self: Pin<&mut Self>, context: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+			let this = self.get_mut();
+			let max = ready!(this.next_step(context))?;
+			let len = buf.len().min(max);
+			this.written.extend_from_slice(&buf[..len]);
+			Poll::Ready(Ok(len))
+		}
+
+		fn poll_write_vectored(self: Pin<&mut Self>, context: &mut Context, bufs: &[IoSlice]) -> Poll<std::io::Result<usize>> {
+			let this = self.get_mut();
+			let mut remaining = ready!(this.next_step(context))?;
+			let mut total = 0;
+			for buf in bufs {
+				let len = buf.len().min(remaining);
+				this.written.extend_from_slice(&buf[..len]);
+				remaining -= len;
+				total += len;
+			}
+			Poll::Ready(Ok(total))
+		}
+
+		fn is_write_vectored(&self) -> bool {
+			true
+		}
+
+		fn poll_flush(self: Pin<&mut Self>, _context: &mut Context) -> Poll<std::io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+
+		fn poll_shutdown(self: Pin<&mut Self>, _context: &mut Context) -> Poll<std::io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+	}
+
+	async fn read_exact(stream: &mut ScriptedStream, buf: &mut [u8], filled: &mut usize) -> std::io::Result<()> {
+		std::future::poll_fn(|context| poll_read_exact(Pin::new(&mut *stream), context, buf, filled)).await
+	}
+
+	async fn write_all(stream: &mut ScriptedStream, bufs: &[&[u8]], written: &mut usize) -> std::io::Result<()> {
+		std::future::poll_fn(|context| poll_write_all_vectored(Pin::new(&mut *stream), context, bufs, written)).await
+	}
+
+	#[tokio::test]
+	async fn read_exact_resumes_partial_reads() {
+		let script = [Step::Partial(1), Step::Interrupted, Step::Pending, Step::Partial(2), Step::Interrupted];
+		let mut stream = ScriptedStream::new(&script, b"Hello world!");
+		let mut buf = [0u8; 12];
+		let mut filled = 0;
+		assert!(let Ok(()) = read_exact(&mut stream, &mut buf, &mut filled).await);
+		assert!(filled == 12);
+		assert!(&buf == b"Hello world!");
+	}
+
+	#[tokio::test]
+	async fn read_exact_reports_progress_on_error() {
+		let script = [Step::Partial(4), Step::Fail];
+		let mut stream = ScriptedStream::new(&script, b"Hello world!");
+		let mut buf = [0u8; 12];
+		let mut filled = 0;
+		let_assert!(Err(e) = read_exact(&mut stream, &mut buf, &mut filled).await);
+		assert!(e.kind() == std::io::ErrorKind::BrokenPipe);
+		assert!(filled == 4);
+
+		// The read can be resumed after a non-fatal error.
+		assert!(let Ok(()) = read_exact(&mut stream, &mut buf, &mut filled).await);
+		assert!(&buf == b"Hello world!");
+	}
+
+	#[tokio::test]
+	async fn read_exact_end_of_file() {
+		let mut stream = ScriptedStream::new(&[], b"Hello");
+		let mut buf = [0u8; 12];
+		let mut filled = 0;
+		let_assert!(Err(e) = read_exact(&mut stream, &mut buf, &mut filled).await);
+		assert!(e.kind() == std::io::ErrorKind::ConnectionAborted);
+		assert!(filled == 5);
+
+		// Reading an empty buffer never touches the stream.
+		let mut filled = 0;
+		assert!(let Ok(()) = read_exact(&mut stream, &mut [], &mut filled).await);
+	}
+
+	#[tokio::test]
+	async fn write_all_resumes_partial_writes() {
+		let script = [Step::Partial(2), Step::Pending, Step::Interrupted, Step::Partial(5), Step::Partial(1)];
+		let mut stream = ScriptedStream::new(&script, b"");
+		let mut written = 0;
+		assert!(let Ok(()) = write_all(&mut stream, &[b"head", b"", b"er", b"body data"], &mut written).await);
+		assert!(written == 15);
+		assert!(stream.written == b"headerbody data");
+	}
+
+	#[tokio::test]
+	async fn write_all_reports_progress_on_error() {
+		let script = [Step::Partial(5), Step::Fail];
+		let mut stream = ScriptedStream::new(&script, b"");
+		let mut written = 0;
+		let_assert!(Err(e) = write_all(&mut stream, &[b"header", b"body"], &mut written).await);
+		assert!(e.kind() == std::io::ErrorKind::BrokenPipe);
+		assert!(written == 5);
+
+		assert!(let Ok(()) = write_all(&mut stream, &[b"header", b"body"], &mut written).await);
+		assert!(stream.written == b"headerbody");
+	}
+
+	#[tokio::test]
+	async fn write_all_write_zero() {
+		let mut stream = ScriptedStream::new(&[Step::Partial(0)], b"");
+		let mut written = 0;
+		let_assert!(Err(e) = write_all(&mut stream, &[b"header"], &mut written).await);
+		assert!(e.kind() == std::io::ErrorKind::WriteZero);
+		assert!(written == 0);
+	}
+
+	#[test]
+	fn remaining_slices_skips_written_bytes() {
+		let bufs: &[&[u8]] = &[b"abc", b"", b"de", b"fgh"];
+		let slices = remaining_slices(bufs, 4);
+		let slices: Vec<&[u8]> = slices.iter().map(|x| &x[..]).collect();
+		assert!(slices == [&b"e"[..], &b"fgh"[..]]);
+		assert!(remaining_slices(bufs, 8).is_empty());
+	}
+}
@@ -1,4 +1,5 @@
 use std::future::Future;
+use std::os::unix::io::OwnedFd;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
@@ -16,7 +17,7 @@ pub trait Listener {
 	fn poll_accept(self: Pin<&mut Self>, context: &mut Context) -> Poll<std::io::Result<(Self::Connection, Self::Address)>>;
 
 	/// Asynchronously accept a new connection.
-	fn accept(&mut self) -> Accept<Self>
+	fn accept(&mut self) -> Accept<'_, Self>
 	where
 		Self: Unpin,
 	{
@@ -33,6 +34,28 @@ pub trait Bind<'a, Address: 'a>: Sized + Listener {
 	fn bind(address: Address) -> Self::Future;
 }
 
+/// Trait for creating a listener from a listening socket of the standard library.
+pub trait FromStd: Sized + Listener {
+	/// The standard library type of the listening socket.
+	type Std;
+
+	/// Create a new listener from a listening socket of the standard library.
+	///
+	/// The socket is put in non-blocking mode.
+	/// This function must be called from within a tokio runtime.
+	fn from_std(listener: Self::Std) -> std::io::Result<Self>;
+}
+
+/// Trait for creating a listener from the file descriptor of a listening socket.
+pub trait FromFd: Sized + Listener {
+	/// Create a new listener from the file descriptor of a listening socket.
+	///
+	/// The file descriptor must refer to a socket of the right type that is already listening for connections.
+	/// The socket is put in non-blocking mode.
+	/// This function must be called from within a tokio runtime.
+	fn from_fd(fd: OwnedFd) -> std::io::Result<Self>;
+}
+
 /// Future type returned by [`Listener::accept`].
 pub struct Accept<'a, L: ?Sized> {
 	inner: &'a mut L,
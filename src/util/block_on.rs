@@ -0,0 +1,54 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake};
+
+/// Run a future to completion on the current thread.
+///
+/// The thread is parked while the future is pending, and unparked when the future is woken.
+/// This does not require a tokio runtime on the current thread,
+/// so it only works for futures that are woken by other threads,
+/// like the futures that wait for the command channels of a peer.
+///
+/// # Panics
+/// This function panics if it is called from within a tokio runtime,
+/// because blocking the thread could prevent the runtime from ever waking the future.
+#[track_caller]
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+	if tokio::runtime::Handle::try_current().is_ok() {
+		panic!("cannot block the current thread from within a runtime, use the async version of the function instead");
+	}
+
+	let waker = Arc::new(ThreadWaker(std::thread::current())).into();
+	let mut context = Context::from_waker(&waker);
+	tokio::pin!(future);
+	loop {
+		if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+			return output;
+		}
+		std::thread::park();
+	}
+}
+
+/// Waker that unparks a thread.
+struct ThreadWaker(std::thread::Thread);
+
+impl Wake for ThreadWaker {
+	fn wake(self: Arc<Self>) {
+		self.0.unpark();
+	}
+
+	fn wake_by_ref(self: &Arc<Self>) {
+		self.0.unpark();
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[tokio::test]
+	#[should_panic(expected = "cannot block the current thread from within a runtime")]
+	async fn block_on_panics_in_runtime() {
+		block_on(async {});
+	}
+}
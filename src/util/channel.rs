@@ -0,0 +1,157 @@
+//! Internal channels that are unbounded by default, and bounded with the `strict-memory` feature.
+
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+
+use crate::error::private::{connection_aborted, InnerError};
+use crate::Error;
+
+/// The sending half of an internal channel.
+pub(crate) struct Sender<T> {
+	#[cfg(not(feature = "strict-memory"))]
+	inner: mpsc::UnboundedSender<T>,
+
+	#[cfg(feature = "strict-memory")]
+	inner: mpsc::Sender<T>,
+}
+
+/// The receiving half of an internal channel.
+pub(crate) struct Receiver<T> {
+	#[cfg(not(feature = "strict-memory"))]
+	inner: mpsc::UnboundedReceiver<T>,
+
+	#[cfg(feature = "strict-memory")]
+	inner: mpsc::Receiver<T>,
+}
+
+/// Error returned when a value could not be sent on an internal channel.
+pub(crate) enum SendError<T> {
+	/// The receiving half of the channel was dropped or closed.
+	Closed(T),
+
+	/// The channel is full.
+	///
+	/// This can only happen with the `strict-memory` feature.
+	#[cfg_attr(not(feature = "strict-memory"), allow(dead_code))]
+	Full(T),
+}
+
+/// Create a new internal channel.
+///
+/// With the `strict-memory` feature, the channel can hold at most `capacity` values (but at least one).
+/// Without the feature, the capacity is ignored and the channel is unbounded.
+pub(crate) fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+	#[cfg(not(feature = "strict-memory"))]
+	let (tx, rx) = {
+		let _ = capacity;
+		mpsc::unbounded_channel()
+	};
+
+	#[cfg(feature = "strict-memory")]
+	let (tx, rx) = mpsc::channel(capacity.max(1));
+
+	(Sender { inner: tx }, Receiver { inner: rx })
+}
+
+impl<T> Sender<T> {
+	/// Send a value without waiting for free capacity.
+	pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+		#[cfg(not(feature = "strict-memory"))]
+		return self.inner.send(value).map_err(|mpsc::error::SendError(value)| SendError::Closed(value));
+
+		#[cfg(feature = "strict-memory")]
+		return self.inner.try_send(value).map_err(|e| match e {
+			mpsc::error::TrySendError::Closed(value) => SendError::Closed(value),
+			mpsc::error::TrySendError::Full(value) => SendError::Full(value),
+		});
+	}
+
+	/// Send a value without waiting for free capacity, but leave room for at least one more value.
+	///
+	/// This is used to make sure that there is always room for a final message on the channel.
+	pub fn send_keep_one(&self, value: T) -> Result<(), SendError<T>> {
+		#[cfg(feature = "strict-memory")]
+		if self.inner.capacity() <= 1 && !self.inner.is_closed() {
+			return Err(SendError::Full(value));
+		}
+		self.send(value)
+	}
+
+	/// Send a value, waiting for free capacity if needed.
+	///
+	/// Returns the value back if the receiving half of the channel was dropped or closed.
+	pub async fn send_wait(&self, value: T) -> Result<(), T> {
+		#[cfg(not(feature = "strict-memory"))]
+		return self.inner.send(value).map_err(|mpsc::error::SendError(value)| value);
+
+		#[cfg(feature = "strict-memory")]
+		return self.inner.send(value).await.map_err(|mpsc::error::SendError(value)| value);
+	}
+
+	/// Check if the receiving half of the channel was dropped or closed.
+	pub fn is_closed(&self) -> bool {
+		self.inner.is_closed()
+	}
+
+	/// Wait until the receiving half of the channel is dropped or closed.
+	pub async fn closed(&self) {
+		self.inner.closed().await
+	}
+
+	/// Check if this sender sends to the same channel as `other`.
+	pub fn same_channel(&self, other: &Self) -> bool {
+		self.inner.same_channel(&other.inner)
+	}
+}
+
+impl<T> Clone for Sender<T> {
+	fn clone(&self) -> Self {
+		Self { inner: self.inner.clone() }
+	}
+}
+
+impl<T> Receiver<T> {
+	/// Receive the next value from the channel.
+	///
+	/// Returns `None` if the channel is closed and empty.
+	pub async fn recv(&mut self) -> Option<T> {
+		self.inner.recv().await
+	}
+
+	/// Poll the channel for the next value.
+	pub fn poll_recv(&mut self, context: &mut Context) -> Poll<Option<T>> {
+		self.inner.poll_recv(context)
+	}
+
+	/// Get the number of values waiting in the channel.
+	pub fn len(&self) -> usize {
+		self.inner.len()
+	}
+
+	/// Close the channel, so no more values can be sent.
+	///
+	/// Values that were already sent can still be received.
+	pub fn close(&mut self) {
+		self.inner.close()
+	}
+}
+
+impl<T> SendError<T> {
+	/// Get the value that could not be sent.
+	pub fn into_inner(self) -> T {
+		match self {
+			Self::Closed(value) => value,
+			Self::Full(value) => value,
+		}
+	}
+
+	/// Convert the send error into an RPC error.
+	///
+	/// A closed channel means the peer stopped, so it results in a "connection aborted" error.
+	pub fn into_error(self) -> Error {
+		match self {
+			Self::Closed(_) => connection_aborted(),
+			Self::Full(_) => InnerError::CapacityExceeded.into(),
+		}
+	}
+}
@@ -0,0 +1,156 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::transport::{Transport, TransportError, TransportReadHalf, TransportWriteHalf};
+use crate::{Message, MessageHeader};
+
+/// A value that is one of two types.
+///
+/// This is used to combine two transports with the same body type into a single transport type,
+/// for example to serve TCP and Unix stream connections with one [`Listener`][crate::Listener].
+/// See [`EitherListener`][super::EitherListener] for more details.
+///
+/// The enum implements the transport traits if both variants implement them with the same body type.
+/// The combined transport uses a tuple of the configuration of both transports as configuration,
+/// and the information of the transport is an [`Either`] of the information of both transports.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Either<A, B> {
+	/// The first type.
+	Left(A),
+
+	/// The second type.
+	Right(B),
+}
+
+impl<A, B> Either<A, B> {
+	/// Get the left value, if this is a [`Self::Left`].
+	pub fn left(self) -> Option<A> {
+		match self {
+			Self::Left(x) => Some(x),
+			Self::Right(_) => None,
+		}
+	}
+
+	/// Get the right value, if this is a [`Self::Right`].
+	pub fn right(self) -> Option<B> {
+		match self {
+			Self::Left(_) => None,
+			Self::Right(x) => Some(x),
+		}
+	}
+
+	/// Convert a reference to an [`Either`] into an [`Either`] of references.
+	pub fn as_ref(&self) -> Either<&A, &B> {
+		match self {
+			Self::Left(x) => Either::Left(x),
+			Self::Right(x) => Either::Right(x),
+		}
+	}
+}
+
+impl<A, B> super::IntoTransport for Either<A, B>
+where
+	A: super::IntoTransport,
+	B: super::IntoTransport<Body = A::Body>,
+{
+	type Body = A::Body;
+	type Config = (A::Config, B::Config);
+	type Transport = Either<A::Transport, B::Transport>;
+
+	fn into_transport(self, (config_a, config_b): Self::Config) -> Self::Transport {
+		match self {
+			Self::Left(x) => Either::Left(x.into_transport(config_a)),
+			Self::Right(x) => Either::Right(x.into_transport(config_b)),
+		}
+	}
+}
+
+impl<A, B> Transport for Either<A, B>
+where
+	A: Transport,
+	B: Transport<Body = A::Body>,
+{
+	type Body = A::Body;
+	type Info = Either<A::Info, B::Info>;
+	type Config = (A::Config, B::Config);
+	type ReadHalf<'a> = Either<A::ReadHalf<'a>, B::ReadHalf<'a>>;
+	type WriteHalf<'a> = Either<A::WriteHalf<'a>, B::WriteHalf<'a>>;
+
+	fn split(&mut self) -> (Self::ReadHalf<'_>, Self::WriteHalf<'_>) {
+		match self {
+			Self::Left(x) => {
+				let (read_half, write_half) = x.split();
+				(Either::Left(read_half), Either::Left(write_half))
+			},
+			Self::Right(x) => {
+				let (read_half, write_half) = x.split();
+				(Either::Right(read_half), Either::Right(write_half))
+			},
+		}
+	}
+
+	fn info(&self) -> std::io::Result<Self::Info> {
+		match self {
+			Self::Left(x) => Ok(Either::Left(x.info()?)),
+			Self::Right(x) => Ok(Either::Right(x.info()?)),
+		}
+	}
+
+	fn describe_remote(&self) -> Option<String> {
+		match self {
+			Self::Left(x) => x.describe_remote(),
+			Self::Right(x) => x.describe_remote(),
+		}
+	}
+}
+
+impl<A, B> TransportReadHalf for Either<A, B>
+where
+	A: TransportReadHalf,
+	B: TransportReadHalf<Body = A::Body>,
+{
+	type Body = A::Body;
+
+	fn poll_read_msg(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<Message<Self::Body>, TransportError>> {
+		match self.get_mut() {
+			Self::Left(x) => Pin::new(x).poll_read_msg(context),
+			Self::Right(x) => Pin::new(x).poll_read_msg(context),
+		}
+	}
+}
+
+impl<A, B> TransportWriteHalf for Either<A, B>
+where
+	A: TransportWriteHalf,
+	B: TransportWriteHalf<Body = A::Body>,
+{
+	type Body = A::Body;
+
+	fn poll_write_msg(self: Pin<&mut Self>, context: &mut Context, header: &MessageHeader, body: &Self::Body) -> Poll<Result<(), TransportError>> {
+		match self.get_mut() {
+			Self::Left(x) => Pin::new(x).poll_write_msg(context, header, body),
+			Self::Right(x) => Pin::new(x).poll_write_msg(context, header, body),
+		}
+	}
+
+	fn poll_write_msgs(self: Pin<&mut Self>, context: &mut Context, messages: &[Message<Self::Body>], written: &mut usize) -> Poll<Result<(), TransportError>> {
+		match self.get_mut() {
+			Self::Left(x) => Pin::new(x).poll_write_msgs(context, messages, written),
+			Self::Right(x) => Pin::new(x).poll_write_msgs(context, messages, written),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), TransportError>> {
+		match self.get_mut() {
+			Self::Left(x) => Pin::new(x).poll_flush(context),
+			Self::Right(x) => Pin::new(x).poll_flush(context),
+		}
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), TransportError>> {
+		match self.get_mut() {
+			Self::Left(x) => Pin::new(x).poll_shutdown(context),
+			Self::Right(x) => Pin::new(x).poll_shutdown(context),
+		}
+	}
+}
@@ -5,16 +5,28 @@
 //!
 //! However, if you wish to implement a custom transport,
 //! you may also wish to implement these traits.
+//!
+//! The module also contains the [`MultiListener`] and [`EitherListener`] types,
+//! which can be used to let a single [`Listener`][crate::Listener] accept connections from multiple sockets.
 
 mod accept;
+mod block_on;
+pub(crate) mod channel;
 mod connect;
+mod either;
 mod into_transport;
+mod multi_listener;
 mod select;
+mod spawn;
 
-pub use accept::{Accept, Bind, Listener};
+pub use accept::{Accept, Bind, FromFd, FromStd, Listener};
 pub use connect::Connect;
+pub use either::Either;
 pub use into_transport::IntoTransport;
+pub use multi_listener::{EitherListener, MultiListener};
 
-// `select` is not a trait, but it's not exported publicly.
+// `block_on` and `select` are not traits, but they're not exported publicly.
 // So the module documentation is still fine.
-pub(crate) use select::{select, Either};
+pub(crate) use block_on::block_on;
+pub(crate) use select::select;
+pub(crate) use spawn::spawn_named;
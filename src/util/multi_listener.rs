@@ -0,0 +1,182 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::{Bind, Either, Listener};
+
+/// Listener that accepts connections from multiple listening sockets of the same type.
+///
+/// This can be used to listen on multiple addresses at once,
+/// for example on an IPv4 and an IPv6 address.
+///
+/// The sockets are polled in turn, so that a busy socket can not starve the others.
+/// To create a [`Listener`][crate::Listener] bound to multiple addresses, pass a [`Vec`] of addresses to [`Listener::bind()`][crate::Listener::bind].
+#[derive(Debug)]
+pub struct MultiListener<L> {
+	/// The listening sockets.
+	listeners: Vec<L>,
+
+	/// The index of the socket to poll first on the next accept.
+	next: usize,
+}
+
+/// Listener that accepts connections from two listening sockets of a different type.
+///
+/// The accepted connections are wrapped in an [`Either`],
+/// which can be turned into a transport if both connection types produce transports with the same body type.
+/// This can be used to serve local clients over a Unix socket and remote clients over TCP with a single [`Listener`][crate::Listener]:
+///
+/// ```no_run
+/// # use fizyr_rpc::util::EitherListener;
+/// # use fizyr_rpc::{Listener, StreamConfig};
+/// # async fn foo() -> std::io::Result<()> {
+/// type TcpOrUnix = EitherListener<tokio::net::TcpListener, tokio::net::UnixListener>;
+/// let config = (StreamConfig::default(), StreamConfig::default());
+/// let mut listener = Listener::<TcpOrUnix>::bind(("[::]:1337", "/run/server.sock"), config).await?;
+/// let (peer, info) = listener.accept().await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The sockets are polled in turn, so that a busy socket can not starve the other.
+#[derive(Debug)]
+pub struct EitherListener<A, B> {
+	/// The first listening socket.
+	left: A,
+
+	/// The second listening socket.
+	right: B,
+
+	/// If true, the second socket is polled first on the next accept.
+	right_first: bool,
+}
+
+impl<L> MultiListener<L> {
+	/// Create a listener that accepts connections from all given sockets.
+	///
+	/// If the list of sockets is empty, the listener never accepts any connection.
+	pub fn new(listeners: Vec<L>) -> Self {
+		Self { listeners, next: 0 }
+	}
+
+	/// Get the listening sockets.
+	pub fn listeners(&self) -> &[L] {
+		&self.listeners
+	}
+
+	/// Consume the listener to get the listening sockets.
+	pub fn into_listeners(self) -> Vec<L> {
+		self.listeners
+	}
+}
+
+impl<A, B> EitherListener<A, B> {
+	/// Create a listener that accepts connections from both sockets.
+	pub fn new(left: A, right: B) -> Self {
+		Self {
+			left,
+			right,
+			right_first: false,
+		}
+	}
+
+	/// Get the first listening socket.
+	pub fn left(&self) -> &A {
+		&self.left
+	}
+
+	/// Get the second listening socket.
+	pub fn right(&self) -> &B {
+		&self.right
+	}
+
+	/// Consume the listener to get both listening sockets.
+	pub fn into_inner(self) -> (A, B) {
+		(self.left, self.right)
+	}
+}
+
+impl<L> Listener for MultiListener<L>
+where
+	L: Listener + Unpin,
+{
+	type Address = L::Address;
+	type Connection = L::Connection;
+
+	fn poll_accept(self: Pin<&mut Self>, context: &mut Context) -> Poll<std::io::Result<(Self::Connection, Self::Address)>> {
+		let this = self.get_mut();
+		let count = this.listeners.len();
+		for i in 0..count {
+			let index = (this.next + i) % count;
+			if let Poll::Ready(result) = Pin::new(&mut this.listeners[index]).poll_accept(context) {
+				this.next = (index + 1) % count;
+				return Poll::Ready(result);
+			}
+		}
+		Poll::Pending
+	}
+}
+
+impl<A, B> Listener for EitherListener<A, B>
+where
+	A: Listener + Unpin,
+	B: Listener + Unpin,
+{
+	type Address = Either<A::Address, B::Address>;
+	type Connection = Either<A::Connection, B::Connection>;
+
+	fn poll_accept(self: Pin<&mut Self>, context: &mut Context) -> Poll<std::io::Result<(Self::Connection, Self::Address)>> {
+		let this = self.get_mut();
+		for _ in 0..2 {
+			let right = this.right_first;
+			this.right_first = !this.right_first;
+			if right {
+				if let Poll::Ready(result) = Pin::new(&mut this.right).poll_accept(context) {
+					return Poll::Ready(result.map(|(connection, address)| (Either::Right(connection), Either::Right(address))));
+				}
+			} else if let Poll::Ready(result) = Pin::new(&mut this.left).poll_accept(context) {
+				return Poll::Ready(result.map(|(connection, address)| (Either::Left(connection), Either::Left(address))));
+			}
+		}
+		Poll::Pending
+	}
+}
+
+impl<'a, L, Address> Bind<'a, Vec<Address>> for MultiListener<L>
+where
+	L: Bind<'a, Address> + Unpin + 'a,
+	L::Future: 'a,
+	Address: 'a,
+{
+	type Future = Pin<Box<dyn Future<Output = std::io::Result<Self>> + 'a>>;
+
+	fn bind(addresses: Vec<Address>) -> Self::Future {
+		Box::pin(async move {
+			let mut listeners = Vec::with_capacity(addresses.len());
+			for address in addresses {
+				listeners.push(L::bind(address).await?);
+			}
+			Ok(Self::new(listeners))
+		})
+	}
+}
+
+impl<'a, A, B, AddressA, AddressB> Bind<'a, (AddressA, AddressB)> for EitherListener<A, B>
+where
+	A: Bind<'a, AddressA> + Unpin + 'a,
+	B: Bind<'a, AddressB> + Unpin + 'a,
+	A::Future: 'a,
+	B::Future: 'a,
+	AddressA: 'a,
+	AddressB: 'a,
+{
+	type Future = Pin<Box<dyn Future<Output = std::io::Result<Self>> + 'a>>;
+
+	fn bind((address_a, address_b): (AddressA, AddressB)) -> Self::Future {
+		Box::pin(async move {
+			let left = A::bind(address_a).await?;
+			let right = B::bind(address_b).await?;
+			Ok(Self::new(left, right))
+		})
+	}
+}
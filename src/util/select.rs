@@ -4,10 +4,7 @@ use std::pin::Pin;
 use std::task::Context;
 use std::task::Poll;
 
-pub enum Either<A, B> {
-	Left(A),
-	Right(B),
-}
+use super::Either;
 
 pub struct Select<A, B> {
 	inner: Option<(A, B)>,
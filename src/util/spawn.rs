@@ -0,0 +1,28 @@
+use std::future::Future;
+use tokio::task::JoinHandle;
+
+/// Spawn a task with a name.
+///
+/// With the `tokio-console` feature and the `tokio_unstable` cfg flag,
+/// the task is spawned with the given name so it can be identified in `tokio-console`.
+/// Otherwise, the name is not computed and the task is spawned with [`tokio::spawn()`].
+pub(crate) fn spawn_named<F>(name: impl FnOnce() -> String, future: F) -> JoinHandle<F::Output>
+where
+	F: Future + Send + 'static,
+	F::Output: Send + 'static,
+{
+	#[cfg(all(tokio_unstable, feature = "tokio-console"))]
+	{
+		let name = name();
+		match tokio::task::Builder::new().name(&name).spawn(future) {
+			Ok(x) => x,
+			Err(e) => panic!("failed to spawn task {:?}: {}", name, e),
+		}
+	}
+
+	#[cfg(not(all(tokio_unstable, feature = "tokio-console")))]
+	{
+		let _ = name;
+		tokio::spawn(future)
+	}
+}